@@ -0,0 +1,73 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Benchmarks of lexing, parsing, compiling and satisfying the workloads of the
+//! `generators` module. See that module for how to compare against a baseline.
+
+#[macro_use]
+extern crate criterion;
+extern crate script_descriptor;
+
+use criterion::Criterion;
+
+use script_descriptor::generators;
+use script_descriptor::parse::lex;
+use script_descriptor::{ParseTree, ScriptContext};
+
+fn lex_large_script(c: &mut Criterion) {
+    let script = generators::script(&generators::threshold(67, 100));
+    c.bench_function("lex 67-of-100 threshold", move |b| {
+        b.iter(|| lex(&script, ScriptContext::SegwitV0).unwrap())
+    });
+}
+
+fn parse_deep_tree(c: &mut Criterion) {
+    let script = generators::script(&generators::nested_or(30));
+    c.bench_function("parse 30 nested ors", move |b| {
+        b.iter(|| ParseTree::parse(&script).unwrap())
+    });
+}
+
+fn compile_wide_threshold(c: &mut Criterion) {
+    let desc = generators::threshold(67, 100);
+    c.bench_function("compile 67-of-100 threshold", move |b| {
+        b.iter(|| ParseTree::compile(&desc).unwrap())
+    });
+}
+
+fn compile_deep_or(c: &mut Criterion) {
+    let desc = generators::nested_or(30);
+    c.bench_function("compile 30 nested ors", move |b| {
+        b.iter(|| ParseTree::compile(&desc).unwrap())
+    });
+}
+
+fn satisfy_nested_or(c: &mut Criterion) {
+    let tree = ParseTree::compile(&generators::nested_or(30)).unwrap();
+    // Only the innermost branch can be satisfied
+    let sigs = generators::signatures(&[30]);
+    c.bench_function("satisfy 30 nested ors", move |b| {
+        b.iter(|| tree.satisfy(&sigs).unwrap())
+    });
+}
+
+criterion_group!(
+    benches,
+    lex_large_script,
+    parse_deep_tree,
+    compile_wide_threshold,
+    compile_deep_or,
+    satisfy_nested_or
+);
+criterion_main!(benches);
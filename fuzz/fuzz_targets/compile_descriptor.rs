@@ -12,8 +12,9 @@ fn do_test(data: &[u8]) {
 
     let data_str = String::from_utf8_lossy(data);
     if let Ok(desc) = &Descriptor::<secp256k1::PublicKey>::from_str(&data_str) {
-        let pt = ParseTree::compile(&desc);
-        let _ = pt.serialize();
+        if let Ok(pt) = ParseTree::compile(&desc) {
+            let _ = pt.serialize();
+        }
     }
 }
 
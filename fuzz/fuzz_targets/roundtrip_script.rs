@@ -0,0 +1,58 @@
+extern crate script_descriptor;
+
+use script_descriptor::arbitrary::{arbitrary_parse_tree, ByteSource};
+use script_descriptor::ParseTree;
+
+fn do_test(data: &[u8]) {
+    let pt = arbitrary_parse_tree(&mut ByteSource::new(data), 4);
+    let script = pt.serialize();
+    let reparsed = ParseTree::parse(&script).expect("compiled script parses");
+    assert_eq!(reparsed.serialize(), script);
+}
+
+#[cfg(feature = "afl")]
+extern crate afl;
+#[cfg(feature = "afl")]
+fn main() {
+    afl::read_stdio_bytes(|data| {
+        do_test(&data);
+    });
+}
+
+#[cfg(feature = "honggfuzz")]
+#[macro_use] extern crate honggfuzz;
+#[cfg(feature = "honggfuzz")]
+fn main() {
+    loop {
+        fuzz!(|data| {
+            do_test(data);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn extend_vec_from_hex(hex: &str, out: &mut Vec<u8>) {
+        let mut b = 0;
+        for (idx, c) in hex.as_bytes().iter().enumerate() {
+            b <<= 4;
+            match *c {
+                b'A'...b'F' => b |= c - b'A' + 10,
+                b'a'...b'f' => b |= c - b'a' + 10,
+                b'0'...b'9' => b |= c - b'0',
+                _ => panic!("Bad hex"),
+            }
+            if (idx & 1) == 1 {
+                out.push(b);
+                b = 0;
+            }
+        }
+    }
+
+    #[test]
+    fn exhausted_input() {
+        let mut a = Vec::new();
+        extend_vec_from_hex("0000", &mut a);
+        super::do_test(&a);
+    }
+}
@@ -0,0 +1,225 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Random Generation
+//!
+//! Generators of random descriptors and parse trees, enabled by the `arbitrary` feature.
+//! Randomness is drawn from a byte string, so that fuzzers can drive generation
+//! directly from their input; every byte string, including the empty one, gives a
+//! valid result, and running out of bytes makes the remainder of the result as
+//! small as possible.
+//!
+//! Only descriptors which the compiler accepts are generated: there are no `sh`, `wsh`
+//! or `wpkh` wrappers, no `combo`, `addr` or `raw` forms, and every threshold can be
+//! satisfied. Public keys are drawn from a fixed set, listed by `ByteSource::keys`.
+//!
+
+use secp256k1;
+
+use bitcoin::util::hash::{Hash160, Sha256dHash};
+
+use bitcoin_hashes::Hash;
+
+use bitcoin_hashes::ripemd160::Hash as Ripemd160Hash;
+
+use descriptor::SEQUENCE_TYPE_FLAG;
+use {sha256, Descriptor, ParseTree};
+
+/// Number of distinct public keys which generated descriptors use
+const N_KEYS: usize = 8;
+
+/// Source of the randomness which drives generation
+pub struct ByteSource<'a> {
+    data: &'a [u8],
+    keys: Vec<secp256k1::PublicKey>,
+}
+
+impl<'a> ByteSource<'a> {
+    /// Creates a source reading from the given bytes
+    pub fn new(data: &'a [u8]) -> ByteSource<'a> {
+        let secp = secp256k1::Secp256k1::new();
+        let keys = (0..N_KEYS).map(|i| {
+            let sk = secp256k1::SecretKey::from_slice(&secp, &[i as u8 + 1; 32]).expect("secret key");
+            secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context")
+        }).collect();
+        ByteSource {
+            data,
+            keys,
+        }
+    }
+
+    /// The public keys which generated descriptors may contain
+    pub fn keys(&self) -> &[secp256k1::PublicKey] {
+        &self.keys
+    }
+
+    /// Takes the next byte, or 0 once the data is used up
+    pub fn byte(&mut self) -> u8 {
+        match self.data.split_first() {
+            Some((first, rest)) => {
+                self.data = rest;
+                *first
+            }
+            None => 0,
+        }
+    }
+
+    /// Takes a number below `n`, which must be nonzero
+    pub fn below(&mut self, n: usize) -> usize {
+        let x = ((self.byte() as usize) << 8) | self.byte() as usize;
+        x % n
+    }
+
+    /// Takes one of the public keys
+    fn key(&mut self) -> secp256k1::PublicKey {
+        let i = self.below(N_KEYS);
+        self.keys[i]
+    }
+}
+
+/// Generates a descriptor whose subdescriptors are nested at most `depth` deep
+pub fn arbitrary_descriptor(src: &mut ByteSource, depth: usize) -> Descriptor<secp256k1::PublicKey> {
+    // Leaves come first, so that an exhausted source gives a single key
+    let choice = if depth == 0 { src.below(8) } else { src.below(14) };
+    match choice {
+        0 => Descriptor::Key(src.key()),
+        1 => Descriptor::KeyHash(src.key()),
+        2 => {
+            let n = 1 + src.below(3);
+            let k = 1 + src.below(n);
+            Descriptor::Multi(k, (0..n).map(|_| src.key()).collect())
+        }
+        3 => Descriptor::Hash(sha256::Hash::from_data(&[src.byte()])),
+        4 => Descriptor::Hash160(Hash160::from_data(&[src.byte()])),
+        5 => Descriptor::Ripemd160(Ripemd160Hash::hash(&[src.byte()])),
+        6 => Descriptor::Hash256(Sha256dHash::from_data(&[src.byte()])),
+        7 => {
+            let n = 1 + src.below(0xffff) as u32;
+            if src.byte() & 1 == 0 {
+                Descriptor::Time(n)
+            } else {
+                Descriptor::Time(n | SEQUENCE_TYPE_FLAG)
+            }
+        }
+        8 => {
+            let n = 2 + src.below(3);
+            let k = 1 + src.below(n);
+            Descriptor::Threshold(k, (0..n).map(|_| arbitrary_descriptor(src, depth - 1)).collect())
+        }
+        9 | 10 => Descriptor::And(
+            Box::new(arbitrary_descriptor(src, depth - 1)),
+            Box::new(arbitrary_descriptor(src, depth - 1)),
+        ),
+        11 => Descriptor::Or(
+            Box::new(arbitrary_descriptor(src, depth - 1)),
+            Box::new(arbitrary_descriptor(src, depth - 1)),
+        ),
+        12 => Descriptor::AsymmetricOr(
+            Box::new(arbitrary_descriptor(src, depth - 1)),
+            Box::new(arbitrary_descriptor(src, depth - 1)),
+        ),
+        _ => Descriptor::WeightedOr(
+            1 + src.below(10),
+            Box::new(arbitrary_descriptor(src, depth - 1)),
+            1 + src.below(10),
+            Box::new(arbitrary_descriptor(src, depth - 1)),
+        ),
+    }
+}
+
+/// Generates a parse tree, by compiling a descriptor from `arbitrary_descriptor`.
+/// Every fragment which the compiler may produce can occur.
+pub fn arbitrary_parse_tree(src: &mut ByteSource, depth: usize) -> ParseTree {
+    let desc = arbitrary_descriptor(src, depth);
+    ParseTree::compile(&desc).expect("generated descriptors compile")
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1;
+
+    use bitcoin::util::hash::Hash160;
+
+    use parse::{AstElem, Policy};
+    use {Descriptor, ParseTree};
+    use super::{arbitrary_descriptor, ByteSource};
+
+    /// Deterministic stream of pseudorandom bytes (xorshift)
+    fn test_bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9e3779b97f4a7c15) | 1;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 32) as u8
+        }).collect()
+    }
+
+    /// Converts a policy back into a descriptor, recovering the key behind each
+    /// public key hash from `keys`
+    fn lift(policy: &Policy, keys: &[secp256k1::PublicKey]) -> Descriptor<secp256k1::PublicKey> {
+        match *policy {
+            Policy::Key(pk) => Descriptor::Key(pk),
+            Policy::KeyHash(hash) => {
+                let pk = keys.iter().find(|pk| Hash160::from_data(&pk.serialize()[..]) == hash);
+                Descriptor::KeyHash(*pk.expect("hashed key is known"))
+            }
+            Policy::Sha256(hash) => Descriptor::Hash(hash),
+            Policy::Hash160(hash) => Descriptor::Hash160(hash),
+            Policy::Ripemd160(hash) => Descriptor::Ripemd160(hash),
+            Policy::Hash256(hash) => Descriptor::Hash256(hash),
+            Policy::Csv(n) => Descriptor::Time(n),
+            Policy::Raw(ref script) => Descriptor::RawVerify(script.clone()),
+            #[cfg(feature = "elements")]
+            Policy::MessageSig(pk, msg) => Descriptor::SigFromStack(pk, msg),
+            Policy::Threshold(k, ref subs) => {
+                Descriptor::Threshold(k, subs.iter().map(|sub| lift(sub, keys)).collect())
+            }
+        }
+    }
+
+    #[test]
+    fn exhausted_source() {
+        let mut src = ByteSource::new(&[]);
+        let pk = src.keys()[0];
+        match arbitrary_descriptor(&mut src, 5) {
+            Descriptor::Key(key) => assert_eq!(key, pk),
+            desc => panic!("unexpected descriptor {}", desc),
+        }
+    }
+
+    #[test]
+    fn invariants() {
+        for seed in 0..200 {
+            let data = test_bytes(seed, 256);
+            let mut src = ByteSource::new(&data);
+            // Deeper nesting makes comparing spending conditions slow
+            let desc = arbitrary_descriptor(&mut src, 2);
+
+            // Descriptor strings round-trip
+            let reparsed: Descriptor<secp256k1::PublicKey> = desc.to_string().parse().expect("descriptor reparses");
+            assert_eq!(reparsed.to_string(), desc.to_string());
+
+            // Scripts round-trip
+            let tree = ParseTree::compile(&desc).expect("generated descriptors compile");
+            let script = tree.serialize();
+            let reparsed = ParseTree::parse(&script).expect("compiled script parses");
+            assert_eq!(reparsed.serialize(), script, "descriptor {}", desc);
+
+            // Compilation preserves the spending conditions
+            let lifted = lift(&tree.0.policy(), src.keys());
+            assert!(lifted.is_equivalent(&desc), "descriptor {} compiled to {}", desc, lifted);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Descriptor Checksums
+//!
+//! The BCH-based checksum which Bitcoin Core appends to descriptors, as an
+//! 8-character suffix separated by `#`.
+//!
+
+use Error;
+
+/// Characters which may appear in a descriptor, ordered so that the characters
+/// most likely to be confused with one another differ only in their low bits
+const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+
+/// The bech32 character set, used to encode the checksum
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Length of a checksum, in characters
+const CHECKSUM_LENGTH: usize = 8;
+
+/// Adds the 5-bit value `val` to the checksum state `c`, which represents a
+/// polynomial over GF(32)
+fn polymod(c: u64, val: u64) -> u64 {
+    let c0 = c >> 35;
+    let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+    if c0 & 1 != 0 { c ^= 0xf5dee51989; }
+    if c0 & 2 != 0 { c ^= 0xa9fdca3312; }
+    if c0 & 4 != 0 { c ^= 0x1bab10e32d; }
+    if c0 & 8 != 0 { c ^= 0x3706b1677a; }
+    if c0 & 16 != 0 { c ^= 0x644d626ffd; }
+    c
+}
+
+/// Computes the checksum of a descriptor (which should not already have one)
+pub fn compute(desc: &str) -> Result<String, Error> {
+    let mut c = 1;
+    let mut cls = 0;
+    let mut clscount = 0;
+    for ch in desc.chars() {
+        let pos = match INPUT_CHARSET.find(ch) {
+            Some(pos) => pos as u64,
+            None => return Err(Error::Unprintable(ch)),
+        };
+        // Each character contributes its low 5 bits directly, and its "class" (the
+        // remaining bits) in groups of three
+        c = polymod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        clscount += 1;
+        if clscount == 3 {
+            c = polymod(c, cls);
+            cls = 0;
+            clscount = 0;
+        }
+    }
+    if clscount > 0 {
+        c = polymod(c, cls);
+    }
+    for _ in 0..CHECKSUM_LENGTH {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+
+    let mut ret = String::with_capacity(CHECKSUM_LENGTH);
+    for j in 0..CHECKSUM_LENGTH {
+        ret.push(CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char);
+    }
+    Ok(ret)
+}
+
+/// Checks the checksum of a descriptor, if it has one, and returns the descriptor
+/// with the checksum removed. Descriptors without a checksum are returned unchanged.
+pub fn verify(s: &str) -> Result<&str, Error> {
+    let mut split = s.splitn(2, '#');
+    let desc = split.next().expect("splitn yields at least one item");
+    if let Some(checksum) = split.next() {
+        if checksum.len() != CHECKSUM_LENGTH || compute(desc)? != checksum {
+            return Err(Error::BadChecksum(checksum.to_owned()));
+        }
+    }
+    Ok(desc)
+}
+
+#[cfg(test)]
+mod tests {
+    use Error;
+    use super::{compute, verify};
+
+    #[test]
+    fn checksum() {
+        assert_eq!(compute("raw(deadbeef)").unwrap(), "89f8spxm");
+        assert_eq!(verify("raw(deadbeef)#89f8spxm"), Ok("raw(deadbeef)"));
+        assert_eq!(verify("raw(deadbeef)"), Ok("raw(deadbeef)"));
+
+        assert_eq!(verify("raw(deadbeef)#"), Err(Error::BadChecksum("".to_owned())));
+        assert_eq!(verify("raw(deadbeef)#89f8spxmx"), Err(Error::BadChecksum("89f8spxmx".to_owned())));
+        assert_eq!(verify("raw(deedbeef)#89f8spxm"), Err(Error::BadChecksum("89f8spxm".to_owned())));
+        assert_eq!(verify("raw(deadbeef)##9f8spxm"), Err(Error::BadChecksum("#9f8spxm".to_owned())));
+        assert_eq!(compute("raw(deadbeef)\u{e9}"), Err(Error::Unprintable('\u{e9}')));
+        assert_eq!(compute("raw(\u{1f4a9})"), Err(Error::Unprintable('\u{1f4a9}')));
+        assert_eq!(Error::Unprintable('\u{1f4a9}').to_string(), "unprintable character 0x1f4a9");
+    }
+}
@@ -0,0 +1,1954 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Descriptor Compiler
+//!
+//! Optimizing compiler from descriptors to parse trees. For every descriptor node and
+//! every one of the five expression types, each fragment which could implement the node
+//! is considered, with its subexpressions compiled for the probabilities with which that
+//! fragment satisfies and dissatisfies them. Since the expected size of a fragment is a
+//! sum of independent contributions from its subexpressions, keeping only the cheapest
+//! candidate per node, type and probabilities yields the script of minimal expected
+//! size (script plus witness). Results are memoized, and ties are broken by the order
+//! in which candidates are listed, so compilation is deterministic.
+//!
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use secp256k1;
+
+use bitcoin::blockdata::script;
+use bitcoin::util::hash::{Hash160, Sha256dHash};
+use bitcoin_hashes::ripemd160::Hash as Ripemd160Hash;
+
+use descriptor::{KeyAggregator, PublicKey};
+use parse::{AstElem, Translator, E, F, T, V, W};
+use {sha256, CompileError, Descriptor, ExprType, Fragment, ParseTree, Wrapper};
+
+/// Cost of a 32-byte hash preimage in a witness
+const PREIMAGE_COST: f64 = 33.0;
+/// Maximum nesting depth of a descriptor which will be compiled, to bound recursion
+const MAX_POLICY_DEPTH: usize = 100;
+/// Maximum number of keys in a `CHECKMULTISIG` (consensus)
+const MAX_MULTISIG_KEYS: usize = 20;
+
+/// Parameters of the cost model which the compiler minimizes: the expected size of
+/// a script and its witness, with each byte of either weighted by a factor
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CostParams {
+    /// Size of a signature in a witness, including its push prefix and sighash byte
+    pub sig_size: f64,
+    /// Size of a compressed public key revealed in a witness, including its push prefix
+    pub pk_size: f64,
+    /// Weight of each byte of the script
+    pub script_weight: f64,
+    /// Weight of each byte of the witness (or scriptSig) satisfying the script
+    pub witness_weight: f64,
+}
+
+impl Default for CostParams {
+    /// Raw byte counts, with ECDSA signatures of up to 72 bytes of DER
+    fn default() -> CostParams {
+        CostParams {
+            sig_size: 74.0,
+            pk_size: 34.0,
+            script_weight: 1.0,
+            witness_weight: 1.0,
+        }
+    }
+}
+
+impl CostParams {
+    /// Costs in weight units of a script embedded in outputs of type `wrapper`.
+    /// Redeem scripts, scriptSigs and bare scripts count four units per byte,
+    /// witness scripts and witnesses one.
+    pub fn for_wrapper(wrapper: Wrapper) -> CostParams {
+        let weight = match wrapper {
+            Wrapper::Bare | Wrapper::Sh => 4.0,
+            Wrapper::Wsh | Wrapper::ShWsh | Wrapper::Wpkh | Wrapper::ShWpkh => 1.0,
+        };
+        CostParams {
+            script_weight: weight,
+            witness_weight: weight,
+            ..CostParams::default()
+        }
+    }
+
+    /// The same costs with 64-byte Schnorr signatures in place of ECDSA ones
+    pub fn schnorr(self) -> CostParams {
+        CostParams {
+            sig_size: 66.0,
+            ..self
+        }
+    }
+}
+
+/// Version of the choices the compiler makes. Any change to the compiler which
+/// alters the script of some descriptor gets a new version, and compiling with an
+/// earlier version keeps reproducing that version's scripts byte-for-byte, so that a
+/// wallet which records the version it compiled a descriptor with can regenerate
+/// the same scriptPubKeys later.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CompilerVersion {
+    /// The exhaustive search, compiling descriptors exactly as written
+    V1,
+    /// Parts of the descriptor which impose no condition are folded away before
+    /// compiling, as `Descriptor::fold_constants` does
+    V2,
+}
+
+impl Default for CompilerVersion {
+    /// The latest version
+    fn default() -> CompilerVersion {
+        CompilerVersion::latest()
+    }
+}
+
+impl CompilerVersion {
+    /// The version `compile` and the other entry points without a version use
+    pub fn latest() -> CompilerVersion {
+        CompilerVersion::V2
+    }
+
+    /// The number of the version, for storing alongside a descriptor
+    pub fn to_u32(self) -> u32 {
+        match self {
+            CompilerVersion::V1 => 1,
+            CompilerVersion::V2 => 2,
+        }
+    }
+
+    /// The version with the given number, if this compiler knows it
+    pub fn from_u32(n: u32) -> Option<CompilerVersion> {
+        match n {
+            1 => Some(CompilerVersion::V1),
+            2 => Some(CompilerVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+/// A compiled fragment along with its script size and the expected witness sizes
+/// needed to satisfy and to dissatisfy it
+#[derive(Clone, Debug)]
+pub struct Cost<X> {
+    /// The fragment, shared with every larger fragment compiled from it
+    pub ast: Arc<X>,
+    pub pk_cost: usize,
+    pub sat_cost: f64,
+    pub dissat_cost: f64,
+}
+
+impl<X: AstElem> Cost<X> {
+    fn new(ast: X, sat_cost: f64, dissat_cost: f64) -> Cost<X> {
+        let pk_cost = ast.serialize(script::Builder::new()).into_script().len();
+        Cost {
+            ast: Arc::new(ast),
+            pk_cost,
+            sat_cost,
+            dissat_cost,
+        }
+    }
+}
+
+impl<X: Clone> Cost<X> {
+    /// The fragment itself, copying only its top node if it is still shared
+    fn into_ast(self) -> X {
+        Arc::try_unwrap(self.ast).unwrap_or_else(|ast| (*ast).clone())
+    }
+}
+
+impl<X> Cost<X> {
+    /// Expected (weighted) size of the fragment's script and witness, given the
+    /// probabilities that it is satisfied and dissatisfied when the script is spent
+    fn weight(&self, params: &CostParams, p_sat: f64, p_dissat: f64) -> f64 {
+        params.script_weight * self.pk_cost as f64 +
+            params.witness_weight * (p_sat * self.sat_cost + p_dissat * self.dissat_cost)
+    }
+}
+
+/// Role of a subexpression within a fragment, given as the probabilities that the
+/// subexpression is (satisfied, dissatisfied) when the fragment is satisfied, and
+/// when the fragment is dissatisfied. Subexpressions which are not executed at all
+/// need neither.
+#[derive(Copy, Clone, Debug)]
+struct Role {
+    sat: (f64, f64),
+    dissat: (f64, f64),
+}
+
+impl Role {
+    /// Satisfied and dissatisfied along with the fragment
+    const MIRROR: Role = Role { sat: (1.0, 0.0), dissat: (0.0, 1.0) };
+    /// Satisfied along with the fragment, not executed when it is dissatisfied
+    const SAT_ONLY: Role = Role { sat: (1.0, 0.0), dissat: (0.0, 0.0) };
+
+    /// First branch of a disjunction which is executed unconditionally; the branch
+    /// is taken with probability `weight` when the disjunction is satisfied
+    fn or_first(weight: f64) -> Role {
+        Role { sat: (weight, 1.0 - weight), dissat: (0.0, 1.0) }
+    }
+
+    /// Second branch of a parallel disjunction, i.e. one where both branches are
+    /// always executed
+    fn or_parallel_second(weight: f64) -> Role {
+        Role { sat: (1.0 - weight, weight), dissat: (0.0, 1.0) }
+    }
+
+    /// Second branch of a cascading disjunction, which is only executed if the first
+    /// branch was dissatisfied
+    fn or_cascade_second(weight: f64) -> Role {
+        Role { sat: (1.0 - weight, 0.0), dissat: (0.0, 1.0) }
+    }
+
+    /// Branch of a switch, which is executed only when it is taken
+    fn or_switch(weight: f64) -> Role {
+        Role { sat: (weight, 0.0), dissat: (0.0, 0.0) }
+    }
+
+    /// Subexpression of a threshold, which is among those satisfied with probability
+    /// `frac` when the threshold is satisfied
+    fn threshold(frac: f64) -> Role {
+        Role { sat: (frac, 1.0 - frac), dissat: (0.0, 1.0) }
+    }
+
+    /// Probabilities that the subexpression is satisfied and dissatisfied, given
+    /// those of the fragment
+    fn probs(&self, p_sat: f64, p_dissat: f64) -> (f64, f64) {
+        (
+            p_sat * self.sat.0 + p_dissat * self.dissat.0,
+            p_sat * self.sat.1 + p_dissat * self.dissat.1,
+        )
+    }
+
+    /// Adds the contribution of a compiled subexpression to the (satisfaction,
+    /// dissatisfaction) cost of the fragment
+    fn add_costs<X>(&self, sub: &Cost<X>, costs: &mut (f64, f64)) {
+        costs.0 += self.sat.0 * sub.sat_cost + self.sat.1 * sub.dissat_cost;
+        costs.1 += self.dissat.0 * sub.sat_cost + self.dissat.1 * sub.dissat_cost;
+    }
+}
+
+/// Compiles each subexpression of a fragment for its role and builds the cost of the
+/// fragment, which adds `$extra` (satisfaction, dissatisfaction) witness data of its own
+macro_rules! fragment(
+    ($compiler:expr, $p_sat:expr, $p_dissat:expr, $extra:expr;
+     $($sub:ident = $compile:ident($desc:expr, $role:expr)),*;
+     $ast:expr) => ({
+        let mut costs: (f64, f64) = $extra;
+        $(
+            let role: Role = $role;
+            let (p_sat, p_dissat) = role.probs($p_sat, $p_dissat);
+            let $sub = $compiler.$compile($desc, p_sat, p_dissat)?;
+            role.add_costs(&$sub, &mut costs);
+            let $sub = $sub.ast;
+        )*
+        Cost::new($ast, costs.0, costs.1)
+    })
+);
+
+/// Returns the index of the candidate with the lowest expected cost, preferring
+/// earlier candidates in case of a tie
+fn cheapest<X>(
+    desc: &Descriptor<secp256k1::PublicKey>,
+    candidates: &[Cost<X>],
+    params: &CostParams,
+    p_sat: f64,
+    p_dissat: f64,
+) -> Result<usize, CompileError> {
+    if candidates.is_empty() {
+        return Err(CompileError::Unsupported(desc.to_string()));
+    }
+    let mut best = 0;
+    for (i, candidate) in candidates.iter().enumerate().skip(1) {
+        if candidate.weight(params, p_sat, p_dissat) < candidates[best].weight(params, p_sat, p_dissat) {
+            best = i;
+        }
+    }
+    Ok(best)
+}
+
+/// A fragment which the compiler considered for a descriptor node, as reported by
+/// `ParseTree::compile_with_report`. Costs are those of the fragment along with the
+/// subexpressions chosen for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    /// The fragment at the top of the candidate
+    pub fragment: Fragment,
+    /// Size of its script
+    pub pk_cost: usize,
+    /// Expected size of a witness satisfying it
+    pub sat_cost: f64,
+    /// Expected size of a witness dissatisfying it
+    pub dissat_cost: f64,
+    /// Expected cost under the cost model, which the compiler minimizes
+    pub weight: f64,
+}
+
+/// Choice made by the compiler for one descriptor node, expression type and pair
+/// of probabilities, as reported by `ParseTree::compile_with_report`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileStep {
+    /// The descriptor node being compiled
+    pub descriptor: String,
+    /// The type of expression it was compiled to
+    pub expr_type: ExprType,
+    /// Probability that the expression is satisfied when the script is spent
+    pub p_sat: f64,
+    /// Probability that the expression is dissatisfied when the script is spent
+    pub p_dissat: f64,
+    /// Every fragment considered, in the order that ties are broken in
+    pub candidates: Vec<Candidate>,
+    /// Index of the candidate chosen
+    pub chosen: usize,
+}
+
+/// Sorts keys by their serialization, as BIP67 requires of `sortedmulti`
+fn sorted_keys(keys: &[secp256k1::PublicKey]) -> Vec<secp256k1::PublicKey> {
+    let mut ret = keys.to_vec();
+    ret.sort_by_key(|pk| pk.serialize().to_vec());
+    ret
+}
+
+/// Checks that a `k`-of-`n` threshold or multisig can be compiled at all
+fn check_threshold(k: usize, n: usize) -> Result<(), CompileError> {
+    if n == 0 {
+        Err(CompileError::EmptyThreshold)
+    } else if k > n {
+        Err(CompileError::ThresholdKTooLarge(k, n))
+    } else {
+        Ok(())
+    }
+}
+
+/// The keys of a threshold whose subexpressions are all single keys, which may
+/// then be compiled as a multisig instead, if there are few enough of them
+fn threshold_keys(subs: &[Descriptor<secp256k1::PublicKey>]) -> Option<Vec<secp256k1::PublicKey>> {
+    if subs.len() > MAX_MULTISIG_KEYS {
+        return None;
+    }
+    subs.iter().map(|sub| match *sub {
+        Descriptor::Key(ref pk) => Some(*pk),
+        _ => None,
+    }).collect()
+}
+
+/// Checks that a descriptor is nested no deeper than `MAX_POLICY_DEPTH`, without
+/// recursing any further than that, and that no weighted `or` has zero total odds
+fn check_depth(desc: &Descriptor<secp256k1::PublicKey>, depth: usize) -> Result<(), CompileError> {
+    if depth > MAX_POLICY_DEPTH {
+        return Err(CompileError::PolicyTooDeep(MAX_POLICY_DEPTH));
+    }
+    match *desc {
+        Descriptor::Threshold(_, ref subs) => {
+            for sub in subs {
+                check_depth(sub, depth + 1)?;
+            }
+            Ok(())
+        }
+        Descriptor::WeightedOr(0, _, 0, _) => Err(CompileError::ZeroOdds(desc.to_string())),
+        Descriptor::And(ref left, ref right) |
+        Descriptor::Or(ref left, ref right) |
+        Descriptor::AsymmetricOr(ref left, ref right) |
+        Descriptor::WeightedOr(_, ref left, _, ref right) => {
+            check_depth(left, depth + 1)?;
+            check_depth(right, depth + 1)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Probability that the left branch of a disjunction is the one taken, given that
+/// the disjunction is satisfied
+fn left_weight(desc: &Descriptor<secp256k1::PublicKey>) -> f64 {
+    match *desc {
+        Descriptor::AsymmetricOr(..) => 1.0,
+        Descriptor::WeightedOr(left_odds, _, right_odds, _) => {
+            left_odds as f64 / (left_odds + right_odds) as f64
+        }
+        _ => 0.5,
+    }
+}
+
+/// Numbers of past spends of a descriptor in which each of its branches was satisfied,
+/// such as from a wallet's history, for the compiler to weigh branches by in place of
+/// even splits. Branches are identified by their string form, so any subdescriptor
+/// may be recorded; those not recorded count as never used.
+///
+/// With statistics, the left branch of an `or` is taken with probability the count of
+/// the left branch over the sum of both counts, and each subexpression of a `k`-of-`n`
+/// threshold is among those satisfied with probability `k` times its count over the
+/// sum of all `n` counts, up to 1. Nodes whose branches were never used are split
+/// evenly as usual, and the explicit odds of `aor` and weighted `or` are kept.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpendStats {
+    counts: HashMap<String, u64>,
+}
+
+impl SpendStats {
+    /// Creates statistics without any spends
+    pub fn new() -> SpendStats {
+        SpendStats::default()
+    }
+
+    /// Records `count` more spends in which `branch` was satisfied
+    pub fn record<P: PublicKey>(&mut self, branch: &Descriptor<P>, count: u64) {
+        *self.counts.entry(branch.to_string()).or_insert(0) += count;
+    }
+
+    /// Number of recorded spends in which `branch` was satisfied
+    pub fn count<P: PublicKey>(&self, branch: &Descriptor<P>) -> u64 {
+        self.counts.get(&branch.to_string()).cloned().unwrap_or(0)
+    }
+}
+
+/// Witness cost of the selector of a switch whose first branch is taken with
+/// probability `weight`; taking the first branch requires a 1 rather than an empty push
+fn switch_cost(weight: f64) -> f64 {
+    2.0 * weight + 1.0 - weight
+}
+
+/// Granularity to which probabilities are rounded before compiling a node
+const PROB_SCALE: f64 = 256.0;
+
+/// Memoization key: the address of the descriptor node and the probabilities, in
+/// units of `1 / PROB_SCALE`
+type Key = (usize, u64, u64);
+
+/// Rounds the probabilities a node is compiled for and gives its memoization key.
+/// The probabilities reaching a node of nested disjunctions are sums over the paths
+/// to it, which take exponentially many distinct values with depth; on a fixed grid
+/// they take a bounded number, so the tables are hit and compile time stays
+/// polynomial. Each probability moves by at most `1 / (2 * PROB_SCALE)`.
+fn key(desc: &Descriptor<secp256k1::PublicKey>, p_sat: &mut f64, p_dissat: &mut f64) -> Key {
+    let (sat, dissat) = ((*p_sat * PROB_SCALE).round(), (*p_dissat * PROB_SCALE).round());
+    *p_sat = sat / PROB_SCALE;
+    *p_dissat = dissat / PROB_SCALE;
+    (desc as *const _ as usize, sat as u64, dissat as u64)
+}
+
+/// Memoizing compiler, whose tables are only valid for a single (borrowed) descriptor
+#[derive(Default)]
+struct Compiler<'a> {
+    aggregator: Option<&'a dyn KeyAggregator>,
+    params: CostParams,
+    /// Observed usage of branches, if any, to weigh them by
+    stats: Option<&'a SpendStats>,
+    /// Whether single keys may also be checked against their hash, as `pkh` keys are
+    hash_keys: bool,
+    e: HashMap<Key, Cost<E>>,
+    w: HashMap<Key, Cost<W>>,
+    f: HashMap<Key, Cost<F>>,
+    v: HashMap<Key, Cost<V>>,
+    t: HashMap<Key, Cost<T>>,
+    /// Key lists of multisig-like descriptors, shared by every fragment compiled
+    /// from them rather than copied into each candidate
+    keys: HashMap<usize, Arc<[secp256k1::PublicKey]>>,
+    /// Every choice made so far, if they are to be reported
+    steps: Option<Vec<CompileStep>>,
+}
+
+impl<'a> Compiler<'a> {
+    /// Chooses the cheapest of `candidates` for `desc`, recording the choice if
+    /// choices are being reported
+    fn choose<X: AstElem>(
+        &mut self,
+        desc: &Descriptor<secp256k1::PublicKey>,
+        mut candidates: Vec<Cost<X>>,
+        p_sat: f64,
+        p_dissat: f64,
+    ) -> Result<Cost<X>, CompileError> {
+        let best = cheapest(desc, &candidates, &self.params, p_sat, p_dissat)?;
+        if let Some(ref mut steps) = self.steps {
+            let params = &self.params;
+            steps.push(CompileStep {
+                descriptor: desc.to_string(),
+                expr_type: candidates[best].ast.expr_type(),
+                p_sat,
+                p_dissat,
+                candidates: candidates.iter().map(|candidate| Candidate {
+                    fragment: candidate.ast.fragment().0,
+                    pk_cost: candidate.pk_cost,
+                    sat_cost: candidate.sat_cost,
+                    dissat_cost: candidate.dissat_cost,
+                    weight: candidate.weight(params, p_sat, p_dissat),
+                }).collect(),
+                chosen: best,
+            });
+        }
+        Ok(candidates.swap_remove(best))
+    }
+
+    /// The key list to check for `desc`, built by `make` the first time it is needed
+    fn shared_keys<G>(&mut self, desc: &Descriptor<secp256k1::PublicKey>, make: G) -> Arc<[secp256k1::PublicKey]>
+        where G: FnOnce() -> Vec<secp256k1::PublicKey>
+    {
+        self.keys.entry(desc as *const _ as usize).or_insert_with(|| make().into()).clone()
+    }
+
+    /// Aggregates the keys of the key aggregate `desc`
+    fn aggregate(&self, desc: &Descriptor<secp256k1::PublicKey>, keys: &[secp256k1::PublicKey]) -> Result<secp256k1::PublicKey, CompileError> {
+        check_threshold(keys.len(), keys.len())?;
+        match self.aggregator {
+            Some(aggregator) => aggregator.aggregate(keys).map_err(CompileError::BadAggregate),
+            None => Err(CompileError::NoAggregator(desc.to_string())),
+        }
+    }
+
+    /// Probability that the left branch of a disjunction is the one taken, given that
+    /// it is satisfied, from the spend statistics for a plain `or` which has them
+    fn left_weight(&self, desc: &Descriptor<secp256k1::PublicKey>) -> f64 {
+        if let (Some(stats), Descriptor::Or(left, right)) = (self.stats, desc) {
+            let (left, right) = (stats.count(left), stats.count(right));
+            if left + right > 0 {
+                return left as f64 / (left + right) as f64;
+            }
+        }
+        left_weight(desc)
+    }
+
+    /// Role of each subexpression of a `k`-of-`n` threshold. Without spend statistics,
+    /// every set of `k` subexpressions is assumed equally likely to be the one satisfied.
+    fn threshold_roles(&self, k: usize, subs: &[Descriptor<secp256k1::PublicKey>]) -> Vec<Role> {
+        let counts: Vec<u64> = match self.stats {
+            Some(stats) => subs.iter().map(|sub| stats.count(sub)).collect(),
+            None => vec![],
+        };
+        let total: u64 = counts.iter().sum();
+        (0..subs.len()).map(|i| {
+            if total > 0 {
+                Role::threshold((k as f64 * counts[i] as f64 / total as f64).min(1.0))
+            } else {
+                Role::threshold(k as f64 / subs.len() as f64)
+            }
+        }).collect()
+    }
+
+    /// Compiles the subexpressions of a threshold, using `subs[first]` as the
+    /// leading `E` expression and the others as `W` expressions, and adds their
+    /// contributions to `costs`
+    fn threshold_subs(
+        &mut self,
+        k: usize,
+        subs: &[Descriptor<secp256k1::PublicKey>],
+        first: usize,
+        p_sat: f64,
+        p_dissat: f64,
+        costs: &mut (f64, f64),
+    ) -> Result<(Arc<E>, Vec<W>), CompileError> {
+        let roles = self.threshold_roles(k, subs);
+        let (sub_sat, sub_dissat) = roles[first].probs(p_sat, p_dissat);
+
+        let e = self.compile_e(&subs[first], sub_sat, sub_dissat)?;
+        roles[first].add_costs(&e, costs);
+        let mut ws = Vec::with_capacity(subs.len() - 1);
+        for (i, sub) in subs.iter().enumerate() {
+            if i != first {
+                let (sub_sat, sub_dissat) = roles[i].probs(p_sat, p_dissat);
+                let w = self.compile_w(sub, sub_sat, sub_dissat)?;
+                roles[i].add_costs(&w, costs);
+                ws.push((*w.ast).clone());
+            }
+        }
+        Ok((e.ast, ws))
+    }
+
+    fn compile_e(&mut self, desc: &Descriptor<secp256k1::PublicKey>, mut p_sat: f64, mut p_dissat: f64) -> Result<Cost<E>, CompileError> {
+        let key = key(desc, &mut p_sat, &mut p_dissat);
+        if let Some(cost) = self.e.get(&key) {
+            return Ok(cost.clone());
+        }
+
+        let mut candidates = vec![];
+        match *desc {
+            Descriptor::Key(ref pk) => {
+                candidates.push(Cost::new(E::CheckSig(*pk), self.params.sig_size, 1.0));
+                if self.hash_keys {
+                    let hash = Hash160::from_data(&pk.serialize()[..]);
+                    candidates.push(Cost::new(E::CheckSigHash(hash), self.params.sig_size + self.params.pk_size, 1.0 + self.params.pk_size));
+                    candidates.push(Cost::new(E::CheckSigHashF(hash), self.params.sig_size + self.params.pk_size, 1.0));
+                }
+            }
+            #[cfg(feature = "elements")]
+            Descriptor::SigFromStack(ref pk, msg) => {
+                candidates.push(Cost::new(E::CheckSigFromStack(*pk, msg), self.params.sig_size, 1.0));
+            }
+            Descriptor::KeyHash(ref pk) => {
+                let hash = Hash160::from_data(&pk.serialize()[..]);
+                candidates.push(Cost::new(E::CheckSigHash(hash), self.params.sig_size + self.params.pk_size, 1.0 + self.params.pk_size));
+                candidates.push(Cost::new(E::CheckSigHashF(hash), self.params.sig_size + self.params.pk_size, 1.0));
+            }
+            Descriptor::Multi(k, ref keys) => {
+                check_threshold(k, keys.len())?;
+                let sat_cost = 1.0 + self.params.sig_size * k as f64;
+                let keys = self.shared_keys(desc, || keys.clone());
+                candidates.push(Cost::new(E::CheckMultiSig(k, keys.clone()), sat_cost, 1.0 + k as f64));
+                candidates.push(Cost::new(E::CheckMultiSigF(k, keys), sat_cost, 1.0));
+            }
+            Descriptor::SortedMulti(k, ref keys) => {
+                check_threshold(k, keys.len())?;
+                let sat_cost = 1.0 + self.params.sig_size * k as f64;
+                let keys = self.shared_keys(desc, || sorted_keys(keys));
+                candidates.push(Cost::new(E::CheckMultiSig(k, keys.clone()), sat_cost, 1.0 + k as f64));
+                candidates.push(Cost::new(E::CheckMultiSigF(k, keys), sat_cost, 1.0));
+            }
+            Descriptor::MultiA(k, ref keys) => {
+                check_threshold(k, keys.len())?;
+                // every key which does not sign takes an empty push
+                let sat_cost = self.params.sig_size * k as f64 + (keys.len() - k) as f64;
+                let n = keys.len() as f64;
+                let keys = self.shared_keys(desc, || keys.clone());
+                candidates.push(Cost::new(E::CheckSigAdd(k, keys), sat_cost, n));
+            }
+            Descriptor::Aggregate(ref keys) => {
+                let pk = self.aggregate(desc, keys)?;
+                let keys = self.shared_keys(desc, || keys.clone());
+                candidates.push(Cost::new(E::CheckSigAggregate(pk, keys), self.params.sig_size, 1.0));
+            }
+            Descriptor::Hash(hash) => {
+                candidates.push(Cost::new(E::HashEqual(hash), PREIMAGE_COST, 1.0));
+            }
+            Descriptor::Hash160(hash) => {
+                candidates.push(Cost::new(E::Hash160Equal(hash), PREIMAGE_COST, 1.0));
+            }
+            Descriptor::Ripemd160(hash) => {
+                candidates.push(Cost::new(E::Ripemd160Equal(hash), PREIMAGE_COST, 1.0));
+            }
+            Descriptor::Hash256(hash) => {
+                candidates.push(Cost::new(E::Hash256Equal(hash), PREIMAGE_COST, 1.0));
+            }
+            Descriptor::Time(_) | Descriptor::RawVerify(_) => {}
+            Descriptor::Threshold(k, ref subs) => {
+                check_threshold(k, subs.len())?;
+                for first in 0..subs.len() {
+                    let mut costs = (0.0, 0.0);
+                    let (e, ws) = self.threshold_subs(k, subs, first, p_sat, p_dissat, &mut costs)?;
+                    candidates.push(Cost::new(E::Threshold(k, e, ws), costs.0, costs.1));
+                }
+                // k <pk...> n CHECKMULTISIG
+                if let Some(keys) = threshold_keys(subs) {
+                    let keys = self.shared_keys(desc, || keys);
+                    let sat_cost = 1.0 + self.params.sig_size * k as f64;
+                    candidates.push(Cost::new(E::CheckMultiSig(k, keys.clone()), sat_cost, 1.0 + k as f64));
+                    candidates.push(Cost::new(E::CheckMultiSigF(k, keys), sat_cost, 1.0));
+                }
+            }
+            Descriptor::And(ref left, ref right) => {
+                for &(a, b) in &[(&**left, &**right), (&**right, &**left)] {
+                    // a b BOOLAND
+                    candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+                        l = compile_e(a, Role::MIRROR), r = compile_w(b, Role::MIRROR);
+                        E::ParallelAnd(l, r)
+                    ));
+                    // a IF b ELSE 0 ENDIF
+                    candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+                        l = compile_e(a, Role::MIRROR), r = compile_f(b, Role::SAT_ONLY);
+                        E::CascadeAnd(l, r)
+                    ));
+                }
+            }
+            Descriptor::Or(ref left, ref right) |
+            Descriptor::AsymmetricOr(ref left, ref right) |
+            Descriptor::WeightedOr(_, ref left, _, ref right) => {
+                let weight = self.left_weight(desc);
+                for &(a, b, w) in &[(&**left, &**right, weight), (&**right, &**left, 1.0 - weight)] {
+                    // a b BOOLOR
+                    candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+                        l = compile_e(a, Role::or_first(w)), r = compile_w(b, Role::or_parallel_second(w));
+                        E::ParallelOr(l, r)
+                    ));
+                    // a IFDUP NOTIF b ENDIF
+                    candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+                        l = compile_e(a, Role::or_first(w)), r = compile_e(b, Role::or_cascade_second(w));
+                        E::CascadeOr(l, r)
+                    ));
+                }
+            }
+            Descriptor::Wpkh(_) | Descriptor::Sh(_) | Descriptor::Wsh(_) |
+            Descriptor::Combo(_) | Descriptor::Addr(_) | Descriptor::Raw(_) | Descriptor::OpReturn(_) => {
+                return Err(CompileError::Unsupported(desc.to_string()));
+            }
+        }
+        // SIZE EQUALVERIFY IF f ELSE 0 ENDIF, selected by a 1 or an empty push
+        candidates.push(fragment!(self, p_sat, p_dissat, (2.0, 1.0);
+            f = compile_f(desc, Role::SAT_ONLY);
+            E::CastF(f)
+        ));
+
+        let best = self.choose(desc, candidates, p_sat, p_dissat)?;
+        self.e.insert(key, best.clone());
+        Ok(best)
+    }
+
+    fn compile_w(&mut self, desc: &Descriptor<secp256k1::PublicKey>, mut p_sat: f64, mut p_dissat: f64) -> Result<Cost<W>, CompileError> {
+        let key = key(desc, &mut p_sat, &mut p_dissat);
+        if let Some(cost) = self.w.get(&key) {
+            return Ok(cost.clone());
+        }
+
+        let mut candidates = vec![];
+        match *desc {
+            Descriptor::Key(ref pk) => {
+                candidates.push(Cost::new(W::CheckSig(*pk), self.params.sig_size, 1.0));
+            }
+            Descriptor::Aggregate(ref keys) => {
+                let pk = self.aggregate(desc, keys)?;
+                let keys = self.shared_keys(desc, || keys.clone());
+                candidates.push(Cost::new(W::CheckSigAggregate(pk, keys), self.params.sig_size, 1.0));
+            }
+            Descriptor::Hash(hash) => {
+                candidates.push(Cost::new(W::HashEqual(hash), PREIMAGE_COST, 1.0));
+            }
+            Descriptor::Hash160(hash) => {
+                candidates.push(Cost::new(W::Hash160Equal(hash), PREIMAGE_COST, 1.0));
+            }
+            Descriptor::Ripemd160(hash) => {
+                candidates.push(Cost::new(W::Ripemd160Equal(hash), PREIMAGE_COST, 1.0));
+            }
+            Descriptor::Hash256(hash) => {
+                candidates.push(Cost::new(W::Hash256Equal(hash), PREIMAGE_COST, 1.0));
+            }
+            Descriptor::Time(n) => {
+                candidates.push(Cost::new(W::Csv(n), 2.0, 1.0));
+            }
+            _ => {}
+        }
+        // TOALTSTACK e FROMALTSTACK
+        candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+            e = compile_e(desc, Role::MIRROR);
+            W::CastE(e)
+        ));
+
+        let best = self.choose(desc, candidates, p_sat, p_dissat)?;
+        self.w.insert(key, best.clone());
+        Ok(best)
+    }
+
+    fn compile_f(&mut self, desc: &Descriptor<secp256k1::PublicKey>, mut p_sat: f64, mut p_dissat: f64) -> Result<Cost<F>, CompileError> {
+        let key = key(desc, &mut p_sat, &mut p_dissat);
+        if let Some(cost) = self.f.get(&key) {
+            return Ok(cost.clone());
+        }
+
+        let mut candidates = vec![];
+        match *desc {
+            Descriptor::Key(ref pk) => {
+                candidates.push(Cost::new(F::CheckSig(*pk), self.params.sig_size, 0.0));
+            }
+            #[cfg(feature = "elements")]
+            Descriptor::SigFromStack(ref pk, msg) => {
+                candidates.push(Cost::new(F::CheckSigFromStack(*pk, msg), self.params.sig_size, 0.0));
+            }
+            Descriptor::KeyHash(ref pk) => {
+                let hash = Hash160::from_data(&pk.serialize()[..]);
+                candidates.push(Cost::new(F::CheckSigHash(hash), self.params.sig_size + self.params.pk_size, 0.0));
+            }
+            Descriptor::Multi(k, ref keys) => {
+                check_threshold(k, keys.len())?;
+                let keys = self.shared_keys(desc, || keys.clone());
+                candidates.push(Cost::new(F::CheckMultiSig(k, keys), 1.0 + self.params.sig_size * k as f64, 0.0));
+            }
+            Descriptor::SortedMulti(k, ref keys) => {
+                check_threshold(k, keys.len())?;
+                let keys = self.shared_keys(desc, || sorted_keys(keys));
+                candidates.push(Cost::new(F::CheckMultiSig(k, keys), 1.0 + self.params.sig_size * k as f64, 0.0));
+            }
+            Descriptor::MultiA(k, ref keys) => {
+                check_threshold(k, keys.len())?;
+                let sat_cost = self.params.sig_size * k as f64 + (keys.len() - k) as f64;
+                let keys = self.shared_keys(desc, || keys.clone());
+                candidates.push(Cost::new(F::CheckSigAdd(k, keys), sat_cost, 0.0));
+            }
+            Descriptor::Aggregate(ref keys) => {
+                let pk = self.aggregate(desc, keys)?;
+                let keys = self.shared_keys(desc, || keys.clone());
+                candidates.push(Cost::new(F::CheckSigAggregate(pk, keys), self.params.sig_size, 0.0));
+            }
+            Descriptor::Time(n) => {
+                candidates.push(Cost::new(F::Csv(n), 0.0, 0.0));
+            }
+            Descriptor::RawVerify(ref script) => {
+                // the size of the satisfaction is unknown
+                candidates.push(Cost::new(F::Raw(script.clone()), 0.0, 0.0));
+            }
+            Descriptor::Hash(hash) => {
+                candidates.push(Cost::new(F::HashEqual(hash), PREIMAGE_COST, 0.0));
+            }
+            Descriptor::Hash160(hash) => {
+                candidates.push(Cost::new(F::Hash160Equal(hash), PREIMAGE_COST, 0.0));
+            }
+            Descriptor::Ripemd160(hash) => {
+                candidates.push(Cost::new(F::Ripemd160Equal(hash), PREIMAGE_COST, 0.0));
+            }
+            Descriptor::Hash256(hash) => {
+                candidates.push(Cost::new(F::Hash256Equal(hash), PREIMAGE_COST, 0.0));
+            }
+            Descriptor::Threshold(k, ref subs) => {
+                check_threshold(k, subs.len())?;
+                for first in 0..subs.len() {
+                    let mut costs = (0.0, 0.0);
+                    let (e, ws) = self.threshold_subs(k, subs, first, p_sat, p_dissat, &mut costs)?;
+                    candidates.push(Cost::new(F::Threshold(k, e, ws), costs.0, 0.0));
+                }
+                if let Some(keys) = threshold_keys(subs) {
+                    let keys = self.shared_keys(desc, || keys);
+                    candidates.push(Cost::new(F::CheckMultiSig(k, keys), 1.0 + self.params.sig_size * k as f64, 0.0));
+                }
+            }
+            Descriptor::And(ref left, ref right) => {
+                for &(a, b) in &[(&**left, &**right), (&**right, &**left)] {
+                    // a b
+                    candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+                        l = compile_v(a, Role::SAT_ONLY), r = compile_f(b, Role::SAT_ONLY);
+                        F::And(l, r)
+                    ));
+                }
+            }
+            Descriptor::Or(ref left, ref right) |
+            Descriptor::AsymmetricOr(ref left, ref right) |
+            Descriptor::WeightedOr(_, ref left, _, ref right) => {
+                let weight = self.left_weight(desc);
+                for &(a, b, w) in &[(&**left, &**right, weight), (&**right, &**left, 1.0 - weight)] {
+                    // a b BOOLOR VERIFY 1
+                    candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+                        l = compile_e(a, Role::or_first(w)), r = compile_w(b, Role::or_parallel_second(w));
+                        F::ParallelOr(l, r)
+                    ));
+                    // a IFDUP NOTIF b ENDIF
+                    candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+                        l = compile_e(a, Role::or_first(w)), r = compile_f(b, Role::or_cascade_second(w));
+                        F::CascadeOr(l, r)
+                    ));
+                    // a NOTIF b ENDIF 1
+                    candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+                        l = compile_e(a, Role::or_first(w)), r = compile_v(b, Role::or_cascade_second(w));
+                        F::CascadeOrV(l, r)
+                    ));
+                    // SIZE EQUALVERIFY IF a ELSE b ENDIF
+                    candidates.push(fragment!(self, p_sat, p_dissat, (switch_cost(w), 0.0);
+                        l = compile_f(a, Role::or_switch(w)), r = compile_f(b, Role::or_switch(1.0 - w));
+                        F::SwitchOr(l, r)
+                    ));
+                    // SIZE EQUALVERIFY IF a ELSE b ENDIF 1
+                    candidates.push(fragment!(self, p_sat, p_dissat, (switch_cost(w), 0.0);
+                        l = compile_v(a, Role::or_switch(w)), r = compile_v(b, Role::or_switch(1.0 - w));
+                        F::SwitchOrV(l, r)
+                    ));
+                }
+            }
+            Descriptor::Wpkh(_) | Descriptor::Sh(_) | Descriptor::Wsh(_) |
+            Descriptor::Combo(_) | Descriptor::Addr(_) | Descriptor::Raw(_) | Descriptor::OpReturn(_) => {
+                return Err(CompileError::Unsupported(desc.to_string()));
+            }
+        }
+
+        let best = self.choose(desc, candidates, p_sat, p_dissat)?;
+        self.f.insert(key, best.clone());
+        Ok(best)
+    }
+
+    fn compile_v(&mut self, desc: &Descriptor<secp256k1::PublicKey>, mut p_sat: f64, mut p_dissat: f64) -> Result<Cost<V>, CompileError> {
+        let key = key(desc, &mut p_sat, &mut p_dissat);
+        if let Some(cost) = self.v.get(&key) {
+            return Ok(cost.clone());
+        }
+
+        let mut candidates = vec![];
+        match *desc {
+            Descriptor::Key(ref pk) => {
+                candidates.push(Cost::new(V::CheckSig(*pk), self.params.sig_size, 0.0));
+            }
+            #[cfg(feature = "elements")]
+            Descriptor::SigFromStack(ref pk, msg) => {
+                candidates.push(Cost::new(V::CheckSigFromStack(*pk, msg), self.params.sig_size, 0.0));
+            }
+            Descriptor::KeyHash(ref pk) => {
+                let hash = Hash160::from_data(&pk.serialize()[..]);
+                candidates.push(Cost::new(V::CheckSigHash(hash), self.params.sig_size + self.params.pk_size, 0.0));
+            }
+            Descriptor::Multi(k, ref keys) => {
+                check_threshold(k, keys.len())?;
+                let keys = self.shared_keys(desc, || keys.clone());
+                candidates.push(Cost::new(V::CheckMultiSig(k, keys), 1.0 + self.params.sig_size * k as f64, 0.0));
+            }
+            Descriptor::SortedMulti(k, ref keys) => {
+                check_threshold(k, keys.len())?;
+                let keys = self.shared_keys(desc, || sorted_keys(keys));
+                candidates.push(Cost::new(V::CheckMultiSig(k, keys), 1.0 + self.params.sig_size * k as f64, 0.0));
+            }
+            Descriptor::MultiA(k, ref keys) => {
+                check_threshold(k, keys.len())?;
+                let sat_cost = self.params.sig_size * k as f64 + (keys.len() - k) as f64;
+                let keys = self.shared_keys(desc, || keys.clone());
+                candidates.push(Cost::new(V::CheckSigAdd(k, keys), sat_cost, 0.0));
+            }
+            Descriptor::Aggregate(ref keys) => {
+                let pk = self.aggregate(desc, keys)?;
+                let keys = self.shared_keys(desc, || keys.clone());
+                candidates.push(Cost::new(V::CheckSigAggregate(pk, keys), self.params.sig_size, 0.0));
+            }
+            Descriptor::Time(n) => {
+                candidates.push(Cost::new(V::Csv(n), 0.0, 0.0));
+            }
+            Descriptor::RawVerify(ref script) => {
+                // the size of the satisfaction is unknown
+                candidates.push(Cost::new(V::Raw(script.clone()), 0.0, 0.0));
+            }
+            Descriptor::Hash(hash) => {
+                candidates.push(Cost::new(V::HashEqual(hash), PREIMAGE_COST, 0.0));
+            }
+            Descriptor::Hash160(hash) => {
+                candidates.push(Cost::new(V::Hash160Equal(hash), PREIMAGE_COST, 0.0));
+            }
+            Descriptor::Ripemd160(hash) => {
+                candidates.push(Cost::new(V::Ripemd160Equal(hash), PREIMAGE_COST, 0.0));
+            }
+            Descriptor::Hash256(hash) => {
+                candidates.push(Cost::new(V::Hash256Equal(hash), PREIMAGE_COST, 0.0));
+            }
+            Descriptor::Threshold(k, ref subs) => {
+                check_threshold(k, subs.len())?;
+                for first in 0..subs.len() {
+                    let mut costs = (0.0, 0.0);
+                    let (e, ws) = self.threshold_subs(k, subs, first, p_sat, p_dissat, &mut costs)?;
+                    candidates.push(Cost::new(V::Threshold(k, e, ws), costs.0, 0.0));
+                }
+                if let Some(keys) = threshold_keys(subs) {
+                    let keys = self.shared_keys(desc, || keys);
+                    candidates.push(Cost::new(V::CheckMultiSig(k, keys), 1.0 + self.params.sig_size * k as f64, 0.0));
+                }
+            }
+            Descriptor::And(ref left, ref right) => {
+                // a b
+                candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+                    l = compile_v(left, Role::SAT_ONLY), r = compile_v(right, Role::SAT_ONLY);
+                    V::And(l, r)
+                ));
+            }
+            Descriptor::Or(ref left, ref right) |
+            Descriptor::AsymmetricOr(ref left, ref right) |
+            Descriptor::WeightedOr(_, ref left, _, ref right) => {
+                let weight = self.left_weight(desc);
+                for &(a, b, w) in &[(&**left, &**right, weight), (&**right, &**left, 1.0 - weight)] {
+                    // a b BOOLOR VERIFY
+                    candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+                        l = compile_e(a, Role::or_first(w)), r = compile_w(b, Role::or_parallel_second(w));
+                        V::ParallelOr(l, r)
+                    ));
+                    // a NOTIF b ENDIF
+                    candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+                        l = compile_e(a, Role::or_first(w)), r = compile_v(b, Role::or_cascade_second(w));
+                        V::CascadeOr(l, r)
+                    ));
+                    // SIZE EQUALVERIFY IF a ELSE b ENDIF
+                    candidates.push(fragment!(self, p_sat, p_dissat, (switch_cost(w), 0.0);
+                        l = compile_v(a, Role::or_switch(w)), r = compile_v(b, Role::or_switch(1.0 - w));
+                        V::SwitchOr(l, r)
+                    ));
+                    // SIZE EQUALVERIFY IF a ELSE b ENDIF VERIFY
+                    candidates.push(fragment!(self, p_sat, p_dissat, (switch_cost(w), 0.0);
+                        l = compile_t(a, Role::or_switch(w)), r = compile_t(b, Role::or_switch(1.0 - w));
+                        V::SwitchOrT(l, r)
+                    ));
+                }
+            }
+            Descriptor::Wpkh(_) | Descriptor::Sh(_) | Descriptor::Wsh(_) |
+            Descriptor::Combo(_) | Descriptor::Addr(_) | Descriptor::Raw(_) | Descriptor::OpReturn(_) => {
+                return Err(CompileError::Unsupported(desc.to_string()));
+            }
+        }
+
+        let best = self.choose(desc, candidates, p_sat, p_dissat)?;
+        self.v.insert(key, best.clone());
+        Ok(best)
+    }
+
+    fn compile_t(&mut self, desc: &Descriptor<secp256k1::PublicKey>, mut p_sat: f64, mut p_dissat: f64) -> Result<Cost<T>, CompileError> {
+        let key = key(desc, &mut p_sat, &mut p_dissat);
+        if let Some(cost) = self.t.get(&key) {
+            return Ok(cost.clone());
+        }
+
+        let mut candidates = vec![];
+        match *desc {
+            Descriptor::Hash(hash) => {
+                candidates.push(Cost::new(T::HashEqual(hash), PREIMAGE_COST, 0.0));
+            }
+            Descriptor::Hash160(hash) => {
+                candidates.push(Cost::new(T::Hash160Equal(hash), PREIMAGE_COST, 0.0));
+            }
+            Descriptor::Ripemd160(hash) => {
+                candidates.push(Cost::new(T::Ripemd160Equal(hash), PREIMAGE_COST, 0.0));
+            }
+            Descriptor::Hash256(hash) => {
+                candidates.push(Cost::new(T::Hash256Equal(hash), PREIMAGE_COST, 0.0));
+            }
+            Descriptor::And(ref left, ref right) => {
+                for &(a, b) in &[(&**left, &**right), (&**right, &**left)] {
+                    // a b
+                    candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+                        l = compile_v(a, Role::SAT_ONLY), r = compile_t(b, Role::SAT_ONLY);
+                        T::And(l, r)
+                    ));
+                }
+            }
+            Descriptor::Or(ref left, ref right) |
+            Descriptor::AsymmetricOr(ref left, ref right) |
+            Descriptor::WeightedOr(_, ref left, _, ref right) => {
+                let weight = self.left_weight(desc);
+                for &(a, b, w) in &[(&**left, &**right, weight), (&**right, &**left, 1.0 - weight)] {
+                    // a IFDUP NOTIF b ENDIF
+                    candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+                        l = compile_e(a, Role::or_first(w)), r = compile_t(b, Role::or_cascade_second(w));
+                        T::CascadeOr(l, r)
+                    ));
+                    // SIZE EQUALVERIFY IF a ELSE b ENDIF
+                    candidates.push(fragment!(self, p_sat, p_dissat, (switch_cost(w), 0.0);
+                        l = compile_t(a, Role::or_switch(w)), r = compile_t(b, Role::or_switch(1.0 - w));
+                        T::SwitchOr(l, r)
+                    ));
+                }
+            }
+            _ => {}
+        }
+        candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+            e = compile_e(desc, Role::SAT_ONLY);
+            T::CastE(e)
+        ));
+        candidates.push(fragment!(self, p_sat, p_dissat, (0.0, 0.0);
+            f = compile_f(desc, Role::SAT_ONLY);
+            T::CastF(f)
+        ));
+
+        let best = self.choose(desc, candidates, p_sat, p_dissat)?;
+        self.t.insert(key, best.clone());
+        Ok(best)
+    }
+}
+
+/// Compiles a descriptor, which must not contain any wrappers, to the top-level
+/// expression of minimal expected cost. The descriptor is first folded with
+/// `Descriptor::fold_constants`, as it is by all the other entry points except
+/// `compile_unfolded`.
+pub fn compile(desc: &Descriptor<secp256k1::PublicKey>) -> Result<T, CompileError> {
+    compile_inner(desc, None, CostParams::default(), None)
+}
+
+/// Compiles a descriptor as for `compile`, but exactly as written, without folding
+/// away the parts which impose no condition
+pub fn compile_unfolded(desc: &Descriptor<secp256k1::PublicKey>) -> Result<T, CompileError> {
+    check_depth(desc, 0)?;
+    Ok(Compiler::default().compile_t(desc, 1.0, 0.0)?.into_ast())
+}
+
+/// Compiles a descriptor as the given version of the compiler did, which gives the
+/// same script as `compile` for the latest version
+pub fn compile_with_version(desc: &Descriptor<secp256k1::PublicKey>, version: CompilerVersion) -> Result<T, CompileError> {
+    match version {
+        CompilerVersion::V1 => compile_unfolded(desc),
+        CompilerVersion::V2 => compile(desc),
+    }
+}
+
+/// Compiles a descriptor as for `compile`, aggregating the keys of key aggregates
+/// with `aggregator`
+pub fn compile_with_aggregator(desc: &Descriptor<secp256k1::PublicKey>, aggregator: &dyn KeyAggregator) -> Result<T, CompileError> {
+    compile_inner(desc, Some(aggregator), CostParams::default(), None)
+}
+
+/// Compiles a descriptor as for `compile`, minimizing the expected cost under `params`
+pub fn compile_with_params(desc: &Descriptor<secp256k1::PublicKey>, params: CostParams) -> Result<T, CompileError> {
+    compile_inner(desc, None, params, None)
+}
+
+/// Compiles a descriptor as for `compile`, weighing its branches by how often they
+/// were used according to `stats`
+pub fn compile_with_stats(desc: &Descriptor<secp256k1::PublicKey>, stats: &SpendStats) -> Result<T, CompileError> {
+    compile_inner(desc, None, CostParams::default(), Some(stats))
+}
+
+/// Compiles a descriptor as for `compile`, also returning every choice made by the
+/// compiler, in the order they were completed; the last is that of the root
+pub fn compile_with_report(desc: &Descriptor<secp256k1::PublicKey>) -> Result<(T, Vec<CompileStep>), CompileError> {
+    check_depth(desc, 0)?;
+    let desc = &folded(desc);
+    let mut compiler = Compiler {
+        steps: Some(vec![]),
+        ..Compiler::default()
+    };
+    let ast = compiler.compile_t(desc, 1.0, 0.0)?.into_ast();
+    Ok((ast, compiler.steps.unwrap_or_default()))
+}
+
+/// Compiles a descriptor as for `compile`, but to a script of at most `max_size`
+/// bytes, such as the 520 bytes of a P2SH redeem script. If the usual compilation is
+/// too large, the descriptor is compiled again to minimize the size of its script,
+/// with witness size only breaking ties, first as written and then allowing any key
+/// to be checked against its hash as though it were a `pkh`.
+pub fn compile_with_budget(desc: &Descriptor<secp256k1::PublicKey>, max_size: usize) -> Result<T, CompileError> {
+    let ast = compile_inner(desc, None, CostParams::default(), None)?;
+    let mut smallest = script_size(&ast);
+    if smallest <= max_size {
+        return Ok(ast);
+    }
+
+    let params = CostParams {
+        witness_weight: 1.0 / 1024.0,
+        ..CostParams::default()
+    };
+    let desc = &folded(desc);
+    for &hash_keys in &[false, true] {
+        let mut compiler = Compiler {
+            params,
+            hash_keys,
+            ..Compiler::default()
+        };
+        let ast = compiler.compile_t(desc, 1.0, 0.0)?.into_ast();
+        let size = script_size(&ast);
+        if size <= max_size {
+            return Ok(ast);
+        }
+        smallest = cmp::min(smallest, size);
+    }
+    Err(CompileError::OverBudget(max_size, smallest))
+}
+
+/// A copy of a descriptor with its constants folded, as compiled
+fn folded(desc: &Descriptor<secp256k1::PublicKey>) -> Descriptor<secp256k1::PublicKey> {
+    let copy: Descriptor<secp256k1::PublicKey> = desc.translate(|pk| Ok::<_, ()>(*pk))
+        .expect("copying keys cannot fail");
+    copy.fold_constants()
+}
+
+/// Size in bytes of the script of a compiled expression
+fn script_size(ast: &T) -> usize {
+    ast.serialize(script::Builder::new()).into_script().len()
+}
+
+fn compile_inner(
+    desc: &Descriptor<secp256k1::PublicKey>,
+    aggregator: Option<&dyn KeyAggregator>,
+    params: CostParams,
+    stats: Option<&SpendStats>,
+) -> Result<T, CompileError> {
+    check_depth(desc, 0)?;
+    let desc = &folded(desc);
+    let mut compiler = Compiler {
+        aggregator,
+        params,
+        stats,
+        ..Compiler::default()
+    };
+    Ok(compiler.compile_t(desc, 1.0, 0.0)?.into_ast())
+}
+
+/// A key or hash of a descriptor, abstracted away by `shape`
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Atom {
+    Key(secp256k1::PublicKey),
+    Sha256(sha256::Hash),
+    Hash160(Hash160),
+    Ripemd160(Ripemd160Hash),
+    Hash256(Sha256dHash),
+}
+
+/// Writes the index of `atom` in `atoms`, adding it if it is new
+fn write_atom(atom: Atom, atoms: &mut Vec<Atom>, out: &mut String) {
+    let index = match atoms.iter().position(|a| *a == atom) {
+        Some(index) => index,
+        None => {
+            atoms.push(atom);
+            atoms.len() - 1
+        }
+    };
+    write!(out, "@{}", index).expect("write to string");
+}
+
+/// Writes the structure of a descriptor with every key and hash replaced by its
+/// index in `atoms`. Descriptors with the same structure compile to the same
+/// fragments, up to their keys and hashes.
+fn shape(desc: &Descriptor<secp256k1::PublicKey>, atoms: &mut Vec<Atom>, out: &mut String) {
+    match *desc {
+        Descriptor::Key(ref pk) => {
+            out.push_str("pk(");
+            write_atom(Atom::Key(*pk), atoms, out);
+        }
+        Descriptor::KeyHash(ref pk) => {
+            out.push_str("pkh(");
+            write_atom(Atom::Key(*pk), atoms, out);
+        }
+        Descriptor::Multi(k, ref keys) | Descriptor::SortedMulti(k, ref keys) |
+        Descriptor::MultiA(k, ref keys) => {
+            let name = match *desc {
+                Descriptor::Multi(..) => "multi",
+                Descriptor::SortedMulti(..) => "sortedmulti",
+                _ => "multi_a",
+            };
+            write!(out, "{}({}", name, k).expect("write to string");
+            for pk in keys {
+                out.push(',');
+                write_atom(Atom::Key(*pk), atoms, out);
+            }
+        }
+        Descriptor::Aggregate(ref keys) => {
+            out.push_str("musig(");
+            for pk in keys {
+                write_atom(Atom::Key(*pk), atoms, out);
+                out.push(',');
+            }
+        }
+        Descriptor::Hash(hash) => {
+            out.push_str("hash(");
+            write_atom(Atom::Sha256(hash), atoms, out);
+        }
+        Descriptor::Hash160(hash) => {
+            out.push_str("hash160(");
+            write_atom(Atom::Hash160(hash), atoms, out);
+        }
+        Descriptor::Ripemd160(hash) => {
+            out.push_str("ripemd160(");
+            write_atom(Atom::Ripemd160(hash), atoms, out);
+        }
+        Descriptor::Hash256(hash) => {
+            out.push_str("hash256(");
+            write_atom(Atom::Hash256(hash), atoms, out);
+        }
+        Descriptor::Time(n) => write!(out, "time({}", n).expect("write to string"),
+        // Messages are not substituted, so are part of the shape
+        #[cfg(feature = "elements")]
+        Descriptor::SigFromStack(ref pk, msg) => {
+            out.push_str("csfs(");
+            write_atom(Atom::Key(*pk), atoms, out);
+            write!(out, ",{}", msg).expect("write to string");
+        }
+        // Raw scripts are not substituted, so are part of the shape
+        Descriptor::RawVerify(..) => {
+            write!(out, "{}", desc).expect("write to string");
+            return;
+        }
+        Descriptor::Threshold(k, ref subs) => {
+            write!(out, "thres({}", k).expect("write to string");
+            for sub in subs {
+                out.push(',');
+                shape(sub, atoms, out);
+            }
+        }
+        Descriptor::And(ref left, ref right) |
+        Descriptor::Or(ref left, ref right) |
+        Descriptor::AsymmetricOr(ref left, ref right) => {
+            out.push_str(match *desc {
+                Descriptor::And(..) => "and(",
+                Descriptor::Or(..) => "or(",
+                _ => "aor(",
+            });
+            shape(left, atoms, out);
+            out.push(',');
+            shape(right, atoms, out);
+        }
+        Descriptor::WeightedOr(left_odds, ref left, right_odds, ref right) => {
+            write!(out, "or({}@", left_odds).expect("write to string");
+            shape(left, atoms, out);
+            write!(out, ",{}@", right_odds).expect("write to string");
+            shape(right, atoms, out);
+        }
+        // Wrappers do not compile, so are never cached; they only need to be
+        // distinguished from everything else
+        Descriptor::Wpkh(..) | Descriptor::Sh(..) | Descriptor::Wsh(..) |
+        Descriptor::Combo(..) | Descriptor::Addr(..) | Descriptor::Raw(..) | Descriptor::OpReturn(..) => {
+            write!(out, "{}", desc).expect("write to string");
+            return;
+        }
+    }
+    out.push(')');
+}
+
+/// Substitution of the keys and hashes of a cached compilation by those of a
+/// descriptor of the same structure
+struct Substitution<'a> {
+    from: &'a [Atom],
+    to: &'a [Atom],
+}
+
+impl<'a> Substitution<'a> {
+    /// Finds the atom corresponding to the first of `from` matching `pred`
+    fn find<P: Fn(&Atom) -> bool>(&self, pred: P) -> Option<Atom> {
+        self.from.iter().position(pred).map(|i| self.to[i])
+    }
+}
+
+impl<'a> Translator<()> for Substitution<'a> {
+    fn pk(&mut self, pk: &secp256k1::PublicKey) -> Result<secp256k1::PublicKey, ()> {
+        match self.find(|a| *a == Atom::Key(*pk)) {
+            Some(Atom::Key(pk)) => Ok(pk),
+            _ => Err(()),
+        }
+    }
+
+    fn pkh(&mut self, hash: &Hash160) -> Result<Hash160, ()> {
+        let matches = |a: &Atom| match *a {
+            Atom::Key(ref pk) => Hash160::from_data(&pk.serialize()[..]) == *hash,
+            _ => false,
+        };
+        match self.find(matches) {
+            Some(Atom::Key(pk)) => Ok(Hash160::from_data(&pk.serialize()[..])),
+            _ => Err(()),
+        }
+    }
+
+    fn sha256(&mut self, hash: &sha256::Hash) -> Result<sha256::Hash, ()> {
+        match self.find(|a| *a == Atom::Sha256(*hash)) {
+            Some(Atom::Sha256(hash)) => Ok(hash),
+            _ => Err(()),
+        }
+    }
+
+    fn hash160(&mut self, hash: &Hash160) -> Result<Hash160, ()> {
+        match self.find(|a| *a == Atom::Hash160(*hash)) {
+            Some(Atom::Hash160(hash)) => Ok(hash),
+            _ => Err(()),
+        }
+    }
+
+    fn ripemd160(&mut self, hash: &Ripemd160Hash) -> Result<Ripemd160Hash, ()> {
+        match self.find(|a| *a == Atom::Ripemd160(*hash)) {
+            Some(Atom::Ripemd160(hash)) => Ok(hash),
+            _ => Err(()),
+        }
+    }
+
+    fn hash256(&mut self, hash: &Sha256dHash) -> Result<Sha256dHash, ()> {
+        match self.find(|a| *a == Atom::Hash256(*hash)) {
+            Some(Atom::Hash256(hash)) => Ok(hash),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Cache of compilations for descriptors which differ only in their keys and hashes,
+/// such as those derived for the addresses of a wallet. The first descriptor of each
+/// structure is compiled as usual; later ones reuse its fragments, substituting their
+/// own keys and hashes, without searching again.
+#[derive(Clone, Debug, Default)]
+pub struct CompileCache {
+    params: CostParams,
+    /// Compiled fragments by structure, with the keys and hashes they were compiled for
+    trees: HashMap<String, (Vec<Atom>, T)>,
+}
+
+impl CompileCache {
+    /// Creates an empty cache, compiling with the default cost model
+    pub fn new() -> CompileCache {
+        CompileCache::default()
+    }
+
+    /// Creates an empty cache, compiling to minimize the expected cost under `params`
+    pub fn with_params(params: CostParams) -> CompileCache {
+        CompileCache {
+            params,
+            trees: HashMap::new(),
+        }
+    }
+
+    /// Number of distinct structures compiled so far
+    pub fn len(&self) -> usize {
+        self.trees.len()
+    }
+
+    /// Whether nothing has been compiled yet
+    pub fn is_empty(&self) -> bool {
+        self.trees.is_empty()
+    }
+
+    /// Compiles a descriptor, giving the same result as `ParseTree::compile_with_params`
+    /// with this cache's parameters. Failed compilations are not cached.
+    pub fn compile(&mut self, desc: &Descriptor<secp256k1::PublicKey>) -> Result<ParseTree, CompileError> {
+        check_depth(desc, 0)?;
+        let desc = &folded(desc);
+        let mut atoms = vec![];
+        let mut structure = String::new();
+        shape(desc, &mut atoms, &mut structure);
+
+        if let Some((from, ast)) = self.trees.get(&structure) {
+            let mut subst = Substitution { from, to: &atoms };
+            if let Ok(ast) = ast.translate(&mut subst) {
+                return Ok(ParseTree(Arc::new(ast)));
+            }
+        }
+
+        let ast = compile_inner(desc, None, self.params, None)?;
+        self.trees.insert(structure, (atoms, ast.clone()));
+        Ok(ParseTree(Arc::new(ast)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use secp256k1;
+
+    use bitcoin::blockdata::script;
+
+    use parse::{AstElem, E, T, V, W};
+    use {sha256, CompileError, Descriptor, ExprType, Fragment, ParseTree, Wrapper};
+    use super::{compile, compile_unfolded, compile_with_params, compile_with_version, CompilerVersion, compile_with_stats, CompileCache, Compiler, CostParams, SpendStats, MAX_POLICY_DEPTH};
+
+    fn pubkeys(n: usize) -> Vec<secp256k1::PublicKey> {
+        let mut ret = Vec::with_capacity(n);
+        let secp = secp256k1::Secp256k1::new();
+        let mut sk = [0; 32];
+        for i in 1..n+1 {
+            sk[0] = i as u8;
+            sk[1] = (i >> 8) as u8;
+            sk[2] = (i >> 16) as u8;
+
+            let pk = secp256k1::PublicKey::from_secret_key(
+                &secp,
+                &secp256k1::SecretKey::from_slice(&secp, &sk[..]).expect("secret key"),
+            ).expect("signing context");
+            ret.push(pk);
+        }
+        ret
+    }
+
+    #[test]
+    fn or_of_keys() {
+        let keys = pubkeys(3);
+
+        let desc = Descriptor::Or(
+            Box::new(Descriptor::Key(keys[0])),
+            Box::new(Descriptor::Key(keys[1])),
+        );
+        assert_eq!(
+            compile(&desc).unwrap(),
+            T::CastE(Arc::new(E::ParallelOr(
+                Arc::new(E::CheckSig(keys[0])),
+                Arc::new(W::CheckSig(keys[1])),
+            )))
+        );
+        let cost = Compiler::default().compile_t(&desc, 1.0, 0.0).unwrap();
+        assert_eq!(cost.pk_cost, 72);
+        assert_eq!(cost.sat_cost, 75.0);
+
+        // Nested in a conjunction, a cascade is cheaper since only one branch of the
+        // disjunction needs to leave anything on the stack
+        let desc = Descriptor::And(
+            Box::new(Descriptor::Key(keys[0])),
+            Box::new(Descriptor::Or(
+                Box::new(Descriptor::Key(keys[1])),
+                Box::new(Descriptor::Key(keys[2])),
+            )),
+        );
+        assert_eq!(
+            compile(&desc).unwrap(),
+            T::And(
+                Arc::new(V::CascadeOr(
+                    Arc::new(E::CheckSig(keys[1])),
+                    Arc::new(V::CheckSig(keys[2])),
+                )),
+                Arc::new(T::CastE(Arc::new(E::CheckSig(keys[0])))),
+            )
+        );
+        let cost = Compiler::default().compile_t(&desc, 1.0, 0.0).unwrap();
+        assert_eq!(cost.pk_cost, 107);
+        assert_eq!(cost.sat_cost, 148.5);
+    }
+
+    #[test]
+    fn cost_params() {
+        let keys = pubkeys(2);
+        let desc = Descriptor::Or(
+            Box::new(Descriptor::Key(keys[0])),
+            Box::new(Descriptor::Key(keys[1])),
+        );
+        assert_eq!(compile_with_params(&desc, CostParams::default()).unwrap(), compile(&desc).unwrap());
+        assert_eq!(compile_with_params(&desc, CostParams::for_wrapper(Wrapper::Wsh)).unwrap(), compile(&desc).unwrap());
+
+        // If script bytes are cheap, a one byte longer cascade saves half a byte of
+        // witness on average
+        let params = CostParams { script_weight: 0.1, ..CostParams::default() };
+        let script = ParseTree(Arc::new(compile_with_params(&desc, params).unwrap())).serialize();
+        assert_eq!(script.len(), 73);
+        assert_eq!(ParseTree::compile(&desc).unwrap().serialize().len(), 72);
+
+        let schnorr = CostParams::for_wrapper(Wrapper::Sh).schnorr();
+        assert_eq!(schnorr.sig_size, 66.0);
+        assert_eq!(schnorr.witness_weight, 4.0);
+    }
+
+    #[test]
+    fn report() {
+        let keys = pubkeys(3);
+        let or = Descriptor::Or(
+            Box::new(Descriptor::Key(keys[1])),
+            Box::new(Descriptor::Key(keys[2])),
+        );
+        let or_string = or.to_string();
+        let desc = Descriptor::And(Box::new(Descriptor::Key(keys[0])), Box::new(or));
+        let (tree, steps) = ParseTree::compile_with_report(&desc).unwrap();
+        assert_eq!(tree, ParseTree::compile(&desc).unwrap());
+
+        let root = steps.last().expect("root step");
+        assert_eq!(root.descriptor, desc.to_string());
+        assert_eq!(root.expr_type, ExprType::T);
+        assert_eq!((root.p_sat, root.p_dissat), (1.0, 0.0));
+        assert_eq!(root.candidates[root.chosen].fragment, Fragment::And);
+        assert_eq!(root.candidates[root.chosen].pk_cost, tree.serialize().len());
+
+        for step in &steps {
+            let best = step.candidates[step.chosen].weight;
+            assert!(step.candidates.iter().all(|candidate| candidate.weight >= best));
+            assert!(step.candidates[..step.chosen].iter().all(|candidate| candidate.weight > best));
+        }
+        // The disjunction was compiled as a `V` expression, among others
+        assert!(steps.iter().any(|step| step.descriptor == or_string && step.expr_type == ExprType::V));
+    }
+
+    #[test]
+    fn weighted_or() {
+        let keys = pubkeys(4);
+        let weighted = |left_odds, right_odds| Descriptor::WeightedOr(
+            left_odds,
+            Box::new(Descriptor::Key(keys[0])),
+            right_odds,
+            Box::new(Descriptor::Multi(2, keys[1..4].to_owned())),
+        );
+
+        // If the key is likely to be used, check it first and skip the multisig
+        let key_first = T::CascadeOr(
+            Arc::new(E::CheckSig(keys[0])),
+            Arc::new(T::CastE(Arc::new(E::CheckMultiSig(2, keys[1..4].into())))),
+        );
+        assert_eq!(compile(&weighted(9, 1)).unwrap(), key_first);
+        assert_eq!(compile(&weighted(1, 1)).unwrap(), key_first);
+
+        // Otherwise only pay for a dissatisfaction of the key
+        assert_eq!(
+            compile(&weighted(1, 9)).unwrap(),
+            T::CastE(Arc::new(E::ParallelOr(
+                Arc::new(E::CheckMultiSig(2, keys[1..4].into())),
+                Arc::new(W::CheckSig(keys[0])),
+            )))
+        );
+
+        // Either branch may be given no weight, but not both
+        assert_eq!(compile(&weighted(1, 0)).unwrap(), key_first);
+        let zero = weighted(0, 0);
+        assert_eq!(compile(&zero), Err(CompileError::ZeroOdds(zero.to_string())));
+        assert_eq!(compile_unfolded(&zero), Err(CompileError::ZeroOdds(zero.to_string())));
+    }
+
+    #[test]
+    fn spend_stats() {
+        let keys = pubkeys(4);
+        let key = Descriptor::Key(keys[0]);
+        let multi = Descriptor::Multi(2, keys[1..4].to_owned());
+        let or = Descriptor::Or(Box::new(Descriptor::Key(keys[0])), Box::new(Descriptor::Multi(2, keys[1..4].to_owned())));
+
+        // Without any recorded spends the branches are split evenly
+        let mut stats = SpendStats::new();
+        assert_eq!(compile_with_stats(&or, &stats), compile(&or));
+
+        // Spends mostly by the multisig compile as though it had been weighted so
+        stats.record(&key, 1);
+        stats.record(&multi, 5);
+        stats.record(&multi, 4);
+        assert_eq!(stats.count(&multi), 9);
+        let weighted = Descriptor::WeightedOr(1, Box::new(key), 9, Box::new(multi));
+        assert_eq!(compile_with_stats(&or, &stats), compile(&weighted));
+        assert!(compile_with_stats(&or, &stats) != compile(&or));
+
+        // Explicit odds are kept
+        assert_eq!(compile_with_stats(&weighted, &SpendStats::new()), compile(&weighted));
+
+        // Thresholds whose subexpressions were used equally often compile as usual
+        let thresh = Descriptor::Threshold(2, vec![
+            Descriptor::Key(keys[0]),
+            Descriptor::Key(keys[1]).and_with(Descriptor::Time(100)),
+            Descriptor::Hash(sha256::Hash::from_data(&[])),
+        ]);
+        let mut stats = SpendStats::new();
+        if let Descriptor::Threshold(_, ref subs) = thresh {
+            for sub in subs {
+                stats.record(sub, 3);
+            }
+        }
+        assert_eq!(compile_with_stats(&thresh, &stats), compile(&thresh));
+    }
+
+    #[test]
+    fn verify_or() {
+        let keys = pubkeys(3);
+
+        for &asymmetric in &[false, true] {
+            let or = || {
+                let (a, b) = (Box::new(Descriptor::Key(keys[0])), Box::new(Descriptor::Key(keys[1])));
+                if asymmetric {
+                    Descriptor::AsymmetricOr(a, b)
+                } else {
+                    Descriptor::Or(a, b)
+                }
+            };
+
+            match *Compiler::default().compile_v(&or(), 1.0, 0.0).unwrap().ast {
+                V::ParallelOr(..) | V::CascadeOr(..) | V::SwitchOr(..) | V::SwitchOrT(..) => {}
+                ref x => panic!("unexpected compilation {}", x),
+            }
+
+            // With the disjunction on the left of a conjunction, it may be compiled
+            // in its `V` form ahead of the key
+            let desc = Descriptor::And(Box::new(or()), Box::new(Descriptor::Key(keys[2])));
+            let script = ParseTree::compile(&desc).unwrap().serialize();
+            let reparsed = ParseTree::parse(&script).expect("compiled script parses");
+            assert_eq!(reparsed.serialize(), script);
+        }
+
+        let desc = Descriptor::And(
+            Box::new(Descriptor::Or(
+                Box::new(Descriptor::Key(keys[0])),
+                Box::new(Descriptor::Key(keys[1])),
+            )),
+            Box::new(Descriptor::Key(keys[2])),
+        );
+        assert_eq!(
+            compile(&desc).unwrap(),
+            T::And(
+                Arc::new(V::CascadeOr(
+                    Arc::new(E::CheckSig(keys[0])),
+                    Arc::new(V::CheckSig(keys[1])),
+                )),
+                Arc::new(T::CastE(Arc::new(E::CheckSig(keys[2])))),
+            )
+        );
+    }
+
+    #[test]
+    fn threshold_leading_e() {
+        let keys = pubkeys(2);
+
+        // Whichever subexpression leads the threshold, the compilation is no larger
+        // than one led by a key, with the rest (including the timelock) as `W`s
+        let key_led = T::CastE(Arc::new(E::Threshold(
+            2,
+            Arc::new(E::CheckSig(keys[0])),
+            vec![W::Csv(1000), W::CheckSig(keys[1])],
+        ))).serialize(script::Builder::new()).into_script();
+        let descs = vec![
+            Descriptor::Threshold(2, vec![
+                Descriptor::Time(1000),
+                Descriptor::Key(keys[0]),
+                Descriptor::Key(keys[1]),
+            ]),
+            Descriptor::Threshold(2, vec![
+                Descriptor::Key(keys[0]),
+                Descriptor::Time(1000),
+                Descriptor::Key(keys[1]),
+            ]),
+        ];
+        for desc in &descs {
+            match compile(desc).unwrap() {
+                T::CastE(e) => match *e {
+                    E::Threshold(2, _, ref subw) => assert_eq!(subw.len(), 2),
+                    ref x => panic!("unexpected compilation {}", x),
+                },
+                ref x => panic!("unexpected compilation {}", x),
+            }
+            assert!(ParseTree::compile(desc).unwrap().serialize().len() <= key_led.len());
+        }
+
+        // When the key comes first it is kept as the leading `E`
+        match compile(&descs[1]).unwrap() {
+            T::CastE(e) => match *e {
+                E::Threshold(2, ref sube, ref subw) => {
+                    assert_eq!(**sube, E::CheckSig(keys[0]));
+                    assert_eq!(subw, &vec![W::Csv(1000), W::CheckSig(keys[1])]);
+                }
+                ref x => panic!("unexpected compilation {}", x),
+            },
+            x => panic!("unexpected compilation {}", x),
+        }
+    }
+
+    #[test]
+    fn threshold_of_keys() {
+        let keys = pubkeys(21);
+
+        // A threshold of single keys is a multisig in all but name
+        let desc = Descriptor::Threshold(2, keys[0..3].iter().map(|pk| Descriptor::Key(*pk)).collect());
+        assert_eq!(
+            compile(&desc).unwrap(),
+            T::CastE(Arc::new(E::CheckMultiSig(2, keys[0..3].into())))
+        );
+        assert_eq!(
+            *Compiler::default().compile_v(&desc, 1.0, 0.0).unwrap().ast,
+            V::CheckMultiSig(2, keys[0..3].into())
+        );
+
+        // but not if it has more keys than a `CHECKMULTISIG` allows
+        let desc = Descriptor::Threshold(2, keys.iter().map(|pk| Descriptor::Key(*pk)).collect());
+        match compile(&desc).unwrap() {
+            T::CastE(e) => match *e {
+                E::Threshold(2, _, ref subw) => assert_eq!(subw.len(), 20),
+                ref x => panic!("unexpected compilation {}", x),
+            },
+            x => panic!("unexpected compilation {}", x),
+        }
+    }
+
+    #[test]
+    fn shared_keys() {
+        let keys = pubkeys(3);
+
+        // Every fragment compiled from a multisig checks the same key list
+        let desc = Descriptor::SortedMulti(2, keys);
+        let mut compiler = Compiler::default();
+        let e = compiler.compile_e(&desc, 1.0, 0.0).unwrap().ast;
+        let v = compiler.compile_v(&desc, 1.0, 0.0).unwrap().ast;
+        match (&*e, &*v) {
+            (E::CheckMultiSig(2, ref e_keys), V::CheckMultiSig(2, ref v_keys)) => {
+                assert!(Arc::ptr_eq(e_keys, v_keys));
+            }
+            (e, v) => panic!("unexpected compilations {} and {}", e, v),
+        }
+    }
+
+    #[test]
+    fn shared_fragments() {
+        let keys = pubkeys(3);
+
+        // A fragment is compiled once, and every larger fragment points to it
+        let desc = Descriptor::Multi(2, keys);
+        let mut compiler = Compiler::default();
+        let e = compiler.compile_e(&desc, 1.0, 0.0).unwrap().ast;
+        assert!(Arc::ptr_eq(&e, &compiler.compile_e(&desc, 1.0, 0.0).unwrap().ast));
+        match *compiler.compile_t(&desc, 1.0, 0.0).unwrap().ast {
+            T::CastE(ref sub) => assert!(Arc::ptr_eq(sub, &e)),
+            ref t => panic!("unexpected compilation {}", t),
+        }
+
+        // Cloning a tree copies no fragments
+        let tree = ParseTree::compile(&desc).unwrap();
+        let copy = tree.clone();
+        assert!(Arc::ptr_eq(&tree.0, &copy.0));
+    }
+
+    #[test]
+    fn constant_folding() {
+        let keys = pubkeys(3);
+        let pk = |i: usize| Descriptor::Key(keys[i]);
+        let time = |n: u32| Descriptor::Time(n);
+
+        let cases = vec![
+            (Descriptor::Threshold(1, vec![pk(0)]), pk(0)),
+            (pk(0).and_with(time(0)), pk(0)),
+            (time(0).and_with(pk(0).or_with(pk(1))), pk(0).or_with(pk(1))),
+            (Descriptor::Multi(1, vec![keys[0]]), pk(0)),
+            (
+                Descriptor::Threshold(2, vec![time(0), pk(0).and_with(time(0)), Descriptor::Multi(1, vec![keys[1]])]),
+                Descriptor::Threshold(1, vec![pk(0), pk(1)]),
+            ),
+            (
+                pk(2).or_with(Descriptor::Threshold(1, vec![pk(0).and_with(time(100))])),
+                pk(2).or_with(pk(0).and_with(time(100))),
+            ),
+        ];
+        for (desc, simple) in cases {
+            assert_eq!(desc.translate(|key| Ok::<_, ()>(*key)).unwrap().fold_constants().to_string(), simple.to_string());
+            assert_eq!(compile(&desc), compile(&simple));
+            assert_eq!(compile(&desc), compile_unfolded(&simple));
+            assert_eq!(CompileCache::new().compile(&desc).unwrap(), ParseTree::compile(&simple).unwrap());
+        }
+
+        // As written, the trivial parts cost script
+        let desc = pk(0).and_with(time(0));
+        assert!(ParseTree::compile_unfolded(&desc).unwrap().serialize().len() >
+                ParseTree::compile(&desc).unwrap().serialize().len());
+        // Other thresholds and timelocks are kept
+        let desc = Descriptor::Threshold(2, vec![pk(0), pk(1), time(10)]);
+        assert_eq!(compile(&desc), compile_unfolded(&desc));
+    }
+
+    #[test]
+    fn compiler_versions() {
+        let keys = pubkeys(2);
+        let desc = Descriptor::Key(keys[0]).and_with(Descriptor::Time(0));
+
+        // Each version reproduces its own output
+        assert_eq!(compile_with_version(&desc, CompilerVersion::V1), compile_unfolded(&desc));
+        assert_eq!(compile_with_version(&desc, CompilerVersion::V2), compile(&desc));
+        assert!(compile_with_version(&desc, CompilerVersion::V1) != compile(&desc));
+        assert_eq!(
+            ParseTree::compile_with_version(&desc, CompilerVersion::latest()).unwrap(),
+            ParseTree::compile(&desc).unwrap()
+        );
+        assert_eq!(CompilerVersion::default(), CompilerVersion::latest());
+
+        // and is stored as its number
+        for &version in &[CompilerVersion::V1, CompilerVersion::V2] {
+            assert_eq!(CompilerVersion::from_u32(version.to_u32()), Some(version));
+        }
+        assert_eq!(CompilerVersion::from_u32(0), None);
+        assert_eq!(CompilerVersion::from_u32(CompilerVersion::latest().to_u32() + 1), None);
+    }
+
+    #[test]
+    fn compile_errors() {
+        let keys = pubkeys(2);
+
+        assert_eq!(compile(&Descriptor::Threshold(1, vec![])), Err(CompileError::EmptyThreshold));
+        assert_eq!(compile(&Descriptor::Multi(0, vec![])), Err(CompileError::EmptyThreshold));
+        assert_eq!(
+            compile(&Descriptor::Threshold(2, vec![Descriptor::Time(10)])),
+            Err(CompileError::ThresholdKTooLarge(2, 1))
+        );
+        assert_eq!(
+            compile(&Descriptor::Or(
+                Box::new(Descriptor::Key(keys[0])),
+                Box::new(Descriptor::Multi(3, keys.clone())),
+            )),
+            Err(CompileError::ThresholdKTooLarge(3, 2))
+        );
+        match compile(&Descriptor::And(
+            Box::new(Descriptor::Key(keys[0])),
+            Box::new(Descriptor::Wpkh(keys[1])),
+        )) {
+            Err(CompileError::Unsupported(_)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        let mut desc = Descriptor::Key(keys[0]);
+        for _ in 0..MAX_POLICY_DEPTH {
+            desc = Descriptor::And(Box::new(Descriptor::Time(10)), Box::new(desc));
+        }
+        assert!(compile(&desc).is_ok());
+        let desc = Descriptor::And(Box::new(Descriptor::Time(10)), Box::new(desc));
+        assert_eq!(compile(&desc), Err(CompileError::PolicyTooDeep(MAX_POLICY_DEPTH)));
+    }
+
+    #[test]
+    fn deep_disjunctions() {
+        // The probabilities reaching each node of a chain of disjunctions differ along
+        // each of the 2^30 paths to it, but are rounded to few enough distinct values
+        // for the tables to be hit
+        let keys = pubkeys(31);
+        let mut desc = Descriptor::Key(keys[30]);
+        for pk in keys[..30].iter().rev() {
+            desc = Descriptor::Or(Box::new(Descriptor::Key(*pk)), Box::new(desc));
+        }
+        let mut compiler = Compiler::default();
+        assert!(compiler.compile_t(&desc, 1.0, 0.0).is_ok());
+        assert!(compiler.e.len() < 1 << 16);
+        assert!(compiler.w.len() < 1 << 16);
+    }
+
+    #[test]
+    fn compile_with_budget() {
+        let keys = pubkeys(2);
+        let desc = Descriptor::Or(
+            Box::new(Descriptor::Key(keys[0])),
+            Box::new(Descriptor::Key(keys[1])),
+        );
+        let usual = ParseTree::compile(&desc).unwrap();
+        assert_eq!(ParseTree::compile_with_budget(&desc, 100).unwrap(), usual);
+        assert_eq!(usual.serialize().len(), 72);
+
+        // Keys are checked against their hashes to make room
+        let small = ParseTree::compile_with_budget(&desc, 60).unwrap();
+        assert!(small.serialize().len() <= 60);
+        assert!(!small.key_report().key_hashes.is_empty());
+
+        match ParseTree::compile_with_budget(&desc, 20) {
+            Err(CompileError::OverBudget(20, size)) => assert!(size > 20 && size <= 60),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn compile_cache() {
+        let keys = pubkeys(8);
+        let policy = |keys: &[secp256k1::PublicKey], preimage: u8| Descriptor::AsymmetricOr(
+            Box::new(Descriptor::Multi(2, keys[0..3].to_owned())),
+            Box::new(Descriptor::And(
+                Box::new(Descriptor::KeyHash(keys[3])),
+                Box::new(Descriptor::Hash(sha256::Hash::from_data(&[preimage]))),
+            )),
+        );
+
+        let mut cache = CompileCache::new();
+        assert!(cache.is_empty());
+        for (i, desc) in [policy(&keys[0..4], 0), policy(&keys[4..8], 1)].iter().enumerate() {
+            assert_eq!(cache.compile(desc).unwrap(), ParseTree::compile(desc).unwrap());
+            assert_eq!(cache.compile(desc).unwrap(), ParseTree::compile(desc).unwrap());
+            assert_eq!(cache.len(), 1, "descriptor {}", i);
+        }
+
+        // Reusing a key changes the structure
+        let mut reused = keys[0..4].to_owned();
+        reused[3] = reused[0];
+        let desc = policy(&reused, 0);
+        assert_eq!(cache.compile(&desc).unwrap(), ParseTree::compile(&desc).unwrap());
+        assert_eq!(cache.len(), 2);
+
+        // Failures are reported each time and not cached
+        let desc = Descriptor::Multi(3, keys[0..2].to_owned());
+        assert_eq!(cache.compile(&desc), Err(CompileError::ThresholdKTooLarge(3, 2)));
+        assert_eq!(cache.compile(&desc), Err(CompileError::ThresholdKTooLarge(3, 2)));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn compile_parse_roundtrip() {
+        let keys = pubkeys(6);
+        let hash = sha256::Hash::from_data(&[0; 32]);
+
+        let descs = vec![
+            Descriptor::Threshold(2, vec![
+                Descriptor::Key(keys[0]),
+                Descriptor::Time(100),
+                Descriptor::Hash(hash),
+            ]),
+            Descriptor::AsymmetricOr(
+                Box::new(Descriptor::Multi(2, keys[0..3].to_owned())),
+                Box::new(Descriptor::Or(
+                    Box::new(Descriptor::KeyHash(keys[3])),
+                    Box::new(Descriptor::And(
+                        Box::new(Descriptor::Key(keys[4])),
+                        Box::new(Descriptor::Time(1000)),
+                    )),
+                )),
+            ),
+            Descriptor::And(
+                Box::new(Descriptor::Or(
+                    Box::new(Descriptor::Key(keys[0])),
+                    Box::new(Descriptor::Hash(hash)),
+                )),
+                Box::new(Descriptor::Threshold(1, vec![
+                    Descriptor::KeyHash(keys[1]),
+                    Descriptor::Multi(1, keys[2..6].to_owned()),
+                ])),
+            ),
+        ];
+
+        for desc in &descs {
+            let script = ParseTree::compile(desc).unwrap().serialize();
+            let cost = Compiler::default().compile_t(desc, 1.0, 0.0).unwrap();
+            assert_eq!(cost.pk_cost, script.len());
+            let reparsed = ParseTree::parse(&script).expect("compiled script parses");
+            assert_eq!(reparsed.serialize(), script);
+        }
+    }
+}
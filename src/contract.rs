@@ -0,0 +1,160 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Pay-to-Contract
+//!
+//! Tweaking of the keys of a descriptor by a contract commitment, as in the contracthash
+//! scheme of Elements: each public key `P` becomes `P + HMAC-SHA256(P, contract)·G`,
+//! where `P` is the compressed serialization of the key and is used as the HMAC key.
+//! Anybody who knows the template and the contract can recompute the tweaked keys,
+//! and so check that an output commits to the contract, while only the holder of the
+//! secret key of `P` can sign for the tweaked key.
+//!
+//! Elements uses 40-byte contracts, but any commitment is accepted here.
+//!
+
+use secp256k1;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin_hashes::{Hash, HashEngine};
+use bitcoin_hashes::hmac::{Hmac, HmacEngine};
+use bitcoin_hashes::sha256::Hash as Sha256Hash;
+
+use {Descriptor, Error};
+
+/// Computes the scalar by which `pk` is tweaked for `contract`
+fn tweak_scalar(secp: &secp256k1::Secp256k1, pk: &secp256k1::PublicKey, contract: &[u8]) -> Result<secp256k1::SecretKey, Error> {
+    let mut engine = HmacEngine::<Sha256Hash>::new(&pk.serialize()[..]);
+    engine.input(contract);
+    let tweak = Hmac::from_engine(engine);
+    secp256k1::SecretKey::from_slice(secp, &tweak[..]).map_err(Error::BadTweak)
+}
+
+/// Tweaks `pk` with a context which must be capable of verification
+fn tweak_key_with(secp: &secp256k1::Secp256k1, pk: &secp256k1::PublicKey, contract: &[u8]) -> Result<secp256k1::PublicKey, Error> {
+    let tweak = tweak_scalar(secp, pk, contract)?;
+    let mut ret = *pk;
+    ret.add_exp_assign(secp, &tweak).map_err(Error::BadTweak)?;
+    Ok(ret)
+}
+
+/// Tweaks a public key by a contract commitment
+pub fn tweak_key(pk: &secp256k1::PublicKey, contract: &[u8]) -> Result<secp256k1::PublicKey, Error> {
+    tweak_key_with(&secp256k1::Secp256k1::new(), pk, contract)
+}
+
+/// Tweaks a secret key by a contract commitment, giving the secret key of the public
+/// key which `tweak_key` gives for its public key
+pub fn tweak_secret_key(sk: &secp256k1::SecretKey, contract: &[u8]) -> Result<secp256k1::SecretKey, Error> {
+    let secp = secp256k1::Secp256k1::new();
+    let pk = secp256k1::PublicKey::from_secret_key(&secp, sk).expect("signing context");
+    let tweak = tweak_scalar(&secp, &pk, contract)?;
+    let mut ret = *sk;
+    ret.add_assign(&secp, &tweak).map_err(Error::BadTweak)?;
+    Ok(ret)
+}
+
+/// Tweaks every key of a descriptor template by a contract commitment, including the
+/// keys which only appear hashed in its script
+pub fn tweak_descriptor(
+    template: &Descriptor<secp256k1::PublicKey>,
+    contract: &[u8],
+) -> Result<Descriptor<secp256k1::PublicKey>, Error> {
+    let secp = secp256k1::Secp256k1::new();
+    template.translate(|pk| tweak_key_with(&secp, pk, contract))
+}
+
+/// Checks that `script` is the script of `template` with every key tweaked by
+/// `contract`. The script is compared against the `witness_script` of the tweaked
+/// descriptor, so it should be the redeem or witness script of a wrapped template,
+/// and it must have been compiled by this library for the comparison to succeed.
+pub fn verify_tweaked_script(
+    template: &Descriptor<secp256k1::PublicKey>,
+    contract: &[u8],
+    script: &Script,
+) -> Result<bool, Error> {
+    let tweaked = tweak_descriptor(template, contract)?;
+    Ok(tweaked.witness_script()? == *script)
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1;
+
+    use bitcoin::util::hash::Hash160;
+    use bitcoin::util::misc::hex_bytes;
+
+    use {sha256, Descriptor};
+    use super::{tweak_descriptor, tweak_key, tweak_scalar, tweak_secret_key, verify_tweaked_script};
+
+    fn keys(n: usize) -> Vec<(secp256k1::SecretKey, secp256k1::PublicKey)> {
+        let secp = secp256k1::Secp256k1::new();
+        (1..n + 1).map(|i| {
+            let sk = secp256k1::SecretKey::from_slice(&secp, &[i as u8; 32]).expect("secret key");
+            (sk, secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context"))
+        }).collect()
+    }
+
+    #[test]
+    fn tweak_keys() {
+        let secp = secp256k1::Secp256k1::new();
+        let keys = keys(2);
+        let contract = [0x2a; 40];
+
+        // The tweaked secret key matches the tweaked public key
+        let (sk, pk) = keys[0];
+        let tweaked = tweak_key(&pk, &contract).unwrap();
+        assert!(tweaked != pk);
+        let tweaked_sk = tweak_secret_key(&sk, &contract).unwrap();
+        assert_eq!(secp256k1::PublicKey::from_secret_key(&secp, &tweaked_sk).expect("signing context"), tweaked);
+
+        // The tweak is HMAC-SHA256 of the contract, keyed by the serialized key
+        let tweak = tweak_scalar(&secp, &pk, &contract).unwrap();
+        let expected = hex_bytes("6d622e97670af46ed22872ed04416dff25c0de19660b49a07e2e74caf21bc3e0").unwrap();
+        assert_eq!(&tweak[..], &expected[..]);
+
+        // Different contracts and different keys give different tweaks
+        assert!(tweak_key(&pk, &[0x2b; 40]).unwrap() != tweaked);
+        assert!(tweak_key(&keys[1].1, &contract).unwrap() != tweaked);
+    }
+
+    #[test]
+    fn tweak_and_verify() {
+        let keys = keys(3);
+        let contract = b"pay to contract commitment";
+        let hash = sha256::Hash::from_data(b"preimage");
+        let template = Descriptor::Wsh(Box::new(Descriptor::Or(
+            Box::new(Descriptor::Multi(2, vec![keys[0].1, keys[1].1])),
+            Box::new(Descriptor::And(Box::new(Descriptor::KeyHash(keys[2].1)), Box::new(Descriptor::Hash(hash)))),
+        )));
+
+        let tweaked = tweak_descriptor(&template, contract).unwrap();
+        let tweaked_keys: Vec<_> = keys.iter().map(|&(_, pk)| tweak_key(&pk, contract).unwrap()).collect();
+        let expected = Descriptor::Wsh(Box::new(Descriptor::Or(
+            Box::new(Descriptor::Multi(2, vec![tweaked_keys[0], tweaked_keys[1]])),
+            Box::new(Descriptor::And(Box::new(Descriptor::KeyHash(tweaked_keys[2])), Box::new(Descriptor::Hash(hash)))),
+        )));
+        assert_eq!(tweaked.to_string(), expected.to_string());
+
+        // Hashed keys are tweaked before hashing
+        let script = tweaked.witness_script().unwrap();
+        let hashed = Hash160::from_data(&tweaked_keys[2].serialize()[..]);
+        assert!(script[..].windows(20).any(|w| w == &hashed[..]));
+
+        assert_eq!(verify_tweaked_script(&template, contract, &script), Ok(true));
+        assert_eq!(verify_tweaked_script(&template, b"another commitment", &script), Ok(false));
+        let untweaked = template.witness_script().unwrap();
+        assert_eq!(verify_tweaked_script(&template, contract, &untweaked), Ok(false));
+    }
+}
@@ -28,9 +28,18 @@ use std::str::{self, FromStr};
 
 use secp256k1;
 
-use bitcoin::util::hash::Sha256dHash; // TODO needs to be sha256, not sha256d
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{self, Script};
+use bitcoin::network::constants::Network;
+use bitcoin::util::address::Address;
+use bitcoin::util::hash::Hash160;
+use bitcoin::util::hash::Sha256dHash;
+use bitcoin::util::misc::hex_bytes;
+use bitcoin_hashes::Hash as BitcoinHash;
+use bitcoin_hashes::ripemd160::Hash as Ripemd160Hash;
 
-use Error;
+use parse::MAX_PARSE_DEPTH;
+use {checksum, sha256, Error, KeyReport, ParseTree, SanityError, Wrapper};
 
 /// Abstraction over "public key" which can be used when converting to/from a scriptpubkey
 pub trait PublicKey: Hash + Eq + Sized {
@@ -46,6 +55,13 @@ pub trait PublicKey: Hash + Eq + Sized {
     fn instantiate(&self, aux: Option<&Self::Aux>) -> Result<secp256k1::PublicKey, Error>;
 }
 
+/// Function combining the keys of an `Aggregate` descriptor into the single key which
+/// its script checks a signature with, such as MuSig key aggregation
+pub trait KeyAggregator {
+    /// Aggregate the given keys, which are in the order they appear in the descriptor
+    fn aggregate(&self, keys: &[secp256k1::PublicKey]) -> Result<secp256k1::PublicKey, secp256k1::Error>;
+}
+
 impl PublicKey for secp256k1::PublicKey {
     type Aux = ();
 
@@ -58,33 +74,150 @@ impl PublicKey for secp256k1::PublicKey {
     }
 
     fn from_str(s: &str) -> Result<secp256k1::PublicKey, Error> {
-        let bytes = s.as_bytes();
-        let mut ret = [0; 33];
-        if bytes.len() != 66 {
-            return Err(Error::Unexpected(s.to_string()))
-        }
-        // TODO uncompressed keys
-        for i in 0..ret.len() {
-           let hi = match bytes[2*i] {
-               b @ b'0'...b'9' => (b - b'0') as u8, 
-               b @ b'a'...b'f' => (b - b'a' + 10) as u8, 
-               b @ b'A'...b'F' => (b - b'A' + 10) as u8, 
-               b => return Err(Error::Unexpected(format!("{}", b as char)))
-           };  
-           let lo = match bytes[2*i + 1] {
-               b @ b'0'...b'9' => (b - b'0') as u8, 
-               b @ b'a'...b'f' => (b - b'a' + 10) as u8, 
-               b @ b'A'...b'F' => (b - b'A' + 10) as u8, 
-               b => return Err(Error::Unexpected(format!("{}", b as char)))
-           };  
-           ret[ret.len() - 1 - i] = hi * 0x10 + lo; 
-        }
+        let ret = key_from_hex(s)?;
         let secp = secp256k1::Secp256k1::without_caps();
         secp256k1::PublicKey::from_slice(&secp, &ret[..]).map_err(Error::BadPubkey)
     }
 
     fn instantiate(&self, _: Option<&()>) -> Result<secp256k1::PublicKey, Error> {
-        Ok(self.clone())
+        Ok(*self)
+    }
+}
+
+/// Decodes the hex encoding of a 33-byte compressed key, without checking that it is
+/// a point on the curve
+fn key_from_hex(s: &str) -> Result<[u8; 33], Error> {
+    let bytes = s.as_bytes();
+    let mut ret = [0; 33];
+    // Uncompressed keys are rejected, as by `ParseTree::parse`, since fragments
+    // only hold compressed keys
+    if bytes.len() == 130 && s.starts_with("04") {
+        return Err(Error::Unexpected(format!("uncompressed key {}", s)));
+    }
+    if bytes.len() != 66 {
+        return Err(Error::Unexpected(s.to_string()));
+    }
+    for i in 0..ret.len() {
+        let hi = match bytes[2*i] {
+            b @ b'0'..=b'9' => b - b'0',
+            b @ b'a'..=b'f' => b - b'a' + 10,
+            b @ b'A'..=b'F' => b - b'A' + 10,
+            b => return Err(Error::Unexpected(format!("{}", b as char))),
+        };
+        let lo = match bytes[2*i + 1] {
+            b @ b'0'..=b'9' => b - b'0',
+            b @ b'a'..=b'f' => b - b'a' + 10,
+            b @ b'A'..=b'F' => b - b'A' + 10,
+            b => return Err(Error::Unexpected(format!("{}", b as char))),
+        };
+        ret[i] = hi * 0x10 + lo;
+    }
+    Ok(ret)
+}
+
+/// A key with an optional label naming its role, such as "cold", "hot" or "arbiter",
+/// written `label:KEY` in a descriptor. Labels are opaque to the library; they are
+/// carried through compilation into the `KeyReport` of the descriptor so that wallets
+/// can explain what each spend path needs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Labeled<P> {
+    /// The key
+    pub key: P,
+    /// Its label, which is nonempty and consists of alphanumerics, `-` and `_`
+    pub label: Option<String>,
+}
+
+impl<P: PublicKey> PublicKey for Labeled<P> {
+    type Aux = P::Aux;
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref label) = self.label {
+            write!(f, "{}:", label)?;
+        }
+        self.key.fmt(f)
+    }
+
+    fn from_str(s: &str) -> Result<Labeled<P>, Error> {
+        let (label, key) = match s.find(':') {
+            Some(n) => (Some(&s[..n]), &s[n + 1..]),
+            None => (None, s),
+        };
+        if let Some(label) = label {
+            if label.is_empty() || !label.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_') {
+                return Err(Error::Unexpected(format!("key label {}", label)));
+            }
+        }
+        Ok(Labeled {
+            key: P::from_str(key)?,
+            label: label.map(str::to_owned),
+        })
+    }
+
+    fn instantiate(&self, aux: Option<&P::Aux>) -> Result<secp256k1::PublicKey, Error> {
+        self.key.instantiate(aux)
+    }
+}
+
+/// A compressed key carried as its 33 serialized bytes, for tools which parse and
+/// classify descriptors without needing the keys to be valid. Only the length and the
+/// prefix byte are checked when parsing; whether the key is on the curve is checked
+/// when it is instantiated, or for a whole descriptor with `Descriptor::verify_keys`.
+#[cfg(feature = "no-crypto")]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RawKey(pub [u8; 33]);
+
+#[cfg(feature = "no-crypto")]
+impl fmt::Debug for RawKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RawKey(")?;
+        PublicKey::fmt(self, f)?;
+        write!(f, ")")
+    }
+}
+
+#[cfg(feature = "no-crypto")]
+impl RawKey {
+    /// Wraps a serialized compressed key, checking only its length and prefix byte
+    pub fn from_slice(bytes: &[u8]) -> Result<RawKey, Error> {
+        if bytes.len() != 33 || (bytes[0] != 0x02 && bytes[0] != 0x03) {
+            return Err(Error::InvalidPush(bytes.to_owned()));
+        }
+        let mut ret = [0; 33];
+        ret.copy_from_slice(bytes);
+        Ok(RawKey(ret))
+    }
+
+    /// Checks that the key is a point on the curve
+    pub fn verify(&self) -> Result<secp256k1::PublicKey, Error> {
+        let secp = secp256k1::Secp256k1::without_caps();
+        secp256k1::PublicKey::from_slice(&secp, &self.0[..]).map_err(Error::BadPubkey)
+    }
+}
+
+#[cfg(feature = "no-crypto")]
+impl From<secp256k1::PublicKey> for RawKey {
+    fn from(pk: secp256k1::PublicKey) -> RawKey {
+        RawKey(pk.serialize())
+    }
+}
+
+#[cfg(feature = "no-crypto")]
+impl PublicKey for RawKey {
+    type Aux = ();
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for x in &self.0[..] {
+            write!(f, "{:02x}", *x)?;
+        }
+        Ok(())
+    }
+
+    fn from_str(s: &str) -> Result<RawKey, Error> {
+        RawKey::from_slice(&key_from_hex(s)?[..])
+    }
+
+    fn instantiate(&self, _: Option<&()>) -> Result<secp256k1::PublicKey, Error> {
+        self.verify()
     }
 }
 
@@ -96,10 +229,37 @@ pub enum Descriptor<P: PublicKey> {
     KeyHash(P),
     /// A set of keys, signatures must be provided for `k` of them
     Multi(usize, Vec<P>),
+    /// Same as `Multi`, but the keys are sorted by their serialization when the
+    /// script is generated (BIP67), so that the order they are given in does not
+    /// matter. The descriptor itself keeps the order it was written in.
+    SortedMulti(usize, Vec<P>),
+    /// Same as `Multi`, but checked one key at a time with `OP_CHECKSIGADD`, which
+    /// replaces `OP_CHECKMULTISIG` in tapscripts and is not available elsewhere
+    MultiA(usize, Vec<P>),
+    /// A set of keys which must all sign, checked as a single signature with their
+    /// aggregate key (as with MuSig); see `ParseTree::compile_with_aggregator`
+    Aggregate(Vec<P>),
     /// A SHA256 whose preimage must be provided to satisfy the descriptor
-    Hash(Sha256dHash),
+    Hash(sha256::Hash),
+    /// A HASH160 whose preimage must be provided to satisfy the descriptor
+    Hash160(Hash160),
+    /// A RIPEMD160 whose preimage must be provided to satisfy the descriptor
+    Ripemd160(Ripemd160Hash),
+    /// A HASH256 (double-SHA256) whose preimage must be provided to satisfy the descriptor
+    Hash256(Sha256dHash),
     /// A locktime restriction
     Time(u32),
+    /// Literal script bytes, for conditions this library does not understand (such as
+    /// introspection opcodes). The script must consume its satisfaction and leave
+    /// nothing on the stack, or fail. Satisfactions are given by the caller through
+    /// `Satisfier::lookup_raw`, and are not counted in satisfaction size estimates.
+    RawVerify(Script),
+    /// A public key which must sign the given 32-byte message, checked with Elements'
+    /// `OP_CHECKSIGFROMSTACK`. The signature does not commit to the spending
+    /// transaction, as with an oracle attesting to an event. Only Elements scripts
+    /// may contain this.
+    #[cfg(feature = "elements")]
+    SigFromStack(P, sha256::Hash),
     /// A set of descriptors, satisfactions must be provided for `k` of them
     Threshold(usize, Vec<Descriptor<P>>),
     /// A list of descriptors, all of which must be satisfied
@@ -108,76 +268,492 @@ pub enum Descriptor<P: PublicKey> {
     Or(Box<Descriptor<P>>, Box<Descriptor<P>>),
     /// Same as `Or`, but the second option is assumed to never be taken for costing purposes
     AsymmetricOr(Box<Descriptor<P>>, Box<Descriptor<P>>),
+    /// Same as `Or`, but annotated with the relative odds of each option being the one
+    /// taken, for costing purposes. At least one of the odds must be nonzero.
+    WeightedOr(usize, Box<Descriptor<P>>, usize, Box<Descriptor<P>>),
     /// Pay-to-Witness-PubKey-Hash
     Wpkh(P),
     /// Pay-to-ScriptHash
     Sh(Box<Descriptor<P>>),
     /// Pay-to-Witness-ScriptHash
     Wsh(Box<Descriptor<P>>),
+    /// All of the standard outputs paying to a single key: pay-to-pubkey,
+    /// pay-to-pubkey-hash, and pay-to-witness-pubkey-hash both bare and wrapped in P2SH
+    Combo(P),
+    /// The output paying to an address, whose spending conditions are unknown
+    Addr(Address),
+    /// A literal scriptpubkey, whose spending conditions are unknown
+    Raw(Script),
+    /// A provably unspendable output carrying data, `OP_RETURN <data>`
+    OpReturn(Vec<u8>),
 }
 
 impl<P: PublicKey> Descriptor<P> {
     /// Convert a descriptor using abstract keys to one using specific keys
     pub fn instantiate(&self, keymap: &HashMap<P, P::Aux>) -> Result<Descriptor<secp256k1::PublicKey>, Error> {
+        self.translate(|pk| pk.instantiate(keymap.get(pk)))
+    }
+
+    /// Convert a descriptor to one using another type of key, by mapping every key
+    /// through `f` and stopping at the first error
+    pub fn translate<Q, Err, Fun>(&self, mut f: Fun) -> Result<Descriptor<Q>, Err>
+        where Q: PublicKey, Fun: FnMut(&P) -> Result<Q, Err>
+    {
+        self.translate_inner(&mut f)
+    }
+
+    fn translate_inner<Q, Err, Fun>(&self, f: &mut Fun) -> Result<Descriptor<Q>, Err>
+        where Q: PublicKey, Fun: FnMut(&P) -> Result<Q, Err>
+    {
         match *self {
-            Descriptor::Key(ref pk) => {
-                let secp_pk = pk.instantiate(keymap.get(pk))?;
-                Ok(Descriptor::Key(secp_pk))
-            }
-            Descriptor::KeyHash(ref pk) => {
-                let secp_pk = pk.instantiate(keymap.get(pk))?;
-                Ok(Descriptor::KeyHash(secp_pk))
-            }
+            Descriptor::Key(ref pk) => Ok(Descriptor::Key(f(pk)?)),
+            Descriptor::KeyHash(ref pk) => Ok(Descriptor::KeyHash(f(pk)?)),
             Descriptor::Multi(k, ref keys) => {
-                let mut new_keys = Vec::with_capacity(keys.len());
-                for key in keys {
-                    let secp_pk = key.instantiate(keymap.get(key))?;
-                    new_keys.push(secp_pk);
-                }
+                let new_keys = keys.iter().map(&mut *f).collect::<Result<_, _>>()?;
                 Ok(Descriptor::Multi(k, new_keys))
             }
+            Descriptor::SortedMulti(k, ref keys) => {
+                let new_keys = keys.iter().map(&mut *f).collect::<Result<_, _>>()?;
+                Ok(Descriptor::SortedMulti(k, new_keys))
+            }
+            Descriptor::MultiA(k, ref keys) => {
+                let new_keys = keys.iter().map(&mut *f).collect::<Result<_, _>>()?;
+                Ok(Descriptor::MultiA(k, new_keys))
+            }
+            Descriptor::Aggregate(ref keys) => {
+                let new_keys = keys.iter().map(&mut *f).collect::<Result<_, _>>()?;
+                Ok(Descriptor::Aggregate(new_keys))
+            }
             Descriptor::Threshold(k, ref subs) => {
                 let mut new_subs = Vec::with_capacity(subs.len());
                 for sub in subs {
-                    new_subs.push(sub.instantiate(keymap)?);
+                    new_subs.push(sub.translate_inner(f)?);
                 }
                 Ok(Descriptor::Threshold(k, new_subs))
             }
             Descriptor::Hash(hash) => Ok(Descriptor::Hash(hash)),
+            Descriptor::Hash160(hash) => Ok(Descriptor::Hash160(hash)),
+            Descriptor::Ripemd160(hash) => Ok(Descriptor::Ripemd160(hash)),
+            Descriptor::Hash256(hash) => Ok(Descriptor::Hash256(hash)),
             Descriptor::And(ref left, ref right) => {
                 Ok(Descriptor::And(
-                    Box::new(left.instantiate(keymap)?),
-                    Box::new(right.instantiate(keymap)?)
+                    Box::new(left.translate_inner(f)?),
+                    Box::new(right.translate_inner(f)?)
                 ))
             }
             Descriptor::Or(ref left, ref right) => {
                 Ok(Descriptor::Or(
-                    Box::new(left.instantiate(keymap)?),
-                    Box::new(right.instantiate(keymap)?)
+                    Box::new(left.translate_inner(f)?),
+                    Box::new(right.translate_inner(f)?)
                 ))
             }
             Descriptor::AsymmetricOr(ref left, ref right) => {
                 Ok(Descriptor::AsymmetricOr(
-                    Box::new(left.instantiate(keymap)?),
-                    Box::new(right.instantiate(keymap)?)
+                    Box::new(left.translate_inner(f)?),
+                    Box::new(right.translate_inner(f)?)
+                ))
+            }
+            Descriptor::WeightedOr(left_odds, ref left, right_odds, ref right) => {
+                Ok(Descriptor::WeightedOr(
+                    left_odds,
+                    Box::new(left.translate_inner(f)?),
+                    right_odds,
+                    Box::new(right.translate_inner(f)?)
                 ))
             }
             Descriptor::Time(n) => Ok(Descriptor::Time(n)),
-            Descriptor::Wpkh(ref pk) => {
-                let secp_pk = pk.instantiate(keymap.get(pk))?;
-                Ok(Descriptor::Wpkh(secp_pk))
+            Descriptor::RawVerify(ref script) => Ok(Descriptor::RawVerify(script.clone())),
+            #[cfg(feature = "elements")]
+            Descriptor::SigFromStack(ref pk, msg) => Ok(Descriptor::SigFromStack(f(pk)?, msg)),
+            Descriptor::Wpkh(ref pk) => Ok(Descriptor::Wpkh(f(pk)?)),
+            Descriptor::Sh(ref desc) => Ok(Descriptor::Sh(Box::new(desc.translate_inner(f)?))),
+            Descriptor::Wsh(ref desc) => Ok(Descriptor::Wsh(Box::new(desc.translate_inner(f)?))),
+            Descriptor::Combo(ref pk) => Ok(Descriptor::Combo(f(pk)?)),
+            Descriptor::Addr(ref addr) => Ok(Descriptor::Addr(addr.clone())),
+            Descriptor::Raw(ref script) => Ok(Descriptor::Raw(script.clone())),
+            Descriptor::OpReturn(ref data) => Ok(Descriptor::OpReturn(data.clone())),
+        }
+    }
+
+    /// Whether every way of satisfying this descriptor also satisfies `other`. Only the
+    /// spending conditions are compared, so differences in tree shape, in the choice of
+    /// wrapper or between `pk` and `pkh` do not matter; a relative locktime entails any
+    /// smaller locktime of the same type.
+    pub fn entails(&self, other: &Descriptor<P>) -> bool {
+        let ours = Semantic::from_descriptor(self);
+        let theirs = Semantic::from_descriptor(other);
+        ours.sufficient_sets().iter().all(|set| theirs.satisfied_by(set))
+    }
+
+    /// Serializes the descriptor with its checksum appended, in the form accepted by
+    /// Bitcoin Core
+    pub fn to_string_with_checksum(&self) -> String {
+        let s = self.to_string();
+        let sum = checksum::compute(&s).expect("descriptor serialization uses only checksum charset");
+        format!("{}#{}", s, sum)
+    }
+
+    /// Serializes the descriptor with each key found in `aliases` replaced by its name,
+    /// such as `and(pk(CEO),or(pk(CFO),time(1000)))`, for people to review a policy
+    /// without comparing key hex. Other keys are written out as usual. The result is
+    /// for display only, and in general does not parse back.
+    pub fn display_with_aliases(&self, aliases: &HashMap<P, String>) -> String {
+        Aliased { desc: self, aliases }.to_string()
+    }
+
+    /// Whether this descriptor and `other` can be satisfied in exactly the same ways,
+    /// in the sense of `entails`
+    pub fn is_equivalent(&self, other: &Descriptor<P>) -> bool {
+        self.entails(other) && other.entails(self)
+    }
+
+    /// Conjunction of this descriptor and `other`
+    pub fn and_with(self, other: Descriptor<P>) -> Descriptor<P> {
+        Descriptor::And(Box::new(self), Box::new(other))
+    }
+
+    /// Disjunction of this descriptor and `other`
+    pub fn or_with(self, other: Descriptor<P>) -> Descriptor<P> {
+        Descriptor::Or(Box::new(self), Box::new(other))
+    }
+
+    /// Threshold requiring any `k` of `subs`
+    pub fn threshold_of(k: usize, subs: Vec<Descriptor<P>>) -> Descriptor<P> {
+        Descriptor::Threshold(k, subs)
+    }
+
+    /// Rewrites the descriptor into an equivalent one with less redundancy, for
+    /// policies built up programmatically. Nested conjunctions, and nested
+    /// disjunctions, are flattened (including thresholds of `n` of `n` and of 1 of `n`)
+    /// and their duplicate operands removed, so that `or(X,X)` becomes `X`. Binary
+    /// conjunctions and disjunctions are rebuilt as right-nested chains; thresholds
+    /// stay thresholds. Other thresholds, costing annotations and wrappers are kept,
+    /// though their children are simplified.
+    pub fn simplify(self) -> Descriptor<P> {
+        match self {
+            Descriptor::And(left, right) => {
+                let mut ops = vec![];
+                left.simplify().collect_operands(true, &mut ops);
+                right.simplify().collect_operands(true, &mut ops);
+                Descriptor::chain(ops, true)
             }
-            Descriptor::Sh(ref desc) => {
-                Ok(Descriptor::Sh(Box::new(desc.instantiate(keymap)?)))
+            Descriptor::Or(left, right) => {
+                let mut ops = vec![];
+                left.simplify().collect_operands(false, &mut ops);
+                right.simplify().collect_operands(false, &mut ops);
+                Descriptor::chain(ops, false)
             }
-            Descriptor::Wsh(ref desc) => {
-                Ok(Descriptor::Wsh(Box::new(desc.instantiate(keymap)?)))
+            Descriptor::Threshold(k, subs) => {
+                let n = subs.len();
+                let subs = subs.into_iter().map(Descriptor::simplify);
+                if n == 0 || (k != n && k != 1) {
+                    return Descriptor::Threshold(k, subs.collect());
+                }
+                let mut ops = vec![];
+                for sub in subs {
+                    sub.collect_operands(k == n, &mut ops);
+                }
+                if ops.len() == 1 {
+                    ops.pop().expect("one operand")
+                } else if k == n {
+                    Descriptor::Threshold(ops.len(), ops)
+                } else {
+                    Descriptor::Threshold(1, ops)
+                }
+            }
+            Descriptor::AsymmetricOr(left, right) => {
+                let (left, right) = (left.simplify(), right.simplify());
+                if left.to_string() == right.to_string() {
+                    left
+                } else {
+                    Descriptor::AsymmetricOr(Box::new(left), Box::new(right))
+                }
+            }
+            Descriptor::WeightedOr(left_odds, left, right_odds, right) => {
+                let (left, right) = (left.simplify(), right.simplify());
+                if left.to_string() == right.to_string() {
+                    left
+                } else {
+                    Descriptor::WeightedOr(left_odds, Box::new(left), right_odds, Box::new(right))
+                }
+            }
+            Descriptor::Sh(sub) => Descriptor::Sh(Box::new(sub.simplify())),
+            Descriptor::Wsh(sub) => Descriptor::Wsh(Box::new(sub.simplify())),
+            desc => desc,
+        }
+    }
+
+    /// Folds away the parts of the descriptor which impose no condition: `time(0)`
+    /// operands of conjunctions and thresholds, which require no relative locktime,
+    /// and thresholds and multisigs of 1 of 1, which are just their one operand.
+    /// Unlike `simplify`, nothing else is rewritten, so that compiling the folded
+    /// descriptor makes the same choices as for the original, apart from the folded
+    /// parts. The compiler does this before compiling.
+    pub fn fold_constants(self) -> Descriptor<P> {
+        match self {
+            Descriptor::Multi(k, mut keys) => {
+                if k == 1 && keys.len() == 1 {
+                    Descriptor::Key(keys.pop().expect("one key"))
+                } else {
+                    Descriptor::Multi(k, keys)
+                }
+            }
+            Descriptor::SortedMulti(k, mut keys) => {
+                if k == 1 && keys.len() == 1 {
+                    Descriptor::Key(keys.pop().expect("one key"))
+                } else {
+                    Descriptor::SortedMulti(k, keys)
+                }
+            }
+            Descriptor::Threshold(mut k, subs) => {
+                let mut folded = Vec::with_capacity(subs.len());
+                for sub in subs {
+                    match sub.fold_constants() {
+                        // Always satisfied, so counts towards the threshold for free
+                        Descriptor::Time(0) if k > 1 => k -= 1,
+                        sub => folded.push(sub),
+                    }
+                }
+                if k == 1 && folded.len() == 1 {
+                    folded.pop().expect("one operand")
+                } else {
+                    Descriptor::Threshold(k, folded)
+                }
+            }
+            Descriptor::And(left, right) => match (left.fold_constants(), right.fold_constants()) {
+                (Descriptor::Time(0), other) | (other, Descriptor::Time(0)) => other,
+                (left, right) => left.and_with(right),
+            },
+            Descriptor::Or(left, right) => left.fold_constants().or_with(right.fold_constants()),
+            Descriptor::AsymmetricOr(left, right) => {
+                Descriptor::AsymmetricOr(Box::new(left.fold_constants()), Box::new(right.fold_constants()))
+            }
+            Descriptor::WeightedOr(left_odds, left, right_odds, right) => Descriptor::WeightedOr(
+                left_odds,
+                Box::new(left.fold_constants()),
+                right_odds,
+                Box::new(right.fold_constants()),
+            ),
+            Descriptor::Sh(sub) => Descriptor::Sh(Box::new(sub.fold_constants())),
+            Descriptor::Wsh(sub) => Descriptor::Wsh(Box::new(sub.fold_constants())),
+            desc => desc,
+        }
+    }
+
+    /// Appends the operands of a simplified conjunction (if `is_and`) or disjunction
+    /// (otherwise) to `ops`, skipping any which are already there
+    fn collect_operands(self, is_and: bool, ops: &mut Vec<Descriptor<P>>) {
+        let is_op = match self {
+            Descriptor::And(..) => is_and,
+            Descriptor::Or(..) => !is_and,
+            Descriptor::Threshold(k, ref subs) => {
+                !subs.is_empty() && if is_and { k == subs.len() } else { k == 1 }
+            }
+            _ => false,
+        };
+        if !is_op {
+            let s = self.to_string();
+            if !ops.iter().any(|op| op.to_string() == s) {
+                ops.push(self);
+            }
+            return;
+        }
+        match self {
+            Descriptor::And(left, right) | Descriptor::Or(left, right) => {
+                left.collect_operands(is_and, ops);
+                right.collect_operands(is_and, ops);
+            }
+            Descriptor::Threshold(_, subs) => {
+                for sub in subs {
+                    sub.collect_operands(is_and, ops);
+                }
+            }
+            _ => unreachable!("only conjunctions and disjunctions have operands"),
+        }
+    }
+
+    /// Combines a nonempty list of operands into a right-nested chain of conjunctions
+    /// (if `is_and`) or disjunctions (otherwise)
+    fn chain(mut ops: Vec<Descriptor<P>>, is_and: bool) -> Descriptor<P> {
+        let mut ret = ops.pop().expect("nonempty operand list");
+        while let Some(op) = ops.pop() {
+            ret = if is_and { op.and_with(ret) } else { op.or_with(ret) };
+        }
+        ret
+    }
+
+    /// Rewrites the descriptor into a normal form, so that descriptors differing only
+    /// in the order of commutative operands, or in how their conjunctions and
+    /// disjunctions are nested, canonicalize to the same descriptor. On top of
+    /// `simplify`, the operands of conjunctions, disjunctions and thresholds are
+    /// sorted by their string form, as are the branches of weighted disjunctions.
+    /// The keys of multisigs are left in order, since their order is part of the
+    /// script; use `sortedmulti` where it should not matter.
+    pub fn canonicalize(self) -> Descriptor<P> {
+        let mut ret = self.simplify().sort_operands();
+        // Sorting may make operands equal which were not, so simplify again until
+        // nothing changes
+        loop {
+            let before = ret.to_string();
+            ret = ret.simplify().sort_operands();
+            if ret.to_string() == before {
+                return ret;
+            }
+        }
+    }
+
+    /// Identifier of the policy of the descriptor, which is the SHA256 of the string
+    /// form of its canonicalization. Parties who want to check that they are about
+    /// to compile the same policy can compare these.
+    pub fn policy_id(&self) -> sha256::Hash where P: Clone {
+        let copy: Descriptor<P> = self.translate(|pk| Ok::<P, ()>(pk.clone()))
+            .expect("copying keys cannot fail");
+        sha256::Hash::from_data(copy.canonicalize().to_string().as_bytes())
+    }
+
+    /// Sorts the operands of the commutative nodes of a simplified descriptor
+    fn sort_operands(self) -> Descriptor<P> {
+        match self {
+            Descriptor::And(..) | Descriptor::Or(..) => {
+                let is_and = matches!(self, Descriptor::And(..));
+                let mut ops = vec![];
+                self.collect_operands(is_and, &mut ops);
+                let mut ops = Descriptor::sorted(ops);
+                ops.dedup_by_key(|op| op.to_string());
+                Descriptor::chain(ops, is_and)
+            }
+            Descriptor::Threshold(k, subs) => Descriptor::Threshold(k, Descriptor::sorted(subs)),
+            Descriptor::AsymmetricOr(left, right) => {
+                Descriptor::AsymmetricOr(Box::new(left.sort_operands()), Box::new(right.sort_operands()))
+            }
+            Descriptor::WeightedOr(left_odds, left, right_odds, right) => {
+                let (left, right) = (left.sort_operands(), right.sort_operands());
+                if left.to_string() > right.to_string() {
+                    Descriptor::WeightedOr(right_odds, Box::new(right), left_odds, Box::new(left))
+                } else {
+                    Descriptor::WeightedOr(left_odds, Box::new(left), right_odds, Box::new(right))
+                }
+            }
+            Descriptor::Sh(sub) => Descriptor::Sh(Box::new(sub.sort_operands())),
+            Descriptor::Wsh(sub) => Descriptor::Wsh(Box::new(sub.sort_operands())),
+            desc => desc,
+        }
+    }
+
+    /// Sorts the given operands, after sorting their own operands, by string form
+    fn sorted(ops: Vec<Descriptor<P>>) -> Vec<Descriptor<P>> {
+        let mut ret: Vec<Descriptor<P>> = ops.into_iter().map(Descriptor::sort_operands).collect();
+        ret.sort_by_key(|a| a.to_string());
+        ret
+    }
+
+    /// Checks the descriptor for policies which are well-formed but nonsensical:
+    /// thresholds which can never or always be satisfied, multisigs which are not
+    /// standard, relative locktimes which do nothing, and conjunctions of height-based
+    /// and time-based locktimes, which no transaction input can satisfy. Returns the
+    /// first problem found.
+    pub fn sanity_check(&self) -> Result<(), SanityError> {
+        self.timelock_mixes().map(|_| ())
+    }
+
+    /// Checks the descriptor, returning the set of combinations of relative locktime
+    /// types which its satisfactions may require, as a mask of `TIMELOCK_*` bits
+    fn timelock_mixes(&self) -> Result<u8, SanityError> {
+        match *self {
+            Descriptor::Key(..) | Descriptor::KeyHash(..) |
+            Descriptor::Hash(..) | Descriptor::Hash160(..) |
+            Descriptor::Ripemd160(..) | Descriptor::Hash256(..) |
+            Descriptor::Wpkh(..) | Descriptor::Combo(..) |
+            Descriptor::Addr(..) | Descriptor::Raw(..) |
+            Descriptor::OpReturn(..) | Descriptor::RawVerify(..) => Ok(TIMELOCK_NONE),
+            #[cfg(feature = "elements")]
+            Descriptor::SigFromStack(..) => Ok(TIMELOCK_NONE),
+            Descriptor::Multi(k, ref keys) | Descriptor::SortedMulti(k, ref keys) |
+            Descriptor::MultiA(k, ref keys) => {
+                if k == 0 || k > keys.len() {
+                    return Err(SanityError::BadThreshold(k, keys.len()));
+                }
+                if keys.len() > MAX_MULTISIG_KEYS {
+                    return Err(SanityError::TooManyKeys(keys.len()));
+                }
+                for (i, key) in keys.iter().enumerate() {
+                    if keys[..i].contains(key) {
+                        return Err(SanityError::DuplicateKey(self.to_string()));
+                    }
+                }
+                Ok(TIMELOCK_NONE)
+            }
+            Descriptor::Aggregate(ref keys) => {
+                // there is no limit on the number of keys, only one of which appears in script
+                if keys.is_empty() {
+                    return Err(SanityError::BadThreshold(0, 0));
+                }
+                for (i, key) in keys.iter().enumerate() {
+                    if keys[..i].contains(key) {
+                        return Err(SanityError::DuplicateKey(self.to_string()));
+                    }
+                }
+                Ok(TIMELOCK_NONE)
             }
+            Descriptor::Time(n) => {
+                if n == 0 || n >= SEQUENCE_DISABLE_FLAG {
+                    Err(SanityError::BadTimelock(n))
+                } else if n & SEQUENCE_TYPE_FLAG == 0 {
+                    Ok(TIMELOCK_HEIGHT)
+                } else {
+                    Ok(TIMELOCK_TIME)
+                }
+            }
+            Descriptor::Threshold(k, ref subs) => {
+                if k == 0 || k > subs.len() {
+                    return Err(SanityError::BadThreshold(k, subs.len()));
+                }
+                // mixes[j] are the combinations possible with exactly j satisfied subs
+                let mut mixes = vec![0; k + 1];
+                mixes[0] = TIMELOCK_NONE;
+                for sub in subs {
+                    let sub_mix = sub.timelock_mixes()?;
+                    for j in (1..k + 1).rev() {
+                        mixes[j] |= join_timelock_mixes(mixes[j - 1], sub_mix);
+                    }
+                }
+                self.check_timelock_mix(mixes[k])
+            }
+            Descriptor::And(ref left, ref right) => {
+                let mix = join_timelock_mixes(left.timelock_mixes()?, right.timelock_mixes()?);
+                self.check_timelock_mix(mix)
+            }
+            Descriptor::Or(ref left, ref right) |
+            Descriptor::AsymmetricOr(ref left, ref right) => {
+                Ok(left.timelock_mixes()? | right.timelock_mixes()?)
+            }
+            Descriptor::WeightedOr(left_odds, ref left, right_odds, ref right) => {
+                if left_odds == 0 && right_odds == 0 {
+                    return Err(SanityError::ZeroOdds(self.to_string()));
+                }
+                Ok(left.timelock_mixes()? | right.timelock_mixes()?)
+            }
+            Descriptor::Sh(ref sub) | Descriptor::Wsh(ref sub) => sub.timelock_mixes(),
+        }
+    }
+
+    /// Rejects a conjunction which may require both types of relative locktime
+    fn check_timelock_mix(&self, mix: u8) -> Result<u8, SanityError> {
+        if mix & TIMELOCK_BOTH == 0 {
+            Ok(mix)
+        } else {
+            Err(SanityError::MixedTimelocks(self.to_string()))
         }
     }
 
     fn from_tree<'a>(top: &FunctionTree<'a>) -> Result<Descriptor<P>, Error> {
-        match (top.name, top.args.len() as u32) {
+        Descriptor::from_named_tree(top.name, top)
+    }
+
+    /// Parses a function tree as a descriptor, using `name` in place of the tree's own
+    /// name (which may carry a prefix such as an odds annotation)
+    fn from_named_tree<'a>(name: &str, top: &FunctionTree<'a>) -> Result<Descriptor<P>, Error> {
+        match (name, top.args.len() as u32) {
             ("pk", 1) => {
                 let pk = &top.args[0];
                 if pk.args.is_empty() {
@@ -194,7 +770,7 @@ impl<P: PublicKey> Descriptor<P> {
                     Err(errorize(pk.args[0].name))
                 }
             }
-            ("multi", nkeys) => {
+            ("multi", nkeys) | ("sortedmulti", nkeys) | ("multi_a", nkeys) => {
                 for arg in &top.args {
                     if !arg.args.is_empty() {
                         return Err(errorize(arg.args[0].name));
@@ -210,15 +786,57 @@ impl<P: PublicKey> Descriptor<P> {
                 for arg in &top.args[1..] {
                     keys.push(P::from_str(arg.name)?);
                 }
-                Ok(Descriptor::Multi(thresh as usize, keys))
+                match name {
+                    "multi" => Ok(Descriptor::Multi(thresh as usize, keys)),
+                    "sortedmulti" => Ok(Descriptor::SortedMulti(thresh as usize, keys)),
+                    _ => Ok(Descriptor::MultiA(thresh as usize, keys)),
+                }
+            }
+            ("musig", _) => {
+                let mut keys = Vec::with_capacity(top.args.len());
+                for arg in &top.args {
+                    if !arg.args.is_empty() {
+                        return Err(errorize(arg.args[0].name));
+                    }
+                    keys.push(P::from_str(arg.name)?);
+                }
+                Ok(Descriptor::Aggregate(keys))
             }
             ("hash", 1) => {
                 let hash_t = &top.args[0];
                 if hash_t.args.is_empty() {
-                    if let Ok(hash) = Sha256dHash::from_hex(hash_t.args[0].name) {
+                    if let Ok(hash) = sha256::Hash::from_hex(hash_t.name) {
                         Ok(Descriptor::Hash(hash))
                     } else {
-                        Err(errorize(hash_t.args[0].name))
+                        Err(errorize(hash_t.name))
+                    }
+                } else {
+                    Err(errorize(hash_t.args[0].name))
+                }
+            }
+            ("hash160", 1) => {
+                let hash_t = &top.args[0];
+                if hash_t.args.is_empty() {
+                    Ok(Descriptor::Hash160(Hash160::from(&parse_hash20(hash_t.name)?[..])))
+                } else {
+                    Err(errorize(hash_t.args[0].name))
+                }
+            }
+            ("ripemd160", 1) => {
+                let hash_t = &top.args[0];
+                if hash_t.args.is_empty() {
+                    Ok(Descriptor::Ripemd160(Ripemd160Hash::from_slice(&parse_hash20(hash_t.name)?[..]).expect("20-byte hash")))
+                } else {
+                    Err(errorize(hash_t.args[0].name))
+                }
+            }
+            ("hash256", 1) => {
+                let hash_t = &top.args[0];
+                if hash_t.args.is_empty() {
+                    if let Ok(hash) = Sha256dHash::from_hex(hash_t.name) {
+                        Ok(Descriptor::Hash256(hash))
+                    } else {
+                        Err(errorize(hash_t.name))
                     }
                 } else {
                     Err(errorize(hash_t.args[0].name))
@@ -227,11 +845,35 @@ impl<P: PublicKey> Descriptor<P> {
             ("time", 1) => {
                 let time_t = &top.args[0];
                 if time_t.args.is_empty() {
-                    Ok(Descriptor::Time(parse_num(time_t.args[0].name)?))
+                    Ok(Descriptor::Time(parse_num(time_t.name)?))
                 } else {
                     Err(errorize(time_t.args[0].name))
                 }
             }
+            ("raw_verify", 1) => {
+                let script = &top.args[0];
+                if script.args.is_empty() {
+                    hex_bytes(script.name)
+                        .map(|bytes| Descriptor::RawVerify(Script::from(bytes)))
+                        .map_err(|_| errorize(script.name))
+                } else {
+                    Err(errorize(script.args[0].name))
+                }
+            }
+            #[cfg(feature = "elements")]
+            ("csfs", 2) => {
+                let (pk, msg) = (&top.args[0], &top.args[1]);
+                if !pk.args.is_empty() {
+                    return Err(errorize(pk.args[0].name));
+                }
+                if !msg.args.is_empty() {
+                    return Err(errorize(msg.args[0].name));
+                }
+                match sha256::Hash::from_hex(msg.name) {
+                    Ok(hash) => Ok(Descriptor::SigFromStack(P::from_str(pk.name)?, hash)),
+                    Err(_) => Err(errorize(msg.name)),
+                }
+            }
             ("thresh", nsubs) => {
                 if !top.args[0].args.is_empty() {
                     return Err(errorize(top.args[0].args[0].name));
@@ -254,39 +896,443 @@ impl<P: PublicKey> Descriptor<P> {
                     Box::new(Descriptor::from_tree(&top.args[1])?),
                 ))
             }
-            ("or", 2) => {
-                Ok(Descriptor::Or(
-                    Box::new(Descriptor::from_tree(&top.args[0])?),
-                    Box::new(Descriptor::from_tree(&top.args[1])?),
-                ))
+            ("or", 2) => {
+                let left = &top.args[0];
+                let right = &top.args[1];
+                match (split_odds(left.name)?, split_odds(right.name)?) {
+                    (None, None) => {
+                        Ok(Descriptor::Or(
+                            Box::new(Descriptor::from_tree(left)?),
+                            Box::new(Descriptor::from_tree(right)?),
+                        ))
+                    }
+                    (Some((left_odds, left_name)), Some((right_odds, right_name))) => {
+                        if left_odds == 0 && right_odds == 0 {
+                            return Err(errorize(top.name));
+                        }
+                        Ok(Descriptor::WeightedOr(
+                            left_odds as usize,
+                            Box::new(Descriptor::from_named_tree(left_name, left)?),
+                            right_odds as usize,
+                            Box::new(Descriptor::from_named_tree(right_name, right)?),
+                        ))
+                    }
+                    // Either both branches are annotated or neither is
+                    (None, Some(..)) => Err(errorize(left.name)),
+                    (Some(..), None) => Err(errorize(right.name)),
+                }
+            }
+            ("aor", 2) => {
+                Ok(Descriptor::AsymmetricOr(
+                    Box::new(Descriptor::from_tree(&top.args[0])?),
+                    Box::new(Descriptor::from_tree(&top.args[1])?),
+                ))
+            }
+            ("wpkh", 1) => {
+                let pk = &top.args[0];
+                if pk.args.is_empty() {
+                    Ok(Descriptor::Wpkh(P::from_str(pk.name)?))
+                } else {
+                    Err(errorize(pk.args[0].name))
+                }
+            }
+            ("sh", 1) => {
+                let sub = Descriptor::from_tree(&top.args[0])?;
+                Ok(Descriptor::Sh(Box::new(sub)))
+            }
+            ("wsh", 1) => {
+                let sub = Descriptor::from_tree(&top.args[0])?;
+                Ok(Descriptor::Wsh(Box::new(sub)))
+            }
+            ("combo", 1) => {
+                let pk = &top.args[0];
+                if pk.args.is_empty() {
+                    Ok(Descriptor::Combo(P::from_str(pk.name)?))
+                } else {
+                    Err(errorize(pk.args[0].name))
+                }
+            }
+            ("addr", 1) => {
+                let addr = &top.args[0];
+                if addr.args.is_empty() {
+                    Address::from_str(addr.name)
+                        .map(Descriptor::Addr)
+                        .map_err(|_| errorize(addr.name))
+                } else {
+                    Err(errorize(addr.args[0].name))
+                }
+            }
+            ("raw", 1) => {
+                let script = &top.args[0];
+                if script.args.is_empty() {
+                    hex_bytes(script.name)
+                        .map(|bytes| Descriptor::Raw(Script::from(bytes)))
+                        .map_err(|_| errorize(script.name))
+                } else {
+                    Err(errorize(script.args[0].name))
+                }
+            }
+            ("opreturn", 1) => {
+                let data = &top.args[0];
+                if data.args.is_empty() {
+                    hex_bytes(data.name)
+                        .map(Descriptor::OpReturn)
+                        .map_err(|_| errorize(data.name))
+                } else {
+                    Err(errorize(data.args[0].name))
+                }
+            }
+            _ => Err(errorize(top.name))
+        }
+    }
+}
+
+/// Bit of a `CHECKSEQUENCEVERIFY` value which selects time-based rather than
+/// height-based locks
+pub(crate) const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+/// Bit of a `CHECKSEQUENCEVERIFY` value which disables the relative locktime
+pub(crate) const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+/// Bits of a `CHECKSEQUENCEVERIFY` value which hold the locktime itself, in blocks or
+/// in units of 512 seconds depending on `SEQUENCE_TYPE_FLAG`
+pub(crate) const SEQUENCE_LOCKTIME_MASK: u32 = 0xffff;
+/// Maximum number of keys in a standard `CHECKMULTISIG`
+const MAX_MULTISIG_KEYS: usize = 20;
+
+/// Satisfaction requires no relative locktime
+pub(crate) const TIMELOCK_NONE: u8 = 1 << 0;
+/// Satisfaction requires a height-based relative locktime
+pub(crate) const TIMELOCK_HEIGHT: u8 = 1 << 1;
+/// Satisfaction requires a time-based relative locktime
+pub(crate) const TIMELOCK_TIME: u8 = 1 << 2;
+/// Satisfaction requires both types of relative locktime
+pub(crate) const TIMELOCK_BOTH: u8 = 1 << 3;
+
+/// Combines the possible locktime requirements of two conditions which must both
+/// be satisfied
+pub(crate) fn join_timelock_mixes(left: u8, right: u8) -> u8 {
+    let mut ret = 0;
+    for i in 0..4 {
+        for j in 0..4 {
+            // bit `i` stands for a combination which includes height-based locktimes
+            // if `i & 1` and time-based ones if `i & 2`
+            if left & (1 << i) != 0 && right & (1 << j) != 0 {
+                ret |= 1 << (i | j);
+            }
+        }
+    }
+    ret
+}
+
+/// The spending condition of a descriptor, with wrappers and costing annotations
+/// removed and nested conjunctions and disjunctions flattened
+#[derive(PartialEq)]
+enum Semantic<'a, P: 'a> {
+    Key(&'a P),
+    Hash(sha256::Hash),
+    Hash160(Hash160),
+    Ripemd160(Ripemd160Hash),
+    Hash256(Sha256dHash),
+    Time(u32),
+    /// Unknown condition, identified by the descriptor which imposes it
+    Opaque(String),
+    Threshold(usize, Vec<Semantic<'a, P>>),
+}
+
+impl<'a, P: PublicKey> Semantic<'a, P> {
+    fn from_descriptor(desc: &'a Descriptor<P>) -> Semantic<'a, P> {
+        match *desc {
+            Descriptor::Key(ref pk) | Descriptor::KeyHash(ref pk) | Descriptor::Wpkh(ref pk) => {
+                Semantic::Key(pk)
+            }
+            Descriptor::Multi(k, ref keys) | Descriptor::SortedMulti(k, ref keys) |
+            Descriptor::MultiA(k, ref keys) => {
+                Semantic::threshold(k, keys.iter().map(Semantic::Key).collect())
+            }
+            Descriptor::Aggregate(ref keys) => {
+                Semantic::threshold(keys.len(), keys.iter().map(Semantic::Key).collect())
+            }
+            Descriptor::Hash(hash) => Semantic::Hash(hash),
+            Descriptor::Hash160(hash) => Semantic::Hash160(hash),
+            Descriptor::Ripemd160(hash) => Semantic::Ripemd160(hash),
+            Descriptor::Hash256(hash) => Semantic::Hash256(hash),
+            Descriptor::Time(n) => Semantic::Time(n),
+            Descriptor::Threshold(k, ref subs) => {
+                Semantic::threshold(k, subs.iter().map(Semantic::from_descriptor).collect())
+            }
+            Descriptor::And(ref left, ref right) => {
+                Semantic::threshold(2, vec![Semantic::from_descriptor(left), Semantic::from_descriptor(right)])
+            }
+            Descriptor::Or(ref left, ref right) |
+            Descriptor::AsymmetricOr(ref left, ref right) |
+            Descriptor::WeightedOr(_, ref left, _, ref right) => {
+                Semantic::threshold(1, vec![Semantic::from_descriptor(left), Semantic::from_descriptor(right)])
+            }
+            Descriptor::Sh(ref sub) | Descriptor::Wsh(ref sub) => Semantic::from_descriptor(sub),
+            Descriptor::Combo(ref pk) => Semantic::Key(pk),
+            Descriptor::Addr(..) | Descriptor::Raw(..) |
+            Descriptor::OpReturn(..) | Descriptor::RawVerify(..) => Semantic::Opaque(desc.to_string()),
+            #[cfg(feature = "elements")]
+            Descriptor::SigFromStack(..) => Semantic::Opaque(desc.to_string()),
+        }
+    }
+
+    /// Builds a threshold, merging in any children which are conjunctions (if the
+    /// threshold is itself a conjunction) or disjunctions (likewise)
+    fn threshold(k: usize, subs: Vec<Semantic<'a, P>>) -> Semantic<'a, P> {
+        let is_and = k == subs.len();
+        let mut flat = Vec::with_capacity(subs.len());
+        for sub in subs {
+            let merge = match sub {
+                Semantic::Threshold(subk, ref subsubs) => {
+                    (is_and && subk == subsubs.len()) || (k == 1 && subk == 1)
+                }
+                _ => false,
+            };
+            if !merge {
+                flat.push(sub);
+            } else if let Semantic::Threshold(_, subsubs) = sub {
+                flat.extend(subsubs);
+            }
+        }
+
+        if flat.len() == 1 && k == 1 {
+            flat.pop().expect("nonempty")
+        } else if is_and {
+            Semantic::Threshold(flat.len(), flat)
+        } else {
+            Semantic::Threshold(k, flat)
+        }
+    }
+
+    /// Whether the policy is satisfied when exactly the conditions in `atoms` are met
+    fn satisfied_by(&self, atoms: &[&Semantic<'a, P>]) -> bool {
+        match *self {
+            Semantic::Time(n) => atoms.iter().any(|atom| match **atom {
+                Semantic::Time(m) => (m & SEQUENCE_TYPE_FLAG) == (n & SEQUENCE_TYPE_FLAG) && m >= n,
+                _ => false,
+            }),
+            Semantic::Threshold(k, ref subs) => {
+                subs.iter().filter(|sub| sub.satisfied_by(atoms)).count() >= k
+            }
+            _ => atoms.contains(&self),
+        }
+    }
+
+    /// Every set of conditions obtained by choosing `k` children of each threshold;
+    /// together these include every minimal satisfying set of the policy
+    fn sufficient_sets(&self) -> Vec<Vec<&Semantic<'a, P>>> {
+        match *self {
+            Semantic::Threshold(k, ref subs) => {
+                // `chosen[j]` holds the sets satisfying `j` of the children considered so far
+                let mut chosen = vec![vec![]; k + 1];
+                chosen[0].push(vec![]);
+                for sub in subs {
+                    let sub_sets = sub.sufficient_sets();
+                    for j in (1..k + 1).rev() {
+                        let mut extended = vec![];
+                        for set in &chosen[j - 1] {
+                            for sub_set in &sub_sets {
+                                let mut union: Vec<&Semantic<'a, P>> = Vec::clone(set);
+                                union.extend(sub_set.iter().cloned());
+                                extended.push(union);
+                            }
+                        }
+                        chosen[j].extend(extended);
+                    }
+                }
+                chosen.pop().expect("k + 1 entries")
+            }
+            _ => vec![vec![self]],
+        }
+    }
+}
+
+#[cfg(feature = "no-crypto")]
+impl Descriptor<RawKey> {
+    /// Checks that every key of the descriptor is a point on the curve, giving the
+    /// descriptor with its keys parsed
+    pub fn verify_keys(&self) -> Result<Descriptor<secp256k1::PublicKey>, Error> {
+        self.translate(|pk| pk.verify())
+    }
+}
+
+impl<P: PublicKey> Descriptor<Labeled<P>> {
+    /// Compile the descriptor, instantiating its keys with `keymap`, and report its
+    /// keys as `ParseTree::key_report` would, along with the label of each
+    pub fn key_report(&self, keymap: &HashMap<P, P::Aux>) -> Result<KeyReport, Error> {
+        let mut labels = HashMap::new();
+        let desc = self.translate(|pk| -> Result<secp256k1::PublicKey, Error> {
+            let key = pk.key.instantiate(keymap.get(&pk.key))?;
+            if let Some(ref label) = pk.label {
+                labels.insert(key, label.clone());
+            }
+            Ok(key)
+        })?;
+        let mut report = ParseTree::from_descriptor(&desc)?.0.key_report();
+        for source in &mut report.keys {
+            source.label = labels.get(&source.key).cloned();
+        }
+        Ok(report)
+    }
+}
+
+impl Descriptor<secp256k1::PublicKey> {
+    /// Computes the scriptpubkey of the descriptor. The `Sh`, `Wsh` and `Wpkh` wrappers
+    /// are translated to the corresponding hash-based outputs, `Addr` and `Raw` give
+    /// their scriptpubkey directly, `OpReturn` gives `OP_RETURN` followed by a push of
+    /// its data, and all other descriptors are compiled to a bare script. Fails for
+    /// `Combo`, which describes several scriptpubkeys; use `script_pubkeys` for those.
+    pub fn script_pubkey(&self) -> Result<Script, Error> {
+        match *self {
+            Descriptor::Addr(ref addr) => return Ok(addr.script_pubkey()),
+            Descriptor::Raw(ref script) => return Ok(script.clone()),
+            Descriptor::OpReturn(ref data) => {
+                return Ok(script::Builder::new()
+                    .push_opcode(opcodes::All::OP_RETURN)
+                    .push_slice(data)
+                    .into_script());
+            }
+            Descriptor::Combo(..) => return Err(Error::Unexpected(self.to_string())),
+            _ => {}
+        }
+
+        let (tree, wrapper) = ParseTree::from_descriptor(self)?;
+        let script = tree.serialize();
+        Ok(match wrapper {
+            Wrapper::Bare => script,
+            Wrapper::Sh => script.to_p2sh(),
+            Wrapper::Wsh => Address::p2wsh(&script, Network::Bitcoin).script_pubkey(),
+            Wrapper::ShWsh => Address::p2wsh(&script, Network::Bitcoin).script_pubkey().to_p2sh(),
+            Wrapper::Wpkh => p2wpkh_program(&script),
+            Wrapper::ShWpkh => p2wpkh_program(&script).to_p2sh(),
+        })
+    }
+
+    /// Computes every scriptpubkey described by the descriptor. This is the single
+    /// `script_pubkey` except for `Combo`, which gives P2PK, P2PKH, P2WPKH and
+    /// P2SH-P2WPKH outputs, in that order.
+    pub fn script_pubkeys(&self) -> Result<Vec<Script>, Error> {
+        match *self {
+            Descriptor::Combo(ref pk) => {
+                let mut ret = Vec::with_capacity(4);
+                ret.push(Descriptor::Key(*pk).script_pubkey()?);
+                ret.push(Descriptor::KeyHash(*pk).script_pubkey()?);
+                let wpkh = Descriptor::Wpkh(*pk);
+                ret.push(wpkh.script_pubkey()?);
+                ret.push(Descriptor::Sh(Box::new(wpkh)).script_pubkey()?);
+                Ok(ret)
+            }
+            _ => Ok(vec![self.script_pubkey()?]),
+        }
+    }
+
+    /// Computes the script which is actually executed when spending the output: the
+    /// witness script for `Wsh` (including `Sh(Wsh)`), the redeem script for `Sh`,
+    /// the implied pay-to-pubkey-hash script for `Wpkh`, and the scriptpubkey itself
+    /// for bare descriptors
+    pub fn witness_script(&self) -> Result<Script, Error> {
+        let (tree, _) = ParseTree::from_descriptor(self)?;
+        Ok(tree.serialize())
+    }
+
+    /// Computes the address corresponding to the descriptor, if it has one. Bare
+    /// descriptors other than a single `pkh` have no address form.
+    pub fn address(&self, network: Network) -> Result<Option<Address>, Error> {
+        match *self {
+            Descriptor::KeyHash(ref pk) => Ok(Some(Address::p2pkh(pk, network))),
+            Descriptor::Wpkh(ref pk) => Ok(Some(Address::p2wpkh(pk, network))),
+            Descriptor::Sh(ref desc) => {
+                let redeem_script = match **desc {
+                    Descriptor::Wpkh(..) | Descriptor::Wsh(..) => desc.script_pubkey()?,
+                    _ => self.witness_script()?,
+                };
+                Ok(Some(Address::p2sh(&redeem_script, network)))
             }
-            ("aor", 2) => {
-                Ok(Descriptor::AsymmetricOr(
-                    Box::new(Descriptor::from_tree(&top.args[0])?),
-                    Box::new(Descriptor::from_tree(&top.args[1])?),
-                ))
+            Descriptor::Wsh(..) => Ok(Some(Address::p2wsh(&self.witness_script()?, network))),
+            Descriptor::Addr(ref addr) => Ok(Some(addr.clone())),
+            Descriptor::Combo(..) | Descriptor::Raw(..) | Descriptor::OpReturn(..) => Ok(None),
+            _ => {
+                // Still reject descriptors with misplaced wrappers
+                ParseTree::from_descriptor(self)?;
+                Ok(None)
             }
-            ("wpkh", 1) => {
-                let pk = &top.args[0];
-                if pk.args.is_empty() {
-                    Ok(Descriptor::Wpkh(P::from_str(pk.name)?))
-                } else {
-                    Err(errorize(pk.args[0].name))
-                }
+        }
+    }
+
+    /// Computes the Electrum protocol script hash of the descriptor's scriptpubkey, by
+    /// which Electrum servers index outputs: the SHA256 of the scriptpubkey, as hex with
+    /// its bytes reversed. Fails for `Combo`, as for `script_pubkey`. The keys of the
+    /// descriptor are already instantiated, so there is no child index to derive; for
+    /// ranged descriptors use `Descriptor<DescriptorXPub>::electrum_scripthash`, which
+    /// derives child `index` first.
+    pub fn electrum_scripthash(&self) -> Result<String, Error> {
+        let hash = sha256::Hash::from_data(&self.script_pubkey()?[..]);
+        Ok(hash[..].iter().rev().map(|x| format!("{:02x}", x)).collect())
+    }
+
+    /// Computes an upper bound on the weight of the scriptSig and witness needed to
+    /// spend an output of this descriptor, including the length prefixes of the
+    /// scriptSig and of the witness stack. Signatures are assumed to be 73 bytes, as
+    /// in `ParseTree::max_satisfaction_size`.
+    pub fn max_satisfaction_weight(&self) -> Result<usize, Error> {
+        let (tree, wrapper) = ParseTree::from_descriptor(self)?;
+        let script_len = tree.serialize().len();
+        let sat_size = tree.max_satisfaction_size();
+        let sat_items = tree.max_satisfaction_items();
+
+        Ok(match wrapper {
+            Wrapper::Bare => 4 * (varint_len(sat_size) + sat_size),
+            Wrapper::Sh => {
+                let script_sig_len = sat_size + push_len(script_len) + script_len;
+                4 * (varint_len(script_sig_len) + script_sig_len)
             }
-            ("sh", 1) => {
-                let sub = Descriptor::from_tree(&top.args[0])?;
-                Ok(Descriptor::Sh(Box::new(sub)))
+            Wrapper::Wsh | Wrapper::ShWsh => {
+                // scriptSig is empty, or a push of the 34-byte witness program
+                let script_sig_len = if wrapper == Wrapper::Wsh { 0 } else { 35 };
+                4 * (1 + script_sig_len) +
+                    varint_len(sat_items + 1) + sat_size +
+                    varint_len(script_len) + script_len
             }
-            ("wsh", 1) => {
-                let sub = Descriptor::from_tree(&top.args[0])?;
-                Ok(Descriptor::Wsh(Box::new(sub)))
+            Wrapper::Wpkh | Wrapper::ShWpkh => {
+                // scriptSig is empty, or a push of the 22-byte witness program
+                let script_sig_len = if wrapper == Wrapper::Wpkh { 0 } else { 23 };
+                4 * (1 + script_sig_len) + varint_len(sat_items) + sat_size
             }
-            _ => Err(errorize(top.name))
-        }
+        })
+    }
+}
+
+/// Size of the encoding of `n` as a Bitcoin variable-length integer
+fn varint_len(n: usize) -> usize {
+    if n < 0xfd {
+        1
+    } else if n <= 0xffff {
+        3
+    } else {
+        5
+    }
+}
+
+/// Size of the push opcode(s) preceding an `n`-byte push in a script
+fn push_len(n: usize) -> usize {
+    if n < 76 {
+        1
+    } else if n < 0x100 {
+        2
+    } else {
+        3
     }
 }
 
+/// Converts the pay-to-pubkey-hash script of a `Wpkh` output to its witness program
+fn p2wpkh_program(script: &Script) -> Script {
+    // `DUP HASH160 <20-byte hash> EQUALVERIFY CHECKSIG`
+    script::Builder::new()
+        .push_int(0)
+        .push_slice(&script[3..23])
+        .into_script()
+}
+
 fn errorize(s: &str) -> Error {
     Error::Unexpected(s.to_owned())
 }
@@ -295,17 +1341,42 @@ fn parse_num(s: &str) -> Result<u32, Error> {
     u32::from_str(s).map_err(|_| errorize(s))
 }
 
+/// Splits an odds annotation such as the `9@` in `9@pk(...)` off a function name
+fn split_odds(s: &str) -> Result<Option<(u32, &str)>, Error> {
+    match s.find('@') {
+        Some(n) => Ok(Some((parse_num(&s[..n])?, &s[n + 1..]))),
+        None => Ok(None),
+    }
+}
+
+/// Parses a 20-byte hash, given in hex in the order that it appears in script
+fn parse_hash20(s: &str) -> Result<Vec<u8>, Error> {
+    match hex_bytes(s) {
+        Ok(ref bytes) if bytes.len() == 20 => Ok(bytes.clone()),
+        _ => Err(errorize(s)),
+    }
+}
+
+/// Writes a 20-byte hash in hex, in the order that it appears in script
+fn fmt_hash20(f: &mut fmt::Formatter, hash: &[u8]) -> fmt::Result {
+    for x in hash {
+        write!(f, "{:02x}", *x)?;
+    }
+    Ok(())
+}
+
 impl<P: PublicKey> FromStr for Descriptor<P> {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Descriptor<P>, Error> {
-        for ch in s.as_bytes() {
-            if *ch < 20 || *ch > 127 {
-                return Err(Error::Unprintable(*ch));
+        for ch in s.chars() {
+            if (ch as u32) < 20 || (ch as u32) > 127 {
+                return Err(Error::Unprintable(ch));
             }
         }
 
-        let (top, rem) = FunctionTree::from_slice(s)?;
+        let s = checksum::verify(s)?;
+        let (top, rem) = FunctionTree::from_slice(s, MAX_PARSE_DEPTH)?;
         if !rem.is_empty() {
             return Err(errorize(rem));
         }
@@ -315,52 +1386,158 @@ impl<P: PublicKey> FromStr for Descriptor<P> {
 
 impl <P: PublicKey> fmt::Display for Descriptor<P> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with(f, &<P as PublicKey>::fmt)
+    }
+}
+
+/// Displays a descriptor with some of its keys replaced by names
+struct Aliased<'a, P: 'a + PublicKey> {
+    desc: &'a Descriptor<P>,
+    aliases: &'a HashMap<P, String>,
+}
+
+impl<'a, P: PublicKey> fmt::Display for Aliased<'a, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let aliases = self.aliases;
+        self.desc.fmt_with(f, &|pk: &P, f: &mut fmt::Formatter| match aliases.get(pk) {
+            Some(name) => f.write_str(name),
+            None => pk.fmt(f),
+        })
+    }
+}
+
+impl<P: PublicKey> Descriptor<P> {
+    /// Writes out the descriptor, writing each key with `fmt_key`
+    fn fmt_with(&self, f: &mut fmt::Formatter, fmt_key: &dyn Fn(&P, &mut fmt::Formatter) -> fmt::Result) -> fmt::Result {
         match *self {
             Descriptor::Key(ref p) => {
                 f.write_str("pk(")?;
-                p.fmt(f)?;
+                fmt_key(p, f)?;
             }
             Descriptor::KeyHash(ref p) => {
                 f.write_str("pkh(")?;
-                p.fmt(f)?;
+                fmt_key(p, f)?;
             }
             Descriptor::Multi(k, ref keys) => {
                 write!(f, "multi({}", k)?;
                 for key in keys {
-                    key.fmt(f)?;
                     f.write_str(",")?;
+                    fmt_key(key, f)?;
+                }
+            }
+            Descriptor::SortedMulti(k, ref keys) => {
+                write!(f, "sortedmulti({}", k)?;
+                for key in keys {
+                    f.write_str(",")?;
+                    fmt_key(key, f)?;
+                }
+            }
+            Descriptor::MultiA(k, ref keys) => {
+                write!(f, "multi_a({}", k)?;
+                for key in keys {
+                    f.write_str(",")?;
+                    fmt_key(key, f)?;
+                }
+            }
+            Descriptor::Aggregate(ref keys) => {
+                f.write_str("musig(")?;
+                for (i, key) in keys.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    fmt_key(key, f)?;
                 }
             }
             Descriptor::Hash(hash) => {
                 write!(f, "hash({}", hash)?;
             }
+            Descriptor::Hash160(hash) => {
+                f.write_str("hash160(")?;
+                fmt_hash20(f, &hash[..])?;
+            }
+            Descriptor::Ripemd160(hash) => {
+                f.write_str("ripemd160(")?;
+                fmt_hash20(f, &hash[..])?;
+            }
+            Descriptor::Hash256(hash) => {
+                write!(f, "hash256({}", hash)?;
+            }
             Descriptor::Time(n) => {
                 write!(f, "time({}", n)?;
             }
+            Descriptor::RawVerify(ref script) => {
+                f.write_str("raw_verify(")?;
+                for x in &script[..] {
+                    write!(f, "{:02x}", *x)?;
+                }
+            }
+            #[cfg(feature = "elements")]
+            Descriptor::SigFromStack(ref p, msg) => {
+                f.write_str("csfs(")?;
+                fmt_key(p, f)?;
+                write!(f, ",{}", msg)?;
+            }
             Descriptor::Threshold(k, ref descs) => {
-                write!(f, "multi({}", k)?;
+                write!(f, "thresh({}", k)?;
                 for desc in descs {
-                    write!(f, "{},", desc)?;
+                    f.write_str(",")?;
+                    desc.fmt_with(f, fmt_key)?;
                 }
             }
             Descriptor::And(ref left, ref right) => {
-                write!(f, "and({}, {}", left, right)?;
+                write!(f, "and(")?;
+                left.fmt_with(f, fmt_key)?;
+                f.write_str(",")?;
+                right.fmt_with(f, fmt_key)?;
             }
             Descriptor::Or(ref left, ref right) => {
-                write!(f, "or({}, {}", left, right)?;
+                write!(f, "or(")?;
+                left.fmt_with(f, fmt_key)?;
+                f.write_str(",")?;
+                right.fmt_with(f, fmt_key)?;
             }
             Descriptor::AsymmetricOr(ref left, ref right) => {
-                write!(f, "aor({}, {}", left, right)?;
+                write!(f, "aor(")?;
+                left.fmt_with(f, fmt_key)?;
+                f.write_str(",")?;
+                right.fmt_with(f, fmt_key)?;
+            }
+            Descriptor::WeightedOr(left_odds, ref left, right_odds, ref right) => {
+                write!(f, "or({}@", left_odds)?;
+                left.fmt_with(f, fmt_key)?;
+                write!(f, ",{}@", right_odds)?;
+                right.fmt_with(f, fmt_key)?;
             }
             Descriptor::Wpkh(ref p) => {
                 f.write_str("wpkh(")?;
-                p.fmt(f)?;
+                fmt_key(p, f)?;
             }
             Descriptor::Sh(ref desc) => {
-                write!(f, "sh({}", desc)?;
+                f.write_str("sh(")?;
+                desc.fmt_with(f, fmt_key)?;
             }
             Descriptor::Wsh(ref desc) => {
-                write!(f, "wsh({}", desc)?;
+                f.write_str("wsh(")?;
+                desc.fmt_with(f, fmt_key)?;
+            }
+            Descriptor::Combo(ref p) => {
+                f.write_str("combo(")?;
+                fmt_key(p, f)?;
+            }
+            Descriptor::Addr(ref addr) => {
+                write!(f, "addr({}", addr.to_string())?;
+            }
+            Descriptor::Raw(ref script) => {
+                f.write_str("raw(")?;
+                for x in &script[..] {
+                    write!(f, "{:02x}", *x)?;
+                }
+            }
+            Descriptor::OpReturn(ref data) => {
+                f.write_str("opreturn(")?;
+                for x in data {
+                    write!(f, "{:02x}", *x)?;
+                }
             }
         }
         f.write_str(")")
@@ -373,7 +1550,9 @@ struct FunctionTree<'a> {
 }
 
 impl<'a> FunctionTree<'a> {
-    fn from_slice(mut sl: &'a str) -> Result<(FunctionTree<'a>, &'a str), Error> {
+    /// Parses a function and its arguments, which may be nested no more than
+    /// `max_depth` deep
+    fn from_slice(mut sl: &'a str, max_depth: usize) -> Result<(FunctionTree<'a>, &'a str), Error> {
         enum Found { Nothing, Lparen(usize), Comma(usize), Rparen(usize) }
 
         let mut found = Found::Nothing;
@@ -401,6 +1580,9 @@ impl<'a> FunctionTree<'a> {
             }
             // Function call
             Found::Lparen(n) => {
+                if max_depth == 0 {
+                    return Err(Error::MaxRecursionDepthExceeded(MAX_PARSE_DEPTH));
+                }
                 let mut ret = FunctionTree {
                     name: &sl[..n],
                     args: vec![],
@@ -408,7 +1590,7 @@ impl<'a> FunctionTree<'a> {
 
                 sl = &sl[n + 1..];
                 loop {
-                    let (arg, new_sl) = FunctionTree::from_slice(sl)?;
+                    let (arg, new_sl) = FunctionTree::from_slice(sl, max_depth - 1)?;
                     ret.args.push(arg);
 
                     if new_sl.is_empty() {
@@ -436,8 +1618,17 @@ mod tests {
 
     use bitcoin::blockdata::opcodes;
     use bitcoin::blockdata::script::{self, Script};
-    use Descriptor;
+    use bitcoin::blockdata::transaction::SigHashType;
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::address::Address;
+    use bitcoin::util::hash::Hash160;
+    use parse::MAX_PARSE_DEPTH;
+    use satisfy::Older;
+    use {Descriptor, Error, SanityError};
     use ParseTree;
+    use super::Labeled;
+    #[cfg(feature = "no-crypto")]
+    use super::RawKey;
 
     fn pubkeys_and_a_sig(n: usize) -> (Vec<secp256k1::PublicKey>, secp256k1::Signature) {
         let mut ret = Vec::with_capacity(n);
@@ -451,25 +1642,223 @@ mod tests {
             let pk = secp256k1::PublicKey::from_secret_key(
                 &secp,
                 &secp256k1::SecretKey::from_slice(&secp, &sk[..]).expect("secret key"),
-            );
+            ).expect("signing context");
             ret.push(pk);
         }
         let sig = secp.sign(
             &secp256k1::Message::from_slice(&sk[..]).expect("secret key"),
             &secp256k1::SecretKey::from_slice(&secp, &sk[..]).expect("secret key"),
-        );
+        ).expect("signing context");
         (ret, sig)
     }
 
+    #[test]
+    fn equivalence() {
+        let (keys, _) = pubkeys_and_a_sig(3);
+        let pk = |i: usize| Box::new(Descriptor::Key(keys[i]));
+
+        // Tree shape, wrappers and key encodings do not matter
+        let nested = Descriptor::Wsh(Box::new(Descriptor::Or(
+            pk(0),
+            Box::new(Descriptor::AsymmetricOr(pk(1), Box::new(Descriptor::KeyHash(keys[2])))),
+        )));
+        let flat = Descriptor::Sh(Box::new(Descriptor::Multi(1, keys.clone())));
+        assert!(nested.is_equivalent(&flat));
+
+        let and = Descriptor::And(pk(0), Box::new(Descriptor::And(pk(1), pk(2))));
+        let thresh = Descriptor::Threshold(3, vec![*pk(2), *pk(1), *pk(0)]);
+        assert!(and.is_equivalent(&thresh));
+        assert!(and.entails(&flat));
+        assert!(!flat.entails(&and));
+
+        let two_of_three = Descriptor::Multi(2, keys.clone());
+        let expanded = Descriptor::Or(
+            Box::new(Descriptor::And(pk(0), Box::new(Descriptor::Or(pk(1), pk(2))))),
+            Box::new(Descriptor::And(pk(1), pk(2))),
+        );
+        assert!(two_of_three.is_equivalent(&expanded));
+        assert!(!two_of_three.is_equivalent(&Descriptor::And(pk(0), pk(1))));
+
+        // Longer locktimes entail shorter ones, but only of the same type
+        let long = Descriptor::And(pk(0), Box::new(Descriptor::Time(1000)));
+        let short = Descriptor::And(pk(0), Box::new(Descriptor::Time(100)));
+        let seconds = Descriptor::And(pk(0), Box::new(Descriptor::Time((1 << 22) | 1000)));
+        assert!(long.entails(&short));
+        assert!(!short.entails(&long));
+        assert!(!seconds.entails(&short));
+    }
+
+    #[test]
+    fn simplify() {
+        let (keys, _) = pubkeys_and_a_sig(3);
+        let pk = |i: usize| Descriptor::Key(keys[i]);
+
+        assert_eq!(pk(0).or_with(pk(0)).simplify().to_string(), pk(0).to_string());
+        let nested = pk(0).and_with(pk(1)).and_with(pk(2).and_with(pk(0)));
+        let simple = nested.simplify();
+        assert_eq!(simple.to_string(), pk(0).and_with(pk(1).and_with(pk(2))).to_string());
+
+        // Thresholds which are conjunctions or disjunctions absorb nested ones
+        let thresh = Descriptor::threshold_of(1, vec![pk(0).or_with(pk(1)), Descriptor::threshold_of(1, vec![pk(2), pk(1)])]);
+        assert_eq!(
+            thresh.simplify().to_string(),
+            Descriptor::threshold_of(1, vec![pk(0), pk(1), pk(2)]).to_string()
+        );
+        let single = Descriptor::threshold_of(2, vec![pk(0), pk(0).and_with(pk(0))]);
+        assert_eq!(single.simplify().to_string(), pk(0).to_string());
+
+        // Other thresholds keep their duplicates, which may matter
+        let two = Descriptor::threshold_of(2, vec![pk(0), pk(0), pk(1)]);
+        assert_eq!(two.simplify().to_string(), Descriptor::threshold_of(2, vec![pk(0), pk(0), pk(1)]).to_string());
+
+        let wrapped = Descriptor::Wsh(Box::new(Descriptor::AsymmetricOr(
+            Box::new(pk(1).or_with(pk(1))),
+            Box::new(pk(1)),
+        )));
+        assert_eq!(wrapped.simplify().to_string(), Descriptor::Wsh(Box::new(pk(1))).to_string());
+        assert!(ParseTree::compile(&pk(0).and_with(pk(0)).simplify()).unwrap().serialize().len() <
+                ParseTree::compile(&pk(0).and_with(pk(0))).unwrap().serialize().len());
+    }
+
+    #[test]
+    fn canonicalize() {
+        let (keys, _) = pubkeys_and_a_sig(4);
+        let pk = |i: usize| Descriptor::Key(keys[i]);
+
+        let one = pk(2).and_with(pk(1).or_with(pk(0))).and_with(pk(3));
+        let two = pk(3).and_with(pk(0).or_with(pk(1)).and_with(pk(2)));
+        assert_eq!(one.policy_id(), two.policy_id());
+        assert_eq!(one.canonicalize().to_string(), two.canonicalize().to_string());
+        let other = pk(3).or_with(pk(0).or_with(pk(1)).and_with(pk(2)));
+        assert!(other.policy_id() != pk(3).and_with(pk(0).or_with(pk(1)).and_with(pk(2))).policy_id());
+
+        // Operands which only become equal once sorted are merged
+        let merged = Descriptor::threshold_of(1, vec![pk(0).and_with(pk(1)), pk(1).and_with(pk(0))]);
+        assert_eq!(merged.canonicalize().to_string(), pk(0).and_with(pk(1)).canonicalize().to_string());
+
+        let thresh = |subs: Vec<Descriptor<secp256k1::PublicKey>>| Descriptor::threshold_of(2, subs);
+        assert_eq!(
+            thresh(vec![pk(2), pk(0), pk(1)]).policy_id(),
+            thresh(vec![pk(1), pk(2), pk(0)]).policy_id()
+        );
+        let weighted = |a: usize, b: usize| Descriptor::WeightedOr(a, Box::new(pk(a)), b, Box::new(pk(b)));
+        assert_eq!(weighted(1, 3).policy_id(), weighted(3, 1).policy_id());
+        assert!(weighted(1, 3).policy_id() != Descriptor::WeightedOr(3, Box::new(pk(1)), 1, Box::new(pk(3))).policy_id());
+
+        // Multisig key order is part of the script
+        let multi = |ks: Vec<usize>| Descriptor::Multi(2, ks.into_iter().map(|i| keys[i]).collect());
+        assert!(multi(vec![0, 1]).policy_id() != multi(vec![1, 0]).policy_id());
+    }
+
+    #[test]
+    fn key_labels() {
+        let (keys, _) = pubkeys_and_a_sig(3);
+        let labeled = |i: usize, label: Option<&str>| Labeled {
+            key: keys[i],
+            label: label.map(str::to_owned),
+        };
+        let desc = Descriptor::And(
+            Box::new(Descriptor::Key(labeled(0, Some("cold")))),
+            Box::new(Descriptor::Multi(1, vec![labeled(1, Some("hot")), labeled(2, None)])),
+        );
+        let s = desc.to_string();
+        assert!(s.starts_with("and(pk(cold:"));
+        assert!(s.contains(",multi(1,hot:"));
+        assert_eq!(Descriptor::<Labeled<secp256k1::PublicKey>>::from_str(&s).unwrap().to_string(), s);
+        assert!(Descriptor::<Labeled<secp256k1::PublicKey>>::from_str(&s.replace("hot:", "h@t:")).is_err());
+
+        let report = desc.key_report(&HashMap::new()).unwrap();
+        let label_of = |i: usize| {
+            report.keys.iter().find(|source| source.key == keys[i]).unwrap().label.clone()
+        };
+        assert_eq!(label_of(0), Some("cold".to_owned()));
+        assert_eq!(label_of(1), Some("hot".to_owned()));
+        assert_eq!(label_of(2), None);
+    }
+
+    #[cfg(feature = "no-crypto")]
+    #[test]
+    fn raw_keys() {
+        let (keys, _) = pubkeys_and_a_sig(2);
+        let desc = Descriptor::Multi(1, keys.clone());
+        let raw = Descriptor::<RawKey>::from_str(&desc.to_string()).unwrap();
+        assert_eq!(raw.to_string(), desc.to_string());
+        assert_eq!(raw.verify_keys().unwrap().to_string(), desc.to_string());
+
+        // Points off the curve parse, but fail verification
+        let off_curve = format!("pk(02{})", "00".repeat(32));
+        assert!(Descriptor::<secp256k1::PublicKey>::from_str(&off_curve).is_err());
+        let raw = Descriptor::<RawKey>::from_str(&off_curve).unwrap();
+        assert!(raw.verify_keys().is_err());
+        assert!(Descriptor::<RawKey>::from_str(&format!("pk(05{})", "00".repeat(32))).is_err());
+        assert_eq!(RawKey::from(keys[0]).verify(), Ok(keys[0]));
+    }
+
+    #[test]
+    fn uncompressed_keys() {
+        let (keys, _) = pubkeys_and_a_sig(1);
+        let hex: String = keys[0].serialize_uncompressed().iter().map(|x| format!("{:02x}", x)).collect();
+        match Descriptor::<secp256k1::PublicKey>::from_str(&format!("pk({})", hex)) {
+            Err(Error::Unexpected(ref s)) if s.starts_with("uncompressed key") => {}
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(desc) => panic!("parsed {}", desc),
+        }
+    }
+
+    #[test]
+    fn sanity_check() {
+        let (keys, _) = pubkeys_and_a_sig(21);
+        let pk = |i: usize| Box::new(Descriptor::Key(keys[i]));
+        let time = |n: u32| Box::new(Descriptor::Time(n));
+
+        let liquid = Descriptor::AsymmetricOr(
+            Box::new(Descriptor::Multi(3, keys[0..5].to_owned())),
+            Box::new(Descriptor::And(time(10000), Box::new(Descriptor::Multi(2, keys[5..8].to_owned())))),
+        );
+        assert_eq!(liquid.sanity_check(), Ok(()));
+
+        assert_eq!(Descriptor::Multi(0, keys[0..2].to_owned()).sanity_check(), Err(SanityError::BadThreshold(0, 2)));
+        assert_eq!(
+            Descriptor::Threshold(3, vec![*pk(0), *pk(1)]).sanity_check(),
+            Err(SanityError::BadThreshold(3, 2))
+        );
+        assert_eq!(Descriptor::Multi(1, keys.clone()).sanity_check(), Err(SanityError::TooManyKeys(21)));
+        match Descriptor::Multi(1, vec![keys[0], keys[1], keys[0]]).sanity_check() {
+            Err(SanityError::DuplicateKey(..)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(Descriptor::And(pk(0), time(0)).sanity_check(), Err(SanityError::BadTimelock(0)));
+        assert_eq!(Descriptor::And(pk(0), time(1 << 31)).sanity_check(), Err(SanityError::BadTimelock(1 << 31)));
+
+        // Height-based and time-based locktimes may be alternatives, but not both required
+        let seconds = (1 << 22) | 100;
+        assert_eq!(Descriptor::Or(time(100), time(seconds)).sanity_check(), Ok(()));
+        assert_eq!(
+            Descriptor::Threshold(1, vec![*pk(0), *time(100), *time(seconds)]).sanity_check(),
+            Ok(())
+        );
+        match Descriptor::And(pk(0), Box::new(Descriptor::Or(
+            Box::new(Descriptor::And(time(100), time(seconds))),
+            pk(1),
+        ))).sanity_check() {
+            Err(SanityError::MixedTimelocks(..)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        match Descriptor::Threshold(2, vec![*pk(0), *time(100), *time(seconds)]).sanity_check() {
+            Err(SanityError::MixedTimelocks(..)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
     #[test]
     fn compile() {
         let (keys, sig) = pubkeys_and_a_sig(10);
         let desc: Descriptor<secp256k1::PublicKey> = Descriptor::Time(100);
-        let pt = ParseTree::compile(&desc);
+        let pt = ParseTree::compile(&desc).unwrap();
         assert_eq!(pt.serialize(), Script::from(vec![0x01, 0x64, 0xb2]));
 
-        let desc = Descriptor::Key(keys[0].clone());
-        let pt = ParseTree::compile(&desc);
+        let desc = Descriptor::Key(keys[0]);
+        let pt = ParseTree::compile(&desc).unwrap();
         assert_eq!(
             pt.serialize(),
             script::Builder::new()
@@ -484,7 +1873,7 @@ mod tests {
             Box::new(Descriptor::Time(10000)),
             Box::new(Descriptor::Multi(2, keys[5..8].to_owned())),
         );
-        let pt = ParseTree::compile(&desc);
+        let pt = ParseTree::compile(&desc).unwrap();
         assert_eq!(
             pt.serialize(),
             script::Builder::new()
@@ -507,7 +1896,7 @@ mod tests {
                 Box::new(Descriptor::Multi(2, keys[5..8].to_owned())),
             )),
         );
-        let pt = ParseTree::compile(&desc);
+        let pt = ParseTree::compile(&desc).unwrap();
         assert_eq!(
             pt.serialize(),
             script::Builder::new()
@@ -538,48 +1927,163 @@ mod tests {
             &keys[0..8]
         );
 
-        let mut map = HashMap::new();
-        assert!(pt.satisfy(&map, &HashMap::new(), &HashMap::new(), 0).is_err());
+        let mut sigser = sig.serialize_der(&secp256k1::Secp256k1::without_caps());
+        sigser.push(0x01); // sighash_all
+
+        let mut map: HashMap<secp256k1::PublicKey, secp256k1::Signature> = HashMap::new();
+        assert!(pt.satisfy(&map).is_err());
 
-        map.insert(keys[0].clone(), sig.clone());
-        map.insert(keys[1].clone(), sig.clone());
-        assert!(pt.satisfy(&map, &HashMap::new(), &HashMap::new(), 0).is_err());
+        map.insert(keys[0], sig);
+        map.insert(keys[1], sig);
+        assert!(pt.satisfy(&map).is_err());
 
-        map.insert(keys[2].clone(), sig.clone());
+        map.insert(keys[2], sig);
         assert_eq!(
-            pt.satisfy(&map, &HashMap::new(), &HashMap::new(), 0).unwrap(),
+            pt.satisfy(&map).unwrap(),
             vec![
-                sig.serialize_der(&secp256k1::Secp256k1::without_caps()),
-                sig.serialize_der(&secp256k1::Secp256k1::without_caps()),
-                sig.serialize_der(&secp256k1::Secp256k1::without_caps()),
                 vec![],
+                sigser.clone(),
+                sigser.clone(),
+                sigser.clone(),
             ]
         );
 
-        map.insert(keys[5].clone(), sig.clone());
-        map.insert(keys[6].clone(), sig.clone());
+        map.insert(keys[5], sig);
+        map.insert(keys[6], sig);
         assert_eq!(
-            pt.satisfy(&map, &HashMap::new(), &HashMap::new(), 0).unwrap(),
+            pt.satisfy(&map).unwrap(),
             vec![
-                sig.serialize_der(&secp256k1::Secp256k1::without_caps()),
-                sig.serialize_der(&secp256k1::Secp256k1::without_caps()),
-                sig.serialize_der(&secp256k1::Secp256k1::without_caps()),
                 vec![],
+                sigser.clone(),
+                sigser.clone(),
+                sigser.clone(),
             ]
         );
 
         assert_eq!(
-            pt.satisfy(&map, &HashMap::new(), &HashMap::new(), 10000).unwrap(),
+            pt.satisfy(&(&map, Older(10000))).unwrap(),
             vec![
                 vec![],
+                sigser.clone(),
+                sigser.clone(),
                 vec![],
                 vec![],
                 vec![],
-                sig.serialize_der(&secp256k1::Secp256k1::without_caps()),
-                sig.serialize_der(&secp256k1::Secp256k1::without_caps()),
                 vec![],
             ]
         );
+
+        // Non-default sighash types are serialized along with the signature
+        let mut acp_sigser = sig.serialize_der(&secp256k1::Secp256k1::without_caps());
+        acp_sigser.push(0x81); // sighash_all|anyonecanpay
+
+        let pt = ParseTree::compile(&Descriptor::Key(keys[0])).unwrap();
+        let mut map: HashMap<secp256k1::PublicKey, (secp256k1::Signature, SigHashType)> = HashMap::new();
+        map.insert(keys[0], (sig, SigHashType::AllPlusAnyoneCanPay));
+        assert_eq!(pt.satisfy(&map).unwrap(), vec![acp_sigser]);
+    }
+
+    #[test]
+    fn max_satisfaction_weight() {
+        let (keys, _) = pubkeys_and_a_sig(1);
+
+        // 73-byte signature, 33-byte key, each with a length prefix
+        let wpkh = Descriptor::Wpkh(keys[0]);
+        assert_eq!(wpkh.max_satisfaction_weight().unwrap(), 4 + 1 + 74 + 34);
+        let shwpkh = Descriptor::Sh(Box::new(Descriptor::Wpkh(keys[0])));
+        assert_eq!(shwpkh.max_satisfaction_weight().unwrap(), 4 * 24 + 1 + 74 + 34);
+        let pkh = Descriptor::KeyHash(keys[0]);
+        assert_eq!(pkh.max_satisfaction_weight().unwrap(), 4 * (1 + 74 + 34));
+
+        // 35-byte `<pk> CHECKSIG` script
+        let wsh = Descriptor::Wsh(Box::new(Descriptor::Key(keys[0])));
+        assert_eq!(wsh.max_satisfaction_weight().unwrap(), 4 + 1 + 74 + 1 + 35);
+        let sh = Descriptor::Sh(Box::new(Descriptor::Key(keys[0])));
+        assert_eq!(sh.max_satisfaction_weight().unwrap(), 4 * (1 + 74 + 1 + 35));
+    }
+
+    #[test]
+    fn script_pubkey() {
+        let (keys, _) = pubkeys_and_a_sig(3);
+        let pk_script = ParseTree::compile(&Descriptor::Key(keys[0])).unwrap().serialize();
+
+        let bare = Descriptor::Key(keys[0]);
+        assert_eq!(bare.script_pubkey().unwrap(), pk_script);
+        assert_eq!(bare.witness_script().unwrap(), pk_script);
+        assert!(bare.address(Network::Bitcoin).unwrap().is_none());
+
+        let wpkh = Descriptor::Wpkh(keys[0]);
+        assert_eq!(
+            wpkh.script_pubkey().unwrap(),
+            script::Builder::new()
+                .push_int(0)
+                .push_slice(&Hash160::from_data(&keys[0].serialize()[..])[..])
+                .into_script()
+        );
+        assert_eq!(
+            wpkh.address(Network::Bitcoin).unwrap().unwrap().script_pubkey(),
+            wpkh.script_pubkey().unwrap()
+        );
+
+        let sh = Descriptor::Sh(Box::new(Descriptor::Key(keys[0])));
+        assert_eq!(sh.witness_script().unwrap(), pk_script);
+        assert_eq!(
+            sh.script_pubkey().unwrap(),
+            script::Builder::new()
+                .push_opcode(opcodes::All::OP_HASH160)
+                .push_slice(&Hash160::from_data(&pk_script[..])[..])
+                .push_opcode(opcodes::All::OP_EQUAL)
+                .into_script()
+        );
+        assert_eq!(
+            sh.address(Network::Testnet).unwrap().unwrap().script_pubkey(),
+            sh.script_pubkey().unwrap()
+        );
+
+        let wsh = Descriptor::Wsh(Box::new(Descriptor::Multi(2, keys.clone())));
+        let wsh_spk = wsh.script_pubkey().unwrap();
+        assert_eq!(wsh_spk.len(), 34);
+        assert_eq!(&wsh_spk[0..2], &[0x00, 0x20][..]);
+        assert_eq!(wsh.witness_script().unwrap(), ParseTree::compile(&Descriptor::Multi(2, keys.clone())).unwrap().serialize());
+
+        let shwsh = Descriptor::Sh(Box::new(Descriptor::Wsh(Box::new(Descriptor::Multi(2, keys.clone())))));
+        assert_eq!(shwsh.script_pubkey().unwrap(), wsh_spk.to_p2sh());
+        assert_eq!(shwsh.witness_script().unwrap(), wsh.witness_script().unwrap());
+        assert_eq!(
+            shwsh.address(Network::Bitcoin).unwrap().unwrap().script_pubkey(),
+            shwsh.script_pubkey().unwrap()
+        );
+
+        let nested = Descriptor::Wsh(Box::new(Descriptor::Sh(Box::new(Descriptor::Key(keys[0])))));
+        assert!(nested.script_pubkey().is_err());
+        assert!(nested.address(Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn electrum_scripthash() {
+        // Example from the Electrum protocol documentation
+        let genesis = Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+        assert_eq!(
+            Descriptor::<secp256k1::PublicKey>::Addr(genesis).electrum_scripthash().unwrap(),
+            "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161"
+        );
+
+        let (keys, _) = pubkeys_and_a_sig(2);
+        let shwsh = Descriptor::Sh(Box::new(Descriptor::Wsh(Box::new(Descriptor::Multi(1, keys.clone())))));
+        let addr = shwsh.address(Network::Bitcoin).unwrap().unwrap();
+        assert_eq!(shwsh.electrum_scripthash(), Descriptor::<secp256k1::PublicKey>::Addr(addr).electrum_scripthash());
+        // The hash is of the P2SH or P2WSH scriptpubkey, not of the inner script
+        for wrapped in &[
+            Descriptor::Sh(Box::new(Descriptor::Multi(1, keys.clone()))),
+            Descriptor::Wsh(Box::new(Descriptor::Multi(1, keys.clone()))),
+        ] {
+            let addr = wrapped.address(Network::Bitcoin).unwrap().unwrap();
+            let hash = wrapped.electrum_scripthash().unwrap();
+            assert_eq!(Ok(hash.clone()), Descriptor::<secp256k1::PublicKey>::Addr(addr).electrum_scripthash());
+            assert!(hash != Descriptor::Multi(1, keys.clone()).electrum_scripthash().unwrap());
+            assert!(hash != shwsh.electrum_scripthash().unwrap());
+        }
+        assert!(Descriptor::Combo(keys[0]).electrum_scripthash().is_err());
     }
 
     #[test]
@@ -591,5 +2095,231 @@ mod tests {
 
         assert!(Descriptor::<secp256k1::PublicKey>::from_str("pk(020000000000000000000000000000000000000000000000000000000000000002)").is_ok());
     }
-}
 
+    #[test]
+    fn core_output_forms() {
+        let (keys, _) = pubkeys_and_a_sig(1);
+
+        let combo = Descriptor::Combo(keys[0]);
+        assert!(combo.script_pubkey().is_err());
+        let wpkh = Descriptor::Wpkh(keys[0]);
+        assert_eq!(
+            combo.script_pubkeys().unwrap(),
+            vec![
+                Descriptor::Key(keys[0]).script_pubkey().unwrap(),
+                Descriptor::KeyHash(keys[0]).script_pubkey().unwrap(),
+                wpkh.script_pubkey().unwrap(),
+                Descriptor::Sh(Box::new(wpkh)).script_pubkey().unwrap(),
+            ]
+        );
+        assert!(combo.address(Network::Bitcoin).unwrap().is_none());
+
+        let raw = Descriptor::<secp256k1::PublicKey>::from_str("raw(deadbeef)").unwrap();
+        assert_eq!(raw.to_string(), "raw(deadbeef)");
+        assert_eq!(raw.script_pubkey().unwrap(), Script::from(vec![0xde, 0xad, 0xbe, 0xef]));
+        assert!(raw.witness_script().is_err());
+        assert!(Descriptor::<secp256k1::PublicKey>::from_str("raw(deadbee)").is_err());
+
+        let opreturn = Descriptor::<secp256k1::PublicKey>::from_str("opreturn(deadbeef)").unwrap();
+        assert_eq!(opreturn.to_string(), "opreturn(deadbeef)");
+        assert_eq!(
+            opreturn.script_pubkey().unwrap(),
+            script::Builder::new()
+                .push_opcode(opcodes::All::OP_RETURN)
+                .push_slice(&[0xde, 0xad, 0xbe, 0xef])
+                .into_script()
+        );
+        assert!(opreturn.address(Network::Bitcoin).unwrap().is_none());
+        assert_eq!(ParseTree::from_descriptor(&opreturn).err(), Some(Error::Unspendable));
+        assert!(Descriptor::Sh(Box::new(opreturn)).script_pubkey().is_err());
+
+        let pkh = Descriptor::KeyHash(keys[0]);
+        let address = pkh.address(Network::Bitcoin).unwrap().unwrap();
+        let addr = Descriptor::<secp256k1::PublicKey>::from_str(&format!("addr({})", address.to_string())).unwrap();
+        assert_eq!(addr.to_string(), format!("addr({})", address.to_string()));
+        assert_eq!(addr.script_pubkey().unwrap(), pkh.script_pubkey().unwrap());
+        assert!(Descriptor::<secp256k1::PublicKey>::from_str("addr(1nvalid)").is_err());
+    }
+
+    #[test]
+    fn parse_checksum() {
+        let s = "pk(020000000000000000000000000000000000000000000000000000000000000002)";
+        let desc = Descriptor::<secp256k1::PublicKey>::from_str(&format!("{}#7yxkn84h", s)).unwrap();
+        assert_eq!(desc.to_string_with_checksum(), format!("{}#7yxkn84h", s));
+
+        assert_eq!(
+            Descriptor::<secp256k1::PublicKey>::from_str(&format!("{}#7yxkn84j", s)).err(),
+            Some(Error::BadChecksum("7yxkn84j".to_owned()))
+        );
+    }
+
+    #[test]
+    fn display_with_aliases() {
+        let (keys, _) = pubkeys_and_a_sig(3);
+        let desc = Descriptor::And(
+            Box::new(Descriptor::Key(keys[0])),
+            Box::new(Descriptor::Or(
+                Box::new(Descriptor::Multi(1, vec![keys[1], keys[2]])),
+                Box::new(Descriptor::Time(1000)),
+            )),
+        );
+
+        let mut aliases = HashMap::new();
+        aliases.insert(keys[0], "CEO".to_owned());
+        aliases.insert(keys[1], "CFO".to_owned());
+        // Keys without an alias are written out in full
+        let hex: String = keys[2].serialize().iter().map(|x| format!("{:02x}", x)).collect();
+        assert_eq!(
+            desc.display_with_aliases(&aliases),
+            format!("and(pk(CEO),or(multi(1,CFO,{}),time(1000)))", hex)
+        );
+        assert_eq!(desc.display_with_aliases(&HashMap::new()), desc.to_string());
+    }
+
+    #[test]
+    fn parse_odds() {
+        let pk = "pk(020000000000000000000000000000000000000000000000000000000000000002)";
+
+        let desc = Descriptor::<secp256k1::PublicKey>::from_str(
+            &format!("or(9@{},1@and({},time(100)))", pk, pk)
+        ).unwrap();
+        match desc {
+            Descriptor::WeightedOr(9, ref left, 1, ref right) => {
+                match (&**left, &**right) {
+                    (&Descriptor::Key(..), &Descriptor::And(..)) => {}
+                    _ => panic!("wrong branches"),
+                }
+            }
+            _ => panic!("expected weighted or"),
+        }
+
+        let desc = Descriptor::<secp256k1::PublicKey>::from_str(&format!("or({},{})", pk, pk)).unwrap();
+        match desc {
+            Descriptor::Or(..) => {}
+            _ => panic!("expected unweighted or"),
+        }
+
+        // Odds must be given for both branches, and must not both be zero
+        assert!(Descriptor::<secp256k1::PublicKey>::from_str(&format!("or(9@{},{})", pk, pk)).is_err());
+        assert!(Descriptor::<secp256k1::PublicKey>::from_str(&format!("or(0@{},0@{})", pk, pk)).is_err());
+        assert!(Descriptor::<secp256k1::PublicKey>::from_str(&format!("or(x@{},1@{})", pk, pk)).is_err());
+    }
+
+    #[test]
+    fn display_roundtrip() {
+        let (keys, _) = pubkeys_and_a_sig(3);
+        let desc = Descriptor::Threshold(2, vec![
+            Descriptor::Multi(1, keys[0..2].to_owned()),
+            Descriptor::And(Box::new(Descriptor::Key(keys[2])), Box::new(Descriptor::Time(100))),
+            Descriptor::WeightedOr(
+                9, Box::new(Descriptor::KeyHash(keys[0])),
+                1, Box::new(Descriptor::AsymmetricOr(Box::new(Descriptor::Key(keys[1])), Box::new(Descriptor::Key(keys[2])))),
+            ),
+        ]);
+        let s = desc.to_string();
+        assert!(s.starts_with("thresh(2,multi(1,"));
+        let reparsed = Descriptor::<secp256k1::PublicKey>::from_str(&s).unwrap();
+        assert_eq!(reparsed.to_string(), s);
+        assert!(reparsed.is_equivalent(&desc));
+
+        let multi_a = Descriptor::MultiA(2, keys.clone());
+        let s = multi_a.to_string();
+        assert!(s.starts_with("multi_a(2,"));
+        let reparsed = Descriptor::<secp256k1::PublicKey>::from_str(&s).unwrap();
+        assert_eq!(reparsed.to_string(), s);
+        assert!(reparsed.is_equivalent(&multi_a));
+
+        // The odds prefix does not turn a weighted `multi` into `multi_a`
+        let weighted = Descriptor::WeightedOr(
+            3, Box::new(Descriptor::Multi(1, keys[0..2].to_owned())),
+            1, Box::new(Descriptor::Key(keys[2])),
+        );
+        let s = weighted.to_string();
+        assert_eq!(Descriptor::<secp256k1::PublicKey>::from_str(&s).unwrap().to_string(), s);
+
+        // Key aggregates mean the same as n-of-n multisigs
+        let musig = Descriptor::Aggregate(keys.clone());
+        let s = musig.to_string();
+        assert!(s.starts_with("musig(0"));
+        let reparsed = Descriptor::<secp256k1::PublicKey>::from_str(&s).unwrap();
+        assert_eq!(reparsed.to_string(), s);
+        assert!(reparsed.is_equivalent(&Descriptor::Multi(3, keys.clone())));
+        assert_eq!(musig.sanity_check(), Ok(()));
+        assert_eq!(Descriptor::<secp256k1::PublicKey>::Aggregate(vec![]).sanity_check(), Err(SanityError::BadThreshold(0, 0)));
+        match Descriptor::Aggregate(vec![keys[0], keys[0]]).sanity_check() {
+            Err(SanityError::DuplicateKey(..)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn translate() {
+        let (keys, _) = pubkeys_and_a_sig(4);
+        let desc = Descriptor::Wsh(Box::new(Descriptor::Or(
+            Box::new(Descriptor::Multi(1, keys[0..2].to_owned())),
+            Box::new(Descriptor::And(Box::new(Descriptor::KeyHash(keys[2])), Box::new(Descriptor::Time(100)))),
+        )));
+
+        // Every key is visited, in order, and may be replaced
+        let mut seen = vec![];
+        let swapped = desc.translate(|pk| -> Result<_, ()> {
+            seen.push(*pk);
+            Ok(if *pk == keys[2] { keys[3] } else { *pk })
+        }).unwrap();
+        assert_eq!(seen, keys[0..3].to_owned());
+        let expected = Descriptor::Wsh(Box::new(Descriptor::Or(
+            Box::new(Descriptor::Multi(1, keys[0..2].to_owned())),
+            Box::new(Descriptor::And(Box::new(Descriptor::KeyHash(keys[3])), Box::new(Descriptor::Time(100)))),
+        )));
+        assert_eq!(swapped.to_string(), expected.to_string());
+
+        // The first error stops the translation
+        let mut calls = 0;
+        let res = desc.translate(|pk| {
+            calls += 1;
+            if *pk == keys[1] { Err("unknown key") } else { Ok(*pk) }
+        });
+        assert_eq!(res.err(), Some("unknown key"));
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn max_depth() {
+        let pk = "pk(020000000000000000000000000000000000000000000000000000000000000002)";
+        let nested = |depth: usize| format!("{}{}{}", "or(".repeat(depth), pk, format!(",{})", pk).repeat(depth));
+
+        assert!(Descriptor::<secp256k1::PublicKey>::from_str(&nested(50)).is_ok());
+        match Descriptor::<secp256k1::PublicKey>::from_str(&nested(150)) {
+            Err(Error::MaxRecursionDepthExceeded(n)) => assert_eq!(n, MAX_PARSE_DEPTH),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(desc) => panic!("unexpectedly parsed {}", desc),
+        }
+    }
+
+    #[test]
+    fn sortedmulti() {
+        let (keys, _) = pubkeys_and_a_sig(4);
+        let mut sorted = keys.clone();
+        sorted.sort_by_key(|pk| pk.serialize().to_vec());
+        let mut reversed = sorted.clone();
+        reversed.reverse();
+
+        // The descriptor keeps its key order, only the script is sorted
+        let desc = Descriptor::Wsh(Box::new(Descriptor::SortedMulti(2, reversed.clone())));
+        let s = desc.to_string();
+        assert!(s.starts_with("wsh(sortedmulti(2,"));
+        let reparsed = Descriptor::<secp256k1::PublicKey>::from_str(&s).unwrap();
+        assert_eq!(reparsed.to_string(), s);
+        assert_eq!(reparsed.sanity_check(), Ok(()));
+
+        let multi = Descriptor::Wsh(Box::new(Descriptor::Multi(2, sorted.clone())));
+        assert_eq!(desc.witness_script().unwrap(), multi.witness_script().unwrap());
+        let shuffled = Descriptor::Wsh(Box::new(Descriptor::SortedMulti(2, keys.clone())));
+        assert_eq!(shuffled.script_pubkey().unwrap(), multi.script_pubkey().unwrap());
+        assert!(shuffled.is_equivalent(&desc));
+
+        // An unsorted `multi` gives a different script
+        let unsorted = Descriptor::Wsh(Box::new(Descriptor::Multi(2, reversed)));
+        assert!(unsorted.script_pubkey().unwrap() != desc.script_pubkey().unwrap());
+    }
+}
@@ -0,0 +1,251 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # C Interface
+//!
+//! `extern "C"` functions for use of the library from other languages, enabled by the
+//! `ffi` feature. Build a shared library with
+//! `cargo rustc --release --features ffi -- --crate-type cdylib`.
+//!
+//! Parse trees are passed around as opaque handles, which must be released with
+//! `sd_parse_tree_free`. Functions returning a handle return null on failure.
+//!
+//! Functions producing variable-length data take an output buffer `out` of capacity
+//! `out_cap` bytes, and return the number of bytes the data takes up, or 0 on failure.
+//! The data is only written if it fits, so a caller may first pass a null buffer of
+//! capacity 0 to learn the size needed.
+//!
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::{ptr, slice};
+
+use secp256k1;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::SigHashType;
+use bitcoin::network::serialize;
+
+use {Descriptor, ParseTree};
+
+/// Copies `data` into the caller's buffer if it fits, returning its length
+unsafe fn write_out(data: &[u8], out: *mut u8, out_cap: usize) -> usize {
+    if !out.is_null() && data.len() <= out_cap {
+        ptr::copy_nonoverlapping(data.as_ptr(), out, data.len());
+    }
+    data.len()
+}
+
+/// Parses the `len`-byte script at `script` into a parse tree
+///
+/// # Safety
+///
+/// `script` must be null or point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn sd_parse_tree_parse(script: *const u8, len: usize) -> *mut ParseTree {
+    if script.is_null() {
+        return ptr::null_mut();
+    }
+    let script = Script::from(slice::from_raw_parts(script, len).to_owned());
+    match ParseTree::parse(&script) {
+        Ok(tree) => Box::into_raw(Box::new(tree)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Compiles the NUL-terminated descriptor string `policy`, which must not contain any
+/// `sh`, `wsh` or `wpkh` wrappers, into a parse tree
+///
+/// # Safety
+///
+/// `policy` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn sd_parse_tree_compile(policy: *const c_char) -> *mut ParseTree {
+    if policy.is_null() {
+        return ptr::null_mut();
+    }
+    let desc = match CStr::from_ptr(policy).to_str() {
+        Ok(s) => match s.parse::<Descriptor<secp256k1::PublicKey>>() {
+            Ok(desc) => desc,
+            Err(_) => return ptr::null_mut(),
+        },
+        Err(_) => return ptr::null_mut(),
+    };
+    match ParseTree::compile(&desc) {
+        Ok(tree) => Box::into_raw(Box::new(tree)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Releases a parse tree. Passing null does nothing.
+///
+/// # Safety
+///
+/// `tree` must be null or a parse tree returned by this library which has not
+/// already been released.
+#[no_mangle]
+pub unsafe extern "C" fn sd_parse_tree_free(tree: *mut ParseTree) {
+    if !tree.is_null() {
+        drop(Box::from_raw(tree));
+    }
+}
+
+/// Writes out the script of a parse tree
+///
+/// # Safety
+///
+/// `tree` must be null or a live parse tree returned by this library, and `out` must
+/// be null or point to `out_cap` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn sd_parse_tree_serialize(tree: *const ParseTree, out: *mut u8, out_cap: usize) -> usize {
+    match tree.as_ref() {
+        Some(tree) => write_out(&tree.serialize()[..], out, out_cap),
+        None => 0,
+    }
+}
+
+/// Writes out the public keys which might contribute to satisfying a parse tree, as
+/// consecutive 33-byte compressed keys
+///
+/// # Safety
+///
+/// `tree` must be null or a live parse tree returned by this library, and `out` must
+/// be null or point to `out_cap` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn sd_parse_tree_required_keys(tree: *const ParseTree, out: *mut u8, out_cap: usize) -> usize {
+    let tree = match tree.as_ref() {
+        Some(tree) => tree,
+        None => return 0,
+    };
+    let mut keys = Vec::with_capacity(33 * tree.required_keys().len());
+    for pk in tree.required_keys() {
+        keys.extend(&pk.serialize()[..]);
+    }
+    write_out(&keys, out, out_cap)
+}
+
+/// Builds a satisfaction of a parse tree from `n_sigs` signatures. The signature
+/// `sigs[i]`, of `sig_lens[i]` bytes, must be a DER signature followed by its sighash
+/// type byte, made with the 33-byte compressed key at `keys + 33 * i`.
+///
+/// The satisfaction is written out in the serialization of a segwit witness: the
+/// number of stack elements, then each element prefixed with its length, with the
+/// top of the stack last.
+///
+/// # Safety
+///
+/// `tree` must be null or a live parse tree returned by this library. Unless `n_sigs`
+/// is zero, `keys` must point to `33 * n_sigs` readable bytes and `sigs` and `sig_lens`
+/// to `n_sigs` entries each, with every `sigs[i]` pointing to `sig_lens[i]` readable
+/// bytes. `out` must be null or point to `out_cap` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn sd_parse_tree_satisfy(
+    tree: *const ParseTree,
+    keys: *const u8,
+    sigs: *const *const u8,
+    sig_lens: *const usize,
+    n_sigs: usize,
+    out: *mut u8,
+    out_cap: usize,
+) -> usize {
+    let tree = match tree.as_ref() {
+        Some(tree) => tree,
+        None => return 0,
+    };
+    if n_sigs > 0 && (keys.is_null() || sigs.is_null() || sig_lens.is_null()) {
+        return 0;
+    }
+
+    let secp = secp256k1::Secp256k1::without_caps();
+    let mut sig_map = HashMap::new();
+    for i in 0..n_sigs {
+        let pk = match secp256k1::PublicKey::from_slice(&secp, slice::from_raw_parts(keys.offset(33 * i as isize), 33)) {
+            Ok(pk) => pk,
+            Err(_) => return 0,
+        };
+        let sig_ptr = *sigs.add(i);
+        if sig_ptr.is_null() {
+            return 0;
+        }
+        let sig = slice::from_raw_parts(sig_ptr, *sig_lens.add(i));
+        let (hashtype, der) = match sig.split_last() {
+            Some(split) => split,
+            None => return 0,
+        };
+        match secp256k1::Signature::from_der(&secp, der) {
+            Ok(sig) => sig_map.insert(pk, (sig, SigHashType::from_u32(*hashtype as u32))),
+            Err(_) => return 0,
+        };
+    }
+
+    match tree.satisfy(&sig_map) {
+        Ok(witness) => {
+            let ser = serialize::serialize(&witness).expect("witnesses always serialize");
+            write_out(&ser, out, out_cap)
+        }
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::ptr;
+
+    use secp256k1;
+
+    use super::*;
+
+    #[test]
+    fn ffi_roundtrip() {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let pk = secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let mut sig = secp.sign(&msg, &sk).expect("signing context").serialize_der(&secp);
+        sig.push(SigHashType::All.as_u32() as u8);
+
+        unsafe {
+            let policy = CString::new(Descriptor::Key(pk).to_string()).unwrap();
+            let tree = sd_parse_tree_compile(policy.as_ptr());
+            assert!(!tree.is_null());
+            assert!(sd_parse_tree_compile(CString::new("pk(").unwrap().as_ptr()).is_null());
+
+            // Serialize and parse back
+            let len = sd_parse_tree_serialize(tree, ptr::null_mut(), 0);
+            let mut script = vec![0; len];
+            assert_eq!(sd_parse_tree_serialize(tree, script.as_mut_ptr(), len), len);
+            let parsed = sd_parse_tree_parse(script.as_ptr(), len);
+            assert_eq!(*parsed, *tree);
+            sd_parse_tree_free(parsed);
+
+            let mut keys = [0; 33];
+            assert_eq!(sd_parse_tree_required_keys(tree, keys.as_mut_ptr(), 33), 33);
+            assert_eq!(&keys[..], &pk.serialize()[..]);
+
+            // A witness with the single signature
+            let mut witness = vec![0; 100];
+            let sigs = [sig.as_ptr()];
+            let sig_lens = [sig.len()];
+            let len = sd_parse_tree_satisfy(tree, keys.as_ptr(), sigs.as_ptr(), sig_lens.as_ptr(), 1, witness.as_mut_ptr(), 100);
+            let mut expected = vec![1, sig.len() as u8];
+            expected.extend(sig);
+            assert_eq!(&witness[..len], &expected[..]);
+            assert_eq!(sd_parse_tree_satisfy(tree, ptr::null(), ptr::null(), ptr::null(), 0, witness.as_mut_ptr(), 100), 0);
+
+            sd_parse_tree_free(tree);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Workload Generators
+//!
+//! Deterministic descriptors, scripts and satisfiers of a given size, as used by the
+//! benchmarks in `benches/`, so that performance work on the lexer, parser and
+//! compiler can be measured against the same workloads downstream. Unlike the
+//! generators of the `arbitrary` module, these give one fixed result per size.
+//!
+//! The benchmarks are run with `cargo bench`; to check a change for regressions,
+//! save a baseline before it with `cargo bench -- --save-baseline before` and compare
+//! against it afterwards with `cargo bench -- --baseline before`.
+//!
+
+use std::collections::HashMap;
+
+use secp256k1;
+
+use bitcoin::blockdata::script::Script;
+
+use {Descriptor, ParseTree};
+
+/// Secret key number `i`, which is nonzero and far below the curve order
+fn secret_key(secp: &secp256k1::Secp256k1, i: usize) -> secp256k1::SecretKey {
+    let mut sk = [0; 32];
+    sk[24..].copy_from_slice(&[
+        0, 0, 0, 1,
+        (i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8,
+    ]);
+    secp256k1::SecretKey::from_slice(secp, &sk).expect("secret key")
+}
+
+/// The first `n` of a fixed sequence of distinct public keys
+pub fn keys(n: usize) -> Vec<secp256k1::PublicKey> {
+    let secp = secp256k1::Secp256k1::new();
+    (0..n).map(|i| secp256k1::PublicKey::from_secret_key(&secp, &secret_key(&secp, i)).expect("signing context")).collect()
+}
+
+/// A `k`-of-`n` threshold of the first `n` keys, such as the 67-of-100 of a large
+/// federation. Thresholds of more than 20 keys cannot be compiled as a multisig, so
+/// are compiled as a sum of signature checks.
+pub fn threshold(k: usize, n: usize) -> Descriptor<secp256k1::PublicKey> {
+    Descriptor::Threshold(k, keys(n).into_iter().map(Descriptor::Key).collect())
+}
+
+/// A chain of `depth` disjunctions of the first `depth + 1` keys, nested on the right:
+/// `or(pk(K0),or(pk(K1),...pk(Kdepth)))`. The compiler accepts depths up to 100.
+pub fn nested_or(depth: usize) -> Descriptor<secp256k1::PublicKey> {
+    let mut keys = keys(depth + 1);
+    let mut ret = Descriptor::Key(keys.pop().expect("at least one key"));
+    while let Some(pk) = keys.pop() {
+        ret = Descriptor::Key(pk).or_with(ret);
+    }
+    ret
+}
+
+/// The script of the compilation of `desc`, for benchmarking lexing and parsing
+pub fn script(desc: &Descriptor<secp256k1::PublicKey>) -> Script {
+    ParseTree::compile(desc).expect("generated descriptors compile").serialize()
+}
+
+/// Signatures of a fixed message by each key `keys(n)[i]` for `i` in `signers`, for
+/// satisfying the descriptors above with only some of their keys
+pub fn signatures(signers: &[usize]) -> HashMap<secp256k1::PublicKey, secp256k1::Signature> {
+    let secp = secp256k1::Secp256k1::new();
+    let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+    let mut ret = HashMap::new();
+    for &i in signers {
+        let sk = secret_key(&secp, i);
+        ret.insert(secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context"), secp.sign(&msg, &sk).expect("signing context"));
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use {ParseTree, ScriptContext};
+    use parse::lex;
+    use super::{keys, nested_or, script, signatures, threshold};
+
+    #[test]
+    fn generators() {
+        let ks = keys(100);
+        assert_eq!(ks.len(), 100);
+        assert!(ks[1..].iter().all(|pk| *pk != ks[0]));
+
+        let wide = script(&threshold(67, 100));
+        assert!(lex(&wide, ScriptContext::SegwitV0).is_ok());
+        let sigs = signatures(&(0..67).collect::<Vec<_>>());
+        assert!(ParseTree::parse(&wide).unwrap().satisfy(&sigs).is_ok());
+
+        // Unoptimized builds need more than the default test thread stack to
+        // parse this deep
+        ::std::thread::Builder::new().stack_size(64 << 20).spawn(|| {
+            let deep = ParseTree::parse(&script(&nested_or(30))).unwrap();
+            assert!(deep.satisfy(&signatures(&[30])).is_ok());
+            assert!(deep.satisfy(&signatures(&[])).is_err());
+        }).unwrap().join().unwrap();
+    }
+}
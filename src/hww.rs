@@ -0,0 +1,647 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Hardware Wallet Signing Requests
+//!
+//! Everything a hardware wallet needs to sign an input spending a descriptor output,
+//! gathered into one compact structure: the signature hash, the script being
+//! executed, and the keys which may sign, each with the fingerprint and derivation
+//! path by which the device finds its private key. Requests are rendered as JSON or
+//! CBOR, so that bridges to Ledger or Trezor style devices can be driven without
+//! knowing anything about the script themselves.
+//!
+//! In both encodings a request is a map with the entries `index`, `type` (one of
+//! `bare`, `sh`, `wsh`, `sh-wsh`, `wpkh` and `sh-wpkh`), `amount`, `sighash_type`,
+//! `sighash`, `script` and `keys`. Each key is a map of `pubkey` and, if its origin is
+//! known, `fingerprint` and `path`, where hardened path steps have their top bit set.
+//! Binary data is given as hex strings in JSON and as byte strings in CBOR.
+//!
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use secp256k1;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{SigHashType, Transaction};
+use bitcoin::util::bip32::ChildNumber;
+use bitcoin::util::hash::Sha256dHash;
+
+use psbt::KeyOrigin;
+use sign::{descriptor_keys, signature_hash};
+use xpub::DescriptorXPub;
+use {Descriptor, Error, ParseTree, Wrapper};
+
+/// A key which may sign an input, and where a hardware wallet can find it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SigningKey {
+    /// The key
+    pub key: secp256k1::PublicKey,
+    /// Fingerprint of the master key and the path from it to `key`, if known
+    pub origin: Option<KeyOrigin>,
+}
+
+/// The signatures needed for one input of a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SigningRequest {
+    /// Index of the input in the spending transaction
+    pub index: usize,
+    /// Type of the output being spent
+    pub wrapper: Wrapper,
+    /// Value of the output being spent, which segwit signatures commit to
+    pub amount: u64,
+    /// Sighash type of the signatures, which is always `SIGHASH_ALL`
+    pub sighash_type: SigHashType,
+    /// The signature hash which must be signed
+    pub sighash: Sha256dHash,
+    /// The script being executed: the redeem script or witness script, or for
+    /// `Wpkh` outputs the implied pay-to-pubkeyhash script
+    pub script: Script,
+    /// Every key in the descriptor, in order of first appearance, even if some spend
+    /// paths do not need it
+    pub keys: Vec<SigningKey>,
+}
+
+/// Name of the output type in the `type` field of an encoded request
+fn wrapper_name(wrapper: Wrapper) -> &'static str {
+    match wrapper {
+        Wrapper::Bare => "bare",
+        Wrapper::Sh => "sh",
+        Wrapper::Wsh => "wsh",
+        Wrapper::ShWsh => "sh-wsh",
+        Wrapper::Wpkh => "wpkh",
+        Wrapper::ShWpkh => "sh-wpkh",
+    }
+}
+
+/// A derivation step as a 32-bit index, with the top bit set if hardened
+fn path_index(step: &ChildNumber) -> u32 {
+    match *step {
+        ChildNumber::Normal(index) => index,
+        ChildNumber::Hardened(index) => index | 1 << 31,
+    }
+}
+
+/// Writes `data` as a JSON hex string
+fn write_json_hex(data: &[u8], out: &mut String) {
+    out.push('"');
+    for x in data {
+        write!(out, "{:02x}", *x).expect("write to string");
+    }
+    out.push('"');
+}
+
+/// Writes the head of a CBOR data item of major type `major` and argument `n`
+fn write_cbor_head(major: u8, n: u64, out: &mut Vec<u8>) {
+    let major = major << 5;
+    if n < 24 {
+        out.push(major | n as u8);
+    } else if n <= 0xff {
+        out.push(major | 24);
+        out.push(n as u8);
+    } else if n <= 0xffff {
+        out.push(major | 25);
+        out.extend_from_slice(&[(n >> 8) as u8, n as u8]);
+    } else if n <= 0xffff_ffff {
+        out.push(major | 26);
+        out.extend_from_slice(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]);
+    } else {
+        out.push(major | 27);
+        for i in (0..8).rev() {
+            out.push((n >> (8 * i)) as u8);
+        }
+    }
+}
+
+/// Writes a CBOR unsigned integer
+fn write_cbor_uint(n: u64, out: &mut Vec<u8>) {
+    write_cbor_head(0, n, out);
+}
+
+/// Writes a CBOR byte string
+fn write_cbor_bytes(data: &[u8], out: &mut Vec<u8>) {
+    write_cbor_head(2, data.len() as u64, out);
+    out.extend_from_slice(data);
+}
+
+/// Writes a CBOR text string
+fn write_cbor_text(s: &str, out: &mut Vec<u8>) {
+    write_cbor_head(3, s.len() as u64, out);
+    out.extend_from_slice(s.as_bytes());
+}
+
+impl SigningKey {
+    /// Appends the key as a JSON object
+    fn write_json(&self, out: &mut String) {
+        out.push_str("{\"pubkey\":");
+        write_json_hex(&self.key.serialize()[..], out);
+        if let Some((ref fingerprint, ref path)) = self.origin {
+            out.push_str(",\"fingerprint\":");
+            write_json_hex(&fingerprint[..], out);
+            let steps: Vec<String> = path.iter().map(|step| path_index(step).to_string()).collect();
+            write!(out, ",\"path\":[{}]", steps.join(",")).expect("write to string");
+        }
+        out.push('}');
+    }
+
+    /// Appends the key as a CBOR map
+    fn write_cbor(&self, out: &mut Vec<u8>) {
+        write_cbor_head(5, if self.origin.is_some() { 3 } else { 1 }, out);
+        write_cbor_text("pubkey", out);
+        write_cbor_bytes(&self.key.serialize()[..], out);
+        if let Some((ref fingerprint, ref path)) = self.origin {
+            write_cbor_text("fingerprint", out);
+            write_cbor_bytes(&fingerprint[..], out);
+            write_cbor_text("path", out);
+            write_cbor_head(4, path.len() as u64, out);
+            for step in path {
+                write_cbor_uint(path_index(step) as u64, out);
+            }
+        }
+    }
+}
+
+impl SigningRequest {
+    /// The request as a JSON object
+    pub fn to_json(&self) -> String {
+        let mut ret = String::new();
+        write!(
+            ret,
+            "{{\"index\":{},\"type\":\"{}\",\"amount\":{},\"sighash_type\":{},\"sighash\":",
+            self.index,
+            wrapper_name(self.wrapper),
+            self.amount,
+            self.sighash_type.as_u32(),
+        ).expect("write to string");
+        write_json_hex(&self.sighash[..], &mut ret);
+        ret.push_str(",\"script\":");
+        write_json_hex(&self.script[..], &mut ret);
+        ret.push_str(",\"keys\":[");
+        for (i, key) in self.keys.iter().enumerate() {
+            if i > 0 {
+                ret.push(',');
+            }
+            key.write_json(&mut ret);
+        }
+        ret.push_str("]}");
+        ret
+    }
+
+    /// Appends the request as a CBOR map
+    fn write_cbor(&self, out: &mut Vec<u8>) {
+        write_cbor_head(5, 7, out);
+        write_cbor_text("index", out);
+        write_cbor_uint(self.index as u64, out);
+        write_cbor_text("type", out);
+        write_cbor_text(wrapper_name(self.wrapper), out);
+        write_cbor_text("amount", out);
+        write_cbor_uint(self.amount, out);
+        write_cbor_text("sighash_type", out);
+        write_cbor_uint(self.sighash_type.as_u32() as u64, out);
+        write_cbor_text("sighash", out);
+        write_cbor_bytes(&self.sighash[..], out);
+        write_cbor_text("script", out);
+        write_cbor_bytes(&self.script[..], out);
+        write_cbor_text("keys", out);
+        write_cbor_head(4, self.keys.len() as u64, out);
+        for key in &self.keys {
+            key.write_cbor(out);
+        }
+    }
+
+    /// The request as a CBOR map
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut ret = vec![];
+        self.write_cbor(&mut ret);
+        ret
+    }
+}
+
+/// The requests as a JSON array
+pub fn requests_json(requests: &[SigningRequest]) -> String {
+    let elements: Vec<String> = requests.iter().map(|req| req.to_json()).collect();
+    format!("[{}]", elements.join(","))
+}
+
+/// The requests as a CBOR array
+pub fn requests_cbor(requests: &[SigningRequest]) -> Vec<u8> {
+    let mut ret = vec![];
+    write_cbor_head(4, requests.len() as u64, &mut ret);
+    for req in requests {
+        req.write_cbor(&mut ret);
+    }
+    ret
+}
+
+/// Returns the signing request for input `index` of `tx`, which spends an output of
+/// value `amount` described by `desc`. Keys are given the origins found in `origins`.
+/// The signature hash is the one `sign::input_sighashes` gives.
+pub fn input_signing_request(
+    tx: &Transaction,
+    index: usize,
+    amount: u64,
+    desc: &Descriptor<secp256k1::PublicKey>,
+    origins: &HashMap<secp256k1::PublicKey, KeyOrigin>,
+) -> Result<SigningRequest, Error> {
+    if index >= tx.input.len() {
+        return Err(Error::InputIndexOutOfRange(index));
+    }
+
+    let (tree, wrapper) = ParseTree::from_descriptor(desc)?;
+    let script = tree.serialize();
+    let sighash = signature_hash(tx, index, amount, &script, wrapper);
+
+    let mut keys = vec![];
+    descriptor_keys(desc, &mut keys);
+    Ok(SigningRequest {
+        index,
+        wrapper,
+        amount,
+        sighash_type: SigHashType::All,
+        sighash,
+        script,
+        keys: keys.into_iter().map(|pk| SigningKey { key: pk, origin: origins.get(&pk).cloned() }).collect(),
+    })
+}
+
+/// Returns the signing requests for every input of `tx`, where `spent[i]` is the
+/// descriptor and value of the output spent by input `i`
+pub fn signing_requests(
+    tx: &Transaction,
+    spent: &[(&Descriptor<secp256k1::PublicKey>, u64)],
+    origins: &HashMap<secp256k1::PublicKey, KeyOrigin>,
+) -> Result<Vec<SigningRequest>, Error> {
+    if spent.len() != tx.input.len() {
+        return Err(Error::InputIndexOutOfRange(cmp::min(spent.len(), tx.input.len())));
+    }
+
+    let mut ret = Vec::with_capacity(spent.len());
+    for (index, &(desc, amount)) in spent.iter().enumerate() {
+        ret.push(input_signing_request(tx, index, amount, desc, origins)?);
+    }
+    Ok(ret)
+}
+
+/// Returns the signing requests for every input of `tx`, where `spent[i]` is the
+/// range descriptor, child index and value of the output spent by input `i`. Key
+/// origins are taken from the extended keys.
+pub fn derived_signing_requests(
+    tx: &Transaction,
+    spent: &[(&Descriptor<DescriptorXPub>, u32, u64)],
+) -> Result<Vec<SigningRequest>, Error> {
+    if spent.len() != tx.input.len() {
+        return Err(Error::InputIndexOutOfRange(cmp::min(spent.len(), tx.input.len())));
+    }
+
+    let mut ret = Vec::with_capacity(spent.len());
+    for (index, &(desc, child, amount)) in spent.iter().enumerate() {
+        let (derived, origins) = desc.derive_with_origins(child)?;
+        ret.push(input_signing_request(tx, index, amount, &derived, &origins)?);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use secp256k1;
+
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{SigHashType, Transaction, TxIn, TxOut};
+    use bitcoin::util::bip32::{ChildNumber, Fingerprint};
+    use bitcoin::util::hash::Sha256dHash;
+    use import::Json;
+    use sign::input_sighashes;
+    use xpub::DescriptorXPub;
+    use {Descriptor, Error, Wrapper};
+    use super::{
+        derived_signing_requests, input_signing_request, path_index, requests_cbor, requests_json, wrapper_name,
+        write_cbor_head, SigningKey, SigningRequest,
+    };
+
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    fn spending_tx(inputs: usize) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: (0..inputs).map(|i| TxIn {
+                prev_hash: Sha256dHash::from_data(&[]),
+                prev_index: i as u32,
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: vec![],
+            }).collect(),
+            output: vec![TxOut {
+                value: 99_000,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|x| format!("{:02x}", x)).collect()
+    }
+
+    fn test_keys(n: u8) -> Vec<secp256k1::PublicKey> {
+        let secp = secp256k1::Secp256k1::new();
+        (1..n + 1).map(|i| {
+            let sk = secp256k1::SecretKey::from_slice(&secp, &[i; 32]).expect("secret key");
+            secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context")
+        }).collect()
+    }
+
+    /// A decoded CBOR data item, of the major types which requests use
+    #[derive(Debug, PartialEq)]
+    enum Cbor {
+        Uint(u64),
+        Bytes(Vec<u8>),
+        Text(String),
+        Array(Vec<Cbor>),
+        Map(Vec<(Cbor, Cbor)>),
+    }
+
+    /// Decodes the data item at `pos`, advancing `pos` past it
+    fn decode_cbor(data: &[u8], pos: &mut usize) -> Cbor {
+        let major = data[*pos] >> 5;
+        let n = match data[*pos] & 0x1f {
+            n @ 0..=23 => { *pos += 1; n as u64 }
+            n @ 24..=27 => {
+                let len = 1 << (n - 24);
+                let arg = data[*pos + 1..*pos + 1 + len].iter().fold(0, |acc, x| acc << 8 | *x as u64);
+                // Arguments are encoded in as few bytes as possible
+                assert!(len == 1 && arg >= 24 || len > 1 && arg >> (4 * len) != 0, "non-minimal head");
+                *pos += 1 + len;
+                arg
+            }
+            n => panic!("unexpected additional information {}", n),
+        };
+        match major {
+            0 => Cbor::Uint(n),
+            2 | 3 => {
+                let bytes = data[*pos..*pos + n as usize].to_vec();
+                *pos += n as usize;
+                if major == 2 { Cbor::Bytes(bytes) } else { Cbor::Text(String::from_utf8(bytes).unwrap()) }
+            }
+            4 => Cbor::Array((0..n).map(|_| decode_cbor(data, pos)).collect()),
+            5 => Cbor::Map((0..n).map(|_| (decode_cbor(data, pos), decode_cbor(data, pos))).collect()),
+            major => panic!("unexpected major type {}", major),
+        }
+    }
+
+    /// Decodes a whole CBOR document, which must be a single data item
+    fn decode_cbor_all(data: &[u8]) -> Cbor {
+        let mut pos = 0;
+        let ret = decode_cbor(data, &mut pos);
+        assert_eq!(pos, data.len());
+        ret
+    }
+
+    /// The decoded CBOR which a request should encode to, per the module documentation
+    fn expected_cbor(req: &SigningRequest) -> Cbor {
+        let text = |s: &str| Cbor::Text(s.to_owned());
+        let keys = req.keys.iter().map(|key| {
+            let mut entries = vec![(text("pubkey"), Cbor::Bytes(key.key.serialize().to_vec()))];
+            if let Some((ref fingerprint, ref path)) = key.origin {
+                entries.push((text("fingerprint"), Cbor::Bytes(fingerprint[..].to_vec())));
+                entries.push((text("path"), Cbor::Array(path.iter().map(|step| Cbor::Uint(path_index(step) as u64)).collect())));
+            }
+            Cbor::Map(entries)
+        }).collect();
+        Cbor::Map(vec![
+            (text("index"), Cbor::Uint(req.index as u64)),
+            (text("type"), text(wrapper_name(req.wrapper))),
+            (text("amount"), Cbor::Uint(req.amount)),
+            (text("sighash_type"), Cbor::Uint(req.sighash_type.as_u32() as u64)),
+            (text("sighash"), Cbor::Bytes(req.sighash[..].to_vec())),
+            (text("script"), Cbor::Bytes(req.script[..].to_vec())),
+            (text("keys"), Cbor::Array(keys)),
+        ])
+    }
+
+    #[test]
+    fn signing_request() {
+        let secp = secp256k1::Secp256k1::new();
+        let keys: Vec<_> = (1..3).map(|i| {
+            let sk = secp256k1::SecretKey::from_slice(&secp, &[i; 32]).expect("secret key");
+            secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context")
+        }).collect();
+        let desc = Descriptor::Wsh(Box::new(Descriptor::Multi(1, keys.clone())));
+        let origin = (Fingerprint::from(&[0xd3, 0x4d, 0xb3, 0x3f][..]), vec![ChildNumber::Hardened(48), ChildNumber::Normal(7)]);
+        let mut origins = HashMap::new();
+        origins.insert(keys[1], origin.clone());
+
+        let tx = spending_tx(1);
+        let req = input_signing_request(&tx, 0, 100_000, &desc, &origins).unwrap();
+        let sighashes = input_sighashes(&tx, 0, 100_000, &desc).unwrap();
+        assert_eq!(&req.sighash[..], &sighashes[0].msg[..]);
+        assert_eq!(req.wrapper, Wrapper::Wsh);
+        assert_eq!(req.script, desc.witness_script().unwrap());
+        assert_eq!(req.keys, vec![
+            SigningKey { key: keys[0], origin: None },
+            SigningKey { key: keys[1], origin: Some(origin) },
+        ]);
+
+        assert_eq!(
+            req.to_json(),
+            format!(
+                "{{\"index\":0,\"type\":\"wsh\",\"amount\":100000,\"sighash_type\":1,\"sighash\":\"{}\",\"script\":\"{}\",\"keys\":[{{\"pubkey\":\"{}\"}},{{\"pubkey\":\"{}\",\"fingerprint\":\"d34db33f\",\"path\":[2147483696,7]}}]}}",
+                hex(&req.sighash[..]),
+                hex(&req.script[..]),
+                hex(&keys[0].serialize()[..]),
+                hex(&keys[1].serialize()[..]),
+            )
+        );
+
+        let cbor = req.to_cbor();
+        // A map of seven entries, the first being `index: 0`
+        assert_eq!(&cbor[..8], &[0xa7, 0x65, b'i', b'n', b'd', b'e', b'x', 0x00][..]);
+        // ending with the path of the second key
+        assert_eq!(
+            &cbor[cbor.len() - 12..],
+            &[0x64, b'p', b'a', b't', b'h', 0x82, 0x1a, 0x80, 0x00, 0x00, 0x30, 0x07][..]
+        );
+
+        assert_eq!(input_signing_request(&tx, 1, 100_000, &desc, &origins), Err(Error::InputIndexOutOfRange(1)));
+    }
+
+    #[test]
+    fn cbor_heads() {
+        for &(major, n, ref expected) in &[
+            (0, 0, vec![0x00]),
+            (0, 23, vec![0x17]),
+            (0, 24, vec![0x18, 0x18]),
+            (2, 0xff, vec![0x58, 0xff]),
+            (3, 0x100, vec![0x79, 0x01, 0x00]),
+            (4, 0x1_0000, vec![0x9a, 0x00, 0x01, 0x00, 0x00]),
+            (0, 0x1_0000_0000, vec![0x1b, 0, 0, 0, 1, 0, 0, 0, 0]),
+        ] {
+            let mut out = vec![];
+            write_cbor_head(major, n, &mut out);
+            assert_eq!(out, *expected);
+        }
+    }
+
+    #[test]
+    fn cbor_vector() {
+        let keys = test_keys(2);
+        let req = SigningRequest {
+            index: 1,
+            wrapper: Wrapper::ShWsh,
+            amount: 5_000_000_000,
+            sighash_type: SigHashType::All,
+            sighash: Sha256dHash::from(&[0x11; 32][..]),
+            script: Script::from(vec![0x51]),
+            keys: vec![
+                SigningKey {
+                    key: keys[0],
+                    origin: Some((Fingerprint::from(&[0xd3, 0x4d, 0xb3, 0x3f][..]), vec![ChildNumber::Hardened(0), ChildNumber::Normal(24)])),
+                },
+                SigningKey { key: keys[1], origin: None },
+            ],
+        };
+
+        let expected = [
+            "a7",                                                   // map(7)
+            "65696e646578", "01",                                   // "index": 1
+            "6474797065", "6673682d777368",                         // "type": "sh-wsh"
+            "66616d6f756e74", "1b000000012a05f200",                 // "amount": 5000000000
+            "6c736967686173685f74797065", "01",                     // "sighash_type": 1
+            "6773696768617368", "5820", &"11".repeat(32),           // "sighash": h'1111..'
+            "66736372697074", "4151",                               // "script": h'51'
+            "646b657973", "82",                                     // "keys": array(2)
+            "a3",                                                   // map(3)
+            "667075626b6579", "5821", &hex(&keys[0].serialize()[..]), // "pubkey": h'..'
+            "6b66696e6765727072696e74", "44d34db33f",               // "fingerprint": h'd34db33f'
+            "6470617468", "82", "1a80000000", "1818",               // "path": [0x80000000, 24]
+            "a1",                                                   // map(1)
+            "667075626b6579", "5821", &hex(&keys[1].serialize()[..]), // "pubkey": h'..'
+        ].concat();
+        assert_eq!(hex(&req.to_cbor()), expected);
+        assert_eq!(decode_cbor_all(&req.to_cbor()), expected_cbor(&req));
+        assert_eq!(hex(&requests_cbor(&[req])), format!("81{}", expected));
+    }
+
+    #[test]
+    fn cbor_round_trip() {
+        let keys = test_keys(3);
+        let origin = |path: Vec<ChildNumber>| Some((Fingerprint::from(&[0, 0, 0, 1][..]), path));
+        let wrappers = [Wrapper::Bare, Wrapper::Sh, Wrapper::Wsh, Wrapper::ShWsh, Wrapper::Wpkh, Wrapper::ShWpkh];
+        // Values on either side of each size of CBOR head
+        let amounts = [0, 23, 24, 0xff, 0x100, 0xffff, 0x1_0000, 0xffff_ffff, 0x1_0000_0000, u64::MAX];
+        let mut reqs = vec![];
+        for (i, &amount) in amounts.iter().enumerate() {
+            reqs.push(SigningRequest {
+                index: i * 1000,
+                wrapper: wrappers[i % wrappers.len()],
+                amount,
+                sighash_type: SigHashType::All,
+                sighash: Sha256dHash::from_data(&[i as u8]),
+                script: Script::from(vec![0xac; i * 30]),
+                keys: keys[..i % 4].iter().enumerate().map(|(j, pk)| SigningKey {
+                    key: *pk,
+                    origin: if j == 1 { None } else { origin(vec![ChildNumber::Hardened(j as u32); j * 10]) },
+                }).collect(),
+            });
+        }
+
+        for req in &reqs {
+            assert_eq!(decode_cbor_all(&req.to_cbor()), expected_cbor(req));
+        }
+        assert_eq!(
+            decode_cbor_all(&requests_cbor(&reqs)),
+            Cbor::Array(reqs.iter().map(expected_cbor).collect())
+        );
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let keys = test_keys(2);
+        let req = SigningRequest {
+            index: 3,
+            wrapper: Wrapper::ShWpkh,
+            amount: u64::MAX,
+            sighash_type: SigHashType::All,
+            sighash: Sha256dHash::from_data(&[]),
+            script: Script::from(vec![0x76, 0xa9]),
+            keys: vec![
+                SigningKey { key: keys[0], origin: None },
+                SigningKey { key: keys[1], origin: Some((Fingerprint::from(&[1, 2, 3, 4][..]), vec![ChildNumber::Hardened(84), ChildNumber::Normal(0)])) },
+            ],
+        };
+
+        let json = Json::parse(&req.to_json()).unwrap();
+        let string = |s: &str| Some(Json::String(s.to_owned()));
+        let number = |n: u64| Some(Json::Number(n.to_string()));
+        assert_eq!(json.get("index").cloned(), number(3));
+        assert_eq!(json.get("type").cloned(), string("sh-wpkh"));
+        assert_eq!(json.get("amount").cloned(), number(u64::MAX));
+        assert_eq!(json.get("sighash_type").cloned(), number(1));
+        assert_eq!(json.get("sighash").cloned(), string(&hex(&req.sighash[..])));
+        assert_eq!(json.get("script").cloned(), string("76a9"));
+        assert_eq!(json.get("keys").cloned(), Some(Json::Array(vec![
+            Json::Object(vec![("pubkey".to_owned(), Json::String(hex(&keys[0].serialize()[..])))]),
+            Json::Object(vec![
+                ("pubkey".to_owned(), Json::String(hex(&keys[1].serialize()[..]))),
+                ("fingerprint".to_owned(), Json::String("01020304".to_owned())),
+                ("path".to_owned(), Json::Array(vec![Json::Number("2147483732".to_owned()), Json::Number("0".to_owned())])),
+            ]),
+        ])));
+
+        match Json::parse(&requests_json(&[req.clone(), req])).unwrap() {
+            Json::Array(ref elements) => assert_eq!(elements.len(), 2),
+            json => panic!("not an array: {:?}", json),
+        }
+    }
+
+    #[test]
+    fn derived_requests() {
+        let receive: Descriptor<DescriptorXPub> = format!("wpkh([d34db33f/84'/0'/0']{}/0/*)", XPUB).parse().unwrap();
+        let change: Descriptor<DescriptorXPub> = format!("sh(wpkh({}/1/*))", XPUB).parse().unwrap();
+
+        let tx = spending_tx(2);
+        let reqs = derived_signing_requests(&tx, &[(&receive, 3, 50_000), (&change, 0, 60_000)]).unwrap();
+        assert_eq!(reqs.len(), 2);
+        assert_eq!(reqs[0].wrapper, Wrapper::Wpkh);
+        assert_eq!(reqs[1].wrapper, Wrapper::ShWpkh);
+        assert_eq!(reqs[1].index, 1);
+        assert_eq!(reqs[1].sighash_type, SigHashType::All);
+        assert_eq!(
+            reqs[0].keys[0].origin.as_ref().map(|(fingerprint, path)| (fingerprint[..].to_vec(), path.len())),
+            Some((vec![0xd3, 0x4d, 0xb3, 0x3f], 5))
+        );
+        // Without an origin, the path starts at the xpub
+        assert_eq!(
+            reqs[1].keys[0].origin.as_ref().map(|(_, path)| path.clone()),
+            Some(vec![ChildNumber::Normal(1), ChildNumber::Normal(0)])
+        );
+
+        let json = requests_json(&reqs);
+        assert!(json.starts_with("[{\"index\":0,\"type\":\"wpkh\",\"amount\":50000,"));
+        assert!(json.contains("{\"index\":1,\"type\":\"sh-wpkh\",\"amount\":60000,"));
+        let cbor = requests_cbor(&reqs);
+        assert_eq!(cbor[0], 0x82);
+        assert_eq!(cbor[1..].to_vec(), [reqs[0].to_cbor(), reqs[1].to_cbor()].concat());
+
+        assert_eq!(
+            derived_signing_requests(&tx, &[(&receive, 3, 50_000)]),
+            Err(Error::InputIndexOutOfRange(1))
+        );
+        let empty: Vec<SigningRequest> = vec![];
+        assert_eq!(requests_json(&empty), "[]");
+        assert_eq!(requests_cbor(&empty), vec![0x80]);
+    }
+}
@@ -0,0 +1,724 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Bitcoin Core Import
+//!
+//! Renders descriptors as the JSON requests of Bitcoin Core's `importdescriptors`
+//! and `importmulti` RPCs, for watching them from a Core wallet. Descriptors are
+//! given with their checksums. Core only understands its own subset of the descriptor
+//! language (`pk`, `pkh`, `multi`, `sortedmulti`, `wpkh`, `sh`, `wsh`, `combo`, `addr`
+//! and `raw`), so descriptors using the policy operators of this library will be
+//! rejected by it.
+//!
+//! In the other direction, reads the descriptors of a Core wallet, with the metadata
+//! Core keeps about them, out of the JSON output of its `listdescriptors` and
+//! `getaddressinfo` RPCs, for migrating the wallet. Their keys are parsed as the
+//! descriptor's key type, which must accept every key form the wallet uses.
+//!
+
+use std::fmt::Write;
+use std::str::{self, FromStr};
+
+use descriptor::PublicKey;
+use {Descriptor, Error};
+
+/// Deepest nesting of JSON arrays and objects which is read; Core's output nests
+/// no deeper than three
+const MAX_JSON_DEPTH: usize = 32;
+
+/// Time from which Core should rescan the chain for outputs of an imported descriptor
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Timestamp {
+    /// Do not rescan; the descriptor has not been used yet
+    Now,
+    /// UNIX time at which the descriptor was created
+    Time(u64),
+}
+
+/// A descriptor to import, along with the options of the import
+pub struct ImportRequest<'a, P: PublicKey + 'a> {
+    /// The descriptor
+    pub descriptor: &'a Descriptor<P>,
+    /// Inclusive range of child indices to import, for descriptors with wildcard keys
+    pub range: Option<(u32, u32)>,
+    /// Where to start rescanning from
+    pub timestamp: Timestamp,
+    /// Whether the outputs are change outputs. Core rejects labels for these.
+    pub internal: bool,
+    /// Label of the outputs in the wallet
+    pub label: Option<String>,
+    /// Whether Core should hand out addresses of a ranged descriptor
+    /// (`importdescriptors` only)
+    pub active: bool,
+}
+
+impl<'a, P: PublicKey> ImportRequest<'a, P> {
+    /// An import of `descriptor` without a range, label or rescan
+    pub fn new(descriptor: &'a Descriptor<P>) -> ImportRequest<'a, P> {
+        ImportRequest {
+            descriptor,
+            range: None,
+            timestamp: Timestamp::Now,
+            internal: false,
+            label: None,
+            active: false,
+        }
+    }
+
+    /// Writes the fields common to both RPCs, without the closing brace
+    fn write_common(&self, out: &mut String) {
+        out.push_str("{\"desc\":");
+        write_json_string(&self.descriptor.to_string_with_checksum(), out);
+        if let Some((start, end)) = self.range {
+            write!(out, ",\"range\":[{},{}]", start, end).expect("write to string");
+        }
+        match self.timestamp {
+            Timestamp::Now => out.push_str(",\"timestamp\":\"now\""),
+            Timestamp::Time(t) => write!(out, ",\"timestamp\":{}", t).expect("write to string"),
+        }
+        write!(out, ",\"internal\":{}", self.internal).expect("write to string");
+        if let Some(ref label) = self.label {
+            out.push_str(",\"label\":");
+            write_json_string(label, out);
+        }
+    }
+
+    /// The request as an element of the `requests` array of `importdescriptors`
+    pub fn import_descriptors_json(&self) -> String {
+        let mut ret = String::new();
+        self.write_common(&mut ret);
+        write!(ret, ",\"active\":{}}}", self.active).expect("write to string");
+        ret
+    }
+
+    /// The request as an element of the `requests` array of `importmulti`, which
+    /// imports the outputs as watch-only
+    pub fn import_multi_json(&self) -> String {
+        let mut ret = String::new();
+        self.write_common(&mut ret);
+        ret.push_str(",\"watchonly\":true}");
+        ret
+    }
+}
+
+/// The `requests` argument of `importdescriptors` for the given imports
+pub fn import_descriptors<P: PublicKey>(requests: &[ImportRequest<P>]) -> String {
+    let elements: Vec<String> = requests.iter().map(|req| req.import_descriptors_json()).collect();
+    format!("[{}]", elements.join(","))
+}
+
+/// The `requests` argument of `importmulti` for the given imports
+pub fn import_multi<P: PublicKey>(requests: &[ImportRequest<P>]) -> String {
+    let elements: Vec<String> = requests.iter().map(|req| req.import_multi_json()).collect();
+    format!("[{}]", elements.join(","))
+}
+
+/// A descriptor of a Bitcoin Core wallet, with the metadata Core keeps about it
+pub struct CoreDescriptor<P: PublicKey> {
+    /// The descriptor, whose checksum has been verified
+    pub descriptor: Descriptor<P>,
+    /// Inclusive range of child indices Core watches, for descriptors with wildcard keys
+    pub range: Option<(u32, u32)>,
+    /// Time from which Core scanned the chain for outputs of the descriptor
+    pub timestamp: Timestamp,
+    /// Whether Core hands out addresses of the descriptor
+    pub active: bool,
+    /// Whether the outputs are change outputs
+    pub internal: bool,
+    /// Child index of the next address Core will hand out, for active descriptors
+    pub next_index: Option<u32>,
+}
+
+impl<P: PublicKey> CoreDescriptor<P> {
+    /// Reads one element of the `descriptors` array of `listdescriptors`
+    fn from_json(json: &Json) -> Result<CoreDescriptor<P>, Error> {
+        let range = match json.get("range") {
+            None => None,
+            // `importdescriptors` also accepts the end of a range starting at 0
+            Some(&Json::Number(..)) => Some((0, json.u32_field("range")?)),
+            Some(Json::Array(ends)) if ends.len() == 2 => Some((ends[0].to_u32("range")?, ends[1].to_u32("range")?)),
+            Some(_) => return Err(Error::BadJson("\"range\" is not a range".to_owned())),
+        };
+        let timestamp = match json.get("timestamp") {
+            None => Timestamp::Now,
+            Some(Json::String(s)) if s == "now" => Timestamp::Now,
+            Some(t) => Timestamp::Time(t.to_u64("timestamp")?),
+        };
+        let next_index = match json.get("next") {
+            None => None,
+            Some(next) => Some(next.to_u32("next")?),
+        };
+        Ok(CoreDescriptor {
+            descriptor: json.descriptor_field()?,
+            range,
+            timestamp,
+            active: json.bool_field("active")?.unwrap_or(false),
+            internal: json.bool_field("internal")?.unwrap_or(false),
+            next_index,
+        })
+    }
+}
+
+/// Reads the descriptors of a wallet from the output of Core's `listdescriptors`,
+/// which may be given whole or as just its `descriptors` array
+pub fn from_list_descriptors<P: PublicKey>(json: &str) -> Result<Vec<CoreDescriptor<P>>, Error> {
+    let json = Json::parse(json)?;
+    let descriptors = match json {
+        Json::Array(..) => &json,
+        _ => json.get("descriptors").ok_or_else(|| missing_field("descriptors"))?,
+    };
+    match *descriptors {
+        Json::Array(ref elements) => elements.iter().map(CoreDescriptor::from_json).collect(),
+        _ => Err(Error::BadJson("\"descriptors\" is not an array".to_owned())),
+    }
+}
+
+/// Reads the descriptor of a single address from the output of Core's
+/// `getaddressinfo`, which only gives one for addresses the wallet can solve. The
+/// descriptor has no wildcard and is never active; whether it is internal is taken
+/// from the `ischange` field.
+pub fn from_address_info<P: PublicKey>(json: &str) -> Result<CoreDescriptor<P>, Error> {
+    let json = Json::parse(json)?;
+    let timestamp = match json.get("timestamp") {
+        None => Timestamp::Now,
+        Some(t) => Timestamp::Time(t.to_u64("timestamp")?),
+    };
+    Ok(CoreDescriptor {
+        descriptor: json.descriptor_field()?,
+        range: None,
+        timestamp,
+        active: false,
+        internal: json.bool_field("ischange")?.unwrap_or(false),
+        next_index: None,
+    })
+}
+
+fn missing_field(name: &str) -> Error {
+    Error::BadJson(format!("missing \"{}\"", name))
+}
+
+/// A parsed JSON value. Numbers are kept as written, and object fields in order.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub(crate) fn parse(s: &str) -> Result<Json, Error> {
+        let mut reader = JsonReader { s: s.as_bytes(), pos: 0 };
+        let ret = reader.value(0)?;
+        reader.skip_whitespace();
+        if reader.pos < reader.s.len() {
+            return Err(reader.error("end of input"));
+        }
+        Ok(ret)
+    }
+
+    /// The field `name` of an object
+    pub(crate) fn get(&self, name: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref fields) => fields.iter().find(|field| field.0 == name).map(|field| &field.1),
+            _ => None,
+        }
+    }
+
+    /// The value as a number of the given type, where it is the field `name`
+    fn to_number<N: FromStr>(&self, name: &str) -> Result<N, Error> {
+        match *self {
+            Json::Number(ref n) => n.parse().map_err(|_| Error::BadJson(format!("\"{}\" is out of range", name))),
+            _ => Err(Error::BadJson(format!("\"{}\" is not a number", name))),
+        }
+    }
+
+    fn to_u32(&self, name: &str) -> Result<u32, Error> {
+        self.to_number(name)
+    }
+
+    fn to_u64(&self, name: &str) -> Result<u64, Error> {
+        self.to_number(name)
+    }
+
+    fn u32_field(&self, name: &str) -> Result<u32, Error> {
+        self.get(name).ok_or_else(|| missing_field(name))?.to_u32(name)
+    }
+
+    /// The boolean field `name`, if present
+    fn bool_field(&self, name: &str) -> Result<Option<bool>, Error> {
+        match self.get(name) {
+            None => Ok(None),
+            Some(&Json::Bool(b)) => Ok(Some(b)),
+            Some(_) => Err(Error::BadJson(format!("\"{}\" is not a boolean", name))),
+        }
+    }
+
+    /// The descriptor of the `desc` field, which is checked against its checksum
+    fn descriptor_field<P: PublicKey>(&self) -> Result<Descriptor<P>, Error> {
+        match self.get("desc") {
+            Some(Json::String(desc)) => Descriptor::from_str(desc),
+            Some(_) => Err(Error::BadJson("\"desc\" is not a string".to_owned())),
+            None => Err(missing_field("desc")),
+        }
+    }
+}
+
+/// Reader of a JSON document, at byte offset `pos`
+struct JsonReader<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonReader<'a> {
+    fn error(&self, expected: &str) -> Error {
+        Error::BadJson(format!("expected {} at byte {}", expected, self.pos))
+    }
+
+    /// The byte at `pos`, if any
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.peek() {
+                Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') => self.pos += 1,
+                _ => return,
+            }
+        }
+    }
+
+    /// Skips whitespace and then `ch`, if it comes next
+    fn eat(&mut self, ch: u8) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(ch) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Skips the literal `word`, if it comes next
+    fn eat_word(&mut self, word: &str) -> bool {
+        if self.s[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Reads a value nested in `depth` arrays and objects
+    fn value(&mut self, depth: usize) -> Result<Json, Error> {
+        if depth > MAX_JSON_DEPTH {
+            return Err(Error::MaxRecursionDepthExceeded(MAX_JSON_DEPTH));
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = vec![];
+                if !self.eat(b'}') {
+                    loop {
+                        self.skip_whitespace();
+                        let name = self.string()?;
+                        if !self.eat(b':') {
+                            return Err(self.error("':'"));
+                        }
+                        fields.push((name, self.value(depth + 1)?));
+                        if self.eat(b'}') {
+                            break;
+                        } else if !self.eat(b',') {
+                            return Err(self.error("',' or '}'"));
+                        }
+                    }
+                }
+                Ok(Json::Object(fields))
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut elements = vec![];
+                if !self.eat(b']') {
+                    loop {
+                        elements.push(self.value(depth + 1)?);
+                        if self.eat(b']') {
+                            break;
+                        } else if !self.eat(b',') {
+                            return Err(self.error("',' or ']'"));
+                        }
+                    }
+                }
+                Ok(Json::Array(elements))
+            }
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'-') | Some(b'0'..=b'9') => self.number().map(Json::Number),
+            _ if self.eat_word("true") => Ok(Json::Bool(true)),
+            _ if self.eat_word("false") => Ok(Json::Bool(false)),
+            _ if self.eat_word("null") => Ok(Json::Null),
+            _ => Err(self.error("a value")),
+        }
+    }
+
+    /// Reads a number literal: an optional minus sign, an integer part without leading
+    /// zeroes, and optional fraction and exponent parts
+    fn number(&mut self) -> Result<String, Error> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        match self.peek() {
+            Some(b'0') => self.pos += 1,
+            Some(b'1'..=b'9') => self.digits(),
+            _ => return Err(self.error("a digit")),
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if !self.digit_next() {
+                return Err(self.error("a digit"));
+            }
+            self.digits();
+        }
+        if self.peek() == Some(b'e') || self.peek() == Some(b'E') {
+            self.pos += 1;
+            if self.peek() == Some(b'+') || self.peek() == Some(b'-') {
+                self.pos += 1;
+            }
+            if !self.digit_next() {
+                return Err(self.error("a digit"));
+            }
+            self.digits();
+        }
+        Ok(str::from_utf8(&self.s[start..self.pos]).expect("ASCII digits").to_owned())
+    }
+
+    /// Whether the byte at `pos` is a digit
+    fn digit_next(&self) -> bool {
+        matches!(self.peek(), Some(b'0'..=b'9'))
+    }
+
+    /// Skips the digits starting at `pos`
+    fn digits(&mut self) {
+        while self.digit_next() {
+            self.pos += 1;
+        }
+    }
+
+    /// Reads a string literal, undoing its escapes
+    fn string(&mut self) -> Result<String, Error> {
+        if self.s.get(self.pos) != Some(&b'"') {
+            return Err(self.error("a string"));
+        }
+        self.pos += 1;
+        let mut ret = vec![];
+        loop {
+            match self.peek() {
+                None => return Err(self.error("'\"'")),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let ch = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode_escape()?,
+                        _ => return Err(self.error("an escape")),
+                    };
+                    let mut buf = [0; 4];
+                    ret.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+                }
+                Some(0..=0x1f) => return Err(self.error("an escaped control character")),
+                Some(byte) => ret.push(byte),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        // Only whole characters of the (UTF-8) input were copied
+        Ok(String::from_utf8(ret).expect("valid UTF-8"))
+    }
+
+    /// Reads the `XXXX` of a `\uXXXX` escape, with the `u` at `pos`, and the low half
+    /// of a surrogate pair if it is the high half. Leaves `pos` at the last digit.
+    fn unicode_escape(&mut self) -> Result<char, Error> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            self.pos += 1;
+            if !self.eat_word("\\u") {
+                return Err(self.error("low surrogate"));
+            }
+            self.pos -= 1;
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(self.error("low surrogate"));
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        ::std::char::from_u32(code).ok_or_else(|| self.error("a character"))
+    }
+
+    /// Reads four hex digits following `pos`, leaving `pos` at the last
+    fn hex4(&mut self) -> Result<u32, Error> {
+        let digits = self.s.get(self.pos + 1..self.pos + 5)
+            .filter(|digits| digits.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|digits| str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("four hex digits"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+/// Writes `s` as a JSON string literal
+fn write_json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => write!(out, "\\u{:04x}", ch as u32).expect("write to string"),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1;
+
+    use {checksum, Descriptor, Error};
+    use xpub::DescriptorXPub;
+    use super::{
+        from_address_info, from_list_descriptors, import_descriptors, import_multi, write_json_string,
+        ImportRequest, Json, Timestamp, MAX_JSON_DEPTH,
+    };
+
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    #[test]
+    fn import_json() {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let desc = Descriptor::Wpkh(secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context"));
+        let desc_json = format!("\"{}\"", desc.to_string_with_checksum());
+
+        let request = ImportRequest::new(&desc);
+        assert_eq!(
+            request.import_descriptors_json(),
+            format!("{{\"desc\":{},\"timestamp\":\"now\",\"internal\":false,\"active\":false}}", desc_json)
+        );
+        assert_eq!(
+            request.import_multi_json(),
+            format!("{{\"desc\":{},\"timestamp\":\"now\",\"internal\":false,\"watchonly\":true}}", desc_json)
+        );
+
+        let labelled = ImportRequest {
+            range: Some((0, 999)),
+            timestamp: Timestamp::Time(1_500_000_000),
+            label: Some("cold \"storage\"".to_owned()),
+            active: true,
+            ..ImportRequest::new(&desc)
+        };
+        assert_eq!(
+            labelled.import_descriptors_json(),
+            format!(
+                "{{\"desc\":{},\"range\":[0,999],\"timestamp\":1500000000,\"internal\":false,\"label\":\"cold \\\"storage\\\"\",\"active\":true}}",
+                desc_json
+            )
+        );
+
+        let both = [request, labelled];
+        assert!(import_descriptors(&both).starts_with("[{\"desc\":"));
+        assert_eq!(import_multi(&both).matches("\"watchonly\":true").count(), 2);
+        assert_eq!(import_multi::<secp256k1::PublicKey>(&[]), "[]");
+    }
+
+    #[test]
+    fn list_descriptors() {
+        let receive = format!("wpkh({}/0/*)", XPUB);
+        let change = format!("wpkh({}/1/*)", XPUB);
+        let with_sum = |desc: &str| format!("{}#{}", desc, checksum::compute(desc).unwrap());
+        let elements = format!(
+            r#"[
+                {{"desc": "{}", "timestamp": 1500000000, "active": true, "internal": false, "range": [0, 999], "next": 12}},
+                {{"desc": "{}", "timestamp": "now", "active": true, "internal": true, "range": 99}}
+            ]"#,
+            with_sum(&receive),
+            with_sum(&change)
+        );
+        let json = format!("{{\"wallet_name\": \"caf\\u00e9\", \"descriptors\": {}}}", elements);
+
+        for json in &[json, elements] {
+            let descs = from_list_descriptors::<DescriptorXPub>(json).unwrap();
+            assert_eq!(descs.len(), 2);
+            assert_eq!(descs[0].descriptor.to_string(), receive);
+            assert_eq!(descs[0].range, Some((0, 999)));
+            assert_eq!(descs[0].timestamp, Timestamp::Time(1_500_000_000));
+            assert!(descs[0].active && !descs[0].internal);
+            assert_eq!(descs[0].next_index, Some(12));
+            assert_eq!(descs[1].descriptor.to_string(), change);
+            assert_eq!(descs[1].range, Some((0, 99)));
+            assert_eq!(descs[1].timestamp, Timestamp::Now);
+            assert!(descs[1].active && descs[1].internal);
+            assert_eq!(descs[1].next_index, None);
+        }
+
+        // Checksums are verified
+        let bad_sum = format!("[{{\"desc\": \"{}#qqqqqqqq\"}}]", receive);
+        match from_list_descriptors::<DescriptorXPub>(&bad_sum) {
+            Err(Error::BadChecksum(..)) => {}
+            _ => panic!("accepted a bad checksum"),
+        }
+        for bad in &["{}", "[{\"timestamp\": 1}]", "[{\"desc\": 1}]", "[", "[1,]", "{\"descriptors\": []} x"] {
+            match from_list_descriptors::<DescriptorXPub>(bad) {
+                Err(Error::BadJson(..)) => {}
+                _ => panic!("accepted {}", bad),
+            }
+        }
+        let deep = format!("{}{}", "[".repeat(100), "]".repeat(100));
+        assert!(from_list_descriptors::<DescriptorXPub>(&deep).is_err());
+    }
+
+    #[test]
+    fn address_info() {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let desc = Descriptor::Wpkh(secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context"));
+        let json = format!(
+            "{{\"address\": \"bc1q\", \"desc\": \"{}\", \"ischange\": true, \"timestamp\": 1600000000, \"labels\": [], \"iswatchonly\": false}}",
+            desc.to_string_with_checksum()
+        );
+        let info = from_address_info::<secp256k1::PublicKey>(&json).unwrap();
+        assert_eq!(info.descriptor.to_string(), desc.to_string());
+        assert_eq!(info.range, None);
+        assert_eq!(info.timestamp, Timestamp::Time(1_600_000_000));
+        assert!(info.internal && !info.active);
+
+        // Addresses the wallet cannot solve have no descriptor
+        match from_address_info::<secp256k1::PublicKey>("{\"address\": \"bc1q\", \"solvable\": false}") {
+            Err(Error::BadJson(..)) => {}
+            _ => panic!("no descriptor to read"),
+        }
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(
+            Json::parse(r#" "caf\u00e9 \ud83d\ude00 \"q\"\\\/\n" "#),
+            Ok(Json::String("café \u{1f600} \"q\"\\/\n".to_owned()))
+        );
+        assert_eq!(
+            Json::parse("{\"a\": [null, false, -1.5e3]}"),
+            Ok(Json::Object(vec![(
+                "a".to_owned(),
+                Json::Array(vec![Json::Null, Json::Bool(false), Json::Number("-1.5e3".to_owned())]),
+            )]))
+        );
+        assert!(Json::parse(r#""\ud83d""#).is_err());
+        assert!(Json::parse(r#""\x""#).is_err());
+        assert!(Json::parse("\"open").is_err());
+    }
+
+    #[test]
+    fn json_escapes() {
+        assert_eq!(
+            Json::parse(r#""\b\f\n\r\t\u0000\u001F\u00E9\uD83D\uDE00""#),
+            Ok(Json::String("\u{8}\u{c}\n\r\t\u{0}\u{1f}\u{e9}\u{1f600}".to_owned()))
+        );
+        // Unescaped non-ASCII characters are kept as they are
+        assert_eq!(Json::parse("\"café 😀\""), Ok(Json::String("café 😀".to_owned())));
+
+        for bad in &[
+            r#""\ude00""#,        // low surrogate without a high one
+            r#""\ud83d\u0041""#,  // high surrogate followed by a non-surrogate
+            r#""\ud83dx""#,       // high surrogate followed by a character
+            r#""\u12""#,          // too few hex digits
+            r#""\u12g4""#,        // not hex
+            r#""\u+12a""#,        // sign before the hex digits
+            r#""\"#,              // escape at the end of the input
+            "\"a\nb\"",           // unescaped control character
+            "\"\t\"",
+        ] {
+            match Json::parse(bad) {
+                Err(Error::BadJson(..)) => {}
+                x => panic!("accepted {:?}: {:?}", bad, x),
+            }
+        }
+
+        // Strings written for import requests read back the same
+        for s in &["", "plain", "\"quoted\" \\ back", "line\nfeed\r\ttab", "\u{0}\u{1}\u{1f}\u{7f}", "café \u{1f600}"] {
+            let mut json = String::new();
+            write_json_string(s, &mut json);
+            assert_eq!(Json::parse(&json), Ok(Json::String(s.to_string())), "{}", json);
+        }
+    }
+
+    #[test]
+    fn json_nesting() {
+        assert_eq!(
+            Json::parse(" { \"a\" : [ { } , [ [ ] ] , { \"b\" : { \"c\" : true } } ] , \"d\" : null } "),
+            Ok(Json::Object(vec![
+                ("a".to_owned(), Json::Array(vec![
+                    Json::Object(vec![]),
+                    Json::Array(vec![Json::Array(vec![])]),
+                    Json::Object(vec![("b".to_owned(), Json::Object(vec![("c".to_owned(), Json::Bool(true))]))]),
+                ])),
+                ("d".to_owned(), Json::Null),
+            ]))
+        );
+        // Fields are kept in order, and the first of a repeated name is used
+        let json = Json::parse("{\"x\": 1, \"y\": 2, \"x\": 3}").unwrap();
+        assert_eq!(json.get("x"), Some(&Json::Number("1".to_owned())));
+        assert_eq!(json.get("z"), None);
+
+        // Values nested `MAX_JSON_DEPTH` deep are read, and no deeper
+        let nested = |n: usize| format!("{}{}", "[".repeat(n), "]".repeat(n));
+        assert!(Json::parse(&nested(MAX_JSON_DEPTH + 1)).is_ok());
+        assert_eq!(Json::parse(&nested(MAX_JSON_DEPTH + 2)), Err(Error::MaxRecursionDepthExceeded(MAX_JSON_DEPTH)));
+        let objects = format!("{}1{}", "{\"a\":".repeat(MAX_JSON_DEPTH + 1), "}".repeat(MAX_JSON_DEPTH + 1));
+        assert_eq!(Json::parse(&objects), Err(Error::MaxRecursionDepthExceeded(MAX_JSON_DEPTH)));
+    }
+
+    #[test]
+    fn json_malformed() {
+        for good in &["0", "-0", "10", "1.5", "-0.25e10", "1E+3", "2e-7"] {
+            assert_eq!(Json::parse(good), Ok(Json::Number(good.to_string())));
+        }
+
+        for bad in &[
+            "", " ", "{", "}", "[", "]", "[1", "[1 2]", "[,1]", "[1]]", "{}{}",
+            "{\"a\"}", "{\"a\":}", "{\"a\":1,}", "{\"a\" 1}", "{a:1}", "{1:1}", "'a'",
+            "01", "-", "+1", ".5", "1.", "1.e3", "1e", "1e+", "1-2", "0x10", "NaN", "Infinity",
+            "tru", "nul", "True", "\"unterminated",
+        ] {
+            match Json::parse(bad) {
+                Err(Error::BadJson(..)) => {}
+                x => panic!("accepted {:?}: {:?}", bad, x),
+            }
+        }
+    }
+}
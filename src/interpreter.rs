@@ -0,0 +1,967 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Script Interpreter
+//!
+//! Executes a script of the recognized subset against a witness stack. Rather than
+//! decoding the script one opcode at a time, execution follows the parse tree, with
+//! each fragment carrying out the stack operations of its opcodes. This way a failure
+//! can be attributed to the innermost fragment which caused it, and the conditions
+//! met by a successful execution can be reported.
+//!
+//! Checking signatures and timelocks against a spending transaction is delegated to a
+//! `Checker`. Where only the structure of a spend is of interest, such as for spends
+//! already in the blockchain, `NoChecks` skips these checks entirely.
+//!
+
+use secp256k1;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{SigHashType, Transaction};
+use bitcoin::util::hash::{Hash160, Sha256dHash};
+use bitcoin_hashes::Hash;
+use bitcoin_hashes::ripemd160::Hash as Ripemd160Hash;
+
+use descriptor::{SEQUENCE_DISABLE_FLAG, SEQUENCE_LOCKTIME_MASK, SEQUENCE_TYPE_FLAG};
+use parse::{E, F, T, V, W};
+use sign::signature_hash;
+use {scriptnum, sha256, Condition, Error, HashImage, Wrapper};
+
+/// Checks the parts of a satisfaction which depend on the spending transaction
+pub trait Checker {
+    /// Whether `sig`, a DER signature followed by its sighash type byte, is a valid
+    /// signature of the spending transaction with `pk`
+    fn check_sig(&self, pk: &secp256k1::PublicKey, sig: &[u8]) -> bool;
+
+    /// Whether the spending transaction meets the relative locktime given by the
+    /// `CHECKSEQUENCEVERIFY` value `n`
+    fn check_older(&self, n: u32) -> bool;
+
+    /// Whether the dummy element consumed by `CHECKMULTISIG` must be empty, as the
+    /// NULLDUMMY rule of BIP147 requires of every spend since segwit activated. Spends
+    /// from before then may have any dummy element.
+    fn require_null_dummy(&self) -> bool {
+        true
+    }
+}
+
+/// Checker which accepts every non-empty signature and every relative locktime
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NoChecks;
+
+impl Checker for NoChecks {
+    fn check_sig(&self, _: &secp256k1::PublicKey, _: &[u8]) -> bool {
+        true
+    }
+
+    fn check_older(&self, _: u32) -> bool {
+        true
+    }
+}
+
+/// Checker which accepts any `CHECKMULTISIG` dummy element, as for spends from before
+/// BIP147, and otherwise checks as the wrapped checker does
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LaxNullDummy<C: Checker>(pub C);
+
+impl<C: Checker> Checker for LaxNullDummy<C> {
+    fn check_sig(&self, pk: &secp256k1::PublicKey, sig: &[u8]) -> bool {
+        self.0.check_sig(pk, sig)
+    }
+
+    fn check_older(&self, n: u32) -> bool {
+        self.0.check_older(n)
+    }
+
+    fn require_null_dummy(&self) -> bool {
+        false
+    }
+}
+
+/// The outcome of successfully executing a script against a witness
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Execution {
+    /// The conditions which were met, in the order in which the script checked them.
+    /// These make up the spend path which was taken, as listed by `ParseTree::spend_paths`
+    pub branch: Vec<Condition>,
+    /// Every relative locktime which was checked, each of which the sequence number of
+    /// the spending input must meet
+    pub timelocks: Vec<u32>,
+    /// Each public key which signed, in the order in which the script checked them,
+    /// along with its signature (including the sighash type byte)
+    pub signatures: Vec<(secp256k1::PublicKey, Vec<u8>)>,
+    /// Each hash whose preimage was revealed, along with the preimage
+    pub preimages: Vec<(HashImage, Vec<u8>)>,
+}
+
+/// A condition met during execution, along with the witness data which met it
+struct Met {
+    condition: Condition,
+    /// The key which signed, for signature conditions
+    key: Option<secp256k1::PublicKey>,
+    /// The signature or preimage, if any
+    data: Vec<u8>,
+}
+
+/// Checker for input `index` of `tx`, which spends an output of value `amount`
+/// whose script (of the given output type) is `script_code`
+pub(crate) struct TxChecker<'a> {
+    pub tx: &'a Transaction,
+    pub index: usize,
+    pub amount: u64,
+    pub script_code: Script,
+    pub wrapper: Wrapper,
+}
+
+impl<'a> Checker for TxChecker<'a> {
+    fn check_sig(&self, pk: &secp256k1::PublicKey, sig: &[u8]) -> bool {
+        let (hashtype, der) = match sig.split_last() {
+            Some(split) => split,
+            None => return false,
+        };
+        let hashtype = *hashtype as u32;
+        let sighash = if hashtype == SigHashType::All.as_u32() {
+            signature_hash(self.tx, self.index, self.amount, &self.script_code, self.wrapper)
+        } else {
+            match self.wrapper {
+                Wrapper::Bare | Wrapper::Sh => {
+                    self.tx.signature_hash(self.index, &self.script_code, hashtype)
+                }
+                // BIP143 hashes for other sighash types are not supported by rust-bitcoin
+                Wrapper::Wsh | Wrapper::ShWsh | Wrapper::Wpkh | Wrapper::ShWpkh => return false,
+            }
+        };
+
+        let secp = secp256k1::Secp256k1::new();
+        let msg = secp256k1::Message::from_slice(&sighash[..]).expect("32-byte sighash");
+        match secp256k1::Signature::from_der(&secp, der) {
+            Ok(sig) => secp.verify(&msg, &sig, pk).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn check_older(&self, n: u32) -> bool {
+        // As in BIP112, a locktime with the disable flag set is no locktime at all
+        if n & SEQUENCE_DISABLE_FLAG != 0 {
+            return true;
+        }
+        let sequence = self.tx.input[self.index].sequence;
+        self.tx.version >= 2 &&
+            sequence & SEQUENCE_DISABLE_FLAG == 0 &&
+            sequence & SEQUENCE_TYPE_FLAG == n & SEQUENCE_TYPE_FLAG &&
+            sequence & SEQUENCE_LOCKTIME_MASK >= n & SEQUENCE_LOCKTIME_MASK
+    }
+}
+
+/// Decodes a script number of at most 4 bytes, as accepted by arithmetic opcodes
+fn read_script_num(bytes: &[u8]) -> Result<i64, &'static str> {
+    scriptnum::decode(bytes, 4).ok_or("number too large for arithmetic")
+}
+
+/// Interprets a stack element as a boolean
+fn read_bool(bytes: &[u8]) -> bool {
+    match bytes.split_last() {
+        // Negative zero is false
+        Some((last, rest)) => rest.iter().any(|b| *b != 0) || (*last != 0 && *last != 0x80),
+        None => false,
+    }
+}
+
+/// Stack machine on which fragments act
+struct Machine<'a> {
+    stack: Vec<Vec<u8>>,
+    checker: &'a dyn Checker,
+    met: Vec<Met>,
+}
+
+impl<'a> Machine<'a> {
+    fn pop(&mut self) -> Result<Vec<u8>, &'static str> {
+        self.stack.pop().ok_or("stack underflow")
+    }
+
+    fn push_bool(&mut self, b: bool) {
+        self.stack.push(if b { vec![1] } else { vec![] });
+    }
+
+    fn pop_bool(&mut self) -> Result<bool, &'static str> {
+        self.pop().map(|x| read_bool(&x))
+    }
+
+    /// `VERIFY` of a boolean which is not on the stack
+    fn verify(&self, b: bool) -> Result<(), &'static str> {
+        if b {
+            Ok(())
+        } else {
+            Err("verification failed")
+        }
+    }
+
+    /// `SIZE IF`, which checks whether the top element is nonempty without consuming it
+    fn top_nonempty(&mut self) -> Result<bool, &'static str> {
+        self.stack.last().map(|x| !x.is_empty()).ok_or("stack underflow")
+    }
+
+    /// `IFDUP NOTIF`, which consumes the top element only if it is false
+    fn pop_if_false(&mut self) -> Result<bool, &'static str> {
+        let top = self.stack.last().map(|x| read_bool(x)).ok_or("stack underflow")?;
+        if !top {
+            self.stack.pop();
+        }
+        Ok(!top)
+    }
+
+    /// `SIZE EQUALVERIFY IF`, which consumes a selector that must be exactly 0 or 1.
+    /// The selector is taken to be a single stack element, as satisfactions provide it
+    fn select(&mut self) -> Result<bool, &'static str> {
+        let x = self.pop()?;
+        if x.is_empty() {
+            Ok(false)
+        } else if x == [1] {
+            Ok(true)
+        } else {
+            Err("selector is neither 0 nor 1")
+        }
+    }
+
+    /// `CHECKSIG` with the given key, recording the signature as meeting `condition`
+    fn pop_sig(&mut self, pk: &secp256k1::PublicKey, condition: Condition) -> Result<bool, &'static str> {
+        let sig = self.pop()?;
+        if sig.is_empty() {
+            Ok(false)
+        } else if self.checker.check_sig(pk, &sig) {
+            self.met.push(Met { condition, key: Some(*pk), data: sig });
+            Ok(true)
+        } else {
+            Err("invalid signature")
+        }
+    }
+
+    /// `<pk> CHECKSIG`
+    fn checksig(&mut self, pk: &secp256k1::PublicKey) -> Result<bool, &'static str> {
+        self.pop_sig(pk, Condition::Sig(*pk))
+    }
+
+    /// `<msg> <pk> CHECKSIGFROMSTACK`. The signature is of the SHA256 of `msg` rather
+    /// than of the spending transaction, so is checked here rather than by the checker.
+    #[cfg(feature = "elements")]
+    fn checksigfromstack(&mut self, pk: &secp256k1::PublicKey, msg: &sha256::Hash) -> Result<bool, &'static str> {
+        let sig = self.pop()?;
+        if sig.is_empty() {
+            return Ok(false);
+        }
+        let secp = secp256k1::Secp256k1::new();
+        let digest = sha256::Hash::from_data(&msg[..]);
+        let message = secp256k1::Message::from_slice(&digest[..]).expect("32-byte hash");
+        match secp256k1::Signature::from_der(&secp, &sig) {
+            Ok(parsed) if secp.verify(&message, &parsed, pk).is_ok() => {
+                self.met.push(Met { condition: Condition::MessageSig(*pk, *msg), key: Some(*pk), data: sig });
+                Ok(true)
+            }
+            _ => Err("invalid signature"),
+        }
+    }
+
+    /// `DUP HASH160 <hash> EQUALVERIFY CHECKSIG`
+    fn checksighash(&mut self, hash: &Hash160) -> Result<bool, &'static str> {
+        let pk = self.pop()?;
+        if Hash160::from_data(&pk) != *hash {
+            return Err("public key does not match hash");
+        }
+        let secp = secp256k1::Secp256k1::without_caps();
+        let pk = secp256k1::PublicKey::from_slice(&secp, &pk).map_err(|_| "invalid public key")?;
+        self.pop_sig(&pk, Condition::PkhSig(*hash))
+    }
+
+    /// `<k> <pk...> <len(pk)> CHECKMULTISIG`
+    fn checkmultisig(&mut self, k: usize, keys: &[secp256k1::PublicKey]) -> Result<bool, &'static str> {
+        let mut sigs = Vec::with_capacity(k);
+        for _ in 0..k {
+            sigs.push(self.pop()?);
+        }
+        // The dummy element sits below the signatures
+        if !self.pop()?.is_empty() && self.checker.require_null_dummy() {
+            return Err("non-empty CHECKMULTISIG dummy element");
+        }
+
+        // The topmost signature is checked against the last key first, and each
+        // signature may only be matched with a key earlier than the last one matched
+        let checker = self.checker;
+        let mut remaining_keys = keys.iter().rev();
+        let mut matched = Vec::with_capacity(k);
+        for sig in &sigs {
+            match remaining_keys.find(|pk| !sig.is_empty() && checker.check_sig(pk, sig)) {
+                Some(pk) => matched.push(Met { condition: Condition::Sig(*pk), key: Some(*pk), data: sig.clone() }),
+                None => break,
+            }
+        }
+        if matched.len() == k {
+            // Report the keys in script order
+            self.met.extend(matched.into_iter().rev());
+            Ok(true)
+        } else if sigs.iter().any(|sig| !sig.is_empty()) {
+            Err("invalid signature")
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// `<pk> CHECKSIG <pk> CHECKSIGADD ... <pk> CHECKSIGADD <k> NUMEQUAL`
+    fn checksigadd(&mut self, k: usize, keys: &[secp256k1::PublicKey]) -> Result<bool, &'static str> {
+        let mut count = 0;
+        for pk in keys {
+            if self.pop_sig(pk, Condition::Sig(*pk))? {
+                count += 1;
+            }
+        }
+        Ok(count == k)
+    }
+
+    /// `SIZE 32 EQUALVERIFY <hash op> <hash> EQUAL`
+    fn preimage(&mut self, image: HashImage) -> Result<bool, &'static str> {
+        let pre = self.pop()?;
+        if pre.len() != 32 {
+            return Err("preimage is not 32 bytes");
+        }
+        let ret = match image {
+            HashImage::Sha256(hash) => sha256::Hash::from_data(&pre) == hash,
+            HashImage::Hash160(hash) => Hash160::from_data(&pre) == hash,
+            HashImage::Ripemd160(hash) => Ripemd160Hash::hash(&pre) == hash,
+            HashImage::Hash256(hash) => Sha256dHash::from_data(&pre) == hash,
+        };
+        if ret {
+            self.met.push(Met { condition: Condition::Preimage(image), key: None, data: pre });
+        }
+        Ok(ret)
+    }
+
+    /// `<n> CSV`, which leaves `n` on the stack
+    fn csv(&mut self, n: u32) -> Result<(), &'static str> {
+        if self.checker.check_older(n) {
+            self.met.push(Met { condition: Condition::Csv(n), key: None, data: vec![] });
+            self.stack.push(scriptnum::encode(n as i64));
+            Ok(())
+        } else {
+            Err("relative locktime not met")
+        }
+    }
+
+    /// `ADD`
+    fn add(&mut self) -> Result<(), &'static str> {
+        let a = read_script_num(&self.pop()?)?;
+        let b = read_script_num(&self.pop()?)?;
+        self.stack.push(scriptnum::encode(a + b));
+        Ok(())
+    }
+
+    /// `<k> EQUAL`
+    fn equal_num(&mut self, k: usize) -> Result<bool, &'static str> {
+        let x = self.pop()?;
+        Ok(x == scriptnum::encode(k as i64))
+    }
+
+    /// `BOOLAND` or `BOOLOR`
+    fn bool_op(&mut self, and: bool) -> Result<(), &'static str> {
+        let a = read_script_num(&self.pop()?)? != 0;
+        let b = read_script_num(&self.pop()?)? != 0;
+        self.push_bool(if and { a && b } else { a || b });
+        Ok(())
+    }
+
+    /// Runs an operation of `W` form which is `SWAP` followed by an operation of `E`
+    /// form, leaving the result above the element which was on top
+    fn swapped<X, Op>(&mut self, op: Op) -> Result<X, &'static str>
+        where Op: FnOnce(&mut Machine<'a>) -> Result<X, &'static str>
+    {
+        let acc = self.pop()?;
+        let ret = op(self)?;
+        let result = self.pop()?;
+        self.stack.push(acc);
+        self.stack.push(result);
+        Ok(ret)
+    }
+}
+
+/// Attributes a failed stack operation to the fragment executing it
+macro_rules! op(
+    ($frag:expr, $op:expr) => (
+        $op.map_err(|reason| Error::ExecutionFailed {
+            fragment: $frag.to_string(),
+            reason,
+        })?
+    )
+);
+
+/// `<E> <W> ADD ... <W> ADD <k> EQUAL`
+fn threshold<X: ToString>(m: &mut Machine, frag: &X, k: usize, sube: &E, subw: &[W]) -> Result<bool, Error> {
+    execute_e(m, sube)?;
+    for w in subw {
+        execute_w(m, w)?;
+        op!(frag, m.add());
+    }
+    Ok(op!(frag, m.equal_num(k)))
+}
+
+fn execute_e(m: &mut Machine, e: &E) -> Result<(), Error> {
+    let met_len = m.met.len();
+    match *e {
+        E::CheckSig(ref pk) | E::CheckSigAggregate(ref pk, _) => {
+            let b = op!(e, m.checksig(pk));
+            m.push_bool(b);
+        }
+        E::CheckSigHash(ref hash) => {
+            let b = op!(e, m.checksighash(hash));
+            m.push_bool(b);
+        }
+        E::CheckSigHashF(ref hash) => if op!(e, m.top_nonempty()) {
+            let b = op!(e, m.checksighash(hash));
+            op!(e, m.verify(b));
+            m.push_bool(true);
+        },
+        E::CheckMultiSig(k, ref keys) => {
+            let b = op!(e, m.checkmultisig(k, keys));
+            m.push_bool(b);
+        }
+        E::CheckMultiSigF(k, ref keys) => if op!(e, m.top_nonempty()) {
+            let b = op!(e, m.checkmultisig(k, keys));
+            op!(e, m.verify(b));
+            m.push_bool(true);
+        },
+        E::CheckSigAdd(k, ref keys) => {
+            let b = op!(e, m.checksigadd(k, keys));
+            m.push_bool(b);
+        }
+        #[cfg(feature = "elements")]
+        E::CheckSigFromStack(ref pk, ref msg) => {
+            let b = op!(e, m.checksigfromstack(pk, msg));
+            m.push_bool(b);
+        }
+        E::HashEqual(hash) => execute_hash_e(m, e, HashImage::Sha256(hash))?,
+        E::Hash160Equal(hash) => execute_hash_e(m, e, HashImage::Hash160(hash))?,
+        E::Ripemd160Equal(hash) => execute_hash_e(m, e, HashImage::Ripemd160(hash))?,
+        E::Hash256Equal(hash) => execute_hash_e(m, e, HashImage::Hash256(hash))?,
+        E::Threshold(k, ref sube, ref subw) => {
+            let b = threshold(m, e, k, sube, subw)?;
+            m.push_bool(b);
+        }
+        E::ParallelAnd(ref left, ref right) => {
+            execute_e(m, left)?;
+            execute_w(m, right)?;
+            op!(e, m.bool_op(true));
+        }
+        E::CascadeAnd(ref left, ref right) => {
+            execute_e(m, left)?;
+            if op!(e, m.pop_bool()) {
+                execute_f(m, right)?;
+            } else {
+                m.push_bool(false);
+            }
+        }
+        E::ParallelOr(ref left, ref right) => {
+            execute_e(m, left)?;
+            execute_w(m, right)?;
+            op!(e, m.bool_op(false));
+        }
+        E::CascadeOr(ref left, ref right) => {
+            execute_e(m, left)?;
+            if op!(e, m.pop_if_false()) {
+                execute_e(m, right)?;
+            }
+        }
+        E::CastF(ref f) => {
+            if op!(e, m.select()) {
+                execute_f(m, f)?;
+            } else {
+                m.push_bool(false);
+            }
+        }
+    }
+    // A dissatisfied expression is not part of the spend path, even where some of
+    // its subexpressions were satisfied
+    if m.stack.last().is_none_or(|x| !read_bool(x)) {
+        m.met.truncate(met_len);
+    }
+    Ok(())
+}
+
+/// `SIZE IF SIZE 32 EQUALVERIFY <hash op> <hash> EQUALVERIFY 1 ENDIF`
+fn execute_hash_e<X: ToString>(m: &mut Machine, frag: &X, image: HashImage) -> Result<(), Error> {
+    if op!(frag, m.top_nonempty()) {
+        let b = op!(frag, m.preimage(image));
+        op!(frag, m.verify(b));
+        m.push_bool(true);
+    }
+    Ok(())
+}
+
+fn execute_w(m: &mut Machine, w: &W) -> Result<(), Error> {
+    match *w {
+        W::CheckSig(ref pk) | W::CheckSigAggregate(ref pk, _) => op!(w, m.swapped(|m| {
+            let b = m.checksig(pk)?;
+            m.push_bool(b);
+            Ok(())
+        })),
+        W::HashEqual(hash) => execute_hash_w(m, w, HashImage::Sha256(hash))?,
+        W::Hash160Equal(hash) => execute_hash_w(m, w, HashImage::Hash160(hash))?,
+        W::Ripemd160Equal(hash) => execute_hash_w(m, w, HashImage::Ripemd160(hash))?,
+        W::Hash256Equal(hash) => execute_hash_w(m, w, HashImage::Hash256(hash))?,
+        W::Csv(n) => op!(w, m.swapped(|m| {
+            if m.select()? {
+                m.csv(n)
+            } else {
+                m.push_bool(false);
+                Ok(())
+            }
+        })),
+        W::CastE(ref e) => {
+            let acc = op!(w, m.pop());
+            execute_e(m, e)?;
+            m.stack.push(acc);
+        }
+    }
+    Ok(())
+}
+
+/// `SWAP SIZE IF SIZE 32 EQUALVERIFY <hash op> <hash> EQUALVERIFY 1 ENDIF`
+fn execute_hash_w(m: &mut Machine, w: &W, image: HashImage) -> Result<(), Error> {
+    op!(w, m.swapped(|m| {
+        if m.top_nonempty()? {
+            let b = m.preimage(image)?;
+            m.verify(b)?;
+            m.push_bool(true);
+        }
+        Ok(())
+    }));
+    Ok(())
+}
+
+fn execute_f(m: &mut Machine, f: &F) -> Result<(), Error> {
+    match *f {
+        F::CheckSig(ref pk) | F::CheckSigAggregate(ref pk, _) => {
+            let b = op!(f, m.checksig(pk));
+            op!(f, m.verify(b));
+        }
+        F::CheckMultiSig(k, ref keys) => {
+            let b = op!(f, m.checkmultisig(k, keys));
+            op!(f, m.verify(b));
+        }
+        F::CheckSigAdd(k, ref keys) => {
+            let b = op!(f, m.checksigadd(k, keys));
+            op!(f, m.verify(b));
+        }
+        #[cfg(feature = "elements")]
+        F::CheckSigFromStack(ref pk, ref msg) => {
+            let b = op!(f, m.checksigfromstack(pk, msg));
+            op!(f, m.verify(b));
+        }
+        F::CheckSigHash(ref hash) => {
+            let b = op!(f, m.checksighash(hash));
+            op!(f, m.verify(b));
+        }
+        F::Csv(n) => {
+            op!(f, m.csv(n));
+            return Ok(());
+        }
+        F::Raw(..) => op!(f, Err("raw script cannot be executed")),
+        F::HashEqual(hash) => {
+            let b = op!(f, m.preimage(HashImage::Sha256(hash)));
+            op!(f, m.verify(b));
+        }
+        F::Hash160Equal(hash) => {
+            let b = op!(f, m.preimage(HashImage::Hash160(hash)));
+            op!(f, m.verify(b));
+        }
+        F::Ripemd160Equal(hash) => {
+            let b = op!(f, m.preimage(HashImage::Ripemd160(hash)));
+            op!(f, m.verify(b));
+        }
+        F::Hash256Equal(hash) => {
+            let b = op!(f, m.preimage(HashImage::Hash256(hash)));
+            op!(f, m.verify(b));
+        }
+        F::Threshold(k, ref sube, ref subw) => {
+            let b = threshold(m, f, k, sube, subw)?;
+            op!(f, m.verify(b));
+        }
+        F::And(ref left, ref right) => {
+            execute_v(m, left)?;
+            execute_f(m, right)?;
+            return Ok(());
+        }
+        F::ParallelOr(ref left, ref right) => {
+            execute_e(m, left)?;
+            execute_w(m, right)?;
+            op!(f, m.bool_op(false));
+            let b = op!(f, m.pop_bool());
+            op!(f, m.verify(b));
+        }
+        F::SwitchOr(ref left, ref right) => {
+            if op!(f, m.select()) {
+                execute_f(m, left)?;
+            } else {
+                execute_f(m, right)?;
+            }
+            return Ok(());
+        }
+        F::SwitchOrV(ref left, ref right) => {
+            if op!(f, m.select()) {
+                execute_v(m, left)?;
+            } else {
+                execute_v(m, right)?;
+            }
+        }
+        F::CascadeOr(ref left, ref right) => {
+            execute_e(m, left)?;
+            if op!(f, m.pop_if_false()) {
+                execute_f(m, right)?;
+            }
+            return Ok(());
+        }
+        F::CascadeOrV(ref left, ref right) => {
+            execute_e(m, left)?;
+            if !op!(f, m.pop_bool()) {
+                execute_v(m, right)?;
+            }
+        }
+    }
+    // Every fragment which does not return early ends by pushing a 1
+    m.push_bool(true);
+    Ok(())
+}
+
+fn execute_v(m: &mut Machine, v: &V) -> Result<(), Error> {
+    match *v {
+        V::CheckSig(ref pk) | V::CheckSigAggregate(ref pk, _) => {
+            let b = op!(v, m.checksig(pk));
+            op!(v, m.verify(b));
+        }
+        V::CheckMultiSig(k, ref keys) => {
+            let b = op!(v, m.checkmultisig(k, keys));
+            op!(v, m.verify(b));
+        }
+        V::CheckSigAdd(k, ref keys) => {
+            let b = op!(v, m.checksigadd(k, keys));
+            op!(v, m.verify(b));
+        }
+        #[cfg(feature = "elements")]
+        V::CheckSigFromStack(ref pk, ref msg) => {
+            let b = op!(v, m.checksigfromstack(pk, msg));
+            op!(v, m.verify(b));
+        }
+        V::CheckSigHash(ref hash) => {
+            let b = op!(v, m.checksighash(hash));
+            op!(v, m.verify(b));
+        }
+        V::Csv(n) => {
+            op!(v, m.csv(n));
+            op!(v, m.pop());
+        }
+        V::Raw(..) => op!(v, Err("raw script cannot be executed")),
+        V::HashEqual(hash) => {
+            let b = op!(v, m.preimage(HashImage::Sha256(hash)));
+            op!(v, m.verify(b));
+        }
+        V::Hash160Equal(hash) => {
+            let b = op!(v, m.preimage(HashImage::Hash160(hash)));
+            op!(v, m.verify(b));
+        }
+        V::Ripemd160Equal(hash) => {
+            let b = op!(v, m.preimage(HashImage::Ripemd160(hash)));
+            op!(v, m.verify(b));
+        }
+        V::Hash256Equal(hash) => {
+            let b = op!(v, m.preimage(HashImage::Hash256(hash)));
+            op!(v, m.verify(b));
+        }
+        V::Threshold(k, ref sube, ref subw) => {
+            let b = threshold(m, v, k, sube, subw)?;
+            op!(v, m.verify(b));
+        }
+        V::And(ref left, ref right) => {
+            execute_v(m, left)?;
+            execute_v(m, right)?;
+        }
+        V::ParallelOr(ref left, ref right) => {
+            execute_e(m, left)?;
+            execute_w(m, right)?;
+            op!(v, m.bool_op(false));
+            let b = op!(v, m.pop_bool());
+            op!(v, m.verify(b));
+        }
+        V::SwitchOr(ref left, ref right) => {
+            if op!(v, m.select()) {
+                execute_v(m, left)?;
+            } else {
+                execute_v(m, right)?;
+            }
+        }
+        V::SwitchOrT(ref left, ref right) => {
+            if op!(v, m.select()) {
+                execute_t(m, left)?;
+            } else {
+                execute_t(m, right)?;
+            }
+            let b = op!(v, m.pop_bool());
+            op!(v, m.verify(b));
+        }
+        V::CascadeOr(ref left, ref right) => {
+            execute_e(m, left)?;
+            if !op!(v, m.pop_bool()) {
+                execute_v(m, right)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn execute_t(m: &mut Machine, t: &T) -> Result<(), Error> {
+    match *t {
+        T::HashEqual(hash) => {
+            let b = op!(t, m.preimage(HashImage::Sha256(hash)));
+            m.push_bool(b);
+        }
+        T::Hash160Equal(hash) => {
+            let b = op!(t, m.preimage(HashImage::Hash160(hash)));
+            m.push_bool(b);
+        }
+        T::Ripemd160Equal(hash) => {
+            let b = op!(t, m.preimage(HashImage::Ripemd160(hash)));
+            m.push_bool(b);
+        }
+        T::Hash256Equal(hash) => {
+            let b = op!(t, m.preimage(HashImage::Hash256(hash)));
+            m.push_bool(b);
+        }
+        T::And(ref left, ref right) => {
+            execute_v(m, left)?;
+            execute_t(m, right)?;
+        }
+        T::SwitchOr(ref left, ref right) => {
+            if op!(t, m.select()) {
+                execute_t(m, left)?;
+            } else {
+                execute_t(m, right)?;
+            }
+        }
+        T::CascadeOr(ref left, ref right) => {
+            execute_e(m, left)?;
+            if op!(t, m.pop_if_false()) {
+                execute_t(m, right)?;
+            }
+        }
+        T::CastE(ref e) => execute_e(m, e)?,
+        T::CastF(ref f) => execute_f(m, f)?,
+    }
+    Ok(())
+}
+
+/// Executes the script `top` against `witness`, whose last element is the top of the
+/// stack, succeeding if the script leaves exactly one true element on the stack
+pub(crate) fn execute(top: &T, witness: &[Vec<u8>], checker: &dyn Checker) -> Result<Execution, Error> {
+    let mut m = Machine {
+        stack: witness.to_owned(),
+        checker,
+        met: vec![],
+    };
+    execute_t(&mut m, top)?;
+    match m.stack.len() {
+        1 if read_bool(&m.stack[0]) => {
+            let mut ret = Execution {
+                branch: Vec::with_capacity(m.met.len()),
+                timelocks: vec![],
+                signatures: vec![],
+                preimages: vec![],
+            };
+            for met in m.met {
+                match (met.condition, met.key) {
+                    (Condition::Csv(n), _) => ret.timelocks.push(n),
+                    (Condition::Preimage(image), _) => ret.preimages.push((image, met.data)),
+                    (_, Some(pk)) => ret.signatures.push((pk, met.data)),
+                    (_, None) => unreachable!("signature conditions are met with a key"),
+                }
+                ret.branch.push(met.condition);
+            }
+            Ok(ret)
+        }
+        1 => Err(Error::ExecutionFailed { fragment: top.to_string(), reason: "script returned false" }),
+        _ => Err(Error::ExecutionFailed { fragment: top.to_string(), reason: "stack not clean" }),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use secp256k1;
+
+    use parse::{E, F, T, V, W};
+    use {Condition, Error};
+    use super::{execute, Checker, Execution, LaxNullDummy};
+
+    /// Checker which accepts any signature, and relative locktimes of up to 10
+    struct Lenient;
+
+    impl Checker for Lenient {
+        fn check_sig(&self, _: &secp256k1::PublicKey, sig: &[u8]) -> bool {
+            sig == [1]
+        }
+
+        fn check_older(&self, n: u32) -> bool {
+            n <= 10
+        }
+    }
+
+    fn pubkeys(n: usize) -> Vec<secp256k1::PublicKey> {
+        let secp = secp256k1::Secp256k1::new();
+        (1..n as u8 + 1).map(|i| {
+            let sk = secp256k1::SecretKey::from_slice(&secp, &[i; 32]).expect("secret key");
+            secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context")
+        }).collect()
+    }
+
+    #[test]
+    fn execute_fragments() {
+        let keys = pubkeys(3);
+
+        let single = T::CastE(Arc::new(E::CheckSig(keys[0])));
+        assert_eq!(
+            execute(&single, &[vec![1]], &Lenient),
+            Ok(Execution {
+                branch: vec![Condition::Sig(keys[0])],
+                timelocks: vec![],
+                signatures: vec![(keys[0], vec![1])],
+                preimages: vec![],
+            })
+        );
+        assert_eq!(
+            execute(&single, &[vec![]], &Lenient),
+            Err(Error::ExecutionFailed { fragment: single.to_string(), reason: "script returned false" })
+        );
+        assert_eq!(
+            execute(&single, &[vec![2]], &Lenient),
+            Err(Error::ExecutionFailed { fragment: E::CheckSig(keys[0]).to_string(), reason: "invalid signature" })
+        );
+        assert_eq!(
+            execute(&single, &[vec![], vec![1]], &Lenient),
+            Err(Error::ExecutionFailed { fragment: single.to_string(), reason: "stack not clean" })
+        );
+
+        // Each `W` accumulates the sum below its own input
+        let thresh = T::CastE(Arc::new(E::Threshold(
+            2,
+            Arc::new(E::CheckSig(keys[0])),
+            vec![W::CheckSig(keys[1]), W::CheckSig(keys[2])],
+        )));
+        let branch = |witness: &[Vec<u8>]| execute(&thresh, witness, &Lenient).map(|exec| exec.branch);
+        assert_eq!(branch(&[vec![], vec![1], vec![1]]), Ok(vec![Condition::Sig(keys[0]), Condition::Sig(keys[1])]));
+        assert_eq!(branch(&[vec![1], vec![], vec![1]]), Ok(vec![Condition::Sig(keys[0]), Condition::Sig(keys[2])]));
+        assert!(execute(&thresh, &[vec![], vec![], vec![1]], &Lenient).is_err());
+
+        let older = T::CastF(Arc::new(F::Csv(11)));
+        assert_eq!(
+            execute(&older, &[], &Lenient),
+            Err(Error::ExecutionFailed { fragment: F::Csv(11).to_string(), reason: "relative locktime not met" })
+        );
+
+        // Selectors must be minimal
+        let switch = T::CastE(Arc::new(E::CastF(Arc::new(F::Csv(10)))));
+        assert_eq!(
+            execute(&switch, &[vec![1]], &Lenient).map(|exec| (exec.branch, exec.timelocks)),
+            Ok((vec![Condition::Csv(10)], vec![10]))
+        );
+        assert!(execute(&switch, &[vec![2]], &Lenient).is_err());
+    }
+
+    #[test]
+    fn satisfied_branch() {
+        let keys = pubkeys(3);
+
+        // The left branch of the cascade is dissatisfied, despite one of its signatures
+        let tree = T::CascadeOr(
+            Arc::new(E::Threshold(
+                2,
+                Arc::new(E::CheckSig(keys[0])),
+                vec![W::CheckSig(keys[1])],
+            )),
+            Arc::new(T::CastF(Arc::new(F::And(
+                Arc::new(V::Csv(5)),
+                Arc::new(F::CheckSig(keys[2])),
+            )))),
+        );
+        assert_eq!(
+            execute(&tree, &[vec![1], vec![], vec![1]], &Lenient).map(|exec| (exec.branch, exec.timelocks)),
+            Ok((vec![Condition::Csv(5), Condition::Sig(keys[2])], vec![5]))
+        );
+        assert_eq!(
+            execute(&tree, &[vec![1], vec![1]], &Lenient).map(|exec| (exec.branch, exec.timelocks)),
+            Ok((vec![Condition::Sig(keys[0]), Condition::Sig(keys[1])], vec![]))
+        );
+    }
+
+    /// Checker which accepts only the signature `[i]` for the `i`th of `pubkeys(3)`
+    struct PerKey(Vec<secp256k1::PublicKey>);
+
+    impl Checker for PerKey {
+        fn check_sig(&self, pk: &secp256k1::PublicKey, sig: &[u8]) -> bool {
+            self.0.iter().position(|key| key == pk).map(|i| sig == [i as u8]) == Some(true)
+        }
+
+        fn check_older(&self, _: u32) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn checkmultisig() {
+        let keys = pubkeys(3);
+        let checker = PerKey(keys.clone());
+        let multi = T::CastE(Arc::new(E::CheckMultiSig(2, keys[..].into())));
+        let sigs = |witness: &[Vec<u8>], checker: &dyn Checker| execute(&multi, witness, checker).map(|exec| exec.signatures);
+
+        // As in Bitcoin Core, the dummy is below the signatures, which are in the order
+        // of their keys with the last on top
+        assert_eq!(
+            sigs(&[vec![], vec![0], vec![2]], &checker),
+            Ok(vec![(keys[0], vec![0]), (keys[2], vec![2])])
+        );
+        assert_eq!(
+            sigs(&[vec![], vec![1], vec![2]], &checker),
+            Ok(vec![(keys[1], vec![1]), (keys[2], vec![2])])
+        );
+        assert_eq!(
+            sigs(&[vec![], vec![2], vec![0]], &checker),
+            Err(Error::ExecutionFailed { fragment: E::CheckMultiSig(2, keys[..].into()).to_string(), reason: "invalid signature" })
+        );
+        assert_eq!(
+            sigs(&[vec![], vec![], vec![]], &checker),
+            Err(Error::ExecutionFailed { fragment: multi.to_string(), reason: "script returned false" })
+        );
+
+        // NULLDUMMY, unless relaxed
+        assert_eq!(
+            sigs(&[vec![0], vec![0], vec![2]], &checker),
+            Err(Error::ExecutionFailed {
+                fragment: E::CheckMultiSig(2, keys[..].into()).to_string(),
+                reason: "non-empty CHECKMULTISIG dummy element",
+            })
+        );
+        assert!(checker.require_null_dummy());
+        let lax = LaxNullDummy(PerKey(keys.clone()));
+        assert!(!lax.require_null_dummy());
+        assert_eq!(
+            sigs(&[vec![0], vec![0], vec![2]], &lax),
+            Ok(vec![(keys[0], vec![0]), (keys[2], vec![2])])
+        );
+        // The dummy is still required to be there
+        assert!(sigs(&[vec![0], vec![2]], &lax).is_err());
+    }
+}
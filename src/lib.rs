@@ -20,73 +20,184 @@
 //!
 
 extern crate bitcoin;
+extern crate bitcoin_hashes;
 extern crate secp256k1;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
 
+#[cfg(any(test, feature = "arbitrary"))]
+pub mod arbitrary;
+pub mod checksum;
+mod compiler;
+pub mod contract;
 pub mod descriptor;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod generators;
+pub mod hww;
+pub mod import;
+pub mod interpreter;
 pub mod parse;
+pub mod psbt;
+pub mod satisfy;
+pub mod scriptnum;
+pub mod sha256;
+pub mod sign;
+#[cfg(feature = "signer")]
+pub mod signer;
+pub mod taproot;
+pub mod templates;
+pub mod wallet;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod xprv;
+pub mod xpub;
 
 use std::{error, fmt};
 
 use bitcoin::blockdata::{opcodes, script};
+use bitcoin::util::bip32;
 use bitcoin::util::hash::{Hash160, Sha256dHash};
+use bitcoin_hashes::ripemd160::Hash as Ripemd160Hash;
 
+pub use compiler::{Candidate, CompileCache, CompileStep, CompilerVersion, CostParams, SpendStats};
 pub use descriptor::Descriptor;
-pub use parse::ParseTree;
+pub use interpreter::{Checker, Execution, LaxNullDummy, NoChecks};
+pub use parse::{Condition, ExprType, Fragment, HashImage, KeyReport, KeySource, LimitViolation, Malleability, Node, ParseTree, ScriptContext, SpendPathWeight, SpendTime, Template, TimelockConflict, Token, TokenStream, TreeStats, Wrapper};
+pub use satisfy::{SatisfactionMode, SatisfactionState, Satisfier};
 
 /// Script Descriptor error
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
-    /// Opcode appeared which is not part of the script subset
-    InvalidOpcode(opcodes::All),
+    /// Opcode appeared which is not part of the script subset, at the given byte offset
+    /// in the script
+    InvalidOpcode(opcodes::All, usize),
     /// Push was illegal in some context
     InvalidPush(Vec<u8>),
-    /// rust-bitcoin script error
-    Script(script::Error),
+    /// rust-bitcoin script error, at the given byte offset in the script
+    Script(script::Error, usize),
     /// Encountered unprintable character in descriptor
-    Unprintable(u8),
+    Unprintable(char),
     /// expected character while parsing descriptor; didn't find one
     ExpectedChar(char),
     /// While parsing backward, hit beginning of script
     UnexpectedStart,
     /// Got something we were not expecting
     Unexpected(String),
+    /// Script did not match the recognized subset
+    ParseError {
+        /// Byte offset in the script of the opcode at which parsing failed
+        offset: usize,
+        /// Index of that opcode among all the opcodes of the script
+        index: usize,
+        /// The token or subexpression which was not recognized
+        found: String,
+        /// What would have been recognized in its place
+        expected: &'static str,
+    },
     /// Failed to parse a push as a public key
     BadPubkey(secp256k1::Error),
     /// Could not satisfy a script (fragment) because of a missing hash preimage
-    MissingHash(Sha256dHash),
+    MissingHash(sha256::Hash),
+    /// Could not satisfy a script (fragment) because of a missing HASH160 preimage
+    MissingHash160(Hash160),
+    /// Could not satisfy a script (fragment) because of a missing RIPEMD160 preimage
+    MissingRipemd160(Ripemd160Hash),
+    /// Could not satisfy a script (fragment) because of a missing HASH256 preimage
+    MissingHash256(Sha256dHash),
     /// Could not satisfy a script (fragment) because of a missing signature
     MissingSig(secp256k1::PublicKey),
     /// Could not satisfy a script (fragment) because of a missing pubkey corresponding to a pkh hash
     MissingPubkey(Hash160),
     /// Could not satisfy, locktime not met
     LocktimeNotMet(u32),
+    /// Could not satisfy a raw script fragment, for which the satisfier gave nothing
+    MissingRaw(script::Script),
     /// General failure to satisfy
-    CouldNotSatisfy
+    CouldNotSatisfy,
+    /// Attempted to satisfy an output which can never be spent, such as an `OP_RETURN` output
+    Unspendable,
+    /// Attempted to satisfy a transaction input which does not exist
+    InputIndexOutOfRange(usize),
+    /// Descriptor checksum was malformed or did not match the descriptor
+    BadChecksum(String),
+    /// Enumerating the spend paths of a script exceeded the given limit
+    TooManySpendPaths(usize),
+    /// Descriptor could not be compiled into a script
+    Compile(CompileError),
+    /// Executing a script against a witness failed
+    ExecutionFailed {
+        /// The innermost fragment whose execution failed
+        fragment: String,
+        /// Why it failed
+        reason: &'static str,
+    },
+    /// A scriptSig or witness did not have the form of a spend of the script
+    MalformedInput(&'static str),
+    /// A script or descriptor was nested more deeply than the given limit
+    MaxRecursionDepthExceeded(usize),
+    /// Tweaking a key by a contract commitment gave an invalid key
+    BadTweak(secp256k1::Error),
+    /// Deriving a child of an extended public key failed
+    Bip32(bip32::Error),
+    /// A wildcard extended public key was instantiated without a child index
+    MissingChildIndex(String),
+    /// JSON output of Bitcoin Core was malformed or lacked a field
+    BadJson(String),
+    /// A satisfaction exceeds a standardness limit on witnesses, so a transaction
+    /// using it would not be relayed
+    NonStandardWitness(LimitViolation),
+    /// A signature given to satisfy a script is not canonical low-S DER with a defined
+    /// sighash type, so a transaction using it would not be relayed
+    NonStandardSignature(&'static str),
 }
 
 impl error::Error for Error {
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
             Error::BadPubkey(ref e) => Some(e),
+            Error::BadTweak(ref e) => Some(e),
+            Error::Bip32(ref e) => Some(e),
+            Error::Compile(ref e) => Some(e),
             _ => None,
         }
     }
 
+    #[allow(deprecated)]
     fn description(&self) -> &str {
         match *self {
             Error::InvalidOpcode(..) => "invalid opcode",
             Error::InvalidPush(..) => "invalid push",
-            Error::Script(ref e) => error::Error::description(e),
+            Error::Script(ref e, _) => error::Error::description(e),
             Error::Unprintable(..) => "unprintable character in descriptor",
             Error::ExpectedChar(..) => "invalid character in descriptor",
             Error::UnexpectedStart => "unexpected start of script",
             Error::Unexpected(..) => "unexpected token",
+            Error::ParseError { .. } => "script does not match recognized subset",
             Error::MissingHash(..) => "missing hash preimage",
+            Error::MissingHash160(..) => "missing hash160 preimage",
+            Error::MissingRipemd160(..) => "missing ripemd160 preimage",
+            Error::MissingHash256(..) => "missing hash256 preimage",
             Error::MissingSig(..) => "missing signature (checksig)",
             Error::MissingPubkey(..) => "missing pubkey (p2pkh)",
             Error::LocktimeNotMet(..) => "locktime not met",
+            Error::MissingRaw(..) => "missing satisfaction of raw script",
             Error::CouldNotSatisfy => "could not satisfy",
+            Error::Unspendable => "output is unspendable",
+            Error::InputIndexOutOfRange(..) => "input index out of range",
+            Error::BadChecksum(..) => "invalid descriptor checksum",
+            Error::TooManySpendPaths(..) => "too many spend paths",
+            Error::Compile(ref e) => error::Error::description(e),
+            Error::ExecutionFailed { .. } => "script execution failed",
+            Error::MalformedInput(..) => "malformed transaction input",
+            Error::MaxRecursionDepthExceeded(..) => "maximum recursion depth exceeded",
             Error::BadPubkey(ref e) => error::Error::description(e),
+            Error::BadTweak(..) => "invalid contract tweak",
+            Error::Bip32(ref e) => error::Error::description(e),
+            Error::MissingChildIndex(..) => "missing child index for wildcard key",
+            Error::BadJson(..) => "invalid Bitcoin Core JSON",
+            Error::NonStandardWitness(..) => "nonstandard witness",
+            Error::NonStandardSignature(..) => "nonstandard signature",
         }
     }
 }
@@ -94,20 +205,165 @@ impl error::Error for Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Error::InvalidOpcode(ref op) => write!(f, "invalid opcode {}", op),
+            Error::InvalidOpcode(ref op, offset) => write!(f, "invalid opcode {} at byte {}", op, offset),
             Error::InvalidPush(ref push) => write!(f, "invalid push {:?}", push), // TODO hexify this
-            Error::Script(ref e) => fmt::Display::fmt(e, f),
-            Error::Unprintable(x) => write!(f, "unprintable character 0x{:02x}", x),
+            Error::Script(ref e, offset) => write!(f, "{} at byte {}", e, offset),
+            Error::Unprintable(x) => write!(f, "unprintable character 0x{:02x}", x as u32),
             Error::ExpectedChar(c) => write!(f, "expected {}", c),
             Error::UnexpectedStart => f.write_str("unexpected start of script"),
             Error::Unexpected(ref s) => write!(f, "unexpected «{}»", s),
+            Error::ParseError { offset, index, ref found, expected } => write!(
+                f,
+                "unexpected «{}» at byte {} (opcode {}), expected {}",
+                found, offset, index, expected
+            ),
             Error::MissingHash(ref h) => write!(f, "missing preimage of hash {}", h),
+            Error::MissingHash160(ref h) => write!(f, "missing preimage of hash160 {:?}", h),
+            Error::MissingRipemd160(ref h) => write!(f, "missing preimage of ripemd160 {:?}", h),
+            Error::MissingHash256(ref h) => write!(f, "missing preimage of hash256 {}", h),
             Error::MissingSig(ref pk) => write!(f, "missing signature for key {:?}", pk),
             Error::MissingPubkey(ref hash) => write!(f, "missing public key for hash {:?}", hash),
             Error::LocktimeNotMet(n) => write!(f, "required locktime of {} blocks, not met", n),
+            Error::MissingRaw(ref script) => write!(f, "missing satisfaction of raw script {}", script),
             Error::CouldNotSatisfy => f.write_str("could not satisfy"),
+            Error::Unspendable => f.write_str("cannot satisfy a provably unspendable output"),
+            Error::InputIndexOutOfRange(n) => write!(f, "transaction has no input {}", n),
+            Error::BadChecksum(ref s) => write!(f, "invalid descriptor checksum «{}»", s),
+            Error::TooManySpendPaths(n) => write!(f, "script has more than {} spend paths", n),
+            Error::Compile(ref e) => fmt::Display::fmt(e, f),
+            Error::ExecutionFailed { ref fragment, reason } => write!(f, "{} in «{}»", reason, fragment),
+            Error::MalformedInput(s) => write!(f, "malformed transaction input: {}", s),
+            Error::MaxRecursionDepthExceeded(n) => write!(f, "nesting deeper than the maximum of {} levels", n),
             Error::BadPubkey(ref e) => fmt::Display::fmt(e, f),
+            Error::BadTweak(ref e) => write!(f, "contract tweak gave an invalid key: {}", e),
+            Error::Bip32(ref e) => fmt::Display::fmt(e, f),
+            Error::MissingChildIndex(ref s) => write!(f, "no child index given for wildcard key «{}»", s),
+            Error::BadJson(ref s) => write!(f, "invalid Bitcoin Core JSON: {}", s),
+            Error::NonStandardWitness(v) => write!(f, "satisfaction exceeds standardness limit: {:?}", v),
+            Error::NonStandardSignature(s) => write!(f, "nonstandard signature: {}", s),
         }
     }
 
 }
+
+/// Descriptor compilation error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    /// Threshold or multisig with no subexpressions or keys
+    EmptyThreshold,
+    /// Threshold or multisig requiring more of its (k, n) subexpressions than it has
+    ThresholdKTooLarge(usize, usize),
+    /// Descriptor (fragment) which has no script of its own, such as a wrapper
+    Unsupported(String),
+    /// Descriptor was nested more deeply than the given limit
+    PolicyTooDeep(usize),
+    /// Descriptor cannot be compiled for the given kind of script
+    UnsupportedContext(ScriptContext),
+    /// Bare descriptor does not compile to a standard scriptpubkey template
+    NonStandardBare(String),
+    /// Compiled script exceeds a limit of the kind of script it is compiled for
+    LimitExceeded(ScriptContext, LimitViolation),
+    /// Descriptor contains a key aggregate, but no aggregation function was given
+    NoAggregator(String),
+    /// Aggregation function failed to aggregate the keys of a key aggregate
+    BadAggregate(secp256k1::Error),
+    /// No compilation fits within the given script size; the smallest found had the
+    /// second size
+    OverBudget(usize, usize),
+    /// Compiled script contains a raw script fragment which cannot be tokenized
+    BadRawScript(String),
+    /// Weighted disjunction whose branches both have odds of zero
+    ZeroOdds(String),
+}
+
+impl error::Error for CompileError {
+    fn cause(&self) -> Option<&dyn error::Error> {
+        match *self {
+            CompileError::BadAggregate(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            CompileError::EmptyThreshold => "empty threshold",
+            CompileError::ThresholdKTooLarge(..) => "threshold larger than number of subexpressions",
+            CompileError::Unsupported(..) => "descriptor cannot be compiled",
+            CompileError::PolicyTooDeep(..) => "descriptor nested too deeply",
+            CompileError::UnsupportedContext(..) => "cannot compile for this kind of script",
+            CompileError::NonStandardBare(..) => "nonstandard bare script",
+            CompileError::LimitExceeded(..) => "compiled script exceeds limits",
+            CompileError::NoAggregator(..) => "no key aggregation function",
+            CompileError::BadAggregate(..) => "key aggregation failed",
+            CompileError::OverBudget(..) => "compiled script too large",
+            CompileError::BadRawScript(..) => "raw script cannot be tokenized",
+            CompileError::ZeroOdds(..) => "disjunction with zero odds on both branches",
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompileError::EmptyThreshold => f.write_str("cannot compile empty threshold"),
+            CompileError::ThresholdKTooLarge(k, n) => write!(f, "cannot compile {}-of-{} threshold", k, n),
+            CompileError::Unsupported(ref s) => write!(f, "cannot compile «{}»", s),
+            CompileError::PolicyTooDeep(n) => write!(f, "descriptor nested more than {} deep", n),
+            CompileError::UnsupportedContext(ctx) => write!(f, "cannot compile {} scripts", ctx),
+            CompileError::NonStandardBare(ref s) => write!(f, "«{}» is not a standard bare script", s),
+            CompileError::LimitExceeded(ctx, v) => write!(f, "compiled {} script exceeds limit: {:?}", ctx, v),
+            CompileError::NoAggregator(ref s) => write!(f, "cannot compile «{}» without a key aggregation function", s),
+            CompileError::BadAggregate(ref e) => write!(f, "key aggregation failed: {}", e),
+            CompileError::OverBudget(max, size) => write!(f, "smallest compiled script has {} bytes, more than {}", size, max),
+            CompileError::BadRawScript(ref s) => write!(f, "«{}» contains a raw script which cannot be tokenized", s),
+            CompileError::ZeroOdds(ref s) => write!(f, "«{}» gives both branches odds of zero", s),
+        }
+    }
+}
+
+/// Descriptor sanity check failure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SanityError {
+    /// Threshold or multisig whose k (of n) is zero or larger than n
+    BadThreshold(usize, usize),
+    /// Multisig with more keys than the standardness limit of 20
+    TooManyKeys(usize),
+    /// Multisig which contains the same key more than once
+    DuplicateKey(String),
+    /// Relative locktime which is zero or has the disable flag set
+    BadTimelock(u32),
+    /// Conjunction which may require both a height-based and a time-based relative locktime
+    MixedTimelocks(String),
+    /// Weighted disjunction whose odds are both zero
+    ZeroOdds(String),
+}
+
+impl error::Error for SanityError {
+    fn cause(&self) -> Option<&dyn error::Error> {
+        None
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            SanityError::BadThreshold(..) => "threshold out of range",
+            SanityError::TooManyKeys(..) => "too many keys in multisig",
+            SanityError::DuplicateKey(..) => "duplicate key in multisig",
+            SanityError::BadTimelock(..) => "invalid relative locktime",
+            SanityError::MixedTimelocks(..) => "mixed height-based and time-based locktimes",
+            SanityError::ZeroOdds(..) => "disjunction with zero odds",
+        }
+    }
+}
+
+impl fmt::Display for SanityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SanityError::BadThreshold(k, n) => write!(f, "{}-of-{} threshold out of range", k, n),
+            SanityError::TooManyKeys(n) => write!(f, "multisig has {} keys, more than 20", n),
+            SanityError::DuplicateKey(ref s) => write!(f, "duplicate key in «{}»", s),
+            SanityError::BadTimelock(n) => write!(f, "invalid relative locktime {}", n),
+            SanityError::MixedTimelocks(ref s) => write!(f, "mixed height-based and time-based locktimes in «{}»", s),
+            SanityError::ZeroOdds(ref s) => write!(f, "both odds are zero in «{}»", s),
+        }
+    }
+}
@@ -22,29 +22,74 @@
 //! than going directly to script.
 //!
 
-use std::fmt;
-use std::collections::HashMap;
+use std::{cmp, fmt};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
 use secp256k1;
 
 use bitcoin::blockdata::script;
 use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::transaction::{SigHashType, Transaction};
 use bitcoin::util::hash::Hash160;
-use bitcoin::util::hash::Sha256dHash; // TODO needs to be sha256, not sha256d
-
-use super::{Descriptor, Error};
+use bitcoin::util::hash::Sha256dHash;
+use bitcoin_hashes::Hash;
+use bitcoin_hashes::ripemd160::Hash as Ripemd160Hash;
+
+use compiler::CompileStep;
+use interpreter::{self, Checker, Execution, NoChecks, TxChecker};
+use psbt::KeyOrigin;
+use sign::disassemble_input;
+use satisfy::{validate_signature, Strategy};
+use super::{compiler, scriptnum, sha256, CompileError, CompilerVersion, CostParams, Descriptor, Error, SatisfactionMode, Satisfier, SpendStats};
+use descriptor::{join_timelock_mixes, KeyAggregator, SEQUENCE_LOCKTIME_MASK, SEQUENCE_TYPE_FLAG};
+use descriptor::{TIMELOCK_BOTH, TIMELOCK_HEIGHT, TIMELOCK_NONE, TIMELOCK_TIME};
+
+/// Serializes a signature for inclusion in a witness, i.e. in DER form followed by
+/// the sighash type byte
+fn serialize_sig(sig: &secp256k1::Signature, hashtype: SigHashType) -> Vec<u8> {
+    let secp = secp256k1::Secp256k1::without_caps();
+    let mut ret = sig.serialize_der(&secp);
+    ret.push(hashtype.as_u32() as u8);
+    ret
+}
 
-/// Computes witness size, assuming individual pushes are less than 254 bytes
-fn satisfy_cost(s: &[Vec<u8>]) -> usize {
-    s.iter().map(|s| 1 + s.len()).sum()
+/// Looks up a signature with `pk` and serializes it as for `serialize_sig`, checking
+/// that it is standard unless the satisfier says otherwise
+fn lookup_sig(satisfier: &dyn Satisfier, pk: &secp256k1::PublicKey) -> Result<Option<Vec<u8>>, Error> {
+    match satisfier.lookup_sig(pk) {
+        Some((sig, hashtype)) => {
+            let ret = serialize_sig(&sig, hashtype);
+            if satisfier.require_standard_sigs() {
+                validate_signature(&ret)?;
+            }
+            Ok(Some(ret))
+        }
+        None => Ok(None),
+    }
 }
 
 /// Helper function that produces a checksig(verify) satisfaction
 fn satisfy_checksig(
     pk: &secp256k1::PublicKey,
-    key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
+    satisfier: &dyn Satisfier,
 ) -> Result<Vec<Vec<u8>>, Error> {
-    let secp = secp256k1::Secp256k1::without_caps();
-    if let Some(sig) = key_map.get(&pk) {
+    if let Some(sig) = lookup_sig(satisfier, pk)? {
+        Ok(vec![sig])
+    } else {
+        Err(Error::MissingSig(*pk))
+    }
+}
+
+/// Helper function that produces a checksigfromstack(verify) satisfaction, a signature
+/// of the message without a sighash byte
+#[cfg(feature = "elements")]
+fn satisfy_checksigfromstack(
+    pk: &secp256k1::PublicKey,
+    msg: &sha256::Hash,
+    satisfier: &dyn Satisfier,
+) -> Result<Vec<Vec<u8>>, Error> {
+    if let Some(sig) = satisfier.lookup_message_sig(pk, msg) {
+        let secp = secp256k1::Secp256k1::without_caps();
         Ok(vec![sig.serialize_der(&secp)])
     } else {
         Err(Error::MissingSig(*pk))
@@ -54,167 +99,266 @@ fn satisfy_checksig(
 /// Helper function that produces a checksig(verify)hash satisfaction
 fn satisfy_checksighash(
     hash: &Hash160,
-    key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
-    pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
+    satisfier: &dyn Satisfier,
 ) -> Result<Vec<Vec<u8>>, Error> {
-    let secp = secp256k1::Secp256k1::without_caps();
-    if let Some(pk) = pkh_map.get(hash) {
-        if let Some(sig) = key_map.get(pk) {
+    if let Some(pk) = satisfier.lookup_pkh(hash) {
+        if let Some(sig) = lookup_sig(satisfier, &pk)? {
             Ok(vec![
-                sig.serialize_der(&secp),
+                sig,
                 pk.serialize()[..].to_owned(),
             ])
         } else {
-            Err(Error::MissingSig(*pk))
+            Err(Error::MissingSig(pk))
         }
     } else {
         Err(Error::MissingPubkey(*hash))
     }
 }
 
-/// Helper function that produces a checkmultisig(verify) satisfaction
+/// Helper function that produces a checkmultisig(verify) satisfaction: the empty dummy
+/// element which `CHECKMULTISIG` consumes below the signatures, then `k` signatures in
+/// the order of their keys, so that the last key's signature is on top. Which `k` of
+/// the available signatures are used is up to `strategy`; they are put back in key
+/// order once chosen, as `CHECKMULTISIG` requires.
 fn satisfy_checkmultisig(
     k: usize,
     keys: &[secp256k1::PublicKey],
-    key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
+    satisfier: &dyn Satisfier,
+    strategy: Strategy,
 ) -> Result<Vec<Vec<u8>>, Error> {
-    let secp = secp256k1::Secp256k1::without_caps();
-    let mut ret = Vec::with_capacity(k);
-    for pk in keys {
-        if let Some(sig) = key_map.get(pk) {
-            ret.push(sig.serialize_der(&secp));
-            if ret.len() > k {
-                let max_idx = ret
-                    .iter()
-                    .enumerate()
-                    .max_by_key(|(_, ref sig)| sig.len())
-                    .unwrap()
-                    .0;
-                ret.remove(max_idx);
-            }
+    // In the modes which take the first `k` signatures, the satisfier need not be
+    // asked about any keys after those
+    let lazy = strategy.mode == SatisfactionMode::Canonical || strategy.mode == SatisfactionMode::Lazy;
+    let mut sigs: Vec<(usize, Vec<u8>)> = Vec::with_capacity(keys.len());
+    for (i, pk) in keys.iter().enumerate() {
+        if lazy && sigs.len() == k {
+            break;
+        }
+        if let Some(sig) = lookup_sig(satisfier, pk)? {
+            sigs.push((i, sig));
         }
     }
-    if ret.len() == k {
-        ret.push(vec![]);
-        Ok(ret)
-    } else {
-        Err(Error::CouldNotSatisfy)
+    if sigs.len() < k {
+        return Err(Error::CouldNotSatisfy);
+    }
+
+    // Ties go to the earlier key
+    match strategy.mode {
+        SatisfactionMode::Smallest => sigs.sort_by_key(|&(i, ref sig)| (sig.len(), i)),
+        SatisfactionMode::Canonical | SatisfactionMode::Lazy => {}
+        SatisfactionMode::Max => sigs.sort_by_key(|&(i, ref sig)| (cmp::Reverse(sig.len()), i)),
+    }
+    sigs.truncate(k);
+    sigs.sort_by_key(|&(i, _)| i);
+
+    let mut ret = Vec::with_capacity(k + 1);
+    ret.push(vec![]);
+    ret.extend(sigs.into_iter().map(|(_, sig)| sig));
+    Ok(ret)
+}
+
+/// Builds the witness for a `CHECKSIGADD` multisig: a signature or an empty push for
+/// every key, the first key's on top
+fn satisfy_checksigadd(
+    k: usize,
+    keys: &[secp256k1::PublicKey],
+    satisfier: &dyn Satisfier,
+    strategy: Strategy,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let mut sigs: Vec<Option<Vec<u8>>> = keys
+        .iter()
+        .map(|pk| lookup_sig(satisfier, pk))
+        .collect::<Result<_, _>>()?;
+    let mut n_sigs = sigs.iter().filter(|sig| sig.is_some()).count();
+    if n_sigs < k {
+        return Err(Error::CouldNotSatisfy);
+    }
+    while n_sigs > k {
+        let drop_idx = {
+            let mut present = sigs
+                .iter()
+                .enumerate()
+                .filter_map(|(i, sig)| sig.as_ref().map(|sig| (i, sig.len())));
+            match strategy.mode {
+                SatisfactionMode::Smallest => present.max_by_key(|&(_, len)| len).unwrap().0,
+                SatisfactionMode::Canonical | SatisfactionMode::Lazy => present.next_back().unwrap().0,
+                SatisfactionMode::Max => present.min_by_key(|&(_, len)| len).unwrap().0,
+            }
+        };
+        sigs[drop_idx] = None;
+        n_sigs -= 1;
     }
+    Ok(sigs.into_iter().rev().map(|sig| sig.unwrap_or_default()).collect())
 }
 
 fn satisfy_hashequal(
-    hash: &Sha256dHash,
-    hash_map: &HashMap<Sha256dHash, [u8; 32]>,
+    hash: &sha256::Hash,
+    satisfier: &dyn Satisfier,
 ) -> Result<Vec<Vec<u8>>, Error> {
-    if let Some(pre) = hash_map.get(&hash) {
+    if let Some(pre) = satisfier.lookup_hash_preimage(hash) {
         Ok(vec![pre[..].to_owned()])
     } else {
         Err(Error::MissingHash(*hash))
     }
 }
 
-fn satisfy_csv(n: u32, age: u32) -> Result<Vec<Vec<u8>>, Error> {
-    if age >= n {
+fn satisfy_hash160equal(
+    hash: &Hash160,
+    satisfier: &dyn Satisfier,
+) -> Result<Vec<Vec<u8>>, Error> {
+    if let Some(pre) = satisfier.lookup_hash160_preimage(hash) {
+        Ok(vec![pre[..].to_owned()])
+    } else {
+        Err(Error::MissingHash160(*hash))
+    }
+}
+
+fn satisfy_ripemd160equal(
+    hash: &Ripemd160Hash,
+    satisfier: &dyn Satisfier,
+) -> Result<Vec<Vec<u8>>, Error> {
+    if let Some(pre) = satisfier.lookup_ripemd160_preimage(hash) {
+        Ok(vec![pre[..].to_owned()])
+    } else {
+        Err(Error::MissingRipemd160(*hash))
+    }
+}
+
+fn satisfy_hash256equal(
+    hash: &Sha256dHash,
+    satisfier: &dyn Satisfier,
+) -> Result<Vec<Vec<u8>>, Error> {
+    if let Some(pre) = satisfier.lookup_hash256_preimage(hash) {
+        Ok(vec![pre[..].to_owned()])
+    } else {
+        Err(Error::MissingHash256(*hash))
+    }
+}
+
+fn satisfy_csv(n: u32, satisfier: &dyn Satisfier) -> Result<Vec<Vec<u8>>, Error> {
+    if satisfier.check_older(n) {
         Ok(vec![])
     } else {
         Err(Error::LocktimeNotMet(n))
     }
 }
 
+fn satisfy_raw(script: &script::Script, satisfier: &dyn Satisfier) -> Result<Vec<Vec<u8>>, Error> {
+    if let Some(sat) = satisfier.lookup_raw(script) {
+        Ok(sat)
+    } else {
+        Err(Error::MissingRaw(script.clone()))
+    }
+}
+
 fn satisfy_threshold(
     k: usize,
     sube: &E,
     subw: &[W],
-    key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
-    pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
-    hash_map: &HashMap<Sha256dHash, [u8; 32]>,
-    age: u32,
+    satisfier: &dyn Satisfier,
+    strategy: Strategy,
 ) -> Result<Vec<Vec<u8>>, Error> {
-    if k == 0 {
-        return Ok(vec![]);
-    }
-
-    let mut satisfactions = Vec::with_capacity(1 + subw.len());
-    if let Ok(sat) = sube.satisfy(key_map, pkh_map, hash_map, age) {
-        satisfactions.push(sat);
+    let mut subs: Vec<&dyn AstElem> = Vec::with_capacity(1 + subw.len());
+    subs.push(sube);
+    subs.extend(subw.iter().map(|w| w as &dyn AstElem));
+
+    // In the modes which take the first `k` satisfactions, the satisfier need not
+    // be asked about any subexpressions after those
+    let mut order: Vec<usize> = (0..subs.len()).collect();
+    if strategy.mode == SatisfactionMode::Lazy {
+        order.sort_by_key(|i| subs[*i].max_satisfaction_size());
     }
-    for sub in subw {
-        if let Ok(sat) = sub.satisfy(key_map, pkh_map, hash_map, age) {
-            satisfactions.push(sat);
+    let lazy = strategy.mode == SatisfactionMode::Canonical || strategy.mode == SatisfactionMode::Lazy;
+    let mut satisfactions = vec![None; subs.len()];
+    let mut n_satisfied = 0;
+    for i in order {
+        if let Ok(sat) = subs[i].satisfy(satisfier, strategy) {
+            satisfactions[i] = Some(sat);
+            n_satisfied += 1;
+            if lazy && n_satisfied == k {
+                break;
+            }
         }
     }
-    if satisfactions.len() < k {
+    if n_satisfied < k {
         return Err(Error::CouldNotSatisfy);
     }
 
-    let mut indices: Vec<usize> = (0..satisfactions.len()).collect();
-    indices.sort_by_key(|i| satisfy_cost(&satisfactions[*i]));
-
-    let mut n_pushes = 0;
-    for idx in indices.iter().take(k) {
-        n_pushes += satisfactions[*idx].len();
+    // Sorting is stable, so equally costly satisfactions are chosen in script order
+    let mut satisfied: Vec<usize> = (0..subs.len()).filter(|i| satisfactions[*i].is_some()).collect();
+    match strategy.mode {
+        SatisfactionMode::Smallest => satisfied.sort_by_key(|i| strategy.cost(satisfactions[*i].as_ref().unwrap())),
+        SatisfactionMode::Canonical | SatisfactionMode::Lazy => {}
+        SatisfactionMode::Max => satisfied.sort_by_key(|i| cmp::Reverse(strategy.cost(satisfactions[*i].as_ref().unwrap()))),
+    }
+    for i in satisfied.into_iter().skip(k) {
+        satisfactions[i] = None;
     }
 
-    let mut ret = Vec::with_capacity(n_pushes);
-    for idx in indices.into_iter().take(k) {
-        use std::mem;
-        let obj = mem::replace(&mut satisfactions[idx], vec![]);
-        ret.extend(obj);
+    // `E` executes first and so must find its witness on top of the stack, i.e. at
+    // the end, with each `W` in turn below it. Every subexpression which was not
+    // chosen is dissatisfied in its place
+    let mut ret = vec![];
+    for (i, sat) in satisfactions.into_iter().enumerate().rev() {
+        match sat {
+            Some(sat) => ret.extend(sat),
+            None if i == 0 => ret.extend(sube.dissatisfy(satisfier)?),
+            None => ret.extend(subw[i - 1].dissatisfy(satisfier)?),
+        }
     }
     Ok(ret)
 }
 
+// The left subexpression of a combinator executes first, so its part of the
+// witness goes at the end, where it is on top of the stack
 fn satisfy_parallel_or(
     left: &E,
     right: &W,
-    key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
-    pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
-    hash_map: &HashMap<Sha256dHash, [u8; 32]>,
-    age: u32,
+    satisfier: &dyn Satisfier,
+    strategy: Strategy,
 ) -> Result<Vec<Vec<u8>>, Error> {
     match (
-        left.satisfy(key_map, pkh_map, hash_map, age),
-        right.satisfy(key_map, pkh_map, hash_map, age),
+        left.satisfy(satisfier, strategy),
+        right.satisfy(satisfier, strategy),
     ) {
-        (Ok(mut lsat), Err(..)) => {
-            let rdissat = right.dissatisfy(pkh_map)?;
-            lsat.extend(rdissat);
-            Ok(lsat)
+        (Ok(lsat), Err(..)) => {
+            let mut rdissat = right.dissatisfy(satisfier)?;
+            rdissat.extend(lsat);
+            Ok(rdissat)
         }
-        (Err(..), Ok(rsat)) => {
-            let mut ldissat = left.dissatisfy(pkh_map)?;
-            ldissat.extend(rsat);
-            Ok(ldissat)
+        (Err(..), Ok(mut rsat)) => {
+            let ldissat = left.dissatisfy(satisfier)?;
+            rsat.extend(ldissat);
+            Ok(rsat)
         }
         (Err(e), Err(..)) => {
             Err(e)
         }
-        (Ok(mut lsat), Ok(rsat)) => {
-            let mut ldissat = left.dissatisfy(pkh_map)?;
-            let rdissat = right.dissatisfy(pkh_map)?;
-
-            if satisfy_cost(&lsat) + satisfy_cost(&rdissat) <= satisfy_cost(&rsat) + satisfy_cost(&ldissat) {
-                lsat.extend(rdissat);
-                Ok(lsat)
+        (Ok(lsat), Ok(mut rsat)) => {
+            let ldissat = left.dissatisfy(satisfier)?;
+            let mut rdissat = right.dissatisfy(satisfier)?;
+
+            let lcost = strategy.cost(&lsat) + strategy.cost(&rdissat);
+            if strategy.prefer_first(lcost, strategy.cost(&rsat) + strategy.cost(&ldissat)) {
+                rdissat.extend(lsat);
+                Ok(rdissat)
             } else {
-                ldissat.extend(rsat);
-                Ok(ldissat)
+                rsat.extend(ldissat);
+                Ok(rsat)
             }
         }
     }
 }
 
 fn satisfy_switch_or<T: AstElem>(
-    left: &Box<T>,
-    right: &Box<T>,
-    key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
-    pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
-    hash_map: &HashMap<Sha256dHash, [u8; 32]>,
-    age: u32,
+    left: &Arc<T>,
+    right: &Arc<T>,
+    satisfier: &dyn Satisfier,
+    strategy: Strategy,
 ) -> Result<Vec<Vec<u8>>, Error> {
     match (
-        left.satisfy(key_map, pkh_map, hash_map, age),
-        right.satisfy(key_map, pkh_map, hash_map, age),
+        left.satisfy(satisfier, strategy),
+        right.satisfy(satisfier, strategy),
     ) {
         (Err(e), Err(..)) => Err(e),
         (Ok(mut lsat), Err(..)) => {
@@ -226,7 +370,8 @@ fn satisfy_switch_or<T: AstElem>(
             Ok(rsat)
         }
         (Ok(mut lsat), Ok(mut rsat)) => {
-            if satisfy_cost(&lsat) + 2 <= satisfy_cost(&rsat) + 1 {
+            let lcost = strategy.cost(&lsat) + strategy.cost(&[vec![1]]);
+            if strategy.prefer_first(lcost, strategy.cost(&rsat) + strategy.cost(&[vec![]])) {
                 lsat.push(vec![1]);
                 Ok(lsat)
             } else {
@@ -238,71 +383,137 @@ fn satisfy_switch_or<T: AstElem>(
 }
 
 fn satisfy_cascade_or<T: AstElem>(
-    left: &Box<E>,
-    right: &Box<T>,
-    key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
-    pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
-    hash_map: &HashMap<Sha256dHash, [u8; 32]>,
-    age: u32,
+    left: &Arc<E>,
+    right: &Arc<T>,
+    satisfier: &dyn Satisfier,
+    strategy: Strategy,
 ) -> Result<Vec<Vec<u8>>, Error> {
     match (
-        left.satisfy(key_map, pkh_map, hash_map, age),
-        right.satisfy(key_map, pkh_map, hash_map, age),
+        left.satisfy(satisfier, strategy),
+        right.satisfy(satisfier, strategy),
     ) {
         (Err(e), Err(..)) => Err(e),
         (Ok(lsat), Err(..)) => Ok(lsat),
-        (Err(..), Ok(rsat)) => {
-            let mut ldissat = left.dissatisfy(pkh_map)?;
-            ldissat.extend(rsat);
-            Ok(ldissat)
+        (Err(..), Ok(mut rsat)) => {
+            let ldissat = left.dissatisfy(satisfier)?;
+            rsat.extend(ldissat);
+            Ok(rsat)
         }
-        (Ok(lsat), Ok(rsat)) => {
-            let mut ldissat = left.dissatisfy(pkh_map)?;
+        (Ok(lsat), Ok(mut rsat)) => {
+            let ldissat = left.dissatisfy(satisfier)?;
 
-            if satisfy_cost(&lsat) <= satisfy_cost(&rsat) + satisfy_cost(&ldissat) {
+            if strategy.prefer_first(strategy.cost(&lsat), strategy.cost(&rsat) + strategy.cost(&ldissat)) {
                 Ok(lsat)
             } else {
-                ldissat.extend(rsat);
-                Ok(ldissat)
+                rsat.extend(ldissat);
+                Ok(rsat)
             }
         }
     }
 }
 
+/// `OP_CHECKSIGADD`, which tapscript assigns to an opcode that is otherwise invalid
+/// and which therefore has no name of its own in rust-bitcoin
+pub(crate) const OP_CHECKSIGADD: opcodes::All = opcodes::All::OP_RETURN_186;
+
+/// `OP_CHECKSIGFROMSTACK`, which Elements assigns to an opcode that is invalid in Bitcoin
+#[cfg(feature = "elements")]
+pub(crate) const OP_CHECKSIGFROMSTACK: opcodes::All = opcodes::All::OP_RETURN_193;
 
-/// Atom of a tokenized version of a script
+/// `OP_CHECKSIGFROMSTACKVERIFY`, which Elements assigns to an opcode that is invalid
+/// in Bitcoin
+#[cfg(feature = "elements")]
+pub(crate) const OP_CHECKSIGFROMSTACKVERIFY: opcodes::All = opcodes::All::OP_RETURN_194;
+
+/// Atom of a tokenized version of a script, as produced by `lex`. Each opcode token
+/// stands for the opcode of the same name; the remaining tokens stand for pushes.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-#[allow(missing_docs)]
 pub enum Token {
+    /// `OP_BOOLAND`
     BoolAnd,
+    /// `OP_BOOLOR`
     BoolOr,
+    /// `OP_ADD`
     Add,
+    /// `OP_EQUAL`
     Equal,
+    /// `OP_EQUALVERIFY`
     EqualVerify,
+    /// `OP_CHECKSIG`
     CheckSig,
+    /// `OP_CHECKSIGVERIFY`
     CheckSigVerify,
+    /// `OP_CHECKMULTISIG`
     CheckMultiSig,
+    /// `OP_CHECKMULTISIGVERIFY`
     CheckMultiSigVerify,
+    /// `OP_CHECKSIGADD`, which is only recognized in tapscripts
+    CheckSigAdd,
+    /// `OP_CHECKSIGFROMSTACK`, which is only recognized in Elements scripts
+    #[cfg(feature = "elements")]
+    CheckSigFromStack,
+    /// `OP_CHECKSIGFROMSTACKVERIFY`, which is only recognized in Elements scripts
+    #[cfg(feature = "elements")]
+    CheckSigFromStackVerify,
+    /// `OP_NUMEQUAL`
+    NumEqual,
+    /// `OP_NUMEQUALVERIFY`
+    NumEqualVerify,
+    /// `OP_CHECKSEQUENCEVERIFY`
     CheckSequenceVerify,
+    /// `OP_CHECKLOCKTIMEVERIFY`, which no fragment uses, so scripts containing it are
+    /// lexed but not parsed
+    CheckLockTimeVerify,
+    /// `OP_FROMALTSTACK`
     FromAltStack,
+    /// `OP_TOALTSTACK`
     ToAltStack,
+    /// `OP_DROP`
     Drop,
+    /// `OP_DUP`
     Dup,
+    /// `OP_IF`
     If,
+    /// `OP_IFDUP`
     IfDup,
+    /// `OP_NOTIF`
     NotIf,
+    /// `OP_ELSE`
     Else,
+    /// `OP_ENDIF`
     EndIf,
+    /// `OP_SIZE`
     Size,
+    /// `OP_SWAP`
     Swap,
+    /// `OP_TUCK`
     Tuck,
+    /// `OP_VERIFY`
     Verify,
+    /// `OP_HASH160`
     Hash160,
+    /// `OP_RIPEMD160`
+    Ripemd160,
+    /// `OP_SHA256`
     Sha256,
+    /// `OP_HASH256`
+    Hash256,
+    /// A nonnegative number, minimally encoded
     Number(u32),
+    /// A 20-byte push
     Hash160Hash(Hash160),
-    Sha256Hash(Sha256dHash),
+    /// A 32-byte push
+    Sha256Hash(sha256::Hash),
+    /// A 33-byte (compressed) public key
     Pubkey(secp256k1::PublicKey),
+    /// A 65-byte public key, which is only recognized in legacy scripts
+    UncompressedPubkey(secp256k1::PublicKey),
+    /// A 32-byte public key, which is only recognized in tapscripts; the key is the
+    /// one with even y-coordinate
+    XOnlyPubkey(secp256k1::PublicKey),
+    /// One of the opcodes `OP_NOP1` and `OP_NOP4` to `OP_NOP10`, reserved for soft
+    /// forks, which is only lexed by a `TokenStream` allowing them
+    ReservedNop(opcodes::All),
 }
 
 impl Token {
@@ -318,7 +529,15 @@ impl Token {
             Token::CheckSigVerify => builder.push_opcode(opcodes::All::OP_CHECKSIGVERIFY),
             Token::CheckMultiSig => builder.push_opcode(opcodes::All::OP_CHECKMULTISIG),
             Token::CheckMultiSigVerify => builder.push_opcode(opcodes::All::OP_CHECKMULTISIGVERIFY),
+            Token::CheckSigAdd => builder.push_opcode(OP_CHECKSIGADD),
+            #[cfg(feature = "elements")]
+            Token::CheckSigFromStack => builder.push_opcode(OP_CHECKSIGFROMSTACK),
+            #[cfg(feature = "elements")]
+            Token::CheckSigFromStackVerify => builder.push_opcode(OP_CHECKSIGFROMSTACKVERIFY),
+            Token::NumEqual => builder.push_opcode(opcodes::All::OP_NUMEQUAL),
+            Token::NumEqualVerify => builder.push_opcode(opcodes::All::OP_NUMEQUALVERIFY),
             Token::CheckSequenceVerify => builder.push_opcode(opcodes::OP_CSV),
+            Token::CheckLockTimeVerify => builder.push_opcode(opcodes::OP_CLTV),
             Token::FromAltStack => builder.push_opcode(opcodes::All::OP_FROMALTSTACK),
             Token::ToAltStack => builder.push_opcode(opcodes::All::OP_TOALTSTACK),
             Token::Drop => builder.push_opcode(opcodes::All::OP_DROP),
@@ -333,11 +552,16 @@ impl Token {
             Token::Tuck => builder.push_opcode(opcodes::All::OP_TUCK),
             Token::Verify => builder.push_opcode(opcodes::All::OP_VERIFY),
             Token::Hash160 => builder.push_opcode(opcodes::All::OP_HASH160),
+            Token::Ripemd160 => builder.push_opcode(opcodes::All::OP_RIPEMD160),
             Token::Sha256 => builder.push_opcode(opcodes::All::OP_SHA256),
-            Token::Number(n) => builder.push_int(n as i64),
+            Token::Hash256 => builder.push_opcode(opcodes::All::OP_HASH256),
+            Token::Number(n) => scriptnum::push(builder, n as i64),
             Token::Hash160Hash(hash) => builder.push_slice(&hash[..]),
             Token::Sha256Hash(hash) => builder.push_slice(&hash[..]),
             Token::Pubkey(pk) => builder.push_slice(&pk.serialize()[..]),
+            Token::UncompressedPubkey(pk) => builder.push_slice(&pk.serialize_uncompressed()[..]),
+            Token::XOnlyPubkey(pk) => builder.push_slice(&pk.serialize()[1..]),
+            Token::ReservedNop(op) => builder.push_opcode(op),
         }
     }
 }
@@ -351,20 +575,54 @@ impl fmt::Display for Token {
 
 #[derive(Debug, Clone)]
 /// Iterator that goes through a vector of tokens backward (our parser wants to read
-/// backward and this is more efficient anyway since we can use `Vec::pop()`).
-struct TokenIter(Vec<Token>);
+/// backward). Tokens are never removed, so that the position of the most recently
+/// read token is known when reporting errors.
+struct TokenIter {
+    tokens: Vec<Token>,
+    /// Byte offset in the script of each token
+    offsets: Vec<usize>,
+    /// Number of tokens not yet read
+    remaining: usize,
+    /// Number of subexpressions currently being parsed
+    depth: usize,
+    /// Limit on `depth`
+    max_depth: usize,
+}
 
 impl TokenIter {
-    fn new(v: Vec<Token>) -> TokenIter {
-        TokenIter(v)
+    fn new(tokens: Vec<Token>, offsets: Vec<usize>, max_depth: usize) -> TokenIter {
+        TokenIter {
+            remaining: tokens.len(),
+            tokens,
+            offsets,
+            depth: 0,
+            max_depth,
+        }
     }
 
     fn peek(&self) -> Option<&Token> {
-        self.0.last()
+        if self.remaining == 0 {
+            None
+        } else {
+            Some(&self.tokens[self.remaining - 1])
+        }
     }
 
     fn un_next(&mut self, tok: Token) {
-        self.0.push(tok)
+        debug_assert_eq!(self.tokens[self.remaining], tok);
+        self.remaining += 1;
+    }
+
+    /// Constructs a parse error located at the most recently read token (which, after
+    /// parsing a subexpression, is the first token of that subexpression)
+    fn error(&self, found: String, expected: &'static str) -> Error {
+        let index = cmp::min(self.remaining, self.tokens.len().saturating_sub(1));
+        Error::ParseError {
+            offset: self.offsets.get(index).cloned().unwrap_or(0),
+            index,
+            found,
+            expected,
+        }
     }
 }
 
@@ -372,136 +630,440 @@ impl Iterator for TokenIter {
     type Item = Token;
 
     fn next(&mut self) -> Option<Token> {
-        self.0.pop()
+        if self.remaining == 0 {
+            None
+        } else {
+            self.remaining -= 1;
+            Some(self.tokens[self.remaining])
+        }
     }
 }
 
 /// Expression that may be satisfied or dissatisfied; both cases must
 /// be non-malleable.
 #[derive(Debug, Clone, PartialEq, Eq)]
-enum E {
+pub(crate) enum E {
     /// `<pk> CHECKSIG`
     CheckSig(secp256k1::PublicKey),
+    /// `<pk> CHECKSIG`, where `pk` is the aggregate of the given keys
+    CheckSigAggregate(secp256k1::PublicKey, Arc<[secp256k1::PublicKey]>),
     /// `DUP HASH160 <hash> EQUALVERIFY CHECKSIG`
     CheckSigHash(Hash160),
     /// `SIZE IF DUP HASH160 <hash> EQUALVERIFY CHECKSIGVERIFY 1 ENDIF`
     CheckSigHashF(Hash160),
     /// `<k> <pk...> <len(pk)> CHECKMULTISIG`
-    CheckMultiSig(usize, Vec<secp256k1::PublicKey>),
+    CheckMultiSig(usize, Arc<[secp256k1::PublicKey]>),
     /// `SIZE IF <k> <pk...> <len(pk)> CHECKMULTISIGVERIFY 1 ENDIF`
-    CheckMultiSigF(usize, Vec<secp256k1::PublicKey>),
+    CheckMultiSigF(usize, Arc<[secp256k1::PublicKey]>),
+    /// `<pk> CHECKSIG <pk> CHECKSIGADD ... <pk> CHECKSIGADD <k> NUMEQUAL`, with
+    /// x-only keys (tapscript only)
+    CheckSigAdd(usize, Arc<[secp256k1::PublicKey]>),
+    /// `<msg> <pk> CHECKSIGFROMSTACK` (Elements only)
+    #[cfg(feature = "elements")]
+    CheckSigFromStack(secp256k1::PublicKey, sha256::Hash),
     /// `SIZE IF SIZE 32 EQUALVERIFY SHA256 <hash> EQUALVERIFY 1 ENDIF`
-    HashEqual(Sha256dHash),
+    HashEqual(sha256::Hash),
+    /// `SIZE IF SIZE 32 EQUALVERIFY HASH160 <hash> EQUALVERIFY 1 ENDIF`
+    Hash160Equal(Hash160),
+    /// `SIZE IF SIZE 32 EQUALVERIFY RIPEMD160 <hash> EQUALVERIFY 1 ENDIF`
+    Ripemd160Equal(Ripemd160Hash),
+    /// `SIZE IF SIZE 32 EQUALVERIFY HASH256 <hash> EQUALVERIFY 1 ENDIF`
+    Hash256Equal(Sha256dHash),
     /// `<E> <W> ADD ... <W> ADD <k> EQUAL`
-    Threshold(usize, Box<E>, Vec<W>),
+    Threshold(usize, Arc<E>, Vec<W>),
     /// `<E> <W> BOOLAND`
-    ParallelAnd(Box<E>, Box<W>),
+    ParallelAnd(Arc<E>, Arc<W>),
     /// `<E> IF <F> ELSE 0 ENDIF`
-    CascadeAnd(Box<E>, Box<F>),
+    CascadeAnd(Arc<E>, Arc<F>),
     /// `<E> <W> BOOLOR`
-    ParallelOr(Box<E>, Box<W>),
+    ParallelOr(Arc<E>, Arc<W>),
     /// `<E> IFDUP NOTIF <E> ENDIF`
-    CascadeOr(Box<E>, Box<E>),
+    CascadeOr(Arc<E>, Arc<E>),
     /// `SIZE EQUALVERIFY IF <F> ELSE 0 ENDIF`
-    CastF(Box<F>),
+    CastF(Arc<F>),
     // TODO missing SIZE EQUALVERIFY IF 0 ELSE F ENDIF which should be there at lesat for F::And
 }
 
 /// Wrapped expression, used as helper for the parallel operations above
 #[derive(Debug, Clone, PartialEq, Eq)]
-enum W {
+pub(crate) enum W {
     /// `SWAP <pk> CHECKSIG`
     CheckSig(secp256k1::PublicKey),
+    /// `SWAP <pk> CHECKSIG`, where `pk` is the aggregate of the given keys
+    CheckSigAggregate(secp256k1::PublicKey, Arc<[secp256k1::PublicKey]>),
     /// `SWAP SIZE IF SIZE 32 EQUALVERIFY SHA256 <hash> EQUALVERIFY 1 ENDIF`
-    HashEqual(Sha256dHash),
+    HashEqual(sha256::Hash),
+    /// `SWAP SIZE IF SIZE 32 EQUALVERIFY HASH160 <hash> EQUALVERIFY 1 ENDIF`
+    Hash160Equal(Hash160),
+    /// `SWAP SIZE IF SIZE 32 EQUALVERIFY RIPEMD160 <hash> EQUALVERIFY 1 ENDIF`
+    Ripemd160Equal(Ripemd160Hash),
+    /// `SWAP SIZE IF SIZE 32 EQUALVERIFY HASH256 <hash> EQUALVERIFY 1 ENDIF`
+    Hash256Equal(Sha256dHash),
     /// `SWAP SIZE EQUALVERIFY IF <n> CSV ELSE 0 ENDIF`
     Csv(u32),
     /// `TOALTSTACK <E> FROMALTSTACK`
-    CastE(Box<E>),
+    CastE(Arc<E>),
 }
 
 /// Expression that must succeed and will leave a 1 on the stack after consuming its inputs
 #[derive(Debug, Clone, PartialEq, Eq)]
-enum F {
+pub(crate) enum F {
     /// `<pk> CHECKSIGVERIFY 1`
     CheckSig(secp256k1::PublicKey),
+    /// `<pk> CHECKSIGVERIFY 1`, where `pk` is the aggregate of the given keys
+    CheckSigAggregate(secp256k1::PublicKey, Arc<[secp256k1::PublicKey]>),
     /// `<k> <pk...> <len(pk)> CHECKMULTISIGVERIFY 1`
-    CheckMultiSig(usize, Vec<secp256k1::PublicKey>),
+    CheckMultiSig(usize, Arc<[secp256k1::PublicKey]>),
+    /// `<pk> CHECKSIG <pk> CHECKSIGADD ... <pk> CHECKSIGADD <k> NUMEQUALVERIFY 1`,
+    /// with x-only keys (tapscript only)
+    CheckSigAdd(usize, Arc<[secp256k1::PublicKey]>),
+    /// `<msg> <pk> CHECKSIGFROMSTACKVERIFY 1` (Elements only)
+    #[cfg(feature = "elements")]
+    CheckSigFromStack(secp256k1::PublicKey, sha256::Hash),
     /// `DUP HASH160 <hash> EQVERIFY CHECKSIGVERIFY 1`
     CheckSigHash(Hash160),
     /// `<n> CSV`
     Csv(u32),
+    /// `<script> 1`, for a script given by the user which is satisfied externally
+    Raw(script::Script),
     /// `SIZE 32 EQUALVERIFY SHA256 <hash> EQUALVERIFY 1`
-    HashEqual(Sha256dHash),
+    HashEqual(sha256::Hash),
+    /// `SIZE 32 EQUALVERIFY HASH160 <hash> EQUALVERIFY 1`
+    Hash160Equal(Hash160),
+    /// `SIZE 32 EQUALVERIFY RIPEMD160 <hash> EQUALVERIFY 1`
+    Ripemd160Equal(Ripemd160Hash),
+    /// `SIZE 32 EQUALVERIFY HASH256 <hash> EQUALVERIFY 1`
+    Hash256Equal(Sha256dHash),
     /// `<E> <W> ADD ... <W> ADD <k> EQUALVERIFY 1`
-    Threshold(usize, Box<E>, Vec<W>),
+    Threshold(usize, Arc<E>, Vec<W>),
     /// `<V> <F>`
-    And(Box<V>, Box<F>),
+    And(Arc<V>, Arc<F>),
     /// `<E> <W> BOOLOR VERIFY 1`
-    ParallelOr(Box<E>, Box<W>),
+    ParallelOr(Arc<E>, Arc<W>),
     /// `SIZE EQUALVERIFY IF <F> ELSE <F> ENDIF`
-    SwitchOr(Box<F>, Box<F>),
+    SwitchOr(Arc<F>, Arc<F>),
     /// `SIZE EQUALVERIFY IF <V> ELSE <V> ENDIF 1`
-    SwitchOrV(Box<V>, Box<V>),
+    SwitchOrV(Arc<V>, Arc<V>),
     /// `<E> IFDUP NOTIF <F> ENDIF`
-    CascadeOr(Box<E>, Box<F>),
+    CascadeOr(Arc<E>, Arc<F>),
     /// `<E> NOTIF <V> ENDIF 1`
-    CascadeOrV(Box<E>, Box<V>),
+    CascadeOrV(Arc<E>, Arc<V>),
 }
 
 /// Expression that must succeed and will leave nothing on the stack after consuming its inputs
 #[derive(Debug, Clone, PartialEq, Eq)]
-enum V {
+pub(crate) enum V {
     /// `<pk> CHECKSIGVERIFY`
     CheckSig(secp256k1::PublicKey),
+    /// `<pk> CHECKSIGVERIFY`, where `pk` is the aggregate of the given keys
+    CheckSigAggregate(secp256k1::PublicKey, Arc<[secp256k1::PublicKey]>),
     /// `<k> <pk...> <len(pk)> CHECKMULTISIGVERIFY`
-    CheckMultiSig(usize, Vec<secp256k1::PublicKey>),
+    CheckMultiSig(usize, Arc<[secp256k1::PublicKey]>),
+    /// `<pk> CHECKSIG <pk> CHECKSIGADD ... <pk> CHECKSIGADD <k> NUMEQUALVERIFY`,
+    /// with x-only keys (tapscript only)
+    CheckSigAdd(usize, Arc<[secp256k1::PublicKey]>),
+    /// `<msg> <pk> CHECKSIGFROMSTACKVERIFY` (Elements only)
+    #[cfg(feature = "elements")]
+    CheckSigFromStack(secp256k1::PublicKey, sha256::Hash),
     /// `DUP HASH160 <hash> EQVERIFY CHECKSIGVERIFY`
     CheckSigHash(Hash160),
     /// `<n> CSV DROP`
     Csv(u32),
+    /// `<script>`, for a script given by the user which is satisfied externally
+    Raw(script::Script),
     /// `SIZE 32 EQUALVERIFY SHA256 <hash> EQUALVERIFY`
-    HashEqual(Sha256dHash),
+    HashEqual(sha256::Hash),
+    /// `SIZE 32 EQUALVERIFY HASH160 <hash> EQUALVERIFY`
+    Hash160Equal(Hash160),
+    /// `SIZE 32 EQUALVERIFY RIPEMD160 <hash> EQUALVERIFY`
+    Ripemd160Equal(Ripemd160Hash),
+    /// `SIZE 32 EQUALVERIFY HASH256 <hash> EQUALVERIFY`
+    Hash256Equal(Sha256dHash),
     /// `<E> <W> ADD ... <W> ADD <k> EQUALVERIFY`
-    Threshold(usize, Box<E>, Vec<W>),
+    Threshold(usize, Arc<E>, Vec<W>),
     /// `<V> <V>`
-    And(Box<V>, Box<V>),
+    And(Arc<V>, Arc<V>),
     /// `<E> <W> BOOLOR VERIFY`
-    ParallelOr(Box<E>, Box<W>),
+    ParallelOr(Arc<E>, Arc<W>),
     /// `SIZE EQUALVERIFY IF <V> ELSE <V> ENDIF`
-    SwitchOr(Box<V>, Box<V>),
+    SwitchOr(Arc<V>, Arc<V>),
     /// `SIZE EQUALVERIFY IF <T> ELSE <T> ENDIF VERIFY`
-    SwitchOrT(Box<T>, Box<T>),
+    SwitchOrT(Arc<T>, Arc<T>),
     /// `<E> NOTIF <V> ENDIF`
-    CascadeOr(Box<E>, Box<V>),
+    CascadeOr(Arc<E>, Arc<V>),
 }
 
 /// "Top" expression, which might succeed or not, or fail or not. Occurs only at the top of a
 /// script, such that its failure will fail the entire thing even if it returns a 0.
 #[derive(Debug, Clone, PartialEq, Eq)]
-enum T {
+pub(crate) enum T {
     /// `SIZE 32 EQUALVERIFY SHA256 <hash> EQUAL`
-    HashEqual(Sha256dHash),
+    HashEqual(sha256::Hash),
+    /// `SIZE 32 EQUALVERIFY HASH160 <hash> EQUAL`
+    Hash160Equal(Hash160),
+    /// `SIZE 32 EQUALVERIFY RIPEMD160 <hash> EQUAL`
+    Ripemd160Equal(Ripemd160Hash),
+    /// `SIZE 32 EQUALVERIFY HASH256 <hash> EQUAL`
+    Hash256Equal(Sha256dHash),
     /// `<V> <T>`
-    And(Box<V>, Box<T>),
+    And(Arc<V>, Arc<T>),
     /// `SIZE EQUALVERIFY IF <T> ELSE <T> ENDIF`
-    SwitchOr(Box<T>, Box<T>),
+    SwitchOr(Arc<T>, Arc<T>),
     /// `<E> IFDUP NOTIF <T> ENDIF`
-    CascadeOr(Box<E>, Box<T>),
+    CascadeOr(Arc<E>, Arc<T>),
     /// `<E>`
-    CastE(Box<E>),
+    CastE(Arc<E>),
     /// `<F>`
-    CastF(Box<F>),
+    CastF(Arc<F>),
+}
+
+/// Mapping of the keys and hashes of a parse tree, as applied by the `translate`
+/// methods of its fragments. Hashes are left alone unless a method says otherwise.
+pub(crate) trait Translator<Err> {
+    /// Maps a public key
+    fn pk(&mut self, pk: &secp256k1::PublicKey) -> Result<secp256k1::PublicKey, Err>;
+    /// Maps the HASH160 of a public key checked by a `pkh` fragment
+    fn pkh(&mut self, hash: &Hash160) -> Result<Hash160, Err> { Ok(*hash) }
+    /// Maps a SHA256 hash whose preimage is checked
+    fn sha256(&mut self, hash: &sha256::Hash) -> Result<sha256::Hash, Err> { Ok(*hash) }
+    /// Maps a HASH160 hash whose preimage is checked
+    fn hash160(&mut self, hash: &Hash160) -> Result<Hash160, Err> { Ok(*hash) }
+    /// Maps a RIPEMD160 hash whose preimage is checked
+    fn ripemd160(&mut self, hash: &Ripemd160Hash) -> Result<Ripemd160Hash, Err> { Ok(*hash) }
+    /// Maps a HASH256 hash whose preimage is checked
+    fn hash256(&mut self, hash: &Sha256dHash) -> Result<Sha256dHash, Err> { Ok(*hash) }
+}
+
+/// Translator which maps keys through a function, as for `ParseTree::translate`
+struct KeyTranslator<Fun>(Fun);
+
+impl<Err, Fun> Translator<Err> for KeyTranslator<Fun>
+    where Fun: FnMut(&secp256k1::PublicKey) -> Result<secp256k1::PublicKey, Err>
+{
+    fn pk(&mut self, pk: &secp256k1::PublicKey) -> Result<secp256k1::PublicKey, Err> {
+        (self.0)(pk)
+    }
+}
+
+/// Maps each of `keys` through `t`
+fn translate_keys<Err, Tr: Translator<Err>>(keys: &[secp256k1::PublicKey], t: &mut Tr) -> Result<Arc<[secp256k1::PublicKey]>, Err> {
+    keys.iter().map(|pk| t.pk(pk)).collect::<Result<Vec<_>, Err>>().map(Arc::from)
+}
+
+/// Maps the subexpressions `subw` of a threshold through `t`
+fn translate_ws<Err, Tr: Translator<Err>>(subw: &[W], t: &mut Tr) -> Result<Vec<W>, Err> {
+    subw.iter().map(|w| w.translate(t)).collect()
+}
+
+impl E {
+    /// Maps every key and hash of the fragment through `t`
+    pub(crate) fn translate<Err, Tr: Translator<Err>>(&self, t: &mut Tr) -> Result<E, Err> {
+        Ok(match *self {
+            E::CheckSig(ref pk) => E::CheckSig(t.pk(pk)?),
+            E::CheckSigAggregate(ref pk, ref keys) => E::CheckSigAggregate(t.pk(pk)?, translate_keys(keys, t)?),
+            E::CheckSigHash(ref hash) => E::CheckSigHash(t.pkh(hash)?),
+            E::CheckSigHashF(ref hash) => E::CheckSigHashF(t.pkh(hash)?),
+            E::CheckMultiSig(k, ref keys) => E::CheckMultiSig(k, translate_keys(keys, t)?),
+            E::CheckMultiSigF(k, ref keys) => E::CheckMultiSigF(k, translate_keys(keys, t)?),
+            E::CheckSigAdd(k, ref keys) => E::CheckSigAdd(k, translate_keys(keys, t)?),
+            #[cfg(feature = "elements")]
+            E::CheckSigFromStack(ref pk, ref msg) => E::CheckSigFromStack(t.pk(pk)?, *msg),
+            E::HashEqual(ref hash) => E::HashEqual(t.sha256(hash)?),
+            E::Hash160Equal(ref hash) => E::Hash160Equal(t.hash160(hash)?),
+            E::Ripemd160Equal(ref hash) => E::Ripemd160Equal(t.ripemd160(hash)?),
+            E::Hash256Equal(ref hash) => E::Hash256Equal(t.hash256(hash)?),
+            E::Threshold(k, ref sube, ref subw) => {
+                E::Threshold(k, Arc::new(sube.translate(t)?), translate_ws(subw, t)?)
+            }
+            E::ParallelAnd(ref left, ref right) => {
+                E::ParallelAnd(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+            E::CascadeAnd(ref left, ref right) => {
+                E::CascadeAnd(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+            E::ParallelOr(ref left, ref right) => {
+                E::ParallelOr(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+            E::CascadeOr(ref left, ref right) => {
+                E::CascadeOr(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+            E::CastF(ref sub) => E::CastF(Arc::new(sub.translate(t)?)),
+        })
+    }
+}
+
+impl W {
+    /// Maps every key and hash of the fragment through `t`
+    pub(crate) fn translate<Err, Tr: Translator<Err>>(&self, t: &mut Tr) -> Result<W, Err> {
+        Ok(match *self {
+            W::CheckSig(ref pk) => W::CheckSig(t.pk(pk)?),
+            W::CheckSigAggregate(ref pk, ref keys) => W::CheckSigAggregate(t.pk(pk)?, translate_keys(keys, t)?),
+            W::HashEqual(ref hash) => W::HashEqual(t.sha256(hash)?),
+            W::Hash160Equal(ref hash) => W::Hash160Equal(t.hash160(hash)?),
+            W::Ripemd160Equal(ref hash) => W::Ripemd160Equal(t.ripemd160(hash)?),
+            W::Hash256Equal(ref hash) => W::Hash256Equal(t.hash256(hash)?),
+            W::Csv(n) => W::Csv(n),
+            W::CastE(ref sub) => W::CastE(Arc::new(sub.translate(t)?)),
+        })
+    }
+}
+
+impl F {
+    /// Maps every key and hash of the fragment through `t`
+    pub(crate) fn translate<Err, Tr: Translator<Err>>(&self, t: &mut Tr) -> Result<F, Err> {
+        Ok(match *self {
+            F::CheckSig(ref pk) => F::CheckSig(t.pk(pk)?),
+            F::CheckSigAggregate(ref pk, ref keys) => F::CheckSigAggregate(t.pk(pk)?, translate_keys(keys, t)?),
+            F::CheckMultiSig(k, ref keys) => F::CheckMultiSig(k, translate_keys(keys, t)?),
+            F::CheckSigAdd(k, ref keys) => F::CheckSigAdd(k, translate_keys(keys, t)?),
+            #[cfg(feature = "elements")]
+            F::CheckSigFromStack(ref pk, ref msg) => F::CheckSigFromStack(t.pk(pk)?, *msg),
+            F::CheckSigHash(ref hash) => F::CheckSigHash(t.pkh(hash)?),
+            F::Csv(n) => F::Csv(n),
+            F::Raw(ref script) => F::Raw(script.clone()),
+            F::HashEqual(ref hash) => F::HashEqual(t.sha256(hash)?),
+            F::Hash160Equal(ref hash) => F::Hash160Equal(t.hash160(hash)?),
+            F::Ripemd160Equal(ref hash) => F::Ripemd160Equal(t.ripemd160(hash)?),
+            F::Hash256Equal(ref hash) => F::Hash256Equal(t.hash256(hash)?),
+            F::Threshold(k, ref sube, ref subw) => {
+                F::Threshold(k, Arc::new(sube.translate(t)?), translate_ws(subw, t)?)
+            }
+            F::And(ref left, ref right) => F::And(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?)),
+            F::ParallelOr(ref left, ref right) => {
+                F::ParallelOr(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+            F::SwitchOr(ref left, ref right) => {
+                F::SwitchOr(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+            F::SwitchOrV(ref left, ref right) => {
+                F::SwitchOrV(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+            F::CascadeOr(ref left, ref right) => {
+                F::CascadeOr(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+            F::CascadeOrV(ref left, ref right) => {
+                F::CascadeOrV(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+        })
+    }
+}
+
+impl V {
+    /// Maps every key and hash of the fragment through `t`
+    pub(crate) fn translate<Err, Tr: Translator<Err>>(&self, t: &mut Tr) -> Result<V, Err> {
+        Ok(match *self {
+            V::CheckSig(ref pk) => V::CheckSig(t.pk(pk)?),
+            V::CheckSigAggregate(ref pk, ref keys) => V::CheckSigAggregate(t.pk(pk)?, translate_keys(keys, t)?),
+            V::CheckMultiSig(k, ref keys) => V::CheckMultiSig(k, translate_keys(keys, t)?),
+            V::CheckSigAdd(k, ref keys) => V::CheckSigAdd(k, translate_keys(keys, t)?),
+            #[cfg(feature = "elements")]
+            V::CheckSigFromStack(ref pk, ref msg) => V::CheckSigFromStack(t.pk(pk)?, *msg),
+            V::CheckSigHash(ref hash) => V::CheckSigHash(t.pkh(hash)?),
+            V::Csv(n) => V::Csv(n),
+            V::Raw(ref script) => V::Raw(script.clone()),
+            V::HashEqual(ref hash) => V::HashEqual(t.sha256(hash)?),
+            V::Hash160Equal(ref hash) => V::Hash160Equal(t.hash160(hash)?),
+            V::Ripemd160Equal(ref hash) => V::Ripemd160Equal(t.ripemd160(hash)?),
+            V::Hash256Equal(ref hash) => V::Hash256Equal(t.hash256(hash)?),
+            V::Threshold(k, ref sube, ref subw) => {
+                V::Threshold(k, Arc::new(sube.translate(t)?), translate_ws(subw, t)?)
+            }
+            V::And(ref left, ref right) => V::And(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?)),
+            V::ParallelOr(ref left, ref right) => {
+                V::ParallelOr(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+            V::SwitchOr(ref left, ref right) => {
+                V::SwitchOr(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+            V::SwitchOrT(ref left, ref right) => {
+                V::SwitchOrT(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+            V::CascadeOr(ref left, ref right) => {
+                V::CascadeOr(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+        })
+    }
+}
+
+impl T {
+    /// Maps every key and hash of the fragment through `t`
+    pub(crate) fn translate<Err, Tr: Translator<Err>>(&self, t: &mut Tr) -> Result<T, Err> {
+        Ok(match *self {
+            T::HashEqual(ref hash) => T::HashEqual(t.sha256(hash)?),
+            T::Hash160Equal(ref hash) => T::Hash160Equal(t.hash160(hash)?),
+            T::Ripemd160Equal(ref hash) => T::Ripemd160Equal(t.ripemd160(hash)?),
+            T::Hash256Equal(ref hash) => T::Hash256Equal(t.hash256(hash)?),
+            T::And(ref left, ref right) => T::And(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?)),
+            T::SwitchOr(ref left, ref right) => {
+                T::SwitchOr(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+            T::CascadeOr(ref left, ref right) => {
+                T::CascadeOr(Arc::new(left.translate(t)?), Arc::new(right.translate(t)?))
+            }
+            T::CastE(ref sub) => T::CastE(Arc::new(sub.translate(t)?)),
+            T::CastF(ref sub) => T::CastF(Arc::new(sub.translate(t)?)),
+        })
+    }
+}
+
+/// Part of the script of an expression, as written out by `AstElem::serialize_pieces`
+pub(crate) enum Piece<'a> {
+    /// Opcodes and pushes, already encoded
+    Script(script::Script),
+    /// A subexpression, whose script has not been written out yet
+    Sub(&'a dyn AstElem),
 }
 
-trait AstElem: fmt::Display {
-    fn serialize(&self, builder: script::Builder) -> script::Builder;
+/// Ends the current run of encoded script in `builder` with the subexpression `sub`,
+/// returning an empty builder for whatever follows it
+fn defer<'a>(builder: script::Builder, sub: &'a dyn AstElem, pieces: &mut Vec<Piece<'a>>) -> script::Builder {
+    pieces.push(Piece::Script(builder.into_script()));
+    pieces.push(Piece::Sub(sub));
+    script::Builder::new()
+}
+
+/// Pushes the pieces of an expression onto a stack of work, so that the first one is
+/// on top
+fn push_pieces<'a>(stack: &mut Vec<Piece<'a>>, mut pieces: Vec<Piece<'a>>, rest: script::Builder) {
+    pieces.push(Piece::Script(rest.into_script()));
+    stack.extend(pieces.into_iter().rev());
+}
+
+pub(crate) trait AstElem: fmt::Display {
+    /// Writes out the script of this expression, up to its first subexpression, into
+    /// `builder`; everything from there on is added to `pieces`, and the builder
+    /// returned holds whatever follows the last subexpression. Subexpressions are
+    /// not written out, so this never recurses.
+    fn serialize_pieces<'a>(&'a self, builder: script::Builder, pieces: &mut Vec<Piece<'a>>) -> script::Builder;
+
+    /// Appends the script of this expression to `builder`. Subexpressions are
+    /// written out from an explicit stack rather than by recursion, so that
+    /// arbitrarily deep trees can be encoded.
+    fn serialize(&self, builder: script::Builder) -> script::Builder {
+        let mut stack = vec![];
+        let mut pieces = vec![];
+        let rest = self.serialize_pieces(script::Builder::new(), &mut pieces);
+        push_pieces(&mut stack, pieces, rest);
+
+        let mut ret = builder.into_script()[..].to_vec();
+        while let Some(piece) = stack.pop() {
+            match piece {
+                Piece::Script(script) => ret.extend(&script[..]),
+                Piece::Sub(sub) => {
+                    let mut pieces = vec![];
+                    let rest = sub.serialize_pieces(script::Builder::new(), &mut pieces);
+                    push_pieces(&mut stack, pieces, rest);
+                }
+            }
+        }
+        script::Builder::from(ret)
+    }
 
-    fn into_e(self: Box<Self>) -> Result<Box<E>, Error> { Err(Error::Unexpected(self.to_string())) }
-    fn into_w(self: Box<Self>) -> Result<Box<W>, Error> { Err(Error::Unexpected(self.to_string())) }
-    fn into_f(self: Box<Self>) -> Result<Box<F>, Error> { Err(Error::Unexpected(self.to_string())) }
-    fn into_v(self: Box<Self>) -> Result<Box<V>, Error> { Err(Error::Unexpected(self.to_string())) }
-    fn into_t(self: Box<Self>) -> Result<Box<T>, Error> { Err(Error::Unexpected(self.to_string())) }
+    fn into_e(self: Box<Self>) -> Result<Arc<E>, Error> { Err(Error::Unexpected(self.to_string())) }
+    fn into_w(self: Box<Self>) -> Result<Arc<W>, Error> { Err(Error::Unexpected(self.to_string())) }
+    fn into_f(self: Box<Self>) -> Result<Arc<F>, Error> { Err(Error::Unexpected(self.to_string())) }
+    fn into_v(self: Box<Self>) -> Result<Arc<V>, Error> { Err(Error::Unexpected(self.to_string())) }
+    fn into_t(self: Box<Self>) -> Result<Arc<T>, Error> { Err(Error::Unexpected(self.to_string())) }
 
     fn is_e(&self) -> bool { false }
     fn is_w(&self) -> bool { false }
@@ -509,2166 +1071,5999 @@ trait AstElem: fmt::Display {
     fn is_v(&self) -> bool { false }
     fn is_t(&self) -> bool { false }
 
-    fn satisfy(
-        &self,
-        key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
-        pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
-        hash_map: &HashMap<Sha256dHash, [u8; 32]>,
-        age: u32,
-    ) -> Result<Vec<Vec<u8>>, Error>;
+    fn satisfy(&self, satisfier: &dyn Satisfier, strategy: Strategy) -> Result<Vec<Vec<u8>>, Error>;
 
     fn required_keys(&self) -> Vec<secp256k1::PublicKey>;
+
+    /// The spending condition enforced by the fragment
+    fn policy(&self) -> Policy;
+
+    /// Which of the expression types the fragment is
+    fn expr_type(&self) -> ExprType;
+
+    /// Description of the fragment, along with its immediate subexpressions
+    fn fragment(&self) -> (Fragment, Vec<&dyn AstElem>);
+
+    /// Records any malleable constructions in `issues`, returning whether every
+    /// satisfaction of the fragment requires a signature
+    fn malleability(&self, issues: &mut Vec<Malleability>) -> bool;
+
+    /// Upper bound on the number of witness elements needed to satisfy the fragment
+    fn max_satisfaction_items(&self) -> usize;
+
+    /// Upper bound on the size of the witness needed to satisfy the fragment, assuming
+    /// 73-byte signatures (including the sighash byte) and one-byte push prefixes
+    fn max_satisfaction_size(&self) -> usize;
 }
 
-/// Top-level script AST type
+/// Construction which allows a third party, who does not hold any of the script's
+/// keys, to replace a valid satisfaction with a different valid satisfaction. Hash
+/// preimages are assumed to become public once they have been used, and timelocks
+/// are assumed to eventually be met. Each variant carries the offending fragment.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ParseTree(Box<T>);
-
-impl ParseTree {
-    /// Attempt to parse a script into an AST
-    pub fn parse(script: &script::Script) -> Result<ParseTree, Error> {
-        let tokens = lex(script)?;
-        let mut iter = TokenIter::new(tokens);
+pub enum Malleability {
+    /// Disjunction where neither branch requires a signature, so a third party can
+    /// switch which branch is satisfied
+    UnsignedOr(String),
+    /// Threshold where more than `k` subexpressions can be satisfied without a
+    /// signature, so a third party can change which ones are satisfied
+    UnsignedThreshold(String),
+}
 
-        let top = parse_subexpression(&mut iter)?.into_t()?;
-        if let Some(leading) = iter.next() {
-            Err(Error::Unexpected(leading.to_string()))
-        } else {
-            Ok(ParseTree(top))
-        }
-    }
+/// Spending condition enforced by a script fragment, with the details of how it is
+/// enforced in script abstracted away. Conjunctions and disjunctions are represented
+/// as thresholds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Policy {
+    /// A signature with the given key
+    Key(secp256k1::PublicKey),
+    /// A signature with a key whose HASH160 is given
+    KeyHash(Hash160),
+    /// A SHA256 preimage
+    Sha256(sha256::Hash),
+    /// A HASH160 preimage
+    Hash160(Hash160),
+    /// A RIPEMD160 preimage
+    Ripemd160(Ripemd160Hash),
+    /// A HASH256 preimage
+    Hash256(Sha256dHash),
+    /// A relative locktime of at least the given value
+    Csv(u32),
+    /// Whatever a raw script fragment requires
+    Raw(script::Script),
+    /// A signature with the given key of the given message, rather than of the spending
+    /// transaction
+    #[cfg(feature = "elements")]
+    MessageSig(secp256k1::PublicKey, sha256::Hash),
+    /// Any `k` of the subpolicies
+    Threshold(usize, Vec<Policy>),
+}
 
-    /// Serialize an AST into script form
-    pub fn serialize(&self) -> script::Script {
-        self.0.serialize(script::Builder::new()).into_script()
+impl Policy {
+    fn and(left: Policy, right: Policy) -> Policy {
+        Policy::Threshold(2, vec![left, right])
     }
 
-    /// Compile an instantiated descriptor into a parse tree
-    pub fn compile(desc: &Descriptor<secp256k1::PublicKey>) -> ParseTree {
-        let t = T::from_descriptor(desc, 1.0);
-        ParseTree(Box::new(t.ast))
+    fn or(left: Policy, right: Policy) -> Policy {
+        Policy::Threshold(1, vec![left, right])
     }
 
-    /// Attempt to produce a satisfying witness for the scriptpubkey represented by the parse tree
-    pub fn satisfy(
-        &self,
-        key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
-        pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
-        hash_map: &HashMap<Sha256dHash, [u8; 32]>,
-        age: u32,
-    ) -> Result<Vec<Vec<u8>>, Error> {
-        self.0.satisfy(key_map, pkh_map, hash_map, age)
+    fn multi(k: usize, keys: &[secp256k1::PublicKey]) -> Policy {
+        Policy::Threshold(k, keys.iter().map(|pk| Policy::Key(*pk)).collect())
     }
 
-    /// Return a list of all public keys which might contribute to satisfaction of the scriptpubkey
-    pub fn required_keys(&self) -> Vec<secp256k1::PublicKey> {
-        self.0.required_keys()
+    /// `(k, n)` if the policy is a threshold of `k` signatures from `n` keys
+    fn multisig(&self) -> Option<(usize, usize)> {
+        match *self {
+            Policy::Threshold(k, ref subs) => {
+                let keys = subs.iter().all(|sub| matches!(*sub, Policy::Key(..) | Policy::KeyHash(..)));
+                if keys { Some((k, subs.len())) } else { None }
+            }
+            _ => None,
+        }
     }
-}
-
-/// Tokenize a script
-pub fn lex(script: &script::Script) -> Result<Vec<Token>, Error> {
-    let mut ret = Vec::with_capacity(script.len());
-    let secp = secp256k1::Secp256k1::without_caps();
 
-    for ins in script {
-        ret.push(match ins {
-            script::Instruction::Error(e) => return Err(Error::Script(e)),
-            script::Instruction::Op(opcodes::All::OP_BOOLAND) => Token::BoolAnd,
-            script::Instruction::Op(opcodes::All::OP_BOOLOR) => Token::BoolOr,
-            script::Instruction::Op(opcodes::All::OP_EQUAL) => Token::Equal,
-            script::Instruction::Op(opcodes::All::OP_EQUALVERIFY) => Token::EqualVerify,
-            script::Instruction::Op(opcodes::All::OP_CHECKSIG) => Token::CheckSig,
-            script::Instruction::Op(opcodes::All::OP_CHECKSIGVERIFY) => Token::CheckSigVerify,
-            script::Instruction::Op(opcodes::All::OP_CHECKMULTISIG) => Token::CheckMultiSig,
-            script::Instruction::Op(opcodes::All::OP_CHECKMULTISIGVERIFY) => Token::CheckMultiSigVerify,
-            script::Instruction::Op(op) if op == opcodes::OP_CSV => Token::CheckSequenceVerify,
-            script::Instruction::Op(opcodes::All::OP_FROMALTSTACK) => Token::FromAltStack,
-            script::Instruction::Op(opcodes::All::OP_TOALTSTACK) => Token::ToAltStack,
-            script::Instruction::Op(opcodes::All::OP_DROP) => Token::Drop,
-            script::Instruction::Op(opcodes::All::OP_DUP) => Token::Dup,
-            script::Instruction::Op(opcodes::All::OP_IF) => Token::If,
-            script::Instruction::Op(opcodes::All::OP_IFDUP) => Token::IfDup,
-            script::Instruction::Op(opcodes::All::OP_NOTIF) => Token::NotIf,
-            script::Instruction::Op(opcodes::All::OP_ELSE) => Token::Else,
-            script::Instruction::Op(opcodes::All::OP_ENDIF) => Token::EndIf,
-            script::Instruction::Op(opcodes::All::OP_SIZE) => Token::Size,
-            script::Instruction::Op(opcodes::All::OP_SWAP) => Token::Swap,
-            script::Instruction::Op(opcodes::All::OP_TUCK) => Token::Tuck,
-            script::Instruction::Op(opcodes::All::OP_VERIFY) => Token::Verify,
-            script::Instruction::Op(opcodes::All::OP_HASH160) => Token::Hash160,
-            script::Instruction::Op(opcodes::All::OP_SHA256) => Token::Sha256,
-            script::Instruction::PushBytes(bytes) => {
-                match bytes.len() {
-                    20 => Token::Hash160Hash(Hash160::from(bytes)),
-                    32 => Token::Sha256Hash(Sha256dHash::from(bytes)),
-                    33 => Token::Pubkey(secp256k1::PublicKey::from_slice(&secp, bytes).map_err(Error::BadPubkey)?),
-                    _ => {
-                        match script::read_scriptint(bytes) {
-                            Ok(v) if v >= 0 => {
-                                // check minimality of the number
-                                if &script::Builder::new().push_int(v).into_script()[1..] != bytes {
-                                    return Err(Error::InvalidPush(bytes.to_owned()));
-                                }
-                                Token::Number(v as u32)
+    /// Merges nested conjunctions into a single conjunction, and nested disjunctions
+    /// into a single disjunction, so that policies compiled from differently nested
+    /// descriptors compare alike
+    fn flatten(&self) -> Policy {
+        match *self {
+            Policy::Threshold(k, ref subs) => {
+                let is_and = k == subs.len() && k > 1;
+                let mut ret_k = k;
+                let mut ret = Vec::with_capacity(subs.len());
+                for sub in subs {
+                    match sub.flatten() {
+                        Policy::Threshold(sub_k, sub_subs) if (k == 1 && sub_k == 1) || (is_and && sub_k == sub_subs.len()) => {
+                            if is_and {
+                                ret_k += sub_k - 1;
                             }
-                            Ok(_) => return Err(Error::InvalidPush(bytes.to_owned())),
-                            Err(e) => return Err(Error::Script(e)),
+                            ret.extend(sub_subs);
                         }
+                        flat => ret.push(flat),
                     }
                 }
+                Policy::Threshold(ret_k, ret)
             }
-            script::Instruction::Op(opcodes::All::OP_PUSHBYTES_0) => Token::Number(0),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_1) => Token::Number(1),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_2) => Token::Number(2),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_3) => Token::Number(3),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_4) => Token::Number(4),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_5) => Token::Number(5),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_6) => Token::Number(6),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_7) => Token::Number(7),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_8) => Token::Number(8),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_9) => Token::Number(9),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_10) => Token::Number(10),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_11) => Token::Number(11),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_12) => Token::Number(12),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_13) => Token::Number(13),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_14) => Token::Number(14),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_15) => Token::Number(15),
-            script::Instruction::Op(opcodes::All::OP_PUSHNUM_16) => Token::Number(16),
-            script::Instruction::Op(op) => return Err(Error::InvalidOpcode(op)),
-        });
+            ref leaf => leaf.clone(),
+        }
     }
-    Ok(ret)
-}
-
-macro_rules! into_fn(
-    (E) => (AstElem::into_e);
-    (W) => (AstElem::into_w);
-    (V) => (AstElem::into_v);
-    (F) => (AstElem::into_f);
-    (T) => (AstElem::into_t);
-);
 
-macro_rules! is_fn(
-    (E) => (AstElem::is_e);
-    (W) => (AstElem::is_w);
-    (V) => (AstElem::is_v);
-    (F) => (AstElem::is_f);
-    (T) => (AstElem::is_t);
-);
+    /// Whether the (flattened) policy enforces the same conditions as `other`, up to
+    /// the order of the subpolicies of thresholds, 1-of-1 thresholds, and whether a
+    /// key is given directly or by its hash
+    fn equivalent(&self, other: &Policy) -> bool {
+        match (self.unwrap_single(), other.unwrap_single()) {
+            (&Policy::Key(ref pk), &Policy::KeyHash(ref hash)) |
+            (&Policy::KeyHash(ref hash), &Policy::Key(ref pk)) => {
+                Hash160::from_data(&pk.serialize()[..]) == *hash
+            }
+            (&Policy::Threshold(k, ref subs), &Policy::Threshold(other_k, ref other_subs)) => {
+                if k != other_k || subs.len() != other_subs.len() {
+                    return false;
+                }
+                // Equivalence is transitive, so matching each subpolicy to the first
+                // unmatched equivalent one finds a pairing whenever there is one
+                let mut matched = vec![false; other_subs.len()];
+                subs.iter().all(|sub| {
+                    let found = other_subs.iter().enumerate().position(|(i, other_sub)| {
+                        !matched[i] && sub.equivalent(other_sub)
+                    });
+                    if let Some(i) = found {
+                        matched[i] = true;
+                    }
+                    found.is_some()
+                })
+            }
+            (a, b) => a == b,
+        }
+    }
 
-macro_rules! expect_token(
-    ($tokens:expr, $expected:pat => $b:block) => ({
-        match $tokens.next() {
-            Some($expected) => $b,
-            Some(tok) => return Err(Error::Unexpected(tok.to_string())),
-            None => return Err(Error::UnexpectedStart),
+    /// The only subpolicy of a 1-of-1 threshold, or else the policy itself
+    fn unwrap_single(&self) -> &Policy {
+        match *self {
+            Policy::Threshold(1, ref subs) if subs.len() == 1 => subs[0].unwrap_single(),
+            _ => self,
         }
-    });
-    ($tokens:expr, $expected:pat) => (expect_token!($tokens, $expected => {}));
-);
+    }
 
-macro_rules! parse_tree(
-    // Tree
-    (
-        // list of tokens passed into macro scope
-        $tokens:expr,
-        // list of expected tokens
-        $($expected:pat $(, $more:pat)* => { $($sub:tt)* }),*
-        // list of expected subexpressions. The whole thing is surrounded
-        // in a $(..)* because it's optional. But it should only be used once.
-        $(
-        #subexpression $($parse_expected:tt: $name:ident $(, $parse_more:pat)* => { $($parse_sub:tt)* }),*
-        )*
-    ) => ({
-        match $tokens.next() {
-            $(Some($expected) => {
-                $(expect_token!($tokens, $more);)*
-                parse_tree!($tokens, $($sub)*)
-            },)*
-            Some(tok) => {
-                #[allow(unused_assignments)]
-                #[allow(unused_mut)]
-                let mut ret: Result<Box<AstElem>, Error> = Err(Error::Unexpected(tok.to_string()));
-                $(
-                $tokens.un_next(tok);
-                let subexpr = parse_subexpression($tokens)?;
-                ret =
-                $(if is_fn!($parse_expected)(&*subexpr) {
-                    let $name = into_fn!($parse_expected)(subexpr).unwrap();
-                    $(expect_token!($tokens, $parse_more);)*
-                    parse_tree!($tokens, $($parse_sub)*)
-                } else)* {
-                    Err(Error::Unexpected(subexpr.to_string()))
-                };
-                )*
-                ret
+    fn threshold(k: usize, sube: &E, subw: &[W]) -> Policy {
+        let mut subs = Vec::with_capacity(1 + subw.len());
+        subs.push(sube.policy());
+        subs.extend(subw.iter().map(|w| w.policy()));
+        Policy::Threshold(k, subs)
+    }
+
+    /// Records every occurrence of a key, along with the indices of the subpolicies
+    /// leading to it from `self`, whose own position is `path`
+    fn key_branches(&self, path: &mut Vec<usize>, ret: &mut Vec<(secp256k1::PublicKey, Vec<usize>)>) {
+        match *self {
+            Policy::Key(ref pk) => ret.push((*pk, path.clone())),
+            Policy::Threshold(_, ref subs) => {
+                for (i, sub) in subs.iter().enumerate() {
+                    path.push(i);
+                    sub.key_branches(path, ret);
+                    path.pop();
+                }
             }
-            None => return Err(Error::UnexpectedStart),
+            _ => {}
         }
-    });
-    // Not a tree; must be a block
-    ($tokens:expr, $($b:tt)*) => ({ $($b)* });
-);
+    }
+
+    /// Records every non-threshold subpolicy, in order of appearance
+    fn leaves<'a>(&'a self, ret: &mut Vec<&'a Policy>) {
+        match *self {
+            Policy::Threshold(_, ref subs) => {
+                for sub in subs {
+                    sub.leaves(ret);
+                }
+            }
+            _ => ret.push(self),
+        }
+    }
 
+    /// Maps every non-threshold subpolicy through `f`, keeping the distinct results
+    /// in order of first appearance
+    fn collect_leaves<X: PartialEq, M: FnMut(&Policy) -> Option<X>>(&self, f: M) -> Vec<X> {
+        let mut leaves = vec![];
+        self.leaves(&mut leaves);
+        let mut ret = vec![];
+        for x in leaves.into_iter().filter_map(f) {
+            if !ret.contains(&x) {
+                ret.push(x);
+            }
+        }
+        ret
+    }
 
-/// Parse a subexpression that is -not- a wexpr (wexpr is special-cased
-/// to avoid splitting expr into expr0 and exprn in the AST structure).
-fn parse_subexpression(tokens: &mut TokenIter) -> Result<Box<AstElem>, Error> {
-    if let Some(tok) = tokens.next() {
-        tokens.un_next(tok);
+    /// Whether every way of satisfying the policy requires a signature with `key`
+    fn requires_key(&self, key: &secp256k1::PublicKey) -> bool {
+        match *self {
+            Policy::Key(ref pk) => pk == key,
+            Policy::Threshold(k, ref subs) => {
+                // The key can be avoided iff `k` subpolicies can be satisfied without it
+                subs.iter().filter(|sub| !sub.requires_key(key)).count() < k
+            }
+            _ => false,
+        }
     }
-    let ret: Result<Box<AstElem>, Error> = parse_tree!(tokens,
-        Token::BoolAnd => {
-            #subexpression
-            W: wexpr => {
-                #subexpression
-                E: expr => {
-                    Ok(Box::new(E::ParallelAnd(expr, wexpr)))
+
+    /// The combinations of locktime types, as a mask of `TIMELOCK_*` bits, with which
+    /// the policy can be satisfied using signatures from `keys`, any preimages if
+    /// `have_preimages`, and relative locktimes no longer than `max_wait`. Zero if it
+    /// cannot be satisfied at all; combinations needing both types of locktime are
+    /// left out, since no transaction input can meet them.
+    fn satisfiable_mixes(&self, keys: &HashSet<secp256k1::PublicKey>, have_preimages: bool, max_wait: Option<u32>) -> u8 {
+        let met = match *self {
+            Policy::Key(ref pk) => keys.contains(pk),
+            Policy::KeyHash(ref hash) => keys.iter().any(|pk| Hash160::from_data(&pk.serialize()[..]) == *hash),
+            Policy::Sha256(..) | Policy::Hash160(..) |
+            Policy::Ripemd160(..) | Policy::Hash256(..) => have_preimages,
+            Policy::Csv(n) => {
+                let reachable = match max_wait {
+                    Some(max) => {
+                        n & SEQUENCE_TYPE_FLAG == max & SEQUENCE_TYPE_FLAG &&
+                        n & SEQUENCE_LOCKTIME_MASK <= max & SEQUENCE_LOCKTIME_MASK
+                    }
+                    None => true,
+                };
+                if !reachable {
+                    return 0;
+                }
+                return if n & SEQUENCE_TYPE_FLAG == 0 { TIMELOCK_HEIGHT } else { TIMELOCK_TIME };
+            }
+            // there is no telling what a raw fragment needs
+            Policy::Raw(..) => false,
+            #[cfg(feature = "elements")]
+            Policy::MessageSig(ref pk, _) => keys.contains(pk),
+            Policy::Threshold(k, ref subs) => {
+                // mixes[j] are the combinations possible with exactly j satisfied subs
+                let mut mixes = vec![0; k + 1];
+                mixes[0] = TIMELOCK_NONE;
+                for sub in subs {
+                    let sub_mix = sub.satisfiable_mixes(keys, have_preimages, max_wait);
+                    if sub_mix == 0 {
+                        continue;
+                    }
+                    for j in (1..k + 1).rev() {
+                        mixes[j] |= join_timelock_mixes(mixes[j - 1], sub_mix);
+                    }
                 }
+                return mixes[k] & !TIMELOCK_BOTH;
             }
-        },
-        Token::BoolOr => {
-            #subexpression
-            W: wexpr => {
-                #subexpression
-                E: expr => {
-                    Ok(Box::new(E::ParallelOr(expr, wexpr)))
+        };
+        if met { TIMELOCK_NONE } else { 0 }
+    }
+
+    /// Records every subpolicy, whose own position is `path`, at which a height-based
+    /// and a time-based relative locktime would first have to be met together, innermost
+    /// first. Returns the combinations of locktime types which satisfactions of the
+    /// policy may require, as a mask of `TIMELOCK_*` bits.
+    fn timelock_conflicts(&self, path: &mut Vec<usize>, ret: &mut Vec<TimelockConflict>) -> u8 {
+        match *self {
+            Policy::Csv(n) if n & SEQUENCE_TYPE_FLAG == 0 => TIMELOCK_HEIGHT,
+            Policy::Csv(_) => TIMELOCK_TIME,
+            Policy::Threshold(k, ref subs) => {
+                // mixes[j] are the combinations possible with exactly j satisfied subs
+                let mut mixes = vec![0; k + 1];
+                mixes[0] = TIMELOCK_NONE;
+                let mut inherited = false;
+                for (i, sub) in subs.iter().enumerate() {
+                    path.push(i);
+                    let sub_mix = sub.timelock_conflicts(path, ret);
+                    path.pop();
+                    inherited |= sub_mix & TIMELOCK_BOTH != 0;
+                    for j in (1..k + 1).rev() {
+                        mixes[j] |= join_timelock_mixes(mixes[j - 1], sub_mix);
+                    }
+                }
+                let mix = mixes[k];
+                if mix & TIMELOCK_BOTH != 0 && !inherited {
+                    let csvs = self.collect_leaves(|leaf| match *leaf {
+                        Policy::Csv(n) => Some(n),
+                        _ => None,
+                    });
+                    ret.push(TimelockConflict {
+                        branch: path.clone(),
+                        heights: csvs.iter().cloned().filter(|n| n & SEQUENCE_TYPE_FLAG == 0).collect(),
+                        times: csvs.iter().cloned().filter(|n| n & SEQUENCE_TYPE_FLAG != 0).collect(),
+                        unsatisfiable: mix == TIMELOCK_BOTH,
+                    });
                 }
+                mix
             }
-        },
-        Token::Equal => {
-            Token::Sha256Hash(hash), Token::Sha256, Token::EqualVerify, Token::Number(32), Token::Size => {
-                Ok(Box::new(T::HashEqual(hash)))
-            },
-            Token::Number(k) => {{
-                let mut ws = vec![];
-                let e;
-                loop {
-                    match tokens.next() {
-                        Some(Token::Add) => {
-                            let next_sub = parse_subexpression(tokens)?;
-                            if next_sub.is_w() {
-                                ws.push(*next_sub.into_w().unwrap());
-                            } else {
-                                return Err(Error::Unexpected(next_sub.to_string()));
+            _ => TIMELOCK_NONE,
+        }
+    }
+
+    /// Computes every minimal set of conditions which satisfies the policy, failing if
+    /// more than `max_paths` sets are needed at any point of the computation
+    fn spend_paths(&self, max_paths: usize) -> Result<Vec<Vec<Condition>>, Error> {
+        let condition = match *self {
+            Policy::Key(pk) => Condition::Sig(pk),
+            Policy::KeyHash(hash) => Condition::PkhSig(hash),
+            Policy::Sha256(hash) => Condition::Preimage(HashImage::Sha256(hash)),
+            Policy::Hash160(hash) => Condition::Preimage(HashImage::Hash160(hash)),
+            Policy::Ripemd160(hash) => Condition::Preimage(HashImage::Ripemd160(hash)),
+            Policy::Hash256(hash) => Condition::Preimage(HashImage::Hash256(hash)),
+            Policy::Csv(n) => Condition::Csv(n),
+            Policy::Raw(ref script) => Condition::Raw(sha256::Hash::from_data(&script[..])),
+            #[cfg(feature = "elements")]
+            Policy::MessageSig(pk, msg) => Condition::MessageSig(pk, msg),
+            Policy::Threshold(k, ref subs) => {
+                // `chosen[j]` holds the spend paths which satisfy exactly `j` of the
+                // subpolicies considered so far
+                let mut chosen = vec![vec![]; k + 1];
+                chosen[0].push(vec![]);
+                for sub in subs {
+                    let sub_paths = sub.spend_paths(max_paths)?;
+                    for j in (1..k + 1).rev() {
+                        let mut extended = vec![];
+                        for path in &chosen[j - 1] {
+                            for sub_path in &sub_paths {
+                                let mut union: Vec<Condition> = path.clone();
+                                for cond in sub_path {
+                                    if !union.contains(cond) {
+                                        union.push(*cond);
+                                    }
+                                }
+                                extended.push(union);
                             }
                         }
-                        Some(x) => {
-                            tokens.un_next(x);
-                            let next_sub = parse_subexpression(tokens)?;
-                            if next_sub.is_e() {
-                                e = next_sub.into_e().unwrap();
-                                break;
-                            } else {
-                                return Err(Error::Unexpected(next_sub.to_string()));
-                            }
+                        chosen[j].extend(extended);
+                        chosen[j] = minimize_paths(&chosen[j]);
+                        if chosen[j].len() > max_paths {
+                            return Err(Error::TooManySpendPaths(max_paths));
                         }
-                        None => return Err(Error::UnexpectedStart)
-                    }
-                }
-                Ok(Box::new(E::Threshold(k as usize, e, ws)))
-            }}
-        },
-        Token::EqualVerify => {
-            Token::Sha256Hash(hash), Token::Sha256, Token::EqualVerify, Token::Number(32), Token::Size => {
-                Ok(Box::new(V::HashEqual(hash)))
-            },
-            Token::Number(k) => {{
-                let mut ws = vec![];
-                let e;
-                loop {
-                    let next_sub = parse_subexpression(tokens)?;
-                    if next_sub.is_w() {
-                        ws.push(*next_sub.into_w().unwrap());
-                    } else if next_sub.is_e() {
-                        e = next_sub.into_e().unwrap();
-                        break;
-                    } else {
-                        return Err(Error::Unexpected(next_sub.to_string()));
                     }
                 }
-                Ok(Box::new(V::Threshold(k as usize, e, ws)))
-            }}
-        },
-        Token::CheckSig => {
-            Token::EqualVerify => {
-                Token::Hash160Hash(hash), Token::Hash160, Token::Dup => {
-                    Ok(Box::new(E::CheckSigHash(hash)))
+                return Ok(chosen.pop().expect("k + 1 entries"));
+            }
+        };
+        Ok(vec![vec![condition]])
+    }
+}
+
+/// Removes duplicate spend paths, and those which are supersets of others
+fn minimize_paths(paths: &[Vec<Condition>]) -> Vec<Vec<Condition>> {
+    let subset = |a: &Vec<Condition>, b: &Vec<Condition>| a.iter().all(|cond| b.contains(cond));
+    let mut ret: Vec<Vec<Condition>> = vec![];
+    for (i, path) in paths.iter().enumerate() {
+        let redundant = paths.iter().enumerate().any(|(j, other)| {
+            // Among equal sets only the first is kept
+            j != i && subset(other, path) && (other.len() < path.len() || j < i)
+        });
+        if !redundant {
+            ret.push(path.clone());
+        }
+    }
+    ret
+}
+
+/// Where a public key appears in a script, as reported by `ParseTree::key_report`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySource {
+    /// The key
+    pub key: secp256k1::PublicKey,
+    /// Every place the key appears. Each is given as the sequence of branch indices
+    /// which lead to the key, when the script is viewed as a tree of thresholds (with
+    /// conjunctions and disjunctions as 2-of-2 and 1-of-2 thresholds, and the keys of
+    /// a `CHECKMULTISIG` as the branches of a threshold)
+    pub branches: Vec<Vec<usize>>,
+    /// Whether a signature with the key is needed for every satisfaction of the script,
+    /// rather than only in some spend paths
+    pub always_required: bool,
+    /// Label of the key in the descriptor the script was compiled from, as given by
+    /// `Descriptor::key_report`; always `None` in `ParseTree::key_report`
+    pub label: Option<String>,
+    /// Fingerprint of the master key of the key and the path from it, as given by
+    /// `Descriptor::key_report` for extended keys; always `None` in
+    /// `ParseTree::key_report`
+    pub origin: Option<KeyOrigin>,
+}
+
+/// Summary of the keys needed to satisfy a script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyReport {
+    /// Each distinct public key in the script, in order of first appearance
+    pub keys: Vec<KeySource>,
+    /// Each distinct public key hash checked by a `DUP HASH160 <hash> EQUALVERIFY`
+    /// fragment, in order of first appearance
+    pub key_hashes: Vec<Hash160>,
+}
+
+/// Well-known kind of output, as recognized by `ParseTree::template`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Template {
+    /// A signature with a single key, given directly or by its hash
+    SingleKey,
+    /// `k` signatures from `n` keys; a Lightning funding output is a 2-of-2
+    Multisig(usize, usize),
+    /// Hashed timelock contract: signatures along with a hash preimage, or signatures
+    /// once a relative locktime has passed
+    Htlc,
+    /// Lightning commitment output paying its owner: a signature with the revocation
+    /// key, or a signature with the delayed key once a relative locktime has passed
+    LightningToLocal,
+    /// Lightning HTLC output: a signature with the revocation key, signatures along
+    /// with a hash preimage, or signatures without one
+    LightningHtlc,
+    /// Multisig which a single key may spend alone once a relative locktime has passed
+    EscrowWithTimeout,
+    /// Liquid-style federation: a multisig of the functionaries, or an emergency
+    /// multisig of other keys once a relative locktime has passed
+    Federation,
+    /// Anything else
+    Custom,
+}
+
+/// What a conjunction of spending conditions requires, as used to recognize templates
+#[derive(Default)]
+struct Requirements {
+    /// Each group of keys which must sign, as `(k, n)` for `k` signatures from `n` keys
+    sigs: Vec<(usize, usize)>,
+    /// Number of hash preimages
+    hashes: usize,
+    /// Number of relative locktimes
+    timelocks: usize,
+    /// Whether anything else, such as a disjunction, is required
+    other: bool,
+}
+
+impl Requirements {
+    fn of(policy: &Policy) -> Requirements {
+        let mut ret = Requirements::default();
+        match *policy {
+            Policy::Threshold(k, ref subs) if k == subs.len() && policy.multisig().is_none() => {
+                for sub in subs {
+                    ret.add(sub);
                 }
+            }
+            _ => ret.add(policy),
+        }
+        ret
+    }
+
+    fn add(&mut self, policy: &Policy) {
+        match *policy {
+            Policy::Key(..) | Policy::KeyHash(..) => self.sigs.push((1, 1)),
+            Policy::Sha256(..) | Policy::Hash160(..) |
+            Policy::Ripemd160(..) | Policy::Hash256(..) => self.hashes += 1,
+            Policy::Csv(..) => self.timelocks += 1,
+            Policy::Raw(..) => self.other = true,
+            #[cfg(feature = "elements")]
+            Policy::MessageSig(..) => self.other = true,
+            Policy::Threshold(..) => match policy.multisig() {
+                Some(group) => self.sigs.push(group),
+                None => self.other = true,
             },
-            Token::Pubkey(pk) => {{
-                match tokens.next() {
-                    Some(Token::Swap) => Ok(Box::new(W::CheckSig(pk))),
-                    Some(x) => {
-                        tokens.un_next(x);
-                        Ok(Box::new(E::CheckSig(pk)))
-                    }
-                    None => Ok(Box::new(E::CheckSig(pk))),
-                }
-            }}
-        },
-        Token::CheckSigVerify => {
-            Token::EqualVerify => {
-                Token::Hash160Hash(hash), Token::Hash160, Token::Dup => {
-                    Ok(Box::new(V::CheckSigHash(hash)))
-                }
+        }
+    }
+
+    /// Total number of signatures required
+    fn n_sigs(&self) -> usize {
+        self.sigs.iter().map(|&(k, _)| k).sum()
+    }
+
+    /// Whether signatures and a single hash preimage, and nothing else, are required
+    fn is_hash_branch(&self) -> bool {
+        self.n_sigs() > 0 && self.hashes == 1 && self.timelocks == 0 && !self.other
+    }
+
+    /// Whether signatures and at most one relative locktime, and nothing else, are required
+    fn is_timeout_branch(&self) -> bool {
+        self.n_sigs() > 0 && self.hashes == 0 && self.timelocks <= 1 && !self.other
+    }
+}
+
+/// A hash whose preimage is checked by a script, as reported by
+/// `ParseTree::required_hash_preimages`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HashImage {
+    /// A SHA256 hash
+    Sha256(sha256::Hash),
+    /// A HASH160 hash
+    Hash160(Hash160),
+    /// A RIPEMD160 hash
+    Ripemd160(Ripemd160Hash),
+    /// A HASH256 hash
+    Hash256(Sha256dHash),
+}
+
+/// A single condition of a spend path, as reported by `ParseTree::spend_paths`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Condition {
+    /// A signature with the given key
+    Sig(secp256k1::PublicKey),
+    /// A public key whose HASH160 is given, and a signature with it
+    PkhSig(Hash160),
+    /// A preimage of the given hash
+    Preimage(HashImage),
+    /// A relative locktime of at least the given value
+    Csv(u32),
+    /// A satisfaction of a raw script fragment, identified by the SHA256 of its script
+    Raw(sha256::Hash),
+    /// A signature with the given key of the given message, rather than of the spending
+    /// transaction
+    #[cfg(feature = "elements")]
+    MessageSig(secp256k1::PublicKey, sha256::Hash),
+}
+
+impl Condition {
+    /// Whether the condition can be met, perhaps after waiting, by someone holding the
+    /// secret keys of `keys` and, if `have_preimages`, any hash preimage. Raw conditions
+    /// are counted as met by nothing.
+    fn can_meet(&self, keys: &HashSet<secp256k1::PublicKey>, have_preimages: bool) -> bool {
+        match *self {
+            Condition::Sig(ref pk) => keys.contains(pk),
+            Condition::PkhSig(ref hash) => keys.iter().any(|pk| Hash160::from_data(&pk.serialize()[..]) == *hash),
+            Condition::Preimage(..) => have_preimages,
+            Condition::Csv(..) => true,
+            Condition::Raw(..) => false,
+            #[cfg(feature = "elements")]
+            Condition::MessageSig(ref pk, _) => keys.contains(pk),
+        }
+    }
+
+    /// The condition, if any, which stops `satisfier` from meeting this one. A key
+    /// hash condition whose public key is known but has no signature is reported as
+    /// a signature with that key. The scripts of raw conditions are looked up among
+    /// `raw_scripts`.
+    fn missing(&self, satisfier: &dyn Satisfier, raw_scripts: &[script::Script]) -> Option<Condition> {
+        let met = match *self {
+            Condition::Sig(ref pk) => satisfier.lookup_sig(pk).is_some(),
+            Condition::PkhSig(ref hash) => match satisfier.lookup_pkh(hash) {
+                Some(pk) => return Condition::Sig(pk).missing(satisfier, raw_scripts),
+                None => false,
             },
-            Token::Pubkey(pk) => {
-                Ok(Box::new(V::CheckSig(pk)))
-            }
-        },
-        Token::CheckMultiSig => {{
-            let n = expect_token!(tokens, Token::Number(n) => { n });
-            let mut pks = vec![];
-            for _ in 0..n {
-                pks.push(expect_token!(tokens, Token::Pubkey(pk) => { pk }));
-            }
-            pks.reverse();
-            let k = expect_token!(tokens, Token::Number(n) => { n });
-            Ok(Box::new(E::CheckMultiSig(k as usize, pks)))
-        }},
-        Token::CheckMultiSigVerify => {{
-            let n = expect_token!(tokens, Token::Number(n) => { n });
-            let mut pks = vec![];
-            for _ in 0..n {
-                pks.push(expect_token!(tokens, Token::Pubkey(pk) => { pk }));
-            }
-            pks.reverse();
-            let k = expect_token!(tokens, Token::Number(n) => { n });
-            Ok(Box::new(V::CheckMultiSig(k as usize, pks)))
-        }},
-        Token::CheckSequenceVerify => {
-            Token::Number(n) => {
-                Ok(Box::new(F::Csv(n)))
+            Condition::Preimage(HashImage::Sha256(ref hash)) => satisfier.lookup_hash_preimage(hash).is_some(),
+            Condition::Preimage(HashImage::Hash160(ref hash)) => satisfier.lookup_hash160_preimage(hash).is_some(),
+            Condition::Preimage(HashImage::Ripemd160(ref hash)) => satisfier.lookup_ripemd160_preimage(hash).is_some(),
+            Condition::Preimage(HashImage::Hash256(ref hash)) => satisfier.lookup_hash256_preimage(hash).is_some(),
+            Condition::Csv(n) => satisfier.check_older(n),
+            Condition::Raw(ref hash) => raw_scripts
+                .iter()
+                .find(|script| sha256::Hash::from_data(&script[..]) == *hash)
+                .is_some_and(|script| satisfier.lookup_raw(script).is_some()),
+            #[cfg(feature = "elements")]
+            Condition::MessageSig(ref pk, ref msg) => satisfier.lookup_message_sig(pk, msg).is_some(),
+        };
+        if met {
+            None
+        } else {
+            Some(*self)
+        }
+    }
+}
+
+/// Satisfier which meets exactly the conditions of one spend path, with signatures of
+/// the maximum size, for measuring the satisfaction of that path
+struct PathSatisfier<'a> {
+    conditions: &'a [Condition],
+    sig: secp256k1::Signature,
+    /// Stand-in for the keys of `PkhSig` conditions, whose keys are unknown
+    pkh_key: secp256k1::PublicKey,
+}
+
+impl<'a> PathSatisfier<'a> {
+    fn new(conditions: &'a [Condition]) -> PathSatisfier<'a> {
+        let secp = secp256k1::Secp256k1::new();
+        // 72 bytes of DER: both `r` and `s` have their high bit set, so need padding
+        let mut der = vec![0x30, 0x46];
+        for _ in 0..2 {
+            der.extend(&[0x02, 0x21, 0x00, 0x80]);
+            der.extend(&[0x01; 31]);
+        }
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[0xfe; 32]).expect("secret key");
+        PathSatisfier {
+            conditions,
+            sig: secp256k1::Signature::from_der(&secp, &der).expect("valid DER"),
+            pkh_key: secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context"),
+        }
+    }
+
+    fn has_preimage(&self, image: HashImage) -> Option<[u8; 32]> {
+        if self.conditions.contains(&Condition::Preimage(image)) {
+            Some([0; 32])
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> Satisfier for PathSatisfier<'a> {
+    fn lookup_sig(&self, pk: &secp256k1::PublicKey) -> Option<(secp256k1::Signature, SigHashType)> {
+        if *pk == self.pkh_key || self.conditions.contains(&Condition::Sig(*pk)) {
+            Some((self.sig, SigHashType::All))
+        } else {
+            None
+        }
+    }
+
+    // The placeholder signature has a high S value, to be as large as any signature
+    fn require_standard_sigs(&self) -> bool {
+        false
+    }
+
+    fn lookup_pkh(&self, hash: &Hash160) -> Option<secp256k1::PublicKey> {
+        if self.conditions.contains(&Condition::PkhSig(*hash)) {
+            Some(self.pkh_key)
+        } else {
+            None
+        }
+    }
+
+    fn lookup_hash_preimage(&self, hash: &sha256::Hash) -> Option<[u8; 32]> {
+        self.has_preimage(HashImage::Sha256(*hash))
+    }
+
+    fn lookup_hash160_preimage(&self, hash: &Hash160) -> Option<[u8; 32]> {
+        self.has_preimage(HashImage::Hash160(*hash))
+    }
+
+    fn lookup_ripemd160_preimage(&self, hash: &Ripemd160Hash) -> Option<[u8; 32]> {
+        self.has_preimage(HashImage::Ripemd160(*hash))
+    }
+
+    fn lookup_hash256_preimage(&self, hash: &Sha256dHash) -> Option<[u8; 32]> {
+        self.has_preimage(HashImage::Hash256(*hash))
+    }
+
+    fn check_older(&self, n: u32) -> bool {
+        self.conditions.contains(&Condition::Csv(n))
+    }
+
+    fn lookup_raw(&self, script: &script::Script) -> Option<Vec<Vec<u8>>> {
+        if self.conditions.contains(&Condition::Raw(sha256::Hash::from_data(&script[..]))) {
+            Some(vec![])
+        } else {
+            None
+        }
+    }
+
+    #[cfg(feature = "elements")]
+    fn lookup_message_sig(&self, pk: &secp256k1::PublicKey, msg: &sha256::Hash) -> Option<secp256k1::Signature> {
+        if self.conditions.contains(&Condition::MessageSig(*pk, *msg)) {
+            Some(self.sig)
+        } else {
+            None
+        }
+    }
+}
+
+/// A spend path along with the cost of using it, as reported by
+/// `ParseTree::spend_path_weights`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpendPathWeight {
+    /// The conditions of the path, as listed by `ParseTree::spend_paths`
+    pub conditions: Vec<Condition>,
+    /// Number of elements of the path's satisfaction
+    pub satisfaction_items: usize,
+    /// Size of the path's satisfaction as a witness, with 73-byte signatures (including
+    /// the sighash byte) and a length prefix for each element
+    pub satisfaction_size: usize,
+    /// The relative locktime which the spending input's sequence number must meet,
+    /// i.e. the largest of the path's `Csv` conditions, if it has any
+    pub relative_locktime: Option<u32>,
+}
+
+/// A spend path which a user can take along with how long they must wait for it, as
+/// reported by `ParseTree::spend_timeline`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpendTime {
+    /// The conditions of the path, as listed by `ParseTree::spend_paths`
+    pub conditions: Vec<Condition>,
+    /// The relative locktime which must pass before the path can be taken, i.e. the
+    /// largest of the path's `Csv` conditions, or `None` if it can be taken at once
+    pub relative_locktime: Option<u32>,
+}
+
+/// A subexpression of a script which may require both a height-based and a time-based
+/// relative locktime to be met, as reported by `ParseTree::check_timelocks`. No single
+/// transaction input can meet both, since it has only one sequence number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimelockConflict {
+    /// Position of the subexpression, as the sequence of branch indices which lead to
+    /// it in the same tree of thresholds as `KeySource::branches`
+    pub branch: Vec<usize>,
+    /// The height-based locktimes of the subexpression
+    pub heights: Vec<u32>,
+    /// The time-based locktimes of the subexpression
+    pub times: Vec<u32>,
+    /// Whether every satisfaction of the subexpression mixes locktime types, so that
+    /// the subexpression can never be satisfied, rather than only some of them
+    pub unsatisfiable: bool,
+}
+
+/// The type of a script fragment, which determines how it may be combined with others
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExprType {
+    /// Expression which may be satisfied or dissatisfied, leaving 1 or 0 on the stack
+    E,
+    /// Same as `E`, but taking its input from under the top stack element
+    W,
+    /// Expression which may only be satisfied, leaving 1 on the stack
+    F,
+    /// Expression which may only be satisfied, leaving nothing on the stack
+    V,
+    /// Expression which may only be satisfied, leaving a nonzero value on the stack
+    T,
+}
+
+/// Description of a single script fragment, as yielded by `ParseTree::iter`. Fragments
+/// which combine others do not carry their subexpressions; these are yielded
+/// immediately after them, one level deeper.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fragment {
+    /// A signature check with the given key
+    CheckSig(secp256k1::PublicKey),
+    /// A signature check with the given aggregate of the given keys
+    CheckSigAggregate(secp256k1::PublicKey, Vec<secp256k1::PublicKey>),
+    /// A signature check with a key whose HASH160 is given
+    CheckSigHash(Hash160),
+    /// A `CHECKMULTISIG` requiring `k` signatures from the given keys
+    CheckMultiSig(usize, Vec<secp256k1::PublicKey>),
+    /// A chain of `CHECKSIGADD`s requiring `k` signatures from the given keys
+    CheckSigAdd(usize, Vec<secp256k1::PublicKey>),
+    /// A SHA256 preimage check
+    HashEqual(sha256::Hash),
+    /// A HASH160 preimage check
+    Hash160Equal(Hash160),
+    /// A RIPEMD160 preimage check
+    Ripemd160Equal(Ripemd160Hash),
+    /// A HASH256 preimage check
+    Hash256Equal(Sha256dHash),
+    /// A relative locktime check
+    Csv(u32),
+    /// Script given by the user, which is satisfied externally
+    Raw(script::Script),
+    /// A `CHECKSIGFROMSTACK` check of a signature with the given key of the given message
+    #[cfg(feature = "elements")]
+    CheckSigFromStack(secp256k1::PublicKey, sha256::Hash),
+    /// A threshold requiring `k` of its subexpressions, combined with `ADD`
+    Threshold(usize),
+    /// Conjunction of two expressions which are both evaluated, combined with `BOOLAND`
+    ParallelAnd,
+    /// Conjunction in which the second expression is only evaluated if the first is satisfied
+    CascadeAnd,
+    /// Conjunction of a `V` expression with a following expression
+    And,
+    /// Disjunction of two expressions which are both evaluated, combined with `BOOLOR`
+    ParallelOr,
+    /// Disjunction in which the second expression is only evaluated if the first is dissatisfied
+    CascadeOr,
+    /// Disjunction in which the branch taken is chosen by an element of the satisfaction
+    SwitchOr,
+    /// Conversion of an expression to another type
+    Cast,
+}
+
+impl Fragment {
+    /// Short name of the kind of fragment, as used in outlines and statistics of trees
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Fragment::CheckSig(..) => "pk",
+            Fragment::CheckSigAggregate(..) => "pk_agg",
+            Fragment::CheckSigHash(..) => "pkh",
+            Fragment::CheckMultiSig(..) => "multi",
+            Fragment::CheckSigAdd(..) => "multi_a",
+            Fragment::HashEqual(..) => "sha256",
+            Fragment::Hash160Equal(..) => "hash160",
+            Fragment::Ripemd160Equal(..) => "ripemd160",
+            Fragment::Hash256Equal(..) => "hash256",
+            Fragment::Csv(..) => "csv",
+            Fragment::Raw(..) => "raw",
+            #[cfg(feature = "elements")]
+            Fragment::CheckSigFromStack(..) => "pk_csfs",
+            Fragment::Threshold(..) => "thresh",
+            Fragment::ParallelAnd => "parallel_and",
+            Fragment::CascadeAnd => "cascade_and",
+            Fragment::And => "and",
+            Fragment::ParallelOr => "parallel_or",
+            Fragment::CascadeOr => "cascade_or",
+            Fragment::SwitchOr => "switch_or",
+            Fragment::Cast => "cast",
+        }
+    }
+}
+
+/// Size and shape of a parse tree, as reported by `ParseTree::stats`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeStats {
+    /// Number of fragments in the tree
+    pub nodes: usize,
+    /// Greatest depth of any fragment, the root having depth 0
+    pub depth: usize,
+    /// Number of fragments of each kind, by `Fragment::name`
+    pub fragments: BTreeMap<&'static str, usize>,
+    /// Number of opcodes in the script other than pushes
+    pub opcodes: usize,
+    /// Number of pushes in the script, including those of small numbers by opcode
+    pub pushes: usize,
+    /// Total size of the data pushed, not counting the push opcodes
+    pub push_bytes: usize,
+}
+
+/// A script fragment along with its position in the tree, as yielded by `ParseTree::iter`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// Number of fragments this one is nested inside; the root has depth 0
+    pub depth: usize,
+    /// The type of the fragment
+    pub expr_type: ExprType,
+    /// What the fragment does
+    pub fragment: Fragment,
+    /// The script encoding of the fragment, including its subexpressions
+    pub script: script::Script,
+}
+
+/// Iterator over the fragments of a parse tree, in pre-order
+pub struct Iter<'a> {
+    stack: Vec<(usize, &'a dyn AstElem)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Node> {
+        let (depth, elem) = self.stack.pop()?;
+        let (fragment, children) = elem.fragment();
+        for child in children.into_iter().rev() {
+            self.stack.push((depth + 1, child));
+        }
+        Some(Node {
+            depth,
+            expr_type: elem.expr_type(),
+            fragment,
+            script: elem.serialize(script::Builder::new()).into_script(),
+        })
+    }
+}
+
+/// Writes the first bytes of a key or hash in hex, for outlines of parse trees
+fn fmt_abbrev(bytes: &[u8], f: &mut fmt::Formatter) -> fmt::Result {
+    for byte in bytes.iter().take(4) {
+        write!(f, "{:02x}", byte)?;
+    }
+    if bytes.len() > 4 {
+        f.write_str("..")?;
+    }
+    Ok(())
+}
+
+/// Writes a list of keys for outlines of parse trees, abbreviating each
+fn fmt_abbrev_keys(keys: &[secp256k1::PublicKey], f: &mut fmt::Formatter) -> fmt::Result {
+    for pk in keys {
+        f.write_str(",")?;
+        fmt_abbrev(&pk.serialize()[..], f)?;
+    }
+    Ok(())
+}
+
+/// Writes a fragment for outlines of parse trees, with abbreviated keys and hashes
+/// and decoded relative locktimes
+fn fmt_fragment(fragment: &Fragment, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(fragment.name())?;
+    match *fragment {
+        Fragment::CheckSig(ref pk) => {
+            f.write_str("(")?;
+            fmt_abbrev(&pk.serialize()[..], f)?;
+        }
+        Fragment::CheckSigAggregate(ref pk, ref keys) => {
+            f.write_str("(")?;
+            fmt_abbrev(&pk.serialize()[..], f)?;
+            fmt_abbrev_keys(keys, f)?;
+        }
+        Fragment::CheckMultiSig(k, ref keys) | Fragment::CheckSigAdd(k, ref keys) => {
+            write!(f, "({}", k)?;
+            fmt_abbrev_keys(keys, f)?;
+        }
+        Fragment::CheckSigHash(ref hash) | Fragment::Hash160Equal(ref hash) => {
+            f.write_str("(")?;
+            fmt_abbrev(&hash[..], f)?;
+        }
+        Fragment::HashEqual(ref hash) => {
+            f.write_str("(")?;
+            fmt_abbrev(&hash[..], f)?;
+        }
+        Fragment::Ripemd160Equal(ref hash) => {
+            f.write_str("(")?;
+            fmt_abbrev(&hash[..], f)?;
+        }
+        Fragment::Hash256Equal(ref hash) => {
+            f.write_str("(")?;
+            fmt_abbrev(&hash[..], f)?;
+        }
+        Fragment::Csv(n) if n & SEQUENCE_TYPE_FLAG != 0 => {
+            write!(f, "({} seconds", (n & SEQUENCE_LOCKTIME_MASK) * 512)?;
+        }
+        Fragment::Csv(n) => write!(f, "({} blocks", n & SEQUENCE_LOCKTIME_MASK)?,
+        Fragment::Raw(ref script) => write!(f, "({}", script)?,
+        #[cfg(feature = "elements")]
+        Fragment::CheckSigFromStack(ref pk, ref msg) => {
+            f.write_str("(")?;
+            fmt_abbrev(&pk.serialize()[..], f)?;
+            f.write_str(",")?;
+            fmt_abbrev(&msg[..], f)?;
+        }
+        Fragment::Threshold(k) => write!(f, "({}", k)?,
+        _ => return Ok(()),
+    }
+    f.write_str(")")
+}
+
+/// Displays the script of the tree. The alternate form (`{:#}`) instead gives an outline
+/// of the tree, one fragment per line, indented by depth and preceded by its type.
+impl fmt::Display for ParseTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !f.alternate() {
+            return fmt::Display::fmt(&*self.0, f);
+        }
+        for (i, node) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{:width$}{:?} ", "", node.expr_type, width = 2 * node.depth)?;
+            fmt_fragment(&node.fragment, f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Maximum size of a script, in bytes (consensus)
+const MAX_SCRIPT_SIZE: usize = 10_000;
+/// Maximum number of non-push opcodes in a script, counting each key of a
+/// `CHECKMULTISIG` as an opcode (consensus)
+const MAX_OPS_PER_SCRIPT: usize = 201;
+/// Maximum number of keys in a `CHECKMULTISIG` (consensus)
+const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+/// Maximum number of keys in a `CHECKMULTISIG` in a bare script (standardness)
+const MAX_STANDARD_BARE_MULTISIG_KEYS: usize = 15;
+/// Maximum number of keys of a bare multisig scriptpubkey which is relayed
+/// (standardness)
+const MAX_STANDARD_BARE_TEMPLATE_KEYS: usize = 3;
+/// Maximum size of a P2SH redeem script, which must fit in a single push (consensus)
+const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+/// Maximum size of a P2WSH witness script (standardness)
+const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3600;
+/// Maximum number of witness elements, excluding the witness script, in a P2WSH
+/// spend (standardness)
+const MAX_STANDARD_P2WSH_STACK_ITEMS: usize = 100;
+/// Maximum size of a witness element, excluding the witness script, in a P2WSH
+/// spend (standardness)
+const MAX_STANDARD_P2WSH_STACK_ITEM_SIZE: usize = 80;
+
+/// Consensus or standardness limit exceeded by a script. Each variant carries the
+/// offending value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LimitViolation {
+    /// Script is larger than 10,000 bytes
+    ScriptSize(usize),
+    /// Script has more than 201 non-push opcodes
+    OpCount(usize),
+    /// A `CHECKMULTISIG` has more than 20 keys, or more than 15 in a bare script
+    MultisigKeys(usize),
+    /// P2SH redeem script is larger than 520 bytes
+    RedeemScriptSize(usize),
+    /// P2WSH witness script is larger than 3,600 bytes
+    WitnessScriptSize(usize),
+    /// P2WSH satisfaction may need more than 100 witness elements
+    WitnessStackItems(usize),
+    /// P2WSH satisfaction has a witness element larger than 80 bytes
+    WitnessItemSize(usize),
+    /// Script uses an opcode which is not available in its context: `CHECKSIGADD`
+    /// outside of tapscripts, or `CHECKMULTISIG` in them
+    DisabledOpcode(opcodes::All),
+}
+
+/// Helper function that bounds the number of witness elements (or witness size) of a
+/// threshold satisfaction, given the satisfaction and dissatisfaction bounds of each
+/// subexpression
+fn max_threshold(k: usize, sats: &[usize], dissats: &[usize]) -> usize {
+    let mut extra: Vec<usize> = sats
+        .iter()
+        .zip(dissats.iter())
+        .map(|(sat, dissat)| sat.saturating_sub(*dissat))
+        .collect();
+    extra.sort();
+    let dissat_sum: usize = dissats.iter().sum();
+    dissat_sum + extra.iter().rev().take(k).sum::<usize>()
+}
+
+/// Helper function that checks a disjunction, given whether each branch requires a signature
+fn malleability_or(
+    left: bool,
+    right: bool,
+    fragment: &dyn AstElem,
+    issues: &mut Vec<Malleability>,
+) -> bool {
+    if !left && !right {
+        issues.push(Malleability::UnsignedOr(fragment.to_string()));
+    }
+    left && right
+}
+
+/// Helper function that checks a threshold, given whether each subexpression requires a signature
+fn malleability_threshold(
+    k: usize,
+    subs: &[bool],
+    fragment: &dyn AstElem,
+    issues: &mut Vec<Malleability>,
+) -> bool {
+    let unsigned = subs.iter().filter(|sig| !**sig).count();
+    if unsigned > k {
+        issues.push(Malleability::UnsignedThreshold(fragment.to_string()));
+    }
+    unsigned < k
+}
+
+/// Type of output that a compiled script is embedded in, as determined by the
+/// `Sh`, `Wsh` and `Wpkh` wrappers of a descriptor
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Wrapper {
+    /// The script is used directly as the scriptpubkey
+    Bare,
+    /// The script is the redeem script of a pay-to-scripthash output
+    Sh,
+    /// The script is the witness script of a pay-to-witness-scripthash output
+    Wsh,
+    /// The script is the witness script of a P2SH-wrapped pay-to-witness-scripthash output
+    ShWsh,
+    /// The script is the implied pay-to-pubkeyhash script of a pay-to-witness-pubkeyhash output
+    Wpkh,
+    /// The script is the implied pay-to-pubkeyhash script of a P2SH-wrapped pay-to-witness-pubkeyhash output
+    ShWpkh,
+}
+
+impl Wrapper {
+    /// The kind of script which is embedded in this type of output
+    pub fn script_context(&self) -> ScriptContext {
+        match *self {
+            Wrapper::Bare => ScriptContext::Bare,
+            Wrapper::Sh => ScriptContext::Legacy,
+            Wrapper::Wsh | Wrapper::ShWsh | Wrapper::Wpkh | Wrapper::ShWpkh => ScriptContext::SegwitV0,
+        }
+    }
+}
+
+/// Kind of script being tokenized, parsed or compiled, which determines the encodings
+/// of public keys that are recognized and the limits that the script must meet
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScriptContext {
+    /// A bare scriptpubkey, in which keys may be compressed or uncompressed. Only
+    /// pay-to-pubkey, pay-to-pubkey-hash and small multisig scripts are standard.
+    Bare,
+    /// A P2SH redeem script, in which keys may be compressed or uncompressed
+    Legacy,
+    /// A segwit v0 witness script, in which keys must be compressed
+    SegwitV0,
+    /// A tapscript, in which keys are 32-byte x-only keys. A 32-byte push is only
+    /// taken to be a key when it is followed by `CHECKSIG` or `CHECKSIGVERIFY`;
+    /// otherwise it is a hash.
+    Taproot,
+    /// A segwit v0 witness script on an Elements chain such as Liquid, which has the
+    /// limits of `SegwitV0` but also recognizes `CHECKSIGFROMSTACK(VERIFY)`
+    #[cfg(feature = "elements")]
+    Elements,
+}
+
+impl ScriptContext {
+    /// Whether the script is a segwit v0 witness script, of Bitcoin or of Elements
+    fn is_segwit_v0(&self) -> bool {
+        match *self {
+            ScriptContext::SegwitV0 => true,
+            #[cfg(feature = "elements")]
+            ScriptContext::Elements => true,
+            _ => false,
+        }
+    }
+
+    /// Whether 65-byte uncompressed keys may appear in the script
+    fn allows_uncompressed(&self) -> bool {
+        match *self {
+            ScriptContext::Bare | ScriptContext::Legacy => true,
+            ScriptContext::SegwitV0 | ScriptContext::Taproot => false,
+            #[cfg(feature = "elements")]
+            ScriptContext::Elements => false,
+        }
+    }
+}
+
+impl fmt::Display for ScriptContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ScriptContext::Bare => "bare",
+            ScriptContext::Legacy => "P2SH",
+            ScriptContext::SegwitV0 => "segwit v0",
+            ScriptContext::Taproot => "tapscript",
+            #[cfg(feature = "elements")]
+            ScriptContext::Elements => "Elements segwit v0",
+        })
+    }
+}
+
+/// Default limit on the nesting of subexpressions when parsing scripts, which keeps
+/// hostile scripts from overflowing the stack
+pub const MAX_PARSE_DEPTH: usize = 100;
+
+/// Top-level script AST type. The tree is shared rather than copied when a parse
+/// tree is cloned, so clones are cheap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseTree(pub(crate) Arc<T>);
+
+impl ParseTree {
+    /// Attempt to parse a script into an AST
+    pub fn parse(script: &script::Script) -> Result<ParseTree, Error> {
+        ParseTree::parse_with_max_depth(script, MAX_PARSE_DEPTH)
+    }
+
+    /// Attempt to parse a script into an AST, failing if subexpressions are nested
+    /// more than `max_depth` deep
+    pub fn parse_with_max_depth(script: &script::Script, max_depth: usize) -> Result<ParseTree, Error> {
+        ParseTree::parse_inner(script, ScriptContext::SegwitV0, max_depth)
+    }
+
+    /// Attempt to parse a script of the given kind into an AST. Parse trees only hold
+    /// compressed keys, so any uncompressed key which the context allows is reported
+    /// as a parse error at the key. X-only keys are only accepted in `CHECKSIGADD`
+    /// multisigs, and are taken to be the keys with even y-coordinate.
+    pub fn parse_with_context(script: &script::Script, context: ScriptContext) -> Result<ParseTree, Error> {
+        ParseTree::parse_inner(script, context, MAX_PARSE_DEPTH)
+    }
+
+    /// Attempt to parse a script of the given kind into an AST as for
+    /// `ParseTree::parse_with_context`, also accepting numbers which are not minimally
+    /// encoded, and keys and hashes pushed with `OP_PUSHDATA1`, `OP_PUSHDATA2` or
+    /// `OP_PUSHDATA4`, as some historic scripts on chain have. Returns the byte offset of
+    /// each such push along with the tree, whose `serialize` encodes them minimally.
+    pub fn parse_nonminimal(script: &script::Script, context: ScriptContext) -> Result<(ParseTree, Vec<usize>), Error> {
+        let mut stream = TokenStream::new(script, context).allow_nonminimal();
+        let tree = ParseTree::parse_stream(&mut stream, MAX_PARSE_DEPTH)?;
+        Ok((tree, stream.nonminimal))
+    }
+
+    /// Attempt to parse the tokens of a stream into an AST. Scripts are parsed from
+    /// the end, so the tokens are still gathered before parsing starts, but they are
+    /// lexed straight into the parser's buffer rather than into a separate list.
+    pub fn parse_token_stream(mut stream: TokenStream) -> Result<ParseTree, Error> {
+        ParseTree::parse_stream(&mut stream, MAX_PARSE_DEPTH)
+    }
+
+    fn parse_inner(script: &script::Script, context: ScriptContext, max_depth: usize) -> Result<ParseTree, Error> {
+        ParseTree::parse_stream(&mut TokenStream::new(script, context), max_depth)
+    }
+
+    fn parse_stream(stream: &mut TokenStream, max_depth: usize) -> Result<ParseTree, Error> {
+        let mut tokens = vec![];
+        let mut offsets = vec![];
+        for res in stream {
+            let (offset, tok) = res?;
+            if let Token::UncompressedPubkey(..) = tok {
+                return Err(Error::ParseError {
+                    offset,
+                    index: tokens.len(),
+                    found: tok.to_string(),
+                    expected: "compressed public key",
+                });
+            }
+            tokens.push(tok);
+            offsets.push(offset);
+        }
+        let mut iter = TokenIter::new(tokens, offsets, max_depth);
+
+        let top = parse_subexpression(&mut iter)?;
+        if !top.is_t() {
+            return Err(iter.error(top.to_string(), "T-expression"));
+        }
+        if let Some(leading) = iter.next() {
+            Err(iter.error(leading.to_string(), "start of script"))
+        } else {
+            Ok(ParseTree(top.into_t().unwrap()))
+        }
+    }
+
+    /// Serialize an AST into script form
+    pub fn serialize(&self) -> script::Script {
+        self.0.serialize(script::Builder::new()).into_script()
+    }
+
+    /// Compile an instantiated descriptor into a parse tree. The descriptor must not
+    /// contain any `Sh`, `Wsh` or `Wpkh` wrappers; use `ParseTree::from_descriptor` for those.
+    /// It must also not contain any `Combo`, `Addr` or `Raw` forms, which have no
+    /// parse tree, nor any empty or unsatisfiable thresholds.
+    pub fn compile(desc: &Descriptor<secp256k1::PublicKey>) -> Result<ParseTree, CompileError> {
+        Ok(ParseTree(Arc::new(compiler::compile(desc)?)))
+    }
+
+    /// Compile an instantiated descriptor into a parse tree as for `ParseTree::compile`,
+    /// but exactly as written. Every other way of compiling first folds away the parts
+    /// of the descriptor which impose no condition, such as the `time(0)` of
+    /// `and(time(0),pk(A))`, as `Descriptor::fold_constants` does.
+    pub fn compile_unfolded(desc: &Descriptor<secp256k1::PublicKey>) -> Result<ParseTree, CompileError> {
+        Ok(ParseTree(Arc::new(compiler::compile_unfolded(desc)?)))
+    }
+
+    /// Compile an instantiated descriptor into a parse tree as the given version of
+    /// the compiler did, giving the same script for the same version in every release
+    /// of this library. Wallets which must regenerate their scripts should record the
+    /// version they compiled with and use this.
+    pub fn compile_with_version(desc: &Descriptor<secp256k1::PublicKey>, version: CompilerVersion) -> Result<ParseTree, CompileError> {
+        Ok(ParseTree(Arc::new(compiler::compile_with_version(desc, version)?)))
+    }
+
+    /// Compile an instantiated descriptor into a parse tree as for `ParseTree::compile`,
+    /// using `aggregator` to compute the keys which the script checks in place of the
+    /// keys of each `Aggregate` descriptor. Without an aggregator, descriptors containing
+    /// key aggregates cannot be compiled.
+    pub fn compile_with_aggregator(desc: &Descriptor<secp256k1::PublicKey>, aggregator: &dyn KeyAggregator) -> Result<ParseTree, CompileError> {
+        Ok(ParseTree(Arc::new(compiler::compile_with_aggregator(desc, aggregator)?)))
+    }
+
+    /// Compile an instantiated descriptor into a parse tree as for `ParseTree::compile`,
+    /// minimizing its expected cost under `params` rather than its expected size in
+    /// bytes, e.g. to account for Schnorr signatures or for the segwit discount
+    pub fn compile_with_params(desc: &Descriptor<secp256k1::PublicKey>, params: CostParams) -> Result<ParseTree, CompileError> {
+        Ok(ParseTree(Arc::new(compiler::compile_with_params(desc, params)?)))
+    }
+
+    /// Compile an instantiated descriptor into a parse tree as for `ParseTree::compile`,
+    /// taking the probabilities that each branch is used from past spends in `stats`
+    /// rather than assuming every branch equally likely, e.g. so that a wallet's
+    /// recovery path, which its history shows is almost never used, is moved out of
+    /// the way of its everyday path
+    pub fn compile_with_stats(desc: &Descriptor<secp256k1::PublicKey>, stats: &SpendStats) -> Result<ParseTree, CompileError> {
+        Ok(ParseTree(Arc::new(compiler::compile_with_stats(desc, stats)?)))
+    }
+
+    /// Compile an instantiated descriptor into a parse tree as for `ParseTree::compile`,
+    /// but whose script is at most `max_size` bytes, such as the 520 bytes allowed of a
+    /// P2SH redeem script. Larger compilations are traded for ones with smaller scripts
+    /// and larger witnesses, which may check keys against their hashes as `pkh` does;
+    /// if none fits, fails with `CompileError::OverBudget`.
+    pub fn compile_with_budget(desc: &Descriptor<secp256k1::PublicKey>, max_size: usize) -> Result<ParseTree, CompileError> {
+        Ok(ParseTree(Arc::new(compiler::compile_with_budget(desc, max_size)?)))
+    }
+
+    /// Compile an instantiated descriptor into a parse tree as for `ParseTree::compile`,
+    /// also reporting every choice the compiler made along the way: for each descriptor
+    /// node, expression type and pair of probabilities it was compiled for, the
+    /// fragments considered, their costs and the one chosen. Choices are listed in
+    /// the order they were completed, so the last one is that of the root.
+    pub fn compile_with_report(desc: &Descriptor<secp256k1::PublicKey>) -> Result<(ParseTree, Vec<CompileStep>), CompileError> {
+        let (ast, steps) = compiler::compile_with_report(desc)?;
+        Ok((ParseTree(Arc::new(ast)), steps))
+    }
+
+    /// Compile an instantiated descriptor into a parse tree for use as a script of
+    /// kind `context`, as for `ParseTree::compile`. Fails rather than producing a
+    /// script that would be invalid or nonstandard there: bare scripts must be one of
+    /// the standard templates, and the limits of `ParseTree::check_context_limits`
+    /// must be met. Compiling tapscripts is not supported.
+    pub fn compile_for_context(desc: &Descriptor<secp256k1::PublicKey>, context: ScriptContext) -> Result<ParseTree, CompileError> {
+        if context == ScriptContext::Taproot {
+            return Err(CompileError::UnsupportedContext(context));
+        }
+        let tree = ParseTree::compile(desc)?;
+        if context == ScriptContext::Bare && !tree.is_standard_bare() {
+            return Err(CompileError::NonStandardBare(desc.to_string()));
+        }
+        let violations = tree.check_context_limits(context)
+            .map_err(|_| CompileError::BadRawScript(desc.to_string()))?;
+        match violations.first() {
+            Some(violation) => Err(CompileError::LimitExceeded(context, *violation)),
+            None => Ok(tree),
+        }
+    }
+
+    /// Compile an instantiated descriptor into a parse tree, first stripping off any
+    /// `Sh`, `Wsh` or `Wpkh` wrappers. Returns the parse tree of the inner script along
+    /// with the type of output it belongs to.
+    pub fn from_descriptor(desc: &Descriptor<secp256k1::PublicKey>) -> Result<(ParseTree, Wrapper), Error> {
+        fn wpkh(pk: &secp256k1::PublicKey) -> ParseTree {
+            let hash = Hash160::from_data(&pk.serialize()[..]);
+            ParseTree(Arc::new(T::CastE(Arc::new(E::CheckSigHash(hash)))))
+        }
+
+        fn inner(desc: &Descriptor<secp256k1::PublicKey>) -> Result<ParseTree, Error> {
+            ParseTree::compile(desc).map_err(Error::Compile)
+        }
+
+        match *desc {
+            Descriptor::Wpkh(ref pk) => Ok((wpkh(pk), Wrapper::Wpkh)),
+            Descriptor::Wsh(ref sub) => Ok((inner(sub)?, Wrapper::Wsh)),
+            Descriptor::Sh(ref sub) => match **sub {
+                Descriptor::Wpkh(ref pk) => Ok((wpkh(pk), Wrapper::ShWpkh)),
+                Descriptor::Wsh(ref subsub) => Ok((inner(subsub)?, Wrapper::ShWsh)),
+                _ => Ok((inner(sub)?, Wrapper::Sh)),
+            },
+            Descriptor::OpReturn(..) => Err(Error::Unspendable),
+            _ => Ok((inner(desc)?, Wrapper::Bare)),
+        }
+    }
+
+    /// Attempt to produce a satisfying witness for the scriptpubkey represented by the parse tree
+    pub fn satisfy<S: Satisfier>(&self, satisfier: &S) -> Result<Vec<Vec<u8>>, Error> {
+        self.satisfy_with_mode(satisfier, SatisfactionMode::Smallest)
+    }
+
+    /// Attempt to produce a satisfying witness, choosing among the ways of satisfying
+    /// the script which the satisfier's data allows as directed by `mode`. Sizes are
+    /// those of witness elements, as for a segwit output
+    pub fn satisfy_with_mode<S: Satisfier>(&self, satisfier: &S, mode: SatisfactionMode) -> Result<Vec<Vec<u8>>, Error> {
+        self.satisfy_for_wrapper(satisfier, mode, Wrapper::Wsh)
+    }
+
+    /// Attempt to produce a satisfying witness as for `satisfy_with_mode`, for the script
+    /// embedded in an output of type `wrapper`. Satisfactions are compared by their weight
+    /// where they will actually be placed: four units per byte of a scriptSig (where
+    /// empty and small numeric elements take one byte), one per byte of a witness
+    pub fn satisfy_for_wrapper<S: Satisfier>(
+        &self,
+        satisfier: &S,
+        mode: SatisfactionMode,
+        wrapper: Wrapper,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let strategy = Strategy {
+            mode,
+            script_sig: wrapper == Wrapper::Bare || wrapper == Wrapper::Sh,
+        };
+        self.0.satisfy(satisfier, strategy)
+    }
+
+    /// Attempt to produce a satisfying witness as for `satisfy_for_wrapper`, failing
+    /// rather than returning a witness which exceeds the standardness limits checked by
+    /// `check_standard_witness`
+    pub fn satisfy_standard<S: Satisfier>(
+        &self,
+        satisfier: &S,
+        mode: SatisfactionMode,
+        wrapper: Wrapper,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let sat = self.satisfy_for_wrapper(satisfier, mode, wrapper)?;
+        self.check_standard_witness(&sat, wrapper)?;
+        Ok(sat)
+    }
+
+    /// Check that `witness`, whose last element is the top of the stack, satisfies the
+    /// script when spending input `index` of `tx`, including that its signatures are valid
+    /// for the transaction. `amount` is the value of the output being spent and `wrapper`
+    /// the type of that output, which together determine the signature hashes
+    pub fn verify_satisfaction(
+        &self,
+        witness: &[Vec<u8>],
+        tx: &Transaction,
+        index: usize,
+        amount: u64,
+        wrapper: Wrapper,
+    ) -> Result<(), Error> {
+        if index >= tx.input.len() {
+            return Err(Error::InputIndexOutOfRange(index));
+        }
+        let checker = TxChecker {
+            tx,
+            index,
+            amount,
+            script_code: self.serialize(),
+            wrapper,
+        };
+        interpreter::execute(&self.0, witness, &checker).map(|_| ())
+    }
+
+    /// Execute the script against `witness`, whose last element is the top of the stack,
+    /// leaving the checking of signatures and relative locktimes to `checker`. On success,
+    /// returns the conditions which the witness met and the relative locktimes which the
+    /// spending input must meet
+    pub fn interpret<C: Checker>(&self, witness: &[Vec<u8>], checker: &C) -> Result<Execution, Error> {
+        interpreter::execute(&self.0, witness, checker)
+    }
+
+    /// Explain an existing spend of an output of the given type whose scriptSig and
+    /// witness are `script_sig` and `witness`, reporting which keys signed, which hash
+    /// preimages were revealed and which relative locktimes were used. Signatures and
+    /// locktimes are not checked, as for a spend already accepted by the network
+    pub fn explain_spend(&self, script_sig: &script::Script, witness: &[Vec<u8>], wrapper: Wrapper) -> Result<Execution, Error> {
+        let sat = disassemble_input(&self.serialize(), wrapper, script_sig, witness)?;
+        interpreter::execute(&self.0, &sat, &NoChecks)
+    }
+
+    /// Return a list of all public keys which might contribute to satisfaction of the scriptpubkey
+    pub fn required_keys(&self) -> Vec<secp256k1::PublicKey> {
+        let mut ret = vec![];
+        for pk in self.0.required_keys() {
+            if !ret.contains(&pk) {
+                ret.push(pk);
+            }
+        }
+        ret
+    }
+
+    /// Like `required_keys`, but also resolves the public key hashes checked by `pkh`
+    /// fragments through `satisfier`. Returns the keys, including those whose hashes
+    /// were resolved, along with the hashes whose keys are unknown.
+    pub fn required_keys_with<S: Satisfier>(&self, satisfier: &S) -> (Vec<secp256k1::PublicKey>, Vec<Hash160>) {
+        let mut keys = self.required_keys();
+        let mut unknown = vec![];
+        for hash in self.key_report().key_hashes {
+            match satisfier.lookup_pkh(&hash) {
+                Some(pk) => if !keys.contains(&pk) {
+                    keys.push(pk);
+                },
+                None => if !unknown.contains(&hash) {
+                    unknown.push(hash);
+                },
+            }
+        }
+        (keys, unknown)
+    }
+
+    /// Return a copy of the tree with every public key mapped through `f`, stopping at
+    /// the first error. Keys which appear in the script only as hashes (`pkh` fragments)
+    /// cannot be recovered, so they are left untouched.
+    pub fn translate<Err, Fun>(&self, f: Fun) -> Result<ParseTree, Err>
+        where Fun: FnMut(&secp256k1::PublicKey) -> Result<secp256k1::PublicKey, Err>
+    {
+        Ok(ParseTree(Arc::new(self.0.translate(&mut KeyTranslator(f))?)))
+    }
+
+    /// Return every public key which might contribute to satisfaction of the scriptpubkey,
+    /// along with where it appears and whether it is needed for every satisfaction, as
+    /// well as the public key hashes which are checked
+    pub fn key_report(&self) -> KeyReport {
+        let policy = self.0.policy();
+
+        let mut occurrences = vec![];
+        policy.key_branches(&mut vec![], &mut occurrences);
+        let mut keys: Vec<KeySource> = vec![];
+        for (pk, branch) in occurrences {
+            if let Some(source) = keys.iter_mut().find(|source| source.key == pk) {
+                source.branches.push(branch);
+                continue;
+            }
+            keys.push(KeySource {
+                key: pk,
+                branches: vec![branch],
+                always_required: policy.requires_key(&pk),
+                label: None,
+                origin: None,
+            });
+        }
+
+        let key_hashes = policy.collect_leaves(|leaf| match *leaf {
+            Policy::KeyHash(hash) => Some(hash),
+            _ => None,
+        });
+        KeyReport {
+            keys,
+            key_hashes,
+        }
+    }
+
+    /// Whether the scriptpubkey could ever be satisfied with signatures from `keys`,
+    /// along with any hash preimages if `have_preimages`, once the output is old enough.
+    /// If `max_wait` is given, relative locktimes longer than it (in the same encoding
+    /// as a `CHECKSEQUENCEVERIFY` value, so of the same type) are considered out of
+    /// reach. Raw script fragments are assumed to be unsatisfiable. No witness is
+    /// produced, so this is cheap enough to decide whether to track an output at all.
+    pub fn is_satisfiable_with(&self, keys: &HashSet<secp256k1::PublicKey>, have_preimages: bool, max_wait: Option<u32>) -> bool {
+        self.0.policy().satisfiable_mixes(keys, have_preimages, max_wait) != 0
+    }
+
+    /// Return every hash whose preimage might contribute to satisfaction of the
+    /// scriptpubkey, without duplicates
+    pub fn required_hash_preimages(&self) -> Vec<HashImage> {
+        self.0.policy().collect_leaves(|leaf| match *leaf {
+            Policy::Sha256(hash) => Some(HashImage::Sha256(hash)),
+            Policy::Hash160(hash) => Some(HashImage::Hash160(hash)),
+            Policy::Ripemd160(hash) => Some(HashImage::Ripemd160(hash)),
+            Policy::Hash256(hash) => Some(HashImage::Hash256(hash)),
+            _ => None,
+        })
+    }
+
+    /// Return every relative locktime (`CHECKSEQUENCEVERIFY` value) which might need to
+    /// be met to satisfy the scriptpubkey, without duplicates. The script language has
+    /// no absolute locktimes, so these are the only timelocks which can appear.
+    pub fn timelocks(&self) -> Vec<u32> {
+        self.0.policy().collect_leaves(|leaf| match *leaf {
+            Policy::Csv(n) => Some(n),
+            _ => None,
+        })
+    }
+
+    /// Return every subexpression of the scriptpubkey at which a height-based and a
+    /// time-based relative locktime would have to be combined, innermost first; those
+    /// marked `unsatisfiable` are dead branches. A subexpression is only reported if
+    /// none of its own subexpressions is, so a dead branch does not also cause every
+    /// conjunction containing it to be reported. The script language has no absolute
+    /// locktimes, so these are the only possible conflicts.
+    pub fn check_timelocks(&self) -> Vec<TimelockConflict> {
+        let mut ret = vec![];
+        self.0.policy().timelock_conflicts(&mut vec![], &mut ret);
+        ret
+    }
+
+    /// Return every minimal set of conditions which suffices to satisfy the scriptpubkey,
+    /// e.g. `[[Sig(A), Sig(B)], [Sig(C), Csv(1000)]]` for `or(and(A, B), and(C, csv(1000)))`.
+    ///
+    /// Since the number of spend paths may be exponential in the size of the script, this
+    /// fails with `Error::TooManySpendPaths` if more than `max_paths` sets are needed for
+    /// the script or any of its subexpressions.
+    pub fn spend_paths(&self, max_paths: usize) -> Result<Vec<Vec<Condition>>, Error> {
+        self.0.policy().spend_paths(max_paths)
+    }
+
+    /// Return, for each of the spend paths listed by `spend_paths`, every condition of
+    /// the path which `satisfier` cannot currently meet, so that a coordinator can tell
+    /// each cosigner what is still needed. Paths with nothing missing can be satisfied.
+    pub fn satisfactions_missing<S: Satisfier>(&self, satisfier: &S, max_paths: usize) -> Result<Vec<Vec<Condition>>, Error> {
+        let paths = self.spend_paths(max_paths)?;
+        let raw_scripts = self.0.policy().collect_leaves(|leaf| match *leaf {
+            Policy::Raw(ref script) => Some(script.clone()),
+            _ => None,
+        });
+        Ok(paths
+            .iter()
+            .map(|path| path.iter().filter_map(|cond| cond.missing(satisfier, &raw_scripts)).collect())
+            .collect())
+    }
+
+    /// Return every spend path listed by `spend_paths` with the size of its satisfaction
+    /// and the relative locktime it needs, to help choose between them. Paths which mix
+    /// height-based and time-based locktimes can never be used, so are left out. The
+    /// script language has no absolute locktimes, so none are reported. Raw script
+    /// fragments are counted as satisfied by nothing.
+    pub fn spend_path_weights(&self, max_paths: usize) -> Result<Vec<SpendPathWeight>, Error> {
+        let mut ret = vec![];
+        for path in self.spend_paths(max_paths)? {
+            let csvs: Vec<u32> = path
+                .iter()
+                .filter_map(|cond| match *cond {
+                    Condition::Csv(n) => Some(n),
+                    _ => None,
+                })
+                .collect();
+            if csvs.iter().any(|n| n & SEQUENCE_TYPE_FLAG == 0) && csvs.iter().any(|n| n & SEQUENCE_TYPE_FLAG != 0) {
+                continue;
+            }
+
+            let sat = self.0.satisfy(&PathSatisfier::new(&path), Strategy {
+                mode: SatisfactionMode::Smallest,
+                script_sig: false,
+            })?;
+            ret.push(SpendPathWeight {
+                satisfaction_items: sat.len(),
+                satisfaction_size: sat.iter().map(|elem| 1 + elem.len()).sum(),
+                relative_locktime: csvs.into_iter().max_by_key(|n| n & SEQUENCE_LOCKTIME_MASK),
+                conditions: path,
+            });
+        }
+        Ok(ret)
+    }
+
+    /// Return every spend path listed by `spend_paths` which someone holding the secret
+    /// keys of `keys` and, if `have_preimages`, any hash preimage could take, along with
+    /// the relative locktime they must wait for first. Paths needing no locktime come
+    /// first, followed by those needing height-based and then time-based locktimes, each
+    /// from shortest to longest wait, so that e.g. `or(multi(2,A,B,C),and(A,csv(1000)))`
+    /// held by `A` and `B` reads as spendable now with 2 of 3 keys, or by `A` alone after
+    /// 1000 blocks. Paths which mix locktime types can never be taken, so are left out.
+    pub fn spend_timeline(&self, keys: &HashSet<secp256k1::PublicKey>, have_preimages: bool, max_paths: usize) -> Result<Vec<SpendTime>, Error> {
+        let mut ret = vec![];
+        for path in self.spend_paths(max_paths)? {
+            if !path.iter().all(|cond| cond.can_meet(keys, have_preimages)) {
+                continue;
+            }
+            let csvs: Vec<u32> = path
+                .iter()
+                .filter_map(|cond| match *cond {
+                    Condition::Csv(n) => Some(n),
+                    _ => None,
+                })
+                .collect();
+            if csvs.iter().any(|n| n & SEQUENCE_TYPE_FLAG == 0) && csvs.iter().any(|n| n & SEQUENCE_TYPE_FLAG != 0) {
+                continue;
+            }
+            ret.push(SpendTime {
+                relative_locktime: csvs.into_iter().max_by_key(|n| n & SEQUENCE_LOCKTIME_MASK),
+                conditions: path,
+            });
+        }
+        ret.sort_by_key(|time| match time.relative_locktime {
+            None => (0, 0),
+            Some(n) if n & SEQUENCE_TYPE_FLAG == 0 => (1, n & SEQUENCE_LOCKTIME_MASK),
+            Some(n) => (2, n & SEQUENCE_LOCKTIME_MASK),
+        });
+        Ok(ret)
+    }
+
+    /// An outline of the tree for debugging, as given by its alternate (`{:#}`) display
+    pub fn pretty(&self) -> String {
+        format!("{:#}", self)
+    }
+
+    /// Count the fragments of the tree, by kind, and the opcodes and pushes of its script,
+    /// for bucketing scripts by complexity. Each fragment's own part of the script is
+    /// encoded on its own, so the whole script is never built.
+    pub fn stats(&self) -> TreeStats {
+        let mut ret = TreeStats {
+            nodes: 0,
+            depth: 0,
+            fragments: BTreeMap::new(),
+            opcodes: 0,
+            pushes: 0,
+            push_bytes: 0,
+        };
+        let mut stack = vec![(0, &*self.0 as &dyn AstElem)];
+        while let Some((depth, elem)) = stack.pop() {
+            ret.nodes += 1;
+            ret.depth = cmp::max(ret.depth, depth);
+            let (fragment, _) = elem.fragment();
+            *ret.fragments.entry(fragment.name()).or_insert(0) += 1;
+
+            let mut pieces = vec![];
+            let rest = elem.serialize_pieces(script::Builder::new(), &mut pieces);
+            pieces.push(Piece::Script(rest.into_script()));
+            for piece in pieces {
+                match piece {
+                    Piece::Script(script) => for ins in &script {
+                        match ins {
+                            script::Instruction::PushBytes(bytes) => {
+                                ret.pushes += 1;
+                                ret.push_bytes += bytes.len();
+                            }
+                            script::Instruction::Op(op) if op as u8 <= opcodes::All::OP_PUSHNUM_16 as u8 => ret.pushes += 1,
+                            script::Instruction::Op(..) => ret.opcodes += 1,
+                            script::Instruction::Error(..) => {}
+                        }
+                    },
+                    Piece::Sub(sub) => stack.push((depth + 1, sub)),
+                }
+            }
+        }
+        ret
+    }
+
+    /// Hash of the shape of the tree, with its keys, hashes, locktimes and raw scripts
+    /// left out, so that scripts differing only in those hash the same. Thresholds and
+    /// the numbers of keys and signatures of multisigs are part of the shape. Grouping
+    /// scripts by this hash gives the templates they are instances of.
+    pub fn structural_hash(&self) -> sha256::Hash {
+        let mut shape = String::new();
+        let mut stack = vec![(0, &*self.0 as &dyn AstElem)];
+        while let Some((depth, elem)) = stack.pop() {
+            let (fragment, children) = elem.fragment();
+            shape.push_str(&format!("{} {:?} {}", depth, elem.expr_type(), fragment.name()));
+            match fragment {
+                Fragment::CheckMultiSig(k, ref keys) | Fragment::CheckSigAdd(k, ref keys) => {
+                    shape.push_str(&format!("({},{})", k, keys.len()));
+                }
+                Fragment::CheckSigAggregate(_, ref keys) => shape.push_str(&format!("({})", keys.len())),
+                Fragment::Threshold(k) => shape.push_str(&format!("({})", k)),
+                _ => {}
+            }
+            shape.push('\n');
+            for child in children.into_iter().rev() {
+                stack.push((depth + 1, child));
+            }
+        }
+        sha256::Hash::from_data(shape.as_bytes())
+    }
+
+    /// Whether the script enforces the same spending conditions as `other`, however
+    /// differently the two encode them, as the output of different versions of the
+    /// compiler may. Fragments are compared by the conditions they enforce, with
+    /// nested `and`s and `or`s merged and the order of branches ignored, so that for
+    /// instance a key check matches a check of the key's hash, and `SwitchOr` matches
+    /// `SwitchOrV`. Keys, hashes and locktimes must be the same.
+    pub fn semantically_eq(&self, other: &ParseTree) -> bool {
+        self.0.policy().flatten().equivalent(&other.0.policy().flatten())
+    }
+
+    /// Iterate over every fragment of the script, starting with the root and visiting
+    /// each fragment's subexpressions (in script order) immediately after it
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { stack: vec![(0, &*self.0 as &dyn AstElem)] }
+    }
+
+    /// Whether third parties are unable to malleate satisfactions of the script, i.e.
+    /// whether `malleability_report` finds nothing
+    pub fn is_non_malleable(&self) -> bool {
+        self.malleability_report().is_empty()
+    }
+
+    /// Return a list of all constructions in the script which allow third parties to
+    /// replace one satisfaction with another, innermost first
+    pub fn malleability_report(&self) -> Vec<Malleability> {
+        let mut issues = vec![];
+        self.0.malleability(&mut issues);
+        issues
+    }
+
+    /// Upper bound on the size of a satisfying witness, not including the witness script
+    /// or the element count. Every element is counted with a one-byte length prefix and
+    /// every signature is assumed to be 73 bytes including its sighash byte, as in the
+    /// compiler's cost estimates. The satisfactions of raw script fragments are unknown,
+    /// so are not counted.
+    pub fn max_satisfaction_size(&self) -> usize {
+        self.0.max_satisfaction_size()
+    }
+
+    /// Upper bound on the number of elements of a satisfying witness, not including
+    /// the witness script or the satisfactions of raw script fragments
+    pub fn max_satisfaction_items(&self) -> usize {
+        self.0.max_satisfaction_items()
+    }
+
+    /// Checks the script against the consensus and standardness limits which apply
+    /// to outputs of type `context`, returning every limit which is exceeded
+    pub fn check_resource_limits(&self, context: Wrapper) -> Result<Vec<LimitViolation>, Error> {
+        self.check_context_limits(context.script_context())
+    }
+
+    /// Checks the script against the consensus and standardness limits which apply
+    /// to scripts of kind `context`, returning every limit which is exceeded. The
+    /// templates which bare scripts are restricted to are not checked; see
+    /// `ParseTree::is_standard_bare`. Tapscripts have no limits on their size or
+    /// number of opcodes, so are only checked for disabled opcodes. Fails if the
+    /// script contains a raw fragment which cannot be tokenized.
+    pub fn check_context_limits(&self, context: ScriptContext) -> Result<Vec<LimitViolation>, Error> {
+        let mut ret = vec![];
+        let script = self.serialize();
+        let tokens = match lex(&script, context) {
+            Ok(tokens) => tokens,
+            // The only opcodes which a parse tree may contain but not lex, other than in
+            // raw fragments, are `CHECKSIGADD` and, outside of Elements, `CHECKSIGFROMSTACK(VERIFY)`
+            Err(Error::InvalidOpcode(op, _)) => {
+                ret.push(LimitViolation::DisabledOpcode(op));
+                return Ok(ret);
+            }
+            Err(e) => return Err(e),
+        };
+
+        if context == ScriptContext::Taproot {
+            for tok in &tokens {
+                match *tok {
+                    Token::CheckMultiSig => {
+                        ret.push(LimitViolation::DisabledOpcode(opcodes::All::OP_CHECKMULTISIG));
+                    }
+                    Token::CheckMultiSigVerify => {
+                        ret.push(LimitViolation::DisabledOpcode(opcodes::All::OP_CHECKMULTISIGVERIFY));
+                    }
+                    _ => {}
+                }
+            }
+            return Ok(ret);
+        }
+
+        if script.len() > MAX_SCRIPT_SIZE {
+            ret.push(LimitViolation::ScriptSize(script.len()));
+        }
+
+        let max_keys = match context {
+            ScriptContext::Bare => MAX_STANDARD_BARE_MULTISIG_KEYS,
+            _ => MAX_PUBKEYS_PER_MULTISIG,
+        };
+        let mut n_ops = 0;
+        let mut last_num = 0;
+        for tok in &tokens {
+            match *tok {
+                Token::Number(n) => last_num = n as usize,
+                Token::Hash160Hash(..) | Token::Sha256Hash(..) | Token::Pubkey(..) |
+                Token::UncompressedPubkey(..) | Token::XOnlyPubkey(..) => {}
+                Token::CheckMultiSig | Token::CheckMultiSigVerify => {
+                    // `last_num` is the number of keys
+                    n_ops += 1 + last_num;
+                    if last_num > max_keys {
+                        ret.push(LimitViolation::MultisigKeys(last_num));
+                    }
+                }
+                _ => n_ops += 1,
+            }
+        }
+        if n_ops > MAX_OPS_PER_SCRIPT {
+            ret.push(LimitViolation::OpCount(n_ops));
+        }
+
+        match context {
+            ScriptContext::Legacy if script.len() > MAX_SCRIPT_ELEMENT_SIZE => {
+                ret.push(LimitViolation::RedeemScriptSize(script.len()));
+            }
+            _ if context.is_segwit_v0() => {
+                if script.len() > MAX_STANDARD_P2WSH_SCRIPT_SIZE {
+                    ret.push(LimitViolation::WitnessScriptSize(script.len()));
+                }
+                let n_items = self.max_satisfaction_items();
+                if n_items > MAX_STANDARD_P2WSH_STACK_ITEMS {
+                    ret.push(LimitViolation::WitnessStackItems(n_items));
+                }
+            }
+            _ => {}
+        }
+
+        Ok(ret)
+    }
+
+    /// Checks `witness`, a satisfaction of the script as returned by `satisfy_for_wrapper`,
+    /// against the standardness limits on spends of outputs of type `wrapper`: a P2WSH
+    /// spend may have at most 100 witness elements of at most 80 bytes each besides
+    /// the witness script, which may have at most 3,600 bytes. Nodes do not relay
+    /// transactions which exceed these, though they are valid in blocks. Other types
+    /// of output have no such limits.
+    pub fn check_standard_witness(&self, witness: &[Vec<u8>], wrapper: Wrapper) -> Result<(), Error> {
+        if wrapper != Wrapper::Wsh && wrapper != Wrapper::ShWsh {
+            return Ok(());
+        }
+        if witness.len() > MAX_STANDARD_P2WSH_STACK_ITEMS {
+            return Err(Error::NonStandardWitness(LimitViolation::WitnessStackItems(witness.len())));
+        }
+        if let Some(item) = witness.iter().find(|item| item.len() > MAX_STANDARD_P2WSH_STACK_ITEM_SIZE) {
+            return Err(Error::NonStandardWitness(LimitViolation::WitnessItemSize(item.len())));
+        }
+        let script_len = self.serialize().len();
+        if script_len > MAX_STANDARD_P2WSH_SCRIPT_SIZE {
+            return Err(Error::NonStandardWitness(LimitViolation::WitnessScriptSize(script_len)));
+        }
+        Ok(())
+    }
+
+    /// Whether the script is one of the templates which are standard as a bare
+    /// scriptpubkey: pay-to-pubkey, pay-to-pubkey-hash, or a multisig of at most
+    /// three keys
+    pub fn is_standard_bare(&self) -> bool {
+        match *self.0 {
+            T::CastE(ref expr) => match **expr {
+                E::CheckSig(..) | E::CheckSigAggregate(..) | E::CheckSigHash(..) => true,
+                E::CheckMultiSig(_, ref pks) => pks.len() <= MAX_STANDARD_BARE_TEMPLATE_KEYS,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Classifies the script as one of the well-known kinds of output, by matching
+    /// its spending conditions against each template. Nested `and`s and `or`s are
+    /// merged first and the order of branches is ignored, so the result does not
+    /// depend on how the compiler arranged the script.
+    pub fn template(&self) -> Template {
+        let policy = self.0.policy().flatten();
+        if let Some((k, n)) = policy.multisig() {
+            return Template::Multisig(k, n);
+        }
+        match policy {
+            Policy::Key(..) | Policy::KeyHash(..) => Template::SingleKey,
+            Policy::Threshold(1, ref branches) if branches.len() == 2 => {
+                let (a, b) = (Requirements::of(&branches[0]), Requirements::of(&branches[1]));
+                for &(first, second) in &[(&a, &b), (&b, &a)] {
+                    let timeout = second.is_timeout_branch() && second.timelocks == 1;
+                    if first.is_hash_branch() && timeout {
+                        return Template::Htlc;
+                    }
+                    let single_key = first.sigs == [(1, 1)] && first.hashes == 0 && first.timelocks == 0 && !first.other;
+                    if single_key && timeout && second.sigs == [(1, 1)] {
+                        return Template::LightningToLocal;
+                    }
+                }
+                for &(multi, timeout) in &[(&branches[0], &b), (&branches[1], &a)] {
+                    let k = multi.multisig().map(|(k, _)| k).unwrap_or(0);
+                    if k > 1 && timeout.is_timeout_branch() && timeout.timelocks == 1 {
+                        return if timeout.n_sigs() == 1 {
+                            Template::EscrowWithTimeout
+                        } else {
+                            Template::Federation
+                        };
+                    }
+                }
+                Template::Custom
+            }
+            Policy::Threshold(1, ref branches) if branches.len() == 3 => {
+                let reqs: Vec<Requirements> = branches.iter().map(Requirements::of).collect();
+                for revocation in 0..3 {
+                    let (x, y) = (&reqs[(revocation + 1) % 3], &reqs[(revocation + 2) % 3]);
+                    let single_key = matches!(branches[revocation], Policy::Key(..) | Policy::KeyHash(..));
+                    if single_key && ((x.is_hash_branch() && y.is_timeout_branch()) ||
+                                      (y.is_hash_branch() && x.is_timeout_branch())) {
+                        return Template::LightningHtlc;
+                    }
+                }
+                Template::Custom
+            }
+            _ => Template::Custom,
+        }
+    }
+}
+
+/// Appends the bytes of a raw script fragment to `builder`, as they are
+fn append_raw(builder: script::Builder, script: &script::Script) -> script::Builder {
+    let mut ret = builder.into_script()[..].to_vec();
+    ret.extend(&script[..]);
+    script::Builder::from(ret)
+}
+
+/// Writes the keys of a `CHECKSIGADD` multisig, each with its signature check, leaving
+/// the number of valid signatures on the stack
+fn serialize_checksigadd_keys(mut builder: script::Builder, pks: &[secp256k1::PublicKey]) -> script::Builder {
+    for (i, pk) in pks.iter().enumerate() {
+        builder = builder.push_slice(&pk.serialize()[1..]);
+        builder = if i == 0 {
+            builder.push_opcode(opcodes::All::OP_CHECKSIG)
+        } else {
+            builder.push_opcode(OP_CHECKSIGADD)
+        };
+    }
+    builder
+}
+
+/// Tokenize a script of the given kind. Only scripts made up of the opcodes of
+/// `Token` and of minimal pushes of numbers, hashes and keys are accepted, so that
+/// `tokens_to_script` gives back exactly the script that was lexed.
+pub fn lex(script: &script::Script, context: ScriptContext) -> Result<Vec<Token>, Error> {
+    TokenStream::new(script, context).map(|res| res.map(|(_, tok)| tok)).collect()
+}
+
+/// Tokenize a script as `lex` does, but on failure also return the tokens lexed before
+/// the error, each with its byte offset, for diagnosing where a script leaves the
+/// recognized subset. Tokens are returned up to the error, or all of them along with
+/// no error if the script lexes.
+pub fn lex_partial(script: &script::Script, context: ScriptContext) -> (Vec<(usize, Token)>, Option<Error>) {
+    let mut tokens = vec![];
+    for res in TokenStream::new(script, context) {
+        match res {
+            Ok(tok) => tokens.push(tok),
+            Err(e) => return (tokens, Some(e)),
+        }
+    }
+    (tokens, None)
+}
+
+/// Tokenize a script as `lex` does, but also accept numbers which are not minimally
+/// encoded, and keys and hashes pushed with a `PUSHDATA` opcode, which consensus allows
+/// and some scripts already on chain have. Returns the tokens along with the byte offset
+/// of each such push. `tokens_to_script` encodes pushes minimally, so it does not give
+/// back the original script if any were found.
+pub fn lex_nonminimal(script: &script::Script, context: ScriptContext) -> Result<(Vec<Token>, Vec<usize>), Error> {
+    let mut stream = TokenStream::new(script, context).allow_nonminimal();
+    let mut tokens = vec![];
+    for res in &mut stream {
+        tokens.push(res?.1);
+    }
+    Ok((tokens, stream.nonminimal))
+}
+
+/// Encode tokens as a script. This is the inverse of `lex`: the tokens of any script
+/// which `lex` accepts encode to that same script, and lexing the encoding gives
+/// back the same tokens when done in the same context. Sequences of tokens which
+/// `lex` cannot produce, such as an x-only key which is not followed by a signature
+/// check, are encoded all the same but may lex differently.
+pub fn tokens_to_script(tokens: &[Token]) -> script::Script {
+    let mut builder = script::Builder::new();
+    for tok in tokens {
+        builder = tok.serialize(builder);
+    }
+    builder.into_script()
+}
+
+/// Length in bytes of the (successfully decoded) instruction at `offset` in `script`
+pub(crate) fn instruction_len(script: &[u8], offset: usize) -> usize {
+    let read_le = |n: usize| {
+        (0..n).fold(0, |acc, i| acc | ((script[offset + 1 + i] as usize) << (8 * i)))
+    };
+    match script[offset] {
+        n @ 0x01..=0x4b => 1 + n as usize,
+        0x4c => 2 + read_le(1),
+        0x4d => 3 + read_le(2),
+        0x4e => 5 + read_le(4),
+        _ => 1,
+    }
+}
+
+/// Whether `op` is one of the `NOP`s reserved for soft forks, i.e. not `OP_NOP` itself
+/// nor one already assigned to `CHECKLOCKTIMEVERIFY` or `CHECKSEQUENCEVERIFY`
+fn is_reserved_nop(op: opcodes::All) -> bool {
+    matches!(
+        op,
+        opcodes::All::OP_NOP1 | opcodes::All::OP_NOP4 | opcodes::All::OP_NOP5 |
+        opcodes::All::OP_NOP6 | opcodes::All::OP_NOP7 | opcodes::All::OP_NOP8 |
+        opcodes::All::OP_NOP9 | opcodes::All::OP_NOP10
+    )
+}
+
+/// Iterator over the tokens of a script, each with its byte offset, which lexes one
+/// instruction at a time instead of building a list of tokens as `lex` does. It
+/// accepts exactly the scripts `lex` accepts, and yields an error, after which it
+/// yields nothing, where `lex` would fail. Tokens own no heap data, so they are
+/// yielded by value and nothing is allocated per token.
+pub struct TokenStream<'a> {
+    script: &'a [u8],
+    instructions: script::Instructions<'a>,
+    context: ScriptContext,
+    secp: secp256k1::Secp256k1,
+    /// Byte offset of the next instruction
+    offset: usize,
+    /// Token lexed ahead, when deciding whether a 32-byte push in a tapscript is a key
+    pending: Option<Result<(usize, Token), Error>>,
+    /// Whether an error has been yielded
+    done: bool,
+    /// Whether to accept pushes which are not minimally encoded
+    allow_nonminimal: bool,
+    /// Whether to accept the opcodes reserved for soft forks
+    allow_reserved_nops: bool,
+    /// Byte offset of each push yielded so far which is not minimally encoded
+    nonminimal: Vec<usize>,
+}
+
+impl<'a> TokenStream<'a> {
+    /// Creates a stream over the tokens of a script of the given kind
+    pub fn new(script: &'a script::Script, context: ScriptContext) -> TokenStream<'a> {
+        TokenStream {
+            script: &script[..],
+            instructions: script.into_iter(),
+            context,
+            secp: secp256k1::Secp256k1::without_caps(),
+            offset: 0,
+            pending: None,
+            done: false,
+            allow_nonminimal: false,
+            allow_reserved_nops: false,
+            nonminimal: vec![],
+        }
+    }
+
+    /// Also accept pushes of numbers, keys and hashes which are not minimally encoded,
+    /// as `lex_nonminimal` does, recording their offsets for `nonminimal_offsets`
+    pub fn allow_nonminimal(mut self) -> TokenStream<'a> {
+        self.allow_nonminimal = true;
+        self
+    }
+
+    /// Also accept the opcodes `OP_NOP1` and `OP_NOP4` to `OP_NOP10`, which are reserved
+    /// for soft forks and rejected by default, as `Token::ReservedNop`s. Scripts using
+    /// future soft forks can then be lexed, though not parsed.
+    pub fn allow_reserved_nops(mut self) -> TokenStream<'a> {
+        self.allow_reserved_nops = true;
+        self
+    }
+
+    /// Byte offset of each push which is not minimally encoded among the tokens lexed
+    /// so far
+    pub fn nonminimal_offsets(&self) -> &[usize] {
+        &self.nonminimal
+    }
+
+    /// Lexes the next instruction, without regard to the instructions after it
+    fn lex_one(&mut self) -> Option<Result<(usize, Token), Error>> {
+        let ins = self.instructions.next()?;
+        let start = self.offset;
+        match ins {
+            script::Instruction::Error(_) => {}
+            _ => self.offset += instruction_len(self.script, start),
+        }
+        Some(self.token(ins, start).map(|tok| (start, tok)))
+    }
+
+    /// Converts the instruction at byte offset `start` into a token
+    fn token(&mut self, ins: script::Instruction, start: usize) -> Result<Token, Error> {
+        Ok(match ins {
+            script::Instruction::Error(e) => return Err(Error::Script(e, start)),
+            script::Instruction::Op(opcodes::All::OP_BOOLAND) => Token::BoolAnd,
+            script::Instruction::Op(opcodes::All::OP_BOOLOR) => Token::BoolOr,
+            script::Instruction::Op(opcodes::All::OP_ADD) => Token::Add,
+            script::Instruction::Op(opcodes::All::OP_EQUAL) => Token::Equal,
+            script::Instruction::Op(opcodes::All::OP_EQUALVERIFY) => Token::EqualVerify,
+            script::Instruction::Op(opcodes::All::OP_CHECKSIG) => Token::CheckSig,
+            script::Instruction::Op(opcodes::All::OP_CHECKSIGVERIFY) => Token::CheckSigVerify,
+            script::Instruction::Op(opcodes::All::OP_CHECKMULTISIG) => Token::CheckMultiSig,
+            script::Instruction::Op(opcodes::All::OP_CHECKMULTISIGVERIFY) => Token::CheckMultiSigVerify,
+            script::Instruction::Op(op) if op == OP_CHECKSIGADD && self.context == ScriptContext::Taproot => {
+                Token::CheckSigAdd
+            }
+            #[cfg(feature = "elements")]
+            script::Instruction::Op(op) if op == OP_CHECKSIGFROMSTACK && self.context == ScriptContext::Elements => {
+                Token::CheckSigFromStack
+            }
+            #[cfg(feature = "elements")]
+            script::Instruction::Op(op) if op == OP_CHECKSIGFROMSTACKVERIFY && self.context == ScriptContext::Elements => {
+                Token::CheckSigFromStackVerify
+            }
+            script::Instruction::Op(opcodes::All::OP_NUMEQUAL) => Token::NumEqual,
+            script::Instruction::Op(opcodes::All::OP_NUMEQUALVERIFY) => Token::NumEqualVerify,
+            script::Instruction::Op(op) if op == opcodes::OP_CSV => Token::CheckSequenceVerify,
+            script::Instruction::Op(op) if op == opcodes::OP_CLTV => Token::CheckLockTimeVerify,
+            script::Instruction::Op(opcodes::All::OP_FROMALTSTACK) => Token::FromAltStack,
+            script::Instruction::Op(opcodes::All::OP_TOALTSTACK) => Token::ToAltStack,
+            script::Instruction::Op(opcodes::All::OP_DROP) => Token::Drop,
+            script::Instruction::Op(opcodes::All::OP_DUP) => Token::Dup,
+            script::Instruction::Op(opcodes::All::OP_IF) => Token::If,
+            script::Instruction::Op(opcodes::All::OP_IFDUP) => Token::IfDup,
+            script::Instruction::Op(opcodes::All::OP_NOTIF) => Token::NotIf,
+            script::Instruction::Op(opcodes::All::OP_ELSE) => Token::Else,
+            script::Instruction::Op(opcodes::All::OP_ENDIF) => Token::EndIf,
+            script::Instruction::Op(opcodes::All::OP_SIZE) => Token::Size,
+            script::Instruction::Op(opcodes::All::OP_SWAP) => Token::Swap,
+            script::Instruction::Op(opcodes::All::OP_TUCK) => Token::Tuck,
+            script::Instruction::Op(opcodes::All::OP_VERIFY) => Token::Verify,
+            script::Instruction::Op(opcodes::All::OP_HASH160) => Token::Hash160,
+            script::Instruction::Op(opcodes::All::OP_RIPEMD160) => Token::Ripemd160,
+            script::Instruction::Op(opcodes::All::OP_SHA256) => Token::Sha256,
+            script::Instruction::Op(opcodes::All::OP_HASH256) => Token::Hash256,
+            script::Instruction::PushBytes(bytes) => {
+                match bytes.len() {
+                    // Every hash or key push is short enough to be encoded by its length
+                    // alone; any other encoding, with a PUSHDATA opcode, is not minimal
+                    20 | 32 | 33 | 65 if self.script[start] as usize != bytes.len() => {
+                        if !self.allow_nonminimal {
+                            return Err(Error::InvalidPush(bytes.to_owned()));
+                        }
+                        self.nonminimal.push(start);
+                    }
+                    _ => {}
+                }
+                match bytes.len() {
+                    20 => Token::Hash160Hash(Hash160::from(bytes)),
+                    32 => Token::Sha256Hash(sha256::Hash::from_slice(bytes)?),
+                    33 => Token::Pubkey(secp256k1::PublicKey::from_slice(&self.secp, bytes).map_err(Error::BadPubkey)?),
+                    65 if self.context.allows_uncompressed() => {
+                        if bytes[0] != 0x04 {
+                            return Err(Error::InvalidPush(bytes.to_owned()));
+                        }
+                        Token::UncompressedPubkey(secp256k1::PublicKey::from_slice(&self.secp, bytes).map_err(Error::BadPubkey)?)
+                    }
+                    _ => {
+                        let n = match scriptnum::decode(bytes, 4) {
+                            Some(n) if n >= 0 => n,
+                            _ => return Err(Error::InvalidPush(bytes.to_owned())),
+                        };
+                        // check minimality of the number, and of its push
+                        if self.script[start..self.offset] != scriptnum::push(script::Builder::new(), n).into_script()[..] {
+                            if !self.allow_nonminimal {
+                                return Err(Error::InvalidPush(bytes.to_owned()));
+                            }
+                            self.nonminimal.push(start);
+                        }
+                        Token::Number(n as u32)
+                    }
+                }
+            }
+            script::Instruction::Op(opcodes::All::OP_PUSHBYTES_0) => Token::Number(0),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_1) => Token::Number(1),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_2) => Token::Number(2),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_3) => Token::Number(3),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_4) => Token::Number(4),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_5) => Token::Number(5),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_6) => Token::Number(6),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_7) => Token::Number(7),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_8) => Token::Number(8),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_9) => Token::Number(9),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_10) => Token::Number(10),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_11) => Token::Number(11),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_12) => Token::Number(12),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_13) => Token::Number(13),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_14) => Token::Number(14),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_15) => Token::Number(15),
+            script::Instruction::Op(opcodes::All::OP_PUSHNUM_16) => Token::Number(16),
+            script::Instruction::Op(op) if self.allow_reserved_nops && is_reserved_nop(op) => Token::ReservedNop(op),
+            script::Instruction::Op(op) => return Err(Error::InvalidOpcode(op, start)),
+        })
+    }
+}
+
+impl<'a> Iterator for TokenStream<'a> {
+    type Item = Result<(usize, Token), Error>;
+
+    fn next(&mut self) -> Option<Result<(usize, Token), Error>> {
+        if self.done {
+            return None;
+        }
+        let next = match self.pending.take() {
+            Some(next) => next,
+            None => self.lex_one()?,
+        };
+        let ret = match next {
+            // In a tapscript, a 32-byte push just before a signature check is an x-only
+            // key rather than a hash
+            Ok((offset, Token::Sha256Hash(hash))) if self.context == ScriptContext::Taproot => {
+                self.pending = self.lex_one();
+                match self.pending {
+                    Some(Ok((_, Token::CheckSig))) | Some(Ok((_, Token::CheckSigVerify))) |
+                    Some(Ok((_, Token::CheckSigAdd))) => {
+                        let mut key = [0x02; 33];
+                        key[1..].copy_from_slice(&hash[..]);
+                        secp256k1::PublicKey::from_slice(&self.secp, &key)
+                            .map(|pk| (offset, Token::XOnlyPubkey(pk)))
+                            .map_err(Error::BadPubkey)
+                    }
+                    _ => Ok((offset, Token::Sha256Hash(hash))),
+                }
+            }
+            ret => ret,
+        };
+        if ret.is_err() {
+            self.done = true;
+        }
+        Some(ret)
+    }
+}
+
+macro_rules! into_fn(
+    (E) => (AstElem::into_e);
+    (W) => (AstElem::into_w);
+    (V) => (AstElem::into_v);
+    (F) => (AstElem::into_f);
+    (T) => (AstElem::into_t);
+);
+
+macro_rules! is_fn(
+    (E) => (AstElem::is_e);
+    (W) => (AstElem::is_w);
+    (V) => (AstElem::is_v);
+    (F) => (AstElem::is_f);
+    (T) => (AstElem::is_t);
+);
+
+macro_rules! parse_fn(
+    (E) => (parse_e_subexpression);
+    (F) => (parse_f_subexpression);
+    ($($other:tt)*) => (parse_subexpression);
+);
+
+macro_rules! expect_token(
+    ($tokens:expr, $expected:pat => $b:block) => ({
+        match $tokens.next() {
+            Some($expected) => $b,
+            Some(tok) => return Err($tokens.error(tok.to_string(), stringify!($expected))),
+            None => return Err(Error::UnexpectedStart),
+        }
+    });
+    ($tokens:expr, $expected:pat) => (expect_token!($tokens, $expected => {}));
+);
+
+macro_rules! parse_tree(
+    // Tree
+    (
+        // list of tokens passed into macro scope
+        $tokens:expr,
+        // list of expected tokens
+        $($expected:pat $(, $more:pat)* => { $($sub:tt)* }),*
+        // list of expected subexpressions. The whole thing is surrounded
+        // in a $(..)* because it's optional. But it should only be used once.
+        $(
+        #subexpression $($parse_expected:tt: $name:ident $(, $parse_more:pat)* => { $($parse_sub:tt)* }),*
+        )*
+    ) => ({
+        match $tokens.next() {
+            $(Some($expected) => {
+                $(expect_token!($tokens, $more);)*
+                parse_tree!($tokens, $($sub)*)
+            },)*
+            Some(tok) => {
+                #[allow(unused_assignments)]
+                #[allow(unused_mut)]
+                let mut ret: Result<Box<dyn AstElem>, Error> = Err($tokens.error(
+                    tok.to_string(),
+                    concat!(
+                        "one of"
+                        $(, " ", stringify!($expected))*
+                        $($(, " ", stringify!($parse_expected), "-expression")*)*
+                    ),
+                ));
+                $(
+                $tokens.un_next(tok);
+                let subexpr = parse_fn!($($parse_expected)*)($tokens)?;
+                ret =
+                $(if is_fn!($parse_expected)(&*subexpr) {
+                    let $name = into_fn!($parse_expected)(subexpr).unwrap();
+                    $(expect_token!($tokens, $parse_more);)*
+                    parse_tree!($tokens, $($parse_sub)*)
+                } else)* {
+                    Err($tokens.error(
+                        subexpr.to_string(),
+                        concat!("one of" $(, " ", stringify!($parse_expected), "-expression")*),
+                    ))
+                };
+                )*
+                ret
+            }
+            None => return Err(Error::UnexpectedStart),
+        }
+    });
+    // Not a tree; must be a block
+    ($tokens:expr, $($b:tt)*) => ({ $($b)* });
+);
+
+
+/// Reads the subexpressions of a threshold, whose `<k> EQUAL(VERIFY)` has
+/// already been read: `W`s each followed by an `ADD`, then the leading `E`
+fn parse_threshold_subs(tokens: &mut TokenIter) -> Result<(Arc<E>, Vec<W>), Error> {
+    let mut ws = vec![];
+    loop {
+        match tokens.next() {
+            Some(Token::Add) => {
+                let next_sub = parse_subexpression(tokens)?;
+                if next_sub.is_w() {
+                    ws.push((*next_sub.into_w().unwrap()).clone());
+                } else {
+                    return Err(tokens.error(next_sub.to_string(), "W-expression"));
+                }
+            }
+            Some(x) => {
+                tokens.un_next(x);
+                let next_sub = parse_e_subexpression(tokens)?;
+                if next_sub.is_e() {
+                    // Subexpressions were read from the end of the script
+                    ws.reverse();
+                    return Ok((next_sub.into_e().unwrap(), ws));
+                } else {
+                    return Err(tokens.error(next_sub.to_string(), "E-expression"));
+                }
+            }
+            None => return Err(Error::UnexpectedStart),
+        }
+    }
+}
+
+/// Reads the keys of a `CHECKSIGADD` multisig, whose `<k> NUMEQUAL(VERIFY)` has
+/// already been read
+fn parse_checksigadd_keys(tokens: &mut TokenIter) -> Result<Vec<secp256k1::PublicKey>, Error> {
+    let mut pks = vec![];
+    loop {
+        match tokens.next() {
+            Some(Token::CheckSigAdd) => {
+                pks.push(expect_token!(tokens, Token::XOnlyPubkey(pk) => { pk }));
+            }
+            Some(Token::CheckSig) => {
+                pks.push(expect_token!(tokens, Token::XOnlyPubkey(pk) => { pk }));
+                break;
+            }
+            Some(tok) => return Err(tokens.error(tok.to_string(), "CHECKSIG or CHECKSIGADD")),
+            None => return Err(Error::UnexpectedStart),
+        }
+    }
+    pks.reverse();
+    Ok(pks)
+}
+
+/// Reads a `<msg> <pk> CHECKSIGFROMSTACK(VERIFY)` check
+#[cfg(feature = "elements")]
+fn parse_checksigfromstack(tokens: &mut TokenIter) -> Result<Box<dyn AstElem>, Error> {
+    let verify = match tokens.next() {
+        Some(Token::CheckSigFromStack) => false,
+        Some(Token::CheckSigFromStackVerify) => true,
+        Some(tok) => return Err(tokens.error(tok.to_string(), "CHECKSIGFROMSTACK or CHECKSIGFROMSTACKVERIFY")),
+        None => return Err(Error::UnexpectedStart),
+    };
+    let pk = expect_token!(tokens, Token::Pubkey(pk) => { pk });
+    let msg = expect_token!(tokens, Token::Sha256Hash(hash) => { hash });
+    if verify {
+        Ok(Box::new(V::CheckSigFromStack(pk, msg)))
+    } else {
+        Ok(Box::new(E::CheckSigFromStack(pk, msg)))
+    }
+}
+
+/// Parse a subexpression
+fn parse_subexpression(tokens: &mut TokenIter) -> Result<Box<dyn AstElem>, Error> {
+    parse_subexpression_depth(tokens, true)
+}
+
+/// Parse a subexpression in a position where only an E-expression is
+/// allowed. Such a subexpression is always the leftmost part of its parent,
+/// so any V-expression preceding it belongs to an enclosing `And` and must
+/// not be absorbed into it.
+fn parse_e_subexpression(tokens: &mut TokenIter) -> Result<Box<dyn AstElem>, Error> {
+    parse_subexpression_depth(tokens, false)
+}
+
+/// Parse a subexpression in a position where only an F-expression is
+/// allowed. A preceding V-expression is folded into a `T::And` by default,
+/// which is turned back into an `F::And` here.
+fn parse_f_subexpression(tokens: &mut TokenIter) -> Result<Box<dyn AstElem>, Error> {
+    let sub = parse_subexpression(tokens)?;
+    if sub.is_f() || !sub.is_t() {
+        return Ok(sub);
+    }
+    let texpr = sub.into_t().unwrap();
+    match t_into_f(&texpr) {
+        Some(f) => Ok(Box::new(f)),
+        None => Ok(Box::new((*texpr).clone())),
+    }
+}
+
+/// Casts a T-expression consisting of V-expressions followed by an F-expression
+/// to the corresponding F-expression, if it is one
+fn t_into_f(texpr: &T) -> Option<F> {
+    match *texpr {
+        T::CastF(ref f) => Some((**f).clone()),
+        T::And(ref left, ref right) => t_into_f(right).map(|right| F::And(left.clone(), Arc::new(right))),
+        _ => None,
+    }
+}
+
+/// Casts a V-expression followed by `1` to the corresponding F-expression, if it
+/// has such a cast. A `V::And` casts by casting its right side, since the parser
+/// folds leading V-expressions into it.
+fn v_into_f(vexpr: &V) -> Option<F> {
+    match *vexpr {
+        V::CheckSig(pk) => Some(F::CheckSig(pk)),
+        V::CheckSigHash(hash) => Some(F::CheckSigHash(hash)),
+        V::CheckMultiSig(k, ref keys) => Some(F::CheckMultiSig(k, keys.clone())),
+        V::CheckSigAdd(k, ref keys) => Some(F::CheckSigAdd(k, keys.clone())),
+        #[cfg(feature = "elements")]
+        V::CheckSigFromStack(pk, msg) => Some(F::CheckSigFromStack(pk, msg)),
+        V::HashEqual(hash) => Some(F::HashEqual(hash)),
+        V::Hash160Equal(hash) => Some(F::Hash160Equal(hash)),
+        V::Ripemd160Equal(hash) => Some(F::Ripemd160Equal(hash)),
+        V::Hash256Equal(hash) => Some(F::Hash256Equal(hash)),
+        V::Threshold(k, ref e, ref ws) => Some(F::Threshold(k, e.clone(), ws.clone())),
+        V::ParallelOr(ref left, ref right) => Some(F::ParallelOr(left.clone(), right.clone())),
+        V::SwitchOr(ref left, ref right) => Some(F::SwitchOrV(left.clone(), right.clone())),
+        V::CascadeOr(ref left, ref right) => Some(F::CascadeOrV(left.clone(), right.clone())),
+        V::And(ref left, ref right) => v_into_f(right).map(|right| F::And(left.clone(), Arc::new(right))),
+        _ => None,
+    }
+}
+
+/// Parse a subexpression, failing rather than recursing past the depth limit
+fn parse_subexpression_depth(tokens: &mut TokenIter, extend: bool) -> Result<Box<dyn AstElem>, Error> {
+    if tokens.depth >= tokens.max_depth {
+        return Err(Error::MaxRecursionDepthExceeded(tokens.max_depth));
+    }
+    tokens.depth += 1;
+    let ret = parse_subexpression_inner(tokens, extend);
+    tokens.depth -= 1;
+    ret
+}
+
+/// Parse a subexpression that is -not- a wexpr (wexpr is special-cased
+/// to avoid splitting expr into expr0 and exprn in the AST structure).
+/// If `extend` is set, a preceding V-expression is folded into an `And`.
+fn parse_subexpression_inner(tokens: &mut TokenIter, extend: bool) -> Result<Box<dyn AstElem>, Error> {
+    if let Some(tok) = tokens.next() {
+        tokens.un_next(tok);
+    }
+    #[cfg(feature = "elements")]
+    match tokens.peek() {
+        Some(&Token::CheckSigFromStack) | Some(&Token::CheckSigFromStackVerify) => {
+            let ret = parse_checksigfromstack(tokens);
+            return fold_and(tokens, ret, extend);
+        }
+        _ => {}
+    }
+    let ret: Result<Box<dyn AstElem>, Error> = parse_tree!(tokens,
+        Token::BoolAnd => {
+            #subexpression
+            W: wexpr => {
+                #subexpression
+                E: expr => {
+                    Ok(Box::new(E::ParallelAnd(expr, wexpr)))
+                }
+            }
+        },
+        Token::BoolOr => {
+            #subexpression
+            W: wexpr => {
+                #subexpression
+                E: expr => {
+                    Ok(Box::new(E::ParallelOr(expr, wexpr)))
+                }
+            }
+        },
+        Token::Equal => {
+            Token::Sha256Hash(hash) => {
+                Token::Sha256, Token::EqualVerify, Token::Number(32), Token::Size => {
+                    Ok(Box::new(T::HashEqual(hash)))
+                },
+                Token::Hash256, Token::EqualVerify, Token::Number(32), Token::Size => {{
+                    Ok(Box::new(T::Hash256Equal(Sha256dHash::from(&hash[..]))))
+                }}
+            },
+            Token::Hash160Hash(hash) => {
+                Token::Hash160, Token::EqualVerify, Token::Number(32), Token::Size => {
+                    Ok(Box::new(T::Hash160Equal(hash)))
+                },
+                Token::Ripemd160, Token::EqualVerify, Token::Number(32), Token::Size => {{
+                    Ok(Box::new(T::Ripemd160Equal(Ripemd160Hash::from_slice(&hash[..]).expect("20-byte hash"))))
+                }}
+            },
+            Token::Number(k) => {{
+                let (e, ws) = parse_threshold_subs(tokens)?;
+                Ok(Box::new(E::Threshold(k as usize, e, ws)))
+            }}
+        },
+        Token::EqualVerify => {
+            Token::Sha256Hash(hash) => {
+                Token::Sha256, Token::EqualVerify, Token::Number(32), Token::Size => {
+                    Ok(Box::new(V::HashEqual(hash)))
+                },
+                Token::Hash256, Token::EqualVerify, Token::Number(32), Token::Size => {{
+                    Ok(Box::new(V::Hash256Equal(Sha256dHash::from(&hash[..]))))
+                }}
+            },
+            Token::Hash160Hash(hash) => {
+                Token::Hash160, Token::EqualVerify, Token::Number(32), Token::Size => {
+                    Ok(Box::new(V::Hash160Equal(hash)))
+                },
+                Token::Ripemd160, Token::EqualVerify, Token::Number(32), Token::Size => {{
+                    Ok(Box::new(V::Ripemd160Equal(Ripemd160Hash::from_slice(&hash[..]).expect("20-byte hash"))))
+                }}
+            },
+            Token::Number(k) => {{
+                let (e, ws) = parse_threshold_subs(tokens)?;
+                Ok(Box::new(V::Threshold(k as usize, e, ws)))
+            }}
+        },
+        Token::CheckSig => {
+            Token::EqualVerify => {
+                Token::Hash160Hash(hash), Token::Hash160, Token::Dup => {
+                    Ok(Box::new(E::CheckSigHash(hash)))
+                }
+            },
+            Token::Pubkey(pk) => {{
+                match tokens.next() {
+                    Some(Token::Swap) => Ok(Box::new(W::CheckSig(pk))),
+                    Some(x) => {
+                        tokens.un_next(x);
+                        Ok(Box::new(E::CheckSig(pk)))
+                    }
+                    None => Ok(Box::new(E::CheckSig(pk))),
+                }
+            }}
+        },
+        Token::CheckSigVerify => {
+            Token::EqualVerify => {
+                Token::Hash160Hash(hash), Token::Hash160, Token::Dup => {
+                    Ok(Box::new(V::CheckSigHash(hash)))
+                }
+            },
+            Token::Pubkey(pk) => {
+                Ok(Box::new(V::CheckSig(pk)))
+            }
+        },
+        Token::CheckMultiSig => {{
+            let n = expect_token!(tokens, Token::Number(n) => { n });
+            let mut pks = vec![];
+            for _ in 0..n {
+                pks.push(expect_token!(tokens, Token::Pubkey(pk) => { pk }));
+            }
+            pks.reverse();
+            let k = expect_token!(tokens, Token::Number(n) => { n });
+            Ok(Box::new(E::CheckMultiSig(k as usize, pks.into())))
+        }},
+        Token::CheckMultiSigVerify => {{
+            let n = expect_token!(tokens, Token::Number(n) => { n });
+            let mut pks = vec![];
+            for _ in 0..n {
+                pks.push(expect_token!(tokens, Token::Pubkey(pk) => { pk }));
+            }
+            pks.reverse();
+            let k = expect_token!(tokens, Token::Number(n) => { n });
+            Ok(Box::new(V::CheckMultiSig(k as usize, pks.into())))
+        }},
+        Token::NumEqual => {{
+            let k = expect_token!(tokens, Token::Number(n) => { n });
+            Ok(Box::new(E::CheckSigAdd(k as usize, parse_checksigadd_keys(tokens)?.into())))
+        }},
+        Token::NumEqualVerify => {{
+            let k = expect_token!(tokens, Token::Number(n) => { n });
+            Ok(Box::new(V::CheckSigAdd(k as usize, parse_checksigadd_keys(tokens)?.into())))
+        }},
+        Token::CheckSequenceVerify => {
+            Token::Number(n) => {
+                Ok(Box::new(F::Csv(n)))
+            }
+        },
+        Token::FromAltStack => {
+            #subexpression
+            E: expr, Token::ToAltStack => {
+                Ok(Box::new(W::CastE(expr)))
+            }
+        },
+        Token::Drop, Token::CheckSequenceVerify => {
+            Token::Number(n) => {
+                Ok(Box::new(V::Csv(n)))
+            }
+        },
+        Token::EndIf => {
+            Token::Number(0), Token::Else => {
+                #subexpression
+                F: right => {
+                    Token::If => {
+                        Token::EqualVerify, Token::Size => {{
+                            match *right {
+                                F::Csv(n) => {{
+                                    match tokens.next() {
+                                        Some(Token::Swap) => Ok(Box::new(W::Csv(n))),
+                                        Some(x) => {
+                                            tokens.un_next(x);
+                                            Ok(Box::new(E::CastF(right)))
+                                        }
+                                        None => Ok(Box::new(E::CastF(right)))
+                                    }
+                                }}
+                                F::And(..) | F::SwitchOr(..) |
+                                F::SwitchOrV(..) | F::CascadeOr(..) => {
+                                    Ok(Box::new(E::CastF(right)))
+                                }
+                                _ => Err(tokens.error(right.to_string(), "F-expression which can be cast to E")),
+                            }
+                        }}
+                        #subexpression
+                        E: left => {
+                            Ok(Box::new(E::CascadeAnd(left, right)))
+                        }
+                    }
+                }
+            }
+            #subexpression
+            E: right => {
+                Token::NotIf, Token::IfDup => {
+                    #subexpression
+                    E: left => {
+                        Ok(Box::new(E::CascadeOr(left, right)))
+                    }
+                },
+                Token::Else => {
+                    #subexpression
+                    T: left, Token::If, Token::EqualVerify, Token::Size => {
+                        Ok(Box::new(T::SwitchOr(left, Arc::new(T::CastE(right)))))
+                    }
+                }
+            },
+            F: right => {
+                Token::NotIf, Token::IfDup => {
+                    #subexpression
+                    E: left => {
+                        Ok(Box::new(F::CascadeOr(left, right)))
+                    }
+                },
+                Token::If, Token::Size => {{
+                    match *right {
+                        F::CheckSigHash(hash) => {
+                            Ok(Box::new(E::CheckSigHashF(hash)))
+                        }
+                        F::CheckMultiSig(k, ref pks) => {
+                            Ok(Box::new(E::CheckMultiSigF(k, pks.clone())))
+                        }
+                        F::HashEqual(hash) => {
+                            match tokens.next() {
+                                Some(Token::Swap) => Ok(Box::new(W::HashEqual(hash))),
+                                Some(x) => {
+                                    tokens.un_next(x);
+                                    Ok(Box::new(E::HashEqual(hash)))
+                                }
+                                None => Ok(Box::new(E::HashEqual(hash))),
+                            }
+                        }
+                        F::Hash160Equal(hash) => {
+                            match tokens.next() {
+                                Some(Token::Swap) => Ok(Box::new(W::Hash160Equal(hash))),
+                                Some(x) => {
+                                    tokens.un_next(x);
+                                    Ok(Box::new(E::Hash160Equal(hash)))
+                                }
+                                None => Ok(Box::new(E::Hash160Equal(hash))),
+                            }
+                        }
+                        F::Ripemd160Equal(hash) => {
+                            match tokens.next() {
+                                Some(Token::Swap) => Ok(Box::new(W::Ripemd160Equal(hash))),
+                                Some(x) => {
+                                    tokens.un_next(x);
+                                    Ok(Box::new(E::Ripemd160Equal(hash)))
+                                }
+                                None => Ok(Box::new(E::Ripemd160Equal(hash))),
+                            }
+                        }
+                        F::Hash256Equal(hash) => {
+                            match tokens.next() {
+                                Some(Token::Swap) => Ok(Box::new(W::Hash256Equal(hash))),
+                                Some(x) => {
+                                    tokens.un_next(x);
+                                    Ok(Box::new(E::Hash256Equal(hash)))
+                                }
+                                None => Ok(Box::new(E::Hash256Equal(hash))),
+                            }
+                        }
+                        ref x => Err(tokens.error(x.to_string(), "F-expression which can be cast to E")),
+                    }
+                }},
+                Token::Else => {
+                    #subexpression
+                    F: left, Token::If, Token::EqualVerify, Token::Size => {
+                        Ok(Box::new(F::SwitchOr(left, right)))
+                    },
+                    T: left, Token::If, Token::EqualVerify, Token::Size => {
+                        Ok(Box::new(T::SwitchOr(left, Arc::new(T::CastF(right)))))
+                    }
+                }
+            },
+            V: right => {
+                Token::Else => {
+                    #subexpression
+                    V: left, Token::If, Token::EqualVerify, Token::Size => {
+                        Ok(Box::new(V::SwitchOr(left, right)))
+                    }
+                },
+                Token::NotIf => {
+                    #subexpression
+                    E: left => {
+                        Ok(Box::new(V::CascadeOr(left, right)))
+                    }
+                }
+            },
+            T: right => {
+                Token::Else => {
+                    #subexpression
+                    T: left, Token::If, Token::EqualVerify, Token::Size => {
+                        Ok(Box::new(T::SwitchOr(left, right)))
+                    }
+                },
+                Token::NotIf, Token::IfDup => {
+                    #subexpression
+                    E: left => {
+                        Ok(Box::new(T::CascadeOr(left, right)))
+                    }
+                }
+            }
+        },
+        Token::Verify => { 
+            Token::EndIf => {
+                #subexpression
+                T: right, Token::Else => {
+                    #subexpression
+                    T: left, Token::If, Token::EqualVerify, Token::Size => {
+                        Ok(Box::new(V::SwitchOrT(left, right)))
+                    }
+                }
+            },
+            Token::BoolOr => {
+                #subexpression
+                W: wexpr => {
+                    #subexpression
+                    E: expr => {
+                        Ok(Box::new(V::ParallelOr(expr, wexpr)))
+                    }
+                }
+            }
+        },
+        Token::Number(1) => {
+            #subexpression
+            V: vexpr => {{
+                match v_into_f(&vexpr) {
+                    Some(f) => Ok(Box::new(f)),
+                    None => Err(tokens.error(vexpr.to_string(), "V-expression which can be cast to F")),
+                }
+            }}
+        }
+    );
+
+    fold_and(tokens, ret, extend)
+}
+
+/// If `extend` is set, folds any V-expression preceding the just-parsed
+/// subexpression `ret` into an `And` with it
+fn fold_and(
+    tokens: &mut TokenIter,
+    ret: Result<Box<dyn AstElem>, Error>,
+    extend: bool,
+) -> Result<Box<dyn AstElem>, Error> {
+    if let Ok(ret) = ret {
+        // vexpr [tfv]expr AND
+        if extend && (ret.is_t() || ret.is_f() || ret.is_v()) {
+            match tokens.peek() {
+                None | Some(&Token::If) | Some(&Token::NotIf) | Some(&Token::Else) => Ok(ret),
+                _ => {
+                    let left = parse_subexpression(tokens)?;
+                    if !left.is_v() {
+                        return Err(tokens.error(left.to_string(), "V-expression"));
+                    }
+                    let left = left.into_v().unwrap();
+
+                    if ret.is_t() {
+                        let right = ret.into_t().unwrap();
+                        Ok(Box::new(T::And(left, right)))
+                    } else if ret.is_f() {
+                        let right = ret.into_f().unwrap();
+                        Ok(Box::new(F::And(left, right)))
+                    } else if ret.is_v() {
+                        let right = ret.into_v().unwrap();
+                        Ok(Box::new(V::And(left, right)))
+                    } else {
+                        unreachable!()
+                    }
+                }
+            }
+        } else {
+            Ok(ret)
+        }
+    } else {
+        ret
+    }
+}
+
+impl fmt::Display for E {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let script = self.serialize(script::Builder::new()).into_script();
+        fmt::Display::fmt(&script, f)
+    }
+}
+
+impl AstElem for E {
+    fn into_e(self: Box<E>) -> Result<Arc<E>, Error> { Ok(Arc::from(self)) }
+    fn into_t(self: Box<E>) -> Result<Arc<T>, Error> { Ok(Arc::new(T::CastE(Arc::from(self)))) }
+    fn is_e(&self) -> bool { true }
+    fn is_t(&self) -> bool { true }
+
+    fn serialize_pieces<'a>(&'a self, mut builder: script::Builder, pieces: &mut Vec<Piece<'a>>) -> script::Builder {
+        match *self {
+            E::CheckSig(ref pk) | E::CheckSigAggregate(ref pk, _) => {
+                builder.push_slice(&pk.serialize()[..])
+                       .push_opcode(opcodes::All::OP_CHECKSIG)
+            }
+            E::CheckSigHash(ref hash) => {
+                builder.push_opcode(opcodes::All::OP_DUP)
+                       .push_opcode(opcodes::All::OP_HASH160)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_CHECKSIG)
+            }
+            E::CheckSigHashF(ref hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_opcode(opcodes::All::OP_IF)
+                       .push_opcode(opcodes::All::OP_DUP)
+                       .push_opcode(opcodes::All::OP_HASH160)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
+                       .push_int(1)
+                       .push_opcode(opcodes::All::OP_ENDIF)
+            }
+            E::CheckMultiSig(k, ref pks) => {
+                builder = builder.push_int(k as i64);
+                for pk in pks.iter() {
+                    builder = builder.push_slice(&pk.serialize()[..]);
+                }
+                builder.push_int(pks.len() as i64)
+                       .push_opcode(opcodes::All::OP_CHECKMULTISIG)
+            }
+            E::CheckMultiSigF(k, ref pks) => {
+                builder = builder.push_opcode(opcodes::All::OP_SIZE)
+                                 .push_opcode(opcodes::All::OP_IF)
+                                 .push_int(k as i64);
+                for pk in pks.iter() {
+                    builder = builder.push_slice(&pk.serialize()[..]);
+                }
+                builder.push_int(pks.len() as i64)
+                       .push_opcode(opcodes::All::OP_CHECKMULTISIGVERIFY)
+                       .push_int(1)
+                       .push_opcode(opcodes::All::OP_ENDIF)
+            }
+            #[cfg(feature = "elements")]
+            E::CheckSigFromStack(pk, msg) => {
+                builder.push_slice(&msg[..])
+                       .push_slice(&pk.serialize()[..])
+                       .push_opcode(OP_CHECKSIGFROMSTACK)
+            }
+            E::CheckSigAdd(k, ref pks) => {
+                serialize_checksigadd_keys(builder, pks)
+                    .push_int(k as i64)
+                    .push_opcode(opcodes::All::OP_NUMEQUAL)
+            }
+            E::HashEqual(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_opcode(opcodes::All::OP_IF)
+                       .push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_SHA256)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_int(1)
+                       .push_opcode(opcodes::All::OP_ENDIF)
+            }
+            E::Hash160Equal(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_opcode(opcodes::All::OP_IF)
+                       .push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_HASH160)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_int(1)
+                       .push_opcode(opcodes::All::OP_ENDIF)
+            }
+            E::Ripemd160Equal(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_opcode(opcodes::All::OP_IF)
+                       .push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_RIPEMD160)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_int(1)
+                       .push_opcode(opcodes::All::OP_ENDIF)
+            }
+            E::Hash256Equal(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_opcode(opcodes::All::OP_IF)
+                       .push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_HASH256)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_int(1)
+                       .push_opcode(opcodes::All::OP_ENDIF)
+            }
+            E::Threshold(k, ref e, ref ws) => {
+                builder = defer(builder, &**e, pieces);
+                for w in ws {
+                    builder = defer(builder, w, pieces).push_opcode(opcodes::All::OP_ADD);
+                }
+                builder.push_int(k as i64)
+                       .push_opcode(opcodes::All::OP_EQUAL)
+            }
+            E::ParallelAnd(ref left, ref right) => {
+                builder = defer(builder, &**left, pieces);
+                builder = defer(builder, &**right, pieces);
+                builder.push_opcode(opcodes::All::OP_BOOLAND)
+            }
+            E::CascadeAnd(ref left, ref right) => {
+                builder = defer(builder, &**left, pieces);
+                builder = builder.push_opcode(opcodes::All::OP_IF);
+                builder = defer(builder, &**right, pieces);
+                builder.push_opcode(opcodes::All::OP_ELSE)
+                       .push_int(0)
+                       .push_opcode(opcodes::All::OP_ENDIF)
+            }
+            E::CascadeOr(ref left, ref right) => {
+                builder = defer(builder, &**left, pieces);
+                builder = builder.push_opcode(opcodes::All::OP_IFDUP)
+                                 .push_opcode(opcodes::All::OP_NOTIF);
+                builder = defer(builder, &**right, pieces);
+                builder.push_opcode(opcodes::All::OP_ENDIF)
+            }
+            E::ParallelOr(ref left, ref right) => {
+                builder = defer(builder, &**left, pieces);
+                builder = defer(builder, &**right, pieces);
+                builder.push_opcode(opcodes::All::OP_BOOLOR)
+            }
+            E::CastF(ref fexpr) => {
+                builder = builder.push_opcode(opcodes::All::OP_SIZE)
+                                 .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                                 .push_opcode(opcodes::All::OP_IF);
+                builder = defer(builder, &**fexpr, pieces);
+                builder.push_opcode(opcodes::All::OP_ELSE)
+                       .push_int(0)
+                       .push_opcode(opcodes::All::OP_ENDIF)
+            }
+        }
+    }
+
+    fn satisfy(&self, satisfier: &dyn Satisfier, strategy: Strategy) -> Result<Vec<Vec<u8>>, Error> {
+        match *self {
+            E::CheckSig(ref pk) | E::CheckSigAggregate(ref pk, _) => satisfy_checksig(pk, satisfier),
+            E::CheckSigHash(ref hash) | E::CheckSigHashF(ref hash) => satisfy_checksighash(hash, satisfier),
+            E::CheckMultiSig(k, ref keys) | E::CheckMultiSigF(k, ref keys) => satisfy_checkmultisig(k, keys, satisfier, strategy),
+            E::CheckSigAdd(k, ref keys) => satisfy_checksigadd(k, keys, satisfier, strategy),
+            #[cfg(feature = "elements")]
+            E::CheckSigFromStack(ref pk, ref msg) => satisfy_checksigfromstack(pk, msg, satisfier),
+            E::HashEqual(ref hash) => satisfy_hashequal(hash, satisfier),
+            E::Hash160Equal(ref hash) => satisfy_hash160equal(hash, satisfier),
+            E::Ripemd160Equal(ref hash) => satisfy_ripemd160equal(hash, satisfier),
+            E::Hash256Equal(ref hash) => satisfy_hash256equal(hash, satisfier),
+            E::Threshold(k, ref sube, ref subw) => satisfy_threshold(k, sube, subw, satisfier, strategy),
+            E::ParallelAnd(ref left, ref right) => {
+                let lsat = left.satisfy(satisfier, strategy)?;
+                let mut ret = right.satisfy(satisfier, strategy)?;
+                ret.extend(lsat);
+                Ok(ret)
+            }
+            E::CascadeAnd(ref left, ref right) => {
+                let lsat = left.satisfy(satisfier, strategy)?;
+                let mut ret = right.satisfy(satisfier, strategy)?;
+                ret.extend(lsat);
+                Ok(ret)
+            }
+            E::ParallelOr(ref left, ref right) => satisfy_parallel_or(left, right, satisfier, strategy),
+            E::CascadeOr(ref left, ref right) => satisfy_cascade_or(left, right, satisfier, strategy),
+            E::CastF(ref f) => {
+                let mut fsat = f.satisfy(satisfier, strategy)?;
+                fsat.push(vec![1]);
+                Ok(fsat)
+            }
+        }
+    }
+
+    fn required_keys(&self) -> Vec<secp256k1::PublicKey> {
+        match *self {
+            E::CheckSig(pk) => vec![pk],
+            E::CheckSigAggregate(_, ref keys) => keys.to_vec(),
+            E::CheckSigHash(..) | E::CheckSigHashF(..) |
+            E::HashEqual(..) | E::Hash160Equal(..) |
+            E::Ripemd160Equal(..) | E::Hash256Equal(..) => vec![],
+            E::CheckMultiSig(_, ref keys) | E::CheckMultiSigF(_, ref keys) |
+            E::CheckSigAdd(_, ref keys) => keys.to_vec(),
+            #[cfg(feature = "elements")]
+            E::CheckSigFromStack(pk, _) => vec![pk],
+            E::Threshold(_, ref sube, ref subw) => {
+                let mut ret = sube.required_keys();
+                for sub in subw {
+                    ret.extend(sub.required_keys());
+                }
+                ret
+            }
+            E::ParallelAnd(ref left, ref right) => {
+                let mut ret = left.required_keys();
+                ret.extend(right.required_keys());
+                ret
+            }
+            E::CascadeAnd(ref left, ref right) => {
+                let mut ret = left.required_keys();
+                ret.extend(right.required_keys());
+                ret
+            }
+            E::ParallelOr(ref left, ref right) => {
+                let mut ret = left.required_keys();
+                ret.extend(right.required_keys());
+                ret
+            }
+            E::CascadeOr(ref left, ref right) => {
+                let mut ret = left.required_keys();
+                ret.extend(right.required_keys());
+                ret
+            }
+            E::CastF(ref f) => f.required_keys(),
+        }
+    }
+
+    fn expr_type(&self) -> ExprType { ExprType::E }
+
+    fn fragment(&self) -> (Fragment, Vec<&dyn AstElem>) {
+        match *self {
+            E::CheckSig(pk) => (Fragment::CheckSig(pk), vec![]),
+            E::CheckSigAggregate(pk, ref keys) => (Fragment::CheckSigAggregate(pk, keys.to_vec()), vec![]),
+            E::CheckSigHash(hash) | E::CheckSigHashF(hash) => (Fragment::CheckSigHash(hash), vec![]),
+            E::CheckMultiSig(k, ref keys) | E::CheckMultiSigF(k, ref keys) => {
+                (Fragment::CheckMultiSig(k, keys.to_vec()), vec![])
+            }
+            E::CheckSigAdd(k, ref keys) => (Fragment::CheckSigAdd(k, keys.to_vec()), vec![]),
+            #[cfg(feature = "elements")]
+            E::CheckSigFromStack(pk, msg) => (Fragment::CheckSigFromStack(pk, msg), vec![]),
+            E::HashEqual(hash) => (Fragment::HashEqual(hash), vec![]),
+            E::Hash160Equal(hash) => (Fragment::Hash160Equal(hash), vec![]),
+            E::Ripemd160Equal(hash) => (Fragment::Ripemd160Equal(hash), vec![]),
+            E::Hash256Equal(hash) => (Fragment::Hash256Equal(hash), vec![]),
+            E::Threshold(k, ref sube, ref subw) => {
+                let mut subs = vec![&**sube as &dyn AstElem];
+                subs.extend(subw.iter().map(|w| w as &dyn AstElem));
+                (Fragment::Threshold(k), subs)
+            }
+            E::ParallelAnd(ref left, ref right) => (Fragment::ParallelAnd, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            E::CascadeAnd(ref left, ref right) => (Fragment::CascadeAnd, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            E::ParallelOr(ref left, ref right) => (Fragment::ParallelOr, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            E::CascadeOr(ref left, ref right) => (Fragment::CascadeOr, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            E::CastF(ref sub) => (Fragment::Cast, vec![&**sub as &dyn AstElem]),
+        }
+    }
+
+    fn policy(&self) -> Policy {
+        match *self {
+            E::CheckSig(ref pk) => Policy::Key(*pk),
+            E::CheckSigAggregate(_, ref keys) => Policy::multi(keys.len(), keys),
+            E::CheckSigHash(hash) | E::CheckSigHashF(hash) => Policy::KeyHash(hash),
+            E::CheckMultiSig(k, ref keys) | E::CheckMultiSigF(k, ref keys) |
+            E::CheckSigAdd(k, ref keys) => Policy::multi(k, keys),
+            #[cfg(feature = "elements")]
+            E::CheckSigFromStack(pk, msg) => Policy::MessageSig(pk, msg),
+            E::HashEqual(hash) => Policy::Sha256(hash),
+            E::Hash160Equal(hash) => Policy::Hash160(hash),
+            E::Ripemd160Equal(hash) => Policy::Ripemd160(hash),
+            E::Hash256Equal(hash) => Policy::Hash256(hash),
+            E::Threshold(k, ref sube, ref subw) => Policy::threshold(k, sube, subw),
+            E::ParallelAnd(ref left, ref right) => Policy::and(left.policy(), right.policy()),
+            E::CascadeAnd(ref left, ref right) => Policy::and(left.policy(), right.policy()),
+            E::ParallelOr(ref left, ref right) => Policy::or(left.policy(), right.policy()),
+            E::CascadeOr(ref left, ref right) => Policy::or(left.policy(), right.policy()),
+            E::CastF(ref sub) => sub.policy(),
+        }
+    }
+
+    fn malleability(&self, issues: &mut Vec<Malleability>) -> bool {
+        match *self {
+            E::CheckSig(..) | E::CheckSigAggregate(..) | E::CheckSigHash(..) | E::CheckSigHashF(..) => true,
+            E::CheckMultiSig(k, _) | E::CheckMultiSigF(k, _) | E::CheckSigAdd(k, _) => k > 0,
+            // the signature does not commit to the spending transaction, and may well
+            // be public, so does not stop third parties from satisfying the fragment
+            #[cfg(feature = "elements")]
+            E::CheckSigFromStack(..) => false,
+            E::HashEqual(..) | E::Hash160Equal(..) |
+            E::Ripemd160Equal(..) | E::Hash256Equal(..) => false,
+            E::Threshold(k, ref sube, ref subw) => {
+                let mut subs = vec![sube.malleability(issues)];
+                for sub in subw {
+                    subs.push(sub.malleability(issues));
+                }
+                malleability_threshold(k, &subs, self, issues)
+            }
+            E::ParallelAnd(ref left, ref right) => {
+                let left = left.malleability(issues);
+                right.malleability(issues) || left
+            }
+            E::CascadeAnd(ref left, ref right) => {
+                let left = left.malleability(issues);
+                right.malleability(issues) || left
+            }
+            E::ParallelOr(ref left, ref right) => {
+                let left = left.malleability(issues);
+                let right = right.malleability(issues);
+                malleability_or(left, right, self, issues)
+            }
+            E::CascadeOr(ref left, ref right) => {
+                let left = left.malleability(issues);
+                let right = right.malleability(issues);
+                malleability_or(left, right, self, issues)
+            }
+            E::CastF(ref f) => f.malleability(issues),
+        }
+    }
+
+    fn max_satisfaction_items(&self) -> usize {
+        match *self {
+            E::CheckSig(..) | E::CheckSigAggregate(..) => 1,
+            E::CheckSigHash(..) | E::CheckSigHashF(..) => 2,
+            E::CheckMultiSig(k, _) | E::CheckMultiSigF(k, _) => k + 1,
+            E::CheckSigAdd(_, ref keys) => keys.len(),
+            #[cfg(feature = "elements")]
+            E::CheckSigFromStack(..) => 1,
+            E::HashEqual(..) | E::Hash160Equal(..) |
+            E::Ripemd160Equal(..) | E::Hash256Equal(..) => 1,
+            E::Threshold(k, ref sube, ref subw) => {
+                let mut sats = vec![sube.max_satisfaction_items()];
+                let mut dissats = vec![sube.max_dissatisfaction_items()];
+                for sub in subw {
+                    sats.push(sub.max_satisfaction_items());
+                    dissats.push(sub.max_dissatisfaction_items());
+                }
+                max_threshold(k, &sats, &dissats)
+            }
+            E::ParallelAnd(ref left, ref right) => {
+                left.max_satisfaction_items() + right.max_satisfaction_items()
+            }
+            E::CascadeAnd(ref left, ref right) => {
+                left.max_satisfaction_items() + right.max_satisfaction_items()
+            }
+            E::ParallelOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_items() + right.max_dissatisfaction_items(),
+                    left.max_dissatisfaction_items() + right.max_satisfaction_items(),
+                )
+            }
+            E::CascadeOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_items(),
+                    left.max_dissatisfaction_items() + right.max_satisfaction_items(),
+                )
+            }
+            E::CastF(ref f) => f.max_satisfaction_items() + 1,
+        }
+    }
+
+    fn max_satisfaction_size(&self) -> usize {
+        match *self {
+            E::CheckSig(..) | E::CheckSigAggregate(..) => 74,
+            E::CheckSigHash(..) | E::CheckSigHashF(..) => 74 + 34,
+            E::CheckMultiSig(k, _) | E::CheckMultiSigF(k, _) => 1 + 74 * k,
+            E::CheckSigAdd(k, ref keys) => 74 * k + keys.len().saturating_sub(k),
+            #[cfg(feature = "elements")]
+            E::CheckSigFromStack(..) => 73,
+            E::HashEqual(..) | E::Hash160Equal(..) |
+            E::Ripemd160Equal(..) | E::Hash256Equal(..) => 33,
+            E::Threshold(k, ref sube, ref subw) => {
+                let mut sats = vec![sube.max_satisfaction_size()];
+                let mut dissats = vec![sube.max_dissatisfaction_size()];
+                for sub in subw {
+                    sats.push(sub.max_satisfaction_size());
+                    dissats.push(sub.max_dissatisfaction_size());
+                }
+                max_threshold(k, &sats, &dissats)
+            }
+            E::ParallelAnd(ref left, ref right) => {
+                left.max_satisfaction_size() + right.max_satisfaction_size()
+            }
+            E::CascadeAnd(ref left, ref right) => {
+                left.max_satisfaction_size() + right.max_satisfaction_size()
+            }
+            E::ParallelOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_size() + right.max_dissatisfaction_size(),
+                    left.max_dissatisfaction_size() + right.max_satisfaction_size(),
+                )
+            }
+            E::CascadeOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_size(),
+                    left.max_dissatisfaction_size() + right.max_satisfaction_size(),
+                )
+            }
+            E::CastF(ref f) => f.max_satisfaction_size() + 2,
+        }
+    }
+}
+
+impl E {
+    fn dissatisfy(&self, satisfier: &dyn Satisfier) -> Result<Vec<Vec<u8>>, Error> {
+        match *self {
+            E::CheckSig(..) | E::CheckSigAggregate(..) => Ok(vec![vec![]]),
+            E::CheckSigHash(hash) | E::CheckSigHashF(hash) => {
+                if let Some(pk) = satisfier.lookup_pkh(&hash) {
+                    Ok(vec![
+                        vec![],
+                        pk.serialize()[..].to_owned(),
+                    ])
+                } else {
+                    Err(Error::MissingPubkey(hash))
+                }
+            }
+            E::CheckMultiSig(k, _) | E::CheckMultiSigF(k, _) => {
+                Ok(vec![vec![]; k + 1])
+            }
+            E::CheckSigAdd(_, ref keys) => Ok(vec![vec![]; keys.len()]),
+            #[cfg(feature = "elements")]
+            E::CheckSigFromStack(..) => Ok(vec![vec![]]),
+            E::HashEqual(..) | E::Hash160Equal(..) |
+            E::Ripemd160Equal(..) | E::Hash256Equal(..) => Ok(vec![vec![]]),
+            E::Threshold(_, ref sube, ref subw) => {
+                let mut ret = vec![];
+                for sub in subw.iter().rev() {
+                    ret.extend(sub.dissatisfy(satisfier)?);
+                }
+                ret.extend(sube.dissatisfy(satisfier)?);
+                Ok(ret)
+            }
+            E::ParallelAnd(ref left, ref right) => {
+                let ldissat = left.dissatisfy(satisfier)?;
+                let mut ret = right.dissatisfy(satisfier)?;
+                ret.extend(ldissat);
+                Ok(ret)
+            }
+            E::CascadeAnd(ref left, _) => left.dissatisfy(satisfier),
+            E::CascadeOr(ref left, ref right) => {
+                let ldissat = left.dissatisfy(satisfier)?;
+                let mut ret = right.dissatisfy(satisfier)?;
+                ret.extend(ldissat);
+                Ok(ret)
+            }
+            E::ParallelOr(ref left, ref right) => {
+                let ldissat = left.dissatisfy(satisfier)?;
+                let mut ret = right.dissatisfy(satisfier)?;
+                ret.extend(ldissat);
+                Ok(ret)
+            }
+            E::CastF(..) => Ok(vec![])
+        }
+    }
+
+    /// Upper bound on the number of witness elements needed to dissatisfy the fragment
+    fn max_dissatisfaction_items(&self) -> usize {
+        match *self {
+            E::CheckSig(..) | E::CheckSigAggregate(..) => 1,
+            E::CheckSigHash(..) => 2,
+            E::CheckSigHashF(..) => 1,
+            E::CheckMultiSig(k, _) => k + 1,
+            E::CheckMultiSigF(..) => 1,
+            E::CheckSigAdd(_, ref keys) => keys.len(),
+            #[cfg(feature = "elements")]
+            E::CheckSigFromStack(..) => 1,
+            E::HashEqual(..) | E::Hash160Equal(..) |
+            E::Ripemd160Equal(..) | E::Hash256Equal(..) => 1,
+            E::Threshold(_, ref sube, ref subw) => {
+                let mut ret = sube.max_dissatisfaction_items();
+                for sub in subw {
+                    ret += sub.max_dissatisfaction_items();
+                }
+                ret
+            }
+            E::ParallelAnd(ref left, ref right) => {
+                left.max_dissatisfaction_items() + right.max_dissatisfaction_items()
+            }
+            E::CascadeAnd(ref left, _) => left.max_dissatisfaction_items(),
+            E::ParallelOr(ref left, ref right) => {
+                left.max_dissatisfaction_items() + right.max_dissatisfaction_items()
+            }
+            E::CascadeOr(ref left, ref right) => {
+                left.max_dissatisfaction_items() + right.max_dissatisfaction_items()
+            }
+            E::CastF(..) => 1,
+        }
+    }
+
+    /// Upper bound on the size of the witness needed to dissatisfy the fragment
+    fn max_dissatisfaction_size(&self) -> usize {
+        match *self {
+            E::CheckSig(..) | E::CheckSigAggregate(..) => 1,
+            E::CheckSigHash(..) => 1 + 34,
+            E::CheckSigHashF(..) => 1,
+            E::CheckMultiSig(k, _) => k + 1,
+            E::CheckMultiSigF(..) => 1,
+            E::CheckSigAdd(_, ref keys) => keys.len(),
+            #[cfg(feature = "elements")]
+            E::CheckSigFromStack(..) => 1,
+            E::HashEqual(..) | E::Hash160Equal(..) |
+            E::Ripemd160Equal(..) | E::Hash256Equal(..) => 1,
+            E::Threshold(_, ref sube, ref subw) => {
+                let mut ret = sube.max_dissatisfaction_size();
+                for sub in subw {
+                    ret += sub.max_dissatisfaction_size();
+                }
+                ret
+            }
+            E::ParallelAnd(ref left, ref right) => {
+                left.max_dissatisfaction_size() + right.max_dissatisfaction_size()
+            }
+            E::CascadeAnd(ref left, _) => left.max_dissatisfaction_size(),
+            E::ParallelOr(ref left, ref right) => {
+                left.max_dissatisfaction_size() + right.max_dissatisfaction_size()
+            }
+            E::CascadeOr(ref left, ref right) => {
+                left.max_dissatisfaction_size() + right.max_dissatisfaction_size()
+            }
+            E::CastF(..) => 1,
+        }
+    }
+}
+
+impl fmt::Display for W {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let script = self.serialize(script::Builder::new()).into_script();
+        fmt::Display::fmt(&script, f)
+    }
+}
+
+impl AstElem for W {
+    fn into_w(self: Box<W>) -> Result<Arc<W>, Error> { Ok(Arc::from(self)) }
+    fn is_w(&self) -> bool { true }
+
+    fn serialize_pieces<'a>(&'a self, mut builder: script::Builder, pieces: &mut Vec<Piece<'a>>) -> script::Builder {
+        match *self {
+            W::CheckSig(pk) | W::CheckSigAggregate(pk, _) => {
+                builder.push_opcode(opcodes::All::OP_SWAP)
+                       .push_slice(&pk.serialize()[..])
+                       .push_opcode(opcodes::All::OP_CHECKSIG)
+            }
+            W::HashEqual(hash) => {
+                builder.push_opcode(opcodes::All::OP_SWAP)
+                       .push_opcode(opcodes::All::OP_SIZE)
+                       .push_opcode(opcodes::All::OP_IF)
+                       .push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_SHA256)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_int(1)
+                       .push_opcode(opcodes::All::OP_ENDIF)
+            }
+            W::Hash160Equal(hash) => {
+                builder.push_opcode(opcodes::All::OP_SWAP)
+                       .push_opcode(opcodes::All::OP_SIZE)
+                       .push_opcode(opcodes::All::OP_IF)
+                       .push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_HASH160)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_int(1)
+                       .push_opcode(opcodes::All::OP_ENDIF)
+            }
+            W::Ripemd160Equal(hash) => {
+                builder.push_opcode(opcodes::All::OP_SWAP)
+                       .push_opcode(opcodes::All::OP_SIZE)
+                       .push_opcode(opcodes::All::OP_IF)
+                       .push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_RIPEMD160)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_int(1)
+                       .push_opcode(opcodes::All::OP_ENDIF)
+            }
+            W::Hash256Equal(hash) => {
+                builder.push_opcode(opcodes::All::OP_SWAP)
+                       .push_opcode(opcodes::All::OP_SIZE)
+                       .push_opcode(opcodes::All::OP_IF)
+                       .push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_HASH256)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_int(1)
+                       .push_opcode(opcodes::All::OP_ENDIF)
+            }
+            W::Csv(n) => {
+                builder.push_opcode(opcodes::All::OP_SWAP)
+                       .push_opcode(opcodes::All::OP_SIZE)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_IF)
+                       .push_int(n as i64)
+                       .push_opcode(opcodes::OP_CSV)
+                       .push_opcode(opcodes::All::OP_ELSE)
+                       .push_int(0)
+                       .push_opcode(opcodes::All::OP_ENDIF)
+            }
+            W::CastE(ref expr) => {
+                builder = builder.push_opcode(opcodes::All::OP_TOALTSTACK);
+                defer(builder, &**expr, pieces).push_opcode(opcodes::All::OP_FROMALTSTACK)
+            }
+        }
+    }
+
+    fn satisfy(&self, satisfier: &dyn Satisfier, strategy: Strategy) -> Result<Vec<Vec<u8>>, Error> {
+        match *self {
+            W::CheckSig(ref pk) | W::CheckSigAggregate(ref pk, _) => satisfy_checksig(pk, satisfier),
+            W::HashEqual(ref hash) => satisfy_hashequal(hash, satisfier),
+            W::Hash160Equal(ref hash) => satisfy_hash160equal(hash, satisfier),
+            W::Ripemd160Equal(ref hash) => satisfy_ripemd160equal(hash, satisfier),
+            W::Hash256Equal(ref hash) => satisfy_hash256equal(hash, satisfier),
+            W::Csv(n) => satisfy_csv(n, satisfier).map(|_| vec![vec![1]]),
+            W::CastE(ref e) => e.satisfy(satisfier, strategy)
+        }
+    }
+
+    fn required_keys(&self) -> Vec<secp256k1::PublicKey> {
+        match *self {
+            W::CheckSig(ref pk) => vec![*pk],
+            W::CheckSigAggregate(_, ref keys) => keys.to_vec(),
+            W::HashEqual(..) | W::Hash160Equal(..) |
+            W::Ripemd160Equal(..) | W::Hash256Equal(..) => vec![],
+            W::Csv(..) => vec![],
+            W::CastE(ref e) => e.required_keys(),
+        }
+    }
+
+    fn expr_type(&self) -> ExprType { ExprType::W }
+
+    fn fragment(&self) -> (Fragment, Vec<&dyn AstElem>) {
+        match *self {
+            W::CheckSig(pk) => (Fragment::CheckSig(pk), vec![]),
+            W::CheckSigAggregate(pk, ref keys) => (Fragment::CheckSigAggregate(pk, keys.to_vec()), vec![]),
+            W::HashEqual(hash) => (Fragment::HashEqual(hash), vec![]),
+            W::Hash160Equal(hash) => (Fragment::Hash160Equal(hash), vec![]),
+            W::Ripemd160Equal(hash) => (Fragment::Ripemd160Equal(hash), vec![]),
+            W::Hash256Equal(hash) => (Fragment::Hash256Equal(hash), vec![]),
+            W::Csv(n) => (Fragment::Csv(n), vec![]),
+            W::CastE(ref sub) => (Fragment::Cast, vec![&**sub as &dyn AstElem]),
+        }
+    }
+
+    fn policy(&self) -> Policy {
+        match *self {
+            W::CheckSig(ref pk) => Policy::Key(*pk),
+            W::CheckSigAggregate(_, ref keys) => Policy::multi(keys.len(), keys),
+            W::HashEqual(hash) => Policy::Sha256(hash),
+            W::Hash160Equal(hash) => Policy::Hash160(hash),
+            W::Ripemd160Equal(hash) => Policy::Ripemd160(hash),
+            W::Hash256Equal(hash) => Policy::Hash256(hash),
+            W::Csv(n) => Policy::Csv(n),
+            W::CastE(ref sub) => sub.policy(),
+        }
+    }
+
+    fn malleability(&self, issues: &mut Vec<Malleability>) -> bool {
+        match *self {
+            W::CheckSig(..) | W::CheckSigAggregate(..) => true,
+            W::HashEqual(..) | W::Hash160Equal(..) |
+            W::Ripemd160Equal(..) | W::Hash256Equal(..) => false,
+            W::Csv(..) => false,
+            W::CastE(ref e) => e.malleability(issues),
+        }
+    }
+
+    fn max_satisfaction_items(&self) -> usize {
+        match *self {
+            W::CheckSig(..) | W::CheckSigAggregate(..) => 1,
+            W::HashEqual(..) | W::Hash160Equal(..) |
+            W::Ripemd160Equal(..) | W::Hash256Equal(..) => 1,
+            W::Csv(..) => 1,
+            W::CastE(ref e) => e.max_satisfaction_items(),
+        }
+    }
+
+    fn max_satisfaction_size(&self) -> usize {
+        match *self {
+            W::CheckSig(..) | W::CheckSigAggregate(..) => 74,
+            W::HashEqual(..) | W::Hash160Equal(..) |
+            W::Ripemd160Equal(..) | W::Hash256Equal(..) => 33,
+            W::Csv(..) => 2,
+            W::CastE(ref e) => e.max_satisfaction_size(),
+        }
+    }
+}
+
+impl W {
+    fn dissatisfy(&self, satisfier: &dyn Satisfier) -> Result<Vec<Vec<u8>>, Error> {
+        match *self {
+            W::CheckSig(..) | W::CheckSigAggregate(..) => Ok(vec![vec![]]),
+            W::HashEqual(..) | W::Hash160Equal(..) |
+            W::Ripemd160Equal(..) | W::Hash256Equal(..) => Ok(vec![vec![]]),
+            W::Csv(..) => Ok(vec![vec![]]),
+            W::CastE(ref e) => e.dissatisfy(satisfier)
+        }
+    }
+
+    /// Upper bound on the number of witness elements needed to dissatisfy the fragment
+    fn max_dissatisfaction_items(&self) -> usize {
+        match *self {
+            W::CheckSig(..) | W::CheckSigAggregate(..) => 1,
+            W::HashEqual(..) | W::Hash160Equal(..) |
+            W::Ripemd160Equal(..) | W::Hash256Equal(..) => 1,
+            W::Csv(..) => 1,
+            W::CastE(ref e) => e.max_dissatisfaction_items(),
+        }
+    }
+
+    /// Upper bound on the size of the witness needed to dissatisfy the fragment
+    fn max_dissatisfaction_size(&self) -> usize {
+        match *self {
+            W::CheckSig(..) | W::CheckSigAggregate(..) => 1,
+            W::HashEqual(..) | W::Hash160Equal(..) |
+            W::Ripemd160Equal(..) | W::Hash256Equal(..) => 1,
+            W::Csv(..) => 1,
+            W::CastE(ref e) => e.max_dissatisfaction_size(),
+        }
+    }
+}
+
+impl fmt::Display for F {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let script = self.serialize(script::Builder::new()).into_script();
+        fmt::Display::fmt(&script, f)
+    }
+}
+
+impl AstElem for F {
+    fn into_f(self: Box<F>) -> Result<Arc<F>, Error> { Ok(Arc::from(self)) }
+    fn into_t(self: Box<F>) -> Result<Arc<T>, Error> { Ok(Arc::new(T::CastF(Arc::from(self)))) }
+    fn is_f(&self) -> bool { true }
+    fn is_t(&self) -> bool { true }
+
+    fn serialize_pieces<'a>(&'a self, mut builder: script::Builder, pieces: &mut Vec<Piece<'a>>) -> script::Builder {
+        match *self {
+            F::CheckSig(ref pk) | F::CheckSigAggregate(ref pk, _) => {
+                builder.push_slice(&pk.serialize()[..])
+                       .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
+                       .push_int(1)
+            }
+            F::CheckSigHash(hash) => {
+                builder.push_opcode(opcodes::All::OP_DUP)
+                       .push_opcode(opcodes::All::OP_HASH160)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
+                       .push_int(1)
+            }
+            F::CheckMultiSig(k, ref pks) => {
+                builder = builder.push_int(k as i64);
+                for pk in pks.iter() {
+                    builder = builder.push_slice(&pk.serialize()[..]);
+                }
+                builder.push_int(pks.len() as i64)
+                       .push_opcode(opcodes::All::OP_CHECKMULTISIGVERIFY)
+                       .push_int(1)
+            }
+            #[cfg(feature = "elements")]
+            F::CheckSigFromStack(pk, msg) => {
+                builder.push_slice(&msg[..])
+                       .push_slice(&pk.serialize()[..])
+                       .push_opcode(OP_CHECKSIGFROMSTACKVERIFY)
+                       .push_int(1)
+            }
+            F::CheckSigAdd(k, ref pks) => {
+                serialize_checksigadd_keys(builder, pks)
+                    .push_int(k as i64)
+                    .push_opcode(opcodes::All::OP_NUMEQUALVERIFY)
+                    .push_int(1)
+            }
+            F::Csv(n) => {
+                builder.push_int(n as i64)
+                       .push_opcode(opcodes::OP_CSV)
+            }
+            F::Raw(ref script) => append_raw(builder, script).push_int(1),
+            F::HashEqual(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_SHA256)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_int(1)
+            }
+            F::Hash160Equal(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_HASH160)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_int(1)
+            }
+            F::Ripemd160Equal(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_RIPEMD160)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_int(1)
+            }
+            F::Hash256Equal(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_HASH256)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_int(1)
+            }
+            F::Threshold(k, ref e, ref ws) => {
+                builder = defer(builder, &**e, pieces);
+                for w in ws {
+                    builder = defer(builder, w, pieces).push_opcode(opcodes::All::OP_ADD);
+                }
+                builder.push_int(k as i64)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_int(1)
+            }
+            F::And(ref left, ref right) => {
+                builder = defer(builder, &**left, pieces);
+                defer(builder, &**right, pieces)
+            }
+            F::ParallelOr(ref left, ref right) => {
+                builder = defer(builder, &**left, pieces);
+                builder = defer(builder, &**right, pieces);
+                builder.push_opcode(opcodes::All::OP_BOOLOR)
+                       .push_opcode(opcodes::All::OP_VERIFY)
+                       .push_int(1)
+            }
+            F::SwitchOr(ref left, ref right) => {
+                builder = builder.push_opcode(opcodes::All::OP_SIZE)
+                                 .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                                 .push_opcode(opcodes::All::OP_IF);
+                builder = defer(builder, &**left, pieces);
+                builder = builder.push_opcode(opcodes::All::OP_ELSE);
+                builder = defer(builder, &**right, pieces);
+                builder.push_opcode(opcodes::All::OP_ENDIF)
+            }
+            F::SwitchOrV(ref left, ref right) => {
+                builder = builder.push_opcode(opcodes::All::OP_SIZE)
+                                 .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                                 .push_opcode(opcodes::All::OP_IF);
+                builder = defer(builder, &**left, pieces);
+                builder = builder.push_opcode(opcodes::All::OP_ELSE);
+                builder = defer(builder, &**right, pieces);
+                builder.push_opcode(opcodes::All::OP_ENDIF)
+                       .push_int(1)
+            }
+            F::CascadeOr(ref left, ref right) => {
+                builder = defer(builder, &**left, pieces);
+                builder = builder.push_opcode(opcodes::All::OP_IFDUP)
+                                 .push_opcode(opcodes::All::OP_NOTIF);
+                builder = defer(builder, &**right, pieces);
+                builder.push_opcode(opcodes::All::OP_ENDIF)
+            }
+            F::CascadeOrV(ref left, ref right) => {
+                builder = defer(builder, &**left, pieces);
+                builder = builder.push_opcode(opcodes::All::OP_NOTIF);
+                builder = defer(builder, &**right, pieces);
+                builder.push_opcode(opcodes::All::OP_ENDIF)
+                       .push_int(1)
+            }
+        }
+    }
+
+    fn satisfy(&self, satisfier: &dyn Satisfier, strategy: Strategy) -> Result<Vec<Vec<u8>>, Error> {
+        match *self {
+            F::CheckSig(ref pk) | F::CheckSigAggregate(ref pk, _) => satisfy_checksig(pk, satisfier),
+            F::CheckMultiSig(k, ref keys) => satisfy_checkmultisig(k, keys, satisfier, strategy),
+            F::CheckSigAdd(k, ref keys) => satisfy_checksigadd(k, keys, satisfier, strategy),
+            #[cfg(feature = "elements")]
+            F::CheckSigFromStack(ref pk, ref msg) => satisfy_checksigfromstack(pk, msg, satisfier),
+            F::CheckSigHash(ref hash) => satisfy_checksighash(hash, satisfier),
+            F::Csv(n) => satisfy_csv(n, satisfier),
+            F::Raw(ref script) => satisfy_raw(script, satisfier),
+            F::HashEqual(ref hash) => satisfy_hashequal(hash, satisfier),
+            F::Hash160Equal(ref hash) => satisfy_hash160equal(hash, satisfier),
+            F::Ripemd160Equal(ref hash) => satisfy_ripemd160equal(hash, satisfier),
+            F::Hash256Equal(ref hash) => satisfy_hash256equal(hash, satisfier),
+            F::Threshold(k, ref sube, ref subw) => satisfy_threshold(k, sube, subw, satisfier, strategy),
+            F::And(ref left, ref right) => {
+                let lsat = left.satisfy(satisfier, strategy)?;
+                let mut ret = right.satisfy(satisfier, strategy)?;
+                ret.extend(lsat);
+                Ok(ret)
+            }
+            F::ParallelOr(ref left, ref right) => satisfy_parallel_or(left, right, satisfier, strategy),
+            F::SwitchOr(ref left, ref right) => satisfy_switch_or(left, right, satisfier, strategy),
+            F::SwitchOrV(ref left, ref right) => satisfy_switch_or(left, right, satisfier, strategy),
+            F::CascadeOr(ref left, ref right) => satisfy_cascade_or(left, right, satisfier, strategy),
+            F::CascadeOrV(ref left, ref right) => satisfy_cascade_or(left, right, satisfier, strategy),
+        }
+    }
+
+    fn required_keys(&self) -> Vec<secp256k1::PublicKey> {
+        match *self {
+            F::CheckSig(pk) => vec![pk],
+            F::CheckSigAggregate(_, ref keys) => keys.to_vec(),
+            F::CheckMultiSig(_, ref keys) | F::CheckSigAdd(_, ref keys) => keys.to_vec(),
+            #[cfg(feature = "elements")]
+            F::CheckSigFromStack(pk, _) => vec![pk],
+            F::CheckSigHash(..) | F::Csv(..) | F::Raw(..) |
+            F::HashEqual(..) | F::Hash160Equal(..) |
+            F::Ripemd160Equal(..) | F::Hash256Equal(..) => vec![],
+            F::Threshold(_, ref sube, ref subw) => {
+                let mut ret = sube.required_keys();
+                for sub in subw {
+                    ret.extend(sub.required_keys());
+                }
+                ret
+            }
+            F::And(ref left, ref right) => {
+                let mut ret = left.required_keys();
+                ret.extend(right.required_keys());
+                ret
+            }
+            F::ParallelOr(ref left, ref right) => {
+                let mut ret = left.required_keys();
+                ret.extend(right.required_keys());
+                ret
+            }
+            F::SwitchOr(ref left, ref right) => {
+                let mut ret = left.required_keys();
+                ret.extend(right.required_keys());
+                ret
+            }
+            F::SwitchOrV(ref left, ref right) => {
+                let mut ret = left.required_keys();
+                ret.extend(right.required_keys());
+                ret
+            }
+            F::CascadeOr(ref left, ref right) => {
+                let mut ret = left.required_keys();
+                ret.extend(right.required_keys());
+                ret
+            }
+            F::CascadeOrV(ref left, ref right) => {
+                let mut ret = left.required_keys();
+                ret.extend(right.required_keys());
+                ret
+            }
+        }
+    }
+
+    fn expr_type(&self) -> ExprType { ExprType::F }
+
+    fn fragment(&self) -> (Fragment, Vec<&dyn AstElem>) {
+        match *self {
+            F::CheckSig(pk) => (Fragment::CheckSig(pk), vec![]),
+            F::CheckSigAggregate(pk, ref keys) => (Fragment::CheckSigAggregate(pk, keys.to_vec()), vec![]),
+            F::CheckMultiSig(k, ref keys) => (Fragment::CheckMultiSig(k, keys.to_vec()), vec![]),
+            F::CheckSigAdd(k, ref keys) => (Fragment::CheckSigAdd(k, keys.to_vec()), vec![]),
+            #[cfg(feature = "elements")]
+            F::CheckSigFromStack(pk, msg) => (Fragment::CheckSigFromStack(pk, msg), vec![]),
+            F::CheckSigHash(hash) => (Fragment::CheckSigHash(hash), vec![]),
+            F::Csv(n) => (Fragment::Csv(n), vec![]),
+            F::Raw(ref script) => (Fragment::Raw(script.clone()), vec![]),
+            F::HashEqual(hash) => (Fragment::HashEqual(hash), vec![]),
+            F::Hash160Equal(hash) => (Fragment::Hash160Equal(hash), vec![]),
+            F::Ripemd160Equal(hash) => (Fragment::Ripemd160Equal(hash), vec![]),
+            F::Hash256Equal(hash) => (Fragment::Hash256Equal(hash), vec![]),
+            F::Threshold(k, ref sube, ref subw) => {
+                let mut subs = vec![&**sube as &dyn AstElem];
+                subs.extend(subw.iter().map(|w| w as &dyn AstElem));
+                (Fragment::Threshold(k), subs)
+            }
+            F::And(ref left, ref right) => (Fragment::And, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            F::ParallelOr(ref left, ref right) => (Fragment::ParallelOr, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            F::SwitchOr(ref left, ref right) => (Fragment::SwitchOr, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            F::SwitchOrV(ref left, ref right) => (Fragment::SwitchOr, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            F::CascadeOr(ref left, ref right) => (Fragment::CascadeOr, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            F::CascadeOrV(ref left, ref right) => (Fragment::CascadeOr, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+        }
+    }
+
+    fn policy(&self) -> Policy {
+        match *self {
+            F::CheckSig(ref pk) => Policy::Key(*pk),
+            F::CheckSigAggregate(_, ref keys) => Policy::multi(keys.len(), keys),
+            F::CheckMultiSig(k, ref keys) | F::CheckSigAdd(k, ref keys) => Policy::multi(k, keys),
+            #[cfg(feature = "elements")]
+            F::CheckSigFromStack(pk, msg) => Policy::MessageSig(pk, msg),
+            F::CheckSigHash(hash) => Policy::KeyHash(hash),
+            F::Csv(n) => Policy::Csv(n),
+            F::Raw(ref script) => Policy::Raw(script.clone()),
+            F::HashEqual(hash) => Policy::Sha256(hash),
+            F::Hash160Equal(hash) => Policy::Hash160(hash),
+            F::Ripemd160Equal(hash) => Policy::Ripemd160(hash),
+            F::Hash256Equal(hash) => Policy::Hash256(hash),
+            F::Threshold(k, ref sube, ref subw) => Policy::threshold(k, sube, subw),
+            F::And(ref left, ref right) => Policy::and(left.policy(), right.policy()),
+            F::ParallelOr(ref left, ref right) => Policy::or(left.policy(), right.policy()),
+            F::SwitchOr(ref left, ref right) => Policy::or(left.policy(), right.policy()),
+            F::SwitchOrV(ref left, ref right) => Policy::or(left.policy(), right.policy()),
+            F::CascadeOr(ref left, ref right) => Policy::or(left.policy(), right.policy()),
+            F::CascadeOrV(ref left, ref right) => Policy::or(left.policy(), right.policy()),
+        }
+    }
+
+    fn malleability(&self, issues: &mut Vec<Malleability>) -> bool {
+        match *self {
+            F::CheckSig(..) | F::CheckSigAggregate(..) | F::CheckSigHash(..) => true,
+            F::CheckMultiSig(k, _) | F::CheckSigAdd(k, _) => k > 0,
+            // the signature does not commit to the spending transaction, and may well
+            // be public, so does not stop third parties from satisfying the fragment
+            #[cfg(feature = "elements")]
+            F::CheckSigFromStack(..) => false,
+            F::Csv(..) | F::Raw(..) |
+            F::HashEqual(..) | F::Hash160Equal(..) |
+            F::Ripemd160Equal(..) | F::Hash256Equal(..) => false,
+            F::Threshold(k, ref sube, ref subw) => {
+                let mut subs = vec![sube.malleability(issues)];
+                for sub in subw {
+                    subs.push(sub.malleability(issues));
+                }
+                malleability_threshold(k, &subs, self, issues)
+            }
+            F::And(ref left, ref right) => {
+                let left = left.malleability(issues);
+                right.malleability(issues) || left
+            }
+            F::ParallelOr(ref left, ref right) => {
+                let left = left.malleability(issues);
+                let right = right.malleability(issues);
+                malleability_or(left, right, self, issues)
             }
-        },
-        Token::FromAltStack => {
-            #subexpression
-            E: expr, Token::ToAltStack => {
-                Ok(Box::new(W::CastE(expr)))
+            F::SwitchOr(ref left, ref right) => {
+                let left = left.malleability(issues);
+                let right = right.malleability(issues);
+                malleability_or(left, right, self, issues)
             }
-        },
-        Token::Drop, Token::CheckSequenceVerify => {
-            Token::Number(n) => {
-                Ok(Box::new(V::Csv(n)))
+            F::SwitchOrV(ref left, ref right) => {
+                let left = left.malleability(issues);
+                let right = right.malleability(issues);
+                malleability_or(left, right, self, issues)
             }
-        },
-        Token::EndIf => {
-            Token::Number(0), Token::Else => {
-                #subexpression
-                F: right => {
-                    Token::If => {
-                        Token::EqualVerify, Token::Size => {{
-                            match *right {
-                                F::Csv(n) => {{
-                                    match tokens.next() {
-                                        Some(Token::Swap) => Ok(Box::new(W::Csv(n))),
-                                        Some(x) => {
-                                            tokens.un_next(x);
-                                            Ok(Box::new(E::CastF(right)))
-                                        }
-                                        None => Ok(Box::new(E::CastF(right)))
-                                    }
-                                }}
-                                F::And(..) | F::SwitchOr(..) |
-                                F::SwitchOrV(..) | F::CascadeOr(..) => {
-                                    Ok(Box::new(E::CastF(right)))
-                                }
-                                _ => Err(Error::Unexpected(right.to_string())),
-                            }
-                        }}
-                        #subexpression
-                        E: left => {
-                            Ok(Box::new(E::CascadeAnd(left, right)))
-                        }
-                    }
-                }
+            F::CascadeOr(ref left, ref right) => {
+                let left = left.malleability(issues);
+                let right = right.malleability(issues);
+                malleability_or(left, right, self, issues)
             }
-            #subexpression
-            E: right => {
-                Token::NotIf, Token::IfDup => {
-                    #subexpression
-                    E: left => {
-                        Ok(Box::new(E::CascadeOr(left, right)))
-                    }
-                }
-            },
-            F: right => {
-                Token::NotIf, Token::IfDup => {
-                    #subexpression
-                    E: left => {
-                        Ok(Box::new(F::CascadeOr(left, right)))
-                    }
-                },
-                Token::If, Token::Size => {{
-                    match *right {
-                        F::CheckSigHash(hash) => {
-                            Ok(Box::new(E::CheckSigHashF(hash)))
-                        }
-                        F::CheckMultiSig(k, pks) => {
-                            Ok(Box::new(E::CheckMultiSigF(k, pks)))
-                        }
-                        F::HashEqual(hash) => {
-                            match tokens.next() {
-                                Some(Token::Swap) => Ok(Box::new(W::HashEqual(hash))),
-                                Some(x) => {
-                                    tokens.un_next(x);
-                                    Ok(Box::new(E::HashEqual(hash)))
-                                }
-                                None => Ok(Box::new(E::HashEqual(hash))),
-                            }
-                        }
-                        x => Err(Error::Unexpected(x.to_string())),
-                    }
-                }},
-                Token::Else => {
-                    #subexpression
-                    F: left, Token::If, Token::EqualVerify, Token::Size => {
-                        Ok(Box::new(F::SwitchOr(left, right)))
-                    }
-                }
-            },
-            V: right => {
-                Token::Else => {
-                    #subexpression
-                    V: left, Token::If, Token::EqualVerify, Token::Size => {
-                        Ok(Box::new(V::SwitchOr(left, right)))
-                    }
-                },
-                Token::NotIf => {
-                    #subexpression
-                    E: left => {
-                        Ok(Box::new(V::CascadeOr(left, right)))
-                    }
-                }
-            },
-            T: right => {
-                Token::Else => {
-                    #subexpression
-                    T: left, Token::If, Token::EqualVerify, Token::Size => {
-                        Ok(Box::new(T::SwitchOr(left, right)))
-                    }
-                },
-                Token::NotIf, Token::IfDup => {
-                    #subexpression
-                    E: left => {
-                        Ok(Box::new(T::CascadeOr(left, right)))
-                    }
-                }
+            F::CascadeOrV(ref left, ref right) => {
+                let left = left.malleability(issues);
+                let right = right.malleability(issues);
+                malleability_or(left, right, self, issues)
             }
-        },
-        Token::Verify => { 
-            Token::EndIf => {
-                #subexpression
-                T: right, Token::Else => {
-                    #subexpression
-                    T: left, Token::If, Token::EqualVerify, Token::Size => {
-                        Ok(Box::new(V::SwitchOrT(left, right)))
-                    }
-                }
-            },
-            Token::BoolOr => {
-                #subexpression
-                W: wexpr => {
-                    #subexpression
-                    E: expr => {
-                        Ok(Box::new(V::ParallelOr(expr, wexpr)))
-                    }
+        }
+    }
+
+    fn max_satisfaction_items(&self) -> usize {
+        match *self {
+            F::CheckSig(..) | F::CheckSigAggregate(..) => 1,
+            F::CheckMultiSig(k, _) => k + 1,
+            F::CheckSigAdd(_, ref keys) => keys.len(),
+            #[cfg(feature = "elements")]
+            F::CheckSigFromStack(..) => 1,
+            F::CheckSigHash(..) => 2,
+            F::Csv(..) => 0,
+            // the satisfaction of a raw script is unknown
+            F::Raw(..) => 0,
+            F::HashEqual(..) | F::Hash160Equal(..) |
+            F::Ripemd160Equal(..) | F::Hash256Equal(..) => 1,
+            F::Threshold(k, ref sube, ref subw) => {
+                let mut sats = vec![sube.max_satisfaction_items()];
+                let mut dissats = vec![sube.max_dissatisfaction_items()];
+                for sub in subw {
+                    sats.push(sub.max_satisfaction_items());
+                    dissats.push(sub.max_dissatisfaction_items());
                 }
+                max_threshold(k, &sats, &dissats)
+            }
+            F::And(ref left, ref right) => {
+                left.max_satisfaction_items() + right.max_satisfaction_items()
+            }
+            F::ParallelOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_items() + right.max_dissatisfaction_items(),
+                    left.max_dissatisfaction_items() + right.max_satisfaction_items(),
+                )
+            }
+            F::SwitchOr(ref left, ref right) => {
+                1 + cmp::max(left.max_satisfaction_items(), right.max_satisfaction_items())
+            }
+            F::SwitchOrV(ref left, ref right) => {
+                1 + cmp::max(left.max_satisfaction_items(), right.max_satisfaction_items())
+            }
+            F::CascadeOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_items(),
+                    left.max_dissatisfaction_items() + right.max_satisfaction_items(),
+                )
+            }
+            F::CascadeOrV(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_items(),
+                    left.max_dissatisfaction_items() + right.max_satisfaction_items(),
+                )
             }
-        },
-        Token::Number(1) => {
-            #subexpression
-            V: vexpr => {{
-                let unboxed = *vexpr; // need this variable, cannot directly match on *vexpr, see https://github.com/rust-lang/rust/issues/16223
-                match unboxed {
-                    V::CheckSig(pk) => Ok(Box::new(F::CheckSig(pk))),
-                    V::CheckSigHash(hash) => Ok(Box::new(F::CheckSigHash(hash))),
-                    V::CheckMultiSig(k, keys) => Ok(Box::new(F::CheckMultiSig(k, keys))),
-                    V::HashEqual(hash) => Ok(Box::new(F::HashEqual(hash))),
-                    V::Threshold(k, e, ws) => Ok(Box::new(F::Threshold(k, e, ws))),
-                    V::ParallelOr(left, right) => Ok(Box::new(F::ParallelOr(left, right))),
-                    V::SwitchOr(left, right) => Ok(Box::new(F::SwitchOrV(left, right))),
-                    V::CascadeOr(left, right) => Ok(Box::new(F::CascadeOrV(left, right))),
-                    x => Err(Error::Unexpected(x.to_string())),
-                }
-            }}
         }
-    );
-
-    if let Ok(ret) = ret {
-        // vexpr [tfv]expr AND
-        if ret.is_t() || ret.is_f() || ret.is_v() {
-            match tokens.peek() {
-                None | Some(&Token::If) | Some(&Token::NotIf) | Some(&Token::Else) => Ok(ret),
-                _ => {
-                    let left = parse_subexpression(tokens)?.into_v()?;
+    }
 
-                    if ret.is_t() {
-                        let right = ret.into_t().unwrap();
-                        Ok(Box::new(T::And(left, right)))
-                    } else if ret.is_f() {
-                        let right = ret.into_f().unwrap();
-                        Ok(Box::new(F::And(left, right)))
-                    } else if ret.is_v() {
-                        let right = ret.into_v().unwrap();
-                        Ok(Box::new(V::And(left, right)))
-                    } else {
-                        unreachable!()
-                    }
+    fn max_satisfaction_size(&self) -> usize {
+        match *self {
+            F::CheckSig(..) | F::CheckSigAggregate(..) => 74,
+            F::CheckMultiSig(k, _) => 1 + 74 * k,
+            F::CheckSigAdd(k, ref keys) => 74 * k + keys.len().saturating_sub(k),
+            #[cfg(feature = "elements")]
+            F::CheckSigFromStack(..) => 73,
+            F::CheckSigHash(..) => 74 + 34,
+            F::Csv(..) => 0,
+            // the satisfaction of a raw script is unknown
+            F::Raw(..) => 0,
+            F::HashEqual(..) | F::Hash160Equal(..) |
+            F::Ripemd160Equal(..) | F::Hash256Equal(..) => 33,
+            F::Threshold(k, ref sube, ref subw) => {
+                let mut sats = vec![sube.max_satisfaction_size()];
+                let mut dissats = vec![sube.max_dissatisfaction_size()];
+                for sub in subw {
+                    sats.push(sub.max_satisfaction_size());
+                    dissats.push(sub.max_dissatisfaction_size());
                 }
+                max_threshold(k, &sats, &dissats)
+            }
+            F::And(ref left, ref right) => {
+                left.max_satisfaction_size() + right.max_satisfaction_size()
+            }
+            F::ParallelOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_size() + right.max_dissatisfaction_size(),
+                    left.max_dissatisfaction_size() + right.max_satisfaction_size(),
+                )
+            }
+            F::SwitchOr(ref left, ref right) => {
+                2 + cmp::max(left.max_satisfaction_size(), right.max_satisfaction_size())
+            }
+            F::SwitchOrV(ref left, ref right) => {
+                2 + cmp::max(left.max_satisfaction_size(), right.max_satisfaction_size())
+            }
+            F::CascadeOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_size(),
+                    left.max_dissatisfaction_size() + right.max_satisfaction_size(),
+                )
+            }
+            F::CascadeOrV(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_size(),
+                    left.max_dissatisfaction_size() + right.max_satisfaction_size(),
+                )
             }
-        } else {
-            Ok(ret)
         }
-    } else {
-        ret
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Debug)]
-struct Cost<T> {
-    ast: T,
-    pk_cost: usize,
-    sat_cost: usize,
-    dissat_cost: usize,
-}
-
-impl fmt::Display for E {
+impl fmt::Display for V {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let script = self.serialize(script::Builder::new()).into_script();
         fmt::Display::fmt(&script, f)
     }
 }
 
-impl AstElem for E {
-    fn into_e(self: Box<E>) -> Result<Box<E>, Error> { Ok(self) }
-    fn into_t(self: Box<E>) -> Result<Box<T>, Error> { Ok(Box::new(T::CastE(self))) }
-    fn is_e(&self) -> bool { true }
-    fn is_t(&self) -> bool { true }
+impl AstElem for V {
+    fn into_v(self: Box<V>) -> Result<Arc<V>, Error> { Ok(Arc::from(self)) }
+    fn is_v(&self) -> bool { true }
 
-    fn serialize(&self, mut builder: script::Builder) -> script::Builder {
+    fn serialize_pieces<'a>(&'a self, mut builder: script::Builder, pieces: &mut Vec<Piece<'a>>) -> script::Builder {
         match *self {
-            E::CheckSig(ref pk) => {
+            V::CheckSig(ref pk) | V::CheckSigAggregate(ref pk, _) => {
                 builder.push_slice(&pk.serialize()[..])
-                       .push_opcode(opcodes::All::OP_CHECKSIG)
+                       .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
             }
-            E::CheckSigHash(ref hash) => {
+            V::CheckSigHash(hash) => {
                 builder.push_opcode(opcodes::All::OP_DUP)
-                       .push_opcode(opcodes::All::OP_HASH160)
-                       .push_slice(&hash[..])
-                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                       .push_opcode(opcodes::All::OP_CHECKSIG)
-            }
-            E::CheckSigHashF(ref hash) => {
-                builder.push_opcode(opcodes::All::OP_SIZE)
-                       .push_opcode(opcodes::All::OP_IF)
-                       .push_opcode(opcodes::All::OP_DUP)
                        .push_opcode(opcodes::All::OP_HASH160)
                        .push_slice(&hash[..])
                        .push_opcode(opcodes::All::OP_EQUALVERIFY)
                        .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
-                       .push_int(1)
-                       .push_opcode(opcodes::All::OP_ENDIF)
             }
-            E::CheckMultiSig(k, ref pks) => {
+            V::CheckMultiSig(k, ref pks) => {
                 builder = builder.push_int(k as i64);
-                for pk in pks {
-                    builder = builder.push_slice(&pk.serialize()[..]);
-                }
-                builder.push_int(pks.len() as i64)
-                       .push_opcode(opcodes::All::OP_CHECKMULTISIG)
-            }
-            E::CheckMultiSigF(k, ref pks) => {
-                builder = builder.push_opcode(opcodes::All::OP_SIZE)
-                                 .push_opcode(opcodes::All::OP_IF)
-                                 .push_int(k as i64);
-                for pk in pks {
+                for pk in pks.iter() {
                     builder = builder.push_slice(&pk.serialize()[..]);
                 }
                 builder.push_int(pks.len() as i64)
                        .push_opcode(opcodes::All::OP_CHECKMULTISIGVERIFY)
-                       .push_int(1)
-                       .push_opcode(opcodes::All::OP_ENDIF)
             }
-            E::HashEqual(hash) => {
+            #[cfg(feature = "elements")]
+            V::CheckSigFromStack(pk, msg) => {
+                builder.push_slice(&msg[..])
+                       .push_slice(&pk.serialize()[..])
+                       .push_opcode(OP_CHECKSIGFROMSTACKVERIFY)
+            }
+            V::CheckSigAdd(k, ref pks) => {
+                serialize_checksigadd_keys(builder, pks)
+                    .push_int(k as i64)
+                    .push_opcode(opcodes::All::OP_NUMEQUALVERIFY)
+            }
+            V::Csv(n) => {
+                builder.push_int(n as i64)
+                       .push_opcode(opcodes::OP_CSV)
+                       .push_opcode(opcodes::All::OP_DROP)
+            }
+            V::Raw(ref script) => append_raw(builder, script),
+            V::HashEqual(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_SHA256)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+            }
+            V::Hash160Equal(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_HASH160)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+            }
+            V::Ripemd160Equal(hash) => {
                 builder.push_opcode(opcodes::All::OP_SIZE)
-                       .push_opcode(opcodes::All::OP_IF)
-                       .push_opcode(opcodes::All::OP_SIZE)
                        .push_int(32)
                        .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                       .push_opcode(opcodes::All::OP_SHA256)
+                       .push_opcode(opcodes::All::OP_RIPEMD160)
                        .push_slice(&hash[..])
                        .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                       .push_int(1)
-                       .push_opcode(opcodes::All::OP_ENDIF)
             }
-            E::Threshold(k, ref e, ref ws) => {
-                builder = e.serialize(builder);
+            V::Hash256Equal(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_HASH256)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+            }
+            V::Threshold(k, ref e, ref ws) => {
+                builder = defer(builder, &**e, pieces);
                 for w in ws {
-                    builder = w.serialize(builder).push_opcode(opcodes::All::OP_ADD);
+                    builder = defer(builder, w, pieces).push_opcode(opcodes::All::OP_ADD);
                 }
                 builder.push_int(k as i64)
-                       .push_opcode(opcodes::All::OP_EQUAL)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
             }
-            E::ParallelAnd(ref left, ref right) => {
-                builder = left.serialize(builder);
-                builder = right.serialize(builder);
-                builder.push_opcode(opcodes::All::OP_BOOLAND)
+            V::And(ref left, ref right) => {
+                builder = defer(builder, &**left, pieces);
+                defer(builder, &**right, pieces)
             }
-            E::CascadeAnd(ref left, ref right) => {
-                builder = left.serialize(builder);
-                builder = builder.push_opcode(opcodes::All::OP_IF);
-                builder = right.serialize(builder);
-                builder.push_opcode(opcodes::All::OP_ELSE)
-                       .push_int(0)
-                       .push_opcode(opcodes::All::OP_ENDIF)
+            V::ParallelOr(ref left, ref right) => {
+                builder = defer(builder, &**left, pieces);
+                builder = defer(builder, &**right, pieces);
+                builder.push_opcode(opcodes::All::OP_BOOLOR)
+                       .push_opcode(opcodes::All::OP_VERIFY)
             }
-            E::CascadeOr(ref left, ref right) => {
-                builder = left.serialize(builder);
-                builder = builder.push_opcode(opcodes::All::OP_IFDUP)
-                                 .push_opcode(opcodes::All::OP_NOTIF);
-                builder = right.serialize(builder);
+            V::SwitchOr(ref left, ref right) => {
+                builder = builder.push_opcode(opcodes::All::OP_SIZE)
+                                 .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                                 .push_opcode(opcodes::All::OP_IF);
+                builder = defer(builder, &**left, pieces);
+                builder = builder.push_opcode(opcodes::All::OP_ELSE);
+                builder = defer(builder, &**right, pieces);
                 builder.push_opcode(opcodes::All::OP_ENDIF)
             }
-            E::ParallelOr(ref left, ref right) => {
-                builder = left.serialize(builder);
-                builder = right.serialize(builder);
-                builder.push_opcode(opcodes::All::OP_BOOLOR)
-            }
-            E::CastF(ref fexpr) => {
+            V::SwitchOrT(ref left, ref right) => {
                 builder = builder.push_opcode(opcodes::All::OP_SIZE)
                                  .push_opcode(opcodes::All::OP_EQUALVERIFY)
                                  .push_opcode(opcodes::All::OP_IF);
-                builder = fexpr.serialize(builder);
-                builder.push_opcode(opcodes::All::OP_ELSE)
-                       .push_int(0)
-                       .push_opcode(opcodes::All::OP_ENDIF)
+                builder = defer(builder, &**left, pieces);
+                builder = builder.push_opcode(opcodes::All::OP_ELSE);
+                builder = defer(builder, &**right, pieces);
+                builder.push_opcode(opcodes::All::OP_ENDIF)
+                       .push_opcode(opcodes::All::OP_VERIFY)
+            }
+            V::CascadeOr(ref left, ref right) => {
+                builder = defer(builder, &**left, pieces);
+                builder = builder.push_opcode(opcodes::All::OP_NOTIF);
+                builder = defer(builder, &**right, pieces);
+                builder.push_opcode(opcodes::All::OP_ENDIF)
             }
         }
     }
 
-    fn satisfy(
-        &self,
-        key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
-        pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
-        hash_map: &HashMap<Sha256dHash, [u8; 32]>,
-        age: u32,
-    ) -> Result<Vec<Vec<u8>>, Error> {
+    fn satisfy(&self, satisfier: &dyn Satisfier, strategy: Strategy) -> Result<Vec<Vec<u8>>, Error> {
         match *self {
-            E::CheckSig(ref pk) => satisfy_checksig(pk, key_map),
-            E::CheckSigHash(ref hash) | E::CheckSigHashF(ref hash) => satisfy_checksighash(hash, key_map, pkh_map),
-            E::CheckMultiSig(k, ref keys) | E::CheckMultiSigF(k, ref keys) => satisfy_checkmultisig(k, keys, key_map),
-            E::HashEqual(ref hash) => satisfy_hashequal(hash, hash_map),
-            E::Threshold(k, ref sube, ref subw) => satisfy_threshold(k, sube, subw, key_map, pkh_map, hash_map, age),
-            E::ParallelAnd(ref left, ref right) => {
-                let mut ret = left.satisfy(key_map, pkh_map, hash_map, age)?;
-                ret.extend(right.satisfy(key_map, pkh_map, hash_map, age)?);
-                Ok(ret)
-            }
-            E::CascadeAnd(ref left, ref right) => {
-                let mut ret = left.satisfy(key_map, pkh_map, hash_map, age)?;
-                ret.extend(right.satisfy(key_map, pkh_map, hash_map, age)?);
+            V::CheckSig(ref pk) | V::CheckSigAggregate(ref pk, _) => satisfy_checksig(pk, satisfier),
+            V::CheckMultiSig(k, ref keys) => satisfy_checkmultisig(k, keys, satisfier, strategy),
+            V::CheckSigAdd(k, ref keys) => satisfy_checksigadd(k, keys, satisfier, strategy),
+            #[cfg(feature = "elements")]
+            V::CheckSigFromStack(ref pk, ref msg) => satisfy_checksigfromstack(pk, msg, satisfier),
+            V::CheckSigHash(ref hash) => satisfy_checksighash(hash, satisfier),
+            V::Csv(n) => satisfy_csv(n, satisfier),
+            V::Raw(ref script) => satisfy_raw(script, satisfier),
+            V::HashEqual(ref hash) => satisfy_hashequal(hash, satisfier),
+            V::Hash160Equal(ref hash) => satisfy_hash160equal(hash, satisfier),
+            V::Ripemd160Equal(ref hash) => satisfy_ripemd160equal(hash, satisfier),
+            V::Hash256Equal(ref hash) => satisfy_hash256equal(hash, satisfier),
+            V::Threshold(k, ref sube, ref subw) => satisfy_threshold(k, sube, subw, satisfier, strategy),
+            V::And(ref left, ref right) => {
+                let lsat = left.satisfy(satisfier, strategy)?;
+                let mut ret = right.satisfy(satisfier, strategy)?;
+                ret.extend(lsat);
                 Ok(ret)
             }
-            E::ParallelOr(ref left, ref right) => satisfy_parallel_or(left, right, key_map, pkh_map, hash_map, age),
-            E::CascadeOr(ref left, ref right) => satisfy_cascade_or(left, right, key_map, pkh_map, hash_map, age),
-            E::CastF(ref f) => {
-                let mut fsat = f.satisfy(key_map, pkh_map, hash_map, age)?;
-                fsat.push(vec![1]);
-                Ok(fsat)
-            }
+            V::ParallelOr(ref left, ref right) => satisfy_parallel_or(left, right, satisfier, strategy),
+            V::SwitchOr(ref left, ref right) => satisfy_switch_or(left, right, satisfier, strategy),
+            V::SwitchOrT(ref left, ref right) => satisfy_switch_or(left, right, satisfier, strategy),
+            V::CascadeOr(ref left, ref right) => satisfy_cascade_or(left, right, satisfier, strategy),
         }
     }
 
     fn required_keys(&self) -> Vec<secp256k1::PublicKey> {
         match *self {
-            E::CheckSig(pk) => vec![pk],
-            E::CheckSigHash(..) | E::CheckSigHashF(..) | E::HashEqual(..) => vec![],
-            E::CheckMultiSig(_, ref keys) | E::CheckMultiSigF(_, ref keys) => keys.clone(),
-            E::Threshold(_, ref sube, ref subw) => {
+            V::CheckSig(pk) => vec![pk],
+            V::CheckSigAggregate(_, ref keys) => keys.to_vec(),
+            V::CheckMultiSig(_, ref keys) | V::CheckSigAdd(_, ref keys) => keys.to_vec(),
+            #[cfg(feature = "elements")]
+            V::CheckSigFromStack(pk, _) => vec![pk],
+            V::CheckSigHash(..) | V::Csv(..) | V::Raw(..) |
+            V::HashEqual(..) | V::Hash160Equal(..) |
+            V::Ripemd160Equal(..) | V::Hash256Equal(..) => vec![],
+            V::Threshold(_, ref sube, ref subw) => {
                 let mut ret = sube.required_keys();
                 for sub in subw {
                     ret.extend(sub.required_keys());
                 }
                 ret
             }
-            E::ParallelAnd(ref left, ref right) => {
+            V::And(ref left, ref right) => {
                 let mut ret = left.required_keys();
                 ret.extend(right.required_keys());
                 ret
             }
-            E::CascadeAnd(ref left, ref right) => {
+            V::ParallelOr(ref left, ref right) => {
                 let mut ret = left.required_keys();
                 ret.extend(right.required_keys());
                 ret
             }
-            E::ParallelOr(ref left, ref right) => {
+            V::SwitchOr(ref left, ref right) => {
                 let mut ret = left.required_keys();
                 ret.extend(right.required_keys());
                 ret
             }
-            E::CascadeOr(ref left, ref right) => {
+            V::SwitchOrT(ref left, ref right) => {
+                let mut ret = left.required_keys();
+                ret.extend(right.required_keys());
+                ret
+            }
+            V::CascadeOr(ref left, ref right) => {
                 let mut ret = left.required_keys();
                 ret.extend(right.required_keys());
                 ret
             }
-            E::CastF(ref f) => f.required_keys(),
         }
     }
-}
 
-fn min_cost<T, S, F: FnOnce(S) -> T>(one: Cost<T>, two: Cost<S>, sat_prob: f64, cast: F) -> Cost<T> {
-    let weight_one = one.pk_cost as f64 + sat_prob * one.sat_cost as f64 + (1.0 - sat_prob) * one.dissat_cost as f64;
-    let weight_two = two.pk_cost as f64 + sat_prob * two.sat_cost as f64 + (1.0 - sat_prob) * two.dissat_cost as f64;
-    if weight_one < weight_two {
-        one
-    } else {
-        Cost {
-            ast: cast(two.ast),
-            pk_cost: two.pk_cost,
-            sat_cost: two.sat_cost,
-            dissat_cost: two.dissat_cost,
+    fn expr_type(&self) -> ExprType { ExprType::V }
+
+    fn fragment(&self) -> (Fragment, Vec<&dyn AstElem>) {
+        match *self {
+            V::CheckSig(pk) => (Fragment::CheckSig(pk), vec![]),
+            V::CheckSigAggregate(pk, ref keys) => (Fragment::CheckSigAggregate(pk, keys.to_vec()), vec![]),
+            V::CheckMultiSig(k, ref keys) => (Fragment::CheckMultiSig(k, keys.to_vec()), vec![]),
+            V::CheckSigAdd(k, ref keys) => (Fragment::CheckSigAdd(k, keys.to_vec()), vec![]),
+            #[cfg(feature = "elements")]
+            V::CheckSigFromStack(pk, msg) => (Fragment::CheckSigFromStack(pk, msg), vec![]),
+            V::CheckSigHash(hash) => (Fragment::CheckSigHash(hash), vec![]),
+            V::Csv(n) => (Fragment::Csv(n), vec![]),
+            V::Raw(ref script) => (Fragment::Raw(script.clone()), vec![]),
+            V::HashEqual(hash) => (Fragment::HashEqual(hash), vec![]),
+            V::Hash160Equal(hash) => (Fragment::Hash160Equal(hash), vec![]),
+            V::Ripemd160Equal(hash) => (Fragment::Ripemd160Equal(hash), vec![]),
+            V::Hash256Equal(hash) => (Fragment::Hash256Equal(hash), vec![]),
+            V::Threshold(k, ref sube, ref subw) => {
+                let mut subs = vec![&**sube as &dyn AstElem];
+                subs.extend(subw.iter().map(|w| w as &dyn AstElem));
+                (Fragment::Threshold(k), subs)
+            }
+            V::And(ref left, ref right) => (Fragment::And, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            V::ParallelOr(ref left, ref right) => (Fragment::ParallelOr, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            V::SwitchOr(ref left, ref right) => (Fragment::SwitchOr, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            V::SwitchOrT(ref left, ref right) => (Fragment::SwitchOr, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            V::CascadeOr(ref left, ref right) => (Fragment::CascadeOr, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
         }
     }
-}
-
-macro_rules! compare_rules(
-    ($sat_prob:expr, $left:expr, $right:expr;
-     $($L:ident: $lty:ident, $lweight:expr; $R:ident: $rty:ident, $rweight:expr; $pk_cost:expr, $sat_cost:expr, $dissat_cost:expr; $result:expr;)*
-    ) => ({
-        let mut ret = vec![];
-        $({
-        #[allow(non_snake_case)]
-        let $L = $lty::from_descriptor($left, $lweight);
-        #[allow(non_snake_case)]
-        let $R = $rty::from_descriptor($right, $rweight);
-
-        ret.push(Cost {
-            ast: $result,
-            pk_cost: $pk_cost,
-            sat_cost: $sat_cost,
-            dissat_cost: $dissat_cost,
-        });
-        })*
 
-        let last = ret.pop().unwrap();
-        ret.into_iter().fold(last, |acc, n| min_cost(acc, n, $sat_prob, |x| x))
-    })
-);
-
-impl E {
-    fn from_descriptor(desc: &Descriptor<secp256k1::PublicKey>, satisfaction_probability: f64) -> Cost<E> {
-        match *desc {
-            Descriptor::Key(ref key) => {
-                Cost {
-                    ast: E::CheckSig(key.clone()),
-                    pk_cost: 35,
-                    sat_cost: 73,
-                    dissat_cost: 1,
-                }
-            },
-            Descriptor::KeyHash(ref key) => {
-                let hash = Hash160::from_data(&key.serialize()[..]);
-                let standard = Cost {
-                    ast: E::CheckSigHash(hash),
-                    pk_cost: 25,
-                    sat_cost: 34 + 73,
-                    dissat_cost: 34 + 1,
-                };
-                let cheap_dissat = Cost {
-                    ast: E::CheckSigHashF(hash),
-                    pk_cost: 29,
-                    sat_cost: 34 + 73,
-                    dissat_cost: 1,
-                };
-                min_cost(standard, cheap_dissat, satisfaction_probability, |x|x)
-            }
-            Descriptor::Multi(k, ref keys) => {
-                let num_cost = match(k > 16, keys.len() > 16) {
-                    (true, true) => 4,
-                    (false, true) => 3,
-                    (true, false) => 3,
-                    (false, false) => 2,
-                };
-                let standard = Cost {
-                    ast: E::CheckMultiSig(k, keys.clone()),
-                    pk_cost: num_cost + 34 * keys.len() + 1,
-                    sat_cost: 1 + 73*k,
-                    dissat_cost: 1 + k,
-                };
-                let cheap_dissat = Cost {
-                    ast: E::CheckMultiSigF(k, keys.clone()),
-                    pk_cost: num_cost + 34 * keys.len() + 5,
-                    sat_cost: 1 + 73*k,
-                    dissat_cost: 1,
-                };
-                min_cost(standard, cheap_dissat, satisfaction_probability, |x|x)
-            }
-            Descriptor::Time(_) => {
-                let f = F::from_descriptor(desc, 1.0);
-                Cost {
-                    ast: E::CastF(Box::new(f.ast)),
-                    pk_cost: f.pk_cost + 6,
-                    sat_cost: 1,
-                    dissat_cost: 2,
-                }
-            }
-            Descriptor::Hash(hash) => {
-                Cost {
-                    ast: E::HashEqual(hash),
-                    pk_cost: 31,
-                    sat_cost: 33,
-                    dissat_cost: 1,
-                }
-            }
-            Descriptor::Threshold(k, ref exprs) => {
-                let num_cost = script::Builder::new().push_int(k as i64).into_script().len();
-                if exprs.is_empty() {
-                    panic!("Cannot have empty threshold in a descriptor");
-                }
-
-                let e = E::from_descriptor(&exprs[0], satisfaction_probability * k as f64 / exprs.len() as f64);
-                let mut pk_cost = 1 + num_cost + e.pk_cost;
-                let mut sat_cost = e.sat_cost;
-                let mut dissat_cost = e.dissat_cost;
-                let mut ws = vec![];
-
-                for expr in &exprs[1..] {
-                    let w = W::from_descriptor(expr, satisfaction_probability * k as f64 / exprs.len() as f64);
-                    pk_cost += w.pk_cost;
-                    sat_cost += w.sat_cost;
-                    dissat_cost += w.dissat_cost;
-                    ws.push(w.ast);
-                }
-
-                Cost {
-                    ast: E::Threshold(k, Box::new(e.ast), ws),
-                    pk_cost: pk_cost,
-                    sat_cost: sat_cost * k / exprs.len(),  // TODO is simply averaging here the right thing to do?
-                    dissat_cost: dissat_cost * k / exprs.len(),
-                }
-            }
-            Descriptor::And(ref left, ref right) => {
-                compare_rules!(satisfaction_probability, left, right;
-                    // e1 w2 BOOLAND
-                    L: E, satisfaction_probability; R: W, satisfaction_probability;
-                    L.pk_cost + R.pk_cost + 1,
-                    L.sat_cost + R.sat_cost,
-                    L.dissat_cost + R.dissat_cost;
-                    E::ParallelAnd(Box::new(L.ast), Box::new(R.ast));
-                    // e2 w1 BOOLAND
-                    L: W, satisfaction_probability; R: E, satisfaction_probability;
-                    L.pk_cost + R.pk_cost + 1,
-                    L.sat_cost + R.sat_cost,
-                    L.dissat_cost + R.dissat_cost;
-                    E::ParallelAnd(Box::new(R.ast), Box::new(L.ast));
-                    // e1 IF f2 ELSE 0 ENDIF
-                    L: E, satisfaction_probability; R: F, 1.0;
-                    L.pk_cost + R.pk_cost + 4,
-                    L.sat_cost + R.sat_cost,
-                    L.dissat_cost;
-                    E::CascadeAnd(Box::new(L.ast), Box::new(R.ast));
-                    // e2 IF f1 ELSE 0 ENDIF
-                    L: F, 1.0; R: E, satisfaction_probability;
-                    L.pk_cost + R.pk_cost + 4,
-                    L.sat_cost + R.sat_cost,
-                    R.dissat_cost;
-                    E::CascadeAnd(Box::new(R.ast), Box::new(L.ast));
-                    // SIZE EQUALVERIFY IFDUP NOTIF v1 f2 ENDIF
-                    L: V, 1.0; R: F, 1.0;
-                    L.pk_cost + R.pk_cost + 6,
-                    L.sat_cost + R.sat_cost + 1,
-                    2;
-                    E::CastF(Box::new(F::And(Box::new(L.ast), Box::new(R.ast))));
-                    // SIZE EQUALVERIFY IFDUP NOTIF v2 f1 ENDIF
-                    L: F, 1.0; R: V, 1.0;
-                    L.pk_cost + R.pk_cost + 6,
-                    L.sat_cost + R.sat_cost + 1,
-                    2;
-                    E::CastF(Box::new(F::And(Box::new(R.ast), Box::new(L.ast))));
-                )
-            }
-            Descriptor::Or(ref left, ref right) => {
-                let e = compare_rules!(satisfaction_probability, left, right;
-                    // e1 w2 BOOLOR
-                    L: E, satisfaction_probability / 2.0; R: W, satisfaction_probability / 2.0;
-                    L.pk_cost + R.pk_cost + 1,
-                    (L.sat_cost + R.sat_cost + L.dissat_cost + R.dissat_cost) / 2,
-                    L.dissat_cost + R.dissat_cost;
-                    E::ParallelOr(Box::new(L.ast), Box::new(R.ast));
-                    // e2 w1 BOOLOR
-                    L: W, satisfaction_probability / 2.0; R: E, satisfaction_probability / 2.0;
-                    L.pk_cost + R.pk_cost + 1,
-                    (L.sat_cost + R.sat_cost + L.dissat_cost + R.dissat_cost) / 2,
-                    L.dissat_cost + R.dissat_cost;
-                    E::ParallelOr(Box::new(R.ast), Box::new(L.ast));
-                );
-                let f = {
-                    let fcost = F::from_descriptor(desc, satisfaction_probability);
-                    Cost {
-                        ast: E::CastF(Box::new(fcost.ast)),
-                        pk_cost: fcost.pk_cost + 6,
-                        sat_cost: 1 + fcost.sat_cost,
-                        dissat_cost: 2,
-                    }
-                };
-                min_cost(e, f, satisfaction_probability, |x|x)
-            }
-            Descriptor::AsymmetricOr(ref left, ref right) => {
-                let e = compare_rules!(satisfaction_probability, left, right;
-                    // e1 w2 BOOLOR
-                    L: E, satisfaction_probability; R: W, 0.0;
-                    L.pk_cost + R.pk_cost + 1,
-                    L.sat_cost + R.dissat_cost,
-                    L.dissat_cost + R.dissat_cost;
-                    E::ParallelOr(Box::new(L.ast), Box::new(R.ast));
-                    // e2 w1 BOOLOR
-                    L: W, satisfaction_probability; R: E, 0.0;
-                    L.pk_cost + R.pk_cost + 1,
-                    L.sat_cost + R.dissat_cost,
-                    L.dissat_cost + R.dissat_cost;
-                    E::ParallelOr(Box::new(R.ast), Box::new(L.ast));
-                );
-                let f = {
-                    let fcost = F::from_descriptor(desc, satisfaction_probability);
-                    Cost {
-                        ast: E::CastF(Box::new(fcost.ast)),
-                        pk_cost: fcost.pk_cost + 6,
-                        sat_cost: 1 + fcost.sat_cost,
-                        dissat_cost: 2,
-                    }
-                };
-                min_cost(e, f, satisfaction_probability, |x|x)
-            }
-            Descriptor::Wpkh(_) | Descriptor::Sh(_) | Descriptor::Wsh(_) => {
-                // handled at at the ParseTree::from_descriptor layer
-                unreachable!()
-            }
+    fn policy(&self) -> Policy {
+        match *self {
+            V::CheckSig(ref pk) => Policy::Key(*pk),
+            V::CheckSigAggregate(_, ref keys) => Policy::multi(keys.len(), keys),
+            V::CheckMultiSig(k, ref keys) | V::CheckSigAdd(k, ref keys) => Policy::multi(k, keys),
+            #[cfg(feature = "elements")]
+            V::CheckSigFromStack(pk, msg) => Policy::MessageSig(pk, msg),
+            V::CheckSigHash(hash) => Policy::KeyHash(hash),
+            V::Csv(n) => Policy::Csv(n),
+            V::Raw(ref script) => Policy::Raw(script.clone()),
+            V::HashEqual(hash) => Policy::Sha256(hash),
+            V::Hash160Equal(hash) => Policy::Hash160(hash),
+            V::Ripemd160Equal(hash) => Policy::Ripemd160(hash),
+            V::Hash256Equal(hash) => Policy::Hash256(hash),
+            V::Threshold(k, ref sube, ref subw) => Policy::threshold(k, sube, subw),
+            V::And(ref left, ref right) => Policy::and(left.policy(), right.policy()),
+            V::ParallelOr(ref left, ref right) => Policy::or(left.policy(), right.policy()),
+            V::SwitchOr(ref left, ref right) => Policy::or(left.policy(), right.policy()),
+            V::SwitchOrT(ref left, ref right) => Policy::or(left.policy(), right.policy()),
+            V::CascadeOr(ref left, ref right) => Policy::or(left.policy(), right.policy()),
         }
     }
 
-    fn dissatisfy(
-        &self,
-        pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
-    ) -> Result<Vec<Vec<u8>>, Error> {
+    fn malleability(&self, issues: &mut Vec<Malleability>) -> bool {
         match *self {
-            E::CheckSig(..) => Ok(vec![vec![]]),
-            E::CheckSigHash(hash) | E::CheckSigHashF(hash) => {
-                if let Some(pk) = pkh_map.get(&hash) {
-                    Ok(vec![
-                        vec![],
-                        pk.serialize()[..].to_owned(),
-                    ])
-                } else {
-                    Err(Error::MissingPubkey(hash))
+            V::CheckSig(..) | V::CheckSigAggregate(..) | V::CheckSigHash(..) => true,
+            V::CheckMultiSig(k, _) | V::CheckSigAdd(k, _) => k > 0,
+            // the signature does not commit to the spending transaction, and may well
+            // be public, so does not stop third parties from satisfying the fragment
+            #[cfg(feature = "elements")]
+            V::CheckSigFromStack(..) => false,
+            V::Csv(..) | V::Raw(..) |
+            V::HashEqual(..) | V::Hash160Equal(..) |
+            V::Ripemd160Equal(..) | V::Hash256Equal(..) => false,
+            V::Threshold(k, ref sube, ref subw) => {
+                let mut subs = vec![sube.malleability(issues)];
+                for sub in subw {
+                    subs.push(sub.malleability(issues));
                 }
+                malleability_threshold(k, &subs, self, issues)
             }
-            E::CheckMultiSig(k, _) | E::CheckMultiSigF(k, _) => {
-                Ok(vec![vec![]; k + 1])
+            V::And(ref left, ref right) => {
+                let left = left.malleability(issues);
+                right.malleability(issues) || left
             }
-            E::HashEqual(..) => Ok(vec![vec![]]),
-            E::Threshold(_, ref sube, ref subw) => {
-                let mut ret = sube.dissatisfy(pkh_map)?;
-                for sub in subw {
-                    ret.extend(sub.dissatisfy(pkh_map)?);
-                }
-                Ok(ret)
+            V::ParallelOr(ref left, ref right) => {
+                let left = left.malleability(issues);
+                let right = right.malleability(issues);
+                malleability_or(left, right, self, issues)
             }
-            E::ParallelAnd(ref left, ref right) => {
-                let mut ret = left.dissatisfy(pkh_map)?;
-                ret.extend(right.dissatisfy(pkh_map)?);
-                Ok(ret)
+            V::SwitchOr(ref left, ref right) => {
+                let left = left.malleability(issues);
+                let right = right.malleability(issues);
+                malleability_or(left, right, self, issues)
             }
-            E::CascadeAnd(ref left, _) => left.dissatisfy(pkh_map),
-            E::CascadeOr(ref left, ref right) => {
-                let mut ret = left.dissatisfy(pkh_map)?;
-                ret.extend(right.dissatisfy(pkh_map)?);
-                Ok(ret)
+            V::SwitchOrT(ref left, ref right) => {
+                let left = left.malleability(issues);
+                let right = right.malleability(issues);
+                malleability_or(left, right, self, issues)
             }
-            E::ParallelOr(ref left, ref right) => {
-                let mut ret = left.dissatisfy(pkh_map)?;
-                ret.extend(right.dissatisfy(pkh_map)?);
-                Ok(ret)
+            V::CascadeOr(ref left, ref right) => {
+                let left = left.malleability(issues);
+                let right = right.malleability(issues);
+                malleability_or(left, right, self, issues)
             }
-            E::CastF(..) => Ok(vec![])
         }
     }
-}
-
-impl fmt::Display for W {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let script = self.serialize(script::Builder::new()).into_script();
-        fmt::Display::fmt(&script, f)
-    }
-}
 
-impl AstElem for W {
-    fn into_w(self: Box<W>) -> Result<Box<W>, Error> { Ok(self) }
-    fn is_w(&self) -> bool { true }
-
-    fn serialize(&self, mut builder: script::Builder) -> script::Builder {
+    fn max_satisfaction_items(&self) -> usize {
         match *self {
-            W::CheckSig(pk) => {
-                builder.push_opcode(opcodes::All::OP_SWAP)
-                       .push_slice(&pk.serialize()[..])
-                       .push_opcode(opcodes::All::OP_CHECKSIG)
+            V::CheckSig(..) | V::CheckSigAggregate(..) => 1,
+            V::CheckMultiSig(k, _) => k + 1,
+            V::CheckSigAdd(_, ref keys) => keys.len(),
+            #[cfg(feature = "elements")]
+            V::CheckSigFromStack(..) => 1,
+            V::CheckSigHash(..) => 2,
+            V::Csv(..) => 0,
+            // the satisfaction of a raw script is unknown
+            V::Raw(..) => 0,
+            V::HashEqual(..) | V::Hash160Equal(..) |
+            V::Ripemd160Equal(..) | V::Hash256Equal(..) => 1,
+            V::Threshold(k, ref sube, ref subw) => {
+                let mut sats = vec![sube.max_satisfaction_items()];
+                let mut dissats = vec![sube.max_dissatisfaction_items()];
+                for sub in subw {
+                    sats.push(sub.max_satisfaction_items());
+                    dissats.push(sub.max_dissatisfaction_items());
+                }
+                max_threshold(k, &sats, &dissats)
             }
-            W::HashEqual(hash) => {
-                builder.push_opcode(opcodes::All::OP_SWAP)
-                       .push_opcode(opcodes::All::OP_SIZE)
-                       .push_opcode(opcodes::All::OP_IF)
-                       .push_opcode(opcodes::All::OP_SIZE)
-                       .push_int(32)
-                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                       .push_opcode(opcodes::All::OP_SHA256)
-                       .push_slice(&hash[..])
-                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                       .push_int(1)
-                       .push_opcode(opcodes::All::OP_ENDIF)
+            V::And(ref left, ref right) => {
+                left.max_satisfaction_items() + right.max_satisfaction_items()
             }
-            W::Csv(n) => {
-                builder.push_opcode(opcodes::All::OP_SWAP)
-                       .push_opcode(opcodes::All::OP_SIZE)
-                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                       .push_opcode(opcodes::All::OP_IF)
-                       .push_int(n as i64)
-                       .push_opcode(opcodes::OP_CSV)
-                       .push_opcode(opcodes::All::OP_ELSE)
-                       .push_int(0)
-                       .push_opcode(opcodes::All::OP_ENDIF)
+            V::ParallelOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_items() + right.max_dissatisfaction_items(),
+                    left.max_dissatisfaction_items() + right.max_satisfaction_items(),
+                )
             }
-            W::CastE(ref expr) => {
-                builder = builder.push_opcode(opcodes::All::OP_TOALTSTACK);
-                expr.serialize(builder).push_opcode(opcodes::All::OP_FROMALTSTACK)
+            V::SwitchOr(ref left, ref right) => {
+                1 + cmp::max(left.max_satisfaction_items(), right.max_satisfaction_items())
+            }
+            V::SwitchOrT(ref left, ref right) => {
+                1 + cmp::max(left.max_satisfaction_items(), right.max_satisfaction_items())
+            }
+            V::CascadeOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_items(),
+                    left.max_dissatisfaction_items() + right.max_satisfaction_items(),
+                )
             }
         }
     }
 
-    fn satisfy(
-        &self,
-        key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
-        pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
-        hash_map: &HashMap<Sha256dHash, [u8; 32]>,
-        age: u32,
-    ) -> Result<Vec<Vec<u8>>, Error> {
-        match *self {
-            W::CheckSig(ref pk) => satisfy_checksig(pk, key_map),
-            W::HashEqual(ref hash) => satisfy_hashequal(hash, hash_map),
-            W::Csv(n) => satisfy_csv(n, age).map(|_| vec![vec![1]]),
-            W::CastE(ref e) => e.satisfy(key_map, pkh_map, hash_map, age)
-        }
-    }
-
-    fn required_keys(&self) -> Vec<secp256k1::PublicKey> {
+    fn max_satisfaction_size(&self) -> usize {
         match *self {
-            W::CheckSig(ref pk) => vec![*pk],
-            W::HashEqual(..) => vec![],
-            W::Csv(..) => vec![],
-            W::CastE(ref e) => e.required_keys(),
-        }
-    }
-}
-
-impl W {
-    fn from_descriptor(desc: &Descriptor<secp256k1::PublicKey>, satisfaction_probability: f64) -> Cost<W> {
-        match *desc {
-            Descriptor::Key(ref key) => {
-                Cost {
-                    ast: W::CheckSig(key.clone()),
-                    pk_cost: 36,
-                    sat_cost: 73,
-                    dissat_cost: 1,
+            V::CheckSig(..) | V::CheckSigAggregate(..) => 74,
+            V::CheckMultiSig(k, _) => 1 + 74 * k,
+            V::CheckSigAdd(k, ref keys) => 74 * k + keys.len().saturating_sub(k),
+            #[cfg(feature = "elements")]
+            V::CheckSigFromStack(..) => 73,
+            V::CheckSigHash(..) => 74 + 34,
+            V::Csv(..) => 0,
+            // the satisfaction of a raw script is unknown
+            V::Raw(..) => 0,
+            V::HashEqual(..) | V::Hash160Equal(..) |
+            V::Ripemd160Equal(..) | V::Hash256Equal(..) => 33,
+            V::Threshold(k, ref sube, ref subw) => {
+                let mut sats = vec![sube.max_satisfaction_size()];
+                let mut dissats = vec![sube.max_dissatisfaction_size()];
+                for sub in subw {
+                    sats.push(sub.max_satisfaction_size());
+                    dissats.push(sub.max_dissatisfaction_size());
                 }
+                max_threshold(k, &sats, &dissats)
             }
-            Descriptor::Hash(hash) => {
-                Cost {
-                    ast: W::HashEqual(hash),
-                    pk_cost: 32,
-                    sat_cost: 33,
-                    dissat_cost: 1,
-                }
+            V::And(ref left, ref right) => {
+                left.max_satisfaction_size() + right.max_satisfaction_size()
             }
-            Descriptor::Time(n) => {
-                let num_cost = script::Builder::new().push_int(n as i64).into_script().len();
-                Cost {
-                    ast: W::Csv(n),
-                    pk_cost: 8 + num_cost,
-                    sat_cost: 1,
-                    dissat_cost: 2,
-                }
+            V::ParallelOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_size() + right.max_dissatisfaction_size(),
+                    left.max_dissatisfaction_size() + right.max_satisfaction_size(),
+                )
             }
-            Descriptor::KeyHash(_) |
-            Descriptor::Multi(_, _) | Descriptor::And(_, _) |
-            Descriptor::Or(_, _) | Descriptor::AsymmetricOr(_, _) |
-            Descriptor::Threshold(_, _) => {
-                let e = E::from_descriptor(desc, satisfaction_probability);
-                Cost {
-                    ast: W::CastE(Box::new(e.ast)),
-                    pk_cost: e.pk_cost + 2,
-                    sat_cost: e.sat_cost,
-                    dissat_cost: e.dissat_cost,
-                }
+            V::SwitchOr(ref left, ref right) => {
+                2 + cmp::max(left.max_satisfaction_size(), right.max_satisfaction_size())
             }
-            Descriptor::Wpkh(_) | Descriptor::Sh(_) | Descriptor::Wsh(_) => {
-                // handled at at the ParseTree::from_descriptor layer
-                unreachable!()
+            V::SwitchOrT(ref left, ref right) => {
+                2 + cmp::max(left.max_satisfaction_size(), right.max_satisfaction_size())
+            }
+            V::CascadeOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_size(),
+                    left.max_dissatisfaction_size() + right.max_satisfaction_size(),
+                )
             }
-        }
-    }
-
-    fn dissatisfy(
-        &self,
-        pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
-    ) -> Result<Vec<Vec<u8>>, Error> {
-        match *self {
-            W::CheckSig(..) => Ok(vec![]),
-            W::HashEqual(..) => Ok(vec![]),
-            W::Csv(..) => Ok(vec![]),
-            W::CastE(ref e) => e.dissatisfy(pkh_map)
         }
     }
 }
 
-impl fmt::Display for F {
+impl fmt::Display for T {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let script = self.serialize(script::Builder::new()).into_script();
         fmt::Display::fmt(&script, f)
     }
 }
 
-impl AstElem for F {
-    fn into_f(self: Box<F>) -> Result<Box<F>, Error> { Ok(self) }
-    fn into_t(self: Box<F>) -> Result<Box<T>, Error> { Ok(Box::new(T::CastF(self))) }
-    fn is_f(&self) -> bool { true }
+impl AstElem for T {
+    fn into_t(self: Box<T>) -> Result<Arc<T>, Error> { Ok(Arc::from(self)) }
     fn is_t(&self) -> bool { true }
 
-    fn serialize(&self, mut builder: script::Builder) -> script::Builder {
+    fn serialize_pieces<'a>(&'a self, mut builder: script::Builder, pieces: &mut Vec<Piece<'a>>) -> script::Builder {
         match *self {
-            F::CheckSig(ref pk) => {
-                builder.push_slice(&pk.serialize()[..])
-                       .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
-                       .push_int(1)
+            T::HashEqual(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                       .push_opcode(opcodes::All::OP_SHA256)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUAL)
             }
-            F::CheckSigHash(hash) => {
-                builder.push_opcode(opcodes::All::OP_DUP)
+            T::Hash160Equal(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
+                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
                        .push_opcode(opcodes::All::OP_HASH160)
                        .push_slice(&hash[..])
-                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                       .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
-                       .push_int(1)
-            }
-            F::CheckMultiSig(k, ref pks) => {
-                builder = builder.push_int(k as i64);
-                for pk in pks {
-                    builder = builder.push_slice(&pk.serialize()[..]);
-                }
-                builder.push_int(pks.len() as i64)
-                       .push_opcode(opcodes::All::OP_CHECKMULTISIGVERIFY)
-                       .push_int(1)
-            }
-            F::Csv(n) => {
-                builder.push_int(n as i64)
-                       .push_opcode(opcodes::OP_CSV)
+                       .push_opcode(opcodes::All::OP_EQUAL)
             }
-            F::HashEqual(hash) => {
+            T::Ripemd160Equal(hash) => {
                 builder.push_opcode(opcodes::All::OP_SIZE)
                        .push_int(32)
-                       .push_opcode(opcodes::All::OP_EQUAL)
-                       .push_opcode(opcodes::All::OP_SHA256)
-                       .push_slice(&hash[..])
                        .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                       .push_int(1)
+                       .push_opcode(opcodes::All::OP_RIPEMD160)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUAL)
             }
-            F::Threshold(k, ref e, ref ws) => {
-                builder = e.serialize(builder);
-                for w in ws {
-                    builder = w.serialize(builder).push_opcode(opcodes::All::OP_ADD);
-                }
-                builder.push_int(k as i64)
+            T::Hash256Equal(hash) => {
+                builder.push_opcode(opcodes::All::OP_SIZE)
+                       .push_int(32)
                        .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                       .push_int(1)
-            }
-            F::And(ref left, ref right) => {
-                builder = left.serialize(builder);
-                right.serialize(builder)
-            }
-            F::ParallelOr(ref left, ref right) => {
-                builder = left.serialize(builder);
-                builder = right.serialize(builder);
-                builder.push_opcode(opcodes::All::OP_BOOLOR)
-                       .push_opcode(opcodes::All::OP_VERIFY)
-                       .push_int(1)
+                       .push_opcode(opcodes::All::OP_HASH256)
+                       .push_slice(&hash[..])
+                       .push_opcode(opcodes::All::OP_EQUAL)
             }
-            F::SwitchOr(ref left, ref right) => {
-                builder = builder.push_opcode(opcodes::All::OP_SIZE)
-                                 .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                                 .push_opcode(opcodes::All::OP_IF);
-                builder = left.serialize(builder);
-                builder = builder.push_opcode(opcodes::All::OP_ELSE);
-                builder = right.serialize(builder);
-                builder.push_opcode(opcodes::All::OP_ENDIF)
+            T::And(ref vexpr, ref top) => {
+                builder = defer(builder, &**vexpr, pieces);
+                defer(builder, &**top, pieces)
             }
-            F::SwitchOrV(ref left, ref right) => {
+            T::SwitchOr(ref left, ref right) => {
                 builder = builder.push_opcode(opcodes::All::OP_SIZE)
                                  .push_opcode(opcodes::All::OP_EQUALVERIFY)
                                  .push_opcode(opcodes::All::OP_IF);
-                builder = left.serialize(builder);
+                builder = defer(builder, &**left, pieces);
                 builder = builder.push_opcode(opcodes::All::OP_ELSE);
-                builder = right.serialize(builder);
+                builder = defer(builder, &**right, pieces);
                 builder.push_opcode(opcodes::All::OP_ENDIF)
-                       .push_int(1)
             }
-            F::CascadeOr(ref left, ref right) => {
-                builder = left.serialize(builder);
+            T::CascadeOr(ref left, ref right) => {
+                builder = defer(builder, &**left, pieces);
                 builder = builder.push_opcode(opcodes::All::OP_IFDUP)
                                  .push_opcode(opcodes::All::OP_NOTIF);
-                builder = right.serialize(builder);
-                builder.push_opcode(opcodes::All::OP_ENDIF)
-            }
-            F::CascadeOrV(ref left, ref right) => {
-                builder = left.serialize(builder);
-                builder = builder.push_opcode(opcodes::All::OP_NOTIF);
-                builder = right.serialize(builder);
+                builder = defer(builder, &**right, pieces);
                 builder.push_opcode(opcodes::All::OP_ENDIF)
-                       .push_int(1)
             }
+            T::CastE(ref expr) => defer(builder, &**expr, pieces),
+            T::CastF(ref expr) => defer(builder, &**expr, pieces),
         }
     }
 
-    fn satisfy(
-        &self,
-        key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
-        pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
-        hash_map: &HashMap<Sha256dHash, [u8; 32]>,
-        age: u32,
-    ) -> Result<Vec<Vec<u8>>, Error> {
+    fn satisfy(&self, satisfier: &dyn Satisfier, strategy: Strategy) -> Result<Vec<Vec<u8>>, Error> {
         match *self {
-            F::CheckSig(ref pk) => satisfy_checksig(pk, key_map),
-            F::CheckMultiSig(k, ref keys) => satisfy_checkmultisig(k, keys, key_map),
-            F::CheckSigHash(ref hash) => satisfy_checksighash(hash, key_map, pkh_map),
-            F::Csv(n) => satisfy_csv(n, age),
-            F::HashEqual(ref hash) => satisfy_hashequal(hash, hash_map),
-            F::Threshold(k, ref sube, ref subw) => satisfy_threshold(k, sube, subw, key_map, pkh_map, hash_map, age),
-            F::And(ref left, ref right) => {
-                let mut ret = left.satisfy(key_map, pkh_map, hash_map, age)?;
-                ret.extend(right.satisfy(key_map, pkh_map, hash_map, age)?);
+            T::HashEqual(ref hash) => satisfy_hashequal(hash, satisfier),
+            T::Hash160Equal(ref hash) => satisfy_hash160equal(hash, satisfier),
+            T::Ripemd160Equal(ref hash) => satisfy_ripemd160equal(hash, satisfier),
+            T::Hash256Equal(ref hash) => satisfy_hash256equal(hash, satisfier),
+            T::And(ref left, ref right) => {
+                let lsat = left.satisfy(satisfier, strategy)?;
+                let mut ret = right.satisfy(satisfier, strategy)?;
+                ret.extend(lsat);
                 Ok(ret)
             }
-            F::ParallelOr(ref left, ref right) => satisfy_parallel_or(left, right, key_map, pkh_map, hash_map, age),
-            F::SwitchOr(ref left, ref right) => satisfy_switch_or(left, right, key_map, pkh_map, hash_map, age),
-            F::SwitchOrV(ref left, ref right) => satisfy_switch_or(left, right, key_map, pkh_map, hash_map, age),
-            F::CascadeOr(ref left, ref right) => satisfy_cascade_or(left, right, key_map, pkh_map, hash_map, age),
-            F::CascadeOrV(ref left, ref right) => satisfy_cascade_or(left, right, key_map, pkh_map, hash_map, age),
+            T::SwitchOr(ref left, ref right) => satisfy_switch_or(left, right, satisfier, strategy),
+            T::CastE(ref e) => e.satisfy(satisfier, strategy),
+            T::CastF(ref f) => f.satisfy(satisfier, strategy),
+            T::CascadeOr(ref left, ref right) => satisfy_cascade_or(left, right, satisfier, strategy),
         }
     }
 
     fn required_keys(&self) -> Vec<secp256k1::PublicKey> {
         match *self {
-            F::CheckSig(pk) => vec![pk],
-            F::CheckMultiSig(_, ref keys) => keys.clone(),
-            F::CheckSigHash(..) | F::Csv(..) | F::HashEqual(..) => vec![],
-            F::Threshold(_, ref sube, ref subw) => {
-                let mut ret = sube.required_keys();
-                for sub in subw {
-                    ret.extend(sub.required_keys());
-                }
-                ret
-            }
-            F::And(ref left, ref right) => {
+            T::HashEqual(..) | T::Hash160Equal(..) |
+            T::Ripemd160Equal(..) | T::Hash256Equal(..) => vec![],
+            T::And(ref left, ref right) => {
                 let mut ret = left.required_keys();
                 ret.extend(right.required_keys());
                 ret
             }
-            F::ParallelOr(ref left, ref right) => {
+            T::SwitchOr(ref left, ref right) => {
                 let mut ret = left.required_keys();
                 ret.extend(right.required_keys());
                 ret
             }
-            F::SwitchOr(ref left, ref right) => {
+            T::CascadeOr(ref left, ref right) => {
                 let mut ret = left.required_keys();
                 ret.extend(right.required_keys());
                 ret
             }
-            F::SwitchOrV(ref left, ref right) => {
-                let mut ret = left.required_keys();
-                ret.extend(right.required_keys());
-                ret
+            T::CastE(ref sub) => sub.required_keys(),
+            T::CastF(ref sub) => sub.required_keys(),
+        }
+    }
+
+    fn expr_type(&self) -> ExprType { ExprType::T }
+
+    fn fragment(&self) -> (Fragment, Vec<&dyn AstElem>) {
+        match *self {
+            T::HashEqual(hash) => (Fragment::HashEqual(hash), vec![]),
+            T::Hash160Equal(hash) => (Fragment::Hash160Equal(hash), vec![]),
+            T::Ripemd160Equal(hash) => (Fragment::Ripemd160Equal(hash), vec![]),
+            T::Hash256Equal(hash) => (Fragment::Hash256Equal(hash), vec![]),
+            T::And(ref left, ref right) => (Fragment::And, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            T::SwitchOr(ref left, ref right) => (Fragment::SwitchOr, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            T::CascadeOr(ref left, ref right) => (Fragment::CascadeOr, vec![&**left as &dyn AstElem, &**right as &dyn AstElem]),
+            T::CastE(ref sub) => (Fragment::Cast, vec![&**sub as &dyn AstElem]),
+            T::CastF(ref sub) => (Fragment::Cast, vec![&**sub as &dyn AstElem]),
+        }
+    }
+
+    fn policy(&self) -> Policy {
+        match *self {
+            T::HashEqual(hash) => Policy::Sha256(hash),
+            T::Hash160Equal(hash) => Policy::Hash160(hash),
+            T::Ripemd160Equal(hash) => Policy::Ripemd160(hash),
+            T::Hash256Equal(hash) => Policy::Hash256(hash),
+            T::And(ref left, ref right) => Policy::and(left.policy(), right.policy()),
+            T::SwitchOr(ref left, ref right) => Policy::or(left.policy(), right.policy()),
+            T::CascadeOr(ref left, ref right) => Policy::or(left.policy(), right.policy()),
+            T::CastE(ref sub) => sub.policy(),
+            T::CastF(ref sub) => sub.policy(),
+        }
+    }
+
+    fn malleability(&self, issues: &mut Vec<Malleability>) -> bool {
+        match *self {
+            T::HashEqual(..) | T::Hash160Equal(..) |
+            T::Ripemd160Equal(..) | T::Hash256Equal(..) => false,
+            T::And(ref left, ref right) => {
+                let left = left.malleability(issues);
+                right.malleability(issues) || left
             }
-            F::CascadeOr(ref left, ref right) => {
-                let mut ret = left.required_keys();
-                ret.extend(right.required_keys());
-                ret
+            T::SwitchOr(ref left, ref right) => {
+                let left = left.malleability(issues);
+                let right = right.malleability(issues);
+                malleability_or(left, right, self, issues)
             }
-            F::CascadeOrV(ref left, ref right) => {
-                let mut ret = left.required_keys();
-                ret.extend(right.required_keys());
-                ret
+            T::CascadeOr(ref left, ref right) => {
+                let left = left.malleability(issues);
+                let right = right.malleability(issues);
+                malleability_or(left, right, self, issues)
             }
+            T::CastE(ref e) => e.malleability(issues),
+            T::CastF(ref f) => f.malleability(issues),
         }
     }
-}
 
-impl F {
-    fn from_descriptor(desc: &Descriptor<secp256k1::PublicKey>, satisfaction_probability: f64) -> Cost<F> {
-        debug_assert_eq!(satisfaction_probability, 1.0);
-        match *desc {
-            Descriptor::Key(ref key) => {
-                Cost {
-                    ast: F::CheckSig(key.clone()),
-                    pk_cost: 36,
-                    sat_cost: 73,
-                    dissat_cost: 0,
-                }
-            }
-            Descriptor::KeyHash(ref key) => {
-                let hash = Hash160::from_data(&key.serialize()[..]);
-                Cost {
-                    ast: F::CheckSigHash(hash),
-                    pk_cost: 26,
-                    sat_cost: 34 + 73,
-                    dissat_cost: 0,
-                }
-            }
-            Descriptor::Multi(k, ref keys) => {
-                let num_cost = match(k > 16, keys.len() > 16) {
-                    (true, true) => 4,
-                    (false, true) => 3,
-                    (true, false) => 3,
-                    (false, false) => 2,
-                };
-                Cost {
-                    ast: F::CheckMultiSig(k, keys.clone()),
-                    pk_cost: num_cost + 34 * keys.len() + 2,
-                    sat_cost: 1 + 73*k,
-                    dissat_cost: 0,
-                }
-            }
-            Descriptor::Threshold(k, ref exprs) => {
-                let num_cost = script::Builder::new().push_int(k as i64).into_script().len();
-                if exprs.is_empty() {
-                    panic!("Cannot have empty threshold in a descriptor");
-                }
-
-                let e = E::from_descriptor(&exprs[0], satisfaction_probability * k as f64 / exprs.len() as f64);
-                let mut pk_cost = 2 + num_cost + e.pk_cost;
-                let mut sat_cost = e.sat_cost;
-                let mut dissat_cost = e.dissat_cost;
-                let mut ws = vec![];
-
-                for expr in &exprs[1..] {
-                    let w = W::from_descriptor(expr, satisfaction_probability * k as f64 / exprs.len() as f64);
-                    pk_cost += w.pk_cost;
-                    sat_cost += w.sat_cost;
-                    dissat_cost += w.dissat_cost;
-                    ws.push(w.ast);
-                }
-
-                Cost {
-                    ast: F::Threshold(k, Box::new(e.ast), ws),
-                    pk_cost: pk_cost,
-                    sat_cost: sat_cost * k / exprs.len(),  // TODO is simply averaging here the right thing to do?
-                    dissat_cost: dissat_cost * k / exprs.len(),
-                }
-            }
-            Descriptor::Time(n) => {
-                let num_cost = script::Builder::new().push_int(n as i64).into_script().len();
-                Cost {
-                    ast: F::Csv(n),
-                    pk_cost: 1 + num_cost,
-                    sat_cost: 0,
-                    dissat_cost: 0,
-                }
-            }
-            Descriptor::Hash(hash) => {
-                Cost {
-                    ast: F::HashEqual(hash),
-                    pk_cost: 28,
-                    sat_cost: 33,
-                    dissat_cost: 0,
-                }
-            }
-            Descriptor::And(ref left, ref right) => {
-                let vl = V::from_descriptor(left, satisfaction_probability);
-                let vr = V::from_descriptor(right, satisfaction_probability);
-                let fl = F::from_descriptor(left, satisfaction_probability);
-                let fr = F::from_descriptor(right, satisfaction_probability);
-
-                if vl.pk_cost + fr.pk_cost + vl.sat_cost + fr.sat_cost <
-                   vr.pk_cost + fl.pk_cost + vr.sat_cost + fl.sat_cost {
-                    Cost {
-                        ast: F::And(Box::new(vl.ast), Box::new(fr.ast)),
-                        pk_cost: vl.pk_cost + fr.pk_cost,
-                        sat_cost: vl.sat_cost + fr.sat_cost,
-                        dissat_cost: 0,
-                    }
-                } else {
-                    Cost {
-                        ast: F::And(Box::new(vr.ast), Box::new(fl.ast)),
-                        pk_cost: vr.pk_cost + fl.pk_cost,
-                        sat_cost: vr.sat_cost + fl.sat_cost,
-                        dissat_cost: 0,
-                    }
-                }
+    fn max_satisfaction_items(&self) -> usize {
+        match *self {
+            T::HashEqual(..) | T::Hash160Equal(..) |
+            T::Ripemd160Equal(..) | T::Hash256Equal(..) => 1,
+            T::And(ref left, ref right) => {
+                left.max_satisfaction_items() + right.max_satisfaction_items()
             }
-            Descriptor::Or(ref left, ref right) => {
-                compare_rules!(satisfaction_probability, left, right;
-                    // e1 w2 BOOLOR VERIFY 1
-                    L: E, satisfaction_probability / 2.0; R: W, satisfaction_probability / 2.0;
-                    L.pk_cost + R.pk_cost + 3,
-                    (L.sat_cost + R.sat_cost + L.dissat_cost + R.dissat_cost) / 2,
-                    0;
-                    F::ParallelOr(Box::new(L.ast), Box::new(R.ast));
-                    // e2 w1 BOOLOR VERIFY 1
-                    L: W, satisfaction_probability / 2.0; R: E, satisfaction_probability / 2.0;
-                    L.pk_cost + R.pk_cost + 3,
-                    (L.sat_cost + R.sat_cost + L.dissat_cost + R.dissat_cost) / 2,
-                    0;
-                    F::ParallelOr(Box::new(R.ast), Box::new(L.ast));
-
-                    // e1 IFDUP NOTIF f2 ENDIF
-                    L: E, satisfaction_probability / 2.0; R: F, 1.0;
-                    L.pk_cost + R.pk_cost + 3,
-                    (L.sat_cost + L.dissat_cost + R.sat_cost) / 2,
-                    0;
-                    F::CascadeOr(Box::new(L.ast), Box::new(R.ast));
-                    // e2 IFDUP NOTIF f1 ENDIF
-                    L: F, 1.0; R: E, satisfaction_probability / 2.0;
-                    L.pk_cost + R.pk_cost + 3,
-                    (R.sat_cost + R.dissat_cost + L.sat_cost) / 2,
-                    0;
-                    F::CascadeOr(Box::new(R.ast), Box::new(L.ast));
-
-                    // e1 NOTIF v2 ENDIF 1
-                    L: E, satisfaction_probability / 2.0; R: V, 1.0;
-                    L.pk_cost + R.pk_cost + 3,
-                    (L.sat_cost + L.dissat_cost + R.sat_cost) / 2,
-                    0;
-                    F::CascadeOrV(Box::new(L.ast), Box::new(R.ast));
-                    // e2 NOTIF v1 ENDIF 1
-                    L: V, 1.0; R: E, satisfaction_probability / 2.0;
-                    L.pk_cost + R.pk_cost + 3,
-                    (R.sat_cost + R.dissat_cost + L.sat_cost) / 2,
-                    0;
-                    F::CascadeOrV(Box::new(R.ast), Box::new(L.ast));
-
-                    // SIZE EQUALVERIFY IF f1 ELSE f2 ENDIF
-                    L: F, 1.0; R: F, 1.0;
-                    L.pk_cost + R.pk_cost + 5,
-                    (L.sat_cost + R.sat_cost + 3) / 2,
-                    0;
-                    F::SwitchOr(Box::new(L.ast), Box::new(R.ast));
-                    // SIZE EQUALVERIFY IF v1 ELSE v2 ENDIF 1
-                    L: V, 1.0; R: V, 1.0;
-                    L.pk_cost + R.pk_cost + 6,
-                    (L.sat_cost + R.sat_cost + 3) / 2,
-                    0;
-                    F::SwitchOrV(Box::new(L.ast), Box::new(R.ast));
-                )
+            T::SwitchOr(ref left, ref right) => {
+                1 + cmp::max(left.max_satisfaction_items(), right.max_satisfaction_items())
             }
-            Descriptor::AsymmetricOr(ref left, ref right) => {
-                compare_rules!(satisfaction_probability, left, right;
-                    // e1 w2 BOOLOR VERIFY 1
-                    L: E, satisfaction_probability; R: W, 0.0;
-                    L.pk_cost + R.pk_cost + 3,
-                    L.sat_cost + R.dissat_cost,
-                    0;
-                    F::ParallelOr(Box::new(L.ast), Box::new(R.ast));
-                    // e2 w1 BOOLOR VERIFY 1
-                    L: W, satisfaction_probability; R: E, 0.0;
-                    L.pk_cost + R.pk_cost + 3,
-                    L.sat_cost + R.dissat_cost,
-                    0;
-                    F::ParallelOr(Box::new(R.ast), Box::new(L.ast));
-
-                    // e1 IFDUP NOTIF f2 ENDIF
-                    L: E, satisfaction_probability; R: F, 1.0;
-                    L.pk_cost + R.pk_cost + 3,
-                    L.sat_cost,
-                    0;
-                    F::CascadeOr(Box::new(L.ast), Box::new(R.ast));
-                    // e2 IFDUP NOTIF f1 ENDIF
-                    L: F, 1.0; R: E, 0.0;
-                    L.pk_cost + R.pk_cost + 3,
-                    R.dissat_cost + L.sat_cost,
-                    0;
-                    F::CascadeOr(Box::new(R.ast), Box::new(L.ast));
-
-                    // e1 NOTIF v2 ENDIF 1
-                    L: E, satisfaction_probability; R: V, 1.0;
-                    L.pk_cost + R.pk_cost + 3,
-                    L.sat_cost,
-                    0;
-                    F::CascadeOrV(Box::new(L.ast), Box::new(R.ast));
-                    // e2 NOTIF v1 ENDIF 1
-                    L: V, 1.0; R: E, 0.0;
-                    L.pk_cost + R.pk_cost + 3,
-                    R.dissat_cost + L.sat_cost,
-                    0;
-                    F::CascadeOrV(Box::new(R.ast), Box::new(L.ast));
-
-                    // SIZE EQUALVERIFY IF f2 ELSE f1 ENDIF
-                    L: F, 1.0; R: F, 1.0;
-                    L.pk_cost + R.pk_cost + 5,
-                    L.sat_cost + 1,
-                    0;
-                    F::SwitchOr(Box::new(R.ast), Box::new(L.ast));
-                    // SIZE EQUALVERIFY IF v2 ELSE v1 ENDIF 1
-                    L: V, 1.0; R: V, 1.0;
-                    L.pk_cost + R.pk_cost + 6,
-                    L.sat_cost + 1,
-                    0;
-                    F::SwitchOrV(Box::new(R.ast), Box::new(L.ast));
+            T::CascadeOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_items(),
+                    left.max_dissatisfaction_items() + right.max_satisfaction_items(),
                 )
             }
-            Descriptor::Wpkh(_) | Descriptor::Sh(_) | Descriptor::Wsh(_) => {
-                // handled at at the ParseTree::from_descriptor layer
-                unreachable!()
+            T::CastE(ref e) => e.max_satisfaction_items(),
+            T::CastF(ref f) => f.max_satisfaction_items(),
+        }
+    }
+
+    fn max_satisfaction_size(&self) -> usize {
+        match *self {
+            T::HashEqual(..) | T::Hash160Equal(..) |
+            T::Ripemd160Equal(..) | T::Hash256Equal(..) => 33,
+            T::And(ref left, ref right) => {
+                left.max_satisfaction_size() + right.max_satisfaction_size()
+            }
+            T::SwitchOr(ref left, ref right) => {
+                2 + cmp::max(left.max_satisfaction_size(), right.max_satisfaction_size())
+            }
+            T::CascadeOr(ref left, ref right) => {
+                cmp::max(
+                    left.max_satisfaction_size(),
+                    left.max_dissatisfaction_size() + right.max_satisfaction_size(),
+                )
             }
+            T::CastE(ref e) => e.max_satisfaction_size(),
+            T::CastF(ref f) => f.max_satisfaction_size(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::util::hash::Sha256dHash;
+
+    use secp256k1;
+
+    fn pubkeys(n: usize) -> Vec<secp256k1::PublicKey> {
+        let mut ret = Vec::with_capacity(n);
+        let secp = secp256k1::Secp256k1::new();
+        let mut sk = [0; 32];
+        for i in 1..n+1 {
+            sk[0] = i as u8;
+            sk[1] = (i >> 8) as u8;
+            sk[2] = (i >> 16) as u8;
+
+            let pk = secp256k1::PublicKey::from_secret_key(
+                &secp,
+                &secp256k1::SecretKey::from_slice(&secp, &sk[..]).expect("secret key"),
+            ).expect("signing context");
+            ret.push(pk);
+        }
+        ret
+    }
+
+    /// Accepts each key's signature only
+    struct ByKey(::std::collections::HashMap<secp256k1::PublicKey, Vec<u8>>);
+
+    impl Checker for ByKey {
+        fn check_sig(&self, pk: &secp256k1::PublicKey, sig: &[u8]) -> bool {
+            self.0.get(pk).map(|expected| &expected[..] == sig) == Some(true)
+        }
+
+        fn check_older(&self, _: u32) -> bool {
+            true
+        }
+    }
+
+    /// A different signature for each key, and a checker accepting exactly those
+    fn signed_by_each(keys: &[secp256k1::PublicKey]) -> (::std::collections::HashMap<secp256k1::PublicKey, secp256k1::Signature>, ByKey) {
+        let secp = secp256k1::Secp256k1::new();
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let sigs: ::std::collections::HashMap<_, _> = keys.iter().enumerate().map(|(i, pk)| {
+            let sk = secp256k1::SecretKey::from_slice(&secp, &[i as u8 + 1; 32]).expect("secret key");
+            (*pk, secp.sign(&msg, &sk).expect("signing context"))
+        }).collect();
+        let checker = ByKey(sigs.iter().map(|(pk, sig)| (*pk, serialize_sig(sig, SigHashType::All))).collect());
+        (sigs, checker)
+    }
+
+    #[test]
+    fn satisfaction_stack_order() {
+        let keys = pubkeys(4);
+        let (mut sigs, checker) = signed_by_each(&keys);
+        let check = |tree: &ParseTree, sigs: &::std::collections::HashMap<_, _>, expected: &[Condition]| {
+            let sat = tree.satisfy(sigs).unwrap();
+            let exec = tree.interpret(&sat, &checker).unwrap_or_else(|e| panic!("{:?}: {:?}", tree, e));
+            assert_eq!(exec.branch, expected, "{:?}", tree);
+        };
+        let both = [Condition::Sig(keys[0]), Condition::Sig(keys[1])];
+
+        // Each kind of `and`, whose left side executes first and so takes the top of the stack
+        let ands = vec![
+            T::And(Arc::new(V::CheckSig(keys[0])), Arc::new(T::CastE(Arc::new(E::CheckSig(keys[1]))))),
+            T::CastF(Arc::new(F::And(Arc::new(V::CheckSig(keys[0])), Arc::new(F::CheckSig(keys[1]))))),
+            T::CastE(Arc::new(E::ParallelAnd(Arc::new(E::CheckSig(keys[0])), Arc::new(W::CheckSig(keys[1]))))),
+            T::CastE(Arc::new(E::CascadeAnd(Arc::new(E::CheckSig(keys[0])), Arc::new(F::CheckSig(keys[1]))))),
+        ];
+        for and in ands {
+            check(&ParseTree(Arc::new(and)), &sigs, &both);
+        }
+        let and_v = T::And(
+            Arc::new(V::And(Arc::new(V::CheckSig(keys[0])), Arc::new(V::CheckSig(keys[1])))),
+            Arc::new(T::CastE(Arc::new(E::CheckSig(keys[2])))),
+        );
+        check(&ParseTree(Arc::new(and_v)), &sigs, &[Condition::Sig(keys[0]), Condition::Sig(keys[1]), Condition::Sig(keys[2])]);
+
+        // Thresholds with every subexpression satisfied
+        let thresh = ParseTree(Arc::new(T::CastE(Arc::new(E::Threshold(
+            3,
+            Arc::new(E::CheckSig(keys[0])),
+            vec![W::CheckSig(keys[1]), W::CheckSig(keys[2])],
+        )))));
+        check(&thresh, &sigs, &[Condition::Sig(keys[0]), Condition::Sig(keys[1]), Condition::Sig(keys[2])]);
+
+        // Compiled from descriptors
+        let desc = Descriptor::And(Box::new(Descriptor::Key(keys[0])), Box::new(Descriptor::Key(keys[1])));
+        let tree = ParseTree::compile(&desc).unwrap();
+        assert!(tree.interpret(&tree.satisfy(&sigs).unwrap(), &checker).is_ok());
+        let desc = Descriptor::Threshold(2, vec![Descriptor::Key(keys[0]), Descriptor::Key(keys[1])]);
+        let tree = ParseTree::compile(&desc).unwrap();
+        assert!(tree.interpret(&tree.satisfy(&sigs).unwrap(), &checker).is_ok());
+
+        // Each kind of `or`, satisfied on either side, with the other side's dissatisfaction
+        // also in its place. The dissatisfied side is a `and` so that it takes several elements
+        let dissat_and = E::ParallelAnd(Arc::new(E::CheckSig(keys[2])), Arc::new(W::CheckSig(keys[3])));
+        let ors = vec![
+            T::CastE(Arc::new(E::ParallelOr(
+                Arc::new(dissat_and.clone()),
+                Arc::new(W::CastE(Arc::new(E::ParallelAnd(Arc::new(E::CheckSig(keys[0])), Arc::new(W::CheckSig(keys[1])))))),
+            ))),
+            T::CastE(Arc::new(E::ParallelOr(
+                Arc::new(E::ParallelAnd(Arc::new(E::CheckSig(keys[0])), Arc::new(W::CheckSig(keys[1])))),
+                Arc::new(W::CastE(Arc::new(dissat_and.clone()))),
+            ))),
+            T::CastE(Arc::new(E::CascadeOr(
+                Arc::new(dissat_and.clone()),
+                Arc::new(E::ParallelAnd(Arc::new(E::CheckSig(keys[0])), Arc::new(W::CheckSig(keys[1])))),
+            ))),
+            T::CascadeOr(
+                Arc::new(dissat_and.clone()),
+                Arc::new(T::And(Arc::new(V::CheckSig(keys[0])), Arc::new(T::CastE(Arc::new(E::CheckSig(keys[1])))))),
+            ),
+        ];
+        sigs.remove(&keys[2]);
+        sigs.remove(&keys[3]);
+        for or in ors {
+            check(&ParseTree(Arc::new(or)), &sigs, &both);
         }
+
+        // A dissatisfied threshold below a satisfied cascade
+        let thresh_or = ParseTree(Arc::new(T::CastE(Arc::new(E::CascadeOr(
+            Arc::new(E::Threshold(2, Arc::new(E::CheckSig(keys[2])), vec![W::CheckSig(keys[3]), W::Csv(10)])),
+            Arc::new(E::ParallelAnd(Arc::new(E::CheckSig(keys[0])), Arc::new(W::CheckSig(keys[1])))),
+        )))));
+        check(&thresh_or, &sigs, &both);
+    }
+
+    fn roundtrip(tree: &ParseTree, s: &str) {
+        let ser = tree.serialize();
+        assert_eq!(ser.to_string(), s);
+        let deser = ParseTree::parse(&ser).expect("deserialize result of serialize");
+        assert_eq!(tree, &deser);
+    }
+
+    #[test]
+    fn serialize() {
+        let keys = pubkeys(5);
+
+        roundtrip(
+            &ParseTree(Arc::new(T::CastE(Arc::new(E::CheckSig(keys[0]))))),
+            "Script(OP_PUSHBYTES_33 028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa OP_CHECKSIG)"
+        );
+        roundtrip(
+            &ParseTree(Arc::new(T::CastE(Arc::new(E::CheckMultiSig(3, keys[..].into()))))),
+            "Script(OP_PUSHNUM_3 OP_PUSHBYTES_33 028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa OP_PUSHBYTES_33 03ab1ac1872a38a2f196bed5a6047f0da2c8130fe8de49fc4d5dfb201f7611d8e2 OP_PUSHBYTES_33 039729247032c0dfcf45b4841fcd72f6e9a2422631fc3466cf863e87154754dd40 OP_PUSHBYTES_33 032564fe9b5beef82d3703a607253f31ef8ea1b365772df434226aee642651b3fa OP_PUSHBYTES_33 0289637f97580a796e050791ad5a2f27af1803645d95df021a3c2d82eb8c2ca7ff OP_PUSHNUM_5 OP_CHECKMULTISIG)"
+        );
+
+        let hash = Hash160::from_data(&keys[0].serialize());
+        roundtrip(
+            &ParseTree(Arc::new(T::CastE(Arc::new(E::CheckSigHash(hash))))),
+            "Script(OP_DUP OP_HASH160 OP_PUSHBYTES_20 60afcdec519698a263417ddfe7cea936737a0ee7 OP_EQUALVERIFY OP_CHECKSIG)"
+        );
+
+        // Liquid policy
+        roundtrip(
+            &ParseTree(Arc::new(T::CascadeOr(
+                Arc::new(E::CheckMultiSig(2, keys[0..2].into())),
+                Arc::new(T::And(
+                     Arc::new(V::CheckMultiSig(2, keys[3..5].into())),
+                     Arc::new(T::CastF(Arc::new(F::Csv(10000)))),
+                 )),
+             ))),
+             "Script(OP_PUSHNUM_2 OP_PUSHBYTES_33 028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa \
+                                  OP_PUSHBYTES_33 03ab1ac1872a38a2f196bed5a6047f0da2c8130fe8de49fc4d5dfb201f7611d8e2 \
+                                  OP_PUSHNUM_2 OP_CHECKMULTISIG \
+                     OP_IFDUP OP_NOTIF \
+                         OP_PUSHNUM_2 OP_PUSHBYTES_33 032564fe9b5beef82d3703a607253f31ef8ea1b365772df434226aee642651b3fa \
+                                      OP_PUSHBYTES_33 0289637f97580a796e050791ad5a2f27af1803645d95df021a3c2d82eb8c2ca7ff \
+                                      OP_PUSHNUM_2 OP_CHECKMULTISIGVERIFY \
+                         OP_PUSHBYTES_2 1027 OP_NOP3 \
+                     OP_ENDIF)"
+         );
+
+        roundtrip(
+            &ParseTree(Arc::new(T::CastF(Arc::new(F::Csv(921))))),
+            "Script(OP_PUSHBYTES_2 9903 OP_NOP3)"
+        );
+
+        roundtrip(
+            &ParseTree(Arc::new(T::HashEqual(sha256::Hash::from_data(&[])))),
+            "Script(OP_SIZE OP_PUSHBYTES_1 20 OP_EQUALVERIFY OP_SHA256 OP_PUSHBYTES_32 e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 OP_EQUAL)"
+        );
+
+        roundtrip(
+            &ParseTree(Arc::new(T::Hash160Equal(Hash160::from_data(&[])))),
+            "Script(OP_SIZE OP_PUSHBYTES_1 20 OP_EQUALVERIFY OP_HASH160 OP_PUSHBYTES_20 b472a266d0bd89c13706a4132ccfb16f7c3b9fcb OP_EQUAL)"
+        );
+
+        roundtrip(
+            &ParseTree(Arc::new(T::And(
+                Arc::new(V::Ripemd160Equal(Ripemd160Hash::hash(&[]))),
+                Arc::new(T::Hash256Equal(Sha256dHash::from_data(&[]))),
+            ))),
+            "Script(OP_SIZE OP_PUSHBYTES_1 20 OP_EQUALVERIFY OP_RIPEMD160 OP_PUSHBYTES_20 9c1185a5c5e9fc54612808977ee8f548b2258d31 OP_EQUALVERIFY \
+                    OP_SIZE OP_PUSHBYTES_1 20 OP_EQUALVERIFY OP_HASH256 OP_PUSHBYTES_32 5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456 OP_EQUAL)"
+        );
+
+        roundtrip(
+            &ParseTree(Arc::new(T::CastE(Arc::new(E::CheckMultiSig(3, keys[0..5].into()))))),
+            "Script(OP_PUSHNUM_3 \
+                    OP_PUSHBYTES_33 028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa \
+                    OP_PUSHBYTES_33 03ab1ac1872a38a2f196bed5a6047f0da2c8130fe8de49fc4d5dfb201f7611d8e2 \
+                    OP_PUSHBYTES_33 039729247032c0dfcf45b4841fcd72f6e9a2422631fc3466cf863e87154754dd40 \
+                    OP_PUSHBYTES_33 032564fe9b5beef82d3703a607253f31ef8ea1b365772df434226aee642651b3fa \
+                    OP_PUSHBYTES_33 0289637f97580a796e050791ad5a2f27af1803645d95df021a3c2d82eb8c2ca7ff \
+                    OP_PUSHNUM_5 OP_CHECKMULTISIG)"
+        );
+
+        roundtrip(
+            &ParseTree(Arc::new(T::HashEqual(sha256::Hash::from_data(&[])))),
+            "Script(OP_SIZE OP_PUSHBYTES_1 20 OP_EQUALVERIFY OP_SHA256 OP_PUSHBYTES_32 e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 OP_EQUAL)"
+        );
+
+        roundtrip(
+            &ParseTree(Arc::new(T::CastF(Arc::new(F::SwitchOrV(
+                Arc::new(V::CheckSig(keys[0])),
+                Arc::new(V::And(
+                    Arc::new(V::CheckSig(keys[1])),
+                    Arc::new(V::CheckSig(keys[2])),
+                ))))),
+            )),
+            "Script(OP_SIZE OP_EQUALVERIFY OP_IF \
+                OP_PUSHBYTES_33 028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa OP_CHECKSIGVERIFY \
+                OP_ELSE \
+                OP_PUSHBYTES_33 03ab1ac1872a38a2f196bed5a6047f0da2c8130fe8de49fc4d5dfb201f7611d8e2 OP_CHECKSIGVERIFY \
+                OP_PUSHBYTES_33 039729247032c0dfcf45b4841fcd72f6e9a2422631fc3466cf863e87154754dd40 OP_CHECKSIGVERIFY \
+                OP_ENDIF OP_PUSHNUM_1)"
+        );
+
+        // fuzzer
+        roundtrip(
+            &ParseTree(Arc::new(T::CastF(Arc::new(F::SwitchOr(
+                Arc::new(F::Csv(9)),
+                Arc::new(F::Csv(7)),
+            ))))),
+            "Script(OP_SIZE OP_EQUALVERIFY OP_IF OP_PUSHNUM_9 OP_NOP3 OP_ELSE OP_PUSHNUM_7 OP_NOP3 OP_ENDIF)"
+        );
+
+        roundtrip(
+            &ParseTree(Arc::new(T::And(
+                Arc::new(V::SwitchOrT(
+                    Arc::new(T::CastF(Arc::new(F::Csv(9)))),
+                    Arc::new(T::CastF(Arc::new(F::Csv(7)))),
+                )),
+                Arc::new(T::CastF(Arc::new(F::Csv(7))))
+            ))),
+            "Script(OP_SIZE OP_EQUALVERIFY OP_IF OP_PUSHNUM_9 OP_NOP3 OP_ELSE OP_PUSHNUM_7 OP_NOP3 OP_ENDIF OP_VERIFY OP_PUSHNUM_7 OP_NOP3)"
+        );
+
+        roundtrip(
+            &ParseTree(Arc::new(T::CastE(Arc::new(E::ParallelOr(
+                Arc::new(E::CheckMultiSig(0, vec![].into())),
+                Arc::new(W::CheckSig(keys[0])),
+            ))))),
+            "Script(OP_0 OP_0 OP_CHECKMULTISIG OP_SWAP OP_PUSHBYTES_33 028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa OP_CHECKSIG OP_BOOLOR)"
+        );
+    }
+
+    #[test]
+    fn malleability() {
+        let keys = pubkeys(2);
+        let hash = sha256::Hash::from_data(&[]);
+
+        let timeout = ParseTree(Arc::new(T::CascadeOr(
+            Arc::new(E::CheckSig(keys[0])),
+            Arc::new(T::CastF(Arc::new(F::Csv(10)))),
+        )));
+        assert!(timeout.is_non_malleable());
+
+        let unsigned_or = T::CascadeOr(
+            Arc::new(E::HashEqual(hash)),
+            Arc::new(T::CastF(Arc::new(F::Csv(10)))),
+        );
+        assert_eq!(
+            ParseTree(Arc::new(unsigned_or.clone())).malleability_report(),
+            vec![Malleability::UnsignedOr(unsigned_or.to_string())]
+        );
+
+        // A signature elsewhere in the script does not commit to the choice of branch
+        let inner = E::CascadeOr(
+            Arc::new(E::HashEqual(hash)),
+            Arc::new(E::CastF(Arc::new(F::Csv(10)))),
+        );
+        let signed = ParseTree(Arc::new(T::And(
+            Arc::new(V::CheckSig(keys[1])),
+            Arc::new(T::CastE(Arc::new(inner.clone()))),
+        )));
+        assert_eq!(signed.malleability_report(), vec![Malleability::UnsignedOr(inner.to_string())]);
+
+        let thresh = E::Threshold(
+            1,
+            Arc::new(E::CheckSig(keys[0])),
+            vec![W::Csv(10), W::HashEqual(hash)],
+        );
+        assert_eq!(
+            ParseTree(Arc::new(T::CastE(Arc::new(thresh.clone())))).malleability_report(),
+            vec![Malleability::UnsignedThreshold(thresh.to_string())]
+        );
+
+        let thresh = E::Threshold(
+            2,
+            Arc::new(E::CheckSig(keys[0])),
+            vec![W::Csv(10), W::HashEqual(hash)],
+        );
+        assert!(ParseTree(Arc::new(T::CastE(Arc::new(thresh)))).is_non_malleable());
     }
-}
 
+    #[test]
+    fn resource_limits() {
+        let keys = pubkeys(16);
 
-impl fmt::Display for V {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let script = self.serialize(script::Builder::new()).into_script();
-        fmt::Display::fmt(&script, f)
-    }
-}
+        let pk = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckSig(keys[0])))));
+        assert!(pk.check_resource_limits(Wrapper::Bare).unwrap().is_empty());
+        assert!(pk.check_resource_limits(Wrapper::Wsh).unwrap().is_empty());
 
-impl AstElem for V {
-    fn into_v(self: Box<V>) -> Result<Box<V>, Error> { Ok(self) }
-    fn is_v(&self) -> bool { true }
+        let multi = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckMultiSig(2, keys[..].into())))));
+        assert_eq!(multi.check_resource_limits(Wrapper::Bare), Ok(vec![LimitViolation::MultisigKeys(16)]));
+        assert_eq!(multi.check_resource_limits(Wrapper::Sh), Ok(vec![LimitViolation::RedeemScriptSize(547)]));
+        assert!(multi.check_resource_limits(Wrapper::Wsh).unwrap().is_empty());
 
-    fn serialize(&self, mut builder: script::Builder) -> script::Builder {
-        match *self {
-            V::CheckSig(ref pk) => {
-                builder.push_slice(&pk.serialize()[..])
-                       .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
-            }
-            V::CheckSigHash(hash) => {
-                builder.push_opcode(opcodes::All::OP_DUP)
-                       .push_opcode(opcodes::All::OP_HASH160)
-                       .push_slice(&hash[..])
-                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                       .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
-            }
-            V::CheckMultiSig(k, ref pks) => {
-                builder = builder.push_int(k as i64);
-                for pk in pks {
-                    builder = builder.push_slice(&pk.serialize()[..]);
-                }
-                builder.push_int(pks.len() as i64)
-                       .push_opcode(opcodes::All::OP_CHECKMULTISIGVERIFY)
-            }
-            V::Csv(n) => {
-                builder.push_int(n as i64)
-                       .push_opcode(opcodes::OP_CSV)
-                       .push_opcode(opcodes::All::OP_DROP)
-            }
-            V::HashEqual(hash) => {
-                builder.push_opcode(opcodes::All::OP_SIZE)
-                       .push_int(32)
-                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                       .push_opcode(opcodes::All::OP_SHA256)
-                       .push_slice(&hash[..])
-                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
-            }
-            V::Threshold(k, ref e, ref ws) => {
-                builder = e.serialize(builder);
-                for w in ws {
-                    builder = w.serialize(builder).push_opcode(opcodes::All::OP_ADD);
-                }
-                builder.push_int(k as i64)
-                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
-            }
-            V::And(ref left, ref right) => {
-                builder = left.serialize(builder);
-                right.serialize(builder)
-            }
-            V::ParallelOr(ref left, ref right) => {
-                builder = left.serialize(builder);
-                builder = right.serialize(builder);
-                builder.push_opcode(opcodes::All::OP_BOOLOR)
-                       .push_opcode(opcodes::All::OP_VERIFY)
-            }
-            V::SwitchOr(ref left, ref right) => {
-                builder = builder.push_opcode(opcodes::All::OP_SIZE)
-                                 .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                                 .push_opcode(opcodes::All::OP_IF);
-                builder = left.serialize(builder);
-                builder = builder.push_opcode(opcodes::All::OP_ELSE);
-                builder = right.serialize(builder);
-                builder.push_opcode(opcodes::All::OP_ENDIF)
-            }
-            V::SwitchOrT(ref left, ref right) => {
-                builder = builder.push_opcode(opcodes::All::OP_SIZE)
-                                 .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                                 .push_opcode(opcodes::All::OP_IF);
-                builder = left.serialize(builder);
-                builder = builder.push_opcode(opcodes::All::OP_ELSE);
-                builder = right.serialize(builder);
-                builder.push_opcode(opcodes::All::OP_ENDIF)
-                       .push_opcode(opcodes::All::OP_VERIFY)
-            }
-            V::CascadeOr(ref left, ref right) => {
-                builder = left.serialize(builder);
-                builder = builder.push_opcode(opcodes::All::OP_NOTIF);
-                builder = right.serialize(builder);
-                builder.push_opcode(opcodes::All::OP_ENDIF)
-            }
+        let mut tree = T::CastE(Arc::new(E::CheckMultiSig(1, vec![keys[0]].into())));
+        for _ in 0..50 {
+            tree = T::And(Arc::new(V::CheckMultiSig(1, vec![keys[0]].into())), Arc::new(tree));
         }
+        let tree = ParseTree(Arc::new(tree));
+        assert!(tree.check_resource_limits(Wrapper::Bare).unwrap().is_empty());
+        assert_eq!(tree.check_resource_limits(Wrapper::Wsh), Ok(vec![LimitViolation::WitnessStackItems(102)]));
     }
 
-    fn satisfy(
-        &self,
-        key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
-        pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
-        hash_map: &HashMap<Sha256dHash, [u8; 32]>,
-        age: u32,
-    ) -> Result<Vec<Vec<u8>>, Error> {
-        match *self {
-            V::CheckSig(ref pk) => satisfy_checksig(pk, key_map),
-            V::CheckMultiSig(k, ref keys) => satisfy_checkmultisig(k, keys, key_map),
-            V::CheckSigHash(ref hash) => satisfy_checksighash(hash, key_map, pkh_map),
-            V::Csv(n) => satisfy_csv(n, age),
-            V::HashEqual(ref hash) => satisfy_hashequal(hash, hash_map),
-            V::Threshold(k, ref sube, ref subw) => satisfy_threshold(k, sube, subw, key_map, pkh_map, hash_map, age),
-            V::And(ref left, ref right) => {
-                let mut ret = left.satisfy(key_map, pkh_map, hash_map, age)?;
-                ret.extend(right.satisfy(key_map, pkh_map, hash_map, age)?);
-                Ok(ret)
-            }
-            V::ParallelOr(ref left, ref right) => satisfy_parallel_or(left, right, key_map, pkh_map, hash_map, age),
-            V::SwitchOr(ref left, ref right) => satisfy_switch_or(left, right, key_map, pkh_map, hash_map, age),
-            V::SwitchOrT(ref left, ref right) => satisfy_switch_or(left, right, key_map, pkh_map, hash_map, age),
-            V::CascadeOr(ref left, ref right) => satisfy_cascade_or(left, right, key_map, pkh_map, hash_map, age),
+    #[test]
+    fn standard_witness() {
+        use std::collections::HashMap;
+
+        let keys = pubkeys(1);
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let mut sigs = HashMap::new();
+        sigs.insert(keys[0], secp.sign(&msg, &sk).expect("signing context"));
+
+        // 51 single-key multisigs take a dummy and a signature each
+        let mut tree = T::CastE(Arc::new(E::CheckMultiSig(1, vec![keys[0]].into())));
+        for _ in 0..50 {
+            tree = T::And(Arc::new(V::CheckMultiSig(1, vec![keys[0]].into())), Arc::new(tree));
         }
-    }
+        let tree = ParseTree(Arc::new(tree));
+        let sat = tree.satisfy(&sigs).unwrap();
+        assert_eq!(sat.len(), 102);
+        for wrapper in &[Wrapper::Wsh, Wrapper::ShWsh] {
+            assert_eq!(
+                tree.satisfy_standard(&sigs, SatisfactionMode::Smallest, *wrapper),
+                Err(Error::NonStandardWitness(LimitViolation::WitnessStackItems(102)))
+            );
+        }
+        assert_eq!(tree.satisfy_standard(&sigs, SatisfactionMode::Smallest, Wrapper::Sh), Ok(sat));
+
+        let pk = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckSig(keys[0])))));
+        let sat = pk.satisfy_standard(&sigs, SatisfactionMode::Smallest, Wrapper::Wsh).unwrap();
+        assert_eq!(pk.check_standard_witness(&sat, Wrapper::Wsh), Ok(()));
+        assert_eq!(
+            pk.check_standard_witness(&[vec![0; 81]], Wrapper::Wsh),
+            Err(Error::NonStandardWitness(LimitViolation::WitnessItemSize(81)))
+        );
+        assert_eq!(pk.check_standard_witness(&[vec![0; 81]], Wrapper::Bare), Ok(()));
 
-    fn required_keys(&self) -> Vec<secp256k1::PublicKey> {
-        match *self {
-            V::CheckSig(pk) => vec![pk],
-            V::CheckMultiSig(_, ref keys) => keys.clone(),
-            V::CheckSigHash(..) | V::Csv(..) | V::HashEqual(..) => vec![],
-            V::Threshold(_, ref sube, ref subw) => {
-                let mut ret = sube.required_keys();
-                for sub in subw {
-                    ret.extend(sub.required_keys());
-                }
-                ret
-            }
-            V::And(ref left, ref right) => {
-                let mut ret = left.required_keys();
-                ret.extend(right.required_keys());
-                ret
-            }
-            V::ParallelOr(ref left, ref right) => {
-                let mut ret = left.required_keys();
-                ret.extend(right.required_keys());
-                ret
-            }
-            V::SwitchOr(ref left, ref right) => {
-                let mut ret = left.required_keys();
-                ret.extend(right.required_keys());
-                ret
-            }
-            V::SwitchOrT(ref left, ref right) => {
-                let mut ret = left.required_keys();
-                ret.extend(right.required_keys());
-                ret
-            }
-            V::CascadeOr(ref left, ref right) => {
-                let mut ret = left.required_keys();
-                ret.extend(right.required_keys());
-                ret
-            }
+        // 108 keys of 34 bytes push the script past 3,600 bytes
+        let keys = pubkeys(108);
+        let mut tree = T::CastE(Arc::new(E::CheckSig(keys[0])));
+        for pk in &keys[1..] {
+            tree = T::And(Arc::new(V::CheckSig(*pk)), Arc::new(tree));
         }
+        let tree = ParseTree(Arc::new(tree));
+        let len = tree.serialize().len();
+        assert!(len > 3600);
+        assert_eq!(
+            tree.check_standard_witness(&[], Wrapper::Wsh),
+            Err(Error::NonStandardWitness(LimitViolation::WitnessScriptSize(len)))
+        );
     }
-}
 
-impl V {
-    fn from_descriptor(desc: &Descriptor<secp256k1::PublicKey>, satisfaction_probability: f64) -> Cost<V> {
-        debug_assert_eq!(satisfaction_probability, 1.0);
-        match *desc {
-            Descriptor::Key(ref key) => {
-                Cost {
-                    ast: V::CheckSig(key.clone()),
-                    pk_cost: 35,
-                    sat_cost: 73,
-                    dissat_cost: 0,
-                }
-            }
-            Descriptor::KeyHash(ref key) => {
-                let hash = Hash160::from_data(&key.serialize()[..]);
-                Cost {
-                    ast: V::CheckSigHash(hash),
-                    pk_cost: 25,
-                    sat_cost: 34 + 73,
-                    dissat_cost: 0,
-                }
-            }
-            Descriptor::Multi(k, ref keys) => {
-                let num_cost = match(k > 16, keys.len() > 16) {
-                    (true, true) => 4,
-                    (false, true) => 3,
-                    (true, false) => 3,
-                    (false, false) => 2,
-                };
-                Cost {
-                    ast: V::CheckMultiSig(k, keys.clone()),
-                    pk_cost: num_cost + 34 * keys.len() + 1,
-                    sat_cost: 1 + 73*k,
-                    dissat_cost: 0,
-                }
-            }
-            Descriptor::Time(n) => {
-                let num_cost = script::Builder::new().push_int(n as i64).into_script().len();
-                Cost {
-                    ast: V::Csv(n),
-                    pk_cost: 2 + num_cost,
-                    sat_cost: 0,
-                    dissat_cost: 0,
-                }
-            }
-            Descriptor::Hash(hash) => {
-                Cost {
-                    ast: V::HashEqual(hash),
-                    pk_cost: 27,
-                    sat_cost: 33,
-                    dissat_cost: 1,
-                }
-            }
-            Descriptor::Threshold(k, ref exprs) => {
-                let num_cost = script::Builder::new().push_int(k as i64).into_script().len();
-                if exprs.is_empty() {
-                    panic!("Cannot have empty threshold in a descriptor");
-                }
+    #[test]
+    fn compile_for_context() {
+        let keys = pubkeys(16);
 
-                let e = E::from_descriptor(&exprs[0], satisfaction_probability * k as f64 / exprs.len() as f64);
-                let mut pk_cost = 1 + num_cost + e.pk_cost;
-                let mut sat_cost = e.sat_cost;
-                let mut dissat_cost = e.dissat_cost;
-                let mut ws = vec![];
+        let pk = Descriptor::Key(keys[0]);
+        for context in &[ScriptContext::Bare, ScriptContext::Legacy, ScriptContext::SegwitV0] {
+            assert_eq!(ParseTree::compile_for_context(&pk, *context), ParseTree::compile(&pk));
+        }
+        assert_eq!(
+            ParseTree::compile_for_context(&pk, ScriptContext::Taproot),
+            Err(CompileError::UnsupportedContext(ScriptContext::Taproot))
+        );
 
-                for expr in &exprs[1..] {
-                    let w = W::from_descriptor(expr, satisfaction_probability * k as f64 / exprs.len() as f64);
-                    pk_cost += w.pk_cost;
-                    sat_cost += w.sat_cost;
-                    dissat_cost += w.dissat_cost;
-                    ws.push(w.ast);
-                }
+        let multi = Descriptor::Multi(2, keys.clone());
+        assert_eq!(
+            ParseTree::compile_for_context(&multi, ScriptContext::Bare),
+            Err(CompileError::NonStandardBare(multi.to_string()))
+        );
+        assert_eq!(
+            ParseTree::compile_for_context(&multi, ScriptContext::Legacy),
+            Err(CompileError::LimitExceeded(ScriptContext::Legacy, LimitViolation::RedeemScriptSize(547)))
+        );
+        assert!(ParseTree::compile_for_context(&multi, ScriptContext::SegwitV0).is_ok());
+
+        let small = Descriptor::Multi(2, keys[0..3].to_vec());
+        assert!(ParseTree::compile_for_context(&small, ScriptContext::Bare).is_ok());
+        let and = Descriptor::And(Box::new(Descriptor::Key(keys[0])), Box::new(Descriptor::Key(keys[1])));
+        assert_eq!(
+            ParseTree::compile_for_context(&and, ScriptContext::Bare),
+            Err(CompileError::NonStandardBare(and.to_string()))
+        );
+        assert!(ParseTree::compile_for_context(&and, ScriptContext::Legacy).is_ok());
+    }
 
-                Cost {
-                    ast: V::Threshold(k, Box::new(e.ast), ws),
-                    pk_cost: pk_cost,
-                    sat_cost: sat_cost * k / exprs.len(),  // TODO is simply averaging here the right thing to do?
-                    dissat_cost: dissat_cost * k / exprs.len(),
-                }
-            }
-            Descriptor::And(ref left, ref right) => {
-                let l = V::from_descriptor(left, satisfaction_probability);
-                let r = V::from_descriptor(right, satisfaction_probability);
-                Cost {
-                    pk_cost: l.pk_cost + r.pk_cost,
-                    sat_cost: l.sat_cost + r.sat_cost,
-                    dissat_cost: 0,
-                    ast: V::And(Box::new(l.ast), Box::new(r.ast)),
-                }
-            }
-            Descriptor::Or(_, _) => unimplemented!(),
-            Descriptor::AsymmetricOr(_, _) => unimplemented!(),
-            Descriptor::Wpkh(_) | Descriptor::Sh(_) | Descriptor::Wsh(_) => {
-                // handled at at the ParseTree::from_descriptor layer
-                unreachable!()
-            }
+    #[test]
+    fn satisfy_timelock_types() {
+        use satisfy::{Older, OlderTime};
+
+        let blocks = ParseTree(Arc::new(T::CastF(Arc::new(F::Csv(144)))));
+        // 10 intervals of 512 seconds
+        let seconds = ParseTree(Arc::new(T::CastF(Arc::new(F::Csv((1 << 22) | 10)))));
+
+        assert_eq!(blocks.satisfy(&Older(144)), Ok(vec![]));
+        assert_eq!(blocks.satisfy(&Older(143)), Err(Error::LocktimeNotMet(144)));
+        // An age in seconds is no use for a height-based lock, however large
+        assert_eq!(blocks.satisfy(&OlderTime(1 << 30)), Err(Error::LocktimeNotMet(144)));
+
+        assert_eq!(seconds.satisfy(&OlderTime(5120)), Ok(vec![]));
+        assert_eq!(seconds.satisfy(&OlderTime(5119)), Err(Error::LocktimeNotMet((1 << 22) | 10)));
+        // ...and vice versa, even though the raw value is less than the age in blocks
+        assert_eq!(seconds.satisfy(&Older(1 << 23)), Err(Error::LocktimeNotMet((1 << 22) | 10)));
+
+        let both = (Older(144), OlderTime(5120));
+        assert_eq!(blocks.satisfy(&both), Ok(vec![]));
+        assert_eq!(seconds.satisfy(&both), Ok(vec![]));
+    }
+
+    #[test]
+    fn satisfaction_modes() {
+        use std::collections::HashMap;
+
+        let keys = pubkeys(3);
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let sig = secp.sign(&msg, &sk).expect("signing context");
+        let sigs: HashMap<secp256k1::PublicKey, secp256k1::Signature> = keys.iter().map(|pk| (*pk, sig)).collect();
+        let sigser = serialize_sig(&sig, SigHashType::All);
+
+        // The cheaper branch comes second
+        let tree = ParseTree(Arc::new(T::CascadeOr(
+            Arc::new(E::CheckMultiSig(2, keys[1..3].into())),
+            Arc::new(T::CastE(Arc::new(E::CheckSig(keys[0])))),
+        )));
+        let multi = vec![vec![], sigser.clone(), sigser.clone()];
+        let single = vec![sigser.clone(), vec![], vec![], vec![]];
+
+        assert_eq!(tree.satisfy(&sigs), Ok(single.clone()));
+        assert_eq!(tree.satisfy_with_mode(&sigs, SatisfactionMode::Smallest), Ok(single.clone()));
+        assert_eq!(tree.satisfy_with_mode(&sigs, SatisfactionMode::Canonical), Ok(multi.clone()));
+        assert_eq!(tree.satisfy_with_mode(&sigs, SatisfactionMode::Max), Ok(multi.clone()));
+
+        // Without the multisig signatures, every mode takes the only possible branch
+        let mut one_sig = HashMap::new();
+        one_sig.insert(keys[0], sig);
+        for mode in &[SatisfactionMode::Smallest, SatisfactionMode::Canonical, SatisfactionMode::Max] {
+            assert_eq!(tree.satisfy_with_mode(&one_sig, *mode), Ok(single.clone()));
         }
     }
-}
 
-impl fmt::Display for T {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let script = self.serialize(script::Builder::new()).into_script();
-        fmt::Display::fmt(&script, f)
+    #[test]
+    fn satisfaction_placement() {
+        use std::collections::HashMap;
+
+        let keys = pubkeys(2);
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let sig = secp.sign(&msg, &sk).expect("signing context");
+        let sigs: HashMap<secp256k1::PublicKey, secp256k1::Signature> = keys.iter().map(|pk| (*pk, sig)).collect();
+        let sigser = serialize_sig(&sig, SigHashType::All);
+
+        // Selecting the first branch takes a one-byte push, which is a two-byte
+        // witness element but a single opcode in a scriptSig
+        let tree = ParseTree(Arc::new(T::SwitchOr(
+            Arc::new(T::CastE(Arc::new(E::CheckSig(keys[0])))),
+            Arc::new(T::CastE(Arc::new(E::CheckSig(keys[1])))),
+        )));
+        let second = vec![sigser.clone(), vec![]];
+        let first = vec![sigser.clone(), vec![1]];
+        assert_eq!(tree.satisfy(&sigs), Ok(second.clone()));
+        for wrapper in &[Wrapper::Wsh, Wrapper::ShWsh] {
+            assert_eq!(tree.satisfy_for_wrapper(&sigs, SatisfactionMode::Smallest, *wrapper), Ok(second.clone()));
+        }
+        for wrapper in &[Wrapper::Bare, Wrapper::Sh] {
+            assert_eq!(tree.satisfy_for_wrapper(&sigs, SatisfactionMode::Smallest, *wrapper), Ok(first.clone()));
+        }
     }
-}
 
-impl AstElem for T {
-    fn into_t(self: Box<T>) -> Result<Box<T>, Error> { Ok(self) }
-    fn is_t(&self) -> bool { true }
+    #[test]
+    fn checkmultisig_dummy() {
+        use std::collections::HashMap;
 
-    fn serialize(&self, mut builder: script::Builder) -> script::Builder {
-        match *self {
-            T::HashEqual(hash) => {
-                builder.push_opcode(opcodes::All::OP_SIZE)
-                       .push_int(32)
-                       .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                       .push_opcode(opcodes::All::OP_SHA256)
-                       .push_slice(&hash[..])
-                       .push_opcode(opcodes::All::OP_EQUAL)
-            }
-            T::And(ref vexpr, ref top) => {
-                builder = vexpr.serialize(builder);
-                top.serialize(builder)
-            }
-            T::SwitchOr(ref left, ref right) => {
-                builder = builder.push_opcode(opcodes::All::OP_SIZE)
-                                 .push_opcode(opcodes::All::OP_EQUALVERIFY)
-                                 .push_opcode(opcodes::All::OP_IF);
-                builder = left.serialize(builder);
-                builder = builder.push_opcode(opcodes::All::OP_ELSE);
-                builder = right.serialize(builder);
-                builder.push_opcode(opcodes::All::OP_ENDIF)
+        /// Accepts any signature for the given keys only
+        struct Signed(Vec<secp256k1::PublicKey>);
+
+        impl Checker for Signed {
+            fn check_sig(&self, pk: &secp256k1::PublicKey, _: &[u8]) -> bool {
+                self.0.contains(pk)
             }
-            T::CascadeOr(ref left, ref right) => {
-                builder = left.serialize(builder);
-                builder = builder.push_opcode(opcodes::All::OP_IFDUP)
-                                 .push_opcode(opcodes::All::OP_NOTIF);
-                builder = right.serialize(builder);
-                builder.push_opcode(opcodes::All::OP_ENDIF)
+
+            fn check_older(&self, _: u32) -> bool {
+                true
             }
-            T::CastE(ref expr) => expr.serialize(builder),
-            T::CastF(ref expr) => expr.serialize(builder),
         }
+
+        let keys = pubkeys(3);
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let sig = secp.sign(&msg, &sk).expect("signing context");
+        let sigser = serialize_sig(&sig, SigHashType::All);
+        let mut sigs = HashMap::new();
+        sigs.insert(keys[0], sig);
+        sigs.insert(keys[2], sig);
+
+        // The empty dummy comes first, at the bottom of the stack
+        let tree = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckMultiSig(2, keys[..].into())))));
+        let sat = tree.satisfy(&sigs).unwrap();
+        assert_eq!(sat, vec![vec![], sigser.clone(), sigser.clone()]);
+        let exec = tree.interpret(&sat, &Signed(vec![keys[0], keys[2]])).unwrap();
+        assert_eq!(exec.branch, vec![Condition::Sig(keys[0]), Condition::Sig(keys[2])]);
+
+        let dissat = E::CheckMultiSig(2, keys[..].into()).dissatisfy(&sigs).unwrap();
+        assert_eq!(dissat, vec![vec![]; 3]);
     }
 
-    fn satisfy(
-        &self,
-        key_map: &HashMap<secp256k1::PublicKey, secp256k1::Signature>,
-        pkh_map: &HashMap<Hash160, secp256k1::PublicKey>,
-        hash_map: &HashMap<Sha256dHash, [u8; 32]>,
-        age: u32,
-    ) -> Result<Vec<Vec<u8>>, Error> {
-        match *self {
-            T::HashEqual(ref hash) => satisfy_hashequal(hash, hash_map),
-            T::And(ref left, ref right) => {
-                let mut ret = left.satisfy(key_map, pkh_map, hash_map, age)?;
-                ret.extend(right.satisfy(key_map, pkh_map, hash_map, age)?);
-                Ok(ret)
-            }
-            T::SwitchOr(ref left, ref right) => satisfy_switch_or(left, right, key_map, pkh_map, hash_map, age),
-            T::CastE(ref e) => e.satisfy(key_map, pkh_map, hash_map, age),
-            T::CastF(ref f) => f.satisfy(key_map, pkh_map, hash_map, age),
-            T::CascadeOr(ref left, ref right) => satisfy_cascade_or(left, right, key_map, pkh_map, hash_map, age),
+    #[test]
+    fn checkmultisig_order() {
+        use std::collections::HashMap;
+
+        /// A signature whose `r` has `r_len` bytes, so that its DER encoding has
+        /// `r_len + 7` bytes and its satisfaction one more
+        fn sized_sig(r_len: usize) -> secp256k1::Signature {
+            let mut compact = [0; 64];
+            for x in compact[32 - r_len..32].iter_mut() {
+                *x = 0x11;
+            }
+            compact[63] = 1;
+            let secp = secp256k1::Secp256k1::without_caps();
+            secp256k1::Signature::from_compact(&secp, &compact).expect("signature")
+        }
+
+        let keys = pubkeys(4);
+        let sizes = [16, 32, 1, 31];
+        let sigs: HashMap<secp256k1::PublicKey, secp256k1::Signature> =
+            keys.iter().zip(sizes.iter()).map(|(pk, len)| (*pk, sized_sig(*len))).collect();
+        let sigser: Vec<Vec<u8>> = keys.iter().map(|pk| serialize_sig(&sigs[pk], SigHashType::All)).collect();
+        assert_eq!(sigser.iter().map(|sig| sig.len()).collect::<Vec<_>>(), vec![24, 40, 9, 39]);
+        let checker = ByKey(keys.iter().cloned().zip(sigser.iter().cloned()).collect());
+
+        // The two cheapest signatures are those of the third and first keys, but
+        // must be given in key order
+        let tree = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckMultiSig(2, keys[..].into())))));
+        for &(mode, ref chosen) in &[
+            (SatisfactionMode::Smallest, [0, 2]),
+            (SatisfactionMode::Canonical, [0, 1]),
+            (SatisfactionMode::Lazy, [0, 1]),
+            (SatisfactionMode::Max, [1, 3]),
+        ] {
+            let sat = tree.satisfy_with_mode(&sigs, mode).unwrap();
+            assert_eq!(sat, vec![vec![], sigser[chosen[0]].clone(), sigser[chosen[1]].clone()], "{:?}", mode);
+            let exec = tree.interpret(&sat, &checker).unwrap();
+            assert_eq!(exec.branch, vec![Condition::Sig(keys[chosen[0]]), Condition::Sig(keys[chosen[1]])]);
         }
+
+        // Signatures out of key order do not verify
+        let swapped = vec![vec![], sigser[2].clone(), sigser[0].clone()];
+        assert!(tree.interpret(&swapped, &checker).is_err());
+
+        // Every choice of keys stays in order
+        let tree = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckMultiSig(3, keys[..].into())))));
+        let sat = tree.satisfy(&sigs).unwrap();
+        assert_eq!(sat, vec![vec![], sigser[0].clone(), sigser[2].clone(), sigser[3].clone()]);
+        assert!(tree.interpret(&sat, &checker).is_ok());
     }
 
-    fn required_keys(&self) -> Vec<secp256k1::PublicKey> {
-        match *self {
-            T::HashEqual(..) => vec![],
-            T::And(ref left, ref right) => {
-                let mut ret = left.required_keys();
-                ret.extend(right.required_keys());
-                ret
-            }
-            T::SwitchOr(ref left, ref right) => {
-                let mut ret = left.required_keys();
-                ret.extend(right.required_keys());
-                ret
-            }
-            T::CascadeOr(ref left, ref right) => {
-                let mut ret = left.required_keys();
-                ret.extend(right.required_keys());
-                ret
-            }
-            T::CastE(ref sub) => sub.required_keys(),
-            T::CastF(ref sub) => sub.required_keys(),
+    #[test]
+    fn threshold_satisfaction_order() {
+        use std::collections::HashMap;
+
+        let keys = pubkeys(3);
+        let (sigs, checker) = signed_by_each(&keys);
+        let preimage = [7; 32];
+        let hash = sha256::Hash::from_data(&preimage);
+        let mut preimages = HashMap::new();
+        preimages.insert(hash, preimage);
+        let satisfier = (sigs, preimages);
+
+        // The preimage is cheaper than any signature, but comes third in script order
+        let tree = ParseTree(Arc::new(T::CastE(Arc::new(E::Threshold(
+            2,
+            Arc::new(E::CheckSig(keys[0])),
+            vec![W::CheckSig(keys[1]), W::HashEqual(hash), W::CheckSig(keys[2])],
+        )))));
+        // Signatures of equal size are chosen in script order
+        let shortest = (0..3).min_by_key(|i| checker.0[&keys[*i]].len()).unwrap();
+        let mut smallest = vec![Condition::Sig(keys[shortest]), Condition::Preimage(HashImage::Sha256(hash))];
+        if shortest == 2 {
+            smallest.reverse();
+        }
+
+        // ...and the two largest, leaving out the preimage
+        let mut largest: Vec<usize> = (0..3).collect();
+        largest.sort_by_key(|i| cmp::Reverse(checker.0[&keys[*i]].len()));
+        largest.truncate(2);
+        largest.sort();
+        let largest = largest.into_iter().map(|i| Condition::Sig(keys[i])).collect();
+
+        for &(mode, ref expected) in &[
+            (SatisfactionMode::Smallest, smallest),
+            (SatisfactionMode::Canonical, vec![Condition::Sig(keys[0]), Condition::Sig(keys[1])]),
+            (SatisfactionMode::Max, largest),
+        ] {
+            // One element for each of the four subexpressions, chosen or not
+            let sat = tree.satisfy_with_mode(&satisfier, mode).unwrap();
+            assert_eq!(sat.len(), 4, "{:?}", mode);
+            let exec = tree.interpret(&sat, &checker).unwrap();
+            assert_eq!(exec.branch, *expected, "{:?}", mode);
+        }
+
+        // A multi-element dissatisfaction in an unchosen slot
+        let tree = ParseTree(Arc::new(T::CastE(Arc::new(E::Threshold(
+            1,
+            Arc::new(E::CheckSig(keys[0])),
+            vec![W::CastE(Arc::new(E::CheckMultiSig(2, keys[1..3].into())))],
+        )))));
+        for &mode in &[SatisfactionMode::Smallest, SatisfactionMode::Canonical] {
+            let sat = tree.satisfy_with_mode(&satisfier, mode).unwrap();
+            assert_eq!(sat.len(), 4, "{:?}", mode);
+            assert_eq!(tree.interpret(&sat, &checker).unwrap().branch, vec![Condition::Sig(keys[0])]);
         }
+        let sat = tree.satisfy_with_mode(&satisfier, SatisfactionMode::Max).unwrap();
+        assert_eq!(sat.len(), 4);
+        assert_eq!(tree.interpret(&sat, &checker).unwrap().branch, vec![Condition::Sig(keys[1]), Condition::Sig(keys[2])]);
     }
-}
 
-impl T {
-    fn from_descriptor(desc: &Descriptor<secp256k1::PublicKey>, satisfaction_probability: f64) -> Cost<T> {
-        debug_assert_eq!(satisfaction_probability, 1.0);
+    #[test]
+    fn nonstandard_signatures() {
+        use std::collections::HashMap;
 
-        match *desc {
-            Descriptor::Key(_) | Descriptor::KeyHash(_) | Descriptor::Multi(_, _) => {
-                let e = E::from_descriptor(desc, satisfaction_probability);
-                Cost {
-                    ast: T::CastE(Box::new(e.ast)),
-                    pk_cost: e.pk_cost,
-                    sat_cost: e.sat_cost,
-                    dissat_cost: 0,
-                }
-            }
-            Descriptor::Time(_) => {
-                let f = F::from_descriptor(desc, satisfaction_probability);
-                Cost {
-                    ast: T::CastF(Box::new(f.ast)),
-                    pk_cost: f.pk_cost,
-                    sat_cost: f.sat_cost,
-                    dissat_cost: 0,
-                }
-            }
-            Descriptor::Hash(hash) => {
-                Cost {
-                    ast: T::HashEqual(hash),
-                    pk_cost: 27,
-                    sat_cost: 33,
-                    dissat_cost: 0,
-                }
-            }
-            Descriptor::And(_, _) |
-            Descriptor::Or(_, _) |
-            Descriptor::AsymmetricOr(_, _) |
-            Descriptor::Threshold(_, _) => {
-                let mut options = vec![
-                    {
-                        let e = E::from_descriptor(desc, 1.0);
-                        Cost {
-                            ast: T::CastE(Box::new(e.ast)),
-                            pk_cost: e.pk_cost,
-                            sat_cost: e.sat_cost,
-                            dissat_cost: 0,
-                        }
-                    },
-                    {
-                        let f = F::from_descriptor(desc, 1.0);
-                        Cost {
-                            ast: T::CastF(Box::new(f.ast)),
-                            pk_cost: f.pk_cost,
-                            sat_cost: f.sat_cost,
-                            dissat_cost: 0,
-                        }
-                    },
-                ];
-
-                match *desc {
-                    Descriptor::And(ref left, ref right) => {
-                        let lv = V::from_descriptor(left, 1.0);
-                        let rv = V::from_descriptor(right, 1.0);
-                        let lt = T::from_descriptor(left, 1.0);
-                        let rt = T::from_descriptor(right, 1.0);
-
-                        options.push(Cost {
-                            ast: T::And(Box::new(lv.ast), Box::new(rt.ast)),
-                            pk_cost: lv.pk_cost + rt.pk_cost,
-                            sat_cost: lv.sat_cost + rt.sat_cost,
-                            dissat_cost: 0,
-                        });
-                        options.push(Cost {
-                            ast: T::And(Box::new(rv.ast), Box::new(lt.ast)),
-                            pk_cost: lt.pk_cost + rv.pk_cost,
-                            sat_cost: lt.sat_cost + rv.sat_cost,
-                            dissat_cost: 0,
-                        });
-                    }
-                    Descriptor::Or(ref left, ref right) => {
-                        let le = E::from_descriptor(left, satisfaction_probability / 2.0);
-                        let re = E::from_descriptor(right, satisfaction_probability / 2.0);
-                        let lt = T::from_descriptor(left, 1.0);
-                        let rt = T::from_descriptor(right, 1.0);
-
-                        let lt1 = lt.clone();
-                        let rt1 = rt.clone();
-
-                        options.push(Cost {
-                            ast: T::CascadeOr(Box::new(le.ast), Box::new(rt.ast)),
-                            pk_cost: le.pk_cost + rt.pk_cost + 3,
-                            sat_cost: (le.sat_cost + le.dissat_cost + rt.sat_cost) / 2,
-                            dissat_cost: 0,
-                        });
-                        options.push(Cost {
-                            ast: T::CascadeOr(Box::new(re.ast), Box::new(lt.ast)),
-                            pk_cost: lt.pk_cost + re.pk_cost + 3,
-                            sat_cost: (re.sat_cost + re.dissat_cost + lt.sat_cost) / 2,
-                            dissat_cost: 0,
-                        });
-
-                        // TODO ask sipa about switchor here
-                        options.push(Cost {
-                            ast: T::SwitchOr(Box::new(lt1.ast), Box::new(rt1.ast)),
-                            pk_cost: le.pk_cost + rt.pk_cost + 5,
-                            sat_cost: (le.sat_cost + re.sat_cost + 3) / 2,
-                            dissat_cost: 0,
-                        });
-                    }
-                    Descriptor::AsymmetricOr(ref left, ref right) => {
-                        let le = E::from_descriptor(left, satisfaction_probability);
-                        let re = E::from_descriptor(right, 0.0);
-                        let lt = T::from_descriptor(left, 1.0);
-                        let rt = T::from_descriptor(right, 1.0);
-
-                        let lt1 = lt.clone();
-                        let rt1 = rt.clone();
-
-                        options.push(Cost {
-                            ast: T::CascadeOr(Box::new(le.ast), Box::new(rt.ast)),
-                            pk_cost: le.pk_cost + rt.pk_cost + 3,
-                            sat_cost: le.sat_cost,
-                            dissat_cost: 0,
-                        });
-                        options.push(Cost {
-                            ast: T::CascadeOr(Box::new(re.ast), Box::new(lt.ast)),
-                            pk_cost: lt.pk_cost + re.pk_cost + 3,
-                            sat_cost: re.dissat_cost + lt.sat_cost,
-                            dissat_cost: 0,
-                        });
-
-                        // TODO ask sipa about switchor here
-                        options.push(Cost {
-                            ast: T::SwitchOr(Box::new(rt1.ast), Box::new(lt1.ast)),
-                            pk_cost: le.pk_cost + rt.pk_cost + 5,
-                            sat_cost: le.sat_cost + 1,
-                            dissat_cost: 0,
-                        });
-                    }
-                    _ => {}
-                }
-                options.into_iter().min_by_key(|c| c.pk_cost + c.sat_cost).unwrap()
-            }
-            Descriptor::Wpkh(_) | Descriptor::Sh(_) | Descriptor::Wsh(_) => {
-                // handled at at the ParseTree::from_descriptor layer
-                unreachable!()
+        let secp = secp256k1::Secp256k1::new();
+        let keys = pubkeys(2);
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[0x11; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[0x22; 32]).expect("message");
+        let low = secp.sign(&msg, &sk).expect("signing context");
+
+        // `r` of one, `s` of the curve order less one
+        let mut compact = [0; 64];
+        compact[31] = 1;
+        compact[32..].copy_from_slice(&[
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+            0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b,
+            0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x40,
+        ]);
+        let high = secp256k1::Signature::from_compact(&secp, &compact).expect("signature");
+
+        let checksig = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckSig(keys[0])))));
+        let multisig = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckMultiSig(1, keys[..].into())))));
+
+        let mut sigs = HashMap::new();
+        sigs.insert(keys[0], low);
+        assert_eq!(checksig.satisfy(&sigs), Ok(vec![serialize_sig(&low, SigHashType::All)]));
+
+        sigs.insert(keys[0], high);
+        assert_eq!(checksig.satisfy(&sigs), Err(Error::NonStandardSignature("high S value")));
+        assert_eq!(multisig.satisfy(&sigs), Err(Error::NonStandardSignature("high S value")));
+    }
+
+    #[test]
+    fn lazy_satisfaction() {
+        use std::cell::RefCell;
+        use std::collections::HashMap;
+
+        /// Signs with every key, recording which were asked for
+        struct Counting(RefCell<Vec<secp256k1::PublicKey>>, HashMap<secp256k1::PublicKey, secp256k1::Signature>);
+
+        impl Satisfier for Counting {
+            fn lookup_sig(&self, pk: &secp256k1::PublicKey) -> Option<(secp256k1::Signature, SigHashType)> {
+                self.0.borrow_mut().push(*pk);
+                self.1.get(pk).map(|sig| (*sig, SigHashType::All))
             }
         }
+
+        let keys = pubkeys(4);
+        let (sigs, checker) = signed_by_each(&keys);
+
+        // The hash branch is the cheapest, so is tried first, but has no preimage
+        let tree = ParseTree(Arc::new(T::CastE(Arc::new(E::Threshold(
+            2,
+            Arc::new(E::HashEqual(sha256::Hash::from_data(&[]))),
+            vec![W::CheckSig(keys[0]), W::CheckSig(keys[1]), W::CheckSig(keys[2])],
+        )))));
+        let counting = Counting(RefCell::new(vec![]), sigs.clone());
+        let sat = tree.satisfy_with_mode(&counting, SatisfactionMode::Lazy).unwrap();
+        assert_eq!(*counting.0.borrow(), keys[0..2].to_owned());
+        // Every subexpression has its place on the stack, in script order from the top
+        assert_eq!(sat, vec![vec![], checker.0[&keys[1]].clone(), checker.0[&keys[0]].clone(), vec![]]);
+        let exec = tree.interpret(&sat, &checker).unwrap();
+        assert_eq!(exec.branch, vec![Condition::Sig(keys[0]), Condition::Sig(keys[1])]);
+
+        // Trying the subexpressions out of script order still leaves each in its place
+        let tree = ParseTree(Arc::new(T::CastE(Arc::new(E::Threshold(
+            2,
+            Arc::new(E::CheckMultiSig(2, keys[0..2].into())),
+            vec![W::CheckSig(keys[2]), W::HashEqual(sha256::Hash::from_data(&[])), W::CheckSig(keys[3])],
+        )))));
+        let counting = Counting(RefCell::new(vec![]), sigs.clone());
+        let sat = tree.satisfy_with_mode(&counting, SatisfactionMode::Lazy).unwrap();
+        assert_eq!(*counting.0.borrow(), keys[2..4].to_owned());
+        assert_eq!(sat.len(), 6);
+        let exec = tree.interpret(&sat, &checker).unwrap();
+        assert_eq!(exec.branch, vec![Condition::Sig(keys[2]), Condition::Sig(keys[3])]);
+
+        let counting = Counting(RefCell::new(vec![]), sigs.clone());
+        let sat = tree.satisfy_with_mode(&counting, SatisfactionMode::Smallest).unwrap();
+        assert_eq!(*counting.0.borrow(), keys.clone());
+        assert!(tree.interpret(&sat, &checker).is_ok());
+
+        let multi = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckMultiSig(2, keys[..].into())))));
+        let counting = Counting(RefCell::new(vec![]), sigs);
+        let sat = multi.satisfy_with_mode(&counting, SatisfactionMode::Lazy).unwrap();
+        assert_eq!(sat, vec![vec![], checker.0[&keys[0]].clone(), checker.0[&keys[1]].clone()]);
+        assert_eq!(*counting.0.borrow(), keys[0..2].to_owned());
+        assert!(multi.interpret(&sat, &checker).is_ok());
     }
-}
 
+    #[test]
+    fn max_satisfaction_size() {
+        use std::collections::HashMap;
+        use satisfy::Older;
+
+        let keys = pubkeys(4);
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let sig = secp.sign(&msg, &sk).expect("signing context");
+
+        let sigs: HashMap<secp256k1::PublicKey, secp256k1::Signature> = keys.iter().map(|pk| (*pk, sig)).collect();
+        let pkhs: HashMap<Hash160, secp256k1::PublicKey> = keys.iter()
+            .map(|pk| (Hash160::from_data(&pk.serialize()[..]), *pk))
+            .collect();
+        let mut preimages = HashMap::new();
+        preimages.insert(sha256::Hash::from_data(&[0; 32]), [0; 32]);
+        let satisfier = (sigs, pkhs, preimages, Older(1000));
+
+        let descs = vec![
+            Descriptor::Key(keys[0]),
+            Descriptor::KeyHash(keys[0]),
+            Descriptor::Multi(2, keys[0..3].to_owned()),
+            Descriptor::Hash(sha256::Hash::from_data(&[0; 32])),
+            Descriptor::Time(1000),
+            Descriptor::And(
+                Box::new(Descriptor::Key(keys[0])),
+                Box::new(Descriptor::Time(1000)),
+            ),
+            Descriptor::Or(
+                Box::new(Descriptor::Key(keys[0])),
+                Box::new(Descriptor::Multi(2, keys[1..4].to_owned())),
+            ),
+            Descriptor::AsymmetricOr(
+                Box::new(Descriptor::Key(keys[0])),
+                Box::new(Descriptor::And(
+                    Box::new(Descriptor::Key(keys[1])),
+                    Box::new(Descriptor::Hash(sha256::Hash::from_data(&[0; 32]))),
+                )),
+            ),
+            Descriptor::Threshold(2, vec![
+                Descriptor::Key(keys[0]),
+                Descriptor::KeyHash(keys[1]),
+                Descriptor::Time(1000),
+            ]),
+        ];
+
+        for desc in &descs {
+            let tree = ParseTree::compile(desc).unwrap();
+            let sat = tree.satisfy(&satisfier).expect("satisfiable");
+            let size: usize = sat.iter().map(|elem| elem.len() + 1).sum();
+            assert!(
+                size <= tree.max_satisfaction_size(),
+                "{}: {} > {}", tree.serialize(), size, tree.max_satisfaction_size()
+            );
+            assert!(sat.len() <= tree.max_satisfaction_items());
+
+            // The largest satisfaction is still a valid one, and within the bounds
+            let max = tree.satisfy_with_mode(&satisfier, SatisfactionMode::Max).expect("satisfiable");
+            assert!(tree.interpret(&max, &NoChecks).is_ok(), "{}", tree.serialize());
+            assert!(max.iter().map(|elem| elem.len() + 1).sum::<usize>() <= tree.max_satisfaction_size());
+            assert!(max.len() <= tree.max_satisfaction_items());
+        }
 
+        // Exact bounds for single fragments
+        assert_eq!(ParseTree::compile(&descs[0]).unwrap().max_satisfaction_size(), 74);
+        assert_eq!(ParseTree::compile(&descs[1]).unwrap().max_satisfaction_size(), 74 + 34);
+        assert_eq!(ParseTree::compile(&descs[2]).unwrap().max_satisfaction_size(), 1 + 2 * 74);
+        assert_eq!(ParseTree::compile(&descs[2]).unwrap().max_satisfaction_items(), 3);
+        assert_eq!(ParseTree::compile(&descs[3]).unwrap().max_satisfaction_size(), 33);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use bitcoin::util::hash::Sha256dHash; // TODO needs to be sha256, not sha256d
+    #[test]
+    fn required_keys_with() {
+        use std::collections::HashMap;
+        use satisfy::pkh_map;
+
+        let keys = pubkeys(3);
+        let tree = ParseTree(Arc::new(T::And(
+            Arc::new(V::CheckSig(keys[0])),
+            Arc::new(T::CascadeOr(
+                Arc::new(E::CheckSigHash(Hash160::from_data(&keys[1].serialize()[..]))),
+                Arc::new(T::CastE(Arc::new(E::CheckSigHash(Hash160::from_data(&keys[2].serialize()[..]))))),
+            )),
+        )));
 
-    use secp256k1;
+        assert_eq!(tree.required_keys(), vec![keys[0]]);
+        assert_eq!(
+            tree.required_keys_with(&pkh_map(&keys[1..2])),
+            (vec![keys[0], keys[1]], vec![Hash160::from_data(&keys[2].serialize()[..])])
+        );
 
-    fn pubkeys(n: usize) -> Vec<secp256k1::PublicKey> {
-        let mut ret = Vec::with_capacity(n);
+        // Signature maps resolve the hashes of their own keys
         let secp = secp256k1::Secp256k1::new();
-        let mut sk = [0; 32];
-        for i in 1..n+1 {
-            sk[0] = i as u8;
-            sk[1] = (i >> 8) as u8;
-            sk[2] = (i >> 16) as u8;
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let sigs: HashMap<secp256k1::PublicKey, secp256k1::Signature> = keys.iter().map(|pk| (*pk, secp.sign(&msg, &sk).expect("signing context"))).collect();
+        assert_eq!(tree.required_keys_with(&sigs), (keys.clone(), vec![]));
+        assert!(tree.satisfy(&sigs).is_ok());
+    }
 
-            let pk = secp256k1::PublicKey::from_secret_key(
-                &secp,
-                &secp256k1::SecretKey::from_slice(&secp, &sk[..]).expect("secret key"),
-            );
-            ret.push(pk);
-        }
-        ret
+    #[test]
+    fn key_report() {
+        let keys = pubkeys(3);
+        let hash = Hash160::from_data(&keys[2].serialize()[..]);
+
+        let tree = ParseTree(Arc::new(T::And(
+            Arc::new(V::CheckSig(keys[0])),
+            Arc::new(T::CascadeOr(
+                Arc::new(E::ParallelOr(
+                    Arc::new(E::CheckSig(keys[1])),
+                    Arc::new(W::CheckSig(keys[0])),
+                )),
+                Arc::new(T::CastE(Arc::new(E::CheckSigHash(hash)))),
+            )),
+        )));
+
+        let report = tree.key_report();
+        assert_eq!(
+            report.keys,
+            vec![
+                KeySource {
+                    key: keys[0],
+                    branches: vec![vec![0], vec![1, 0, 1]],
+                    always_required: true,
+                    label: None,
+                    origin: None,
+                },
+                KeySource {
+                    key: keys[1],
+                    branches: vec![vec![1, 0, 0]],
+                    always_required: false,
+                    label: None,
+                    origin: None,
+                },
+            ]
+        );
+        assert_eq!(report.key_hashes, vec![hash]);
+
+        let required = tree.required_keys();
+        assert_eq!(required.len(), 2);
+        assert!(required.contains(&keys[0]));
+        assert!(required.contains(&keys[1]));
+
+        let multi = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckMultiSig(2, keys[..].into())))));
+        let report = multi.key_report();
+        assert_eq!(report.keys.len(), 3);
+        assert!(report.keys.iter().all(|source| !source.always_required));
+        assert_eq!(report.keys[2].branches, vec![vec![2]]);
+        assert!(report.key_hashes.is_empty());
     }
 
-    fn roundtrip(tree: &ParseTree, s: &str) {
-        let ser = tree.serialize();
-        assert_eq!(ser.to_string(), s);
-        let deser = ParseTree::parse(&ser).expect("deserialize result of serialize");
-        assert_eq!(tree, &deser);
+    #[test]
+    fn is_satisfiable_with() {
+        let keys = pubkeys(3);
+        let owned = |idx: &[usize]| idx.iter().map(|&i| keys[i]).collect::<HashSet<_>>();
+
+        // 2-of-2 of the first keys, or the third after 1000 blocks
+        let desc = Descriptor::Or(
+            Box::new(Descriptor::Multi(2, keys[0..2].to_owned())),
+            Box::new(Descriptor::And(
+                Box::new(Descriptor::Key(keys[2])),
+                Box::new(Descriptor::Time(1000)),
+            )),
+        );
+        let tree = ParseTree::compile(&desc).unwrap();
+        assert!(tree.is_satisfiable_with(&owned(&[0, 1]), false, Some(0)));
+        assert!(!tree.is_satisfiable_with(&owned(&[0]), false, None));
+        assert!(tree.is_satisfiable_with(&owned(&[2]), false, None));
+        assert!(tree.is_satisfiable_with(&owned(&[2]), false, Some(1000)));
+        assert!(!tree.is_satisfiable_with(&owned(&[2]), false, Some(999)));
+        assert!(!tree.is_satisfiable_with(&owned(&[2]), false, Some(SEQUENCE_TYPE_FLAG | 0xffff)));
+
+        // A hash lock needs a preimage, and a pkh fragment is matched by the key's hash
+        let hash = sha256::Hash::from_data(&[1, 2, 3]);
+        let htlc = ParseTree::compile(&Descriptor::And(
+            Box::new(Descriptor::KeyHash(keys[0])),
+            Box::new(Descriptor::Hash(hash)),
+        )).unwrap();
+        assert!(htlc.is_satisfiable_with(&owned(&[0]), true, None));
+        assert!(!htlc.is_satisfiable_with(&owned(&[0]), false, None));
+        assert!(!htlc.is_satisfiable_with(&owned(&[1, 2]), true, None));
+
+        // Locktimes of both types can never be met together
+        let mixed = ParseTree::compile(&Descriptor::And(
+            Box::new(Descriptor::Key(keys[0])),
+            Box::new(Descriptor::And(
+                Box::new(Descriptor::Time(10)),
+                Box::new(Descriptor::Time(SEQUENCE_TYPE_FLAG | 10)),
+            )),
+        )).unwrap();
+        assert!(!mixed.is_satisfiable_with(&owned(&[0, 1, 2]), true, None));
     }
 
     #[test]
-    fn serialize() {
-        let keys = pubkeys(5);
+    fn spend_timeline() {
+        let keys = pubkeys(3);
+        let owned: HashSet<_> = keys[0..2].iter().cloned().collect();
+
+        let desc = Descriptor::Or(
+            Box::new(Descriptor::And(
+                Box::new(Descriptor::Key(keys[0])),
+                Box::new(Descriptor::Time(SEQUENCE_TYPE_FLAG | 10)),
+            )),
+            Box::new(Descriptor::Or(
+                Box::new(Descriptor::And(
+                    Box::new(Descriptor::Key(keys[1])),
+                    Box::new(Descriptor::Time(1000)),
+                )),
+                Box::new(Descriptor::Multi(2, keys.clone())),
+            )),
+        );
+        let tree = ParseTree::compile(&desc).unwrap();
+        let timeline = tree.spend_timeline(&owned, false, 100).unwrap();
+        let waits: Vec<Option<u32>> = timeline.iter().map(|time| time.relative_locktime).collect();
+        assert_eq!(waits, vec![None, Some(1000), Some(SEQUENCE_TYPE_FLAG | 10)]);
+        assert_eq!(timeline[0].conditions.len(), 2);
+        assert!(timeline[1].conditions.contains(&Condition::Sig(keys[1])));
+
+        // Without the second key only the time-based path is left
+        let alone: HashSet<_> = keys[0..1].iter().cloned().collect();
+        let timeline = tree.spend_timeline(&alone, false, 100).unwrap();
+        assert_eq!(timeline.len(), 1);
+        assert_eq!(timeline[0].relative_locktime, Some(SEQUENCE_TYPE_FLAG | 10));
+        assert_eq!(tree.spend_timeline(&HashSet::new(), true, 100).unwrap(), vec![]);
+    }
 
-        roundtrip(
-            &ParseTree(Box::new(T::CastE(Box::new(E::CheckSig(keys[0].clone()))))),
-            "Script(OP_PUSHBYTES_33 028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa OP_CHECKSIG)"
+    #[test]
+    fn check_timelocks() {
+        let keys = pubkeys(2);
+        let seconds = (1 << 22) | 100;
+
+        // The second branch of the disjunction can never be taken
+        let dead = ParseTree(Arc::new(T::And(
+            Arc::new(V::CheckSig(keys[1])),
+            Arc::new(T::CascadeOr(
+                Arc::new(E::CheckSig(keys[0])),
+                Arc::new(T::CastF(Arc::new(F::And(
+                    Arc::new(V::Csv(100)),
+                    Arc::new(F::Csv(seconds)),
+                )))),
+            )),
+        )));
+        assert_eq!(
+            dead.check_timelocks(),
+            vec![TimelockConflict {
+                branch: vec![1, 1],
+                heights: vec![100],
+                times: vec![seconds],
+                unsatisfiable: true,
+            }]
         );
-        roundtrip(
-            &ParseTree(Box::new(T::CastE(Box::new(E::CheckMultiSig(3, keys.clone()))))),
-            "Script(OP_PUSHNUM_3 OP_PUSHBYTES_33 028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa OP_PUSHBYTES_33 03ab1ac1872a38a2f196bed5a6047f0da2c8130fe8de49fc4d5dfb201f7611d8e2 OP_PUSHBYTES_33 039729247032c0dfcf45b4841fcd72f6e9a2422631fc3466cf863e87154754dd40 OP_PUSHBYTES_33 032564fe9b5beef82d3703a607253f31ef8ea1b365772df434226aee642651b3fa OP_PUSHBYTES_33 0289637f97580a796e050791ad5a2f27af1803645d95df021a3c2d82eb8c2ca7ff OP_PUSHNUM_5 OP_CHECKMULTISIG)"
+
+        // Only one of the three ways of satisfying the threshold is impossible
+        let partial = ParseTree(Arc::new(T::CastE(Arc::new(E::Threshold(
+            2,
+            Arc::new(E::CheckSig(keys[0])),
+            vec![W::Csv(100), W::Csv(seconds)],
+        )))));
+        assert_eq!(
+            partial.check_timelocks(),
+            vec![TimelockConflict {
+                branch: vec![],
+                heights: vec![100],
+                times: vec![seconds],
+                unsatisfiable: false,
+            }]
         );
 
-        let hash = Hash160::from_data(&keys[0].serialize());
-        roundtrip(
-            &ParseTree(Box::new(T::CastE(Box::new(E::CheckSigHash(hash))))),
-            "Script(OP_DUP OP_HASH160 OP_PUSHBYTES_20 60afcdec519698a263417ddfe7cea936737a0ee7 OP_EQUALVERIFY OP_CHECKSIG)"
+        let alternatives = ParseTree(Arc::new(T::CascadeOr(
+            Arc::new(E::CastF(Arc::new(F::Csv(100)))),
+            Arc::new(T::CastF(Arc::new(F::Csv(seconds)))),
+        )));
+        assert!(alternatives.check_timelocks().is_empty());
+    }
+
+    #[test]
+    fn hash_preimages_and_timelocks() {
+        let keys = pubkeys(1);
+        let hash = sha256::Hash::from_data(&[]);
+        let hash160 = Hash160::from_data(&[]);
+
+        let tree = ParseTree(Arc::new(T::And(
+            Arc::new(V::Csv(1000)),
+            Arc::new(T::SwitchOr(
+                Arc::new(T::And(Arc::new(V::HashEqual(hash)), Arc::new(T::Hash160Equal(hash160)))),
+                Arc::new(T::CascadeOr(
+                    Arc::new(E::CheckSig(keys[0])),
+                    Arc::new(T::CastF(Arc::new(F::And(
+                        Arc::new(V::Csv(1000)),
+                        Arc::new(F::Csv(144)),
+                    )))),
+                )),
+            )),
+        )));
+        assert_eq!(
+            tree.required_hash_preimages(),
+            vec![HashImage::Sha256(hash), HashImage::Hash160(hash160)]
         );
+        assert_eq!(tree.timelocks(), vec![1000, 144]);
 
-        // Liquid policy
-        roundtrip(
-            &ParseTree(Box::new(T::CascadeOr(
-                Box::new(E::CheckMultiSig(2, keys[0..2].to_owned())),
-                Box::new(T::And(
-                     Box::new(V::CheckMultiSig(2, keys[3..5].to_owned())),
-                     Box::new(T::CastF(Box::new(F::Csv(10000)))),
-                 )),
-             ))),
-             "Script(OP_PUSHNUM_2 OP_PUSHBYTES_33 028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa \
-                                  OP_PUSHBYTES_33 03ab1ac1872a38a2f196bed5a6047f0da2c8130fe8de49fc4d5dfb201f7611d8e2 \
-                                  OP_PUSHNUM_2 OP_CHECKMULTISIG \
-                     OP_IFDUP OP_NOTIF \
-                         OP_PUSHNUM_2 OP_PUSHBYTES_33 032564fe9b5beef82d3703a607253f31ef8ea1b365772df434226aee642651b3fa \
-                                      OP_PUSHBYTES_33 0289637f97580a796e050791ad5a2f27af1803645d95df021a3c2d82eb8c2ca7ff \
-                                      OP_PUSHNUM_2 OP_CHECKMULTISIGVERIFY \
-                         OP_PUSHBYTES_2 1027 OP_NOP3 \
-                     OP_ENDIF)"
-         );
+        let pk = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckSig(keys[0])))));
+        assert!(pk.required_hash_preimages().is_empty());
+        assert!(pk.timelocks().is_empty());
+    }
 
-        roundtrip(
-            &ParseTree(Box::new(T::CastF(Box::new(F::Csv(921))))),
-            "Script(OP_PUSHBYTES_2 9903 OP_NOP3)"
+    #[test]
+    fn spend_paths() {
+        let keys = pubkeys(3);
+
+        let tree = ParseTree(Arc::new(T::CascadeOr(
+            Arc::new(E::ParallelAnd(
+                Arc::new(E::CheckSig(keys[0])),
+                Arc::new(W::CheckSig(keys[1])),
+            )),
+            Arc::new(T::And(
+                Arc::new(V::CheckSig(keys[2])),
+                Arc::new(T::CastF(Arc::new(F::Csv(1000)))),
+            )),
+        )));
+        assert_eq!(
+            tree.spend_paths(10),
+            Ok(vec![
+                vec![Condition::Sig(keys[0]), Condition::Sig(keys[1])],
+                vec![Condition::Sig(keys[2]), Condition::Csv(1000)],
+            ])
+        );
+        assert_eq!(tree.spend_paths(1), Err(Error::TooManySpendPaths(1)));
+
+        // Paths which contain other paths are not minimal
+        let tree = ParseTree(Arc::new(T::And(
+            Arc::new(V::CheckSig(keys[0])),
+            Arc::new(T::CastE(Arc::new(E::ParallelOr(
+                Arc::new(E::CheckSig(keys[0])),
+                Arc::new(W::CheckSig(keys[1])),
+            )))),
+        )));
+        assert_eq!(tree.spend_paths(10), Ok(vec![vec![Condition::Sig(keys[0])]]));
+
+        let multi = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckMultiSig(2, keys[..].into())))));
+        assert_eq!(
+            multi.spend_paths(10),
+            Ok(vec![
+                vec![Condition::Sig(keys[0]), Condition::Sig(keys[1])],
+                vec![Condition::Sig(keys[0]), Condition::Sig(keys[2])],
+                vec![Condition::Sig(keys[1]), Condition::Sig(keys[2])],
+            ])
         );
+    }
 
-        roundtrip(
-            &ParseTree(Box::new(T::HashEqual(Sha256dHash::from_data(&[])))),
-            "Script(OP_SIZE OP_PUSHBYTES_1 20 OP_EQUALVERIFY OP_SHA256 OP_PUSHBYTES_32 5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456 OP_EQUAL)"
+    #[test]
+    fn satisfactions_missing() {
+        use std::collections::HashMap;
+        use satisfy::Older;
+
+        let keys = pubkeys(3);
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let sig = secp.sign(&msg, &sk).expect("signing context");
+        let pkh = Hash160::from_data(&keys[2].serialize()[..]);
+
+        let tree = ParseTree(Arc::new(T::CascadeOr(
+            Arc::new(E::ParallelAnd(
+                Arc::new(E::CheckSig(keys[0])),
+                Arc::new(W::CheckSig(keys[1])),
+            )),
+            Arc::new(T::And(
+                Arc::new(V::CheckSigHash(pkh)),
+                Arc::new(T::CastF(Arc::new(F::Csv(1000)))),
+            )),
+        )));
+        assert_eq!(
+            tree.satisfactions_missing(&(), 10),
+            Ok(vec![
+                vec![Condition::Sig(keys[0]), Condition::Sig(keys[1])],
+                vec![Condition::PkhSig(pkh), Condition::Csv(1000)],
+            ])
         );
 
-        roundtrip(
-            &ParseTree(Box::new(T::CastE(Box::new(E::CheckMultiSig(3, keys[0..5].to_owned()))))),
-            "Script(OP_PUSHNUM_3 \
-                    OP_PUSHBYTES_33 028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa \
-                    OP_PUSHBYTES_33 03ab1ac1872a38a2f196bed5a6047f0da2c8130fe8de49fc4d5dfb201f7611d8e2 \
-                    OP_PUSHBYTES_33 039729247032c0dfcf45b4841fcd72f6e9a2422631fc3466cf863e87154754dd40 \
-                    OP_PUSHBYTES_33 032564fe9b5beef82d3703a607253f31ef8ea1b365772df434226aee642651b3fa \
-                    OP_PUSHBYTES_33 0289637f97580a796e050791ad5a2f27af1803645d95df021a3c2d82eb8c2ca7ff \
-                    OP_PUSHNUM_5 OP_CHECKMULTISIG)"
+        let mut sigs = HashMap::new();
+        sigs.insert(keys[1], sig);
+        let mut pks = HashMap::new();
+        pks.insert(pkh, keys[2]);
+        assert_eq!(
+            tree.satisfactions_missing(&(&sigs, &pks, Older(1000)), 10),
+            Ok(vec![
+                vec![Condition::Sig(keys[0])],
+                vec![Condition::Sig(keys[2])],
+            ])
         );
 
-        roundtrip(
-            &ParseTree(Box::new(T::HashEqual(Sha256dHash::from_data(&[])))),
-            "Script(OP_SIZE OP_PUSHBYTES_1 20 OP_EQUALVERIFY OP_SHA256 OP_PUSHBYTES_32 5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456 OP_EQUAL)"
+        sigs.insert(keys[0], sig);
+        let missing = tree.satisfactions_missing(&sigs, 10).unwrap();
+        assert!(missing[0].is_empty());
+        assert!(tree.satisfy(&sigs).is_ok());
+
+        assert_eq!(tree.satisfactions_missing(&sigs, 1), Err(Error::TooManySpendPaths(1)));
+    }
+
+    #[test]
+    fn raw_fragment() {
+        use std::collections::HashMap;
+        use std::str::FromStr;
+
+        /// Satisfies `1 EQUALVERIFY` with a 1
+        struct One;
+        impl Satisfier for One {
+            fn lookup_raw(&self, script: &script::Script) -> Option<Vec<Vec<u8>>> {
+                if script[..] == [0x51, 0x88] { Some(vec![vec![1]]) } else { None }
+            }
+        }
+
+        let keys = pubkeys(1);
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let mut sigs = HashMap::new();
+        sigs.insert(keys[0], secp.sign(&msg, &sk).expect("signing context"));
+
+        let raw = script::Script::from(vec![0x51, 0x88]);
+        let desc = Descriptor::And(
+            Box::new(Descriptor::Key(keys[0])),
+            Box::new(Descriptor::RawVerify(raw.clone())),
         );
+        let s = desc.to_string();
+        assert!(s.ends_with(",raw_verify(5188))"));
+        assert_eq!(Descriptor::<secp256k1::PublicKey>::from_str(&s).unwrap().to_string(), s);
+        let tree = ParseTree::compile(&desc).unwrap();
+        assert!(tree.serialize()[..].windows(2).any(|w| w == &raw[..]));
+
+        // The raw script is satisfied by the caller, and only by the caller
+        assert_eq!(tree.satisfy(&sigs), Err(Error::MissingRaw(raw.clone())));
+        let witness = tree.satisfy(&(&sigs, One)).unwrap();
+        assert_eq!(witness.len(), 2);
+        assert!(witness.contains(&vec![1]));
+
+        let hash = sha256::Hash::from_data(&raw[..]);
+        let paths = tree.spend_paths(10).unwrap();
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].contains(&Condition::Sig(keys[0])) && paths[0].contains(&Condition::Raw(hash)));
+        assert_eq!(tree.satisfactions_missing(&sigs, 10), Ok(vec![vec![Condition::Raw(hash)]]));
+        assert_eq!(tree.satisfactions_missing(&(&sigs, One), 10), Ok(vec![vec![]]));
+        match tree.interpret(&witness, &NoChecks) {
+            Err(Error::ExecutionFailed { .. }) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
 
-        roundtrip(
-            &ParseTree(Box::new(T::CastF(Box::new(F::SwitchOrV(
-                Box::new(V::CheckSig(keys[0].clone())),
-                Box::new(V::And(
-                    Box::new(V::CheckSig(keys[1].clone())),
-                    Box::new(V::CheckSig(keys[2].clone())),
-                ))))),
-            )),
-            "Script(OP_SIZE OP_EQUALVERIFY OP_IF \
-                OP_PUSHBYTES_33 028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa OP_CHECKSIGVERIFY \
-                OP_ELSE \
-                OP_PUSHBYTES_33 03ab1ac1872a38a2f196bed5a6047f0da2c8130fe8de49fc4d5dfb201f7611d8e2 OP_CHECKSIGVERIFY \
-                OP_PUSHBYTES_33 039729247032c0dfcf45b4841fcd72f6e9a2422631fc3466cf863e87154754dd40 OP_CHECKSIGVERIFY \
-                OP_ENDIF OP_PUSHNUM_1)"
+        // A truncated push cannot be tokenized, so its limits cannot be checked
+        let bad = Descriptor::And(
+            Box::new(Descriptor::Key(keys[0])),
+            Box::new(Descriptor::RawVerify(script::Script::from(vec![0x4c]))),
+        );
+        let tree = ParseTree::compile(&bad).unwrap();
+        assert!(tree.check_context_limits(ScriptContext::SegwitV0).is_err());
+        assert_eq!(
+            ParseTree::compile_for_context(&bad, ScriptContext::SegwitV0),
+            Err(CompileError::BadRawScript(bad.to_string()))
         );
+    }
 
-        // fuzzer
-        roundtrip(
-            &ParseTree(Box::new(T::CastF(Box::new(F::SwitchOr(
-                Box::new(F::Csv(9)),
-                Box::new(F::Csv(7)),
-            ))))),
-            "Script(OP_SIZE OP_EQUALVERIFY OP_IF OP_PUSHNUM_9 OP_NOP3 OP_ELSE OP_PUSHNUM_7 OP_NOP3 OP_ENDIF)"
+    #[cfg(feature = "elements")]
+    #[test]
+    fn checksigfromstack() {
+        use std::collections::HashMap;
+        use std::str::FromStr;
+
+        /// Oracle which has signed one message
+        struct Oracle(secp256k1::PublicKey, sha256::Hash, secp256k1::Signature);
+        impl Satisfier for Oracle {
+            fn lookup_message_sig(&self, pk: &secp256k1::PublicKey, msg: &sha256::Hash) -> Option<secp256k1::Signature> {
+                if *pk == self.0 && *msg == self.1 { Some(self.2) } else { None }
+            }
+        }
+
+        let keys = pubkeys(1);
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[2; 32]).expect("secret key");
+        let oracle_key = secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context");
+        let msg = sha256::Hash::from_data(b"it rained");
+        let digest = sha256::Hash::from_data(&msg[..]);
+        let oracle_sig = secp.sign(&secp256k1::Message::from_slice(&digest[..]).expect("message"), &sk).expect("signing context");
+        let other_digest = sha256::Hash::from_data(b"it did not rain");
+        let other_sig = secp.sign(&secp256k1::Message::from_slice(&other_digest[..]).expect("message"), &sk).expect("signing context");
+        // transaction signatures are not checked below
+        let mut sigs = HashMap::new();
+        sigs.insert(keys[0], other_sig);
+
+        let desc = Descriptor::And(
+            Box::new(Descriptor::Key(keys[0])),
+            Box::new(Descriptor::SigFromStack(oracle_key, msg)),
         );
+        let s = desc.to_string();
+        assert!(s.ends_with(&format!(",{}))", msg)[..]));
+        assert_eq!(Descriptor::<secp256k1::PublicKey>::from_str(&s).unwrap().to_string(), s);
+
+        // Only Elements scripts may use `CHECKSIGFROMSTACK`
+        match ParseTree::compile_for_context(&desc, ScriptContext::SegwitV0) {
+            Err(CompileError::LimitExceeded(ScriptContext::SegwitV0, LimitViolation::DisabledOpcode(_))) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        let tree = ParseTree::compile_for_context(&desc, ScriptContext::Elements).unwrap();
+        let script = tree.serialize();
+        assert_eq!(ParseTree::parse_with_context(&script, ScriptContext::Elements), Ok(tree.clone()));
+        assert!(ParseTree::parse(&script).is_err());
+
+        let cond = Condition::MessageSig(oracle_key, msg);
+        assert_eq!(tree.spend_paths(10).unwrap()[0].len(), 2);
+        assert_eq!(tree.satisfactions_missing(&sigs, 10), Ok(vec![vec![cond]]));
+        assert_eq!(tree.satisfy(&sigs), Err(Error::MissingSig(oracle_key)));
+
+        let oracle = Oracle(oracle_key, msg, oracle_sig);
+        let witness = tree.satisfy(&(&sigs, oracle)).unwrap();
+        assert!(witness.contains(&oracle_sig.serialize_der(&secp)));
+        let exec = tree.interpret(&witness, &NoChecks).unwrap();
+        assert!(exec.branch.contains(&cond));
+
+        // The message signature is checked even without a spending transaction
+        let bad_witness = tree.satisfy(&(&sigs, Oracle(oracle_key, msg, other_sig))).unwrap();
+        match tree.interpret(&bad_witness, &NoChecks) {
+            Err(Error::ExecutionFailed { reason: "invalid signature", .. }) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
 
-        roundtrip(
-            &ParseTree(Box::new(T::And(
-                Box::new(V::SwitchOrT(
-                    Box::new(T::CastF(Box::new(F::Csv(9)))),
-                    Box::new(T::CastF(Box::new(F::Csv(7)))),
-                )),
-                Box::new(T::CastF(Box::new(F::Csv(7))))
-            ))),
-            "Script(OP_SIZE OP_EQUALVERIFY OP_IF OP_PUSHNUM_9 OP_NOP3 OP_ELSE OP_PUSHNUM_7 OP_NOP3 OP_ENDIF OP_VERIFY OP_PUSHNUM_7 OP_NOP3)"
+    #[test]
+    fn combine_satisfaction_states() {
+        use satisfy::SatisfactionState;
+
+        let keys = pubkeys(3);
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let sig = secp.sign(&msg, &sk).expect("signing context");
+        let preimage = [7; 32];
+
+        let tree = ParseTree(Arc::new(T::CascadeOr(
+            Arc::new(E::CheckMultiSig(2, keys[..].into())),
+            Arc::new(T::And(
+                Arc::new(V::CheckSigHash(Hash160::from_data(&keys[0].serialize()[..]))),
+                Arc::new(T::Hash160Equal(Hash160::from_data(&preimage[..]))),
+            )),
+        )));
+
+        // Each cosigner signs on their own
+        let mut first = SatisfactionState::new();
+        first.add_sig(keys[0], sig, SigHashType::All);
+        let mut second = SatisfactionState::new();
+        second.add_sig(keys[2], sig, SigHashType::All);
+        assert!(tree.satisfy(&first).is_err());
+        assert!(tree.satisfy(&second).is_err());
+
+        let mut combined = first.clone();
+        combined.combine(second.clone());
+        assert_eq!(combined.n_sigs(), 2);
+        let missing = tree.satisfactions_missing(&combined, 10).unwrap();
+        assert!(missing.iter().any(|path| path.is_empty()));
+        let sigser = serialize_sig(&sig, SigHashType::All);
+        assert_eq!(tree.satisfy(&combined), Ok(vec![vec![], sigser.clone(), sigser.clone()]));
+
+        // Combining is symmetric in what can be satisfied
+        let mut reversed = second;
+        reversed.combine(first.clone());
+        assert_eq!(reversed, combined);
+
+        // The key hash path only needs the first signature and the preimage
+        let mut with_preimage = SatisfactionState::new();
+        with_preimage.add_preimage(preimage);
+        with_preimage.combine(first);
+        assert!(tree.satisfy(&with_preimage).is_ok());
+    }
+
+    #[test]
+    fn spend_path_weights() {
+        let keys = pubkeys(3);
+        let pkh = Hash160::from_data(&keys[2].serialize()[..]);
+
+        let tree = ParseTree(Arc::new(T::CascadeOr(
+            Arc::new(E::CheckMultiSig(2, keys[0..2].into())),
+            Arc::new(T::And(
+                Arc::new(V::CheckSigHash(pkh)),
+                Arc::new(T::CastF(Arc::new(F::Csv(1000)))),
+            )),
+        )));
+        let weights = tree.spend_path_weights(10).unwrap();
+        assert_eq!(weights.len(), 2);
+
+        // Empty push for the multisig dummy, then two signatures
+        assert_eq!(weights[0].conditions, vec![Condition::Sig(keys[0]), Condition::Sig(keys[1])]);
+        assert_eq!(weights[0].satisfaction_items, 3);
+        assert_eq!(weights[0].satisfaction_size, 1 + 2 * 74);
+        assert_eq!(weights[0].relative_locktime, None);
+
+        // Key and signature, plus the multisig's dissatisfaction
+        assert_eq!(weights[1].conditions, vec![Condition::PkhSig(pkh), Condition::Csv(1000)]);
+        assert_eq!(weights[1].satisfaction_items, 5);
+        assert_eq!(weights[1].satisfaction_size, 3 + 74 + 34);
+        assert_eq!(weights[1].relative_locktime, Some(1000));
+        assert!(weights.iter().all(|weight| weight.satisfaction_size <= tree.max_satisfaction_size()));
+
+        // A path with both types of locktime is unusable
+        let mixed = ParseTree(Arc::new(T::And(
+            Arc::new(V::Csv(100)),
+            Arc::new(T::CastF(Arc::new(F::Csv(SEQUENCE_TYPE_FLAG | 10)))),
+        )));
+        assert_eq!(mixed.spend_paths(10).unwrap().len(), 1);
+        assert_eq!(mixed.spend_path_weights(10), Ok(vec![]));
+    }
+
+    #[test]
+    fn iter() {
+        let keys = pubkeys(2);
+
+        let tree = ParseTree(Arc::new(T::CascadeOr(
+            Arc::new(E::CheckSig(keys[0])),
+            Arc::new(T::And(
+                Arc::new(V::CheckSig(keys[1])),
+                Arc::new(T::CastF(Arc::new(F::Csv(100)))),
+            )),
+        )));
+        let nodes: Vec<Node> = tree.iter().collect();
+        let summary: Vec<(usize, ExprType, Fragment)> = nodes
+            .iter()
+            .map(|node| (node.depth, node.expr_type, node.fragment.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, ExprType::T, Fragment::CascadeOr),
+                (1, ExprType::E, Fragment::CheckSig(keys[0])),
+                (1, ExprType::T, Fragment::And),
+                (2, ExprType::V, Fragment::CheckSig(keys[1])),
+                (2, ExprType::T, Fragment::Cast),
+                (3, ExprType::F, Fragment::Csv(100)),
+            ]
         );
+        assert_eq!(nodes[0].script, tree.serialize());
+        assert_eq!(nodes[1].script.len(), 35);
+    }
 
-        roundtrip(
-            &ParseTree(Box::new(T::CastE(Box::new(E::ParallelOr(
-                Box::new(E::CheckMultiSig(0, vec![])),
-                Box::new(W::CheckSig(keys[0].clone())),
-            ))))),
-            "Script(OP_0 OP_0 OP_CHECKMULTISIG OP_SWAP OP_PUSHBYTES_33 028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa OP_CHECKSIG OP_BOOLOR)"
+    #[test]
+    fn from_descriptor() {
+        let keys = pubkeys(3);
+        let pk = Descriptor::Key(keys[0]);
+
+        let (tree, wrapper) = ParseTree::from_descriptor(&pk).unwrap();
+        assert_eq!(wrapper, Wrapper::Bare);
+        assert_eq!(tree, ParseTree::compile(&pk).unwrap());
+
+        let (tree, wrapper) = ParseTree::from_descriptor(&Descriptor::Wsh(Box::new(Descriptor::Key(keys[0])))).unwrap();
+        assert_eq!(wrapper, Wrapper::Wsh);
+        assert_eq!(tree, ParseTree::compile(&pk).unwrap());
+
+        let shwsh = Descriptor::Sh(Box::new(Descriptor::Wsh(Box::new(Descriptor::Key(keys[0])))));
+        let (tree, wrapper) = ParseTree::from_descriptor(&shwsh).unwrap();
+        assert_eq!(wrapper, Wrapper::ShWsh);
+        assert_eq!(tree, ParseTree::compile(&pk).unwrap());
+
+        let (tree, wrapper) = ParseTree::from_descriptor(&Descriptor::Wpkh(keys[1])).unwrap();
+        assert_eq!(wrapper, Wrapper::Wpkh);
+        assert_eq!(
+            tree,
+            ParseTree(Arc::new(T::CastE(Arc::new(E::CheckSigHash(Hash160::from_data(&keys[1].serialize()[..]))))))
         );
+
+        // Wrappers are only allowed at the top level
+        assert!(ParseTree::from_descriptor(&Descriptor::Wsh(Box::new(Descriptor::Wsh(Box::new(pk))))).is_err());
+        assert!(ParseTree::from_descriptor(&Descriptor::And(
+            Box::new(Descriptor::Key(keys[0])),
+            Box::new(Descriptor::Wpkh(keys[1])),
+        )).is_err());
+        assert!(ParseTree::from_descriptor(&Descriptor::Sh(Box::new(Descriptor::Sh(Box::new(Descriptor::Time(10)))))).is_err());
     }
 
     #[test]
@@ -2687,5 +7082,580 @@ mod tests {
         assert!(ParseTree::parse(&script::Script::from(vec![0x00, 0x00, 0xaf, 0x00, 0x00, 0xae, 0x85])).is_err()); // OR not BOOLOR
         assert!(ParseTree::parse(&script::Script::from(vec![0x00, 0x00, 0xaf, 0x00, 0x00, 0xae, 0x9b])).is_err()); // parallel OR without wrapping
     }
-}
 
+    #[test]
+    fn parse_error_position() {
+        let keys = pubkeys(2);
+
+        let script = script::Builder::new()
+            .push_slice(&keys[0].serialize()[..])
+            .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
+            .push_opcode(opcodes::All::OP_DUP)
+            .push_slice(&keys[1].serialize()[..])
+            .push_opcode(opcodes::All::OP_CHECKSIG)
+            .into_script();
+        match ParseTree::parse(&script) {
+            Err(Error::ParseError { offset, index, found, .. }) => {
+                assert_eq!(offset, 35);
+                assert_eq!(index, 2);
+                assert_eq!(found, Token::Dup.to_string());
+            }
+            x => panic!("unexpected parse result {:?}", x),
+        }
+
+        // A complete script preceded by leftover opcodes
+        let script = script::Builder::new()
+            .push_opcode(opcodes::All::OP_SWAP)
+            .push_slice(&keys[1].serialize()[..])
+            .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
+            .push_int(1)
+            .into_script();
+        match ParseTree::parse(&script) {
+            Err(Error::ParseError { offset, index, .. }) => {
+                assert_eq!(offset, 0);
+                assert_eq!(index, 0);
+            }
+            x => panic!("unexpected parse result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn max_depth() {
+        let keys = pubkeys(1);
+        let mut tree = T::CastE(Arc::new(E::CheckSig(keys[0])));
+        for _ in 0..150 {
+            tree = T::CascadeOr(Arc::new(E::CheckSig(keys[0])), Arc::new(tree));
+        }
+        let script = ParseTree(Arc::new(tree)).serialize();
+
+        // Unoptimized builds need more than the default test thread stack to
+        // parse this deep
+        ::std::thread::Builder::new().stack_size(64 << 20).spawn(move || {
+            assert_eq!(ParseTree::parse(&script), Err(Error::MaxRecursionDepthExceeded(MAX_PARSE_DEPTH)));
+            assert_eq!(ParseTree::parse_with_max_depth(&script, 10), Err(Error::MaxRecursionDepthExceeded(10)));
+            let parsed = ParseTree::parse_with_max_depth(&script, 1000).unwrap();
+            assert_eq!(parsed.serialize(), script);
+        }).unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn serialize_deep() {
+        let keys = pubkeys(1);
+        let mut tree = T::CastE(Arc::new(E::CheckSig(keys[0])));
+        for _ in 0..10000 {
+            tree = T::CascadeOr(Arc::new(E::CheckSig(keys[0])), Arc::new(tree));
+        }
+        let tree = ParseTree(Arc::new(tree));
+
+        // Each level is a 35-byte key and signature check, `IFDUP NOTIF` and `ENDIF`
+        let script = tree.serialize();
+        assert_eq!(script.len(), 35 + 10000 * 38);
+        // The levels' key checks and `IFDUP NOTIF`s all come before their `ENDIF`s
+        assert_eq!(&script[..37], &script[37..74]);
+        assert_eq!(script[..][script.len() - 1], opcodes::All::OP_ENDIF as u8);
+        assert!(tree.0.to_string().ends_with("OP_ENDIF)"));
+    }
+
+    #[test]
+    fn lex_key_encodings() {
+        let keys = pubkeys(4);
+        let even = *keys.iter().find(|pk| pk.serialize()[0] == 0x02).expect("key with even y");
+
+        // Uncompressed keys are only keys in legacy scripts
+        let script = script::Builder::new()
+            .push_slice(&keys[0].serialize_uncompressed()[..])
+            .push_opcode(opcodes::All::OP_CHECKSIG)
+            .into_script();
+        let tokens = lex(&script, ScriptContext::Legacy).unwrap();
+        assert_eq!(tokens, vec![Token::UncompressedPubkey(keys[0]), Token::CheckSig]);
+        assert!(lex(&script, ScriptContext::SegwitV0).is_err());
+        match ParseTree::parse_with_context(&script, ScriptContext::Legacy) {
+            Err(Error::ParseError { offset: 0, index: 0, expected: "compressed public key", .. }) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        // 32-byte pushes are x-only keys in tapscripts, when a signature check follows
+        let script = script::Builder::new()
+            .push_slice(&even.serialize()[1..])
+            .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
+            .push_opcode(opcodes::All::OP_SHA256)
+            .push_slice(&even.serialize()[1..])
+            .push_opcode(opcodes::All::OP_EQUAL)
+            .into_script();
+        let tokens = lex(&script, ScriptContext::Taproot).unwrap();
+        assert_eq!(tokens[0], Token::XOnlyPubkey(even));
+        assert_eq!(tokens[3], Token::Sha256Hash(sha256::Hash::from_slice(&even.serialize()[1..]).unwrap()));
+        match lex(&script, ScriptContext::SegwitV0).unwrap()[0] {
+            Token::Sha256Hash(..) => {}
+            x => panic!("unexpected token {:?}", x),
+        }
+    }
+
+    #[test]
+    fn token_stream() {
+        let keys = pubkeys(4);
+        let even = *keys.iter().find(|pk| pk.serialize()[0] == 0x02).expect("key with even y");
+
+        // The stream yields the tokens of `lex`, with the offset of each
+        let tree = ParseTree::compile(&Descriptor::Multi(2, keys[0..3].to_owned())).unwrap();
+        let script = tree.serialize();
+        let streamed: Vec<(usize, Token)> = TokenStream::new(&script, ScriptContext::SegwitV0).map(Result::unwrap).collect();
+        assert_eq!(streamed.iter().map(|&(_, tok)| tok).collect::<Vec<_>>(), lex(&script, ScriptContext::SegwitV0).unwrap());
+        assert_eq!(streamed.iter().map(|&(offset, _)| offset).collect::<Vec<_>>(), vec![0, 1, 35, 69, 103, 104]);
+        assert_eq!(ParseTree::parse_token_stream(TokenStream::new(&script, ScriptContext::SegwitV0)), Ok(tree.clone()));
+
+        // A 32-byte push is only a key once the following signature check is lexed
+        let script = script::Builder::new()
+            .push_slice(&even.serialize()[1..])
+            .push_opcode(opcodes::All::OP_CHECKSIG)
+            .into_script();
+        let mut stream = TokenStream::new(&script, ScriptContext::Taproot);
+        assert_eq!(stream.next(), Some(Ok((0, Token::XOnlyPubkey(even)))));
+        assert_eq!(stream.next(), Some(Ok((33, Token::CheckSig))));
+        assert_eq!(stream.next(), None);
+
+        // Nothing follows an error
+        let script = script::Builder::new()
+            .push_opcode(opcodes::All::OP_CHECKSIG)
+            .push_opcode(opcodes::All::OP_NOP)
+            .push_opcode(opcodes::All::OP_CHECKSIG)
+            .into_script();
+        let mut stream = TokenStream::new(&script, ScriptContext::SegwitV0);
+        assert_eq!(stream.next(), Some(Ok((0, Token::CheckSig))));
+        assert_eq!(stream.next(), Some(Err(Error::InvalidOpcode(opcodes::All::OP_NOP, 1))));
+        assert_eq!(stream.next(), None);
+        assert_eq!(
+            lex_partial(&script, ScriptContext::SegwitV0),
+            (vec![(0, Token::CheckSig)], Some(Error::InvalidOpcode(opcodes::All::OP_NOP, 1)))
+        );
+
+        // Malformed pushes are reported where they start
+        let truncated = script::Script::from(vec![0xac, 0x69, 0x05, 0x01]);
+        assert_eq!(
+            lex_partial(&truncated, ScriptContext::SegwitV0),
+            (
+                vec![(0, Token::CheckSig), (1, Token::Verify)],
+                Some(Error::Script(script::Error::EarlyEndOfScript, 2)),
+            )
+        );
+        let (tokens, error) = lex_partial(&tree.serialize(), ScriptContext::SegwitV0);
+        assert_eq!(tokens.into_iter().map(|(_, tok)| tok).collect::<Vec<_>>(), lex(&tree.serialize(), ScriptContext::SegwitV0).unwrap());
+        assert_eq!(error, None);
+
+        // Non-minimal numbers are reported as they are lexed
+        let script = script::Builder::new().push_slice(&[5]).push_opcode(opcodes::OP_CSV).into_script();
+        assert!(TokenStream::new(&script, ScriptContext::SegwitV0).any(|res| res.is_err()));
+        let mut stream = TokenStream::new(&script, ScriptContext::SegwitV0).allow_nonminimal();
+        assert_eq!(stream.next(), Some(Ok((0, Token::Number(5)))));
+        assert_eq!(stream.nonminimal_offsets(), &[0]);
+    }
+
+    #[test]
+    fn stats() {
+        let keys = pubkeys(3);
+        let tree = ParseTree(Arc::new(T::And(
+            Arc::new(V::CheckSig(keys[0])),
+            Arc::new(T::CascadeOr(
+                Arc::new(E::CheckMultiSig(2, keys[..].into())),
+                Arc::new(T::And(
+                    Arc::new(V::CheckSigHash(Hash160::from_data(&keys[1].serialize()[..]))),
+                    Arc::new(T::CastF(Arc::new(F::Csv(1000)))),
+                )),
+            )),
+        )));
+        let stats = tree.stats();
+        assert_eq!(stats.nodes, 8);
+        assert_eq!(stats.depth, 4);
+        let fragments: Vec<(&str, usize)> = stats.fragments.into_iter().collect();
+        assert_eq!(fragments, vec![("and", 2), ("cascade_or", 1), ("cast", 1), ("csv", 1), ("multi", 1), ("pk", 1), ("pkh", 1)]);
+
+        // The counts are those of the whole script
+        let (mut n_ops, mut pushes, mut push_bytes) = (0, 0, 0);
+        for ins in &tree.serialize() {
+            match ins {
+                script::Instruction::PushBytes(bytes) => {
+                    pushes += 1;
+                    push_bytes += bytes.len();
+                }
+                script::Instruction::Op(op) if op as u8 <= opcodes::All::OP_PUSHNUM_16 as u8 => pushes += 1,
+                _ => n_ops += 1,
+            }
+        }
+        assert_eq!((stats.opcodes, stats.pushes, stats.push_bytes), (n_ops, pushes, push_bytes));
+        assert_eq!(push_bytes, 33 * 4 + 20 + 2);
+    }
+
+    #[test]
+    fn structural_hash() {
+        let keys = pubkeys(6);
+        let tree = |k: usize, keys: &[secp256k1::PublicKey], csv: u32| ParseTree(Arc::new(T::CascadeOr(
+            Arc::new(E::CheckMultiSig(k, keys[0..3].into())),
+            Arc::new(T::And(
+                Arc::new(V::CheckSigHash(Hash160::from_data(&keys[3].serialize()[..]))),
+                Arc::new(T::CastF(Arc::new(F::Csv(csv)))),
+            )),
+        )));
+
+        // Keys, hashes and locktimes do not matter
+        let hash = tree(2, &keys[0..4], 1000).structural_hash();
+        assert_eq!(tree(2, &keys[2..6], SEQUENCE_TYPE_FLAG | 10).structural_hash(), hash);
+        // Thresholds and the shape do
+        assert!(tree(1, &keys[0..4], 1000).structural_hash() != hash);
+        let swapped = ParseTree(Arc::new(T::CascadeOr(
+            Arc::new(E::CheckMultiSig(2, keys[0..3].into())),
+            Arc::new(T::And(
+                Arc::new(V::CheckSig(keys[3])),
+                Arc::new(T::CastF(Arc::new(F::Csv(1000)))),
+            )),
+        )));
+        assert!(swapped.structural_hash() != hash);
+    }
+
+    #[test]
+    fn semantically_eq() {
+        let keys = pubkeys(3);
+        let tree = |t: T| ParseTree(Arc::new(t));
+        let hash = Hash160::from_data(&keys[0].serialize()[..]);
+
+        // A key matches its hash, however it is checked
+        let key = tree(T::CastE(Arc::new(E::CheckSig(keys[0]))));
+        assert!(key.semantically_eq(&tree(T::CastE(Arc::new(E::CheckSigHash(hash))))));
+        assert!(key.semantically_eq(&tree(T::CastE(Arc::new(E::CheckSigHashF(hash))))));
+        assert!(!key.semantically_eq(&tree(T::CastE(Arc::new(E::CheckSig(keys[1]))))));
+
+        // Branches may be encoded differently and in either order
+        let or = tree(T::CastF(Arc::new(F::SwitchOr(
+            Arc::new(F::CheckSig(keys[0])),
+            Arc::new(F::CheckSig(keys[1])),
+        ))));
+        let or_v = tree(T::CastF(Arc::new(F::SwitchOrV(
+            Arc::new(V::CheckSig(keys[1])),
+            Arc::new(V::CheckSig(keys[0])),
+        ))));
+        assert!(or.semantically_eq(&or_v));
+        assert!(or_v.semantically_eq(&or));
+        let multi = tree(T::CastE(Arc::new(E::CheckMultiSig(1, keys[0..2].into()))));
+        assert!(or.semantically_eq(&multi));
+
+        // but the conditions must be the same
+        let and = tree(T::And(
+            Arc::new(V::CheckSig(keys[0])),
+            Arc::new(T::CastE(Arc::new(E::CheckSig(keys[1])))),
+        ));
+        assert!(!or.semantically_eq(&and));
+        assert!(and.semantically_eq(&tree(T::CastE(Arc::new(E::CheckMultiSig(2, keys[0..2].into()))))));
+        assert!(!multi.semantically_eq(&tree(T::CastE(Arc::new(E::CheckMultiSig(1, keys[1..3].into()))))));
+    }
+
+    #[test]
+    fn lex_nops() {
+        let script = script::Builder::new()
+            .push_int(500_000)
+            .push_opcode(opcodes::OP_CLTV)
+            .push_opcode(opcodes::All::OP_DROP)
+            .push_opcode(opcodes::All::OP_NOP5)
+            .into_script();
+
+        // Reserved NOPs are rejected unless allowed, and CLTV is lexed but not parsed
+        assert_eq!(lex(&script, ScriptContext::SegwitV0), Err(Error::InvalidOpcode(opcodes::All::OP_NOP5, 6)));
+        let tokens: Result<Vec<_>, _> = TokenStream::new(&script, ScriptContext::SegwitV0)
+            .allow_reserved_nops()
+            .map(|res| res.map(|(_, tok)| tok))
+            .collect();
+        let tokens = tokens.unwrap();
+        assert_eq!(
+            tokens,
+            vec![Token::Number(500_000), Token::CheckLockTimeVerify, Token::Drop, Token::ReservedNop(opcodes::All::OP_NOP5)]
+        );
+        assert_eq!(tokens_to_script(&tokens), script);
+        assert!(ParseTree::parse(&script).is_err());
+    }
+
+    #[test]
+    fn pretty() {
+        let keys = pubkeys(2);
+        let hash = Hash160::from_data(&keys[1].serialize()[..]);
+        let tree = ParseTree(Arc::new(T::And(
+            Arc::new(V::CheckSig(keys[0])),
+            Arc::new(T::CascadeOr(
+                Arc::new(E::CheckMultiSig(1, keys[..].into())),
+                Arc::new(T::And(
+                    Arc::new(V::CheckSigHash(hash)),
+                    Arc::new(T::CastF(Arc::new(F::Csv(SEQUENCE_TYPE_FLAG | 7)))),
+                )),
+            )),
+        )));
+        assert_eq!(tree.to_string(), tree.0.to_string());
+
+        let key = |i: usize| keys[i].serialize()[..4].iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let pkh = hash[..4].iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let expected = [
+            "T and".to_owned(),
+            format!("  V pk({}..)", key(0)),
+            "  T cascade_or".to_owned(),
+            format!("    E multi(1,{}..,{}..)", key(0), key(1)),
+            "    T and".to_owned(),
+            format!("      V pkh({}..)", pkh),
+            "      T cast".to_owned(),
+            "        F csv(3584 seconds)".to_owned(),
+        ];
+        assert_eq!(tree.pretty(), expected.join("\n"));
+        assert_eq!(format!("{:#}", tree), tree.pretty());
+    }
+
+    #[test]
+    fn translate() {
+        let keys = pubkeys(4);
+        let compile = |k0, k1| {
+            let desc = Descriptor::Or(
+                Box::new(Descriptor::Multi(1, vec![k0, keys[1]])),
+                Box::new(Descriptor::And(Box::new(Descriptor::Key(k1)), Box::new(Descriptor::KeyHash(keys[2])))),
+            );
+            ParseTree::compile(&desc).expect("compile")
+        };
+
+        // Translating the tree is the same as compiling the translated descriptor, and
+        // hashed keys are never visited
+        let tree = compile(keys[0], keys[0]);
+        let mut seen = vec![];
+        let translated = tree.translate(|pk| -> Result<_, ()> {
+            seen.push(*pk);
+            Ok(if *pk == keys[0] { keys[3] } else { *pk })
+        }).unwrap();
+        assert_eq!(translated, compile(keys[3], keys[3]));
+        assert!(!seen.contains(&keys[2]));
+        assert_eq!(seen.len(), 3);
+
+        assert_eq!(tree.translate(|_| Err(())), Err(()));
+    }
+
+    #[test]
+    fn aggregate() {
+        use std::collections::HashMap;
+
+        /// Aggregates keys by adding up their secret keys, which it knows
+        struct SumAggregator(Vec<secp256k1::SecretKey>);
+
+        impl KeyAggregator for SumAggregator {
+            fn aggregate(&self, keys: &[secp256k1::PublicKey]) -> Result<secp256k1::PublicKey, secp256k1::Error> {
+                let secp = secp256k1::Secp256k1::new();
+                let mut sum: Option<secp256k1::SecretKey> = None;
+                for pk in keys {
+                    let sk = self.0.iter().find(|sk| secp256k1::PublicKey::from_secret_key(&secp, sk).expect("signing context") == *pk);
+                    let sk = *sk.ok_or(secp256k1::Error::InvalidPublicKey)?;
+                    sum = Some(match sum {
+                        Some(mut sum) => {
+                            sum.add_assign(&secp, &sk)?;
+                            sum
+                        }
+                        None => sk,
+                    });
+                }
+                Ok(secp256k1::PublicKey::from_secret_key(&secp, &sum.ok_or(secp256k1::Error::InvalidPublicKey)?).expect("signing context"))
+            }
+        }
+
+        let secp = secp256k1::Secp256k1::new();
+        let sks: Vec<_> = (1..4).map(|i| secp256k1::SecretKey::from_slice(&secp, &[i; 32]).expect("secret key")).collect();
+        let keys: Vec<_> = sks.iter().map(|sk| secp256k1::PublicKey::from_secret_key(&secp, sk).expect("signing context")).collect();
+        let aggregator = SumAggregator(sks.clone());
+        let agg_pk = aggregator.aggregate(&keys[0..2]).unwrap();
+
+        let desc = Descriptor::Or(
+            Box::new(Descriptor::Aggregate(keys[0..2].to_owned())),
+            Box::new(Descriptor::And(Box::new(Descriptor::Key(keys[2])), Box::new(Descriptor::Time(144)))),
+        );
+        let musig: Descriptor<secp256k1::PublicKey> = Descriptor::Aggregate(keys[0..2].to_owned());
+        assert_eq!(ParseTree::compile(&desc), Err(CompileError::NoAggregator(musig.to_string())));
+
+        // The script only checks the aggregate key, while the tree knows the participants
+        let tree = ParseTree::compile_with_aggregator(&desc, &aggregator).unwrap();
+        let script = tree.serialize();
+        let contains = |pk: &secp256k1::PublicKey| script[..].windows(33).any(|w| w == &pk.serialize()[..]);
+        assert!(contains(&agg_pk));
+        assert!(!contains(&keys[0]) && !contains(&keys[1]));
+        let required = tree.required_keys();
+        assert_eq!(required.len(), 3);
+        assert!(keys.iter().all(|pk| required.contains(pk)));
+        assert_eq!(
+            tree.iter().filter_map(|node| match node.fragment {
+                Fragment::CheckSigAggregate(pk, keys) => Some((pk, keys)),
+                _ => None,
+            }).collect::<Vec<_>>(),
+            vec![(agg_pk, keys[0..2].to_owned())]
+        );
+
+        // A single signature with the aggregate key satisfies it
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let mut agg_sk = sks[0];
+        agg_sk.add_assign(&secp, &sks[1]).unwrap();
+        let mut sigs: HashMap<secp256k1::PublicKey, secp256k1::Signature> = HashMap::new();
+        sigs.insert(agg_pk, secp.sign(&msg, &agg_sk).expect("signing context"));
+        let witness = tree.satisfy(&sigs).unwrap();
+        assert!(witness.contains(&serialize_sig(&sigs[&agg_pk], SigHashType::All)));
+    }
+
+    #[test]
+    fn checksigadd() {
+        use std::collections::HashMap;
+
+        // x-only keys are read back as the keys with even y-coordinate
+        let keys: Vec<_> = pubkeys(10).into_iter().filter(|pk| pk.serialize()[0] == 0x02).take(3).collect();
+        assert_eq!(keys.len(), 3);
+
+        let tree = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckSigAdd(2, keys[..].into())))));
+        let script = tree.serialize();
+        assert_eq!(script.len(), 3 * 34 + 2);
+        assert_eq!(ParseTree::parse_with_context(&script, ScriptContext::Taproot), Ok(tree.clone()));
+        assert!(ParseTree::parse(&script).is_err());
+
+        let verify = ParseTree(Arc::new(T::CastF(Arc::new(F::CheckSigAdd(1, keys[..].into())))));
+        let script = verify.serialize();
+        let reparsed = ParseTree::parse_with_context(&script, ScriptContext::Taproot).unwrap();
+        assert_eq!(reparsed.serialize(), script);
+
+        // Every key gets an element, the first key's on top of the stack
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let sig = secp.sign(&msg, &sk).expect("signing context");
+        let sigser = serialize_sig(&sig, SigHashType::All);
+        let mut sigs: HashMap<secp256k1::PublicKey, secp256k1::Signature> = HashMap::new();
+        sigs.insert(keys[0], sig);
+        sigs.insert(keys[2], sig);
+        assert_eq!(tree.satisfy(&sigs), Ok(vec![sigser.clone(), vec![], sigser.clone()]));
+        sigs.insert(keys[1], sig);
+        assert_eq!(
+            tree.satisfy_with_mode(&sigs, SatisfactionMode::Canonical),
+            Ok(vec![vec![], sigser.clone(), sigser.clone()])
+        );
+        sigs.remove(&keys[0]);
+        sigs.remove(&keys[2]);
+        assert_eq!(tree.satisfy(&sigs), Err(Error::CouldNotSatisfy));
+        assert_eq!(tree.max_satisfaction_items(), 3);
+
+        // CHECKSIGADD is only available in tapscripts, and CHECKMULTISIG is not
+        assert!(tree.check_context_limits(ScriptContext::Taproot).unwrap().is_empty());
+        assert_eq!(
+            tree.check_context_limits(ScriptContext::SegwitV0),
+            Ok(vec![LimitViolation::DisabledOpcode(OP_CHECKSIGADD)])
+        );
+        let multi = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckMultiSig(2, keys[..].into())))));
+        assert_eq!(
+            multi.check_context_limits(ScriptContext::Taproot),
+            Ok(vec![LimitViolation::DisabledOpcode(opcodes::All::OP_CHECKMULTISIG)])
+        );
+        let desc = Descriptor::MultiA(2, keys.clone());
+        assert_eq!(
+            ParseTree::compile_for_context(&desc, ScriptContext::SegwitV0),
+            Err(CompileError::LimitExceeded(ScriptContext::SegwitV0, LimitViolation::DisabledOpcode(OP_CHECKSIGADD)))
+        );
+    }
+
+    #[test]
+    fn token_roundtrip() {
+        let keys = pubkeys(3);
+        let hash = sha256::Hash::from_data(&[1, 2, 3]);
+        let tree = ParseTree(Arc::new(T::CastE(Arc::new(E::Threshold(
+            2,
+            Arc::new(E::CheckMultiSig(1, keys[0..2].into())),
+            vec![W::Csv(144), W::HashEqual(hash), W::CheckSig(keys[2])],
+        )))));
+        let script = tree.serialize();
+        let tokens = lex(&script, ScriptContext::SegwitV0).unwrap();
+        assert_eq!(tokens_to_script(&tokens), script);
+        assert_eq!(lex(&tokens_to_script(&tokens), ScriptContext::SegwitV0).unwrap(), tokens);
+        assert!(tokens.contains(&Token::Add));
+
+        let tokens = vec![Token::Number(0), Token::Number(16), Token::Number(17), Token::Number(0x7fffffff)];
+        assert_eq!(lex(&tokens_to_script(&tokens), ScriptContext::Bare).unwrap(), tokens);
+
+        // Non-minimal pushes would not encode back to the same script
+        let mut padded = vec![0x4c, 20];
+        padded.extend_from_slice(&[0; 20]);
+        match lex(&script::Script::from(padded.clone()), ScriptContext::SegwitV0) {
+            Err(Error::InvalidPush(ref bytes)) => assert_eq!(bytes.len(), 20),
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        // ...but non-minimal numbers may be accepted and flagged
+        let csv = script::Builder::new()
+            .push_slice(&[100, 0])
+            .push_opcode(opcodes::OP_CSV)
+            .push_slice(&[5])
+            .push_opcode(opcodes::All::OP_ADD)
+            .into_script();
+        assert_eq!(lex(&csv, ScriptContext::SegwitV0), Err(Error::InvalidPush(vec![100, 0])));
+        let (tokens, flagged) = lex_nonminimal(&csv, ScriptContext::SegwitV0).unwrap();
+        assert_eq!(tokens, vec![Token::Number(100), Token::CheckSequenceVerify, Token::Number(5), Token::Add]);
+        assert_eq!(flagged, vec![0, 4]);
+
+        let tree = ParseTree(Arc::new(T::CastF(Arc::new(F::Csv(100)))));
+        let mut historic = vec![0x02, 100, 0];
+        historic.extend_from_slice(&tree.serialize()[2..]);
+        let historic = script::Script::from(historic);
+        assert!(ParseTree::parse(&historic).is_err());
+        assert_eq!(ParseTree::parse_nonminimal(&historic, ScriptContext::SegwitV0), Ok((tree, vec![0])));
+
+        // ...as may keys and hashes pushed with PUSHDATA opcodes
+        let (padded_tokens, flagged) = lex_nonminimal(&script::Script::from(padded), ScriptContext::SegwitV0).unwrap();
+        assert_eq!(padded_tokens, vec![Token::Hash160Hash(Hash160::from(&[0; 20][..]))]);
+        assert_eq!(flagged, vec![0]);
+        let key = pubkeys(1)[0];
+        let tree = ParseTree(Arc::new(T::CastE(Arc::new(E::CheckSig(key)))));
+        let mut pushdata = vec![0x4e, 33, 0, 0, 0];
+        pushdata.extend_from_slice(&key.serialize()[..]);
+        pushdata.push(0xac);
+        let pushdata = script::Script::from(pushdata);
+        assert!(ParseTree::parse(&pushdata).is_err());
+        assert_eq!(ParseTree::parse_nonminimal(&pushdata, ScriptContext::SegwitV0), Ok((tree, vec![0])));
+    }
+
+    #[test]
+    fn template() {
+        let keys = pubkeys(10);
+        let key = |i: usize| Box::new(Descriptor::Key(keys[i]));
+        let time = || Box::new(Descriptor::Time(1008));
+        let hash = || Box::new(Descriptor::Hash160(Hash160::from_data(&[1, 2, 3])));
+        let template = |desc: Descriptor<secp256k1::PublicKey>| ParseTree::compile(&desc).unwrap().template();
+
+        assert_eq!(template(Descriptor::Key(keys[0])), Template::SingleKey);
+        assert_eq!(template(Descriptor::KeyHash(keys[0])), Template::SingleKey);
+        assert_eq!(template(Descriptor::Multi(2, keys[0..2].to_owned())), Template::Multisig(2, 2));
+        assert_eq!(template(Descriptor::And(key(0), key(1))), Template::Multisig(2, 2));
+
+        let htlc = || Box::new(Descriptor::Or(
+            Box::new(Descriptor::And(key(1), hash())),
+            Box::new(Descriptor::And(time(), key(2))),
+        ));
+        assert_eq!(template(*htlc()), Template::Htlc);
+        assert_eq!(template(Descriptor::Or(key(0), htlc())), Template::LightningHtlc);
+        let offered = Descriptor::Or(
+            key(0),
+            Box::new(Descriptor::Or(Box::new(Descriptor::And(key(1), hash())), Box::new(Descriptor::And(key(1), key(2))))),
+        );
+        assert_eq!(template(offered), Template::LightningHtlc);
+        let to_local = Descriptor::AsymmetricOr(Box::new(Descriptor::And(key(1), time())), key(0));
+        assert_eq!(template(to_local), Template::LightningToLocal);
+
+        let multi = || Box::new(Descriptor::Multi(2, keys[0..3].to_owned()));
+        assert_eq!(template(Descriptor::Or(multi(), Box::new(Descriptor::And(key(3), time())))), Template::EscrowWithTimeout);
+        let liquid = Descriptor::Or(
+            Box::new(Descriptor::Multi(4, keys[0..6].to_owned())),
+            Box::new(Descriptor::And(time(), Box::new(Descriptor::Multi(2, keys[6..9].to_owned())))),
+        );
+        assert_eq!(template(liquid), Template::Federation);
+
+        assert_eq!(template(Descriptor::And(key(0), hash())), Template::Custom);
+        assert_eq!(template(Descriptor::Or(multi(), key(3))), Template::Custom);
+    }
+
+    #[test]
+    fn shared_clone() {
+        let keys = pubkeys(3);
+        let tree = ParseTree::compile(&Descriptor::Multi(2, keys)).unwrap();
+        let copy = tree.clone();
+        assert!(Arc::ptr_eq(&tree.0, &copy.0));
+        assert_eq!(copy.serialize(), tree.serialize());
+    }
+}
@@ -0,0 +1,382 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Partially Signed Transactions
+//!
+//! Support for the Updater and Finalizer roles of BIP174, for inputs spending
+//! descriptor outputs. The version of rust-bitcoin we depend on has no PSBT support,
+//! so this module models the per-input fields of BIP174 which these roles touch;
+//! (de)serialization of the PSBT format itself is left to the caller.
+//!
+
+use std::collections::HashMap;
+
+use secp256k1;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{SigHashType, Transaction};
+use bitcoin::util::bip32::{ChildNumber, Fingerprint};
+use bitcoin::util::hash::{Hash160, Sha256dHash};
+use bitcoin_hashes::ripemd160::Hash as Ripemd160Hash;
+
+use sign::{assemble_input, descriptor_keys};
+use xpub::DescriptorXPub;
+use {sha256, Descriptor, Error, ParseTree, SatisfactionMode, Satisfier, Wrapper};
+
+/// The fingerprint of a master key, and the derivation path from it to some key
+pub type KeyOrigin = (Fingerprint, Vec<ChildNumber>);
+
+/// The BIP174 fields of a single transaction input
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Input {
+    /// Signatures collected so far, each a DER signature followed by its sighash type byte
+    pub partial_sigs: HashMap<secp256k1::PublicKey, Vec<u8>>,
+    /// The redeem script, for `Sh` outputs
+    pub redeem_script: Option<Script>,
+    /// The witness script, for `Wsh` outputs
+    pub witness_script: Option<Script>,
+    /// Origins of the keys needed to sign the input
+    pub bip32_derivation: HashMap<secp256k1::PublicKey, KeyOrigin>,
+    /// The final scriptSig, once the input is finalized
+    pub final_script_sig: Option<Script>,
+    /// The final witness, once the input is finalized
+    pub final_script_witness: Option<Vec<Vec<u8>>>,
+}
+
+/// A partially signed transaction
+#[derive(Clone, Debug, PartialEq)]
+pub struct Psbt {
+    /// The transaction being signed, with empty scriptSigs and witnesses
+    pub unsigned_tx: Transaction,
+    /// Signing data for each input of `unsigned_tx`
+    pub inputs: Vec<Input>,
+}
+
+/// Satisfier which answers signature requests from the partial signatures of a
+/// PSBT input, and forwards everything else
+struct PsbtSatisfier<'a, S: Satisfier + 'a> {
+    input: &'a Input,
+    keys: &'a [secp256k1::PublicKey],
+    inner: &'a S,
+}
+
+impl<'a, S: Satisfier> Satisfier for PsbtSatisfier<'a, S> {
+    fn lookup_sig(&self, pk: &secp256k1::PublicKey) -> Option<(secp256k1::Signature, SigHashType)> {
+        let sig = self.input.partial_sigs.get(pk)?;
+        let (hashtype, der) = sig.split_last()?;
+        let secp = secp256k1::Secp256k1::without_caps();
+        match secp256k1::Signature::from_der(&secp, der) {
+            Ok(sig) => Some((sig, SigHashType::from_u32(*hashtype as u32))),
+            Err(_) => None,
+        }
+    }
+
+    fn lookup_pkh(&self, hash: &Hash160) -> Option<secp256k1::PublicKey> {
+        for pk in self.keys {
+            if *hash == Hash160::from_data(&pk.serialize()[..]) {
+                return Some(*pk);
+            }
+        }
+        self.inner.lookup_pkh(hash)
+    }
+
+    fn lookup_hash_preimage(&self, hash: &sha256::Hash) -> Option<[u8; 32]> {
+        self.inner.lookup_hash_preimage(hash)
+    }
+
+    fn lookup_hash160_preimage(&self, hash: &Hash160) -> Option<[u8; 32]> {
+        self.inner.lookup_hash160_preimage(hash)
+    }
+
+    fn lookup_ripemd160_preimage(&self, hash: &Ripemd160Hash) -> Option<[u8; 32]> {
+        self.inner.lookup_ripemd160_preimage(hash)
+    }
+
+    fn lookup_hash256_preimage(&self, hash: &Sha256dHash) -> Option<[u8; 32]> {
+        self.inner.lookup_hash256_preimage(hash)
+    }
+
+    fn check_older(&self, n: u32) -> bool {
+        self.inner.check_older(n)
+    }
+
+    fn lookup_raw(&self, script: &Script) -> Option<Vec<Vec<u8>>> {
+        self.inner.lookup_raw(script)
+    }
+
+    #[cfg(feature = "elements")]
+    fn lookup_message_sig(&self, pk: &secp256k1::PublicKey, msg: &sha256::Hash) -> Option<secp256k1::Signature> {
+        self.inner.lookup_message_sig(pk, msg)
+    }
+}
+
+impl Psbt {
+    /// Returns the PSBT input at `index`, checking that the transaction has such an input
+    fn input(&self, index: usize) -> Result<&Input, Error> {
+        if index >= self.unsigned_tx.input.len() || index >= self.inputs.len() {
+            return Err(Error::InputIndexOutOfRange(index));
+        }
+        Ok(&self.inputs[index])
+    }
+
+    /// Acts as an Updater for input `index`, which spends an output described by `desc`:
+    /// fills in the redeem script and witness script needed by the output type, and
+    /// the key origin of every key in `desc` which appears in `origins`
+    pub fn update_input(
+        &mut self,
+        index: usize,
+        desc: &Descriptor<secp256k1::PublicKey>,
+        origins: &HashMap<secp256k1::PublicKey, KeyOrigin>,
+    ) -> Result<(), Error> {
+        self.input(index)?;
+        let (tree, wrapper) = ParseTree::from_descriptor(desc)?;
+        let script = tree.serialize();
+
+        let input = &mut self.inputs[index];
+        match (wrapper, desc) {
+            (Wrapper::Sh, _) => input.redeem_script = Some(script),
+            (Wrapper::Wsh, _) => input.witness_script = Some(script),
+            (Wrapper::ShWsh, Descriptor::Sh(inner)) => {
+                input.redeem_script = Some(inner.script_pubkey()?);
+                input.witness_script = Some(script);
+            }
+            (Wrapper::ShWpkh, Descriptor::Sh(inner)) => {
+                input.redeem_script = Some(inner.script_pubkey()?);
+            }
+            _ => {}
+        }
+
+        let mut keys = vec![];
+        descriptor_keys(desc, &mut keys);
+        for pk in keys {
+            if let Some(origin) = origins.get(&pk) {
+                input.bip32_derivation.insert(pk, origin.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Acts as an Updater for input `index`, which spends child `child` of the range
+    /// `desc`: as `update_input`, taking the key origins from the extended keys
+    pub fn update_input_derived(
+        &mut self,
+        index: usize,
+        desc: &Descriptor<DescriptorXPub>,
+        child: u32,
+    ) -> Result<(), Error> {
+        let (derived, origins) = desc.derive_with_origins(child)?;
+        self.update_input(index, &derived, &origins)
+    }
+
+    /// Acts as a Finalizer for input `index`, which spends an output described by `desc`:
+    /// satisfies the output using the collected partial signatures, with any hash
+    /// preimages and timelock information coming from `satisfier`. On success the final
+    /// scriptSig and witness are set, and the fields used to produce them are cleared.
+    pub fn finalize_input<S: Satisfier>(
+        &mut self,
+        index: usize,
+        desc: &Descriptor<secp256k1::PublicKey>,
+        satisfier: &S,
+    ) -> Result<(), Error> {
+        let (script_sig, witness) = {
+            let input = self.input(index)?;
+            let (tree, wrapper) = ParseTree::from_descriptor(desc)?;
+            let script = tree.serialize();
+
+            let mut keys = vec![];
+            descriptor_keys(desc, &mut keys);
+            let psbt_satisfier = PsbtSatisfier { input, keys: &keys, inner: satisfier };
+            let sat = tree.satisfy_for_wrapper(&psbt_satisfier, SatisfactionMode::Smallest, wrapper)?;
+            assemble_input(desc, &script, wrapper, sat)?
+        };
+
+        let input = &mut self.inputs[index];
+        input.partial_sigs.clear();
+        input.redeem_script = None;
+        input.witness_script = None;
+        input.bip32_derivation.clear();
+        input.final_script_sig = Some(script_sig);
+        input.final_script_witness = Some(witness);
+        Ok(())
+    }
+
+    /// Returns the keys in `desc` which have not yet signed input `index`, or an empty
+    /// list if the collected signatures already suffice to satisfy `desc`. Hash
+    /// preimages and timelocks are assumed to be available.
+    pub fn missing_signatures(
+        &self,
+        index: usize,
+        desc: &Descriptor<secp256k1::PublicKey>,
+    ) -> Result<Vec<secp256k1::PublicKey>, Error> {
+        let input = self.input(index)?;
+        let (tree, _) = ParseTree::from_descriptor(desc)?;
+
+        let mut keys = vec![];
+        descriptor_keys(desc, &mut keys);
+        if tree.satisfy(&PsbtSatisfier { input, keys: &keys, inner: &AllConditions }).is_ok() {
+            return Ok(vec![]);
+        }
+        keys.retain(|pk| !input.partial_sigs.contains_key(pk));
+        Ok(keys)
+    }
+}
+
+/// Satisfier which claims every hash preimage as the zero string, every timelock as
+/// met and every raw fragment as satisfied by nothing, for checking whether signatures
+/// alone are what is missing
+struct AllConditions;
+
+impl Satisfier for AllConditions {
+    fn lookup_hash_preimage(&self, _: &sha256::Hash) -> Option<[u8; 32]> {
+        Some([0; 32])
+    }
+
+    fn lookup_hash160_preimage(&self, _: &Hash160) -> Option<[u8; 32]> {
+        Some([0; 32])
+    }
+
+    fn lookup_ripemd160_preimage(&self, _: &Ripemd160Hash) -> Option<[u8; 32]> {
+        Some([0; 32])
+    }
+
+    fn lookup_hash256_preimage(&self, _: &Sha256dHash) -> Option<[u8; 32]> {
+        Some([0; 32])
+    }
+
+    fn check_older(&self, _: u32) -> bool {
+        true
+    }
+
+    fn lookup_raw(&self, _: &Script) -> Option<Vec<Vec<u8>>> {
+        Some(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use secp256k1;
+
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+    use bitcoin::util::bip32::{ChildNumber, Fingerprint};
+    use bitcoin::util::hash::Sha256dHash;
+    use satisfy::Older;
+    use xpub::DescriptorXPub;
+    use {Descriptor, Error};
+    use super::{Input, Psbt};
+
+    fn keys_and_sigs(n: usize) -> Vec<(secp256k1::PublicKey, Vec<u8>)> {
+        let secp = secp256k1::Secp256k1::new();
+        let msg = secp256k1::Message::from_slice(&[0xab; 32]).expect("message");
+        let mut ret = Vec::with_capacity(n);
+        for i in 1..n + 1 {
+            let sk = secp256k1::SecretKey::from_slice(&secp, &[i as u8; 32]).expect("secret key");
+            let pk = secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context");
+            let mut sig = secp.sign(&msg, &sk).expect("signing context").serialize_der(&secp);
+            sig.push(0x01);
+            ret.push((pk, sig));
+        }
+        ret
+    }
+
+    fn psbt() -> Psbt {
+        Psbt {
+            unsigned_tx: Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![TxIn {
+                    prev_hash: Sha256dHash::from_data(&[]),
+                    prev_index: 0,
+                    script_sig: Script::new(),
+                    sequence: 0xffffffff,
+                    witness: vec![],
+                }],
+                output: vec![TxOut {
+                    value: 99_000,
+                    script_pubkey: Script::new(),
+                }],
+            },
+            inputs: vec![Input::default()],
+        }
+    }
+
+    #[test]
+    fn update_and_finalize() {
+        let keys = keys_and_sigs(3);
+        let multi = || Descriptor::Multi(2, keys.iter().map(|&(pk, _)| pk).collect());
+        let desc = Descriptor::Sh(Box::new(Descriptor::Wsh(Box::new(multi()))));
+
+        let mut origins = HashMap::new();
+        let origin = (Fingerprint::from(&[1, 2, 3, 4][..]), vec![ChildNumber::Normal(7)]);
+        origins.insert(keys[1].0, origin.clone());
+
+        let mut psbt = psbt();
+        psbt.update_input(0, &desc, &origins).unwrap();
+        assert_eq!(psbt.inputs[0].witness_script, Some(multi().witness_script().unwrap()));
+        assert_eq!(
+            psbt.inputs[0].redeem_script,
+            Some(Descriptor::Wsh(Box::new(multi())).script_pubkey().unwrap())
+        );
+        assert_eq!(psbt.inputs[0].bip32_derivation.len(), 1);
+        assert_eq!(psbt.inputs[0].bip32_derivation[&keys[1].0], origin);
+
+        assert_eq!(
+            psbt.missing_signatures(0, &desc),
+            Ok(vec![keys[0].0, keys[1].0, keys[2].0])
+        );
+        psbt.inputs[0].partial_sigs.insert(keys[2].0, keys[2].1.clone());
+        assert_eq!(psbt.missing_signatures(0, &desc), Ok(vec![keys[0].0, keys[1].0]));
+        assert_eq!(psbt.finalize_input(0, &desc, &Older(0)), Err(Error::CouldNotSatisfy));
+
+        psbt.inputs[0].partial_sigs.insert(keys[0].0, keys[0].1.clone());
+        assert_eq!(psbt.missing_signatures(0, &desc), Ok(vec![]));
+        psbt.finalize_input(0, &desc, &Older(0)).unwrap();
+
+        let input = &psbt.inputs[0];
+        assert!(input.partial_sigs.is_empty());
+        assert!(input.bip32_derivation.is_empty());
+        assert_eq!(input.witness_script, None);
+        assert_eq!(input.final_script_sig.as_ref().map(|s| s.len()), Some(35));
+        let witness = input.final_script_witness.as_ref().unwrap();
+        assert_eq!(
+            *witness,
+            vec![vec![], keys[0].1.clone(), keys[2].1.clone(), multi().witness_script().unwrap()[..].to_owned()]
+        );
+
+        assert_eq!(psbt.missing_signatures(1, &desc), Err(Error::InputIndexOutOfRange(1)));
+    }
+
+    #[test]
+    fn update_derived() {
+        let xpub = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+        let desc: Descriptor<DescriptorXPub> = format!("wpkh([01020304/84'/0'/0']{}/0/*)", xpub).parse().unwrap();
+        let (derived, origins) = desc.derive_with_origins(5).unwrap();
+
+        let mut updated = psbt();
+        updated.update_input_derived(0, &desc, 5).unwrap();
+        assert_eq!(updated.inputs[0].bip32_derivation, origins);
+        let (_, path) = updated.inputs[0].bip32_derivation.values().next().unwrap().clone();
+        assert_eq!(path, vec![
+            ChildNumber::Hardened(84), ChildNumber::Hardened(0), ChildNumber::Hardened(0),
+            ChildNumber::Normal(0), ChildNumber::Normal(5),
+        ]);
+
+        let mut expected = psbt();
+        expected.update_input(0, &derived, &origins).unwrap();
+        assert_eq!(updated.inputs[0].bip32_derivation, expected.inputs[0].bip32_derivation);
+        assert_eq!(updated.update_input_derived(1, &desc, 5), Err(Error::InputIndexOutOfRange(1)));
+    }
+}
@@ -0,0 +1,645 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Satisfaction
+//!
+//! Abstraction over the data sources needed to produce a satisfying witness for a
+//! script. Satisfaction queries the satisfier one item at a time, so wallets only need
+//! to produce signatures (e.g. by querying an HSM) for keys that are actually used.
+//!
+
+use std::collections::HashMap;
+use secp256k1;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::SigHashType;
+use bitcoin::util::hash::Hash160;
+use bitcoin::util::hash::Sha256dHash;
+use bitcoin_hashes::Hash;
+use bitcoin_hashes::ripemd160::Hash as Ripemd160Hash;
+
+use descriptor::{SEQUENCE_LOCKTIME_MASK, SEQUENCE_TYPE_FLAG};
+use {sha256, Error};
+
+/// How to choose among the satisfactions of a script which a satisfier's data allows
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SatisfactionMode {
+    /// The satisfaction with the smallest witness
+    Smallest,
+    /// A satisfaction which depends only on which data the satisfier has, not on the
+    /// sizes of signatures: the first branches and keys (in script order) which can be
+    /// satisfied are used, so that all parties produce the same witness
+    Canonical,
+    /// The satisfaction with the largest witness, for worst-case fee estimation when
+    /// used with a satisfier which claims to have all data
+    Max,
+    /// A satisfaction which queries the satisfier as little as possible, for satisfiers
+    /// whose signatures are expensive to produce (e.g. one HSM round trip each). The
+    /// subexpressions of a threshold are tried in order of their maximum satisfaction
+    /// size, and those of a multisig in script order, until `k` have been satisfied, and
+    /// the rest are dissatisfied; the witness may be larger than that of `Smallest`
+    Lazy,
+}
+
+impl SatisfactionMode {
+    /// Whether to use the first of two alternative satisfactions, given their sizes
+    pub(crate) fn prefer_first(self, first_cost: usize, second_cost: usize) -> bool {
+        match self {
+            SatisfactionMode::Smallest | SatisfactionMode::Lazy => first_cost <= second_cost,
+            SatisfactionMode::Canonical => true,
+            SatisfactionMode::Max => first_cost >= second_cost,
+        }
+    }
+}
+
+/// How to choose among satisfactions, and where the chosen one will be placed, which
+/// determines the weight of each of its elements
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Strategy {
+    pub mode: SatisfactionMode,
+    /// Whether the satisfaction is pushed in a scriptSig rather than given as a witness
+    pub script_sig: bool,
+}
+
+impl Strategy {
+    /// Whether to use the first of two alternative satisfactions, given their weights
+    pub(crate) fn prefer_first(self, first_cost: usize, second_cost: usize) -> bool {
+        self.mode.prefer_first(first_cost, second_cost)
+    }
+
+    /// Weight of (part of) a satisfaction, in weight units
+    pub(crate) fn cost(self, sat: &[Vec<u8>]) -> usize {
+        if self.script_sig {
+            sat.iter().map(|elem| 4 * script_sig_size(elem)).sum()
+        } else {
+            sat.iter().map(|elem| witness_size(elem)).sum()
+        }
+    }
+}
+
+/// Size of a minimal push of `elem` onto a scriptSig
+fn script_sig_size(elem: &[u8]) -> usize {
+    match elem.len() {
+        0 => 1,
+        1 if elem[0] >= 1 && elem[0] <= 16 => 1,
+        n if n < 0x4c => 1 + n,
+        n if n < 0x100 => 2 + n,
+        n if n < 0x10000 => 3 + n,
+        n => 5 + n,
+    }
+}
+
+/// Size of `elem` as a witness element, with its length prefix
+fn witness_size(elem: &[u8]) -> usize {
+    match elem.len() {
+        n if n < 0xfd => 1 + n,
+        n if n < 0x10000 => 3 + n,
+        n => 5 + n,
+    }
+}
+
+/// Half the order of the secp256k1 group, the largest `S` value of a low-S signature
+const HALF_CURVE_ORDER: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0,
+];
+
+/// Checks that `sig`, a DER signature followed by its sighash type byte as it appears
+/// in a witness, meets the standardness rules which nodes apply to signatures: it must
+/// be strict DER (BIP66), its `S` value must be at most half the curve order (BIP146's
+/// LOW_S) and its sighash type must be one of the defined ones. Signatures breaking any
+/// of these are valid in blocks but not relayed. The error names the broken rule.
+pub fn validate_signature(sig: &[u8]) -> Result<(), Error> {
+    // 0x30 <total length> 0x02 <R length> <R> 0x02 <S length> <S> <sighash type>
+    if sig.len() < 9 {
+        return Err(Error::NonStandardSignature("signature too short"));
+    }
+    if sig.len() > 73 {
+        return Err(Error::NonStandardSignature("signature too long"));
+    }
+    if sig[0] != 0x30 {
+        return Err(Error::NonStandardSignature("signature is not a DER sequence"));
+    }
+    if sig[1] as usize != sig.len() - 3 {
+        return Err(Error::NonStandardSignature("DER sequence length does not match signature"));
+    }
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= sig.len() {
+        return Err(Error::NonStandardSignature("R overruns signature"));
+    }
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != sig.len() {
+        return Err(Error::NonStandardSignature("R and S lengths do not match signature"));
+    }
+
+    let r = &sig[4..4 + len_r];
+    let s = &sig[6 + len_r..6 + len_r + len_s];
+    if sig[2] != 0x02 || sig[4 + len_r] != 0x02 {
+        return Err(Error::NonStandardSignature("R or S is not a DER integer"));
+    }
+    for int in &[r, s] {
+        if int.is_empty() {
+            return Err(Error::NonStandardSignature("R or S is empty"));
+        }
+        if int[0] & 0x80 != 0 {
+            return Err(Error::NonStandardSignature("R or S is negative"));
+        }
+        if int.len() > 1 && int[0] == 0 && int[1] & 0x80 == 0 {
+            return Err(Error::NonStandardSignature("R or S has excess zero padding"));
+        }
+    }
+
+    // Without padding, `S` has at most 32 bytes and compares as a big-endian number
+    let s = if s[0] == 0 { &s[1..] } else { s };
+    if s.len() > 32 || (s.len() == 32 && s > &HALF_CURVE_ORDER[..]) {
+        return Err(Error::NonStandardSignature("high S value"));
+    }
+
+    let hashtype = sig[sig.len() - 1] & !0x80;
+    if !(1..=3).contains(&hashtype) {
+        return Err(Error::NonStandardSignature("undefined sighash type"));
+    }
+    Ok(())
+}
+
+/// Source of signatures, public keys, hash preimages and timelock information
+/// needed to satisfy a script. Every method has a default implementation which
+/// returns nothing, so implementors need only provide the data they have.
+pub trait Satisfier {
+    /// Given a public key, look up a signature with that key, along with the
+    /// sighash type that the signature commits to
+    fn lookup_sig(&self, _: &secp256k1::PublicKey) -> Option<(secp256k1::Signature, SigHashType)> {
+        None
+    }
+
+    /// Given a public key and the signature hash of the transaction input being
+    /// satisfied, produce a signature with that key. By default this falls back to
+    /// `lookup_sig`, which is appropriate for satisfiers holding precomputed signatures.
+    fn sign_sighash(&self, pk: &secp256k1::PublicKey, _: &secp256k1::Message) -> Option<(secp256k1::Signature, SigHashType)> {
+        self.lookup_sig(pk)
+    }
+
+    /// Whether the signatures given by `lookup_sig` and `sign_sighash` must pass
+    /// `validate_signature`; if not, satisfaction fails rather than producing a witness
+    /// which would not be relayed. Satisfiers giving placeholder signatures, such as
+    /// for estimating the size of a witness, may turn this off.
+    fn require_standard_sigs(&self) -> bool {
+        true
+    }
+
+    /// Given the hash of a public key, look up that public key
+    fn lookup_pkh(&self, _: &Hash160) -> Option<secp256k1::PublicKey> {
+        None
+    }
+
+    /// Given a SHA256 hash, look up its preimage
+    fn lookup_hash_preimage(&self, _: &sha256::Hash) -> Option<[u8; 32]> {
+        None
+    }
+
+    /// Given a HASH160 hash, look up its preimage
+    fn lookup_hash160_preimage(&self, _: &Hash160) -> Option<[u8; 32]> {
+        None
+    }
+
+    /// Given a RIPEMD160 hash, look up its preimage
+    fn lookup_ripemd160_preimage(&self, _: &Ripemd160Hash) -> Option<[u8; 32]> {
+        None
+    }
+
+    /// Given a HASH256 (double-SHA256) hash, look up its preimage
+    fn lookup_hash256_preimage(&self, _: &Sha256dHash) -> Option<[u8; 32]> {
+        None
+    }
+
+    /// Determine whether the relative locktime given by the `CHECKSEQUENCEVERIFY`
+    /// value `n` has been reached. As in BIP 68, `n` is a number of blocks unless bit
+    /// 22 is set, in which case it is a number of 512-second intervals; a locktime of
+    /// one type is never reached by an age of the other type.
+    fn check_older(&self, _: u32) -> bool {
+        false
+    }
+
+    /// Given the script of a `raw_verify` fragment, produce the witness elements
+    /// which satisfy it, in the order they appear in the witness
+    fn lookup_raw(&self, _: &Script) -> Option<Vec<Vec<u8>>> {
+        None
+    }
+
+    /// Given a public key and a message, look up a signature with that key of the
+    /// SHA256 of the message, as checked by Elements' `CHECKSIGFROMSTACK`
+    #[cfg(feature = "elements")]
+    fn lookup_message_sig(&self, _: &secp256k1::PublicKey, _: &sha256::Hash) -> Option<secp256k1::Signature> {
+        None
+    }
+}
+
+/// Satisfier which provides nothing, useful for producing dissatisfactions
+impl Satisfier for () {}
+
+impl<S: Satisfier + ?Sized> Satisfier for &S {
+    fn lookup_sig(&self, pk: &secp256k1::PublicKey) -> Option<(secp256k1::Signature, SigHashType)> {
+        (**self).lookup_sig(pk)
+    }
+
+    fn sign_sighash(&self, pk: &secp256k1::PublicKey, msg: &secp256k1::Message) -> Option<(secp256k1::Signature, SigHashType)> {
+        (**self).sign_sighash(pk, msg)
+    }
+
+    fn require_standard_sigs(&self) -> bool {
+        (**self).require_standard_sigs()
+    }
+
+    fn lookup_pkh(&self, hash: &Hash160) -> Option<secp256k1::PublicKey> {
+        (**self).lookup_pkh(hash)
+    }
+
+    fn lookup_hash_preimage(&self, hash: &sha256::Hash) -> Option<[u8; 32]> {
+        (**self).lookup_hash_preimage(hash)
+    }
+
+    fn lookup_hash160_preimage(&self, hash: &Hash160) -> Option<[u8; 32]> {
+        (**self).lookup_hash160_preimage(hash)
+    }
+
+    fn lookup_ripemd160_preimage(&self, hash: &Ripemd160Hash) -> Option<[u8; 32]> {
+        (**self).lookup_ripemd160_preimage(hash)
+    }
+
+    fn lookup_hash256_preimage(&self, hash: &Sha256dHash) -> Option<[u8; 32]> {
+        (**self).lookup_hash256_preimage(hash)
+    }
+
+    fn check_older(&self, n: u32) -> bool {
+        (**self).check_older(n)
+    }
+
+    fn lookup_raw(&self, script: &Script) -> Option<Vec<Vec<u8>>> {
+        (**self).lookup_raw(script)
+    }
+
+    #[cfg(feature = "elements")]
+    fn lookup_message_sig(&self, pk: &secp256k1::PublicKey, msg: &sha256::Hash) -> Option<secp256k1::Signature> {
+        (**self).lookup_message_sig(pk, msg)
+    }
+}
+
+/// Maps the Hash160 of each of `keys` to the key, as needed to satisfy `pkh` fragments
+/// checking those hashes
+pub fn pkh_map<'a, I>(keys: I) -> HashMap<Hash160, secp256k1::PublicKey>
+    where I: IntoIterator<Item = &'a secp256k1::PublicKey>
+{
+    keys.into_iter().map(|pk| (Hash160::from_data(&pk.serialize()[..]), *pk)).collect()
+}
+
+/// Signatures without an explicit sighash type are assumed to be `SIGHASH_ALL`. The
+/// keys of the map are also available for `pkh` fragments.
+impl Satisfier for HashMap<secp256k1::PublicKey, secp256k1::Signature> {
+    fn lookup_sig(&self, pk: &secp256k1::PublicKey) -> Option<(secp256k1::Signature, SigHashType)> {
+        self.get(pk).map(|sig| (*sig, SigHashType::All))
+    }
+
+    fn lookup_pkh(&self, hash: &Hash160) -> Option<secp256k1::PublicKey> {
+        self.keys().find(|pk| Hash160::from_data(&pk.serialize()[..]) == *hash).cloned()
+    }
+}
+
+/// The keys of the map are also available for `pkh` fragments
+impl Satisfier for HashMap<secp256k1::PublicKey, (secp256k1::Signature, SigHashType)> {
+    fn lookup_sig(&self, pk: &secp256k1::PublicKey) -> Option<(secp256k1::Signature, SigHashType)> {
+        self.get(pk).cloned()
+    }
+
+    fn lookup_pkh(&self, hash: &Hash160) -> Option<secp256k1::PublicKey> {
+        self.keys().find(|pk| Hash160::from_data(&pk.serialize()[..]) == *hash).cloned()
+    }
+}
+
+impl Satisfier for HashMap<Hash160, secp256k1::PublicKey> {
+    fn lookup_pkh(&self, hash: &Hash160) -> Option<secp256k1::PublicKey> {
+        self.get(hash).cloned()
+    }
+}
+
+impl Satisfier for HashMap<sha256::Hash, [u8; 32]> {
+    fn lookup_hash_preimage(&self, hash: &sha256::Hash) -> Option<[u8; 32]> {
+        self.get(hash).cloned()
+    }
+}
+
+/// Converts a preimage map keyed by `Sha256dHash`, as earlier versions of this library
+/// satisfied `Sha256` fragments with, into one keyed by `sha256::Hash`. The keys are
+/// taken to already hold single-SHA256 digests and are not rehashed.
+#[deprecated(note = "key SHA256 preimages by sha256::Hash directly")]
+pub fn sha256_preimages_from_sha256d(map: HashMap<Sha256dHash, [u8; 32]>) -> HashMap<sha256::Hash, [u8; 32]> {
+    #[allow(deprecated)]
+    map.into_iter().map(|(hash, preimage)| (sha256::Hash::from_sha256d(hash), preimage)).collect()
+}
+
+/// Accumulated signatures, public keys and hash preimages for satisfying a script, such
+/// as a coordinator collects from cosigners who sign independently. States produced by
+/// different parties can be merged with `combine`, and the result used as a satisfier
+/// once `ParseTree::satisfactions_missing` reports a complete spend path. Timelocks
+/// are not tracked; pair the state with `Older` or `OlderTime` in a tuple to meet them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SatisfactionState {
+    sigs: HashMap<secp256k1::PublicKey, (secp256k1::Signature, SigHashType)>,
+    pks: HashMap<Hash160, secp256k1::PublicKey>,
+    sha256_preimages: HashMap<sha256::Hash, [u8; 32]>,
+    hash160_preimages: HashMap<Hash160, [u8; 32]>,
+    ripemd160_preimages: HashMap<Ripemd160Hash, [u8; 32]>,
+    hash256_preimages: HashMap<Sha256dHash, [u8; 32]>,
+}
+
+impl SatisfactionState {
+    /// Creates an empty state
+    pub fn new() -> SatisfactionState {
+        SatisfactionState::default()
+    }
+
+    /// Adds a signature with `pk`, unless one is already present. The key is also made
+    /// available for `pkh` fragments.
+    pub fn add_sig(&mut self, pk: secp256k1::PublicKey, sig: secp256k1::Signature, hashtype: SigHashType) {
+        self.add_pk(pk);
+        self.sigs.entry(pk).or_insert((sig, hashtype));
+    }
+
+    /// Adds a public key, for `pkh` fragments checking its hash
+    pub fn add_pk(&mut self, pk: secp256k1::PublicKey) {
+        self.pks.insert(Hash160::from_data(&pk.serialize()[..]), pk);
+    }
+
+    /// Adds a hash preimage, for whichever of the four hash functions the script uses
+    pub fn add_preimage(&mut self, preimage: [u8; 32]) {
+        self.sha256_preimages.insert(sha256::Hash::from_data(&preimage[..]), preimage);
+        self.hash160_preimages.insert(Hash160::from_data(&preimage[..]), preimage);
+        self.ripemd160_preimages.insert(Ripemd160Hash::hash(&preimage[..]), preimage);
+        self.hash256_preimages.insert(Sha256dHash::from_data(&preimage[..]), preimage);
+    }
+
+    /// Merges the data of `other` into this state. Where both have a signature with
+    /// the same key, this state's is kept.
+    pub fn combine(&mut self, other: SatisfactionState) {
+        for (pk, sig) in other.sigs {
+            self.sigs.entry(pk).or_insert(sig);
+        }
+        self.pks.extend(other.pks);
+        self.sha256_preimages.extend(other.sha256_preimages);
+        self.hash160_preimages.extend(other.hash160_preimages);
+        self.ripemd160_preimages.extend(other.ripemd160_preimages);
+        self.hash256_preimages.extend(other.hash256_preimages);
+    }
+
+    /// Number of signatures collected
+    pub fn n_sigs(&self) -> usize {
+        self.sigs.len()
+    }
+}
+
+impl Satisfier for SatisfactionState {
+    fn lookup_sig(&self, pk: &secp256k1::PublicKey) -> Option<(secp256k1::Signature, SigHashType)> {
+        self.sigs.get(pk).cloned()
+    }
+
+    fn lookup_pkh(&self, hash: &Hash160) -> Option<secp256k1::PublicKey> {
+        self.pks.get(hash).cloned()
+    }
+
+    fn lookup_hash_preimage(&self, hash: &sha256::Hash) -> Option<[u8; 32]> {
+        self.sha256_preimages.get(hash).cloned()
+    }
+
+    fn lookup_hash160_preimage(&self, hash: &Hash160) -> Option<[u8; 32]> {
+        self.hash160_preimages.get(hash).cloned()
+    }
+
+    fn lookup_ripemd160_preimage(&self, hash: &Ripemd160Hash) -> Option<[u8; 32]> {
+        self.ripemd160_preimages.get(hash).cloned()
+    }
+
+    fn lookup_hash256_preimage(&self, hash: &Sha256dHash) -> Option<[u8; 32]> {
+        self.hash256_preimages.get(hash).cloned()
+    }
+}
+
+/// Satisfier for height-based relative timelocks, which claims that the output being
+/// spent has the given age in blocks
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Older(pub u32);
+
+impl Satisfier for Older {
+    fn check_older(&self, n: u32) -> bool {
+        n & SEQUENCE_TYPE_FLAG == 0 && self.0 >= n & SEQUENCE_LOCKTIME_MASK
+    }
+}
+
+/// Satisfier for time-based relative timelocks, which claims that the output being
+/// spent has the given age in seconds. Combine it with `Older` in a tuple to satisfy
+/// both types of timelock.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OlderTime(pub u32);
+
+impl Satisfier for OlderTime {
+    fn check_older(&self, n: u32) -> bool {
+        n & SEQUENCE_TYPE_FLAG != 0 && self.0 / 512 >= n & SEQUENCE_LOCKTIME_MASK
+    }
+}
+
+macro_rules! impl_tuple_satisfier(
+    ($($ty:ident),*) => (
+        #[allow(non_snake_case)]
+        impl<$($ty: Satisfier),*> Satisfier for ($($ty,)*) {
+            fn lookup_sig(&self, pk: &secp256k1::PublicKey) -> Option<(secp256k1::Signature, SigHashType)> {
+                let &($(ref $ty,)*) = self;
+                $(
+                if let Some(result) = $ty.lookup_sig(pk) {
+                    return Some(result);
+                }
+                )*
+                None
+            }
+
+            fn sign_sighash(&self, pk: &secp256k1::PublicKey, msg: &secp256k1::Message) -> Option<(secp256k1::Signature, SigHashType)> {
+                let &($(ref $ty,)*) = self;
+                $(
+                if let Some(result) = $ty.sign_sighash(pk, msg) {
+                    return Some(result);
+                }
+                )*
+                None
+            }
+
+            fn require_standard_sigs(&self) -> bool {
+                let &($(ref $ty,)*) = self;
+                true $(&& $ty.require_standard_sigs())*
+            }
+
+            fn lookup_pkh(&self, hash: &Hash160) -> Option<secp256k1::PublicKey> {
+                let &($(ref $ty,)*) = self;
+                $(
+                if let Some(result) = $ty.lookup_pkh(hash) {
+                    return Some(result);
+                }
+                )*
+                None
+            }
+
+            fn lookup_hash_preimage(&self, hash: &sha256::Hash) -> Option<[u8; 32]> {
+                let &($(ref $ty,)*) = self;
+                $(
+                if let Some(result) = $ty.lookup_hash_preimage(hash) {
+                    return Some(result);
+                }
+                )*
+                None
+            }
+
+            fn lookup_hash160_preimage(&self, hash: &Hash160) -> Option<[u8; 32]> {
+                let &($(ref $ty,)*) = self;
+                $(
+                if let Some(result) = $ty.lookup_hash160_preimage(hash) {
+                    return Some(result);
+                }
+                )*
+                None
+            }
+
+            fn lookup_ripemd160_preimage(&self, hash: &Ripemd160Hash) -> Option<[u8; 32]> {
+                let &($(ref $ty,)*) = self;
+                $(
+                if let Some(result) = $ty.lookup_ripemd160_preimage(hash) {
+                    return Some(result);
+                }
+                )*
+                None
+            }
+
+            fn lookup_hash256_preimage(&self, hash: &Sha256dHash) -> Option<[u8; 32]> {
+                let &($(ref $ty,)*) = self;
+                $(
+                if let Some(result) = $ty.lookup_hash256_preimage(hash) {
+                    return Some(result);
+                }
+                )*
+                None
+            }
+
+            fn check_older(&self, n: u32) -> bool {
+                let &($(ref $ty,)*) = self;
+                $(
+                if $ty.check_older(n) {
+                    return true;
+                }
+                )*
+                false
+            }
+
+            fn lookup_raw(&self, script: &Script) -> Option<Vec<Vec<u8>>> {
+                let &($(ref $ty,)*) = self;
+                $(
+                if let Some(result) = $ty.lookup_raw(script) {
+                    return Some(result);
+                }
+                )*
+                None
+            }
+
+            #[cfg(feature = "elements")]
+            fn lookup_message_sig(&self, pk: &secp256k1::PublicKey, msg: &sha256::Hash) -> Option<secp256k1::Signature> {
+                let &($(ref $ty,)*) = self;
+                $(
+                if let Some(result) = $ty.lookup_message_sig(pk, msg) {
+                    return Some(result);
+                }
+                )*
+                None
+            }
+        }
+    )
+);
+
+impl_tuple_satisfier!(A, B);
+impl_tuple_satisfier!(A, B, C);
+impl_tuple_satisfier!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bitcoin::util::hash::Sha256dHash;
+    use secp256k1;
+
+    use {sha256, Error};
+    use super::{validate_signature, Satisfier};
+
+    #[test]
+    fn validate_signatures() {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let mut sig = secp.sign(&msg, &sk).expect("signing context").serialize_der(&secp);
+        sig.push(0x01);
+        assert_eq!(validate_signature(&sig), Ok(()));
+        let mut acp = sig.clone();
+        *acp.last_mut().unwrap() = 0x83;
+        assert_eq!(validate_signature(&acp), Ok(()));
+
+        let err = |reason| Err(Error::NonStandardSignature(reason));
+        let mut bad = sig.clone();
+        *bad.last_mut().unwrap() = 0x04;
+        assert_eq!(validate_signature(&bad), err("undefined sighash type"));
+        assert_eq!(validate_signature(&sig[..8]), err("signature too short"));
+        assert_eq!(validate_signature(&sig[1..]), err("signature is not a DER sequence"));
+        let mut bad = sig.clone();
+        bad.push(0x01);
+        assert_eq!(validate_signature(&bad), err("DER sequence length does not match signature"));
+
+        // Padded and negative integers
+        let minimal = [0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x01, 0x01];
+        assert_eq!(validate_signature(&minimal), Ok(()));
+        let padded = [0x30, 0x07, 0x02, 0x02, 0x00, 0x01, 0x02, 0x01, 0x01, 0x01];
+        assert_eq!(validate_signature(&padded), err("R or S has excess zero padding"));
+        let negative = [0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x81, 0x01];
+        assert_eq!(validate_signature(&negative), err("R or S is negative"));
+        let not_int = [0x30, 0x06, 0x02, 0x01, 0x01, 0x03, 0x01, 0x01, 0x01];
+        assert_eq!(validate_signature(&not_int), err("R or S is not a DER integer"));
+
+        // The negation of `S` is an equally valid but high-S signature
+        let mut compact = [0; 64];
+        compact[31] = 1;
+        compact[32..].copy_from_slice(&[
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+            0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x40,
+        ]);
+        let mut high = secp256k1::Signature::from_compact(&secp, &compact).expect("signature").serialize_der(&secp);
+        high.push(0x01);
+        assert_eq!(validate_signature(&high), err("high S value"));
+        let mut half = [0x30, 0x25, 0x02, 0x01, 0x01, 0x02, 0x20].to_vec();
+        half.extend_from_slice(&super::HALF_CURVE_ORDER);
+        half.push(0x01);
+        assert_eq!(validate_signature(&half), Ok(()));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn sha256d_preimage_map() {
+        let preimage = [7; 32];
+        let hash = sha256::Hash::from_data(&preimage);
+        let mut old = HashMap::new();
+        old.insert(Sha256dHash::from(&hash[..]), preimage);
+        let map = super::sha256_preimages_from_sha256d(old);
+        assert_eq!(map.lookup_hash_preimage(&hash), Some(preimage));
+        assert_eq!(map.lookup_hash_preimage(&sha256::Hash::from_data(&[8; 32])), None);
+    }
+}
@@ -0,0 +1,127 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Script Numbers
+//!
+//! Encoding of the numbers which scripts push and do arithmetic on: little-endian
+//! with a sign bit in the top bit of the last byte, and zero as the empty string.
+//! Scripts push the numbers -1 and 0 to 16 with `OP_1NEGATE`, `OP_0` and `OP_1` to
+//! `OP_16`, and any other number as data. The lexer, the interpreter and script
+//! serialization all go through this module, so they agree on which encoding of a
+//! number is the minimal one.
+//!
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script;
+
+/// Encodes a number minimally, as the data which arithmetic opcodes leave on the stack
+pub fn encode(n: i64) -> Vec<u8> {
+    let mut ret = vec![];
+    let neg = n < 0;
+    let mut abs = n.unsigned_abs();
+    while abs > 0 {
+        ret.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if let Some(last) = ret.last().cloned() {
+        if last & 0x80 != 0 {
+            ret.push(if neg { 0x80 } else { 0 });
+        } else if neg {
+            *ret.last_mut().unwrap() |= 0x80;
+        }
+    }
+    ret
+}
+
+/// Decodes a number of at most `max_len` bytes, whether or not it is minimally
+/// encoded. Arithmetic opcodes take numbers of up to 4 bytes.
+pub fn decode(bytes: &[u8], max_len: usize) -> Option<i64> {
+    if bytes.len() > max_len || bytes.len() > 8 {
+        return None;
+    }
+    let mut ret = 0i64;
+    for (i, byte) in bytes.iter().enumerate() {
+        ret |= (*byte as i64) << (8 * i);
+    }
+    if let Some(last) = bytes.last() {
+        if last & 0x80 != 0 {
+            ret &= !(0x80i64 << (8 * (bytes.len() - 1)));
+            ret = -ret;
+        }
+    }
+    Some(ret)
+}
+
+/// Whether `bytes` is the minimal encoding of the number it decodes to, i.e. has
+/// no unneeded trailing zero (or sign-only) byte
+pub fn is_minimal(bytes: &[u8]) -> bool {
+    match bytes.split_last() {
+        Some((last, rest)) => last & 0x7f != 0 || rest.last().is_some_and(|b| b & 0x80 != 0),
+        None => true,
+    }
+}
+
+/// Appends the minimal push of `n` to a script
+pub fn push(builder: script::Builder, n: i64) -> script::Builder {
+    match n {
+        0 => builder.push_opcode(opcodes::All::OP_PUSHBYTES_0),
+        -1 => builder.push_opcode(opcodes::All::OP_PUSHNUM_NEG1),
+        1..=16 => builder.push_opcode(opcodes::All::from(opcodes::All::OP_PUSHNUM_1 as u8 + n as u8 - 1)),
+        _ => builder.push_slice(&encode(n)),
+    }
+}
+
+/// Length in bytes of the minimal push of `n`
+pub fn push_len(n: i64) -> usize {
+    match n {
+        -1..=16 => 1,
+        _ => 1 + encode(n).len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::script;
+
+    use super::{decode, encode, is_minimal, push, push_len};
+
+    #[test]
+    fn script_nums() {
+        for &(n, ref bytes) in &[(0, vec![]), (1, vec![1]), (-1, vec![0x81]), (127, vec![0x7f]),
+                                 (128, vec![0x80, 0]), (-128, vec![0x80, 0x80]), (256, vec![0, 1])] {
+            assert_eq!(encode(n), *bytes);
+            assert_eq!(decode(bytes, 4), Some(n));
+            assert!(is_minimal(bytes));
+        }
+        assert_eq!(decode(&[1, 2, 3, 4, 5], 4), None);
+        assert_eq!(decode(&[1, 2, 3, 4, 5], 5), Some(0x0504030201));
+
+        assert!(!is_minimal(&[0]));
+        assert!(!is_minimal(&[0x80]));
+        assert!(!is_minimal(&[5, 0]));
+        assert_eq!(decode(&[5, 0], 4), Some(5));
+        assert!(is_minimal(&[0xff, 0]));
+    }
+
+    #[test]
+    fn pushes() {
+        // Pushes agree with rust-bitcoin's, including around the edges of each encoding
+        for &n in &[-0x80000000i64, -0x7fffffff, -129, -128, -127, -17, -16, -2, -1, 0, 1, 16, 17,
+                    75, 76, 127, 128, 255, 256, 0x7fff, 0x8000, 0x7fffff, 0x800000, 0x7fffffff] {
+            let script = push(script::Builder::new(), n).into_script();
+            assert_eq!(script, script::Builder::new().push_int(n).into_script());
+            assert_eq!(script.len(), push_len(n));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # SHA256
+//!
+//! Single-SHA256 hash type, as checked by `OP_SHA256`. rust-bitcoin only provides
+//! the double-SHA256 `Sha256dHash`, which is what `OP_HASH256` checks; using it for
+//! `OP_SHA256` fragments leads users to compute the wrong digest.
+//!
+
+use std::{fmt, ops};
+
+use bitcoin::util::hash::Sha256dHash;
+use bitcoin::util::misc::hex_bytes;
+use bitcoin_hashes::Hash as BitcoinHash;
+use bitcoin_hashes::sha256::Hash as Sha256Hash;
+
+use Error;
+
+/// A SHA256 hash
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hash(Sha256Hash);
+
+impl Hash {
+    /// Computes the SHA256 hash of some data
+    pub fn from_data(data: &[u8]) -> Hash {
+        Hash(Sha256Hash::hash(data))
+    }
+
+    /// Interprets a 32-byte slice as a hash
+    pub fn from_slice(data: &[u8]) -> Result<Hash, Error> {
+        Sha256Hash::from_slice(data).map(Hash).map_err(|_| Error::InvalidPush(data.to_owned()))
+    }
+
+    /// Parses a hash from hex. Unlike `Sha256dHash`, the bytes are not reversed, so
+    /// the hex is in the same order that the hash appears in script
+    pub fn from_hex(s: &str) -> Result<Hash, Error> {
+        match hex_bytes(s) {
+            Ok(ref bytes) if bytes.len() == 32 => Hash::from_slice(bytes),
+            _ => Err(Error::Unexpected(s.to_owned())),
+        }
+    }
+
+    /// Reinterprets a `Sha256dHash` which is carrying the bytes of a SHA256 hash,
+    /// as was required by earlier versions of this library
+    #[deprecated(note = "use sha256::Hash throughout rather than Sha256dHash")]
+    pub fn from_sha256d(hash: Sha256dHash) -> Hash {
+        Hash::from_slice(&hash[..]).expect("32-byte hash")
+    }
+
+    /// Returns the underlying bytes
+    pub fn into_inner(self) -> [u8; 32] {
+        self.0.to_byte_array()
+    }
+}
+
+impl Default for Hash {
+    fn default() -> Hash {
+        Hash(Sha256Hash::all_zeros())
+    }
+}
+
+impl From<Sha256Hash> for Hash {
+    fn from(hash: Sha256Hash) -> Hash {
+        Hash(hash)
+    }
+}
+
+impl From<Hash> for Sha256Hash {
+    fn from(hash: Hash) -> Sha256Hash {
+        hash.0
+    }
+}
+
+impl ops::Index<ops::RangeFull> for Hash {
+    type Output = [u8];
+
+    fn index(&self, _: ops::RangeFull) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+impl fmt::LowerHex for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for x in &self.0[..] {
+            write!(f, "{:02x}", *x)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::LowerHex::fmt(self, f)
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::LowerHex::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin_hashes::Hash as BitcoinHash;
+    use bitcoin_hashes::sha256::Hash as Sha256Hash;
+
+    use super::Hash;
+
+    #[test]
+    fn sha256() {
+        let empty = Hash::from_data(&[]);
+        assert_eq!(
+            empty.to_string(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(Hash::from_hex(&empty.to_string()), Ok(empty));
+        assert!(Hash::from_hex("e3b0c442").is_err());
+        assert!(Hash::from_slice(&[0; 20]).is_err());
+
+        let inner = Sha256Hash::hash(&[]);
+        assert_eq!(Hash::from(inner), empty);
+        assert_eq!(Sha256Hash::from(empty), inner);
+        assert_eq!(empty.into_inner(), inner.to_byte_array());
+        assert_eq!(Hash::default().into_inner(), [0; 32]);
+    }
+}
@@ -0,0 +1,623 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Transaction Signing
+//!
+//! Produces complete scriptSigs and witnesses for transaction inputs spending
+//! descriptor outputs. This takes care of computing the (legacy or BIP143) signature
+//! hash, passing it to the satisfier, and adding the redeem script or witness script
+//! required by `Sh` and `Wsh` outputs.
+//!
+
+use std::cmp;
+
+use secp256k1;
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{self, Script};
+use bitcoin::blockdata::transaction::{SigHashType, Transaction};
+use bitcoin::util::bip143;
+use bitcoin::util::hash::{Hash160, Sha256dHash};
+use bitcoin_hashes::ripemd160::Hash as Ripemd160Hash;
+
+use parse::instruction_len;
+use {sha256, Descriptor, Error, ParseTree, SatisfactionMode, Satisfier, Wrapper};
+
+/// Satisfier which answers signature requests by asking the wrapped satisfier
+/// to sign a specific sighash, and forwards everything else
+struct SighashSatisfier<'a, S: Satisfier + 'a> {
+    inner: &'a S,
+    msg: secp256k1::Message,
+    /// Keys of the descriptor being satisfied, which key hashes are resolved
+    /// against if `inner` does not know them
+    keys: Vec<secp256k1::PublicKey>,
+}
+
+impl<'a, S: Satisfier> Satisfier for SighashSatisfier<'a, S> {
+    fn lookup_sig(&self, pk: &secp256k1::PublicKey) -> Option<(secp256k1::Signature, SigHashType)> {
+        // Only `SIGHASH_ALL` signatures commit to the sighash we computed
+        match self.inner.sign_sighash(pk, &self.msg) {
+            Some((sig, SigHashType::All)) => Some((sig, SigHashType::All)),
+            _ => None,
+        }
+    }
+
+    fn require_standard_sigs(&self) -> bool {
+        self.inner.require_standard_sigs()
+    }
+
+    fn lookup_pkh(&self, hash: &Hash160) -> Option<secp256k1::PublicKey> {
+        self.inner.lookup_pkh(hash).or_else(|| {
+            self.keys.iter().find(|pk| Hash160::from_data(&pk.serialize()[..]) == *hash).cloned()
+        })
+    }
+
+    fn lookup_hash_preimage(&self, hash: &sha256::Hash) -> Option<[u8; 32]> {
+        self.inner.lookup_hash_preimage(hash)
+    }
+
+    fn lookup_hash160_preimage(&self, hash: &Hash160) -> Option<[u8; 32]> {
+        self.inner.lookup_hash160_preimage(hash)
+    }
+
+    fn lookup_ripemd160_preimage(&self, hash: &Ripemd160Hash) -> Option<[u8; 32]> {
+        self.inner.lookup_ripemd160_preimage(hash)
+    }
+
+    fn lookup_hash256_preimage(&self, hash: &Sha256dHash) -> Option<[u8; 32]> {
+        self.inner.lookup_hash256_preimage(hash)
+    }
+
+    fn check_older(&self, n: u32) -> bool {
+        self.inner.check_older(n)
+    }
+
+    fn lookup_raw(&self, script: &Script) -> Option<Vec<Vec<u8>>> {
+        self.inner.lookup_raw(script)
+    }
+
+    #[cfg(feature = "elements")]
+    fn lookup_message_sig(&self, pk: &secp256k1::PublicKey, msg: &sha256::Hash) -> Option<secp256k1::Signature> {
+        self.inner.lookup_message_sig(pk, msg)
+    }
+}
+
+/// Computes the `SIGHASH_ALL` signature hash for an input spending an output of the
+/// given type, where `script_code` is the script being executed and `amount` is the
+/// value of the output (only used for segwit outputs)
+pub(crate) fn signature_hash(
+    tx: &Transaction,
+    index: usize,
+    amount: u64,
+    script_code: &Script,
+    wrapper: Wrapper,
+) -> Sha256dHash {
+    match wrapper {
+        Wrapper::Bare | Wrapper::Sh => {
+            tx.signature_hash(index, script_code, SigHashType::All.as_u32())
+        }
+        Wrapper::Wsh | Wrapper::ShWsh | Wrapper::Wpkh | Wrapper::ShWpkh => {
+            let components = bip143::SighashComponents::new(tx);
+            components.sighash_all(&tx.input[index], script_code, amount)
+        }
+    }
+}
+
+/// Collects every public key appearing in a descriptor, without duplicates
+pub(crate) fn descriptor_keys(desc: &Descriptor<secp256k1::PublicKey>, ret: &mut Vec<secp256k1::PublicKey>) {
+    match *desc {
+        Descriptor::Key(ref pk) | Descriptor::KeyHash(ref pk) |
+        Descriptor::Wpkh(ref pk) | Descriptor::Combo(ref pk) => {
+            if !ret.contains(pk) {
+                ret.push(*pk);
+            }
+        }
+        #[cfg(feature = "elements")]
+        Descriptor::SigFromStack(ref pk, _) => {
+            if !ret.contains(pk) {
+                ret.push(*pk);
+            }
+        }
+        Descriptor::Multi(_, ref keys) | Descriptor::SortedMulti(_, ref keys) |
+        Descriptor::MultiA(_, ref keys) | Descriptor::Aggregate(ref keys) => {
+            for pk in keys {
+                if !ret.contains(pk) {
+                    ret.push(*pk);
+                }
+            }
+        }
+        Descriptor::Hash(..) | Descriptor::Hash160(..) |
+        Descriptor::Ripemd160(..) | Descriptor::Hash256(..) | Descriptor::Time(..) |
+        Descriptor::Addr(..) | Descriptor::Raw(..) | Descriptor::RawVerify(..) |
+        Descriptor::OpReturn(..) => {}
+        Descriptor::Threshold(_, ref subs) => {
+            for sub in subs {
+                descriptor_keys(sub, ret);
+            }
+        }
+        Descriptor::And(ref left, ref right) |
+        Descriptor::Or(ref left, ref right) |
+        Descriptor::AsymmetricOr(ref left, ref right) |
+        Descriptor::WeightedOr(_, ref left, _, ref right) => {
+            descriptor_keys(left, ret);
+            descriptor_keys(right, ret);
+        }
+        Descriptor::Sh(ref sub) | Descriptor::Wsh(ref sub) => descriptor_keys(sub, ret),
+    }
+}
+
+/// Pushes a witness element onto a scriptSig, using the minimal encoding for small numbers
+fn push_element(builder: script::Builder, elem: &[u8]) -> script::Builder {
+    if elem.len() == 1 && elem[0] >= 1 && elem[0] <= 16 {
+        builder.push_int(elem[0] as i64)
+    } else {
+        builder.push_slice(elem)
+    }
+}
+
+/// Produces the scriptSig and witness for input `index` of `tx`, which spends an
+/// output of value `amount` described by `desc`. All signatures are requested from
+/// the satisfier (via `Satisfier::sign_sighash`) over the `SIGHASH_ALL` signature hash;
+/// signatures returned with any other sighash type are ignored.
+///
+/// For bare and `Sh` outputs the returned witness is empty; for native segwit
+/// outputs the returned scriptSig is empty.
+pub fn satisfy_transaction_input<S: Satisfier>(
+    tx: &Transaction,
+    index: usize,
+    amount: u64,
+    desc: &Descriptor<secp256k1::PublicKey>,
+    satisfier: &S,
+) -> Result<(Script, Vec<Vec<u8>>), Error> {
+    if index >= tx.input.len() {
+        return Err(Error::InputIndexOutOfRange(index));
+    }
+
+    let (tree, wrapper) = ParseTree::from_descriptor(desc)?;
+    let script = tree.serialize();
+
+    let sighash = signature_hash(tx, index, amount, &script, wrapper);
+    let msg = secp256k1::Message::from_slice(&sighash[..]).expect("32-byte sighash");
+    let mut keys = vec![];
+    descriptor_keys(desc, &mut keys);
+    let sighash_satisfier = SighashSatisfier { inner: satisfier, msg, keys };
+    let sat = tree.satisfy_for_wrapper(&sighash_satisfier, SatisfactionMode::Smallest, wrapper)?;
+    assemble_input(desc, &script, wrapper, sat)
+}
+
+/// A signature which may be needed to satisfy a transaction input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SighashRequest {
+    /// Index of the input in the spending transaction
+    pub index: usize,
+    /// The key which must sign
+    pub key: secp256k1::PublicKey,
+    /// The `SIGHASH_ALL` signature hash which must be signed
+    pub msg: secp256k1::Message,
+}
+
+/// Returns the signature hashes which must be signed, one per key of `desc`, to satisfy
+/// input `index` of `tx` spending an output of value `amount` described by `desc`. This
+/// is the same (legacy or BIP143) `SIGHASH_ALL` hash that `satisfy_transaction_input`
+/// would pass to `Satisfier::sign_sighash`, so external signers can produce signatures
+/// in advance. Every key which appears in the descriptor is listed, even if some spend
+/// paths do not need it.
+pub fn input_sighashes(
+    tx: &Transaction,
+    index: usize,
+    amount: u64,
+    desc: &Descriptor<secp256k1::PublicKey>,
+) -> Result<Vec<SighashRequest>, Error> {
+    if index >= tx.input.len() {
+        return Err(Error::InputIndexOutOfRange(index));
+    }
+
+    let (tree, wrapper) = ParseTree::from_descriptor(desc)?;
+    let sighash = signature_hash(tx, index, amount, &tree.serialize(), wrapper);
+    let msg = secp256k1::Message::from_slice(&sighash[..]).expect("32-byte sighash");
+
+    let mut keys = vec![];
+    descriptor_keys(desc, &mut keys);
+    Ok(keys.into_iter().map(|pk| SighashRequest { index, key: pk, msg }).collect())
+}
+
+/// Returns the signature hashes which must be signed for every input of `tx`, where
+/// `spent[i]` is the descriptor and value of the output spent by input `i`, in the
+/// sense of `input_sighashes`
+pub fn transaction_sighashes(
+    tx: &Transaction,
+    spent: &[(&Descriptor<secp256k1::PublicKey>, u64)],
+) -> Result<Vec<SighashRequest>, Error> {
+    if spent.len() != tx.input.len() {
+        return Err(Error::InputIndexOutOfRange(cmp::min(spent.len(), tx.input.len())));
+    }
+
+    let mut ret = vec![];
+    for (index, &(desc, amount)) in spent.iter().enumerate() {
+        ret.extend(input_sighashes(tx, index, amount, desc)?);
+    }
+    Ok(ret)
+}
+
+/// Arranges a satisfaction of `script` (the compiled form of `desc`, as returned by
+/// `ParseTree::from_descriptor` along with `wrapper`) into a scriptSig and witness
+pub(crate) fn assemble_input(
+    desc: &Descriptor<secp256k1::PublicKey>,
+    script: &Script,
+    wrapper: Wrapper,
+    sat: Vec<Vec<u8>>,
+) -> Result<(Script, Vec<Vec<u8>>), Error> {
+    match wrapper {
+        Wrapper::Bare | Wrapper::Sh => {
+            let mut builder = script::Builder::new();
+            for elem in &sat {
+                builder = push_element(builder, elem);
+            }
+            if wrapper == Wrapper::Sh {
+                builder = builder.push_slice(&script[..]);
+            }
+            Ok((builder.into_script(), vec![]))
+        }
+        Wrapper::Wsh | Wrapper::ShWsh | Wrapper::Wpkh | Wrapper::ShWpkh => {
+            let script_sig = match (wrapper, desc) {
+                // The redeem script of a wrapped segwit output is the witness program
+                (Wrapper::ShWsh, &Descriptor::Sh(ref inner)) |
+                (Wrapper::ShWpkh, &Descriptor::Sh(ref inner)) => {
+                    let program = inner.script_pubkey()?;
+                    script::Builder::new().push_slice(&program[..]).into_script()
+                }
+                _ => Script::new(),
+            };
+            let mut witness = sat;
+            if wrapper == Wrapper::Wsh || wrapper == Wrapper::ShWsh {
+                witness.push(script[..].to_owned());
+            }
+            Ok((script_sig, witness))
+        }
+    }
+}
+
+/// Recovers the satisfaction of `script` from the scriptSig and witness of an input
+/// spending an output of the given type, undoing `assemble_input`. The redeem script
+/// or witness script, if any, must be `script`.
+pub(crate) fn disassemble_input(
+    script: &Script,
+    wrapper: Wrapper,
+    script_sig: &Script,
+    witness: &[Vec<u8>],
+) -> Result<Vec<Vec<u8>>, Error> {
+    match wrapper {
+        Wrapper::Bare | Wrapper::Sh => {
+            if !witness.is_empty() {
+                return Err(Error::MalformedInput("witness spending a non-segwit output"));
+            }
+            let mut sat = vec![];
+            let mut offset = 0;
+            for ins in script_sig {
+                sat.push(match ins {
+                    script::Instruction::PushBytes(bytes) => bytes.to_owned(),
+                    script::Instruction::Op(op) if op as u8 >= opcodes::All::OP_PUSHNUM_1 as u8 &&
+                                                   op as u8 <= opcodes::All::OP_PUSHNUM_16 as u8 => {
+                        vec![op as u8 - opcodes::All::OP_PUSHNUM_1 as u8 + 1]
+                    }
+                    script::Instruction::Op(op) => return Err(Error::InvalidOpcode(op, offset)),
+                    script::Instruction::Error(e) => return Err(Error::Script(e, offset)),
+                });
+                offset += instruction_len(&script_sig[..], offset);
+            }
+            if wrapper == Wrapper::Sh && sat.pop().as_ref().map(|s| &s[..]) != Some(&script[..]) {
+                return Err(Error::MalformedInput("redeem script does not match"));
+            }
+            Ok(sat)
+        }
+        Wrapper::Wsh | Wrapper::ShWsh | Wrapper::Wpkh | Wrapper::ShWpkh => {
+            let mut sat = witness.to_owned();
+            if (wrapper == Wrapper::Wsh || wrapper == Wrapper::ShWsh)
+                && sat.pop().as_ref().map(|s| &s[..]) != Some(&script[..]) {
+                    return Err(Error::MalformedInput("witness script does not match"));
+                }
+            Ok(sat)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1;
+
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{SigHashType, Transaction, TxIn, TxOut};
+    use bitcoin::util::bip143;
+    use bitcoin::util::hash::{Hash160, Sha256dHash};
+    use {Condition, Descriptor, Error, NoChecks, ParseTree, Satisfier, Wrapper};
+    use super::{input_sighashes, satisfy_transaction_input, signature_hash, transaction_sighashes};
+
+    /// Satisfier which signs with a single secret key
+    struct KeySigner(secp256k1::SecretKey, secp256k1::PublicKey);
+
+    impl Satisfier for KeySigner {
+        fn sign_sighash(&self, pk: &secp256k1::PublicKey, msg: &secp256k1::Message) -> Option<(secp256k1::Signature, SigHashType)> {
+            if *pk == self.1 {
+                Some((secp256k1::Secp256k1::new().sign(msg, &self.0).expect("signing context"), SigHashType::All))
+            } else {
+                None
+            }
+        }
+
+        fn lookup_pkh(&self, hash: &Hash160) -> Option<secp256k1::PublicKey> {
+            if *hash == Hash160::from_data(&self.1.serialize()[..]) {
+                Some(self.1)
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Satisfier which only signs, and does not know any key hashes
+    struct SignOnly<'a>(&'a KeySigner);
+
+    impl<'a> Satisfier for SignOnly<'a> {
+        fn sign_sighash(&self, pk: &secp256k1::PublicKey, msg: &secp256k1::Message) -> Option<(secp256k1::Signature, SigHashType)> {
+            self.0.sign_sighash(pk, msg)
+        }
+    }
+
+    fn spending_tx() -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                prev_hash: Sha256dHash::from_data(&[]),
+                prev_index: 0,
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 99_000,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn sign_input() {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let pk = secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context");
+        let signer = KeySigner(sk, pk);
+        let tx = spending_tx();
+
+        // Wsh: witness is the signature followed by the witness script
+        let wsh = Descriptor::Wsh(Box::new(Descriptor::Key(pk)));
+        let ws = wsh.witness_script().unwrap();
+        let (script_sig, witness) = satisfy_transaction_input(&tx, 0, 100_000, &wsh, &signer).unwrap();
+        assert_eq!(script_sig, Script::new());
+        assert_eq!(witness.len(), 2);
+        assert_eq!(witness[1], ws[..].to_owned());
+        assert_eq!(*witness[0].last().unwrap(), 0x01);
+
+        let sighash = bip143::SighashComponents::new(&tx).sighash_all(&tx.input[0], &ws, 100_000);
+        let msg = secp256k1::Message::from_slice(&sighash[..]).unwrap();
+        let sig = secp256k1::Signature::from_der(&secp, &witness[0][..witness[0].len() - 1]).unwrap();
+        assert!(secp.verify(&msg, &sig, &pk).is_ok());
+
+        // Sh(Wsh): same witness, scriptSig pushes the witness program
+        let shwsh = Descriptor::Sh(Box::new(wsh));
+        let (script_sig, witness2) = satisfy_transaction_input(&tx, 0, 100_000, &shwsh, &signer).unwrap();
+        assert_eq!(script_sig.len(), 35);
+        assert_eq!(witness2, witness);
+
+        // Wpkh: witness is the signature and the public key
+        let wpkh = Descriptor::Wpkh(pk);
+        let (script_sig, witness) = satisfy_transaction_input(&tx, 0, 100_000, &wpkh, &signer).unwrap();
+        assert_eq!(script_sig, Script::new());
+        assert_eq!(witness.len(), 2);
+        assert_eq!(witness[1], pk.serialize()[..].to_owned());
+        // the key is taken from the descriptor if the satisfier does not know it
+        let only = satisfy_transaction_input(&tx, 0, 100_000, &wpkh, &SignOnly(&signer)).unwrap();
+        assert_eq!(only, (script_sig, witness));
+
+        // Sh: no witness, scriptSig ends with the redeem script
+        let sh = Descriptor::Sh(Box::new(Descriptor::Key(pk)));
+        let (script_sig, witness) = satisfy_transaction_input(&tx, 0, 100_000, &sh, &signer).unwrap();
+        assert!(witness.is_empty());
+        let redeem_script = sh.witness_script().unwrap();
+        assert_eq!(&script_sig[script_sig.len() - redeem_script.len()..], &redeem_script[..]);
+
+        assert_eq!(
+            satisfy_transaction_input(&tx, 1, 100_000, &sh, &signer),
+            Err(Error::InputIndexOutOfRange(1))
+        );
+    }
+
+    #[test]
+    fn sighashes() {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let pk = secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context");
+        let sk2 = secp256k1::SecretKey::from_slice(&secp, &[2; 32]).expect("secret key");
+        let pk2 = secp256k1::PublicKey::from_secret_key(&secp, &sk2).expect("signing context");
+        let tx = spending_tx();
+
+        // Legacy sighash over the redeem script, for each key of the multisig
+        let sh = Descriptor::Sh(Box::new(Descriptor::Multi(1, vec![pk, pk2])));
+        let requests = input_sighashes(&tx, 0, 100_000, &sh).unwrap();
+        let sighash = tx.signature_hash(0, &sh.witness_script().unwrap(), SigHashType::All.as_u32());
+        let msg = secp256k1::Message::from_slice(&sighash[..]).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!((requests[0].index, requests[0].key, requests[0].msg), (0, pk, msg));
+        assert_eq!((requests[1].index, requests[1].key, requests[1].msg), (0, pk2, msg));
+
+        // BIP143 sighash over the implied pay-to-pubkey-hash script
+        let wpkh = Descriptor::Wpkh(pk);
+        let requests = transaction_sighashes(&tx, &[(&wpkh, 100_000)]).unwrap();
+        let script_code = Descriptor::KeyHash(pk).script_pubkey().unwrap();
+        let sighash = bip143::SighashComponents::new(&tx).sighash_all(&tx.input[0], &script_code, 100_000);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].key, pk);
+        assert_eq!(requests[0].msg, secp256k1::Message::from_slice(&sighash[..]).unwrap());
+
+        // The signatures asked for are the ones produced when satisfying the input
+        let signer = KeySigner(sk, pk);
+        let (_, witness) = satisfy_transaction_input(&tx, 0, 100_000, &wpkh, &signer).unwrap();
+        let sig = secp256k1::Signature::from_der(&secp, &witness[0][..witness[0].len() - 1]).unwrap();
+        assert!(secp.verify(&requests[0].msg, &sig, &pk).is_ok());
+
+        assert_eq!(input_sighashes(&tx, 1, 100_000, &sh), Err(Error::InputIndexOutOfRange(1)));
+        assert_eq!(transaction_sighashes(&tx, &[]), Err(Error::InputIndexOutOfRange(0)));
+    }
+
+    #[test]
+    fn verify_satisfaction() {
+        use satisfy::Older;
+
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let pk = secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context");
+        let signer = KeySigner(sk, pk);
+        let mut tx = spending_tx();
+
+        let wpkh = Descriptor::Wpkh(pk);
+        let (tree, wrapper) = ParseTree::from_descriptor(&wpkh).unwrap();
+        let (_, witness) = satisfy_transaction_input(&tx, 0, 100_000, &wpkh, &signer).unwrap();
+        assert_eq!(tree.verify_satisfaction(&witness, &tx, 0, 100_000, wrapper), Ok(()));
+        let hash = Hash160::from_data(&pk.serialize()[..]);
+        assert_eq!(tree.interpret(&witness, &NoChecks).map(|exec| exec.branch), Ok(vec![Condition::PkhSig(hash)]));
+
+        // The signature commits to the amount being spent
+        match tree.verify_satisfaction(&witness, &tx, 0, 99_999, wrapper) {
+            Err(Error::ExecutionFailed { reason, .. }) => assert_eq!(reason, "invalid signature"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(
+            tree.verify_satisfaction(&witness, &tx, 1, 100_000, wrapper),
+            Err(Error::InputIndexOutOfRange(1))
+        );
+
+        // Relative locktimes are checked against the input's sequence number
+        let csv = Descriptor::Time(10);
+        let (tree, wrapper) = ParseTree::from_descriptor(&csv).unwrap();
+        let witness = tree.satisfy(&Older(10)).unwrap();
+        assert!(tree.verify_satisfaction(&witness, &tx, 0, 100_000, wrapper).is_err());
+        tx.input[0].sequence = 10;
+        assert_eq!(tree.verify_satisfaction(&witness, &tx, 0, 100_000, wrapper), Ok(()));
+        tx.input[0].sequence = 9;
+        assert!(tree.verify_satisfaction(&witness, &tx, 0, 100_000, wrapper).is_err());
+    }
+
+    #[test]
+    fn multi_fragment_inputs() {
+        use std::collections::HashMap;
+        use satisfy::Older;
+        use sha256;
+        use super::disassemble_input;
+
+        let secp = secp256k1::Secp256k1::new();
+        let signers: Vec<KeySigner> = (1..3).map(|i| {
+            let sk = secp256k1::SecretKey::from_slice(&secp, &[i; 32]).expect("secret key");
+            KeySigner(sk, secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context"))
+        }).collect();
+        let pks: Vec<secp256k1::PublicKey> = signers.iter().map(|signer| signer.1).collect();
+        let mut tx = spending_tx();
+        tx.input[0].sequence = 10;
+
+        let preimage = [9; 32];
+        let mut preimages = HashMap::new();
+        preimages.insert(sha256::Hash::from_data(&preimage), preimage);
+
+        // `and(pk,pk)`, `or(pk,and(pk,older))` and `thresh(2,pk,pk,sha256)`
+        let inner = |which: usize| match which {
+            0 => Descriptor::And(Box::new(Descriptor::Key(pks[0])), Box::new(Descriptor::Key(pks[1]))),
+            1 => Descriptor::Or(
+                Box::new(Descriptor::Key(pks[0])),
+                Box::new(Descriptor::And(Box::new(Descriptor::Key(pks[1])), Box::new(Descriptor::Time(10)))),
+            ),
+            _ => Descriptor::Threshold(2, vec![
+                Descriptor::Key(pks[0]),
+                Descriptor::Key(pks[1]),
+                Descriptor::Hash(sha256::Hash::from_data(&preimage)),
+            ]),
+        };
+
+        for which in 0..3 {
+            for desc in &[
+                Descriptor::Wsh(Box::new(inner(which))),
+                Descriptor::Sh(Box::new(inner(which))),
+                Descriptor::Sh(Box::new(Descriptor::Wsh(Box::new(inner(which))))),
+            ] {
+                let (tree, wrapper) = ParseTree::from_descriptor(desc).unwrap();
+                let check = |satisfier: &dyn Satisfier| {
+                    let (script_sig, witness) = satisfy_transaction_input(&tx, 0, 100_000, desc, &satisfier).unwrap();
+                    let sat = disassemble_input(&tree.serialize(), wrapper, &script_sig, &witness).unwrap();
+                    assert_eq!(tree.verify_satisfaction(&sat, &tx, 0, 100_000, wrapper), Ok(()), "{}", desc);
+                };
+
+                match which {
+                    0 => check(&(&signers[0], &signers[1])),
+                    1 => {
+                        check(&signers[0]);
+                        check(&(&signers[1], Older(10)));
+                    }
+                    _ => {
+                        check(&(&signers[0], &signers[1]));
+                        check(&(&signers[0], &preimages));
+                        check(&(&signers[1], &preimages));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn explain_spend() {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let pk = secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context");
+        let signer = KeySigner(sk, pk);
+        let tx = spending_tx();
+        let hash = Hash160::from_data(&pk.serialize()[..]);
+
+        for desc in &[
+            Descriptor::Wpkh(pk),
+            Descriptor::Sh(Box::new(Descriptor::KeyHash(pk))),
+            Descriptor::Wsh(Box::new(Descriptor::Key(pk))),
+        ] {
+            let (tree, wrapper) = ParseTree::from_descriptor(desc).unwrap();
+            let (script_sig, witness) = satisfy_transaction_input(&tx, 0, 100_000, desc, &signer).unwrap();
+            let sighash = signature_hash(&tx, 0, 100_000, &tree.serialize(), wrapper);
+            let sig = secp.sign(&secp256k1::Message::from_slice(&sighash[..]).unwrap(), &sk).expect("signing context");
+            let mut sigser = sig.serialize_der(&secp);
+            sigser.push(SigHashType::All.as_u32() as u8);
+
+            let exec = tree.explain_spend(&script_sig, &witness, wrapper).unwrap();
+            assert_eq!(exec.signatures, vec![(pk, sigser)]);
+            assert!(exec.preimages.is_empty());
+            assert!(exec.timelocks.is_empty());
+            match *desc {
+                Descriptor::Wsh(..) => assert_eq!(exec.branch, vec![Condition::Sig(pk)]),
+                _ => assert_eq!(exec.branch, vec![Condition::PkhSig(hash)]),
+            }
+        }
+
+        // The redeem script must be the one of the tree
+        let sh = Descriptor::Sh(Box::new(Descriptor::KeyHash(pk)));
+        let (script_sig, _) = satisfy_transaction_input(&tx, 0, 100_000, &sh, &signer).unwrap();
+        let (other, _) = ParseTree::from_descriptor(&Descriptor::Sh(Box::new(Descriptor::Key(pk)))).unwrap();
+        assert_eq!(
+            other.explain_spend(&script_sig, &[], Wrapper::Sh),
+            Err(Error::MalformedInput("redeem script does not match"))
+        );
+    }
+}
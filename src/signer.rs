@@ -0,0 +1,147 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Software Signer
+//!
+//! A satisfier holding secret keys in memory, which signs whatever signature hash
+//! `sign::satisfy_transaction_input` asks it to, for small tools which have no
+//! signing stack of their own. Keys may be added one at a time or derived from a
+//! private descriptor. Signatures are always `SIGHASH_ALL`.
+//!
+
+use std::collections::HashMap;
+
+use secp256k1;
+
+use bitcoin::blockdata::transaction::SigHashType;
+use bitcoin::util::hash::Hash160;
+
+use xprv::DescriptorSecretKey;
+use {Descriptor, Error, Satisfier};
+
+/// Satisfier which signs with the secret keys it holds
+pub struct Signer {
+    secp: secp256k1::Secp256k1,
+    keys: HashMap<secp256k1::PublicKey, secp256k1::SecretKey>,
+}
+
+impl Default for Signer {
+    fn default() -> Signer {
+        Signer::new()
+    }
+}
+
+impl Signer {
+    /// Creates a signer without any keys
+    pub fn new() -> Signer {
+        Signer {
+            secp: secp256k1::Secp256k1::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Adds a secret key, returning its public key
+    pub fn add_key(&mut self, sk: secp256k1::SecretKey) -> secp256k1::PublicKey {
+        let pk = secp256k1::PublicKey::from_secret_key(&self.secp, &sk).expect("signing context");
+        self.keys.insert(pk, sk);
+        pk
+    }
+
+    /// Adds the secret keys of a private descriptor for child `index`
+    pub fn add_descriptor(&mut self, desc: &Descriptor<DescriptorSecretKey>, index: u32) -> Result<(), Error> {
+        self.keys.extend(desc.secret_keys(index)?);
+        Ok(())
+    }
+
+    /// Number of keys held
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Whether no keys are held
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl Satisfier for Signer {
+    fn sign_sighash(&self, pk: &secp256k1::PublicKey, msg: &secp256k1::Message) -> Option<(secp256k1::Signature, SigHashType)> {
+        self.keys.get(pk).map(|sk| (self.secp.sign(msg, sk).expect("signing context"), SigHashType::All))
+    }
+
+    fn lookup_pkh(&self, hash: &Hash160) -> Option<secp256k1::PublicKey> {
+        self.keys.keys().find(|pk| Hash160::from_data(&pk.serialize()[..]) == *hash).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use secp256k1;
+
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+    use bitcoin::util::hash::Sha256dHash;
+
+    use descriptor::PublicKey;
+    use sign::{input_sighashes, satisfy_transaction_input};
+    use xprv::DescriptorSecretKey;
+    use {Descriptor, Error};
+    use super::Signer;
+
+    const XPRV: &str = "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi";
+
+    #[test]
+    fn sign_from_private_descriptor() {
+        let secp = secp256k1::Secp256k1::new();
+        let private = Descriptor::<DescriptorSecretKey>::from_str(&format!("sh(wpkh({}/0/*))", XPRV)).unwrap();
+        let mut signer = Signer::new();
+        signer.add_descriptor(&private, 5).unwrap();
+        assert_eq!(signer.len(), 1);
+
+        let desc = private.to_public().unwrap().translate(|pk| pk.instantiate(Some(&5))).unwrap();
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                prev_hash: Sha256dHash::from_data(&[]),
+                prev_index: 0,
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 99_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let (_, witness) = satisfy_transaction_input(&tx, 0, 100_000, &desc, &signer).unwrap();
+        let request = &input_sighashes(&tx, 0, 100_000, &desc).unwrap()[0];
+        let der = &witness[0][..witness[0].len() - 1];
+        let sig = secp256k1::Signature::from_der(&secp, der).unwrap();
+        assert!(secp.verify(&request.msg, &sig, &request.key).is_ok());
+
+        // Keys which are not held cannot sign
+        let other = desc.translate(|_| -> Result<secp256k1::PublicKey, Error> {
+            let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+            Ok(secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context"))
+        }).unwrap();
+        assert!(satisfy_transaction_input(&tx, 0, 100_000, &other, &signer).is_err());
+        let pk = signer.add_key(secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key"));
+        assert_eq!(signer.len(), 2);
+        assert!(satisfy_transaction_input(&tx, 0, 100_000, &other, &signer).is_ok());
+        assert_eq!(input_sighashes(&tx, 0, 100_000, &other).unwrap()[0].key, pk);
+    }
+}
@@ -0,0 +1,186 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Taproot Trees
+//!
+//! Splitting of a descriptor into the leaves of a taproot script tree. Each top-level
+//! disjunction becomes a separate leaf, and leaves are arranged by Huffman coding on
+//! the probability of each being the one spent, which minimizes the expected size of
+//! the control block revealed when spending.
+//!
+//! There is no `tr()` descriptor nor a compiler for tapscripts in this library yet, so
+//! leaves are left as descriptors; it is up to the caller to turn them into scripts.
+//!
+
+use std::fmt;
+
+use descriptor::PublicKey;
+use Descriptor;
+
+/// Size of a control block revealing a leaf at depth 0, in bytes
+const CONTROL_BLOCK_BASE_SIZE: usize = 33;
+/// Growth of a control block for each level of depth of its leaf, in bytes
+const CONTROL_BLOCK_NODE_SIZE: usize = 32;
+
+/// A taproot script tree, whose leaves borrow from the descriptor it was built from
+pub enum TapTree<'a, P: 'a + PublicKey> {
+    /// A single leaf
+    Leaf(&'a Descriptor<P>),
+    /// An internal node with its two children
+    Branch(Box<TapTree<'a, P>>, Box<TapTree<'a, P>>),
+}
+
+impl<'a, P: 'a + PublicKey> TapTree<'a, P> {
+    /// The leaves of the tree from left to right, each with its depth below the root
+    pub fn leaves(&self) -> Vec<(usize, &'a Descriptor<P>)> {
+        let mut ret = vec![];
+        let mut stack = vec![(0, self)];
+        while let Some((depth, node)) = stack.pop() {
+            match *node {
+                TapTree::Leaf(desc) => ret.push((depth, desc)),
+                TapTree::Branch(ref left, ref right) => {
+                    stack.push((depth + 1, &**right));
+                    stack.push((depth + 1, &**left));
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// The size in bytes of the control block needed to spend a leaf at `depth`
+pub fn control_block_size(depth: usize) -> usize {
+    CONTROL_BLOCK_BASE_SIZE + CONTROL_BLOCK_NODE_SIZE * depth
+}
+
+impl<'a, P: 'a + PublicKey> fmt::Display for TapTree<'a, P> {
+    /// Writes the tree in the `{left,right}` notation of `tr()` descriptors
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TapTree::Leaf(desc) => fmt::Display::fmt(desc, f),
+            TapTree::Branch(ref left, ref right) => write!(f, "{{{},{}}}", left, right),
+        }
+    }
+}
+
+/// Adds the leaves of `desc` to `leaves`, given the probability `p` that it is
+/// the part of the whole descriptor which is satisfied
+fn split<'a, P: PublicKey>(desc: &'a Descriptor<P>, p: f64, leaves: &mut Vec<(f64, TapTree<'a, P>)>) {
+    match *desc {
+        Descriptor::Or(ref left, ref right) => {
+            split(left, p / 2.0, leaves);
+            split(right, p / 2.0, leaves);
+        }
+        Descriptor::AsymmetricOr(ref left, ref right) => {
+            split(left, p, leaves);
+            split(right, 0.0, leaves);
+        }
+        Descriptor::WeightedOr(left_odds, ref left, right_odds, ref right) => {
+            let total = (left_odds + right_odds) as f64;
+            split(left, p * left_odds as f64 / total, leaves);
+            split(right, p * right_odds as f64 / total, leaves);
+        }
+        Descriptor::Threshold(1, ref subs) => {
+            for sub in subs {
+                split(sub, p / subs.len() as f64, leaves);
+            }
+        }
+        _ => leaves.push((p, TapTree::Leaf(desc))),
+    }
+}
+
+impl<P: PublicKey> Descriptor<P> {
+    /// Splits the descriptor into taproot leaves at its disjunctions (`or`, `aor`,
+    /// weighted `or` and 1-of-n thresholds), and arranges them in a tree which
+    /// minimizes the expected control block size. The odds of weighted disjunctions
+    /// are taken as the probabilities of each branch being spent; the branches of
+    /// other disjunctions are equally likely, except that the second branch of an
+    /// `aor` is assumed never to be spent.
+    pub fn compile_taproot(&self) -> TapTree<'_, P> {
+        let mut nodes = vec![];
+        split(self, 1.0, &mut nodes);
+
+        // Huffman coding: repeatedly join the two least likely subtrees, preferring
+        // the earlier of equally likely ones so that the result is deterministic
+        while nodes.len() > 1 {
+            let (p_left, left) = take_least_likely(&mut nodes);
+            let (p_right, right) = take_least_likely(&mut nodes);
+            nodes.push((p_left + p_right, TapTree::Branch(Box::new(left), Box::new(right))));
+        }
+        nodes.pop().expect("a descriptor has at least one leaf").1
+    }
+}
+
+/// Removes the least likely subtree from `nodes`
+fn take_least_likely<'a, P: PublicKey>(nodes: &mut Vec<(f64, TapTree<'a, P>)>) -> (f64, TapTree<'a, P>) {
+    let mut min = 0;
+    for i in 1..nodes.len() {
+        if nodes[i].0 < nodes[min].0 {
+            min = i;
+        }
+    }
+    nodes.remove(min)
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1;
+
+    use Descriptor;
+    use super::control_block_size;
+
+    fn pubkeys(n: usize) -> Vec<secp256k1::PublicKey> {
+        let secp = secp256k1::Secp256k1::new();
+        (1..n + 1).map(|i| {
+            let sk = secp256k1::SecretKey::from_slice(&secp, &[i as u8; 32]).expect("secret key");
+            secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context")
+        }).collect()
+    }
+
+    #[test]
+    fn single_leaf() {
+        let keys = pubkeys(2);
+        let desc = Descriptor::And(Box::new(Descriptor::Key(keys[0])), Box::new(Descriptor::Key(keys[1])));
+        let tree = desc.compile_taproot();
+        assert_eq!(tree.to_string(), desc.to_string());
+        assert_eq!(tree.leaves().len(), 1);
+        assert_eq!(tree.leaves()[0].0, 0);
+        assert_eq!(control_block_size(tree.leaves()[0].0), 33);
+    }
+
+    #[test]
+    fn huffman_depths() {
+        let keys = pubkeys(4);
+        let key = |i: usize| Box::new(Descriptor::Key(keys[i]));
+
+        // The likely branch sits next to the root, the unlikely ones deeper
+        let desc = Descriptor::WeightedOr(
+            6, key(0),
+            2, Box::new(Descriptor::WeightedOr(3, key(1), 1, Box::new(Descriptor::AsymmetricOr(key(2), key(3))))),
+        );
+        let tree = desc.compile_taproot();
+        let leaves = tree.leaves();
+        assert_eq!(leaves.len(), 4);
+        for (i, expected) in [1, 2, 3, 3].iter().enumerate() {
+            let leaf = Descriptor::Key(keys[i]).to_string();
+            let depth = leaves.iter().find(|&&(_, desc)| desc.to_string() == leaf).expect("leaf").0;
+            assert_eq!(depth, *expected, "leaf {} in {}", leaf, tree);
+        }
+
+        // 1-of-n thresholds split evenly
+        let desc = Descriptor::Threshold(1, (0..4).map(|i| Descriptor::Key(keys[i])).collect());
+        let tree = desc.compile_taproot();
+        assert!(tree.leaves().iter().all(|&(depth, _)| depth == 2));
+    }
+}
@@ -0,0 +1,211 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Policy Templates
+//!
+//! Constructors for the descriptors of common contracts, for users who would rather
+//! not assemble them from the policy algebra themselves.
+//!
+//! Descriptors only express relative locktimes, so every timeout here is a relative
+//! locktime in the encoding of `Descriptor::Time`, counted from the confirmation of
+//! the output, rather than the absolute `CHECKLOCKTIMEVERIFY` timeouts which Lightning
+//! uses on its HTLC transactions.
+//!
+
+use descriptor::PublicKey;
+use {sha256, Descriptor};
+
+impl<P: PublicKey> Descriptor<P> {
+    /// An HTLC offered by the holder of `local_key`: the holder of `remote_key` may
+    /// take the funds by revealing the SHA256 preimage of `payment_hash`, and the
+    /// holder of `local_key` may take them back once `timeout` has passed
+    pub fn htlc_offered(local_key: P, remote_key: P, payment_hash: sha256::Hash, timeout: u32) -> Descriptor<P> {
+        htlc(remote_key, local_key, payment_hash, timeout)
+    }
+
+    /// An HTLC received by the holder of `local_key`: they may take the funds by
+    /// revealing the SHA256 preimage of `payment_hash`, and the holder of `remote_key`
+    /// may take them back once `timeout` has passed
+    pub fn htlc_received(local_key: P, remote_key: P, payment_hash: sha256::Hash, timeout: u32) -> Descriptor<P> {
+        htlc(local_key, remote_key, payment_hash, timeout)
+    }
+
+    /// A 2-of-3 escrow between `buyer` and `seller`, in which `arbiter` settles disputes
+    /// by signing with whichever party it finds for
+    pub fn escrow(buyer: P, seller: P, arbiter: P) -> Descriptor<P> {
+        Descriptor::Multi(2, vec![buyer, seller, arbiter])
+    }
+
+    /// A Liquid-style federation: `k` of the `functionaries` sign, or, should they fail
+    /// to for `timeout`, `emergency_k` of the `emergency` keys do. The emergency branch
+    /// is assumed never to be taken for costing purposes.
+    pub fn federation(k: usize, functionaries: Vec<P>, emergency_k: usize, emergency: Vec<P>, timeout: u32) -> Descriptor<P> {
+        Descriptor::AsymmetricOr(
+            Box::new(Descriptor::Multi(k, functionaries)),
+            Box::new(Descriptor::And(
+                Box::new(Descriptor::Time(timeout)),
+                Box::new(Descriptor::Multi(emergency_k, emergency)),
+            )),
+        )
+    }
+
+    /// A vault, which `hot_key` may spend at any time and `cold_key` once `delay` has
+    /// passed. Spends with the cold key are assumed to be rare for costing purposes.
+    pub fn vault(hot_key: P, cold_key: P, delay: u32) -> Descriptor<P> {
+        Descriptor::AsymmetricOr(
+            Box::new(Descriptor::Key(hot_key)),
+            Box::new(Descriptor::And(Box::new(Descriptor::Key(cold_key)), Box::new(Descriptor::Time(delay)))),
+        )
+    }
+}
+
+/// An HTLC which `claimer` may spend with the preimage of `payment_hash`, and which
+/// `refunded` may spend after `timeout`
+fn htlc<P: PublicKey>(claimer: P, refunded: P, payment_hash: sha256::Hash, timeout: u32) -> Descriptor<P> {
+    Descriptor::Or(
+        Box::new(Descriptor::And(Box::new(Descriptor::Key(claimer)), Box::new(Descriptor::Hash(payment_hash)))),
+        Box::new(Descriptor::And(Box::new(Descriptor::Key(refunded)), Box::new(Descriptor::Time(timeout)))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1;
+
+    use bitcoin::blockdata::opcodes;
+    use bitcoin::blockdata::script;
+
+    use parse::{AstElem, Policy};
+    use {sha256, Descriptor, ParseTree, Template};
+
+    fn pubkeys(n: usize) -> Vec<secp256k1::PublicKey> {
+        let secp = secp256k1::Secp256k1::new();
+        (1..n + 1).map(|i| {
+            let sk = secp256k1::SecretKey::from_slice(&secp, &[i as u8; 32]).expect("secret key");
+            secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context")
+        }).collect()
+    }
+
+    /// Whether two policies are the same up to the order of threshold children
+    fn same_policy(a: &Policy, b: &Policy) -> bool {
+        match (a, b) {
+            (&Policy::Threshold(k, ref xs), &Policy::Threshold(l, ref ys)) => {
+                if k != l || xs.len() != ys.len() {
+                    return false;
+                }
+                let mut unused: Vec<&Policy> = ys.iter().collect();
+                for x in xs {
+                    match unused.iter().position(|y| same_policy(x, y)) {
+                        Some(i) => { unused.remove(i); }
+                        None => return false,
+                    }
+                }
+                true
+            }
+            _ => a == b,
+        }
+    }
+
+    /// The HTLC script as it might be written by hand: a selector on top of the
+    /// witness picks the claim or the refund branch
+    fn hand_written_htlc(
+        claimer: &secp256k1::PublicKey,
+        refunded: &secp256k1::PublicKey,
+        hash: &sha256::Hash,
+        timeout: u32,
+    ) -> script::Script {
+        script::Builder::new()
+            .push_opcode(opcodes::All::OP_SIZE)
+            .push_opcode(opcodes::All::OP_EQUALVERIFY)
+            .push_opcode(opcodes::All::OP_IF)
+                .push_slice(&claimer.serialize()[..])
+                .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
+                .push_opcode(opcodes::All::OP_SIZE)
+                .push_int(32)
+                .push_opcode(opcodes::All::OP_EQUALVERIFY)
+                .push_opcode(opcodes::All::OP_SHA256)
+                .push_slice(&hash[..])
+                .push_opcode(opcodes::All::OP_EQUAL)
+            .push_opcode(opcodes::All::OP_ELSE)
+                .push_slice(&refunded.serialize()[..])
+                .push_opcode(opcodes::All::OP_CHECKSIGVERIFY)
+                .push_int(timeout as i64)
+                .push_opcode(opcodes::OP_CSV)
+            .push_opcode(opcodes::All::OP_ENDIF)
+            .into_script()
+    }
+
+    #[test]
+    fn htlcs() {
+        let keys = pubkeys(2);
+        let (local, remote) = (keys[0], keys[1]);
+        let hash = sha256::Hash::from_data(b"payment preimage");
+
+        // The compiler may arrange the script differently from the hand-written one,
+        // so compare what the two require instead of their bytes
+        let cases = [
+            (Descriptor::htlc_offered(local, remote, hash, 144), hand_written_htlc(&remote, &local, &hash, 144)),
+            (Descriptor::htlc_received(local, remote, hash, 144), hand_written_htlc(&local, &remote, &hash, 144)),
+        ];
+        for (desc, script) in &cases {
+            assert_eq!(desc.sanity_check(), Ok(()));
+            let compiled = ParseTree::compile(desc).expect("HTLC compiles");
+            let hand_written = ParseTree::parse(script).expect("hand-written HTLC parses");
+            assert_eq!(compiled.template(), Template::Htlc);
+            assert_eq!(hand_written.template(), Template::Htlc);
+            assert!(same_policy(&compiled.0.policy(), &hand_written.0.policy()), "{} against {}", desc, script);
+        }
+
+        // Offering and receiving swap who may claim
+        let offered = ParseTree::compile(&cases[0].0).unwrap().0.policy();
+        let received = ParseTree::compile(&cases[1].0).unwrap().0.policy();
+        assert!(!same_policy(&offered, &received));
+    }
+
+    #[test]
+    fn escrow_federation_vault() {
+        use std::collections::HashMap;
+
+        let keys = pubkeys(8);
+
+        let escrow = Descriptor::escrow(keys[0], keys[1], keys[2]);
+        assert_eq!(escrow.sanity_check(), Ok(()));
+        assert_eq!(ParseTree::compile(&escrow).unwrap().template(), Template::Multisig(2, 3));
+
+        // The Liquid policy of the descriptor tests
+        let liquid = Descriptor::AsymmetricOr(
+            Box::new(Descriptor::Multi(3, keys[0..5].to_owned())),
+            Box::new(Descriptor::And(
+                Box::new(Descriptor::Time(10000)),
+                Box::new(Descriptor::Multi(2, keys[5..8].to_owned())),
+            )),
+        );
+        let federation = Descriptor::federation(3, keys[0..5].to_owned(), 2, keys[5..8].to_owned(), 10000);
+        assert_eq!(federation.to_string(), liquid.to_string());
+        let compiled = ParseTree::compile(&federation).unwrap();
+        assert_eq!(compiled.serialize(), ParseTree::compile(&liquid).unwrap().serialize());
+        assert_eq!(compiled.template(), Template::Federation);
+
+        let vault = Descriptor::vault(keys[0], keys[1], 1008);
+        assert_eq!(vault.sanity_check(), Ok(()));
+        let compiled = ParseTree::compile(&vault).unwrap();
+        let mut sigs = HashMap::new();
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        sigs.insert(keys[0], secp.sign(&msg, &sk).expect("signing context"));
+        // The hot key alone suffices
+        assert!(compiled.satisfy(&sigs).is_ok());
+    }
+}
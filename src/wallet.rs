@@ -0,0 +1,217 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Wallets
+//!
+//! A container for the descriptors of a wallet, such as those of its external
+//! (receiving) and internal (change) chains. Scriptpubkeys derived from the
+//! descriptors are mapped back to the descriptor and child index they came from, so
+//! that a transaction spending outputs of several descriptors can be satisfied in a
+//! single call.
+//!
+
+use std::cmp;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use secp256k1;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
+
+use descriptor::PublicKey;
+use sign::satisfy_transaction_input;
+use xpub::DescriptorXPub;
+use {Descriptor, Error, Satisfier};
+
+/// The scriptSig and witness which satisfy a transaction input
+pub type InputSatisfaction = (Script, Vec<Vec<u8>>);
+
+/// Which chain of a wallet a descriptor derives scripts for
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Chain {
+    /// Scripts handed out to receive payments
+    External,
+    /// Scripts for the wallet's own change outputs
+    Internal,
+}
+
+/// The descriptors of a wallet, along with every scriptpubkey derived from them so far
+#[derive(Default)]
+pub struct Wallet {
+    descriptors: Vec<(Chain, Descriptor<DescriptorXPub>)>,
+    /// Scriptpubkey (as bytes) of each child derived so far, mapped to the position of
+    /// its descriptor and its child index
+    scripts: HashMap<Vec<u8>, (usize, u32)>,
+}
+
+impl Wallet {
+    /// Creates a wallet without any descriptors
+    pub fn new() -> Wallet {
+        Wallet::default()
+    }
+
+    /// Adds a descriptor to the wallet and derives the scriptpubkeys of the children in
+    /// `range`, so that outputs paying them are recognized. Returns the position of the
+    /// descriptor, by which the wallet refers to it.
+    pub fn add_descriptor(&mut self, chain: Chain, desc: Descriptor<DescriptorXPub>, range: Range<u32>) -> Result<usize, Error> {
+        self.descriptors.push((chain, desc));
+        let position = self.descriptors.len() - 1;
+        if let Err(e) = self.extend(position, range) {
+            self.descriptors.pop();
+            return Err(e);
+        }
+        Ok(position)
+    }
+
+    /// Derives the scriptpubkeys of the children in `range` of the descriptor at
+    /// `position`, e.g. to look further ahead once addresses have been used
+    pub fn extend(&mut self, position: usize, range: Range<u32>) -> Result<(), Error> {
+        let mut derived = vec![];
+        for index in range {
+            for spk in self.derive(position, index)?.script_pubkeys()? {
+                derived.push((spk[..].to_vec(), index));
+            }
+        }
+        for (spk, index) in derived {
+            self.scripts.entry(spk).or_insert((position, index));
+        }
+        Ok(())
+    }
+
+    /// The descriptors of the wallet, in order of position
+    pub fn descriptors(&self) -> &[(Chain, Descriptor<DescriptorXPub>)] {
+        &self.descriptors
+    }
+
+    /// Instantiates the descriptor at `position` for child `index`
+    pub fn derive(&self, position: usize, index: u32) -> Result<Descriptor<secp256k1::PublicKey>, Error> {
+        match self.descriptors.get(position) {
+            Some((_, desc)) => desc.translate(|pk| pk.instantiate(Some(&index))),
+            None => Err(Error::Unexpected(format!("no descriptor at position {}", position))),
+        }
+    }
+
+    /// Finds the position of the descriptor and the child index which `script_pubkey`
+    /// was derived from, among the children derived so far
+    pub fn lookup(&self, script_pubkey: &Script) -> Option<(usize, u32)> {
+        self.scripts.get(&script_pubkey[..]).cloned()
+    }
+
+    /// Produces the scriptSig and witness of every input of `tx` which spends an output
+    /// of the wallet, as `sign::satisfy_transaction_input` would for the descriptor and
+    /// child index the output was derived from. `spent[i]` is the output spent by input
+    /// `i`. Inputs spending outputs which the wallet does not recognize are given `None`.
+    pub fn satisfy<S: Satisfier>(
+        &self,
+        tx: &Transaction,
+        spent: &[TxOut],
+        satisfier: &S,
+    ) -> Result<Vec<Option<InputSatisfaction>>, Error> {
+        if spent.len() != tx.input.len() {
+            return Err(Error::InputIndexOutOfRange(cmp::min(spent.len(), tx.input.len())));
+        }
+
+        let mut ret = Vec::with_capacity(spent.len());
+        for (index, txout) in spent.iter().enumerate() {
+            ret.push(match self.lookup(&txout.script_pubkey) {
+                Some((position, child)) => {
+                    let desc = self.derive(position, child)?;
+                    Some(satisfy_transaction_input(tx, index, txout.value, &desc, satisfier)?)
+                }
+                None => None,
+            });
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use secp256k1;
+
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{SigHashType, Transaction, TxIn, TxOut};
+    use bitcoin::util::hash::Sha256dHash;
+
+    use xpub::DescriptorXPub;
+    use {Descriptor, Error, Satisfier};
+    use super::{Chain, Wallet};
+
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    /// Satisfier which gives the same signature for any key
+    struct AnySig(secp256k1::Signature);
+
+    impl Satisfier for AnySig {
+        fn sign_sighash(&self, _: &secp256k1::PublicKey, _: &secp256k1::Message) -> Option<(secp256k1::Signature, SigHashType)> {
+            Some((self.0, SigHashType::All))
+        }
+    }
+
+    #[test]
+    fn wallet() {
+        let mut wallet = Wallet::new();
+        for (chain, step) in [(Chain::External, 0), (Chain::Internal, 1)] {
+            let desc = Descriptor::<DescriptorXPub>::from_str(&format!("wpkh({}/{}/*)", XPUB, step)).unwrap();
+            assert_eq!(wallet.add_descriptor(chain, desc, 0..5), Ok(step));
+        }
+        assert_eq!(wallet.descriptors().len(), 2);
+        assert_eq!(wallet.descriptors()[1].0, Chain::Internal);
+
+        let change = wallet.derive(1, 3).unwrap();
+        let change_spk = change.script_pubkey().unwrap();
+        assert_eq!(wallet.lookup(&change_spk), Some((1, 3)));
+        let far = wallet.derive(0, 10).unwrap().script_pubkey().unwrap();
+        assert_eq!(wallet.lookup(&far), None);
+        wallet.extend(0, 10..11).unwrap();
+        assert_eq!(wallet.lookup(&far), Some((0, 10)));
+        assert!(wallet.derive(2, 0).is_err());
+
+        let txin = TxIn {
+            prev_hash: Sha256dHash::from_data(&[]),
+            prev_index: 0,
+            script_sig: Script::new(),
+            sequence: 0xffffffff,
+            witness: vec![],
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![txin.clone(), txin],
+            output: vec![],
+        };
+        let spent = vec![
+            TxOut { value: 10_000, script_pubkey: Script::new() },
+            TxOut { value: 20_000, script_pubkey: change_spk },
+        ];
+
+        let secp = secp256k1::Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&secp, &[1; 32]).expect("secret key");
+        let msg = secp256k1::Message::from_slice(&[1; 32]).expect("message");
+        let signer = AnySig(secp.sign(&msg, &sk).expect("signing context"));
+
+        let sats = wallet.satisfy(&tx, &spent, &signer).unwrap();
+        assert_eq!(sats[0], None);
+        let (ref script_sig, ref witness) = *sats[1].as_ref().unwrap();
+        assert_eq!(*script_sig, Script::new());
+        match change {
+            Descriptor::Wpkh(pk) => assert_eq!(witness[1], pk.serialize().to_vec()),
+            _ => unreachable!(),
+        }
+        assert_eq!(wallet.satisfy(&tx, &spent[..1], &signer), Err(Error::InputIndexOutOfRange(1)));
+    }
+}
@@ -0,0 +1,111 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # JavaScript Interface
+//!
+//! `wasm-bindgen` wrappers, enabled by the `wasm` feature, for validating descriptors
+//! and deriving their scripts and addresses in a browser. Errors are thrown as
+//! JavaScript strings, and scripts are given in hex.
+//!
+
+use std::fmt::Write;
+
+use secp256k1;
+use wasm_bindgen::prelude::*;
+
+use bitcoin::network::constants::Network;
+
+use Descriptor;
+
+/// Encodes bytes as lowercase hex
+fn hex(data: &[u8]) -> String {
+    let mut ret = String::with_capacity(2 * data.len());
+    for x in data {
+        write!(ret, "{:02x}", *x).expect("writing to a string");
+    }
+    ret
+}
+
+/// Converts any library error into a thrown JavaScript string
+fn js_err<E: ToString>(e: E) -> JsValue {
+    JsValue::from_str(&e.to_string())
+}
+
+/// A parsed descriptor, with concrete public keys
+#[wasm_bindgen]
+pub struct JsDescriptor {
+    inner: Descriptor<secp256k1::PublicKey>,
+}
+
+#[wasm_bindgen]
+impl JsDescriptor {
+    /// Parses a descriptor string, with or without a checksum
+    #[wasm_bindgen(constructor)]
+    pub fn new(s: &str) -> Result<JsDescriptor, JsValue> {
+        Ok(JsDescriptor {
+            inner: s.parse().map_err(js_err)?,
+        })
+    }
+
+    /// The descriptor string, without a checksum
+    #[wasm_bindgen(js_name = toString)]
+    pub fn to_js_string(&self) -> String {
+        self.inner.to_string()
+    }
+
+    /// The descriptor string, followed by its checksum
+    #[wasm_bindgen(js_name = toStringWithChecksum)]
+    pub fn to_string_with_checksum(&self) -> String {
+        self.inner.to_string_with_checksum()
+    }
+
+    /// Throws if the descriptor describes a nonsensical policy
+    #[wasm_bindgen(js_name = sanityCheck)]
+    pub fn sanity_check(&self) -> Result<(), JsValue> {
+        self.inner.sanity_check().map_err(js_err)
+    }
+
+    /// The scriptpubkey of the descriptor, in hex
+    #[wasm_bindgen(js_name = scriptPubkey)]
+    pub fn script_pubkey(&self) -> Result<String, JsValue> {
+        let script = self.inner.script_pubkey().map_err(js_err)?;
+        Ok(hex(&script[..]))
+    }
+
+    /// The script executed when spending the descriptor, in hex; see
+    /// `Descriptor::witness_script`
+    #[wasm_bindgen(js_name = witnessScript)]
+    pub fn witness_script(&self) -> Result<String, JsValue> {
+        let script = self.inner.witness_script().map_err(js_err)?;
+        Ok(hex(&script[..]))
+    }
+
+    /// The address of the descriptor on the network named `network` (either "bitcoin"
+    /// or "testnet"), or `undefined` if it has no address form
+    pub fn address(&self, network: &str) -> Result<Option<String>, JsValue> {
+        let network = match network {
+            "bitcoin" => Network::Bitcoin,
+            "testnet" => Network::Testnet,
+            _ => return Err(JsValue::from_str(&format!("unknown network {}", network))),
+        };
+        let addr = self.inner.address(network).map_err(js_err)?;
+        Ok(addr.map(|addr| addr.to_string()))
+    }
+
+    /// An upper bound on the weight of the scriptSig and witness spending the descriptor
+    #[wasm_bindgen(js_name = maxSatisfactionWeight)]
+    pub fn max_satisfaction_weight(&self) -> Result<u32, JsValue> {
+        self.inner.max_satisfaction_weight().map(|w| w as u32).map_err(js_err)
+    }
+}
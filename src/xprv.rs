@@ -0,0 +1,250 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Extended Private Keys
+//!
+//! Descriptor keys given as BIP32 extended private keys, for private descriptors such
+//! as `wpkh(xprv.../0'/1/*)`. The derivation path may have hardened steps, written
+//! with `'` or `h`, and may end in an unhardened `*` wildcard. A private descriptor
+//! converts to the public descriptor which watches the same scripts, and gives the
+//! secret keys needed to sign for any child index. Single keys in WIF form are not
+//! supported.
+//!
+
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::fmt;
+use std::str::FromStr;
+
+use secp256k1;
+
+use bitcoin::util::bip32::{ChildNumber, ExtendedPrivKey, ExtendedPubKey};
+
+use descriptor::PublicKey;
+use xpub::DescriptorXPub;
+use {Descriptor, Error};
+
+/// An extended private key, the path to derive from it and whether the last step of
+/// the path is a wildcard, to be filled in with a child index when instantiating
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorSecretKey {
+    /// The extended private key
+    pub xprv: ExtendedPrivKey,
+    /// Derivation steps applied to `xprv`, which may be hardened
+    pub path: Vec<ChildNumber>,
+    /// Whether a final unhardened step, given by the child index, follows `path`
+    pub wildcard: bool,
+}
+
+impl Hash for DescriptorSecretKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.to_string().hash(state);
+    }
+}
+
+impl DescriptorSecretKey {
+    /// Derives the extended private key at the end of `path`
+    fn parent(&self) -> Result<ExtendedPrivKey, Error> {
+        let secp = secp256k1::Secp256k1::new();
+        let mut xprv = self.xprv;
+        for step in &self.path {
+            xprv = xprv.ckd_priv(&secp, *step).map_err(Error::Bip32)?;
+        }
+        Ok(xprv)
+    }
+
+    /// The public form of the key, which derives the same public keys. Its extended
+    /// public key is that at the last hardened step of the path, since the steps up to
+    /// there cannot be derived publicly; those steps become its origin, along with the
+    /// fingerprint of `xprv`.
+    pub fn to_public(&self) -> Result<DescriptorXPub, Error> {
+        let secp = secp256k1::Secp256k1::new();
+        let hardened = self.path.iter().rposition(|step| match *step {
+            ChildNumber::Hardened(..) => true,
+            ChildNumber::Normal(..) => false,
+        }).map_or(0, |n| n + 1);
+
+        let mut xprv = self.xprv;
+        for step in &self.path[..hardened] {
+            xprv = xprv.ckd_priv(&secp, *step).map_err(Error::Bip32)?;
+        }
+        let origin = if hardened > 0 {
+            let master = ExtendedPubKey::from_private(&secp, &self.xprv);
+            Some((master.fingerprint(), self.path[..hardened].to_vec()))
+        } else {
+            None
+        };
+        Ok(DescriptorXPub {
+            origin,
+            xpub: ExtendedPubKey::from_private(&secp, &xprv),
+            path: self.path[hardened..].to_vec(),
+            wildcard: self.wildcard,
+        })
+    }
+
+    /// Derives the secret key for child `index`, which is ignored if the key has no
+    /// wildcard
+    pub fn derive_secret(&self, index: u32) -> Result<secp256k1::SecretKey, Error> {
+        let parent = self.parent()?;
+        if self.wildcard {
+            let secp = secp256k1::Secp256k1::new();
+            let child = parent.ckd_priv(&secp, ChildNumber::Normal(index)).map_err(Error::Bip32)?;
+            Ok(child.secret_key)
+        } else {
+            Ok(parent.secret_key)
+        }
+    }
+}
+
+impl fmt::Display for DescriptorSecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.xprv.to_string())?;
+        for step in &self.path {
+            match *step {
+                ChildNumber::Normal(index) => write!(f, "/{}", index)?,
+                ChildNumber::Hardened(index) => write!(f, "/{}'", index)?,
+            }
+        }
+        if self.wildcard {
+            f.write_str("/*")?;
+        }
+        Ok(())
+    }
+}
+
+impl PublicKey for DescriptorSecretKey {
+    /// Child index of a wildcard key
+    type Aux = u32;
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+
+    fn from_str(s: &str) -> Result<DescriptorSecretKey, Error> {
+        let mut parts = s.split('/');
+        let xprv = parts
+            .next()
+            .and_then(|xprv| ExtendedPrivKey::from_str(xprv).ok())
+            .ok_or_else(|| Error::Unexpected(s.to_string()))?;
+
+        let mut path = vec![];
+        let mut wildcard = false;
+        for part in parts {
+            if wildcard {
+                // Nothing may follow the wildcard
+                return Err(Error::Unexpected(s.to_string()));
+            }
+            if part == "*" {
+                wildcard = true;
+                continue;
+            }
+            let (digits, hardened) = if part.ends_with('\'') || part.ends_with('h') {
+                (&part[..part.len() - 1], true)
+            } else {
+                (part, false)
+            };
+            match digits.parse::<u32>() {
+                Ok(index) if index < 1 << 31 && hardened => path.push(ChildNumber::Hardened(index)),
+                Ok(index) if index < 1 << 31 => path.push(ChildNumber::Normal(index)),
+                _ => return Err(Error::Unexpected(s.to_string())),
+            }
+        }
+
+        Ok(DescriptorSecretKey {
+            xprv,
+            path,
+            wildcard,
+        })
+    }
+
+    fn instantiate(&self, aux: Option<&u32>) -> Result<secp256k1::PublicKey, Error> {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = match aux {
+            Some(index) => self.derive_secret(*index)?,
+            None if self.wildcard => return Err(Error::MissingChildIndex(self.to_string())),
+            None => self.derive_secret(0)?,
+        };
+        Ok(secp256k1::PublicKey::from_secret_key(&secp, &sk).expect("signing context"))
+    }
+}
+
+impl Descriptor<DescriptorSecretKey> {
+    /// The public descriptor, which describes the same scripts without revealing any
+    /// secret keys
+    pub fn to_public(&self) -> Result<Descriptor<DescriptorXPub>, Error> {
+        self.translate(|sk| sk.to_public())
+    }
+
+    /// Derives the secret key of every key of the descriptor for child `index`, by
+    /// public key, for signing a spend of that child's script
+    pub fn secret_keys(&self, index: u32) -> Result<HashMap<secp256k1::PublicKey, secp256k1::SecretKey>, Error> {
+        let secp = secp256k1::Secp256k1::new();
+        let mut ret = HashMap::new();
+        self.translate(|sk| -> Result<secp256k1::PublicKey, Error> {
+            let secret = sk.derive_secret(index)?;
+            let pk = secp256k1::PublicKey::from_secret_key(&secp, &secret).expect("signing context");
+            ret.insert(pk, secret);
+            Ok(pk)
+        })?;
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    use secp256k1;
+
+    use descriptor::PublicKey;
+    use {Descriptor, Error};
+    use super::DescriptorSecretKey;
+
+    // BIP32 test vector 1, whose master public key is the `XPUB` of the xpub tests
+    const XPRV: &str = "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi";
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    #[test]
+    fn private_descriptors() {
+        let desc = Descriptor::<DescriptorSecretKey>::from_str(&format!("wpkh({}/0/*)", XPRV)).unwrap();
+        assert_eq!(desc.to_string(), format!("wpkh({}/0/*)", XPRV));
+        assert_eq!(desc.to_public().unwrap().to_string(), format!("wpkh({}/0/*)", XPUB));
+
+        // Hardened steps are derived privately, and accepted in either notation
+        let hardened = Descriptor::<DescriptorSecretKey>::from_str(&format!("wsh(multi(1,{}/0h/1/*,{}/2'))", XPRV, XPRV)).unwrap();
+        assert_eq!(hardened.to_string(), format!("wsh(multi(1,{}/0'/1/*,{}/2'))", XPRV, XPRV));
+        let public = hardened.to_public().unwrap();
+        // and become the origins of the public keys, from the fingerprint of `XPRV`
+        let public_str = public.to_string();
+        assert!(public_str.contains(",[3442193e/0']xpub"));
+        assert!(public_str.contains(",[3442193e/2']xpub"));
+        assert_eq!(public_str.matches('\'').count(), 2);
+
+        // The secret keys are those of the public descriptor's keys
+        let secp = secp256k1::Secp256k1::new();
+        let keys = hardened.secret_keys(7).unwrap();
+        assert_eq!(keys.len(), 2);
+        let instance = public.translate(|pk| pk.instantiate(Some(&7))).unwrap();
+        instance.translate(|pk| -> Result<secp256k1::PublicKey, Error> {
+            assert_eq!(secp256k1::PublicKey::from_secret_key(&secp, &keys[pk]).expect("signing context"), *pk);
+            Ok(*pk)
+        }).unwrap();
+        assert!(hardened.instantiate(&HashMap::new()).is_err());
+
+        for bad in &["*/2", "2147483648", "0''", "x"] {
+            assert!(Descriptor::<DescriptorSecretKey>::from_str(&format!("pk({}/{})", XPRV, bad)).is_err());
+        }
+    }
+}
@@ -0,0 +1,556 @@
+// Script Descriptor Language
+// Written in 2018 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Extended Public Keys
+//!
+//! Descriptor keys given as BIP32 extended public keys, followed by an unhardened
+//! derivation path which may end in a `*` wildcard, e.g. `xpub.../1/*`. A descriptor
+//! with wildcard keys describes a range of scripts, one per child index, such as the
+//! receive addresses of a wallet.
+//!
+//! A key may be preceded by its origin: the fingerprint of the master key it was
+//! derived from and the path from there, as in `[d34db33f/48'/0'/0'/2']xpub.../0/*`.
+//! Hardware wallets need the origin to find the key they hold, so it is carried into
+//! key reports and PSBTs.
+//!
+
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::fmt;
+use std::str::FromStr;
+
+use secp256k1;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::network::constants::Network;
+use bitcoin::util::address::Address;
+use bitcoin::util::bip32::{self, ChildNumber, ExtendedPubKey, Fingerprint};
+
+use descriptor::PublicKey;
+use psbt::KeyOrigin;
+use {Descriptor, Error, KeyReport, ParseTree};
+
+/// An extended public key, the path to derive from it and whether the last step of
+/// the path is a wildcard, to be filled in with a child index when instantiating
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorXPub {
+    /// Fingerprint of the master key and the (possibly hardened) path from it to
+    /// `xpub`, if given
+    pub origin: Option<KeyOrigin>,
+    /// The extended public key
+    pub xpub: ExtendedPubKey,
+    /// Unhardened derivation steps applied to `xpub`
+    pub path: Vec<ChildNumber>,
+    /// Whether a final step, given by the child index, follows `path`
+    pub wildcard: bool,
+}
+
+impl Hash for DescriptorXPub {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if let Some((ref fingerprint, ref path)) = self.origin {
+            fingerprint[..].hash(state);
+            for step in path {
+                match *step {
+                    ChildNumber::Normal(index) => index.hash(state),
+                    ChildNumber::Hardened(index) => (index | 1 << 31).hash(state),
+                }
+            }
+        }
+        self.xpub.to_string().hash(state);
+        for step in &self.path {
+            if let ChildNumber::Normal(index) = *step {
+                index.hash(state);
+            }
+        }
+        self.wildcard.hash(state);
+    }
+}
+
+impl DescriptorXPub {
+    /// Derives the extended key at the end of `path`, which is the parent of every
+    /// child if the key has a wildcard
+    pub fn parent(&self) -> Result<ExtendedPubKey, Error> {
+        let secp = secp256k1::Secp256k1::new();
+        let mut xpub = self.xpub;
+        for step in &self.path {
+            xpub = xpub.ckd_pub(&secp, *step).map_err(Error::Bip32)?;
+        }
+        Ok(xpub)
+    }
+
+    /// Derives the public key for child `index` from `parent`, as computed by `parent()`.
+    /// The index is ignored if the key has no wildcard, but must be unhardened either way.
+    pub fn derive_from(&self, parent: &ExtendedPubKey, index: u32) -> Result<secp256k1::PublicKey, Error> {
+        check_index(index)?;
+        if self.wildcard {
+            let secp = secp256k1::Secp256k1::new();
+            let child = parent.ckd_pub(&secp, ChildNumber::Normal(index)).map_err(Error::Bip32)?;
+            Ok(child.public_key)
+        } else {
+            Ok(parent.public_key)
+        }
+    }
+
+    /// The fingerprint of the master key and the full path from it to the key of child
+    /// `index` (which is ignored if the key has no wildcard), as PSBTs and hardware
+    /// wallets locate keys. Without an origin, `xpub` is taken to be the master key.
+    pub fn key_origin(&self, index: u32) -> KeyOrigin {
+        let (fingerprint, mut path) = match self.origin {
+            Some((fingerprint, ref path)) => (fingerprint, path.clone()),
+            None => (self.xpub.fingerprint(), vec![]),
+        };
+        path.extend_from_slice(&self.path);
+        if self.wildcard {
+            path.push(ChildNumber::Normal(index));
+        }
+        (fingerprint, path)
+    }
+}
+
+/// Checks that `index` is below `2^31`: a wildcard is filled in with an unhardened
+/// step, and hardened ones cannot be derived from an extended public key
+fn check_index(index: u32) -> Result<(), Error> {
+    if index < 1 << 31 {
+        Ok(())
+    } else {
+        Err(Error::Bip32(bip32::Error::InvalidChildNumber(ChildNumber::Hardened(index - (1 << 31)))))
+    }
+}
+
+/// Writes the steps of a derivation path, each preceded by a `/`
+fn fmt_path(f: &mut fmt::Formatter, path: &[ChildNumber]) -> fmt::Result {
+    for step in path {
+        match *step {
+            ChildNumber::Normal(index) => write!(f, "/{}", index)?,
+            ChildNumber::Hardened(index) => write!(f, "/{}'", index)?,
+        }
+    }
+    Ok(())
+}
+
+/// Parses a key origin `d34db33f/48'/0'/0'`, given without its brackets. Hardened
+/// steps may be written with `'` or `h`.
+fn parse_origin(s: &str) -> Result<KeyOrigin, Error> {
+    let mut parts = s.split('/');
+    let fingerprint = parts.next().unwrap_or("");
+    if fingerprint.len() != 8 || !fingerprint.chars().all(|ch| ch.is_ascii_hexdigit()) {
+        return Err(Error::Unexpected(format!("key origin [{}]", s)));
+    }
+    let mut bytes = [0; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&fingerprint[2 * i..2 * i + 2], 16).expect("hex digits");
+    }
+
+    let mut path = vec![];
+    for part in parts {
+        let (digits, hardened) = if part.ends_with('\'') || part.ends_with('h') {
+            (&part[..part.len() - 1], true)
+        } else {
+            (part, false)
+        };
+        match digits.parse::<u32>() {
+            Ok(index) if index < 1 << 31 && hardened => path.push(ChildNumber::Hardened(index)),
+            Ok(index) if index < 1 << 31 => path.push(ChildNumber::Normal(index)),
+            _ => return Err(Error::Unexpected(format!("key origin [{}]", s))),
+        }
+    }
+    Ok((Fingerprint::from(&bytes[..]), path))
+}
+
+impl fmt::Display for DescriptorXPub {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((ref fingerprint, ref path)) = self.origin {
+            f.write_str("[")?;
+            for x in &fingerprint[..] {
+                write!(f, "{:02x}", *x)?;
+            }
+            fmt_path(f, path)?;
+            f.write_str("]")?;
+        }
+        write!(f, "{}", self.xpub.to_string())?;
+        fmt_path(f, &self.path)?;
+        if self.wildcard {
+            f.write_str("/*")?;
+        }
+        Ok(())
+    }
+}
+
+impl PublicKey for DescriptorXPub {
+    /// Child index of a wildcard key
+    type Aux = u32;
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+
+    fn from_str(s: &str) -> Result<DescriptorXPub, Error> {
+        let (origin, key) = if s.starts_with('[') {
+            match s.find(']') {
+                Some(n) => (Some(parse_origin(&s[1..n])?), &s[n + 1..]),
+                None => return Err(Error::ExpectedChar(']')),
+            }
+        } else {
+            (None, s)
+        };
+
+        let mut parts = key.split('/');
+        let xpub = parts
+            .next()
+            .and_then(|xpub| ExtendedPubKey::from_str(xpub).ok())
+            .ok_or_else(|| Error::Unexpected(s.to_string()))?;
+
+        let mut path = vec![];
+        let mut wildcard = false;
+        for part in parts {
+            if wildcard {
+                // Nothing may follow the wildcard
+                return Err(Error::Unexpected(s.to_string()));
+            }
+            if part == "*" {
+                wildcard = true;
+                continue;
+            }
+            // Hardened steps cannot be derived from a public key
+            match part.parse::<u32>() {
+                Ok(index) if index < 1 << 31 => path.push(ChildNumber::Normal(index)),
+                _ => return Err(Error::Unexpected(s.to_string())),
+            }
+        }
+
+        Ok(DescriptorXPub {
+            origin,
+            xpub,
+            path,
+            wildcard,
+        })
+    }
+
+    fn instantiate(&self, aux: Option<&u32>) -> Result<secp256k1::PublicKey, Error> {
+        let parent = self.parent()?;
+        match aux {
+            Some(index) => self.derive_from(&parent, *index),
+            None if self.wildcard => Err(Error::MissingChildIndex(self.to_string())),
+            None => Ok(parent.public_key),
+        }
+    }
+}
+
+/// Iterator over the scripts of a range of child indices of a descriptor, as returned
+/// by `Descriptor::derive_batch`
+pub struct DeriveBatch<'a> {
+    desc: &'a Descriptor<DescriptorXPub>,
+    /// The key at the end of each descriptor key's fixed path
+    parents: HashMap<DescriptorXPub, ExtendedPubKey>,
+    range: Range<u32>,
+    network: Network,
+}
+
+impl<'a> DeriveBatch<'a> {
+    /// Instantiates the descriptor for child `index`
+    fn derive(&self, index: u32) -> Result<Descriptor<secp256k1::PublicKey>, Error> {
+        let parents = &self.parents;
+        self.desc.translate(|pk| pk.derive_from(&parents[pk], index))
+    }
+}
+
+impl<'a> Iterator for DeriveBatch<'a> {
+    type Item = Result<(u32, Script, Option<Address>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.range.next()?;
+        let desc = match self.derive(index) {
+            Ok(desc) => desc,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(desc.script_pubkey().and_then(|spk| Ok((index, spk, desc.address(self.network)?))))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.range.size_hint()
+    }
+}
+
+impl Descriptor<DescriptorXPub> {
+    /// Derives the scriptpubkey and address (on `network`, if the script has one) of
+    /// every child index in `range`, for scanning a wallet's addresses. The fixed part
+    /// of each key's path is derived once up front, so each child costs one derivation
+    /// per wildcard key. Every index of the range must be below `2^31`.
+    pub fn derive_batch(&self, range: Range<u32>, network: Network) -> Result<DeriveBatch<'_>, Error> {
+        if range.start < range.end {
+            check_index(range.end - 1)?;
+        }
+        let mut parents = HashMap::new();
+        self.translate(|pk| -> Result<secp256k1::PublicKey, Error> {
+            let parent = pk.parent()?;
+            let key = parent.public_key;
+            parents.insert(pk.clone(), parent);
+            Ok(key)
+        })?;
+        Ok(DeriveBatch {
+            desc: self,
+            parents,
+            range,
+            network,
+        })
+    }
+
+    /// Instantiates the descriptor for child `index`, also giving the origin of each of
+    /// its keys, by derived key, as the `bip32_derivation` fields of a PSBT need
+    pub fn derive_with_origins(&self, index: u32) -> Result<(Descriptor<secp256k1::PublicKey>, HashMap<secp256k1::PublicKey, KeyOrigin>), Error> {
+        let mut origins = HashMap::new();
+        let desc = self.translate(|pk| -> Result<secp256k1::PublicKey, Error> {
+            let key = pk.instantiate(Some(&index))?;
+            origins.insert(key, pk.key_origin(index));
+            Ok(key)
+        })?;
+        Ok((desc, origins))
+    }
+
+    /// Compile child `index` of the descriptor and report its keys as
+    /// `ParseTree::key_report` would, along with the origin of each
+    pub fn key_report(&self, index: u32) -> Result<KeyReport, Error> {
+        let (desc, origins) = self.derive_with_origins(index)?;
+        let mut report = ParseTree::from_descriptor(&desc)?.0.key_report();
+        for source in &mut report.keys {
+            source.origin = origins.get(&source.key).cloned();
+        }
+        Ok(report)
+    }
+
+    /// Computes the Electrum protocol script hash of the scriptpubkey of child `index`,
+    /// as for `Descriptor::electrum_scripthash`
+    pub fn electrum_scripthash(&self, index: u32) -> Result<String, Error> {
+        let desc = self.translate(|pk| pk.instantiate(Some(&index)))?;
+        desc.electrum_scripthash()
+    }
+
+    /// Finds the first child index in `range` for which the descriptor has the
+    /// scriptpubkey `script_pubkey` (any of its scriptpubkeys, for `combo`), for
+    /// recognizing a wallet's outputs when rescanning. Scripts of a different length
+    /// than the descriptor's are rejected after deriving a single child, and
+    /// descriptors without wildcards are only derived once.
+    pub fn matches_script_pubkey(&self, script_pubkey: &Script, range: Range<u32>) -> Result<Option<u32>, Error> {
+        let batch = self.derive_batch(range.clone(), Network::Bitcoin)?;
+        let wildcard = batch.parents.keys().any(|pk| pk.wildcard);
+        for index in range {
+            let candidates = batch.derive(index)?.script_pubkeys()?;
+            if candidates.contains(script_pubkey) {
+                return Ok(Some(index));
+            }
+            if !wildcard || candidates.iter().all(|spk| spk.len() != script_pubkey.len()) {
+                break;
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::bip32::{self, ChildNumber, ExtendedPubKey, Fingerprint};
+
+    use descriptor::PublicKey;
+    use {sha256, Descriptor, Error};
+    use super::DescriptorXPub;
+
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    #[test]
+    fn parse_display() {
+        for s in &[
+            format!("pkh({})", XPUB),
+            format!("wpkh({}/0/*)", XPUB),
+            format!("wsh(multi(1,{}/1,{}/0/*))", XPUB, XPUB),
+        ] {
+            let desc = Descriptor::<DescriptorXPub>::from_str(s).unwrap();
+            assert_eq!(desc.to_string(), *s);
+        }
+
+        for s in &[
+            format!("pkh({}/0'/*)", XPUB),
+            format!("pkh({}/*/0)", XPUB),
+            format!("pkh({}/x)", XPUB),
+            "pkh(xpub)".to_owned(),
+        ] {
+            assert!(Descriptor::<DescriptorXPub>::from_str(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn derive_batch() {
+        let desc = Descriptor::<DescriptorXPub>::from_str(
+            &format!("wsh(multi(1,{}/1,{}/0/*))", XPUB, XPUB)
+        ).unwrap();
+
+        let batch: Vec<_> = desc.derive_batch(5..8, Network::Bitcoin).unwrap().collect();
+        assert_eq!(batch.len(), 3);
+        for (i, item) in batch.into_iter().enumerate() {
+            let (index, spk, addr) = item.unwrap();
+            assert_eq!(index, 5 + i as u32);
+
+            let mut keymap = HashMap::new();
+            if let Descriptor::Wsh(ref inner) = desc {
+                if let Descriptor::Multi(_, ref keys) = **inner {
+                    for key in keys {
+                        keymap.insert(key.clone(), index);
+                    }
+                }
+            }
+            let instance = desc.instantiate(&keymap).unwrap();
+            assert_eq!(spk, instance.script_pubkey().unwrap());
+            assert_eq!(addr, instance.address(Network::Bitcoin).unwrap());
+        }
+
+        // Children differ, and wildcards need an index
+        let mut scripts: Vec<_> = desc.derive_batch(0..10, Network::Bitcoin).unwrap().map(|item| item.unwrap().1).collect();
+        scripts.dedup();
+        assert_eq!(scripts.len(), 10);
+        match desc.instantiate(&HashMap::new()) {
+            Err(Error::MissingChildIndex(_)) => {}
+            x => panic!("unexpected result {:?}", x.map(|desc| desc.to_string())),
+        }
+    }
+
+    #[test]
+    fn bip32_test_vector() {
+        // Test vector 1 of BIP32, from which only the unhardened steps can be taken
+        let key = |s: &str| ExtendedPubKey::from_str(s).unwrap().public_key;
+        let m_0h = DescriptorXPub::from_str(
+            "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw/*"
+        ).unwrap();
+        let parent = m_0h.parent().unwrap();
+        assert_eq!(
+            m_0h.derive_from(&parent, 1).unwrap(),
+            key("xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ")
+        );
+        let m_0h_1_2h = DescriptorXPub::from_str(
+            "xpub6D4BDPcP2GT577Vvch3R8wDkScZWzQzMMUm3PWbmWvVJrZwQY4VUNgqFJPMM3No2dFDFGTsxxpG5uJh7n7epu4trkrX7x7DogT5Uv6fcLW5/2/1000000000"
+        ).unwrap();
+        assert_eq!(
+            m_0h_1_2h.instantiate(None).unwrap(),
+            key("xpub6H1LXWLaKsWFhvm6RVpEL9P4KfRZSW7abD2ttkWP3SSQvnyA8FSVqNTEcYFgJS2UaFcxupHiYkro49S8yGasTvXEYBVPamhGW6cFJodrTHy")
+        );
+
+        // Hardened indices are rejected rather than derived as unhardened ones
+        let hardened = Err(Error::Bip32(bip32::Error::InvalidChildNumber(ChildNumber::Hardened(0))));
+        assert_eq!(m_0h.derive_from(&parent, 1 << 31), hardened);
+        assert_eq!(m_0h_1_2h.derive_from(&m_0h_1_2h.parent().unwrap(), 1 << 31), hardened);
+        assert!(m_0h.derive_from(&parent, u32::MAX).is_err());
+
+        let desc = Descriptor::Wpkh(m_0h);
+        assert_eq!(desc.derive_batch((1 << 31) - 1..1 << 31, Network::Bitcoin).unwrap().count(), 1);
+        assert!(desc.derive_batch(0..(1 << 31) + 1, Network::Bitcoin).is_err());
+        assert!(desc.derive_batch(u32::MAX - 1..u32::MAX, Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn matches_script_pubkey() {
+        let desc = Descriptor::<DescriptorXPub>::from_str(&format!("sh(wpkh({}/0/*))", XPUB)).unwrap();
+        let spks: Vec<_> = desc.derive_batch(0..20, Network::Bitcoin).unwrap().map(|item| item.unwrap().1).collect();
+
+        assert_eq!(desc.matches_script_pubkey(&spks[13], 0..20), Ok(Some(13)));
+        assert_eq!(desc.matches_script_pubkey(&spks[13], 10..14), Ok(Some(13)));
+        assert_eq!(desc.matches_script_pubkey(&spks[13], 0..13), Ok(None));
+        assert_eq!(desc.matches_script_pubkey(&spks[13], 14..100), Ok(None));
+
+        // A native segwit output of the same key is a different script
+        let wpkh = Descriptor::<DescriptorXPub>::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let native: Vec<_> = wpkh.derive_batch(0..1, Network::Bitcoin).unwrap().map(|item| item.unwrap().1).collect();
+        assert_eq!(desc.matches_script_pubkey(&native[0], 0..20), Ok(None));
+
+        // Without a wildcard, every index gives the same script
+        let fixed = Descriptor::<DescriptorXPub>::from_str(&format!("pkh({}/5)", XPUB)).unwrap();
+        let spk = fixed.derive_batch(0..1, Network::Bitcoin).unwrap().next().unwrap().unwrap().1;
+        assert_eq!(fixed.matches_script_pubkey(&spk, 3..10), Ok(Some(3)));
+    }
+
+    #[test]
+    fn electrum_scripthash() {
+        let desc = Descriptor::<DescriptorXPub>::from_str(
+            &format!("sh(wsh(multi(1,{}/1,{}/0/*)))", XPUB, XPUB)
+        ).unwrap();
+        let spks: Vec<_> = desc.derive_batch(0..3, Network::Bitcoin).unwrap().map(|item| item.unwrap().1).collect();
+
+        // Each child's hash is that of its own P2SH scriptpubkey
+        let mut hashes = vec![];
+        for (index, spk) in spks.iter().enumerate() {
+            let hash = desc.electrum_scripthash(index as u32).unwrap();
+            let expected: String = sha256::Hash::from_data(&spk[..])[..].iter().rev().map(|x| format!("{:02x}", x)).collect();
+            assert_eq!(hash, expected);
+            hashes.push(hash);
+        }
+        hashes.dedup();
+        assert_eq!(hashes.len(), 3);
+
+        // The last indices are hardened, which is an error rather than an overflow
+        let hardened = Err(Error::Bip32(bip32::Error::InvalidChildNumber(ChildNumber::Hardened(u32::MAX >> 1))));
+        assert_eq!(desc.electrum_scripthash(u32::MAX), hardened);
+    }
+
+    #[test]
+    fn key_origins() {
+        let s = format!("wsh(multi(1,[d34db33f/48'/0'/0'/2']{}/0/*,{}/1))", XPUB, XPUB);
+        let desc = Descriptor::<DescriptorXPub>::from_str(&s).unwrap();
+        assert_eq!(desc.to_string(), s);
+        // `h` is accepted for hardened steps, and printed as `'`
+        let h = Descriptor::<DescriptorXPub>::from_str(&s.replace('\'', "h")).unwrap();
+        assert_eq!(h.to_string(), s);
+
+        let fingerprint = Fingerprint::from(&[0xd3, 0x4d, 0xb3, 0x3f][..]);
+        let keys = match desc {
+            Descriptor::Wsh(ref inner) => match **inner {
+                Descriptor::Multi(_, ref keys) => keys.clone(),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            keys[0].key_origin(9),
+            (fingerprint, vec![
+                ChildNumber::Hardened(48), ChildNumber::Hardened(0), ChildNumber::Hardened(0),
+                ChildNumber::Hardened(2), ChildNumber::Normal(0), ChildNumber::Normal(9),
+            ])
+        );
+        // Without an origin the xpub is its own master
+        assert_eq!(keys[1].key_origin(9), (keys[1].xpub.fingerprint(), vec![ChildNumber::Normal(1)]));
+
+        let (_, origins) = desc.derive_with_origins(9).unwrap();
+        assert_eq!(origins.len(), 2);
+        let report = desc.key_report(9).unwrap();
+        assert_eq!(report.keys.len(), 2);
+        for source in &report.keys {
+            assert_eq!(source.origin.as_ref(), origins.get(&source.key));
+        }
+        assert_eq!(origins[&keys[0].instantiate(Some(&9)).unwrap()], keys[0].key_origin(9));
+
+        for s in &[
+            format!("pkh([d34db33]{})", XPUB),
+            format!("pkh([d34db33x]{})", XPUB),
+            format!("pkh([d34db33f/x']{})", XPUB),
+            format!("pkh([d34db33f/2147483648]{})", XPUB),
+        ] {
+            match Descriptor::<DescriptorXPub>::from_str(s) {
+                Err(Error::Unexpected(_)) => {}
+                x => panic!("unexpected result {:?} for {}", x.map(|desc| desc.to_string()), s),
+            }
+        }
+        assert!(Descriptor::<DescriptorXPub>::from_str(&format!("pkh([d34db33f/0'{})", XPUB)).is_err());
+    }
+}